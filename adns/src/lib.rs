@@ -14,6 +14,7 @@ pub struct Resolver {
     buf: Vec<u8>,
     qnum: u16,
     timeout: Duration,
+    stats: Stats,
 }
 
 struct Query {
@@ -35,6 +36,18 @@ pub struct Response {
     pub result: Result<IpAddr, Error>,
 }
 
+/// Lookups made since the resolver was created.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Lookups which sent a query to a server, rather than waiting on
+    /// one already sent for the domain
+    pub misses: u64,
+    /// Domains currently cached
+    pub cached: u64,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Error {
     NotFound,
@@ -52,6 +65,7 @@ impl Resolver {
             timeout: Duration::from_secs(3),
             buf,
             qnum: 0,
+            stats: Stats::default(),
         }
     }
 
@@ -59,6 +73,24 @@ impl Resolver {
         self.cache.clear();
     }
 
+    /// Keeps only the servers of one address family, if there are any,
+    /// so they can all be queried through one socket. Returns whether
+    /// there were.
+    pub fn retain_family(&mut self, v6: bool) -> bool {
+        if !self.servers.iter().any(|s| s.is_ipv6() == v6) {
+            return false;
+        }
+        self.servers.retain(|s| s.is_ipv6() == v6);
+        true
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            cached: self.cache.len() as u64,
+            ..self.stats
+        }
+    }
+
     pub fn from_resolv() -> io::Result<Resolver> {
         let buf = vec![0u8; 512];
         let mut conf = Vec::with_capacity(4096);
@@ -92,6 +124,7 @@ impl Resolver {
             timeout: Duration::from_secs(cfg.timeout as u64),
             buf,
             qnum: 0,
+            stats: Stats::default(),
         })
     }
 
@@ -108,19 +141,18 @@ impl Resolver {
             ));
         }
 
-        if let Some(entry) = self.cache.get(domain) {
-            return Ok(Some(entry.ip));
-        }
         if let Ok(entry) = domain.parse() {
             return Ok(Some(entry));
         }
+        if let Some(entry) = self.cache.get(domain) {
+            self.stats.hits += 1;
+            return Ok(Some(entry.ip));
+        }
         if self.responses.get(domain).is_none() {
             let qn = self.qnum;
             self.qnum = self.qnum.wrapping_add(1);
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(domain, dns_parser::QueryType::A, dns_parser::QueryClass::IN);
-            let packet = query.build().unwrap_or_else(|d| d);
-            sock.send_to(&packet, self.servers[0])?;
+            sock.send_to(&packet(qn, domain, true), self.servers[0])?;
+            self.stats.misses += 1;
 
             self.responses.insert(domain.to_string(), vec![]);
             let now = Instant::now();
@@ -258,31 +290,31 @@ impl Resolver {
 }
 
 impl Query {
+    /// Asks the current server for an IPv6 address, or the next server
+    /// for an IPv4 one.
     pub fn next(&mut self, qn: u16) -> Vec<u8> {
         self.query_deadline = Instant::now() + Duration::from_millis(QUERY_TIMEOUT_MS);
         if self.v4 {
             self.v4 = false;
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(
-                &self.domain,
-                dns_parser::QueryType::AAAA,
-                dns_parser::QueryClass::IN,
-            );
-            query.build().unwrap_or_else(|d| d)
         } else {
             self.server += 1;
             self.v4 = true;
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(
-                &self.domain,
-                dns_parser::QueryType::A,
-                dns_parser::QueryClass::IN,
-            );
-            query.build().unwrap_or_else(|d| d)
         }
+        packet(qn, &self.domain, self.v4)
     }
 }
 
+fn packet(qn: u16, domain: &str, v4: bool) -> Vec<u8> {
+    let qtype = if v4 {
+        dns_parser::QueryType::A
+    } else {
+        dns_parser::QueryType::AAAA
+    };
+    let mut query = dns_parser::Builder::new_query(qn, true);
+    query.add_question(domain, qtype, dns_parser::QueryClass::IN);
+    query.build().unwrap_or_else(|d| d)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -334,4 +366,50 @@ mod tests {
         #[cfg(not(target_os = "macos"))]
         assert!(processed);
     }
+
+    #[test]
+    fn test_stats() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut resolver = Resolver::new(&[server.local_addr().unwrap()]);
+        let mut sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_nonblocking(true).unwrap();
+
+        assert_eq!(
+            resolver.query(&mut sock, 0, "127.0.0.1").unwrap(),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        // Only the first lookup of a domain sends a query
+        assert_eq!(resolver.query(&mut sock, 1, "example.com").unwrap(), None);
+        assert_eq!(resolver.query(&mut sock, 2, "example.com").unwrap(), None);
+        assert_eq!(
+            resolver.stats(),
+            Stats {
+                hits: 0,
+                misses: 1,
+                cached: 0
+            }
+        );
+
+        let mut buf = [0u8; 512];
+        let amnt = server.recv(&mut buf).unwrap();
+        let packet = dns_parser::Packet::parse(&buf[..amnt]).unwrap();
+        assert_eq!(packet.questions[0].qtype, dns_parser::QueryType::A);
+
+        let query = resolver.queries.get_mut(&0).unwrap();
+        let qtypes: Vec<_> = (0..3)
+            .map(|_| {
+                let pkt = query.next(0);
+                let qtype = dns_parser::Packet::parse(&pkt).unwrap().questions[0].qtype;
+                (qtype, query.server)
+            })
+            .collect();
+        assert_eq!(
+            qtypes,
+            vec![
+                (dns_parser::QueryType::AAAA, 0),
+                (dns_parser::QueryType::A, 1),
+                (dns_parser::QueryType::AAAA, 1),
+            ]
+        );
+    }
 }
@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::io::{self, Cursor};
-use std::{cmp, fmt, str};
+use std::{fmt, str};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum BEncode {
@@ -11,6 +11,16 @@ pub enum BEncode {
     Dict(BTreeMap<Vec<u8>, BEncode>),
 }
 
+/// A decoded value borrowing its strings from the data it was decoded
+/// from, for data which is only inspected rather than kept.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BEncodeRef<'a> {
+    Int(i64),
+    String(&'a [u8]),
+    List(Vec<BEncodeRef<'a>>),
+    Dict(BTreeMap<&'a [u8], BEncodeRef<'a>>),
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum BError {
     UTF8Decode,
@@ -18,13 +28,12 @@ pub enum BError {
     InvalidChar(u8),
     ParseInt,
     EOF,
-    IO,
+    Trailing,
+    TooDeep,
+    TooMany,
+    TooLong,
 }
 
-/// This controls the maximum allocation size we'll perform
-/// at once. Needed for parsing strings without OOMing
-const MAX_ALLOC_LEN: usize = 4 * 1024 * 1024;
-
 impl fmt::Display for BError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match *self {
@@ -33,7 +42,10 @@ impl fmt::Display for BError {
             BError::InvalidChar(c) => write!(f, "Invalid character: {}", char::from(c)),
             BError::ParseInt => write!(f, "Invalid integer value encountered"),
             BError::EOF => write!(f, "Unexpected EOF in data"),
-            BError::IO => write!(f, "IO error"),
+            BError::Trailing => write!(f, "Unexpected data after value"),
+            BError::TooDeep => write!(f, "Lists or dicts nested too deeply"),
+            BError::TooMany => write!(f, "Too many values"),
+            BError::TooLong => write!(f, "String too long"),
         }
     }
 }
@@ -172,140 +184,309 @@ impl BEncode {
     }
 }
 
+impl<'a> BEncodeRef<'a> {
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            BEncodeRef::Int(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            BEncodeRef::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match *self {
+            BEncodeRef::String(v) => str::from_utf8(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<BEncodeRef<'a>>> {
+        match *self {
+            BEncodeRef::List(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<&'a [u8], BEncodeRef<'a>>> {
+        match *self {
+            BEncodeRef::Dict(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn encode_to_buf(&self) -> Vec<u8> {
+        let mut buf = Cursor::new(Vec::new());
+        self.encode(&mut buf).unwrap();
+        buf.into_inner()
+    }
+
+    pub fn encode<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        enum Token<'a, 'b> {
+            B(&'b BEncodeRef<'a>),
+            OS(&'a [u8]),
+            E,
+        }
+
+        let mut toks = vec![Token::B(self)];
+        while let Some(tok) = toks.pop() {
+            match tok {
+                Token::B(BEncodeRef::Int(i)) => {
+                    write!(w, "i{}e", i)?;
+                }
+                Token::B(&BEncodeRef::String(s)) | Token::OS(s) => {
+                    write!(w, "{}:", s.len())?;
+                    w.write_all(s)?;
+                }
+                Token::B(BEncodeRef::List(v)) => {
+                    write!(w, "l")?;
+                    toks.push(Token::E);
+                    toks.extend(v.iter().rev().map(Token::B));
+                }
+                Token::B(BEncodeRef::Dict(d)) => {
+                    write!(w, "d")?;
+                    toks.push(Token::E);
+                    for (k, v) in d.iter().rev() {
+                        toks.push(Token::B(v));
+                        toks.push(Token::OS(k));
+                    }
+                }
+                Token::E => {
+                    write!(w, "e")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the strings out of the data. Recursion is bounded by the
+    /// depth limit the value was decoded within.
+    pub fn into_owned(self) -> BEncode {
+        match self {
+            BEncodeRef::Int(i) => BEncode::Int(i),
+            BEncodeRef::String(s) => BEncode::String(s.to_vec()),
+            BEncodeRef::List(l) => BEncode::List(l.into_iter().map(|v| v.into_owned()).collect()),
+            BEncodeRef::Dict(d) => BEncode::Dict(
+                d.into_iter()
+                    .map(|(k, v)| (k.to_vec(), v.into_owned()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Limits on decoded data, so maliciously crafted payloads can exhaust
+/// neither memory nor the stack of code walking the decoded values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Nesting of lists and dicts
+    pub depth: usize,
+    /// Values in total, counting dict keys and nested values
+    pub items: usize,
+    /// Bytes of a string
+    pub string_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            depth: 64,
+            items: 1 << 22,
+            string_len: 1 << 27,
+        }
+    }
+}
+
+/// Decodes a single value spanning all of the bytes.
 pub fn decode_buf(bytes: &[u8]) -> Result<BEncode, BError> {
-    decode(&mut Cursor::new(bytes))
+    decode_with(bytes, &Limits::default())
+}
+
+/// Decodes a single value spanning all of the bytes, within the limits.
+pub fn decode_with(bytes: &[u8], limits: &Limits) -> Result<BEncode, BError> {
+    decode_ref_with(bytes, limits).map(BEncodeRef::into_owned)
+}
+
+/// Decodes a single value spanning all of the bytes without copying
+/// any of its strings.
+pub fn decode_ref(bytes: &[u8]) -> Result<BEncodeRef<'_>, BError> {
+    decode_ref_with(bytes, &Limits::default())
+}
+
+/// Decodes a single value spanning all of the bytes within the limits,
+/// without copying any of its strings.
+pub fn decode_ref_with<'a>(bytes: &'a [u8], limits: &Limits) -> Result<BEncodeRef<'a>, BError> {
+    let mut p = Parser::new(bytes, *limits);
+    let v = p.value()?;
+    if p.pos != bytes.len() {
+        return Err(BError::Trailing);
+    }
+    Ok(v)
 }
 
+/// Decodes the value at the start of the bytes, ignoring any after it.
 pub fn decode_buf_first(bytes: &[u8]) -> Result<BEncode, BError> {
-    decode_first(&mut Cursor::new(bytes))
+    decode_prefix(bytes).map(|(v, _)| v)
 }
 
-pub fn decode_first<R: io::Read>(bytes: &mut R) -> Result<BEncode, BError> {
-    do_decode(bytes, true)
+/// Decodes the value at the start of the bytes within the limits,
+/// without copying any of its strings. Also gives the number of bytes it
+/// spans, e.g. to find data following it.
+pub fn decode_ref_prefix<'a>(
+    bytes: &'a [u8],
+    limits: &Limits,
+) -> Result<(BEncodeRef<'a>, usize), BError> {
+    let mut p = Parser::new(bytes, *limits);
+    let v = p.value()?;
+    Ok((v, p.pos))
 }
 
-pub fn decode<R: io::Read>(bytes: &mut R) -> Result<BEncode, BError> {
-    do_decode(bytes, false)
+/// Decodes the value at the start of the bytes, also giving the number
+/// of bytes it spans, e.g. to find data following it.
+pub fn decode_prefix(bytes: &[u8]) -> Result<(BEncode, usize), BError> {
+    let mut p = Parser::new(bytes, Limits::default());
+    let v = p.value()?;
+    Ok((v.into_owned(), p.pos))
 }
 
-fn do_decode<R: io::Read>(bytes: &mut R, first: bool) -> Result<BEncode, BError> {
-    enum Kind {
-        Dict(usize),
-        List(usize),
-    }
-    let mut cstack = vec![];
-    let mut vstack = vec![];
-    let mut buf = [0];
-    while !first || !(cstack.is_empty() && vstack.len() == 1) {
-        match next_byte(bytes, &mut buf) {
-            Ok(b'i') => {
-                // Multiple non complex values are not allowed
-                if cstack.is_empty() && !vstack.is_empty() {
-                    return Err(BError::EOF);
-                }
-                let s = read_until(bytes, b'e', &mut buf)?;
-                vstack.push(BEncode::Int(decode_int(s)?));
-            }
-            Ok(b'l') => {
-                if cstack.is_empty() && !vstack.is_empty() {
-                    return Err(BError::EOF);
-                }
-                cstack.push(Kind::List(vstack.len()));
-            }
-            Ok(b'd') => {
-                if cstack.is_empty() && !vstack.is_empty() {
-                    return Err(BError::EOF);
+/// Decoder of a buffer, giving values which borrow their strings from it.
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    limits: Limits,
+    items: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(data: &'a [u8], limits: Limits) -> Parser<'a> {
+        Parser {
+            data,
+            pos: 0,
+            limits,
+            items: 0,
+        }
+    }
+
+    /// Decodes a value without recursing, so the depth of nesting is only
+    /// bounded by the limits.
+    fn value(&mut self) -> Result<BEncodeRef<'a>, BError> {
+        enum Kind {
+            Dict(usize),
+            List(usize),
+        }
+        let mut cstack = vec![];
+        let mut vstack = vec![];
+        loop {
+            let c = self.next()?;
+            match c {
+                b'i' => {
+                    self.item()?;
+                    let i = decode_int(self.until(b'e')?)?;
+                    vstack.push(BEncodeRef::Int(i));
                 }
-                cstack.push(Kind::Dict(vstack.len()));
-            }
-            Err(BError::EOF) => break,
-            Ok(b'e') => match cstack.pop() {
-                Some(Kind::List(i)) => {
-                    let mut l = Vec::with_capacity(vstack.len() - i);
-                    while vstack.len() > i {
-                        l.push(vstack.pop().unwrap());
+                b'l' | b'd' => {
+                    self.item()?;
+                    if cstack.len() == self.limits.depth {
+                        return Err(BError::TooDeep);
+                    }
+                    if c == b'l' {
+                        cstack.push(Kind::List(vstack.len()));
+                    } else {
+                        cstack.push(Kind::Dict(vstack.len()));
                     }
-                    l.reverse();
-                    vstack.push(BEncode::List(l));
+                    continue;
                 }
-                Some(Kind::Dict(i)) => {
-                    let mut d = BTreeMap::new();
-                    if (vstack.len() - i) % 2 != 0 {
-                        return Err(BError::InvalidDict);
+                b'e' => match cstack.pop() {
+                    Some(Kind::List(i)) => {
+                        let l = vstack.split_off(i);
+                        vstack.push(BEncodeRef::List(l));
                     }
-                    while vstack.len() > i {
-                        let val = vstack.pop().unwrap();
-                        match vstack.pop().and_then(BEncode::into_bytes) {
-                            Some(key) => {
-                                d.insert(key, val);
+                    Some(Kind::Dict(i)) => {
+                        if (vstack.len() - i) % 2 != 0 {
+                            return Err(BError::InvalidDict);
+                        }
+                        let mut d = BTreeMap::new();
+                        let mut entries = vstack.drain(i..);
+                        while let (Some(key), Some(val)) = (entries.next(), entries.next()) {
+                            match key {
+                                BEncodeRef::String(key) => {
+                                    d.insert(key, val);
+                                }
+                                _ => return Err(BError::InvalidDict),
                             }
-                            None => return Err(BError::InvalidDict),
                         }
+                        drop(entries);
+                        vstack.push(BEncodeRef::Dict(d));
                     }
-                    vstack.push(BEncode::Dict(d))
-                }
-                None => return Err(BError::InvalidChar(b'e')),
-            },
-            Ok(d @ b'0'..=b'9') => {
-                if cstack.is_empty() && !vstack.is_empty() {
-                    return Err(BError::EOF);
+                    None => return Err(BError::InvalidChar(b'e')),
+                },
+                b'0'..=b'9' => {
+                    self.item()?;
+                    self.pos -= 1;
+                    let s = self.string()?;
+                    vstack.push(BEncodeRef::String(s));
                 }
-                let mut slen = read_until(bytes, b':', &mut buf)?;
-                slen.insert(0, d);
-                let len = decode_int(slen)?;
-                let mut v = vec![];
-                while v.len() < len as usize {
-                    let to_read = cmp::min(MAX_ALLOC_LEN, len as usize - v.len());
-                    v.resize(v.len() + to_read, 0u8);
-                    let read_start = v.len() - to_read;
-                    let read_end = v.len();
-                    bytes
-                        .read_exact(&mut v[read_start..read_end])
-                        .map_err(|_| BError::EOF)?;
-                }
-                vstack.push(BEncode::String(v));
+                c => return Err(BError::InvalidChar(c)),
+            }
+            if cstack.is_empty() {
+                return Ok(vstack.pop().unwrap());
             }
-            Err(e) => return Err(e),
-            Ok(c) => return Err(BError::InvalidChar(c)),
         }
     }
 
-    if cstack.is_empty() && vstack.len() == 1 {
-        Ok(vstack.into_iter().next().unwrap())
-    } else {
-        Err(BError::EOF)
+    fn string(&mut self) -> Result<&'a [u8], BError> {
+        let len = decode_int(self.until(b':')?)?;
+        if len < 0 {
+            return Err(BError::ParseInt);
+        }
+        if len as u64 > self.limits.string_len as u64 {
+            return Err(BError::TooLong);
+        }
+        let end = self.pos + len as usize;
+        let s = self.data.get(self.pos..end).ok_or(BError::EOF)?;
+        self.pos = end;
+        Ok(s)
+    }
+
+    fn item(&mut self) -> Result<(), BError> {
+        self.items += 1;
+        if self.items > self.limits.items {
+            return Err(BError::TooMany);
+        }
+        Ok(())
     }
-}
 
-fn next_byte<R: io::Read>(r: &mut R, buf: &mut [u8; 1]) -> Result<u8, BError> {
-    let amnt = r.read(buf).map_err(|_| BError::IO)?;
-    if amnt == 0 {
-        Err(BError::EOF)
-    } else {
-        Ok(buf[0])
+    fn next(&mut self) -> Result<u8, BError> {
+        let b = *self.data.get(self.pos).ok_or(BError::EOF)?;
+        self.pos += 1;
+        Ok(b)
     }
-}
 
-fn read_until<R: io::Read>(r: &mut R, b: u8, buf: &mut [u8; 1]) -> Result<Vec<u8>, BError> {
-    let mut v = vec![];
-    loop {
-        let n = next_byte(r, buf)?;
-        if b == n {
-            return Ok(v);
-        }
-        v.push(n);
+    /// The bytes up to a terminator, skipping past it.
+    fn until(&mut self, end: u8) -> Result<&'a [u8], BError> {
+        let rest = &self.data[self.pos..];
+        let len = rest.iter().position(|&b| b == end).ok_or(BError::EOF)?;
+        self.pos += len + 1;
+        Ok(&rest[..len])
     }
 }
 
-fn decode_int(v: Vec<u8>) -> Result<i64, BError> {
-    String::from_utf8(v)
+fn decode_int(v: &[u8]) -> Result<i64, BError> {
+    str::from_utf8(v)
         .map_err(|_| BError::UTF8Decode)
         .and_then(|i| i.parse().map_err(|_| BError::ParseInt))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{decode_buf, decode_buf_first, BEncode};
+    use super::*;
+    use rand::prng::XorShiftRng;
+    use rand::{Rng, SeedableRng};
     use std::collections::BTreeMap;
 
     #[test]
@@ -401,6 +582,87 @@ mod tests {
         assert_eq!(d, &v[..]);
     }
 
+    #[test]
+    fn test_prefix() {
+        let (v, len) = decode_prefix(b"d1:ai1eeraw").unwrap();
+        assert_eq!(v.as_dict().unwrap().len(), 1);
+        assert_eq!(len, 8);
+        assert_eq!(decode_buf(b"i1eraw"), Err(BError::Trailing));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            depth: 2,
+            items: 4,
+            string_len: 3,
+        };
+        assert!(decode_with(b"lli1eee", &limits).is_ok());
+        assert_eq!(decode_with(b"llli1eeee", &limits), Err(BError::TooDeep));
+        assert!(decode_with(b"li1ei2ei3ee", &limits).is_ok());
+        assert_eq!(
+            decode_with(b"li1ei2ei3ei4ee", &limits),
+            Err(BError::TooMany)
+        );
+        assert!(decode_with(b"3:abc", &limits).is_ok());
+        assert_eq!(decode_with(b"4:abcd", &limits), Err(BError::TooLong));
+
+        // Nesting is rejected before it could overflow the stack of
+        // anything recursing into the value, e.g. its destructor
+        let mut deep = vec![b'l'; 1_000_000];
+        deep.extend(vec![b'e'; 1_000_000]);
+        assert_eq!(decode_buf(&deep), Err(BError::TooDeep));
+    }
+
+    /// Decodes random mutations of valid data, which must fail cleanly or
+    /// survive a roundtrip.
+    #[test]
+    fn test_fuzz() {
+        let seeds: &[&[u8]] = &[
+            b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces0:ee",
+            b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re",
+            b"ld1:ali1ei-2eee3:xyzi0ee",
+        ];
+        let mut rng = XorShiftRng::from_seed(*b"synapse bencode!");
+        let mut rand = move |n: usize| rng.gen_range(0, n);
+        let alphabet = b"ilde0123456789:-";
+        for _ in 0..50_000 {
+            let mut data = seeds[rand(seeds.len())].to_vec();
+            for _ in 0..1 + rand(4) {
+                let i = rand(data.len());
+                let b = alphabet[rand(alphabet.len())];
+                match rand(3) {
+                    0 => data[i] = b,
+                    1 => data.insert(i, b),
+                    _ => {
+                        data.remove(i);
+                    }
+                }
+                if data.is_empty() {
+                    break;
+                }
+            }
+            if let Ok(v) = decode_buf(&data) {
+                encode_decode(&v);
+            }
+            let _ = decode_prefix(&data);
+        }
+    }
+
+    #[test]
+    fn test_decode_ref() {
+        let data = b"d4:infod4:name1:ae4:listli1e3:xyzee";
+        let v = decode_ref(data).unwrap();
+        let d = v.as_dict().unwrap();
+        let name = &d[&b"info"[..]].as_dict().unwrap()[&b"name"[..]];
+        assert_eq!(name.as_str(), Some("a"));
+        // Strings point into the decoded data rather than a copy of it
+        let s = d[&b"list"[..]].as_list().unwrap()[1].as_bytes().unwrap();
+        assert_eq!(s.as_ptr(), data[30..].as_ptr());
+        assert_eq!(v.encode_to_buf(), &data[..]);
+        assert_eq!(v.into_owned(), decode_buf(data).unwrap());
+    }
+
     #[test]
     fn test_non_utf8_dict_key() {
         let content = b"d2:\x80\x811:ae";
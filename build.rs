@@ -20,4 +20,76 @@ fn main() {
         .opt_level(3)
         .debug(debug)
         .compile("fallocate");
+
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Generates the gRPC service from the message types in src/rpc/grpc/proto.rs,
+/// which mirror proto/synapse.proto so that building does not require protoc.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::rpc::grpc::proto::{}", input))
+            .output_type(format!("crate::rpc::grpc::proto::{}", output))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Synapse")
+        .package("synapse")
+        .method(
+            method(
+                "get_resources",
+                "GetResources",
+                "GetResourcesRequest",
+                "Resources",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "list_resources",
+                "ListResources",
+                "ListResourcesRequest",
+                "Resources",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "watch_resources",
+                "WatchResources",
+                "ListResourcesRequest",
+                "ResourceEvent",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .method(
+            method(
+                "add_torrent",
+                "AddTorrent",
+                "AddTorrentRequest",
+                "AddTorrentResponse",
+            )
+            .build(),
+        )
+        .method(method("pause_torrent", "PauseTorrent", "TorrentRequest", "Empty").build())
+        .method(method("resume_torrent", "ResumeTorrent", "TorrentRequest", "Empty").build())
+        .method(
+            method(
+                "remove_resource",
+                "RemoveResource",
+                "RemoveResourceRequest",
+                "Empty",
+            )
+            .build(),
+        )
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
 }
@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use synapse_bencode as bencode;
+
+fuzz_target!(|fuzz_data: &[u8]| {
+    let limits = bencode::Limits {
+        depth: 4,
+        items: 64,
+        string_len: 32,
+    };
+    if let Ok(v) = bencode::decode_with(fuzz_data, &limits) {
+        assert_eq!(bencode::decode_buf(&v.encode_to_buf()).unwrap(), v);
+    }
+    // The value at the start of the data must span the length given
+    if let Ok((v, len)) = bencode::decode_prefix(fuzz_data) {
+        assert_eq!(bencode::decode_buf(&fuzz_data[..len]).unwrap(), v);
+    }
+});
@@ -0,0 +1,119 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::io::{self, Read};
+use synapse_protocol as protocol;
+
+// The peer reader is part of the synapse binary rather than a library, so
+// its source is compiled in here, with the few crate modules it uses
+// replaced by minimal stand-ins.
+#[allow(dead_code)]
+#[path = "../../src/torrent/peer/reader.rs"]
+mod reader;
+#[allow(dead_code)]
+#[path = "../../src/util/io.rs"]
+mod util;
+
+mod buffers {
+    use std::ops::{Deref, DerefMut};
+
+    pub const BUF_SIZE: usize = 16_384;
+
+    #[derive(Clone)]
+    pub struct Buffer {
+        data: Box<[u8; BUF_SIZE]>,
+    }
+
+    impl Buffer {
+        pub fn get() -> Option<Buffer> {
+            Some(Buffer {
+                data: Box::new([0u8; BUF_SIZE]),
+            })
+        }
+    }
+
+    impl Deref for Buffer {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.data[..]
+        }
+    }
+
+    impl DerefMut for Buffer {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            &mut self.data[..]
+        }
+    }
+
+    impl crate::protocol::Buffer for Buffer {}
+}
+
+mod torrent {
+    #[derive(Clone)]
+    pub struct Bitfield {
+        data: Vec<u8>,
+    }
+
+    impl Bitfield {
+        pub fn from(b: &[u8], len: u64) -> Bitfield {
+            let mut data = b.to_vec();
+            data.resize((len as usize + 7) / 8, 0);
+            Bitfield { data }
+        }
+    }
+
+    impl From<Vec<u8>> for Bitfield {
+        fn from(data: Vec<u8>) -> Bitfield {
+            Bitfield { data }
+        }
+    }
+
+    impl crate::protocol::Bitfield for Bitfield {
+        fn bytes(&self) -> usize {
+            self.data.len()
+        }
+
+        fn byte_at(&self, pos: usize) -> u8 {
+            self.data[pos]
+        }
+    }
+
+    pub mod peer {
+        pub type Message = crate::protocol::Message<super::Bitfield, crate::buffers::Buffer>;
+    }
+}
+
+/// Hands out the data in reads of at most step bytes, as a socket would.
+struct Chunked<'a> {
+    data: &'a [u8],
+    step: usize,
+}
+
+impl<'a> Read for Chunked<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let amnt = buf.len().min(self.step).min(self.data.len());
+        buf[..amnt].copy_from_slice(&self.data[..amnt]);
+        self.data = &self.data[amnt..];
+        Ok(amnt)
+    }
+}
+
+fuzz_target!(|fuzz_data: &[u8]| {
+    if fuzz_data.is_empty() {
+        return;
+    }
+    let mut conn = Chunked {
+        data: &fuzz_data[1..],
+        step: fuzz_data[0] as usize + 1,
+    };
+    let mut r = reader::Reader::new();
+    // Every message is either decoded or ends the connection with an
+    // error, which happens at the latest once the data runs out
+    loop {
+        match r.readable(&mut conn) {
+            reader::RRes::Success(_) => {}
+            reader::RRes::Err(_) => break,
+            reader::RRes::Blocked | reader::RRes::Stalled => unreachable!(),
+        }
+    }
+});
@@ -6,4 +6,4 @@ pub mod message;
 pub mod resource;
 
 pub const MAJOR_VERSION: u16 = 0;
-pub const MINOR_VERSION: u16 = 1;
+pub const MINOR_VERSION: u16 = 2;
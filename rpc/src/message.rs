@@ -3,7 +3,9 @@ use std::borrow::Cow;
 use chrono::{DateTime, Utc};
 
 use super::criterion::Criterion;
-use super::resource::{CResourceUpdate, ResourceKind, SResourceUpdate};
+use super::resource::{
+    CResourceUpdate, ErrorKind, Label, ResourceKind, SResourceUpdate, ThrottleGroup,
+};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Version {
@@ -61,6 +63,26 @@ pub enum CMessage {
         start: bool,
         #[serde(default = "default_false")]
         import: bool,
+        #[serde(default = "default_false")]
+        seed_only: bool,
+        label: Option<String>,
+        /// Name shown in place of the torrent's own
+        name: Option<String>,
+        /// Priority of each file, in order
+        #[serde(default)]
+        priorities: Vec<u8>,
+        /// Merge trackers into the torrent if it already exists
+        #[serde(default = "default_false")]
+        merge: bool,
+    },
+    /// Part of a torrent file offered by a TRANSFER_OFFER, for files too
+    /// large to send in one message
+    UploadChunk {
+        serial: u64,
+        token: String,
+        offset: u64,
+        /// Base64 encoded bytes
+        data: String,
     },
     UploadMagnet {
         serial: u64,
@@ -68,6 +90,13 @@ pub enum CMessage {
         path: Option<String>,
         #[serde(default = "default_true")]
         start: bool,
+        label: Option<String>,
+        name: Option<String>,
+        #[serde(default = "default_false")]
+        merge: bool,
+        /// Stop the torrent once its metadata is fetched
+        #[serde(default = "default_false")]
+        metadata_only: bool,
     },
     UploadFiles {
         serial: u64,
@@ -82,15 +111,66 @@ pub enum CMessage {
         serial: u64,
         id: String,
     },
+    PauseAll {
+        serial: u64,
+    },
+    ResumeAll {
+        serial: u64,
+    },
     UpdateTracker {
         serial: u64,
         id: String,
     },
+    ScrapeTorrent {
+        serial: u64,
+        id: String,
+    },
+    ReannounceTorrent {
+        serial: u64,
+        id: String,
+    },
+    RefreshDht {
+        serial: u64,
+        id: String,
+    },
     AddTracker {
         serial: u64,
         id: String,
         uri: String,
     },
+    SetPieceDeadline {
+        serial: u64,
+        id: String,
+        offset: u64,
+        deadline: u64,
+    },
+    CrossSeed {
+        serial: u64,
+        id: String,
+    },
+    /// Searches a directory for the files of a torrent, moving the
+    /// torrent to where they're found
+    FindData {
+        serial: u64,
+        id: String,
+        path: String,
+    },
+    SetLabel {
+        serial: u64,
+        label: Label,
+    },
+    RemoveLabel {
+        serial: u64,
+        name: String,
+    },
+    SetThrottleGroup {
+        serial: u64,
+        group: ThrottleGroup,
+    },
+    RemoveThrottleGroup {
+        serial: u64,
+        name: String,
+    },
     AddPeer {
         serial: u64,
         id: String,
@@ -100,9 +180,99 @@ pub enum CMessage {
         serial: u64,
         ids: Vec<String>,
     },
+    BulkAction {
+        serial: u64,
+        criteria: Vec<Criterion>,
+        action: Action,
+        #[serde(default = "default_false")]
+        artifacts: bool,
+    },
     PurgeDns {
         serial: u64,
     },
+    CreateDownloadLink {
+        serial: u64,
+        id: String,
+        expires: Option<DateTime<Utc>>,
+        rate: Option<u64>,
+    },
+    GetMagnet {
+        serial: u64,
+        id: String,
+    },
+    GetDhtItem {
+        serial: u64,
+        target: Option<String>,
+        key: Option<String>,
+        salt: Option<String>,
+    },
+    PutDhtItem {
+        serial: u64,
+        value: String,
+        #[serde(default = "default_false")]
+        mutable: bool,
+        salt: Option<String>,
+        seq: Option<i64>,
+    },
+    GetHistory {
+        serial: u64,
+        resolution: Resolution,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    },
+    GetSetting {
+        serial: u64,
+        key: String,
+    },
+    SetSetting {
+        serial: u64,
+        key: String,
+        value: serde_json::Value,
+    },
+    ReloadConfig {
+        serial: u64,
+    },
+    /// Halts all peer, tracker and DHT traffic, leaving torrents as they are
+    StopNetwork {
+        serial: u64,
+    },
+    StartNetwork {
+        serial: u64,
+    },
+    /// Listens for peers on a new port, or a random one if 0
+    SetPort {
+        serial: u64,
+        port: u16,
+    },
+    /// Moves the session or download directory, along with its contents
+    MoveDirectory {
+        serial: u64,
+        directory: Directory,
+        path: String,
+    },
+    /// Cancels the validation and downloads of a torrent, the
+    /// downloads of a file or the server's directory move
+    CancelJobs {
+        serial: u64,
+        id: String,
+    },
+    /// Retrieves logged events after the given event ID
+    GetEvents {
+        serial: u64,
+        after: Option<u64>,
+        #[serde(default)]
+        kinds: Vec<EventKind>,
+        torrent_id: Option<String>,
+    },
+    /// Sends each event of the given kinds, or any kind, as it occurs
+    SubscribeEvents {
+        serial: u64,
+        #[serde(default)]
+        kinds: Vec<EventKind>,
+    },
+    UnsubscribeEvents {
+        serial: u64,
+    },
 }
 
 /// Server -> client message
@@ -133,10 +303,80 @@ pub enum SMessage<'a> {
         token: String,
         size: u64,
     },
+    /// Bytes of a chunked upload received so far
+    TransferProgress {
+        serial: u64,
+        token: String,
+        received: u64,
+        size: u64,
+    },
     ResourcePending {
         serial: u64,
         id: String,
     },
+    DownloadLink {
+        serial: u64,
+        path: String,
+        expires: Option<DateTime<Utc>>,
+    },
+    Magnet {
+        serial: u64,
+        uri: String,
+    },
+    DhtItem {
+        serial: u64,
+        target: String,
+        value: Option<String>,
+        key: Option<String>,
+        salt: Option<String>,
+        seq: Option<i64>,
+    },
+    History {
+        serial: u64,
+        resolution: Resolution,
+        samples: Vec<Sample>,
+    },
+    Setting {
+        serial: u64,
+        key: String,
+        value: serde_json::Value,
+    },
+    ConfigReloaded {
+        serial: u64,
+        changed: Vec<String>,
+    },
+    PortChanged {
+        serial: u64,
+        port: u16,
+    },
+    DirectoryMoving {
+        serial: u64,
+        directory: Directory,
+        progress: f32,
+    },
+    DirectoryMoved {
+        serial: u64,
+        directory: Directory,
+        path: String,
+    },
+    /// Sent to every client able to see a torrent when an error stops it
+    Alert {
+        id: String,
+        error_kind: ErrorKind,
+        error: String,
+        /// When the failed jobs will be retried, if they will be
+        retry: Option<DateTime<Utc>>,
+    },
+    Events {
+        serial: u64,
+        events: Vec<Event>,
+    },
+    /// Sent to clients subscribed to events, with the serial of the
+    /// SUBSCRIBE_EVENTS
+    Event {
+        serial: u64,
+        event: Event,
+    },
 
     // Error messages
     UnknownResource(Error),
@@ -147,6 +387,97 @@ pub enum SMessage<'a> {
     TransferFailed(Error),
 }
 
+/// Action applied to every torrent matched by a BULK_ACTION.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Pause,
+    Resume,
+    Validate,
+    Remove,
+    Reannounce,
+    #[serde(rename = "dht_refresh")]
+    DhtRefresh,
+}
+
+/// Period covered by each sample of a HISTORY.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+/// Directory moved by a MOVE_DIRECTORY.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Directory {
+    Session,
+    Download,
+}
+
+/// Bytes transferred by the server in the period beginning at `time`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Sample {
+    pub time: DateTime<Utc>,
+    pub transferred_up: u64,
+    pub transferred_down: u64,
+}
+
+/// Something which happened to the server or a torrent, kept in the
+/// server's event log.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Event {
+    /// Increases with each event logged
+    pub id: u64,
+    pub time: DateTime<Utc>,
+    pub kind: EventKind,
+    /// Torrent the event concerns, and its name at the time
+    pub torrent_id: Option<String>,
+    pub name: Option<String>,
+    pub message: String,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    TorrentAdded,
+    TorrentFinished,
+    TorrentMoved,
+    /// An error other than a disk or tracker failure, such as a piece
+    /// failing its hash check
+    TorrentError,
+    TrackerWarning,
+    /// A disk error stopped a torrent
+    DiskAlert,
+    /// Files of a torrent were missing or resized on startup, so it's
+    /// being validated
+    TorrentChecked,
+    /// The metadata of a metadata only magnet was fetched, so it's stopped
+    MetadataFetched,
+    /// A peer was disconnected for running a refused client
+    PeerBanned,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::TorrentAdded => "torrent_added",
+            EventKind::TorrentFinished => "torrent_finished",
+            EventKind::TorrentMoved => "torrent_moved",
+            EventKind::TorrentError => "torrent_error",
+            EventKind::TrackerWarning => "tracker_warning",
+            EventKind::DiskAlert => "disk_alert",
+            EventKind::TorrentChecked => "torrent_checked",
+            EventKind::MetadataFetched => "metadata_fetched",
+            EventKind::PeerBanned => "peer_banned",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Error {
@@ -154,6 +485,60 @@ pub struct Error {
     pub reason: String,
 }
 
+impl CMessage {
+    pub fn serial(&self) -> u64 {
+        match *self {
+            CMessage::GetResources { serial, .. }
+            | CMessage::Subscribe { serial, .. }
+            | CMessage::Unsubscribe { serial, .. }
+            | CMessage::UpdateResource { serial, .. }
+            | CMessage::RemoveResource { serial, .. }
+            | CMessage::FilterSubscribe { serial, .. }
+            | CMessage::FilterUnsubscribe { serial, .. }
+            | CMessage::UploadTorrent { serial, .. }
+            | CMessage::UploadChunk { serial, .. }
+            | CMessage::UploadMagnet { serial, .. }
+            | CMessage::UploadFiles { serial, .. }
+            | CMessage::PauseTorrent { serial, .. }
+            | CMessage::ResumeTorrent { serial, .. }
+            | CMessage::UpdateTracker { serial, .. }
+            | CMessage::ScrapeTorrent { serial, .. }
+            | CMessage::ReannounceTorrent { serial, .. }
+            | CMessage::RefreshDht { serial, .. }
+            | CMessage::AddTracker { serial, .. }
+            | CMessage::SetPieceDeadline { serial, .. }
+            | CMessage::CrossSeed { serial, .. }
+            | CMessage::FindData { serial, .. }
+            | CMessage::SetLabel { serial, .. }
+            | CMessage::RemoveLabel { serial, .. }
+            | CMessage::SetThrottleGroup { serial, .. }
+            | CMessage::RemoveThrottleGroup { serial, .. }
+            | CMessage::AddPeer { serial, .. }
+            | CMessage::ValidateResources { serial, .. }
+            | CMessage::BulkAction { serial, .. }
+            | CMessage::PurgeDns { serial }
+            | CMessage::CreateDownloadLink { serial, .. }
+            | CMessage::GetMagnet { serial, .. }
+            | CMessage::GetDhtItem { serial, .. }
+            | CMessage::PutDhtItem { serial, .. }
+            | CMessage::GetHistory { serial, .. }
+            | CMessage::GetSetting { serial, .. }
+            | CMessage::SetSetting { serial, .. }
+            | CMessage::ReloadConfig { serial }
+            | CMessage::PauseAll { serial }
+            | CMessage::ResumeAll { serial }
+            | CMessage::StopNetwork { serial }
+            | CMessage::StartNetwork { serial }
+            | CMessage::SetPort { serial, .. }
+            | CMessage::MoveDirectory { serial, .. }
+            | CMessage::CancelJobs { serial, .. }
+            | CMessage::GetEvents { serial, .. }
+            | CMessage::SubscribeEvents { serial, .. }
+            | CMessage::UnsubscribeEvents { serial } => serial,
+        }
+    }
+}
+
 impl Version {
     pub fn current() -> Version {
         Version {
@@ -207,4 +592,77 @@ mod tests {
             unreachable!();
         }
     }
+
+    #[test]
+    fn test_nullable_update() {
+        let data = r#"
+            {
+                "type": "UPDATE_RESOURCE",
+                "serial": 0,
+                "resource": {
+                    "id": "a", "label": null, "seed_time": 60, "name": null, "notes": "n"
+                }
+            }
+            "#;
+        let m = serde_json::from_str(data).unwrap();
+        if let CMessage::UpdateResource { resource: r, .. } = m {
+            assert_eq!(r.label, Some(None));
+            assert_eq!(r.seed_time, Some(Some(60)));
+            assert_eq!(r.seed_ratio, None);
+            assert_eq!(r.name, Some(None));
+            assert_eq!(r.comment, None);
+            assert_eq!(r.notes, Some(Some("n".to_owned())));
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn test_move_directory() {
+        let data = r#"
+            { "type": "MOVE_DIRECTORY", "serial": 1, "directory": "session", "path": "/a" }
+            "#;
+        let m = serde_json::from_str(data).unwrap();
+        if let CMessage::MoveDirectory {
+            directory: Directory::Session,
+            path,
+            ..
+        } = m
+        {
+            assert_eq!(path, "/a");
+        } else {
+            unreachable!();
+        }
+        let m = SMessage::DirectoryMoved {
+            serial: 1,
+            directory: Directory::Download,
+            path: "/b".to_owned(),
+        };
+        assert_eq!(
+            serde_json::to_string(&m).unwrap(),
+            r#"{"type":"DIRECTORY_MOVED","serial":1,"directory":"download","path":"/b"}"#
+        );
+    }
+
+    #[test]
+    fn test_get_events() {
+        let data = r#"
+            { "type": "GET_EVENTS", "serial": 2, "after": 7, "kinds": ["disk_alert"] }
+            "#;
+        let m = serde_json::from_str(data).unwrap();
+        if let CMessage::GetEvents {
+            after: Some(7),
+            kinds,
+            torrent_id: None,
+            ..
+        } = m
+        {
+            assert_eq!(kinds, vec![EventKind::DiskAlert]);
+        } else {
+            unreachable!();
+        }
+        let m: CMessage =
+            serde_json::from_str(r#"{ "type": "SUBSCRIBE_EVENTS", "serial": 3 }"#).unwrap();
+        assert_eq!(m.serial(), 3);
+    }
 }
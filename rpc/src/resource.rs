@@ -74,6 +74,10 @@ pub enum SResourceUpdate<'a> {
         transferred_down: u64,
         ses_transferred_up: u64,
         ses_transferred_down: u64,
+        ses_overhead_up: u64,
+        ses_overhead_down: u64,
+        ratio: f32,
+        uptime: u64,
     },
     ServerSpace {
         id: String,
@@ -87,13 +91,65 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         download_token: String,
     },
+    ServerSnapshot {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        last_snapshot: Option<DateTime<Utc>>,
+    },
+    ServerFilteredPeers {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        filtered_peers: PeerFilterStats,
+    },
+    ServerBuffers {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        buffers: BufferStats,
+    },
+    ServerPort {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        port: u16,
+    },
+    ServerExternalIp {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        external_ipv4: Option<String>,
+        external_ipv6: Option<String>,
+    },
+    ServerDisk {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        disk_jobs: Vec<DiskJobStats>,
+        dirty_files: u64,
+    },
+    ServerDns {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        dns: DnsStats,
+    },
+    ServerNetwork {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        network_stopped: bool,
+    },
 
     TorrentStatus {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
         error: Option<String>,
+        error_kind: Option<ErrorKind>,
         status: Status,
+        state: TorrentState,
     },
     TorrentTransfer {
         id: String,
@@ -103,7 +159,11 @@ pub enum SResourceUpdate<'a> {
         rate_down: u64,
         transferred_up: u64,
         transferred_down: u64,
+        overhead_up: u64,
+        overhead_down: u64,
         progress: f32,
+        wanted_size: Option<u64>,
+        eta: Option<u64>,
     },
     TorrentPeers {
         id: String,
@@ -112,11 +172,59 @@ pub enum SResourceUpdate<'a> {
         peers: u16,
         availability: f32,
     },
+    TorrentPeerSources {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        peer_sources: Vec<PeerSourceStats>,
+    },
+    TorrentCountries {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        countries: Vec<CountryStats>,
+    },
+    TorrentSwarm {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
+        completed: Option<u32>,
+    },
+    TorrentNetwork {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        dht: bool,
+        pex: bool,
+    },
+    TorrentDht {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        last_dht_announce: Option<DateTime<Utc>>,
+    },
+    TorrentDisk {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        disk_read: u64,
+        disk_written: u64,
+        disk_time: u64,
+    },
+    TorrentHashFailures {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        hash_failures: Vec<HashFailure>,
+    },
     TorrentPicker {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
         strategy: Strategy,
+        first_last: bool,
     },
     TorrentPriority {
         id: String,
@@ -136,13 +244,22 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         piece_field: String,
     },
+    TorrentPieceAvailability {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        piece_availability: String,
+    },
 
     TrackerStatus {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
         last_report: DateTime<Utc>,
+        next_report: Option<DateTime<Utc>>,
         error: Option<String>,
+        warning: Option<String>,
+        peers: u32,
     },
 
     FilePriority {
@@ -177,6 +294,51 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         availability: f32,
     },
+    PeerTransfer {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        transferred_up: u64,
+        transferred_down: u64,
+        overhead_up: u64,
+        overhead_down: u64,
+    },
+
+    PeerClient {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        client_name: Option<String>,
+        client_version: Option<String>,
+    },
+
+    ServerLabels {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        labels: Vec<Label>,
+    },
+    TorrentLabel {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        label: Option<String>,
+        seed_ratio: Option<f32>,
+        seed_time: Option<u64>,
+    },
+
+    ServerThrottleGroups {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        throttle_groups: Vec<ThrottleGroup>,
+    },
+    TorrentThrottleGroup {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        throttle_group: Option<String>,
+    },
 }
 
 /// Collection of mutable fields that clients
@@ -188,15 +350,64 @@ pub struct CResourceUpdate {
     pub path: Option<String>,
     pub priority: Option<u8>,
     pub strategy: Option<Strategy>,
+    pub first_last: Option<bool>,
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_up: Option<Option<i64>>,
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_down: Option<Option<i64>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    #[serde(default)]
+    pub label: Option<Option<String>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    #[serde(default)]
+    pub seed_ratio: Option<Option<f32>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    #[serde(default)]
+    pub seed_time: Option<Option<u64>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    #[serde(default)]
+    pub throttle_group: Option<Option<String>>,
+    pub dht: Option<bool>,
+    pub pex: Option<bool>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    #[serde(default)]
+    pub name: Option<Option<String>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    #[serde(default)]
+    pub comment: Option<Option<String>>,
+    #[serde(deserialize_with = "deserialize_nullable")]
+    #[serde(default)]
+    pub notes: Option<Option<String>>,
     pub user_data: Option<json::Value>,
 }
 
+/// A named set of policies which torrents assigned to it inherit.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Label {
+    pub name: String,
+    /// Download directory of new torrents
+    pub path: Option<String>,
+    pub throttle_up: Option<i64>,
+    pub throttle_down: Option<i64>,
+    /// Upload ratio after which torrents are paused
+    pub seed_ratio: Option<f32>,
+    /// Seconds spent seeding after which torrents are paused
+    pub seed_time: Option<u64>,
+}
+
+/// Rate limits shared by all torrents in the group, on top of
+/// their own.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleGroup {
+    pub name: String,
+    pub throttle_up: Option<i64>,
+    pub throttle_down: Option<i64>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Server {
@@ -210,8 +421,37 @@ pub struct Server {
     pub transferred_down: u64,
     pub ses_transferred_up: u64,
     pub ses_transferred_down: u64,
+    /// Bytes of the session transfer which were protocol overhead rather
+    /// than piece data
+    pub ses_overhead_up: u64,
+    pub ses_overhead_down: u64,
+    /// Lifetime upload ratio
+    pub ratio: f32,
+    /// Seconds the server has run for, across restarts
+    pub uptime: u64,
     pub free_space: u64,
     pub started: DateTime<Utc>,
+    /// Time the session was last saved to disk
+    pub last_snapshot: Option<DateTime<Utc>>,
+    /// Peer addresses dropped before being connected to
+    pub filtered_peers: PeerFilterStats,
+    /// Buffers allocated for piece data
+    pub buffers: BufferStats,
+    /// Port peers connect to
+    pub port: u16,
+    /// Whether a client stopped all network activity
+    pub network_stopped: bool,
+    /// Our addresses as seen from the internet, if known
+    pub external_ipv4: Option<String>,
+    pub external_ipv6: Option<String>,
+    /// Disk use this session by kind of job
+    pub disk_jobs: Vec<DiskJobStats>,
+    /// Files written to which haven't been synced to disk yet
+    pub dirty_files: u64,
+    /// Tracker DNS lookups this session
+    pub dns: DnsStats,
+    pub labels: Vec<Label>,
+    pub throttle_groups: Vec<ThrottleGroup>,
     pub user_data: json::Value,
 }
 
@@ -233,6 +473,10 @@ impl Server {
                 transferred_down,
                 ses_transferred_up,
                 ses_transferred_down,
+                ses_overhead_up,
+                ses_overhead_down,
+                ratio,
+                uptime,
                 ..
             } => {
                 self.rate_up = rate_up;
@@ -241,6 +485,10 @@ impl Server {
                 self.transferred_down = transferred_down;
                 self.ses_transferred_up = ses_transferred_up;
                 self.ses_transferred_down = ses_transferred_down;
+                self.ses_overhead_up = ses_overhead_up;
+                self.ses_overhead_down = ses_overhead_down;
+                self.ratio = ratio;
+                self.uptime = uptime;
             }
             SResourceUpdate::ServerToken { download_token, .. } => {
                 self.download_token = download_token;
@@ -248,6 +496,50 @@ impl Server {
             SResourceUpdate::ServerSpace { free_space, .. } => {
                 self.free_space = free_space;
             }
+            SResourceUpdate::ServerSnapshot { last_snapshot, .. } => {
+                self.last_snapshot = last_snapshot;
+            }
+            SResourceUpdate::ServerFilteredPeers { filtered_peers, .. } => {
+                self.filtered_peers = filtered_peers;
+            }
+            SResourceUpdate::ServerBuffers { buffers, .. } => {
+                self.buffers = buffers;
+            }
+            SResourceUpdate::ServerPort { port, .. } => {
+                self.port = port;
+            }
+            SResourceUpdate::ServerExternalIp {
+                external_ipv4,
+                external_ipv6,
+                ..
+            } => {
+                self.external_ipv4 = external_ipv4;
+                self.external_ipv6 = external_ipv6;
+            }
+            SResourceUpdate::ServerDisk {
+                disk_jobs,
+                dirty_files,
+                ..
+            } => {
+                self.disk_jobs = disk_jobs;
+                self.dirty_files = dirty_files;
+            }
+            SResourceUpdate::ServerDns { dns, .. } => {
+                self.dns = dns;
+            }
+            SResourceUpdate::ServerNetwork {
+                network_stopped, ..
+            } => {
+                self.network_stopped = network_stopped;
+            }
+            SResourceUpdate::ServerLabels { labels, .. } => {
+                self.labels = labels;
+            }
+            SResourceUpdate::ServerThrottleGroups {
+                throttle_groups, ..
+            } => {
+                self.throttle_groups = throttle_groups;
+            }
             SResourceUpdate::Rate {
                 rate_up, rate_down, ..
             } => {
@@ -266,30 +558,77 @@ pub struct Torrent {
     pub name: Option<String>,
     pub creator: Option<String>,
     pub comment: Option<String>,
+    /// Notes left by users
+    pub notes: Option<String>,
     pub private: bool,
     pub path: String,
     pub created: DateTime<Utc>,
     pub modified: DateTime<Utc>,
     pub status: Status,
+    /// Lifecycle state, which unlike the status doesn't depend on rates
+    pub state: TorrentState,
     pub error: Option<String>,
+    /// Cause of the error, which is cleared once resolved
+    pub error_kind: Option<ErrorKind>,
     pub priority: u8,
+    /// Fraction of the files which aren't skipped that is downloaded
     pub progress: f32,
+    /// Seconds until the files which aren't skipped are downloaded at the
+    /// smoothed download rate, if they're being downloaded
+    pub eta: Option<u64>,
     pub availability: f32,
     pub strategy: Strategy,
+    /// Whether the first and last pieces of each file are downloaded first
+    pub first_last: bool,
     pub rate_up: u64,
     pub rate_down: u64,
     pub throttle_up: Option<i64>,
     pub throttle_down: Option<i64>,
     pub transferred_up: u64,
     pub transferred_down: u64,
+    /// Bytes of protocol overhead sent and received this session, on top
+    /// of the piece data transferred
+    pub overhead_up: u64,
+    pub overhead_down: u64,
     pub peers: u16,
+    /// Connected peers and data transferred by where peers were found
+    pub peer_sources: Vec<PeerSourceStats>,
+    /// Connected peers and data uploaded by the countries of peers, if a
+    /// GeoIP database is configured
+    pub countries: Vec<CountryStats>,
     pub trackers: u8,
     pub tracker_urls: Vec<String>,
     pub size: Option<u64>,
+    /// Size of the files which aren't skipped
+    pub wanted_size: Option<u64>,
     pub pieces: Option<u64>,
     pub piece_size: Option<u32>,
     pub piece_field: String,
+    /// Number of connected peers with each piece, a byte per piece
+    pub piece_availability: String,
     pub files: Option<u32>,
+    pub label: Option<String>,
+    pub seed_ratio: Option<f32>,
+    pub seed_time: Option<u64>,
+    /// Group whose rate limits the torrent shares
+    pub throttle_group: Option<String>,
+    /// Swarm size as last reported by a tracker, if any has
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    /// Number of times the torrent was downloaded in full
+    pub completed: Option<u32>,
+    /// Bytes read from and written to disk this session
+    pub disk_read: u64,
+    pub disk_written: u64,
+    /// Milliseconds spent on disk jobs this session
+    pub disk_time: u64,
+    /// Latest pieces which failed validation this session
+    pub hash_failures: Vec<HashFailure>,
+    /// Whether peers are looked up in the DHT and exchanged with PEX
+    pub dht: bool,
+    pub pex: bool,
+    /// When the torrent was last announced to the DHT this session
+    pub last_dht_announce: Option<DateTime<Utc>>,
     pub user_data: json::Value,
 }
 
@@ -305,23 +644,77 @@ impl Torrent {
                 self.throttle_up = throttle_up;
                 self.throttle_down = throttle_down;
             }
-            SResourceUpdate::TorrentStatus { error, status, .. } => {
+            SResourceUpdate::TorrentStatus {
+                error,
+                error_kind,
+                status,
+                state,
+                ..
+            } => {
                 self.error = error;
+                self.error_kind = error_kind;
                 self.status = status;
+                self.state = state;
             }
             SResourceUpdate::TorrentTransfer {
                 rate_up,
                 rate_down,
                 transferred_up,
                 transferred_down,
+                overhead_up,
+                overhead_down,
                 progress,
+                wanted_size,
+                eta,
                 ..
             } => {
                 self.rate_up = rate_up;
                 self.rate_down = rate_down;
                 self.transferred_up = transferred_up;
                 self.transferred_down = transferred_down;
+                self.overhead_up = overhead_up;
+                self.overhead_down = overhead_down;
                 self.progress = progress;
+                self.wanted_size = wanted_size;
+                self.eta = eta;
+            }
+            SResourceUpdate::TorrentPeerSources { peer_sources, .. } => {
+                self.peer_sources = peer_sources;
+            }
+            SResourceUpdate::TorrentCountries { countries, .. } => {
+                self.countries = countries;
+            }
+            SResourceUpdate::TorrentSwarm {
+                seeders,
+                leechers,
+                completed,
+                ..
+            } => {
+                self.seeders = seeders;
+                self.leechers = leechers;
+                self.completed = completed;
+            }
+            SResourceUpdate::TorrentNetwork { dht, pex, .. } => {
+                self.dht = dht;
+                self.pex = pex;
+            }
+            SResourceUpdate::TorrentDht {
+                last_dht_announce, ..
+            } => {
+                self.last_dht_announce = last_dht_announce;
+            }
+            SResourceUpdate::TorrentDisk {
+                disk_read,
+                disk_written,
+                disk_time,
+                ..
+            } => {
+                self.disk_read = disk_read;
+                self.disk_written = disk_written;
+                self.disk_time = disk_time;
+            }
+            SResourceUpdate::TorrentHashFailures { hash_failures, .. } => {
+                self.hash_failures = hash_failures;
             }
             SResourceUpdate::TorrentPeers {
                 peers,
@@ -331,15 +724,41 @@ impl Torrent {
                 self.peers = peers;
                 self.availability = availability;
             }
-            SResourceUpdate::TorrentPicker { strategy, .. } => {
+            SResourceUpdate::TorrentPicker {
+                strategy,
+                first_last,
+                ..
+            } => {
                 self.strategy = strategy;
+                self.first_last = first_last;
             }
             SResourceUpdate::TorrentPriority { priority, .. } => {
                 self.priority = priority;
             }
+            SResourceUpdate::TorrentPath { path, .. } => {
+                self.path = path;
+            }
             SResourceUpdate::TorrentPieces { piece_field, .. } => {
                 self.piece_field = piece_field;
             }
+            SResourceUpdate::TorrentPieceAvailability {
+                piece_availability, ..
+            } => {
+                self.piece_availability = piece_availability;
+            }
+            SResourceUpdate::TorrentLabel {
+                label,
+                seed_ratio,
+                seed_time,
+                ..
+            } => {
+                self.label = label;
+                self.seed_ratio = seed_ratio;
+                self.seed_time = seed_time;
+            }
+            SResourceUpdate::TorrentThrottleGroup { throttle_group, .. } => {
+                self.throttle_group = throttle_group;
+            }
             SResourceUpdate::Resource(Cow::Borrowed(Resource::Torrent(t))) => *self = t.clone(),
             SResourceUpdate::Resource(Cow::Owned(Resource::Torrent(mut t))) => {
                 mem::swap(self, &mut t)
@@ -364,14 +783,69 @@ pub enum Status {
     Seeding,
     Hashing,
     Error,
+    /// Downloading, but nothing has been received for a while
+    Stalled,
+}
+
+/// Where a torrent is in its lifecycle. A torrent moving or being checked
+/// is in that state whether or not it's paused, then an error stopping it
+/// takes precedence over it being paused, and a running torrent is queued
+/// until it gets a download slot.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentState {
+    /// Waiting for its data to be validated
+    CheckingQueued,
+    Checking,
+    /// A magnet fetching its metadata from peers
+    DownloadingMetadata,
+    Downloading,
+    Seeding,
+    Paused,
+    /// Waiting for a download slot
+    #[default]
+    Queued,
+    Error,
+    /// Data is being moved to another directory
+    Moving,
 }
 
+/// Cause of a torrent's error.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    DiskFull,
+    PermissionDenied,
+    MissingFile,
+    /// Any other disk error
+    Io,
+    HashFailure,
+    TrackerFailure,
+    /// Another torrent has a file at the same path
+    FileCollision,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::DiskFull => "disk_full",
+            ErrorKind::PermissionDenied => "permission_denied",
+            ErrorKind::MissingFile => "missing_file",
+            ErrorKind::Io => "io",
+            ErrorKind::HashFailure => "hash_failure",
+            ErrorKind::TrackerFailure => "tracker_failure",
+            ErrorKind::FileCollision => "file_collision",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
 #[serde(deny_unknown_fields)]
 pub enum Strategy {
     Rarest,
     Sequential,
+    RandomFirst,
 }
 
 impl Strategy {
@@ -379,10 +853,145 @@ impl Strategy {
         match self {
             &Strategy::Rarest => "rarest",
             &Strategy::Sequential => "sequential",
+            &Strategy::RandomFirst => "random_first",
+        }
+    }
+}
+
+/// Where the address of a peer was found.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSource {
+    #[default]
+    Tracker,
+    Dht,
+    Pex,
+    /// The peer connected to us
+    Incoming,
+    /// Added over RPC
+    Manual,
+}
+
+impl PeerSource {
+    pub const ALL: [PeerSource; 5] = [
+        PeerSource::Tracker,
+        PeerSource::Dht,
+        PeerSource::Pex,
+        PeerSource::Incoming,
+        PeerSource::Manual,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PeerSource::Tracker => "tracker",
+            PeerSource::Dht => "dht",
+            PeerSource::Pex => "pex",
+            PeerSource::Incoming => "incoming",
+            PeerSource::Manual => "manual",
         }
     }
 }
 
+/// Number of peer addresses dropped for each reason.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PeerFilterStats {
+    /// Reserved addresses
+    pub bogon: u64,
+    /// Our own addresses
+    pub own: u64,
+    /// Multicast and broadcast addresses
+    pub multicast: u64,
+    /// Addresses with port 0
+    pub port: u64,
+}
+
+/// Buffers allocated for piece data, in use or kept for reuse.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BufferStats {
+    /// Block buffers in use
+    pub blocks_used: u64,
+    /// Block buffers kept for reuse
+    pub blocks_free: u64,
+    /// Piece buffers in use
+    pub pieces_used: u64,
+    /// Piece buffers kept for reuse
+    pub pieces_free: u64,
+    /// Bytes of piece buffers in use
+    pub piece_bytes_used: u64,
+    /// Bytes of piece buffers kept for reuse
+    pub piece_bytes_free: u64,
+    /// Buffers which couldn't be allocated
+    pub alloc_failures: u64,
+}
+
+/// Disk use of a kind of job.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DiskJobStats {
+    pub kind: String,
+    pub read: u64,
+    pub written: u64,
+    /// Milliseconds spent running jobs
+    pub time: u64,
+    /// Number of time slices jobs ran for
+    pub runs: u64,
+}
+
+/// Lookups of the tracker DNS resolver and its cache.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DnsStats {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Lookups which queried a DNS server
+    pub misses: u64,
+    /// Hosts currently cached
+    pub cached: u64,
+}
+
+/// A piece which failed validation, and the peers its blocks came from.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HashFailure {
+    pub piece: u32,
+    pub time: DateTime<Utc>,
+    pub blocks: Vec<BlockSource>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BlockSource {
+    /// Offset of the block in the piece
+    pub offset: u32,
+    /// ID of the peer resource, which may no longer exist
+    pub peer: String,
+    pub ip: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PeerSourceStats {
+    pub source: PeerSource,
+    /// Currently connected peers
+    pub peers: u16,
+    /// Data transferred with peers from this source in the current session
+    pub transferred_up: u64,
+    pub transferred_down: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CountryStats {
+    /// ISO 3166 country code
+    pub country: String,
+    /// Currently connected peers
+    pub peers: u16,
+    /// Data uploaded to peers in the country in the current session
+    pub transferred_up: u64,
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Piece {
@@ -441,9 +1050,21 @@ pub struct Peer {
     pub id: String,
     pub torrent_id: String,
     pub client_id: String,
+    /// Client identified from the peer ID
+    pub client_name: Option<String>,
+    pub client_version: Option<String>,
     pub ip: String,
+    /// ISO 3166 code of the peer's country, if a GeoIP database is
+    /// configured and has the address
+    pub country: Option<String>,
+    pub source: PeerSource,
     pub rate_up: u64,
     pub rate_down: u64,
+    /// Piece data and protocol overhead transferred with the peer
+    pub transferred_up: u64,
+    pub transferred_down: u64,
+    pub overhead_up: u64,
+    pub overhead_down: u64,
     pub availability: f32,
     pub user_data: json::Value,
 }
@@ -460,6 +1081,26 @@ impl Peer {
             SResourceUpdate::PeerAvailability { availability, .. } => {
                 self.availability = availability;
             }
+            SResourceUpdate::PeerTransfer {
+                transferred_up,
+                transferred_down,
+                overhead_up,
+                overhead_down,
+                ..
+            } => {
+                self.transferred_up = transferred_up;
+                self.transferred_down = transferred_down;
+                self.overhead_up = overhead_up;
+                self.overhead_down = overhead_down;
+            }
+            SResourceUpdate::PeerClient {
+                client_name,
+                client_version,
+                ..
+            } => {
+                self.client_name = client_name;
+                self.client_version = client_version;
+            }
             _ => {}
         }
     }
@@ -473,7 +1114,13 @@ pub struct Tracker {
     #[serde(with = "url_serde")]
     pub url: Url,
     pub last_report: DateTime<Utc>,
+    /// Time of the next scheduled announce, if any
+    pub next_report: Option<DateTime<Utc>>,
     pub error: Option<String>,
+    /// Warning sent with the last successful announce
+    pub warning: Option<String>,
+    /// Peers returned by the last successful announce
+    pub peers: u32,
     pub user_data: json::Value,
 }
 
@@ -481,10 +1128,18 @@ impl Tracker {
     pub fn update(&mut self, update: SResourceUpdate<'_>) {
         match update {
             SResourceUpdate::TrackerStatus {
-                last_report, error, ..
+                last_report,
+                next_report,
+                error,
+                warning,
+                peers,
+                ..
             } => {
                 self.last_report = last_report;
+                self.next_report = next_report;
                 self.error = error;
+                self.warning = warning;
+                self.peers = peers;
             }
             _ => {}
         }
@@ -501,17 +1156,39 @@ impl<'a> SResourceUpdate<'a> {
             | &SResourceUpdate::ServerTransfer { ref id, .. }
             | &SResourceUpdate::ServerToken { ref id, .. }
             | &SResourceUpdate::ServerSpace { ref id, .. }
+            | &SResourceUpdate::ServerSnapshot { ref id, .. }
+            | &SResourceUpdate::ServerFilteredPeers { ref id, .. }
+            | &SResourceUpdate::ServerBuffers { ref id, .. }
+            | &SResourceUpdate::ServerPort { ref id, .. }
+            | &SResourceUpdate::ServerExternalIp { ref id, .. }
+            | &SResourceUpdate::ServerDisk { ref id, .. }
+            | &SResourceUpdate::ServerDns { ref id, .. }
+            | &SResourceUpdate::ServerNetwork { ref id, .. }
+            | &SResourceUpdate::ServerLabels { ref id, .. }
+            | &SResourceUpdate::ServerThrottleGroups { ref id, .. }
             | &SResourceUpdate::TorrentStatus { ref id, .. }
             | &SResourceUpdate::TorrentTransfer { ref id, .. }
             | &SResourceUpdate::TorrentPeers { ref id, .. }
+            | &SResourceUpdate::TorrentPeerSources { ref id, .. }
+            | &SResourceUpdate::TorrentCountries { ref id, .. }
+            | &SResourceUpdate::TorrentSwarm { ref id, .. }
+            | &SResourceUpdate::TorrentNetwork { ref id, .. }
+            | &SResourceUpdate::TorrentDht { ref id, .. }
+            | &SResourceUpdate::TorrentDisk { ref id, .. }
+            | &SResourceUpdate::TorrentHashFailures { ref id, .. }
             | &SResourceUpdate::TorrentPicker { ref id, .. }
             | &SResourceUpdate::TorrentPriority { ref id, .. }
             | &SResourceUpdate::TorrentPath { ref id, .. }
             | &SResourceUpdate::TorrentPieces { ref id, .. }
+            | &SResourceUpdate::TorrentPieceAvailability { ref id, .. }
+            | &SResourceUpdate::TorrentLabel { ref id, .. }
+            | &SResourceUpdate::TorrentThrottleGroup { ref id, .. }
             | &SResourceUpdate::FilePriority { ref id, .. }
             | &SResourceUpdate::FileProgress { ref id, .. }
             | &SResourceUpdate::TrackerStatus { ref id, .. }
             | &SResourceUpdate::PeerAvailability { ref id, .. }
+            | &SResourceUpdate::PeerTransfer { ref id, .. }
+            | &SResourceUpdate::PeerClient { ref id, .. }
             | &SResourceUpdate::PieceAvailable { ref id, .. }
             | &SResourceUpdate::PieceDownloaded { ref id, .. } => id,
         }
@@ -679,6 +1356,14 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  session download: {} B", t.ses_transferred_down)?;
                 write!(f, "\n")?;
+                write!(f, "  session overhead up: {} B", t.ses_overhead_up)?;
+                write!(f, "\n")?;
+                write!(f, "  session overhead down: {} B", t.ses_overhead_down)?;
+                write!(f, "\n")?;
+                write!(f, "  ratio: {:.3}", t.ratio)?;
+                write!(f, "\n")?;
+                write!(f, "  uptime: {} s", t.uptime)?;
+                write!(f, "\n")?;
                 write!(f, "  started at: {}", t.started)?;
                 write!(f, "\n")?;
                 write!(f, "}}")?;
@@ -706,6 +1391,8 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  status: {}", t.status.as_str())?;
                 write!(f, "\n")?;
+                write!(f, "  state: {}", t.state.as_str())?;
+                write!(f, "\n")?;
                 if let Some(ref e) = t.error {
                     write!(f, "  error: {}", e)?;
                     write!(f, "\n")?;
@@ -714,10 +1401,16 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  progress: {}", t.progress)?;
                 write!(f, "\n")?;
+                if let Some(eta) = t.eta {
+                    write!(f, "  eta: {} s", eta)?;
+                    write!(f, "\n")?;
+                }
                 write!(f, "  availability: {}", t.availability)?;
                 write!(f, "\n")?;
                 write!(f, "  strategy: {:?}", t.strategy)?;
                 write!(f, "\n")?;
+                write!(f, "  first/last pieces first: {}", t.first_last)?;
+                write!(f, "\n")?;
                 write!(f, "  upload: {} B/s", t.rate_up)?;
                 write!(f, "\n")?;
                 write!(f, "  download: {} B/s", t.rate_down)?;
@@ -750,6 +1443,10 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  downloaded: {} B", t.transferred_down)?;
                 write!(f, "\n")?;
+                write!(f, "  overhead up: {} B", t.overhead_up)?;
+                write!(f, "\n")?;
+                write!(f, "  overhead down: {} B", t.overhead_down)?;
+                write!(f, "\n")?;
                 write!(f, "  peers: {}", t.peers)?;
                 write!(f, "\n")?;
                 write!(f, "  trackers: {}", t.trackers)?;
@@ -760,6 +1457,10 @@ impl fmt::Display for Resource {
                     write!(f, "  size: Unknown (magnet)")?;
                 }
                 write!(f, "\n")?;
+                if let Some(s) = t.wanted_size.filter(|&w| Some(w) != t.size) {
+                    write!(f, "  wanted size: {} B", s)?;
+                    write!(f, "\n")?;
+                }
                 if let Some(p) = t.pieces {
                     write!(f, "  pieces: {}", p)?;
                 } else {
@@ -797,6 +1498,14 @@ impl fmt::Display for Resource {
     }
 }
 
+fn deserialize_nullable<'de, D, T>(de: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    serde::Deserialize::deserialize(de).map(Some)
+}
+
 fn deserialize_throttle<'de, D>(de: D) -> Result<Option<Option<i64>>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -860,9 +1569,16 @@ impl Queryable for Server {
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
             "ses_transferred_up" => Some(Field::N(self.ses_transferred_up as i64)),
             "ses_transferred_down" => Some(Field::N(self.ses_transferred_down as i64)),
+            "ses_overhead_up" => Some(Field::N(self.ses_overhead_up as i64)),
+            "ses_overhead_down" => Some(Field::N(self.ses_overhead_down as i64)),
+            "ratio" => Some(Field::F(self.ratio)),
+            "uptime" => Some(Field::N(self.uptime as i64)),
             "free_space" => Some(Field::N(self.free_space as i64)),
+            "network_stopped" => Some(Field::B(self.network_stopped)),
+            "dirty_files" => Some(Field::N(self.dirty_files as i64)),
 
             "started" => Some(Field::D(self.started)),
+            "last_snapshot" => Some(self.last_snapshot.map(Field::D).unwrap_or(FNULL)),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -894,14 +1610,26 @@ impl Queryable for Torrent {
                     .map(|v| Field::S(v.as_str()))
                     .unwrap_or(FNULL),
             ),
+            "notes" => Some(
+                self.notes
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
             "path" => Some(Field::S(&self.path)),
             "status" => Some(Field::S(self.status.as_str())),
+            "state" => Some(Field::S(self.state.as_str())),
             "error" => Some(
                 self.error
                     .as_ref()
                     .map(|v| Field::S(v.as_str()))
                     .unwrap_or(FNULL),
             ),
+            "error_kind" => Some(
+                self.error_kind
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
 
             "priority" => Some(Field::N(self.priority as i64)),
             "rate_up" => Some(Field::N(self.rate_up as i64)),
@@ -910,12 +1638,19 @@ impl Queryable for Torrent {
             "throttle_down" => Some(self.throttle_down.map(|v| Field::N(v)).unwrap_or(FNULL)),
             "transferred_up" => Some(Field::N(self.transferred_up as i64)),
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
+            "overhead_up" => Some(Field::N(self.overhead_up as i64)),
+            "overhead_down" => Some(Field::N(self.overhead_down as i64)),
             "peers" => Some(Field::N(self.peers as i64)),
             "trackers" => Some(Field::N(self.trackers as i64)),
             "tracker_urls" => Some(Field::V(
                 self.tracker_urls.iter().map(|url| Field::S(url)).collect(),
             )),
             "size" => Some(self.size.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+            "wanted_size" => Some(
+                self.wanted_size
+                    .map(|v| Field::N(v as i64))
+                    .unwrap_or(FNULL),
+            ),
             "pieces" => Some(self.pieces.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "piece_size" => Some(self.piece_size.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "files" => Some(self.files.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
@@ -924,9 +1659,34 @@ impl Queryable for Torrent {
             "modified" => Some(Field::D(self.modified)),
 
             "progress" => Some(Field::F(self.progress)),
+            "eta" => Some(self.eta.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "availability" => Some(Field::F(self.availability)),
 
             "strategy" => Some(Field::S(self.strategy.as_str())),
+            "first_last" => Some(Field::B(self.first_last)),
+            "label" => Some(
+                self.label
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "seed_ratio" => Some(self.seed_ratio.map(Field::F).unwrap_or(FNULL)),
+            "seed_time" => Some(self.seed_time.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+            "throttle_group" => Some(
+                self.throttle_group
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "seeders" => Some(self.seeders.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+            "leechers" => Some(self.leechers.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+            "completed" => Some(self.completed.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+            "disk_read" => Some(Field::N(self.disk_read as i64)),
+            "disk_written" => Some(Field::N(self.disk_written as i64)),
+            "disk_time" => Some(Field::N(self.disk_time as i64)),
+            "dht" => Some(Field::B(self.dht)),
+            "pex" => Some(Field::B(self.pex)),
+            "last_dht_announce" => Some(self.last_dht_announce.map(Field::D).unwrap_or(FNULL)),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -979,13 +1739,36 @@ impl Queryable for Peer {
             "id" => Some(Field::S(&self.id)),
             "torrent_id" => Some(Field::S(&self.torrent_id)),
             "ip" => Some(Field::S(&self.ip)),
+            "country" => Some(
+                self.country
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "source" => Some(Field::S(self.source.as_str())),
 
             "rate_up" => Some(Field::N(self.rate_up as i64)),
             "rate_down" => Some(Field::N(self.rate_down as i64)),
+            "transferred_up" => Some(Field::N(self.transferred_up as i64)),
+            "transferred_down" => Some(Field::N(self.transferred_down as i64)),
+            "overhead_up" => Some(Field::N(self.overhead_up as i64)),
+            "overhead_down" => Some(Field::N(self.overhead_down as i64)),
 
             "availability" => Some(Field::F(self.availability)),
 
             "client_id" => Some(Field::S(&self.client_id)),
+            "client_name" => Some(
+                self.client_name
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "client_version" => Some(
+                self.client_version
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -1008,6 +1791,14 @@ impl Queryable for Tracker {
             ),
 
             "last_report" => Some(Field::D(self.last_report)),
+            "next_report" => Some(self.next_report.map(Field::D).unwrap_or(FNULL)),
+            "warning" => Some(
+                self.warning
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "peers" => Some(Field::N(i64::from(self.peers))),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -1027,6 +1818,23 @@ impl Status {
             Status::Hashing => "hashing",
             Status::Magnet => "magnet",
             Status::Error => "error",
+            Status::Stalled => "stalled",
+        }
+    }
+}
+
+impl TorrentState {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TorrentState::CheckingQueued => "checking_queued",
+            TorrentState::Checking => "checking",
+            TorrentState::DownloadingMetadata => "downloading_metadata",
+            TorrentState::Downloading => "downloading",
+            TorrentState::Seeding => "seeding",
+            TorrentState::Paused => "paused",
+            TorrentState::Queued => "queued",
+            TorrentState::Error => "error",
+            TorrentState::Moving => "moving",
         }
     }
 }
@@ -1069,9 +1877,25 @@ impl Default for Server {
             transferred_down: 0,
             ses_transferred_up: 0,
             ses_transferred_down: 0,
+            ses_overhead_up: 0,
+            ses_overhead_down: 0,
+            ratio: 0.,
+            uptime: 0,
             free_space: 0,
             download_token: "".to_owned(),
             started: Utc::now(),
+            last_snapshot: None,
+            filtered_peers: PeerFilterStats::default(),
+            buffers: BufferStats::default(),
+            port: 0,
+            network_stopped: false,
+            external_ipv4: None,
+            external_ipv6: None,
+            disk_jobs: vec![],
+            dirty_files: 0,
+            dns: DnsStats::default(),
+            labels: vec![],
+            throttle_groups: vec![],
             user_data: json::Value::Null,
         }
     }
@@ -1083,31 +1907,56 @@ impl Default for Torrent {
             id: "".to_owned(),
             name: None,
             comment: None,
+            notes: None,
             creator: None,
             private: false,
             path: "".to_owned(),
             created: Utc::now(),
             modified: Utc::now(),
             status: Default::default(),
+            state: Default::default(),
             error: None,
+            error_kind: None,
             priority: 0,
             progress: 0.,
+            eta: None,
             availability: 0.,
             strategy: Strategy::Rarest,
+            first_last: false,
             rate_up: 0,
             rate_down: 0,
             throttle_up: None,
             throttle_down: None,
             transferred_up: 0,
             transferred_down: 0,
+            overhead_up: 0,
+            overhead_down: 0,
             peers: 0,
             trackers: 0,
+            peer_sources: vec![],
+            countries: vec![],
             tracker_urls: vec![],
             size: None,
+            wanted_size: None,
             pieces: None,
             piece_size: None,
             piece_field: "".to_owned(),
+            piece_availability: "".to_owned(),
             files: None,
+            label: None,
+            seed_ratio: None,
+            seed_time: None,
+            throttle_group: None,
+            seeders: None,
+            leechers: None,
+            completed: None,
+            disk_read: 0,
+            disk_written: 0,
+            disk_time: 0,
+            hash_failures: vec![],
+            dht: true,
+            pex: true,
+            last_dht_announce: None,
             user_data: json::Value::Null,
         }
     }
@@ -1120,7 +1969,10 @@ impl Default for Tracker {
             torrent_id: "".to_owned(),
             url: Url::parse("http://my.tracker/announce").unwrap(),
             last_report: Utc::now(),
+            next_report: None,
             error: None,
+            warning: None,
+            peers: 0,
             user_data: json::Value::Null,
         }
     }
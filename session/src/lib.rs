@@ -3,7 +3,7 @@ extern crate serde_derive;
 
 pub mod torrent {
     pub use self::current::Session;
-    pub use self::ver_fa1b6f as current;
+    pub use self::ver_a41d7c as current;
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Bitfield {
@@ -12,8 +12,26 @@ pub mod torrent {
     }
 
     pub fn load(data: &[u8]) -> Option<Session> {
-        if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+        if let Ok(m) = bincode::deserialize::<ver_a41d7c::Session>(data) {
             Some(m)
+        } else if let Ok(m) = bincode::deserialize::<ver_2b84f1::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_9e62d4::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_41c7b9::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_d35a0e::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_7c21f8::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_e4a90c::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_b72e05::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_3c9d2e::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+            Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_6e27af::Session>(data) {
             Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_249b1b::Session>(data) {
@@ -25,21 +43,580 @@ pub mod torrent {
         } else {
             None
         }
-    }
+    }
+
+    impl Session {
+        pub fn migrate(self) -> Self {
+            self
+        }
+    }
+
+    pub mod ver_a41d7c {
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub seed_only: bool,
+            pub label: Option<String>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+            /// Seconds spent seeding
+            pub seeded: u64,
+            /// Whether peers are looked up in the DHT
+            pub dht: bool,
+            /// Whether peers are exchanged with PEX
+            pub pex: bool,
+            /// Name shown in place of the torrent's own
+            pub name: Option<String>,
+            /// Comment shown in place of the torrent's own
+            pub comment: Option<String>,
+            /// Notes left by users
+            pub notes: Option<String>,
+            /// Group whose rate limits the torrent shares
+            pub throttle_group: Option<String>,
+            /// Whether the torrent is stopped once its metadata is fetched
+            pub metadata_only: bool,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+            pub attr: Option<String>,
+            pub symlink: Option<PathBuf>,
+        }
+
+        #[derive(Clone, Debug, Serialize, Deserialize)]
+        pub struct Status {
+            pub paused: bool,
+            pub validating: bool,
+            pub error: Option<String>,
+            pub state: StatusState,
+        }
+
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum StatusState {
+            Magnet,
+            // Torrent has not acquired all pieces
+            Incomplete,
+            // Torrent has acquired all pieces, regardless of validity
+            Complete,
+        }
+    }
+
+    pub mod ver_2b84f1 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_a41d7c as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub seed_only: bool,
+            pub label: Option<String>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+            pub seeded: u64,
+            pub dht: bool,
+            pub pex: bool,
+            pub name: Option<String>,
+            pub comment: Option<String>,
+            pub notes: Option<String>,
+            pub throttle_group: Option<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: self.seed_only,
+                    label: self.label,
+                    seed_ratio: self.seed_ratio,
+                    seed_time: self.seed_time,
+                    seeded: self.seeded,
+                    dht: self.dht,
+                    pex: self.pex,
+                    name: self.name,
+                    comment: self.comment,
+                    notes: self.notes,
+                    throttle_group: self.throttle_group,
+                    metadata_only: false,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_9e62d4 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_2b84f1 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub seed_only: bool,
+            pub label: Option<String>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+            pub seeded: u64,
+            pub dht: bool,
+            pub pex: bool,
+            pub name: Option<String>,
+            pub comment: Option<String>,
+            pub notes: Option<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: self.seed_only,
+                    label: self.label,
+                    seed_ratio: self.seed_ratio,
+                    seed_time: self.seed_time,
+                    seeded: self.seeded,
+                    dht: self.dht,
+                    pex: self.pex,
+                    name: self.name,
+                    comment: self.comment,
+                    notes: self.notes,
+                    throttle_group: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_41c7b9 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_9e62d4 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub seed_only: bool,
+            pub label: Option<String>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+            pub seeded: u64,
+            pub dht: bool,
+            pub pex: bool,
+            pub name: Option<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: self.seed_only,
+                    label: self.label,
+                    seed_ratio: self.seed_ratio,
+                    seed_time: self.seed_time,
+                    seeded: self.seeded,
+                    dht: self.dht,
+                    pex: self.pex,
+                    name: self.name,
+                    comment: None,
+                    notes: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_d35a0e {
+        pub use self::next::{Status, StatusState};
+        pub use super::ver_41c7b9 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub seed_only: bool,
+            pub label: Option<String>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+            pub seeded: u64,
+            pub dht: bool,
+            pub pex: bool,
+            pub name: Option<String>,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+            pub attr: Option<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: next::Info {
+                        name: self.info.name,
+                        announce: self.info.announce,
+                        creator: self.info.creator,
+                        comment: self.info.comment,
+                        piece_len: self.info.piece_len,
+                        total_len: self.info.total_len,
+                        hashes: self.info.hashes,
+                        hash: self.info.hash,
+                        files: self
+                            .info
+                            .files
+                            .into_iter()
+                            .map(|f| next::File {
+                                path: f.path,
+                                length: f.length,
+                                attr: f.attr,
+                                symlink: None,
+                            })
+                            .collect(),
+                        private: self.info.private,
+                        be_name: self.info.be_name,
+                        piece_idx: self.info.piece_idx,
+                    },
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: self.seed_only,
+                    label: self.label,
+                    seed_ratio: self.seed_ratio,
+                    seed_time: self.seed_time,
+                    seeded: self.seeded,
+                    dht: self.dht,
+                    pex: self.pex,
+                    name: self.name,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_7c21f8 {
+        pub use self::next::{Status, StatusState};
+        pub use super::ver_d35a0e as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub seed_only: bool,
+            pub label: Option<String>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+            pub seeded: u64,
+            pub dht: bool,
+            pub pex: bool,
+            pub name: Option<String>,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: next::Info {
+                        name: self.info.name,
+                        announce: self.info.announce,
+                        creator: self.info.creator,
+                        comment: self.info.comment,
+                        piece_len: self.info.piece_len,
+                        total_len: self.info.total_len,
+                        hashes: self.info.hashes,
+                        hash: self.info.hash,
+                        files: self
+                            .info
+                            .files
+                            .into_iter()
+                            .map(|f| next::File {
+                                path: f.path,
+                                length: f.length,
+                                attr: None,
+                            })
+                            .collect(),
+                        private: self.info.private,
+                        be_name: self.info.be_name,
+                        piece_idx: self.info.piece_idx,
+                    },
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: self.seed_only,
+                    label: self.label,
+                    seed_ratio: self.seed_ratio,
+                    seed_time: self.seed_time,
+                    seeded: self.seeded,
+                    dht: self.dht,
+                    pex: self.pex,
+                    name: self.name,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_e4a90c {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_7c21f8 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub seed_only: bool,
+            pub label: Option<String>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+            pub seeded: u64,
+            pub dht: bool,
+            pub pex: bool,
+        }
 
-    impl Session {
-        pub fn migrate(self) -> Self {
-            self
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: self.seed_only,
+                    label: self.label,
+                    seed_ratio: self.seed_ratio,
+                    seed_time: self.seed_time,
+                    seeded: self.seeded,
+                    dht: self.dht,
+                    pex: self.pex,
+                    name: None,
+                }
+                .migrate()
+            }
         }
     }
 
-    pub mod ver_fa1b6f {
+    pub mod ver_b72e05 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_e4a90c as next;
+
         use super::Bitfield;
 
         use chrono::{DateTime, Utc};
 
-        use std::path::PathBuf;
-
         #[derive(Serialize, Deserialize)]
         pub struct Session {
             pub info: Info,
@@ -54,45 +631,135 @@ pub mod torrent {
             pub throttle_ul: Option<i64>,
             pub throttle_dl: Option<i64>,
             pub trackers: Vec<String>,
+            pub seed_only: bool,
+            pub label: Option<String>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+            pub seeded: u64,
         }
 
-        #[derive(Clone, Serialize, Deserialize)]
-        pub struct Info {
-            pub name: String,
-            pub announce: Option<String>,
-            pub creator: Option<String>,
-            pub comment: Option<String>,
-            pub piece_len: u32,
-            pub total_len: u64,
-            pub hashes: Vec<Vec<u8>>,
-            pub hash: [u8; 20],
-            pub files: Vec<File>,
-            pub private: bool,
-            pub be_name: Option<Vec<u8>>,
-            pub piece_idx: Vec<(usize, u64)>,
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: self.seed_only,
+                    label: self.label,
+                    seed_ratio: self.seed_ratio,
+                    seed_time: self.seed_time,
+                    seeded: self.seeded,
+                    dht: true,
+                    pex: true,
+                }
+                .migrate()
+            }
         }
+    }
 
-        #[derive(Serialize, Deserialize, Clone, Debug)]
-        pub struct File {
-            pub path: PathBuf,
-            pub length: u64,
+    pub mod ver_3c9d2e {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_b72e05 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub seed_only: bool,
         }
 
-        #[derive(Clone, Debug, Serialize, Deserialize)]
-        pub struct Status {
-            pub paused: bool,
-            pub validating: bool,
-            pub error: Option<String>,
-            pub state: StatusState,
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: self.seed_only,
+                    label: None,
+                    seed_ratio: None,
+                    seed_time: None,
+                    seeded: 0,
+                }
+                .migrate()
+            }
         }
+    }
 
-        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-        pub enum StatusState {
-            Magnet,
-            // Torrent has not acquired all pieces
-            Incomplete,
-            // Torrent has acquired all pieces, regardless of validity
-            Complete,
+    pub mod ver_fa1b6f {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_3c9d2e as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    seed_only: false,
+                }
+                .migrate()
+            }
         }
     }
 
@@ -388,3 +1055,161 @@ pub mod torrent {
         }
     }
 }
+
+pub mod labels {
+    pub use self::current::Label;
+    pub use self::ver_5d0a93 as current;
+
+    pub fn load(data: &[u8]) -> Option<Vec<Label>> {
+        bincode::deserialize::<Vec<ver_5d0a93::Label>>(data).ok()
+    }
+
+    pub mod ver_5d0a93 {
+        #[derive(Serialize, Deserialize, Clone)]
+        pub struct Label {
+            pub name: String,
+            pub path: Option<String>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub seed_ratio: Option<f32>,
+            pub seed_time: Option<u64>,
+        }
+    }
+}
+
+pub mod throttle_groups {
+    pub use self::current::ThrottleGroup;
+    pub use self::ver_c3e95a as current;
+
+    pub fn load(data: &[u8]) -> Option<Vec<ThrottleGroup>> {
+        bincode::deserialize::<Vec<ver_c3e95a::ThrottleGroup>>(data).ok()
+    }
+
+    pub mod ver_c3e95a {
+        #[derive(Serialize, Deserialize, Clone)]
+        pub struct ThrottleGroup {
+            pub name: String,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+        }
+    }
+}
+
+pub mod server {
+    pub use self::current::Session;
+    pub use self::ver_5e19b2 as current;
+
+    pub fn load(data: &[u8]) -> Option<Session> {
+        if let Ok(m) = bincode::deserialize::<ver_5e19b2::Session>(data) {
+            Some(m)
+        } else if let Ok(m) = bincode::deserialize::<ver_c81f4b::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_0a72d3::Session>(data) {
+            Some(m.migrate())
+        } else {
+            None
+        }
+    }
+
+    impl Session {
+        pub fn migrate(self) -> Self {
+            self
+        }
+    }
+
+    pub mod ver_5e19b2 {
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub id: String,
+            pub ul: u64,
+            pub dl: u64,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Seconds the server has been running for, across all sessions
+            pub uptime: u64,
+            /// Whether a client stopped all network activity
+            pub network_stopped: bool,
+        }
+    }
+
+    pub mod ver_c81f4b {
+        pub use super::ver_5e19b2 as next;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub id: String,
+            pub ul: u64,
+            pub dl: u64,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub uptime: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    id: self.id,
+                    ul: self.ul,
+                    dl: self.dl,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    uptime: self.uptime,
+                    network_stopped: false,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_0a72d3 {
+        pub use super::ver_c81f4b as next;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub id: String,
+            pub ul: u64,
+            pub dl: u64,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    id: self.id,
+                    ul: self.ul,
+                    dl: self.dl,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    uptime: 0,
+                }
+                .migrate()
+            }
+        }
+    }
+}
+
+pub mod history {
+    pub use self::current::{Sample, Session};
+    pub use self::ver_7b3e10 as current;
+
+    pub fn load(data: &[u8]) -> Option<Session> {
+        bincode::deserialize::<ver_7b3e10::Session>(data).ok()
+    }
+
+    pub mod ver_7b3e10 {
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub minutes: Vec<Sample>,
+            pub hours: Vec<Sample>,
+            pub days: Vec<Sample>,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Sample {
+            pub time: i64,
+            pub ul: u64,
+            pub dl: u64,
+        }
+    }
+}
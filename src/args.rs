@@ -8,6 +8,20 @@ use crate::log;
 pub struct Args {
     pub config: Option<String>,
     pub level: Option<log::LogLevel>,
+    pub dump_config: bool,
+    pub add: Option<Add>,
+}
+
+/// A torrent to add on startup, for one-off downloads.
+pub struct Add {
+    /// Torrent file or magnet URI
+    pub torrent: String,
+    pub path: Option<String>,
+    pub seed_ratio: Option<f32>,
+    /// Exit once the torrent is downloaded and seeded
+    pub foreground: bool,
+    /// Seconds to wait for the metadata of a magnet in the foreground
+    pub magnet_timeout: Option<u64>,
 }
 
 pub fn args() -> Args {
@@ -16,6 +30,36 @@ pub fn args() -> Args {
     opts.optflag("h", "help", "Show help message.");
     opts.optflag("d", "debug", "Enable debug logging.");
     opts.optopt("c", "config", "Use config file.", "FILE");
+    opts.optflag("", "dump-config", "Print the effective config and exit.");
+    opts.optopt(
+        "",
+        "add",
+        "Add a torrent file or magnet on startup.",
+        "TORRENT",
+    );
+    opts.optopt(
+        "",
+        "download-dir",
+        "Download the added torrent to a directory.",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "seed-until-ratio",
+        "Stop seeding the added torrent at a ratio.",
+        "RATIO",
+    );
+    opts.optflag(
+        "",
+        "foreground",
+        "Exit once the added torrent is downloaded and seeded.",
+    );
+    opts.optopt(
+        "",
+        "magnet-timeout",
+        "Exit if the added magnet's metadata isn't fetched in time.",
+        "SECS",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -28,9 +72,50 @@ pub fn args() -> Args {
         usage(0, opts);
     }
 
+    let seed_ratio = match matches
+        .opt_str("seed-until-ratio")
+        .map(|r| r.parse::<f32>())
+    {
+        Some(Ok(r)) if r >= 0. => Some(r),
+        Some(_) => {
+            println!("The seed ratio must be a non negative number");
+            usage(1, opts);
+        }
+        None => None,
+    };
+    let magnet_timeout = match matches.opt_str("magnet-timeout").map(|t| t.parse::<u64>()) {
+        Some(Ok(t)) => Some(t),
+        Some(Err(_)) => {
+            println!("The magnet timeout must be a number of seconds");
+            usage(1, opts);
+        }
+        None => None,
+    };
+    let add = matches.opt_str("add").map(|torrent| Add {
+        torrent,
+        path: matches.opt_str("download-dir"),
+        seed_ratio,
+        foreground: matches.opt_present("foreground"),
+        magnet_timeout,
+    });
+    if add.is_none()
+        && ["download-dir", "seed-until-ratio", "foreground"]
+            .iter()
+            .any(|o| matches.opt_present(o))
+    {
+        println!("--download-dir, --seed-until-ratio and --foreground require --add");
+        usage(1, opts);
+    }
+    if magnet_timeout.is_some() && !matches.opt_present("foreground") {
+        println!("--magnet-timeout requires --foreground");
+        usage(1, opts);
+    }
+
     let mut args = Args {
         config: None,
         level: None,
+        dump_config: matches.opt_present("dump-config"),
+        add,
     };
 
     if matches.opt_present("d") {
@@ -1,26 +1,159 @@
+use std::convert::TryInto;
+use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic;
+use std::sync::Mutex;
 
 use crate::protocol;
+use crate::rpc::resource::BufferStats;
 
+/// Maximum number of block buffers in use at once
 const MAX_BUFS: usize = 4096;
 pub const BUF_SIZE: usize = 16_384;
-static BUF_COUNT: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+/// Size classes of piece buffers, as powers of two from 32 KiB to 64 MiB
+const MIN_PIECE_SHIFT: u32 = 15;
+const PIECE_CLASSES: usize = 12;
+/// Bytes of piece buffers in use at once beyond which no more are handed
+/// out, unless none are in use
+const MAX_PIECE_BYTES: usize = 256 << 20;
 
-#[derive(Clone)]
+lazy_static! {
+    static ref POOL: Mutex<Pool> = Mutex::new(Pool::new());
+}
+
+/// Buffers which were freed, kept to be reused. Each size class remembers
+/// the most buffers it had in use since it was last shrunk, and buffers
+/// beyond that are released when it is shrunk.
+struct Pool {
+    blocks: Class,
+    pieces: [Class; PIECE_CLASSES],
+    /// Piece buffers too large for any class, which aren't kept
+    unpooled: usize,
+    unpooled_bytes: usize,
+    alloc_failures: u64,
+}
+
+#[derive(Default)]
+struct Class {
+    free: Vec<Vec<u8>>,
+    used: usize,
+    high: usize,
+}
+
+/// A block sized buffer, used for blocks read from and sent to peers.
+/// A thin pointer, so it takes little room in messages.
 pub struct Buffer {
-    data: Box<[u8; BUF_SIZE]>,
+    data: ManuallyDrop<Box<[u8; BUF_SIZE]>>,
+}
+
+/// A buffer holding a whole piece, used to hash pieces. Pieces larger
+/// than the largest size class get a buffer outside of the pool.
+pub struct PieceBuf {
+    data: Vec<u8>,
+    class: Option<usize>,
+}
+
+/// Why a piece buffer couldn't be handed out.
+#[derive(Debug, PartialEq)]
+pub enum PieceErr {
+    /// Too many piece bytes are in use, until some buffers are dropped
+    Busy,
+    /// The buffer couldn't be allocated
+    Alloc,
+}
+
+impl Pool {
+    fn new() -> Pool {
+        Pool {
+            blocks: Class::default(),
+            pieces: Default::default(),
+            unpooled: 0,
+            unpooled_bytes: 0,
+            alloc_failures: 0,
+        }
+    }
+
+    fn take(&mut self, class: Option<usize>, size: usize) -> Option<Vec<u8>> {
+        let c = match class {
+            Some(i) => &mut self.pieces[i],
+            None => &mut self.blocks,
+        };
+        let buf = match c.free.pop() {
+            Some(buf) => buf,
+            None => {
+                let mut buf = Vec::new();
+                if buf.try_reserve_exact(size).is_err() {
+                    self.alloc_failures += 1;
+                    return None;
+                }
+                buf.resize(size, 0);
+                buf
+            }
+        };
+        c.used += 1;
+        c.high = c.high.max(c.used);
+        Some(buf)
+    }
+
+    fn piece_bytes_used(&self) -> usize {
+        self.pieces
+            .iter()
+            .enumerate()
+            .map(|(i, c)| c.used << (i as u32 + MIN_PIECE_SHIFT))
+            .sum::<usize>()
+            + self.unpooled_bytes
+    }
+
+    fn put(&mut self, class: Option<usize>, buf: Vec<u8>) {
+        let c = match class {
+            Some(i) => &mut self.pieces[i],
+            None => &mut self.blocks,
+        };
+        c.used -= 1;
+        c.free.push(buf);
+    }
+}
+
+impl Class {
+    fn shrink(&mut self) {
+        let keep = self.high - self.used;
+        self.free.truncate(keep);
+        self.free.shrink_to_fit();
+        self.high = self.used;
+    }
 }
 
 impl Buffer {
     pub fn get() -> Option<Buffer> {
-        if BUF_COUNT.load(atomic::Ordering::Acquire) >= MAX_BUFS && !cfg!(test) {
+        let mut pool = POOL.lock().unwrap();
+        if pool.blocks.used >= MAX_BUFS && !cfg!(test) {
             return None;
         }
-        BUF_COUNT.fetch_add(1, atomic::Ordering::AcqRel);
-        Some(Buffer {
-            data: Box::new([0; BUF_SIZE]),
-        })
+        pool.take(None, BUF_SIZE).map(Buffer::new)
+    }
+
+    /// Copies the buffer, failing if a buffer can't be allocated. Copies
+    /// aren't held back by the limit, as the original already was.
+    pub fn try_clone(&self) -> Option<Buffer> {
+        let data = POOL.lock().unwrap().take(None, BUF_SIZE)?;
+        let mut buf = Buffer::new(data);
+        buf.copy_from_slice(&self[..]);
+        Some(buf)
+    }
+
+    fn new(data: Vec<u8>) -> Buffer {
+        let data = data.into_boxed_slice().try_into().unwrap();
+        Buffer {
+            data: ManuallyDrop::new(data),
+        }
+    }
+}
+
+impl Clone for Buffer {
+    /// Needed as protocol messages are cloneable. Peers never copy their
+    /// blocks, so this only panics where messages are copied, e.g. in
+    /// tests. Use `try_clone` where an allocation failure must be handled.
+    fn clone(&self) -> Buffer {
+        self.try_clone().expect("Failed to allocate buffer")
     }
 }
 
@@ -28,13 +161,13 @@ impl Deref for Buffer {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &*self.data
+        &self.data[..]
     }
 }
 
 impl DerefMut for Buffer {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut *self.data
+        &mut self.data[..]
     }
 }
 
@@ -42,6 +175,156 @@ impl protocol::Buffer for Buffer {}
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        BUF_COUNT.fetch_sub(1, atomic::Ordering::AcqRel);
+        // data isn't used again after being taken
+        let data = unsafe { ManuallyDrop::take(&mut self.data) };
+        POOL.lock()
+            .unwrap()
+            .put(None, (data as Box<[u8]>).into_vec());
+    }
+}
+
+impl PieceBuf {
+    /// A buffer of len bytes.
+    pub fn get(len: usize) -> Result<PieceBuf, PieceErr> {
+        let shift = len
+            .next_power_of_two()
+            .trailing_zeros()
+            .max(MIN_PIECE_SHIFT);
+        let class = (shift - MIN_PIECE_SHIFT) as usize;
+        let size = if class < PIECE_CLASSES {
+            1 << shift
+        } else {
+            len
+        };
+        let mut pool = POOL.lock().unwrap();
+        let used = pool.piece_bytes_used();
+        if used > 0 && used + size > MAX_PIECE_BYTES {
+            return Err(PieceErr::Busy);
+        }
+        if class >= PIECE_CLASSES {
+            let mut data = Vec::new();
+            if data.try_reserve_exact(len).is_err() {
+                pool.alloc_failures += 1;
+                return Err(PieceErr::Alloc);
+            }
+            data.resize(len, 0);
+            pool.unpooled += 1;
+            pool.unpooled_bytes += len;
+            return Ok(PieceBuf { data, class: None });
+        }
+        let mut data = pool.take(Some(class), size).ok_or(PieceErr::Alloc)?;
+        data.truncate(len);
+        Ok(PieceBuf {
+            data,
+            class: Some(class),
+        })
+    }
+}
+
+impl Deref for PieceBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for PieceBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+impl Drop for PieceBuf {
+    fn drop(&mut self) {
+        let mut pool = POOL.lock().unwrap();
+        match self.class {
+            Some(class) => {
+                let mut data = std::mem::take(&mut self.data);
+                data.resize(1 << (class as u32 + MIN_PIECE_SHIFT), 0);
+                pool.put(Some(class), data);
+            }
+            None => {
+                pool.unpooled -= 1;
+                pool.unpooled_bytes -= self.data.len();
+            }
+        }
+    }
+}
+
+/// Releases free buffers beyond the most used since the last call.
+pub fn shrink() {
+    let mut pool = POOL.lock().unwrap();
+    pool.blocks.shrink();
+    for c in &mut pool.pieces {
+        c.shrink();
+    }
+}
+
+pub fn stats() -> BufferStats {
+    let pool = POOL.lock().unwrap();
+    let mut stats = BufferStats {
+        blocks_used: pool.blocks.used as u64,
+        blocks_free: pool.blocks.free.len() as u64,
+        pieces_used: pool.unpooled as u64,
+        piece_bytes_used: pool.unpooled_bytes as u64,
+        alloc_failures: pool.alloc_failures,
+        ..Default::default()
+    };
+    for (i, c) in pool.pieces.iter().enumerate() {
+        let size = 1u64 << (i as u32 + MIN_PIECE_SHIFT);
+        stats.pieces_used += c.used as u64;
+        stats.pieces_free += c.free.len() as u64;
+        stats.piece_bytes_used += c.used as u64 * size;
+        stats.piece_bytes_free += c.free.len() as u64 * size;
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shrink() {
+        let mut pool = Pool::new();
+        let bufs: Vec<_> = (0..4).map(|_| pool.take(None, 8).unwrap()).collect();
+        for buf in bufs {
+            pool.put(None, buf);
+        }
+        let buf = pool.take(None, 8).unwrap();
+        assert_eq!(pool.blocks.free.len(), 3);
+        // Only buffers which went unused since the last shrink are released
+        pool.blocks.shrink();
+        assert_eq!(pool.blocks.free.len(), 3);
+        pool.blocks.shrink();
+        assert_eq!(pool.blocks.free.len(), 0);
+        pool.put(None, buf);
+        assert_eq!(pool.blocks.free.len(), 1);
+    }
+
+    #[test]
+    fn test_try_clone() {
+        let mut buf = Buffer::get().unwrap();
+        buf[0] = 7;
+        let copy = buf.try_clone().unwrap();
+        assert_eq!(&copy[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_piece_buf() {
+        let mut buf = PieceBuf::get(40_000).unwrap();
+        assert_eq!(buf.len(), 40_000);
+        assert_eq!(buf.class, Some(1));
+        buf[39_999] = 1;
+        assert_eq!(PieceBuf::get(1).unwrap().class, Some(0));
+        // Pieces beyond the largest class get a buffer of their own
+        let buf = PieceBuf::get((64 << 20) + 1).unwrap();
+        assert_eq!(buf.len(), (64 << 20) + 1);
+        assert_eq!(buf.class, None);
+        assert!(stats().piece_bytes_used > 64 << 20);
+        drop(buf);
+        assert_eq!(POOL.lock().unwrap().unpooled, 0);
+        assert!(PieceBuf::get(usize::MAX >> 1).is_err());
     }
 }
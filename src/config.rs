@@ -1,10 +1,18 @@
 use ip_network::IpNetwork;
 use std::collections::HashMap;
-use std::io::Read;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::{fs, process};
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{PoisonError, RwLockReadGuard};
+use std::{fs, process, result};
 
-use crate::args;
+use rand::Rng;
+use serde_json::Value;
+use url::Url;
+
+use crate::rpc::proto::message::EventKind;
+use crate::{args, SETTINGS};
 
 error_chain! {
     errors {
@@ -13,14 +21,24 @@ error_chain! {
                 display("bad env var")
         }
 
-        IO {
+        IO(path: String) {
             description("IO failed")
-                display("IO failed")
+                display("failed to read {}", path)
+        }
+
+        NotFound(path: String) {
+            description("config file not found")
+                display("config file {} does not exist", path)
         }
 
-        Format {
+        Format(path: String) {
             description("invalid config format")
-                display("invalid config format")
+                display("invalid config file {}", path)
+        }
+
+        Invalid(r: String) {
+            description("invalid config value")
+                display("invalid config value: {}", r)
         }
     }
 }
@@ -28,6 +46,8 @@ error_chain! {
 #[derive(Debug, Clone)]
 pub struct Config {
     pub port: u16,
+    pub port_range: Option<(u16, u16)>,
+    pub random_port: bool,
     pub max_dl: u32,
     pub trk: TrkConfig,
     pub dht: DhtConfig,
@@ -35,6 +55,7 @@ pub struct Config {
     pub disk: DiskConfig,
     pub net: NetConfig,
     pub peer: PeerConfig,
+    pub notify: NotifyConfig,
     pub ip_filter: HashMap<IpNetwork, u8>,
 }
 
@@ -42,12 +63,20 @@ pub struct Config {
 pub struct DhtConfig {
     pub port: u16,
     pub bootstrap_node: Option<SocketAddr>,
+    pub bootstrap_node6: Option<SocketAddr>,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigFile {
     #[serde(default = "default_port")]
     pub port: u16,
+    /// Ports to try listening on in place of port, inclusive
+    #[serde(default)]
+    pub port_range: Option<(u16, u16)>,
+    /// Listen on a random port, from port_range if given
+    #[serde(default)]
+    pub random_port: bool,
     #[serde(default = "default_max_dl")]
     pub max_dl: u32,
     #[serde(default)]
@@ -62,11 +91,14 @@ pub struct ConfigFile {
     pub net: NetConfig,
     #[serde(default)]
     pub peer: PeerConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
     #[serde(default = "default_ip_filter")]
     pub ip_filter: HashMap<IpNetwork, u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RpcConfig {
     #[serde(default = "default_rpc_port")]
     pub port: u16,
@@ -80,23 +112,120 @@ pub struct RpcConfig {
     pub ssl_cert: String,
     #[serde(default = "default_ssl")]
     pub ssl_key: String,
+    #[serde(default)]
+    pub ssl_self_signed: bool,
+    #[serde(default = "default_ssl_hostnames")]
+    pub ssl_hostnames: Vec<String>,
+    #[serde(default)]
+    pub ssl_sni: HashMap<String, SniConfig>,
+    #[serde(default)]
+    pub listen: Option<IpAddr>,
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    /// Serve a Transmission compatible RPC endpoint
+    #[serde(default)]
+    pub transmission: bool,
+    /// Serve a qBittorrent compatible WebUI API
+    #[serde(default)]
+    pub qbittorrent: bool,
+    /// Origins of other sites allowed to use the JSON API from a browser
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    /// Port to serve the gRPC interface on, when built with it
+    #[serde(default)]
+    pub grpc_port: Option<u16>,
+    /// Whether to accept RPC connections over TCP
+    #[serde(default = "default_tcp")]
+    pub tcp: bool,
+    /// Path of a unix socket to accept RPC connections on, which are
+    /// trusted without authentication
+    #[serde(default)]
+    pub socket: Option<String>,
+    /// Permissions of the unix socket
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: u32,
+    /// Number of events kept in the event log
+    #[serde(default = "default_max_events")]
+    pub max_events: usize,
+    /// Seconds events are kept for, 0 to keep them until max_events
+    /// newer ones are logged
+    #[serde(default = "default_event_age")]
+    pub event_age: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub token: String,
+    #[serde(default = "default_scope")]
+    pub scope: Scope,
+    /// Name torrents added with this token are attributed to
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Only show torrents attributed to user
+    #[serde(default)]
+    pub restricted: bool,
+}
+
+/// Permission level of an RPC token, in increasing order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// May only view resources
+    Read,
+    /// May view resources and add torrents
+    Add,
+    /// May perform any action
+    Admin,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct TrkConfig {
     #[serde(default = "default_trk_port")]
     pub port: u16,
+    /// Seconds between scrapes of each torrent's tracker, 0 to never scrape
+    #[serde(default = "default_scrape_interval")]
+    pub scrape_interval: u64,
+    /// Peer ID prefix identifying the client, for trackers which only
+    /// allow certain clients
+    #[serde(default)]
+    pub peer_id_prefix: Option<String>,
+    /// User-Agent sent to HTTP trackers
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Key sent with announces
+    #[serde(default)]
+    pub key: Option<u32>,
+    /// Seconds a downloading torrent may receive nothing before it's
+    /// marked stalled and announced again, 0 to never
+    #[serde(default = "default_stall_timeout")]
+    pub stall_timeout: u64,
+    /// HTTP proxy HTTP trackers are contacted through, as host:port
+    #[serde(default)]
+    pub proxy: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DhtConfigFile {
     #[serde(default = "default_dht_port")]
     pub port: u16,
     #[serde(default = "default_bootstrap_node")]
     pub bootstrap_node: Option<String>,
+    #[serde(default = "default_bootstrap_node")]
+    pub bootstrap_node6: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DiskConfig {
     #[serde(default = "default_session_dir")]
     pub session: String,
@@ -104,9 +233,78 @@ pub struct DiskConfig {
     pub directory: String,
     #[serde(default = "default_validate")]
     pub validate: bool,
+    /// Threads used to hash pieces
+    #[serde(default = "default_hash_threads")]
+    pub hash_threads: usize,
+    /// Directories torrents added without a path are placed in
+    #[serde(default)]
+    pub profiles: Vec<ProfileConfig>,
+    /// Rate full validations read data at in MiB/s
+    #[serde(default)]
+    pub validate_rate: Option<u64>,
+    /// Local hours full validations are deferred to, from the first
+    /// until the second, wrapping past midnight
+    #[serde(default)]
+    pub validate_hours: Option<(u32, u32)>,
+    /// When written data is synced to disk
+    #[serde(default)]
+    pub fsync: Fsync,
+    /// Seconds between syncs of written data, for the periodic policy
+    #[serde(default = "default_fsync_interval")]
+    pub fsync_interval: u64,
+}
+
+/// Candidate download directories of torrents added without a path or
+/// a label with a path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub name: String,
+    pub directories: Vec<String>,
+    #[serde(default)]
+    pub policy: Placement,
+    /// Labels of the torrents placed with this profile. Profiles without
+    /// labels are used for other torrents.
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Bytes which must remain free in a directory after placing a torrent
+    #[serde(default)]
+    pub reserve: u64,
+}
+
+/// How a profile's directory is chosen among those with enough space.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+    /// The directory with the most free space
+    #[default]
+    MostFree,
+    /// Each directory in turn
+    RoundRobin,
+    /// The directory holding other torrents of the same label, or else
+    /// the one with the most free space
+    Label,
+}
+
+/// When files written to are synced to disk, trading throughput for
+/// how much data may be lost in a crash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fsync {
+    /// Never, leaving it to the OS and closing files
+    Never,
+    /// When a block only partly covering a file is written, which mostly
+    /// happens as a file is finished
+    #[default]
+    File,
+    /// Once a piece is completed and validated
+    Piece,
+    /// Every `fsync_interval` seconds
+    Periodic,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct NetConfig {
     #[serde(default = "default_max_files")]
     pub max_open_files: usize,
@@ -114,98 +312,637 @@ pub struct NetConfig {
     pub max_open_sockets: usize,
     #[serde(default = "default_max_announces")]
     pub max_open_announces: usize,
+    /// Upload rate limit, replacing the one set over RPC on load
+    #[serde(default)]
+    pub throttle_up: Option<i64>,
+    /// Download rate limit, replacing the one set over RPC on load
+    #[serde(default)]
+    pub throttle_down: Option<i64>,
+    /// Send uploaded blocks which are in the page cache straight from it
+    /// where supported
+    #[serde(default = "default_sendfile")]
+    pub sendfile: bool,
+    /// Local address the peer listener and outgoing peer, tracker and DHT
+    /// sockets are bound to
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+    /// Interface the peer listener and outgoing peer, tracker and DHT
+    /// sockets are bound to
+    #[serde(default)]
+    pub bind_interface: Option<String>,
+    /// STUN server queried for our external address, as host:port
+    #[serde(default)]
+    pub stun_server: Option<String>,
+    /// DNS server trackers, the STUN server and webhooks are resolved
+    /// with, in place of those in /etc/resolv.conf
+    #[serde(default)]
+    pub dns_server: Option<SocketAddr>,
+    /// DSCP peer sockets mark their traffic with
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// Raw IP TOS byte of peer sockets, if no DSCP is set
+    #[serde(default)]
+    pub tos: Option<u8>,
+    /// Firewall mark of peer sockets, for policy routing
+    #[serde(default)]
+    pub mark: Option<u32>,
+    /// Send buffer size of peer sockets in bytes
+    #[serde(default)]
+    pub send_buffer: Option<usize>,
+    /// Receive buffer size of peer sockets in bytes
+    #[serde(default)]
+    pub recv_buffer: Option<usize>,
+    /// TCP congestion control algorithm of peer sockets
+    #[serde(default)]
+    pub congestion: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PeerConfig {
     #[serde(default = "default_prune_timeout")]
     pub prune_timeout: u64,
+    /// Maximum number of peers connected to a single torrent
+    #[serde(default = "default_max_peers")]
+    pub max_peers: usize,
+    /// Maximum number of outgoing connections still handshaking
+    #[serde(default = "default_max_half_open")]
+    pub max_half_open: usize,
+    /// Maximum number of incoming connections still handshaking
+    #[serde(default = "default_max_half_open_incoming")]
+    pub max_half_open_incoming: usize,
+    /// Incoming connections accepted from an address per minute
+    #[serde(default = "default_accept_rate")]
+    pub accept_rate: u32,
+    #[serde(default = "default_suppress_have")]
+    pub suppress_have: bool,
+    #[serde(default)]
+    pub lazy_bitfield: bool,
+    #[serde(default)]
+    pub client_policy: Vec<ClientPolicyConfig>,
+    /// Longest block peers may request, in bytes
+    #[serde(default = "default_max_request_len")]
+    pub max_request_len: u32,
+    /// Length of the requests made to peers, a multiple of the block size
+    #[serde(default = "default_request_size")]
+    pub request_size: u32,
+    /// MaxMind country or city database used to find the countries of peers
+    #[serde(default)]
+    pub geoip_db: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientPolicyConfig {
+    /// Client name as identified from the peer ID
+    pub client: String,
+    /// Only apply to client versions starting with this
+    #[serde(default)]
+    pub version: Option<String>,
+    pub policy: ClientPolicy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub emails: Vec<EmailConfig>,
+    /// Program emails are piped to, which reads their recipients
+    /// from the message
+    #[serde(default = "default_sendmail")]
+    pub sendmail: String,
+    /// Attempts made to deliver each notification
+    #[serde(default = "default_notify_attempts")]
+    pub attempts: u32,
+}
+
+/// A URL which events are POSTed to as JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Events to notify of, or all of them if empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Body of the request, with {{field}} replaced by the event's field.
+    /// The event itself is sent if not given.
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// An address which events are emailed to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub to: String,
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    #[serde(default = "default_email_subject")]
+    pub subject: String,
+    #[serde(default = "default_email_template")]
+    pub template: String,
+}
+
+/// Treatment of peers using a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientPolicy {
+    /// Disconnect from the peer after its handshake
+    Refuse,
+    /// Never unchoke the peer
+    NoUpload,
+}
+
+/// Locations a config file is looked for in, in order.
+const DEFAULT_FILES: [&str; 3] = [
+    "./config.toml",
+    "$XDG_CONFIG_HOME/synapse.toml",
+    "~/.config/synapse.toml",
+];
+
 impl ConfigFile {
-    pub fn try_load() -> Result<ConfigFile> {
+    /// Loads the config file, exiting if it can't be read or is invalid.
+    /// None if there is no config file.
+    pub fn try_load() -> Option<ConfigFile> {
+        match ConfigFile::load_file() {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                error!("Failed to load config, terminating: {}", describe(&e));
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Loads and validates the config file given on the command line, or
+    /// else the first one found in the default locations.
+    pub fn load_file() -> Result<Option<ConfigFile>> {
         let args = args::args();
-        let files = [
-            args.config
-                .as_ref()
-                .map(String::as_str)
-                .unwrap_or("./config.toml"),
-            "$XDG_CONFIG_HOME/synapse.toml",
-            "~/.config/synapse.toml",
-        ];
-        for file in &files {
-            let mut s = String::new();
-            let res: Result<ConfigFile> = shellexpand::full(&file)
-                .chain_err(|| ErrorKind::Env)
-                .and_then(|p| fs::File::open(&*p).chain_err(|| ErrorKind::IO))
-                .and_then(|mut f| f.read_to_string(&mut s).chain_err(|| ErrorKind::IO))
-                .and_then(|_| toml::from_str(&s).chain_err(|| ErrorKind::Format));
-            match res {
-                Ok(mut cfg) => {
-                    if cfg.max_dl == 0 {
-                        error!("Config max_dl must not be 0");
-                        process::exit(1);
-                    }
-                    if !cfg!(debug_assertions) && !cfg.disk.validate {
-                        error!("validation skipping can only be used in development, overriding!");
-                        cfg.disk.validate = true;
-                    }
-                    return Ok(cfg);
-                }
-                Err(e @ Error(ErrorKind::Format, _)) => {
-                    use std::error::Error;
-                    error!(
-                        "Failed to parse config, terminating: {}",
-                        e.source().unwrap()
-                    );
-                    process::exit(1);
+        if let Some(file) = args.config {
+            return match ConfigFile::read(&file)? {
+                Some(cfg) => Ok(Some(cfg)),
+                None => bail!(ErrorKind::NotFound(file)),
+            };
+        }
+        for file in &DEFAULT_FILES {
+            match ConfigFile::read(file) {
+                Ok(None) | Err(Error(ErrorKind::Env, _)) => {
+                    debug!("No config file at {}", file);
                 }
-                Err(e) => {
-                    debug!("Failed to load config file {}: {}", file, e);
+                res => return res,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads and validates a config file, None if it doesn't exist.
+    fn read(file: &str) -> Result<Option<ConfigFile>> {
+        let path = shellexpand::full(file).chain_err(|| ErrorKind::Env)?;
+        match fs::read_to_string(&*path) {
+            Ok(s) => ConfigFile::parse(&path, &s).map(Some),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).chain_err(|| ErrorKind::IO(path.into_owned())),
+        }
+    }
+
+    /// Parses and validates the contents of the config file at path.
+    fn parse(path: &str, s: &str) -> Result<ConfigFile> {
+        let mut cfg: ConfigFile =
+            toml::from_str(s).chain_err(|| ErrorKind::Format(path.to_owned()))?;
+        if let Err((key, reason)) = cfg.validate() {
+            let place = match line_of(s, key) {
+                Some(line) => format!("line {} of {}", line, path),
+                None => path.to_owned(),
+            };
+            bail!(ErrorKind::Invalid(format!("{} at {}", reason, place)));
+        }
+        if !cfg!(debug_assertions) && !cfg.disk.validate {
+            error!("validation skipping can only be used in development, overriding!");
+            cfg.disk.validate = true;
+        }
+        Ok(cfg)
+    }
+
+    /// Checks values which are out of range or conflict, returning the
+    /// key at fault, qualified by its table, and the reason.
+    fn validate(&self) -> result::Result<(), (&'static str, String)> {
+        let invalid = |key, reason: &str| Err((key, reason.to_owned()));
+        if self.max_dl == 0 {
+            return invalid("max_dl", "max_dl must not be 0");
+        }
+        if let Some((start, end)) = self.port_range {
+            if start == 0 || start > end {
+                return invalid("port_range", "port_range is empty");
+            }
+        }
+        if let Some((start, end)) = self.disk.validate_hours {
+            if start == end || start > 23 || end > 23 {
+                return invalid(
+                    "disk.validate_hours",
+                    "validate_hours must be two different hours from 0 to 23",
+                );
+            }
+        }
+        if self.disk.fsync == Fsync::Periodic && self.disk.fsync_interval == 0 {
+            return invalid("disk.fsync_interval", "fsync_interval must be at least 1");
+        }
+        let max_request_len = self.peer.max_request_len;
+        if !(16_384..=1 << 20).contains(&max_request_len) {
+            return invalid(
+                "peer.max_request_len",
+                "max_request_len must be from 16384 to 1048576",
+            );
+        }
+        let size = self.peer.request_size;
+        if size == 0 || !size.is_multiple_of(16_384) || size > 131_072 {
+            return invalid(
+                "peer.request_size",
+                "request_size must be a multiple of 16384 up to 131072",
+            );
+        }
+        if let Some(ref prefix) = self.tracker.peer_id_prefix {
+            if prefix.len() > 20 {
+                return invalid(
+                    "tracker.peer_id_prefix",
+                    "peer_id_prefix must be at most 20 bytes",
+                );
+            }
+        }
+        if let Some(ref proxy) = self.tracker.proxy {
+            // The default port 80 is dropped by Url, so the port is checked
+            // on the string
+            let port = proxy.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+            let valid = Url::parse(&format!("http://{}", proxy))
+                .ok()
+                .is_some_and(|url| {
+                    url.host_str().is_some()
+                        && matches!(port, Some(Ok(_)))
+                        && url.path() == "/"
+                        && url.username().is_empty()
+                        && url.query().is_none()
+                        && url.fragment().is_none()
+                });
+            if !valid {
+                let reason = format!("proxy {} is not a host:port address", proxy);
+                return Err(("tracker.proxy", reason));
+            }
+        }
+        match (self.net.dscp, self.net.tos) {
+            (Some(d), _) if d > 63 => return invalid("net.dscp", "dscp must be at most 63"),
+            (Some(_), Some(_)) => return invalid("net.tos", "only one of dscp and tos may be set"),
+            _ => {}
+        }
+        if self.net.mark.is_some() && !cfg!(target_os = "linux") {
+            return invalid("net.mark", "mark is only supported on Linux");
+        }
+        if let Some(p) = self.disk.profiles.iter().find(|p| p.directories.is_empty()) {
+            return Err((
+                "disk.profiles.directories",
+                format!("profile {} has no directories", p.name),
+            ));
+        }
+        for hook in &self.notify.webhooks {
+            match Url::parse(&hook.url) {
+                Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => {}
+                _ => {
+                    let reason = format!("webhook url {} is not an HTTP URL", hook.url);
+                    return Err(("notify.webhooks.url", reason));
                 }
             }
         }
-        bail!("Failed to find a suitable config!");
+        Ok(())
+    }
+
+    /// Expands ~ in the paths of the config.
+    fn expand_paths(&mut self) {
+        self.disk.session = shellexpand::tilde(&self.disk.session).into();
+        self.disk.directory = shellexpand::tilde(&self.disk.directory).into();
+        for profile in &mut self.disk.profiles {
+            for dir in &mut profile.directories {
+                *dir = shellexpand::tilde(dir).into();
+            }
+        }
+        self.rpc.socket = self
+            .rpc
+            .socket
+            .as_ref()
+            .map(|socket| shellexpand::tilde(socket).into());
+    }
+}
+
+/// Line number of the first assignment to a key, qualified by its table,
+/// in a config file.
+fn line_of(s: &str, key: &str) -> Option<usize> {
+    let mut table = "";
+    let pos = s.lines().position(|line| {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.starts_with('[') {
+            table = line.trim_matches(|c| c == '[' || c == ']').trim();
+            return false;
+        }
+        let name = match line.find('=') {
+            Some(i) => line[..i].trim(),
+            None => return false,
+        };
+        match key.strip_prefix(table) {
+            _ if table.is_empty() => name == key,
+            Some(rest) => rest.strip_prefix('.') == Some(name),
+            None => false,
+        }
+    })?;
+    Some(pos + 1)
+}
+
+/// Points disk.session in the config file at the directory the sessions
+/// were moved to, so they're found there on the next start. If there's
+/// no config file, one is created in the last default location.
+pub fn persist_session(session: &str) -> io::Result<()> {
+    let env_err = |_| io::Error::other("bad env var");
+    let path = match args::args().config {
+        Some(file) => shellexpand::full(&file).map_err(env_err)?.into_owned(),
+        None => {
+            let found = DEFAULT_FILES
+                .iter()
+                .filter_map(|file| shellexpand::full(file).ok())
+                .find(|path| Path::new(&**path).exists());
+            match found {
+                Some(path) => path.into_owned(),
+                None => shellexpand::full(DEFAULT_FILES[2])
+                    .map_err(env_err)?
+                    .into_owned(),
+            }
+        }
+    };
+    let s = match fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let value = toml::Value::String(session.to_owned());
+    let temp = format!("{}.temp", path);
+    fs::write(&temp, set_key(&s, "disk", "session", &value))?;
+    fs::rename(&temp, &path)
+}
+
+/// Assigns a value to a key of a table in a config file, leaving the
+/// rest of the file as it is.
+fn set_key(s: &str, table: &str, key: &str, value: &toml::Value) -> String {
+    let mut lines: Vec<String> = s.lines().map(str::to_owned).collect();
+    let header = format!("[{}]", table);
+    if let Some(line) = line_of(s, &format!("{}.{}", table, key)) {
+        // The key may be dotted, so it's kept as it was written
+        let name = lines[line - 1]
+            .split('=')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_owned();
+        lines[line - 1] = format!("{} = {}", name, value);
+    } else if let Some(i) = lines
+        .iter()
+        .position(|l| l.split('#').next().unwrap_or("").trim() == header)
+    {
+        lines.insert(i + 1, format!("{} = {}", key, value));
+    } else {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(header);
+        lines.push(format!("{} = {}", key, value));
+    }
+    lines.join("\n") + "\n"
+}
+
+/// The effective config as TOML, with defaults filled in for whatever
+/// the config file doesn't set.
+pub fn dump() -> result::Result<String, String> {
+    let mut file = ConfigFile::load_file()
+        .map_err(|e| describe(&e))?
+        .unwrap_or_default();
+    file.expand_paths();
+    let value = toml::Value::try_from(&file).map_err(|e| e.to_string())?;
+    Ok(value.to_string())
+}
+
+/// Describes a config error along with its cause.
+pub fn describe(e: &Error) -> String {
+    use std::error::Error;
+    match e.source() {
+        Some(cause) => format!("{}: {}", e, cause),
+        None => e.to_string(),
     }
 }
 
 impl Config {
+    /// Ports to try listening on for peers, in order. A port of 0 lets
+    /// the OS pick one.
+    pub fn listen_ports(&self) -> Vec<u16> {
+        match self.port_range {
+            Some((start, end)) => {
+                let mut ports: Vec<_> = (start..=end).collect();
+                if self.random_port {
+                    rand::thread_rng().shuffle(&mut ports);
+                }
+                ports
+            }
+            None if self.random_port => vec![0],
+            None => vec![self.port],
+        }
+    }
+
     pub fn load() -> Config {
-        if let Ok(cfg) = ConfigFile::try_load() {
+        if let Some(cfg) = ConfigFile::try_load() {
             info!("Loaded config file");
             Config::from_file(cfg)
         } else {
-            info!("Using default config");
+            info!("No config file found, using the default config");
             Default::default()
         }
     }
 
     pub fn from_file(mut file: ConfigFile) -> Config {
-        let addr = file
-            .dht
-            .bootstrap_node
-            .and_then(|n| n.to_socket_addrs().ok())
-            .and_then(|mut a| a.next());
+        let resolve = |node: &Option<String>| -> Vec<SocketAddr> {
+            node.as_ref()
+                .and_then(|n| n.to_socket_addrs().ok())
+                .map(|a| a.collect())
+                .unwrap_or_default()
+        };
+        let addrs = resolve(&file.dht.bootstrap_node);
+        let addrs6 = if file.dht.bootstrap_node6.is_some() {
+            resolve(&file.dht.bootstrap_node6)
+        } else {
+            addrs.clone()
+        };
         let dht = DhtConfig {
             port: file.dht.port,
-            bootstrap_node: addr,
+            bootstrap_node: addrs.into_iter().find(|a| a.is_ipv4()),
+            bootstrap_node6: addrs6.into_iter().find(|a| a.is_ipv6()),
         };
-        file.disk.session = shellexpand::tilde(&file.disk.session).into();
-        file.disk.directory = shellexpand::tilde(&file.disk.directory).into();
+        file.expand_paths();
         Config {
             port: file.port,
+            port_range: file.port_range,
+            random_port: file.random_port,
             max_dl: file.max_dl,
             trk: file.tracker,
             rpc: file.rpc,
             disk: file.disk,
             net: file.net,
             peer: file.peer,
+            notify: file.notify,
             dht,
             ip_filter: file.ip_filter,
         }
     }
 }
 
+/// Settings which may be changed while running, by reloading the config
+/// or over RPC. Everything else in the config only applies on startup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub max_dl: u32,
+    /// Directory new torrents are downloaded to
+    pub directory: String,
+    /// Directory sessions are stored in, which is only changed by moving it
+    pub session: String,
+    pub max_open_files: usize,
+    pub max_open_sockets: usize,
+    pub max_open_announces: usize,
+    pub prune_timeout: u64,
+    pub max_peers: usize,
+    pub max_half_open: usize,
+    pub suppress_have: bool,
+    pub lazy_bitfield: bool,
+}
+
+impl Settings {
+    /// Names of the settings, as keyed in the config file.
+    pub const KEYS: [&'static str; 10] = [
+        "max_dl",
+        "disk.directory",
+        "net.max_open_files",
+        "net.max_open_sockets",
+        "net.max_open_announces",
+        "peer.prune_timeout",
+        "peer.max_peers",
+        "peer.max_half_open",
+        "peer.suppress_have",
+        "peer.lazy_bitfield",
+    ];
+
+    pub fn new(config: &Config) -> Settings {
+        Settings {
+            max_dl: config.max_dl,
+            directory: config.disk.directory.clone(),
+            session: config.disk.session.clone(),
+            max_open_files: config.net.max_open_files,
+            max_open_sockets: config.net.max_open_sockets,
+            max_open_announces: config.net.max_open_announces,
+            prune_timeout: config.peer.prune_timeout,
+            max_peers: config.peer.max_peers,
+            max_half_open: config.peer.max_half_open,
+            suppress_have: config.peer.suppress_have,
+            lazy_bitfield: config.peer.lazy_bitfield,
+        }
+    }
+
+    pub fn from_file(file: &ConfigFile) -> Settings {
+        Settings {
+            max_dl: file.max_dl,
+            directory: shellexpand::tilde(&file.disk.directory).into(),
+            session: shellexpand::tilde(&file.disk.session).into(),
+            max_open_files: file.net.max_open_files,
+            max_open_sockets: file.net.max_open_sockets,
+            max_open_announces: file.net.max_open_announces,
+            prune_timeout: file.peer.prune_timeout,
+            max_peers: file.peer.max_peers,
+            max_half_open: file.peer.max_half_open,
+            suppress_have: file.peer.suppress_have,
+            lazy_bitfield: file.peer.lazy_bitfield,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        Some(match key {
+            "max_dl" => Value::from(self.max_dl),
+            "disk.directory" => Value::from(self.directory.clone()),
+            "net.max_open_files" => Value::from(self.max_open_files),
+            "net.max_open_sockets" => Value::from(self.max_open_sockets),
+            "net.max_open_announces" => Value::from(self.max_open_announces),
+            "peer.prune_timeout" => Value::from(self.prune_timeout),
+            "peer.max_peers" => Value::from(self.max_peers),
+            "peer.max_half_open" => Value::from(self.max_half_open),
+            "peer.suppress_have" => Value::from(self.suppress_have),
+            "peer.lazy_bitfield" => Value::from(self.lazy_bitfield),
+            _ => return None,
+        })
+    }
+
+    /// Sets a setting, failing if the key is unknown or the value is
+    /// not valid for it.
+    pub fn set(&mut self, key: &str, value: &Value) -> result::Result<(), String> {
+        let uint = || {
+            value
+                .as_u64()
+                .ok_or_else(|| format!("{} must be a non-negative integer", key))
+        };
+        let boolean = || {
+            value
+                .as_bool()
+                .ok_or_else(|| format!("{} must be a boolean", key))
+        };
+        match key {
+            "max_dl" => {
+                self.max_dl = match uint()? {
+                    0 => return Err("max_dl must not be 0".to_owned()),
+                    n => u32::try_from(n).map_err(|_| "max_dl is too large".to_owned())?,
+                }
+            }
+            "disk.directory" => {
+                self.directory = value
+                    .as_str()
+                    .map(|d| shellexpand::tilde(d).into())
+                    .ok_or_else(|| format!("{} must be a string", key))?
+            }
+            "net.max_open_files" => self.max_open_files = uint()? as usize,
+            "net.max_open_sockets" => self.max_open_sockets = uint()? as usize,
+            "net.max_open_announces" => self.max_open_announces = uint()? as usize,
+            "peer.prune_timeout" => self.prune_timeout = uint()?,
+            "peer.max_peers" => self.max_peers = uint()? as usize,
+            "peer.max_half_open" => self.max_half_open = uint()? as usize,
+            "peer.suppress_have" => self.suppress_have = boolean()?,
+            "peer.lazy_bitfield" => self.lazy_bitfield = boolean()?,
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Keys of the settings which differ from another's.
+    pub fn changed(&self, other: &Settings) -> Vec<&'static str> {
+        Settings::KEYS
+            .iter()
+            .filter(|key| self.get(key) != other.get(key))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Current runtime settings.
+pub fn settings() -> RwLockReadGuard<'static, Settings> {
+    SETTINGS.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Replaces the runtime settings, returning the keys of those changed.
+pub fn update_settings(settings: Settings) -> Vec<&'static str> {
+    let mut current = SETTINGS.write().unwrap_or_else(PoisonError::into_inner);
+    let changed = current.changed(&settings);
+    *current = settings;
+    changed
+}
+
 fn default_port() -> u16 {
     16_384
 }
@@ -215,6 +952,12 @@ fn default_max_dl() -> u32 {
 fn default_trk_port() -> u16 {
     16_362
 }
+fn default_stall_timeout() -> u64 {
+    10 * 60
+}
+fn default_scrape_interval() -> u64 {
+    1800
+}
 fn default_dht_port() -> u16 {
     16_309
 }
@@ -224,6 +967,30 @@ fn default_rpc_port() -> u16 {
 fn default_local() -> bool {
     true
 }
+fn default_tcp() -> bool {
+    true
+}
+fn default_socket_mode() -> u32 {
+    0o600
+}
+fn default_sendmail() -> String {
+    "sendmail".to_owned()
+}
+fn default_notify_attempts() -> u32 {
+    5
+}
+fn default_email_subject() -> String {
+    "synapse: {{kind}} {{name}}".to_owned()
+}
+fn default_email_template() -> String {
+    "{{time}} {{name}} ({{torrent_id}}): {{message}}".to_owned()
+}
+fn default_max_events() -> usize {
+    1000
+}
+fn default_event_age() -> u64 {
+    30 * 24 * 60 * 60
+}
 fn default_auth() -> bool {
     false
 }
@@ -233,13 +1000,23 @@ fn default_password() -> String {
 fn default_ssl() -> String {
     "".to_owned()
 }
+fn default_ssl_hostnames() -> Vec<String> {
+    vec![
+        "localhost".to_owned(),
+        "127.0.0.1".to_owned(),
+        "::1".to_owned(),
+    ]
+}
+fn default_scope() -> Scope {
+    Scope::Read
+}
 fn default_bootstrap_node() -> Option<String> {
     None
 }
 fn default_bootstrap_node_addr() -> Option<SocketAddr> {
     default_bootstrap_node()
-       .and_then(|n| n.to_socket_addrs().ok())
-       .and_then(|mut a| a.next())
+        .and_then(|n| n.to_socket_addrs().ok())
+        .and_then(|mut a| a.next())
 }
 fn default_session_dir() -> String {
     shellexpand::full("$XDG_DATA_HOME/synapse")
@@ -252,6 +1029,18 @@ fn default_directory_dir() -> String {
 fn default_validate() -> bool {
     true
 }
+fn default_hash_threads() -> usize {
+    2
+}
+fn default_fsync_interval() -> u64 {
+    30
+}
+fn default_sendfile() -> bool {
+    true
+}
+fn default_suppress_have() -> bool {
+    true
+}
 fn default_max_files() -> usize {
     500
 }
@@ -264,6 +1053,24 @@ fn default_max_announces() -> usize {
 fn default_prune_timeout() -> u64 {
     15
 }
+fn default_max_peers() -> usize {
+    50
+}
+fn default_max_half_open() -> usize {
+    32
+}
+fn default_max_half_open_incoming() -> usize {
+    64
+}
+fn default_accept_rate() -> u32 {
+    30
+}
+fn default_max_request_len() -> u32 {
+    131_072
+}
+fn default_request_size() -> u32 {
+    16_384
+}
 fn default_ip_filter() -> HashMap<IpNetwork, u8> {
     HashMap::from([
         (IpNetwork::from_str_truncate("0.0.0.0/0").unwrap(), 127),
@@ -275,6 +1082,8 @@ impl Default for Config {
     fn default() -> Self {
         Config {
             port: default_port(),
+            port_range: None,
+            random_port: false,
             max_dl: default_max_dl(),
             trk: Default::default(),
             rpc: Default::default(),
@@ -282,6 +1091,26 @@ impl Default for Config {
             net: Default::default(),
             dht: Default::default(),
             peer: Default::default(),
+            notify: Default::default(),
+            ip_filter: default_ip_filter(),
+        }
+    }
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        ConfigFile {
+            port: default_port(),
+            port_range: None,
+            random_port: false,
+            max_dl: default_max_dl(),
+            rpc: Default::default(),
+            tracker: Default::default(),
+            dht: Default::default(),
+            disk: Default::default(),
+            net: Default::default(),
+            peer: Default::default(),
+            notify: Default::default(),
             ip_filter: default_ip_filter(),
         }
     }
@@ -296,6 +1125,20 @@ impl Default for RpcConfig {
             password: default_password(),
             ssl_cert: default_ssl(),
             ssl_key: default_ssl(),
+            ssl_self_signed: false,
+            ssl_hostnames: default_ssl_hostnames(),
+            ssl_sni: HashMap::new(),
+            listen: None,
+            tokens: Vec::new(),
+            transmission: false,
+            qbittorrent: false,
+            cors_origins: Vec::new(),
+            grpc_port: None,
+            tcp: default_tcp(),
+            socket: None,
+            socket_mode: default_socket_mode(),
+            max_events: default_max_events(),
+            event_age: default_event_age(),
         }
     }
 }
@@ -304,6 +1147,12 @@ impl Default for TrkConfig {
     fn default() -> TrkConfig {
         TrkConfig {
             port: default_trk_port(),
+            scrape_interval: default_scrape_interval(),
+            peer_id_prefix: None,
+            user_agent: None,
+            key: None,
+            stall_timeout: default_stall_timeout(),
+            proxy: None,
         }
     }
 }
@@ -313,6 +1162,7 @@ impl Default for DhtConfigFile {
         DhtConfigFile {
             port: default_dht_port(),
             bootstrap_node: default_bootstrap_node(),
+            bootstrap_node6: default_bootstrap_node(),
         }
     }
 }
@@ -322,6 +1172,18 @@ impl Default for DhtConfig {
         DhtConfig {
             port: default_dht_port(),
             bootstrap_node: default_bootstrap_node_addr(),
+            bootstrap_node6: None,
+        }
+    }
+}
+
+impl DiskConfig {
+    /// Whether full validations may run in an hour of the day.
+    pub fn validation_hour(&self, hour: u32) -> bool {
+        match self.validate_hours {
+            Some((start, end)) if start < end => hour >= start && hour < end,
+            Some((start, end)) => hour >= start || hour < end,
+            None => true,
         }
     }
 }
@@ -332,6 +1194,12 @@ impl Default for DiskConfig {
             session: default_session_dir(),
             directory: default_directory_dir(),
             validate: default_validate(),
+            hash_threads: default_hash_threads(),
+            profiles: Vec::new(),
+            validate_rate: None,
+            validate_hours: None,
+            fsync: Fsync::default(),
+            fsync_interval: default_fsync_interval(),
         }
     }
 }
@@ -342,6 +1210,19 @@ impl Default for NetConfig {
             max_open_files: default_max_files(),
             max_open_sockets: default_max_sockets(),
             max_open_announces: default_max_announces(),
+            throttle_up: None,
+            throttle_down: None,
+            sendfile: default_sendfile(),
+            bind_address: None,
+            bind_interface: None,
+            stun_server: None,
+            dns_server: None,
+            dscp: None,
+            tos: None,
+            mark: None,
+            send_buffer: None,
+            recv_buffer: None,
+            congestion: None,
         }
     }
 }
@@ -350,6 +1231,140 @@ impl Default for PeerConfig {
     fn default() -> PeerConfig {
         PeerConfig {
             prune_timeout: default_prune_timeout(),
+            max_peers: default_max_peers(),
+            max_half_open: default_max_half_open(),
+            max_half_open_incoming: default_max_half_open_incoming(),
+            accept_rate: default_accept_rate(),
+            suppress_have: default_suppress_have(),
+            lazy_bitfield: false,
+            client_policy: Vec::new(),
+            max_request_len: default_max_request_len(),
+            request_size: default_request_size(),
+            geoip_db: None,
+        }
+    }
+}
+
+impl Default for NotifyConfig {
+    fn default() -> NotifyConfig {
+        NotifyConfig {
+            webhooks: Vec::new(),
+            emails: Vec::new(),
+            sendmail: default_sendmail(),
+            attempts: default_notify_attempts(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_listen_ports() {
+        let mut config = Config::default();
+        assert_eq!(config.listen_ports(), vec![config.port]);
+        config.random_port = true;
+        assert_eq!(config.listen_ports(), vec![0]);
+        config.port_range = Some((6881, 6889));
+        let mut ports = config.listen_ports();
+        ports.sort();
+        assert_eq!(ports, (6881..=6889).collect::<Vec<_>>());
+        config.random_port = false;
+        assert_eq!(config.listen_ports()[0], 6881);
+    }
+
+    #[test]
+    fn test_parse() {
+        let example = include_str!("../example_config.toml");
+        assert!(ConfigFile::parse("example_config.toml", example).is_ok());
+
+        let err = |s| describe(&ConfigFile::parse("a.toml", s).err().unwrap());
+        assert!(err("[peer]\nbogus = 1\n").contains("unknown field `bogus`"));
+        assert!(err("[rpc]\nport = 70000\n").ends_with("for key `rpc.port` at line 2 column 8"));
+        assert_eq!(
+            err("max_dl = 3\n\n[peer]\nrequest_size = 100\n"),
+            "invalid config value: request_size must be a multiple of 16384 up to 131072 \
+             at line 4 of a.toml"
+        );
+        assert!(err("[net]\ndscp = 1 # ok\ntos = 1\n").ends_with("line 3 of a.toml"));
+        assert!(err("peer.request_size = 100\n").ends_with("line 1 of a.toml"));
+        let profiles = "[[disk.profiles]]\nname = \"a\"\ndirectories = []\n";
+        assert!(err(profiles).ends_with("line 3 of a.toml"));
+        assert!(err("[tracker]\nproxy = \"http://a:8080\"\n").ends_with("line 2 of a.toml"));
+        assert!(err("[tracker]\nproxy = \"a\"\n").contains("not a host:port address"));
+        assert!(ConfigFile::parse("a.toml", "[tracker]\nproxy = \"[::1]:80\"\n").is_ok());
+    }
+
+    #[test]
+    fn test_set_key() {
+        let value = toml::Value::String("/new".to_owned());
+        assert_eq!(
+            set_key(
+                "[disk]\nsession = \"/old\" # here\n",
+                "disk",
+                "session",
+                &value
+            ),
+            "[disk]\nsession = \"/new\"\n"
+        );
+        assert_eq!(
+            set_key("disk.session = \"/old\"\n", "disk", "session", &value),
+            "disk.session = \"/new\"\n"
+        );
+        assert_eq!(
+            set_key(
+                "port = 1\n[disk]\ndirectory = \"/d\"\n",
+                "disk",
+                "session",
+                &value
+            ),
+            "port = 1\n[disk]\nsession = \"/new\"\ndirectory = \"/d\"\n"
+        );
+        assert_eq!(
+            set_key("port = 1\n", "disk", "session", &value),
+            "port = 1\n\n[disk]\nsession = \"/new\"\n"
+        );
+        assert_eq!(
+            set_key("", "disk", "session", &value),
+            "[disk]\nsession = \"/new\"\n"
+        );
+        let updated = set_key("[disk]\nsession = \"/old\"\n", "disk", "session", &value);
+        assert!(ConfigFile::parse("a.toml", &updated).is_ok());
+    }
+
+    #[test]
+    fn test_validation_hour() {
+        let mut disk = DiskConfig::default();
+        assert!(disk.validation_hour(12));
+        disk.validate_hours = Some((1, 6));
+        assert!(disk.validation_hour(1));
+        assert!(!disk.validation_hour(6));
+        assert!(!disk.validation_hour(12));
+        disk.validate_hours = Some((22, 4));
+        assert!(disk.validation_hour(23));
+        assert!(disk.validation_hour(0));
+        assert!(!disk.validation_hour(4));
+    }
+
+    #[test]
+    fn test_settings_set() {
+        let config = Config::default();
+        let mut settings = Settings::new(&config);
+        settings.set("max_dl", &json!(3)).unwrap();
+        settings.set("peer.suppress_have", &json!(false)).unwrap();
+        assert_eq!(settings.get("max_dl"), Some(json!(3)));
+        assert_eq!(
+            Settings::new(&config).changed(&settings),
+            vec!["max_dl", "peer.suppress_have"]
+        );
+
+        assert!(settings.set("max_dl", &json!(0)).is_err());
+        assert!(settings.set("max_dl", &json!("3")).is_err());
+        assert!(settings.set("net.max_open_sockets", &json!(-1)).is_err());
+        assert!(settings.set("peer.lazy_bitfield", &json!(1)).is_err());
+        assert!(settings.set("port", &json!(1)).is_err());
+        assert_eq!(settings.get("max_dl"), Some(json!(3)));
+    }
+}
@@ -1,18 +1,18 @@
 use std::cell::RefCell;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::io;
+use std::net::TcpListener;
 use std::rc::Rc;
-use std::{io, time};
+use std::sync::atomic;
 
 use amy::{self, ChannelError};
 
 use crate::control::cio::{self, Error, ErrorKind, Result, ResultExt};
 use crate::torrent::peer::reader::RRes;
-use crate::util::UHashMap;
-use crate::CONFIG;
+use crate::util::{net, systemd, UHashMap, UHashSet};
+use crate::{config, CONFIG, LISTEN_PORT};
 use crate::{disk, rpc, torrent, tracker};
 
 const POLL_INT_MS: usize = 1000;
-const PRUNE_GOAL: usize = 50;
 
 /// Amy based CIO implementation. Currently the default one used.
 pub struct ACIO {
@@ -34,6 +34,8 @@ struct ACIOData {
     poll: amy::Poller,
     reg: amy::Registrar,
     peers: UHashMap<torrent::PeerConn>,
+    /// Peers with messages queued since the last poll
+    unflushed: UHashSet,
     events: Vec<cio::Event>,
     chans: ACChans,
     crashed: bool,
@@ -43,11 +45,26 @@ struct ACIOData {
 
 impl ACIO {
     pub fn new(poll: amy::Poller, reg: amy::Registrar, chans: ACChans) -> io::Result<ACIO> {
-        let ip = Ipv4Addr::new(0, 0, 0, 0);
-        let port = CONFIG.port;
-        let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
-        listener.set_nonblocking(true)?;
+        let (listener, port) = match systemd::take_tcp("peer")? {
+            Some(listener) => {
+                net::tune(&listener)?;
+                let port = listener.local_addr()?.port();
+                (listener, port)
+            }
+            None => {
+                let mut res = Err(io::ErrorKind::AddrNotAvailable.into());
+                for port in CONFIG.listen_ports() {
+                    res = bind(port);
+                    if res.is_ok() {
+                        break;
+                    }
+                }
+                res?
+            }
+        };
         let lid = reg.register(&listener, amy::Event::Both)?;
+        info!("Listening for peers on port {}", port);
+        LISTEN_PORT.store(port, atomic::Ordering::Relaxed);
 
         let data = ACIOData {
             poll,
@@ -56,6 +73,7 @@ impl ACIO {
             listener,
             lid,
             peers: UHashMap::default(),
+            unflushed: UHashSet::default(),
             events: Vec::new(),
             crashed: false,
         };
@@ -180,6 +198,7 @@ impl cio::CIO for ACIO {
             for event in d.events.drain(..) {
                 events.push(event);
             }
+            d.flush_peers(events);
         }
 
         let res = self.data.borrow_mut().poll.wait(POLL_INT_MS);
@@ -203,27 +222,9 @@ impl cio::CIO for ACIO {
     }
 
     fn add_peer(&mut self, mut peer: torrent::PeerConn) -> Result<cio::PID> {
-        if self.data.borrow().peers.len() > CONFIG.net.max_open_sockets {
-            let mut pruned = Vec::new();
-            for (id, peer) in &self.data.borrow().peers {
-                if peer.last_action().elapsed()
-                    > time::Duration::from_secs(CONFIG.peer.prune_timeout)
-                {
-                    pruned.push(*id)
-                }
-                if pruned.len() == PRUNE_GOAL {
-                    break;
-                }
-            }
-            // We couldn't even prune anything, this client must be really busy...
-            // Either way just return an error
-            if pruned.is_empty() {
-                return Err(ErrorKind::Full.into());
-            }
-
-            for id in pruned {
-                self.remove_peer(id);
-            }
+        // Control reclaims slots when at the limit, this is only a backstop
+        if self.data.borrow().peers.len() >= config::settings().max_open_sockets {
+            return Err(ErrorKind::Full.into());
         }
         let id = self
             .data
@@ -254,6 +255,23 @@ impl cio::CIO for ACIO {
         self.data.borrow_mut().remove_peer(peer);
     }
 
+    fn listen(&mut self, port: u16) -> Result<u16> {
+        let (listener, port) = bind(port).chain_err(|| ErrorKind::IO)?;
+        let mut d = self.data.borrow_mut();
+        let lid = d
+            .reg
+            .register(&listener, amy::Event::Both)
+            .chain_err(|| ErrorKind::IO)?;
+        if let Err(e) = d.reg.deregister(&d.listener) {
+            error!("Failed to deregister listener: {:?}", e);
+        }
+        d.listener = listener;
+        d.lid = lid;
+        info!("Listening for peers on port {}", port);
+        LISTEN_PORT.store(port, atomic::Ordering::Relaxed);
+        Ok(port)
+    }
+
     fn flush_peers(&mut self, peers: Vec<cio::PID>) {
         let mut events = Vec::new();
         let mut d = self.data.borrow_mut();
@@ -278,19 +296,13 @@ impl cio::CIO for ACIO {
 
     fn msg_peer(&mut self, pid: cio::PID, msg: torrent::Message) {
         let mut d = self.data.borrow_mut();
-        let err = if let Some(peer) = d.peers.get_mut(&pid) {
-            peer.write_message(msg).chain_err(|| ErrorKind::IO).err()
+        if let Some(peer) = d.peers.get_mut(&pid) {
+            // Written on the next poll, batched with any other messages
+            peer.queue_message(msg);
+            d.unflushed.insert(pid);
         } else {
             // might happen if removed but still present in a torrent
             debug!("Tried to message peer which has been removed!");
-            None
-        };
-        if let Some(e) = err {
-            d.remove_peer(pid);
-            d.events.push(cio::Event::Peer {
-                peer: pid,
-                event: Err(e),
-            });
         }
     }
 
@@ -336,7 +348,43 @@ impl cio::CIO for ACIO {
     }
 }
 
+impl Drop for ACIOData {
+    fn drop(&mut self) {
+        for (_, mut peer) in self.peers.drain() {
+            peer.close();
+        }
+    }
+}
+
+/// Binds a listener for peers to the configured address and interface,
+/// returning it with the port bound.
+fn bind(port: u16) -> io::Result<(TcpListener, u16)> {
+    let listener = net::tcp_listener(port)?;
+    net::tune(&listener)?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+    Ok((listener, port))
+}
+
 impl ACIOData {
+    /// Writes out messages queued for peers since the last poll.
+    fn flush_peers(&mut self, events: &mut Vec<cio::Event>) {
+        let unflushed = std::mem::take(&mut self.unflushed);
+        for pid in unflushed {
+            let err = match self.peers.get_mut(&pid) {
+                Some(peer) => peer.flush().chain_err(|| ErrorKind::IO).err(),
+                None => None,
+            };
+            if let Some(e) = err {
+                self.remove_peer(pid);
+                events.push(cio::Event::Peer {
+                    peer: pid,
+                    event: Err(e),
+                });
+            }
+        }
+    }
+
     fn remove_peer(&mut self, pid: cio::PID) {
         if let Some(p) = self.peers.remove(&pid) {
             if let Err(e) = self.reg.deregister(p.sock()) {
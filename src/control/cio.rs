@@ -57,6 +57,10 @@ pub trait CIO {
     /// for this to occur before internally removing the peer.
     fn remove_peer(&self, peer: PID);
 
+    /// Listens for peers on a new port in place of the current one,
+    /// returning the port bound, which the OS picks if port is 0
+    fn listen(&mut self, port: u16) -> Result<u16>;
+
     /// Flushes events on the given vec of peers
     fn flush_peers(&mut self, peers: Vec<PID>);
 
@@ -118,6 +122,11 @@ pub mod test {
                 data: Arc::new(Mutex::new(d)),
             }
         }
+
+        /// Messages which have been sent to peers
+        pub fn peer_msgs(&self) -> Vec<(PID, torrent::Message)> {
+            self.data.lock().unwrap().peer_msgs.clone()
+        }
     }
 
     impl CIO for TCIO {
@@ -153,6 +162,10 @@ pub mod test {
             d.peers.remove(&peer);
         }
 
+        fn listen(&mut self, port: u16) -> Result<u16> {
+            Ok(port)
+        }
+
         fn flush_peers(&mut self, mut peers: Vec<PID>) {
             let mut d = self.data.lock().unwrap();
             d.flushed_peers.extend(peers.drain(..));
@@ -162,7 +175,7 @@ pub mod test {
             let mut d = self.data.lock().unwrap();
             d.peer_msgs.push((peer, msg.clone()));
             if let Some(p) = d.peers.get_mut(&peer) {
-                p.write_message(msg).ok();
+                p.queue_message(msg);
             }
         }
 
@@ -0,0 +1,262 @@
+//! Detection of torrents which would write to the same files on disk.
+//! Files of the same length may hold the same data, letting torrents
+//! cross-seed it. Their piece hashes are compared where the pieces line
+//! up, otherwise they're only written to once validation finds them
+//! identical. Files with different data would corrupt each other.
+
+use std::path::{Path, PathBuf};
+
+use super::{cio, Control};
+use crate::config;
+use crate::torrent::{Info, Torrent};
+use crate::util::{MHashMap, MHashSet};
+
+/// Files of a torrent which another torrent also has.
+#[derive(Debug, Default, PartialEq)]
+pub struct Collisions {
+    /// Indices of the files shared with another torrent, having the same
+    /// length, whose pieces can't be compared
+    pub shared: Vec<usize>,
+    /// A file another torrent has with different data, and the other
+    /// torrent's id
+    pub conflict: Option<(PathBuf, usize)>,
+}
+
+/// A file of a torrent, with the hashes of the pieces wholly within it.
+#[derive(Clone, Copy, Debug)]
+struct File<'a> {
+    len: u64,
+    piece_len: u32,
+    /// Offset in the file of the first hashed piece
+    offset: u64,
+    hashes: &'a [Vec<u8>],
+}
+
+impl File<'_> {
+    /// Whether two files of the same path hold the same data, if their
+    /// pieces line up so their hashes can tell.
+    fn same(&self, other: &File<'_>) -> Option<bool> {
+        if self.len != other.len {
+            return Some(false);
+        }
+        if self.piece_len != other.piece_len
+            || self.offset != other.offset
+            || self.hashes.is_empty()
+            || other.hashes.is_empty()
+        {
+            return None;
+        }
+        Some(self.hashes.iter().zip(other.hashes).all(|(a, b)| a == b))
+    }
+}
+
+/// Indices, paths and data of the files a torrent stores in a directory.
+fn files(dir: PathBuf, info: &Info) -> impl Iterator<Item = (usize, PathBuf, File<'_>)> + '_ {
+    info.user_files().map(move |(i, f)| {
+        let (offset, hashes) = info.file_hashes(i);
+        let file = File {
+            len: f.length,
+            piece_len: info.piece_len,
+            offset,
+            hashes,
+        };
+        (i, dir.join(&f.path), file)
+    })
+}
+
+/// Compares a torrent's files to those of other torrents with the same
+/// paths, by id.
+fn find<'a, I>(own: I, others: &MHashMap<PathBuf, Vec<(usize, File<'_>)>>) -> Collisions
+where
+    I: Iterator<Item = (usize, PathBuf, File<'a>)>,
+{
+    let mut collisions = Collisions::default();
+    for (i, path, file) in own {
+        for (id, other) in others.get(&path).into_iter().flatten() {
+            match file.same(other) {
+                Some(true) => {}
+                Some(false) => {
+                    collisions.conflict = Some((path, *id));
+                    return collisions;
+                }
+                None => collisions.shared.push(i),
+            }
+        }
+    }
+    collisions.shared.dedup();
+    collisions
+}
+
+impl<T: cio::CIO> Control<T> {
+    /// Finds the files a torrent shares with others, stopping it with an
+    /// error if one would be overwritten, or validating it before it may
+    /// write to shared files. Returns whether either was done.
+    pub(super) fn check_collisions(&mut self, tid: usize) -> bool {
+        let collisions = self.collisions(tid);
+        self.resolve(tid, collisions)
+    }
+
+    fn resolve(&mut self, tid: usize, collisions: Collisions) -> bool {
+        if let Some((ref path, other)) = collisions.conflict {
+            self.collided(tid, path, other);
+            true
+        } else if !collisions.shared.is_empty() {
+            let t = self.torrents.get_mut(&tid).unwrap();
+            debug!(
+                "{} shares {} files with other torrents",
+                t.info().name,
+                collisions.shared.len()
+            );
+            t.verify_shared(collisions.shared);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Checks a torrent again if its files moved or its metadata was
+    /// fetched since it was last checked.
+    pub(super) fn check_relocated(&mut self, tid: usize) {
+        let relocated = self.torrents.get_mut(&tid).map(|t| t.take_relocated());
+        if relocated.unwrap_or(false) {
+            self.check_collisions(tid);
+        }
+    }
+
+    /// A file of another torrent, and its id, which a torrent's data
+    /// would overwrite if moved to dir.
+    pub(super) fn move_collision(&self, tid: usize, dir: &str) -> Option<(PathBuf, usize)> {
+        let t = self.torrents.get(&tid)?;
+        let own: MHashSet<_> = files(PathBuf::from(dir), t.info())
+            .map(|(_, path, _)| path)
+            .collect();
+        self.other_files(tid)
+            .find(|(_, path, _)| own.contains(path))
+            .map(|(id, path, _)| (path, id))
+    }
+
+    /// Stops the torrents loaded from the session which would overwrite
+    /// the files of another, keeping the first added of each, and
+    /// validates those sharing files which can't be compared.
+    pub(super) fn check_all_collisions(&mut self) {
+        let default = config::settings().directory.clone();
+        let mut ids: Vec<_> = self.torrents.keys().cloned().collect();
+        ids.sort_unstable();
+        let mut seen: MHashMap<PathBuf, Vec<(usize, File<'_>)>> = MHashMap::default();
+        let mut found = Vec::new();
+        for id in ids {
+            let t = &self.torrents[&id];
+            let own: Vec<_> = files(dir(t, &default), t.info()).collect();
+            let collisions = find(own.iter().cloned(), &seen);
+            if collisions.conflict.is_none() {
+                for (_, path, file) in own {
+                    seen.entry(path).or_default().push((id, file));
+                }
+            }
+            if collisions != Collisions::default() {
+                found.push((id, collisions));
+            }
+        }
+        drop(seen);
+        for (id, collisions) in found {
+            self.resolve(id, collisions);
+        }
+    }
+
+    /// Whether a torrent stopped by a collision would still overwrite
+    /// another's files, rather than being resumed. Files it shares are
+    /// validated again, the torrent continuing if they're identical.
+    pub(super) fn still_collides(&mut self, tid: usize) -> bool {
+        let collided = self.torrents.get(&tid).map(|t| t.collided());
+        collided.unwrap_or(false) && self.check_collisions(tid)
+    }
+
+    fn collided(&mut self, tid: usize, path: &Path, other: usize) {
+        let reason = format!(
+            "{} is also a file of {} with different data",
+            path.display(),
+            self.torrents[&other].info().name
+        );
+        let t = self.torrents.get_mut(&tid).unwrap();
+        info!("Stopping {}: {}", t.info().name, reason);
+        t.set_collision(reason);
+    }
+
+    /// Files of a torrent other torrents also have. Torrents without
+    /// metadata have none yet.
+    fn collisions(&self, tid: usize) -> Collisions {
+        let t = match self.torrents.get(&tid) {
+            Some(t) => t,
+            None => return Collisions::default(),
+        };
+        let default = config::settings().directory.clone();
+        let mut others: MHashMap<_, Vec<_>> = MHashMap::default();
+        for (id, path, file) in self.other_files(tid) {
+            others.entry(path).or_default().push((id, file));
+        }
+        find(files(dir(t, &default), t.info()), &others)
+    }
+
+    /// Ids, paths and data of the files of all torrents but one.
+    fn other_files(&self, tid: usize) -> impl Iterator<Item = (usize, PathBuf, File<'_>)> + '_ {
+        let default = config::settings().directory.clone();
+        self.torrents
+            .iter()
+            .filter(move |&(&id, _)| id != tid)
+            .flat_map(move |(&id, o)| {
+                files(dir(o, &default), o.info()).map(move |(_, p, f)| (id, p, f))
+            })
+    }
+}
+
+/// Directory a torrent's files are in.
+fn dir<T: cio::CIO>(t: &Torrent<T>, default: &str) -> PathBuf {
+    PathBuf::from(t.path().unwrap_or(default))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let (h1, h2) = (vec![vec![1]], vec![vec![2]]);
+        let file = |len, offset, hashes| File {
+            len,
+            piece_len: 4,
+            offset,
+            hashes,
+        };
+        let own = || {
+            vec![
+                (0, PathBuf::from("/a/x"), file(5, 1, &h1[..])),
+                (1, PathBuf::from("/a/y"), file(3, 0, &[][..])),
+            ]
+            .into_iter()
+        };
+        fn others<'a>(files: Vec<(&str, File<'a>)>) -> MHashMap<PathBuf, Vec<(usize, File<'a>)>> {
+            let mut others: MHashMap<_, Vec<_>> = MHashMap::default();
+            for (path, f) in files {
+                others.entry(PathBuf::from(path)).or_default().push((1, f));
+            }
+            others
+        }
+        let none = find(own(), &others(vec![("/b/x", file(5, 1, &h1))]));
+        assert_eq!(none, Collisions::default());
+        // Files whose hashes match aren't validated
+        let same = find(own(), &others(vec![("/a/x", file(5, 1, &h1))]));
+        assert_eq!(same, Collisions::default());
+        let shared = others(vec![
+            ("/a/x", file(5, 0, &h2)),
+            ("/a/y", file(3, 0, &[])),
+            ("/a/y", file(3, 0, &[])),
+        ]);
+        let shared = find(own(), &shared);
+        assert_eq!(shared.shared, vec![0, 1]);
+        assert!(shared.conflict.is_none());
+        let differs = find(own(), &others(vec![("/a/x", file(5, 1, &h2))]));
+        assert_eq!(differs.conflict, Some((PathBuf::from("/a/x"), 1)));
+        let conflict = find(own(), &others(vec![("/a/y", file(4, 0, &[]))]));
+        assert_eq!(conflict.conflict, Some((PathBuf::from("/a/y"), 1)));
+    }
+}
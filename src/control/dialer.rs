@@ -0,0 +1,432 @@
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::rpc::resource::PeerSource;
+use crate::util::{MHashMap, UHashMap};
+
+/// Maximum number of addresses remembered per torrent
+const MAX_CANDIDATES: usize = 500;
+/// Number of consecutive failures after which an address is forgotten
+const MAX_FAILURES: u32 = 8;
+/// Delay before retrying an address after its first failure, doubled
+/// with each further failure
+const BACKOFF_BASE_SECS: u64 = 30;
+const BACKOFF_MAX_SECS: u64 = 60 * 60;
+/// Delay before reconnecting to a peer which disconnected
+const RECONNECT_SECS: u64 = 60;
+/// Head start the IPv6 address of a peer known under both IP versions
+/// gets before its IPv4 address is dialed as well
+const HEAD_START_MS: u64 = 250;
+
+const SOURCES: usize = PeerSource::ALL.len();
+
+/// Decides which peers to connect to, deduplicating addresses learned
+/// from different sources, backing off from addresses which fail, and
+/// preferring sources which historically yield working peers. Peers known
+/// under both IP versions are raced, keeping whichever connects first.
+pub struct Dialer {
+    /// Known addresses of each torrent
+    candidates: UHashMap<MHashMap<SocketAddr, Candidate>>,
+    /// Each torrent's peers known under both IP versions, mapping each
+    /// of their addresses to the other
+    aliases: UHashMap<MHashMap<SocketAddr, SocketAddr>>,
+    /// Torrent and address of connections which haven't completed a handshake
+    dialing: UHashMap<(usize, SocketAddr)>,
+    /// Torrent and address of connections which completed a handshake
+    connected: UHashMap<(usize, SocketAddr)>,
+    stats: [SourceStats; SOURCES],
+}
+
+struct Candidate {
+    source: PeerSource,
+    state: State,
+    failures: u32,
+    retry: Instant,
+    /// When the address was last dialed
+    dialed: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Dialing,
+    Connected,
+}
+
+#[derive(Clone, Copy, Default)]
+struct SourceStats {
+    attempts: u64,
+    successes: u64,
+}
+
+impl Dialer {
+    pub fn new() -> Dialer {
+        Dialer {
+            candidates: UHashMap::default(),
+            aliases: UHashMap::default(),
+            dialing: UHashMap::default(),
+            connected: UHashMap::default(),
+            stats: [SourceStats::default(); SOURCES],
+        }
+    }
+
+    /// Adds the address of a torrent's peer, ignoring it if already known.
+    pub fn add(&mut self, tid: usize, addr: SocketAddr, source: PeerSource) {
+        let candidates = self.candidates.entry(tid).or_default();
+        if candidates.contains_key(&addr) {
+            return;
+        }
+        if candidates.len() >= MAX_CANDIDATES {
+            // Forget the address which failed the most to make room
+            let worst = candidates
+                .iter()
+                .filter(|(_, c)| c.state == State::Idle)
+                .max_by_key(|(_, c)| c.failures)
+                .map(|(a, _)| *a);
+            match worst {
+                Some(a) => {
+                    candidates.remove(&a);
+                    self.unlink(tid, a);
+                }
+                None => return,
+            }
+        }
+        let now = Instant::now();
+        self.candidates.entry(tid).or_default().insert(
+            addr,
+            Candidate {
+                source,
+                state: State::Idle,
+                failures: 0,
+                retry: now,
+                dialed: now,
+            },
+        );
+    }
+
+    /// Records the IPv4 and IPv6 addresses of a torrent's peer, adding
+    /// whichever isn't known yet.
+    pub fn link(&mut self, tid: usize, v4: SocketAddr, v6: SocketAddr, source: PeerSource) {
+        self.add(tid, v4, source);
+        self.add(tid, v6, source);
+        let candidates = &self.candidates[&tid];
+        // Either may not have fit
+        if !candidates.contains_key(&v4) || !candidates.contains_key(&v6) {
+            return;
+        }
+        self.unlink(tid, v4);
+        self.unlink(tid, v6);
+        let aliases = self.aliases.entry(tid).or_default();
+        aliases.insert(v4, v6);
+        aliases.insert(v6, v4);
+    }
+
+    /// Addresses which may be connected to now, best first.
+    pub fn ready(&self, now: Instant) -> Vec<(usize, SocketAddr, PeerSource)> {
+        let mut ready: Vec<_> = self
+            .candidates
+            .iter()
+            .flat_map(|(tid, candidates)| {
+                candidates
+                    .iter()
+                    .filter(move |(addr, c)| self.eligible(*tid, addr, c, now))
+                    .map(move |(addr, c)| (*tid, *addr, c))
+            })
+            .collect();
+        ready.sort_by(|a, b| {
+            let (ra, rb) = (self.success_rate(a.2.source), self.success_rate(b.2.source));
+            rb.partial_cmp(&ra)
+                .unwrap_or(Ordering::Equal)
+                .then(a.2.failures.cmp(&b.2.failures))
+        });
+        ready
+            .into_iter()
+            .map(|(tid, addr, c)| (tid, addr, c.source))
+            .collect()
+    }
+
+    /// Whether any address may be connected to now.
+    pub fn any_ready(&self, now: Instant) -> bool {
+        self.candidates.iter().any(|(tid, candidates)| {
+            candidates
+                .iter()
+                .any(|(addr, c)| self.eligible(*tid, addr, c, now))
+        })
+    }
+
+    /// Whether the IPv4 address of a peer whose IPv6 address is being
+    /// dialed has become ready, its head start having passed.
+    pub fn fallback_due(&self, now: Instant) -> bool {
+        self.dialing.values().any(|&(tid, addr)| {
+            addr.is_ipv6()
+                && self
+                    .alias(tid, &addr)
+                    .map(|(alias, c)| self.eligible(tid, &alias, c, now))
+                    .unwrap_or(false)
+        })
+    }
+
+    /// Whether an address may be dialed now. Of a peer's two addresses,
+    /// the IPv6 one is dialed first, then the IPv4 one once the other has
+    /// had its head start or while it's backed off from.
+    fn eligible(&self, tid: usize, addr: &SocketAddr, c: &Candidate, now: Instant) -> bool {
+        if c.state != State::Idle || c.retry > now {
+            return false;
+        }
+        let alias = match self.alias(tid, addr) {
+            Some((_, alias)) => alias,
+            None => return true,
+        };
+        match alias.state {
+            State::Connected => false,
+            State::Dialing => {
+                addr.is_ipv4() && alias.dialed + Duration::from_millis(HEAD_START_MS) <= now
+            }
+            State::Idle => addr.is_ipv6() || alias.retry > now,
+        }
+    }
+
+    /// Records a connection being made to an address.
+    pub fn dialing(&mut self, tid: usize, addr: SocketAddr, pid: usize) {
+        if let Some(c) = self.candidate(tid, addr) {
+            c.state = State::Dialing;
+            c.dialed = Instant::now();
+            let source = c.source;
+            self.stats[source as usize].attempts += 1;
+            self.dialing.insert(pid, (tid, addr));
+        }
+    }
+
+    /// Records the address a peer which connected to us listens on as
+    /// connected, so it's dialed once the peer disconnects.
+    pub fn accepted(&mut self, tid: usize, addr: SocketAddr, pid: usize) {
+        self.add(tid, addr, PeerSource::Incoming);
+        match self.candidate(tid, addr) {
+            Some(c) if c.state == State::Idle => c.state = State::Connected,
+            _ => return,
+        }
+        self.connected.insert(pid, (tid, addr));
+    }
+
+    /// Records a connection which couldn't be made.
+    pub fn failed(&mut self, tid: usize, addr: SocketAddr) {
+        if let Some(c) = self.candidate(tid, addr) {
+            let source = c.source;
+            self.stats[source as usize].attempts += 1;
+        }
+        self.backoff(tid, addr);
+    }
+
+    /// Records a peer completing its handshake, returning the connection
+    /// to its other address if that's still being dialed, which should be
+    /// closed as this one won the race.
+    pub fn connected(&mut self, pid: usize) -> Option<usize> {
+        let (tid, addr) = self.dialing.remove(&pid)?;
+        let c = self.candidate(tid, addr)?;
+        c.state = State::Connected;
+        c.failures = 0;
+        let source = c.source;
+        self.stats[source as usize].successes += 1;
+        self.connected.insert(pid, (tid, addr));
+
+        let (alias, _) = self.alias(tid, &addr)?;
+        let loser = self
+            .dialing
+            .iter()
+            .find(|(_, &d)| d == (tid, alias))
+            .map(|(pid, _)| *pid)?;
+        self.dialing.remove(&loser);
+        if let Some(c) = self.candidate(tid, alias) {
+            c.state = State::Idle;
+            // The attempt was abandoned rather than failed
+            let source = c.source;
+            let stats = &mut self.stats[source as usize];
+            stats.attempts = stats.attempts.saturating_sub(1);
+        }
+        Some(loser)
+    }
+
+    /// Records a peer being disconnected, backing off from its address
+    /// if it never completed a handshake.
+    pub fn closed(&mut self, pid: usize) {
+        if let Some((tid, addr)) = self.dialing.remove(&pid) {
+            self.backoff(tid, addr);
+        } else if let Some((tid, addr)) = self.connected.remove(&pid) {
+            if let Some(c) = self.candidate(tid, addr) {
+                c.state = State::Idle;
+                c.retry = Instant::now() + Duration::from_secs(RECONNECT_SECS);
+            }
+        }
+    }
+
+    /// Forgets the addresses of torrents which no longer exist.
+    pub fn retain<F: Fn(usize) -> bool>(&mut self, f: F) {
+        self.candidates.retain(|tid, _| f(*tid));
+        self.aliases.retain(|tid, _| f(*tid));
+        self.dialing.retain(|_, (tid, _)| f(*tid));
+        self.connected.retain(|_, (tid, _)| f(*tid));
+    }
+
+    fn candidate(&mut self, tid: usize, addr: SocketAddr) -> Option<&mut Candidate> {
+        self.candidates.get_mut(&tid)?.get_mut(&addr)
+    }
+
+    /// The other address of a peer known under both IP versions.
+    fn alias(&self, tid: usize, addr: &SocketAddr) -> Option<(SocketAddr, &Candidate)> {
+        let alias = *self.aliases.get(&tid)?.get(addr)?;
+        Some((alias, self.candidates.get(&tid)?.get(&alias)?))
+    }
+
+    fn unlink(&mut self, tid: usize, addr: SocketAddr) {
+        if let Some(aliases) = self.aliases.get_mut(&tid) {
+            if let Some(alias) = aliases.remove(&addr) {
+                aliases.remove(&alias);
+            }
+        }
+    }
+
+    fn backoff(&mut self, tid: usize, addr: SocketAddr) {
+        let candidates = match self.candidates.get_mut(&tid) {
+            Some(c) => c,
+            None => return,
+        };
+        let forget = match candidates.get_mut(&addr) {
+            Some(c) => {
+                c.state = State::Idle;
+                c.failures += 1;
+                let secs = BACKOFF_BASE_SECS
+                    .saturating_mul(1 << (c.failures - 1).min(16))
+                    .min(BACKOFF_MAX_SECS);
+                c.retry = Instant::now() + Duration::from_secs(secs);
+                c.failures >= MAX_FAILURES
+            }
+            None => false,
+        };
+        if forget {
+            candidates.remove(&addr);
+            self.unlink(tid, addr);
+        }
+    }
+
+    /// Fraction of connections from a source which completed a handshake,
+    /// assuming an even chance for sources with few attempts.
+    fn success_rate(&self, source: PeerSource) -> f64 {
+        let s = &self.stats[source as usize];
+        (s.successes as f64 + 1.) / (s.attempts as f64 + 2.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut d = Dialer::new();
+        d.add(0, addr(1), PeerSource::Tracker);
+        d.add(0, addr(1), PeerSource::Dht);
+        d.add(1, addr(1), PeerSource::Dht);
+        assert_eq!(d.ready(Instant::now()).len(), 2);
+        assert!(d.any_ready(Instant::now()));
+        d.dialing(0, addr(1), 10);
+        d.add(0, addr(1), PeerSource::Pex);
+        assert_eq!(d.ready(Instant::now()), vec![(1, addr(1), PeerSource::Dht)]);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut d = Dialer::new();
+        d.add(0, addr(1), PeerSource::Tracker);
+        d.dialing(0, addr(1), 10);
+        d.closed(10);
+        assert!(d.ready(Instant::now()).is_empty());
+        assert!(!d.any_ready(Instant::now()));
+        let later = Instant::now() + Duration::from_secs(BACKOFF_BASE_SECS + 1);
+        assert_eq!(d.ready(later).len(), 1);
+        d.failed(0, addr(1));
+        assert!(d.ready(later).is_empty());
+        for _ in 2..MAX_FAILURES {
+            d.failed(0, addr(1));
+        }
+        let much_later = Instant::now() + Duration::from_secs(BACKOFF_MAX_SECS * 2);
+        assert!(d.ready(much_later).is_empty());
+    }
+
+    #[test]
+    fn test_source_ranking() {
+        let mut d = Dialer::new();
+        for i in 0..4 {
+            d.add(0, addr(i), PeerSource::Tracker);
+            d.dialing(0, addr(i), i as usize);
+            d.closed(i as usize);
+        }
+        d.add(0, addr(10), PeerSource::Dht);
+        d.dialing(0, addr(10), 10);
+        d.connected(10);
+        d.add(0, addr(20), PeerSource::Tracker);
+        d.add(0, addr(21), PeerSource::Dht);
+        assert_eq!(d.ready(Instant::now())[0].1, addr(21));
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let v4 = addr(1);
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 1));
+        let ready = |d: &Dialer, t| -> Vec<_> { d.ready(t).into_iter().map(|r| r.1).collect() };
+        let mut d = Dialer::new();
+        d.add(1, v4, PeerSource::Tracker);
+        d.dialing(1, v4, 1);
+        d.connected(1);
+        d.link(1, v4, v6, PeerSource::Pex);
+        // The peer is already connected under its other address
+        assert!(ready(&d, Instant::now()).is_empty());
+
+        let mut d = Dialer::new();
+        d.link(0, v4, v6, PeerSource::Tracker);
+        let now = Instant::now();
+        assert_eq!(ready(&d, now), vec![v6]);
+        d.dialing(0, v6, 1);
+        assert!(!d.fallback_due(now));
+        let head_start = Instant::now() + Duration::from_millis(HEAD_START_MS);
+        assert!(d.fallback_due(head_start));
+        assert_eq!(ready(&d, head_start), vec![v4]);
+        d.dialing(0, v4, 2);
+        assert!(!d.fallback_due(head_start));
+        // IPv4 won the race, so the IPv6 connection is closed
+        assert_eq!(d.connected(2), Some(1));
+        d.closed(1);
+        assert_eq!(d.candidates[&0][&v6].failures, 0);
+        assert!(ready(&d, head_start).is_empty());
+
+        // Once disconnected IPv6 goes first again, unless backed off from
+        d.closed(2);
+        let later = Instant::now() + Duration::from_secs(RECONNECT_SECS + 1);
+        assert_eq!(ready(&d, later), vec![v6]);
+        d.dialing(0, v6, 3);
+        d.closed(3);
+        d.failed(0, v6);
+        d.failed(0, v6);
+        assert_eq!(ready(&d, later), vec![v4]);
+    }
+
+    #[test]
+    fn test_accepted() {
+        let mut d = Dialer::new();
+        d.accepted(0, addr(1), 10);
+        assert!(d.ready(Instant::now()).is_empty());
+        // Peers which connected to us are dialed once they disconnect
+        d.closed(10);
+        let later = Instant::now() + Duration::from_secs(RECONNECT_SECS + 1);
+        assert_eq!(d.ready(later), vec![(0, addr(1), PeerSource::Incoming)]);
+        // Addresses already being dialed are left to that connection
+        d.dialing(0, addr(1), 11);
+        d.accepted(0, addr(1), 12);
+        d.closed(12);
+        assert_eq!(d.dialing.get(&11), Some(&(0, addr(1))));
+    }
+}
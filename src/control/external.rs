@@ -0,0 +1,131 @@
+use std::net::IpAddr;
+
+use crate::util::MHashMap;
+
+/// Number of peers which must agree on our address before it's used
+const PEER_VOTES: usize = 5;
+/// Maximum number of peer votes held at once
+const MAX_VOTES: usize = 64;
+
+/// Our external addresses, as reported by trackers, the DHT, a STUN
+/// server or agreed on by peers.
+#[derive(Default)]
+pub struct External {
+    pub v4: Option<IpAddr>,
+    pub v6: Option<IpAddr>,
+    /// Addresses peers see us as, by peer address
+    votes: MHashMap<IpAddr, IpAddr>,
+}
+
+impl External {
+    pub fn new() -> External {
+        External::default()
+    }
+
+    /// Records an address from a trusted source, returning whether
+    /// it changed our known addresses.
+    pub fn report(&mut self, ip: IpAddr) -> bool {
+        if !is_global(&ip) {
+            return false;
+        }
+        let slot = if ip.is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+        if *slot == Some(ip) {
+            return false;
+        }
+        info!("External IP is {}", ip);
+        *slot = Some(ip);
+        true
+    }
+
+    /// Records the address a peer sees us as, which is reported once
+    /// enough peers agree on it.
+    pub fn vote(&mut self, voter: IpAddr, ip: IpAddr) -> bool {
+        if !is_global(&ip) {
+            return false;
+        }
+        if self.votes.len() >= MAX_VOTES && !self.votes.contains_key(&voter) {
+            self.votes.clear();
+        }
+        self.votes.insert(voter, ip);
+        let agreed = self.votes.values().filter(|v| **v == ip).count();
+        if agreed < PEER_VOTES {
+            return false;
+        }
+        self.votes.retain(|_, v| *v != ip);
+        self.report(ip)
+    }
+}
+
+/// Whether an address may be reachable from the internet.
+fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space used by carrier grade NAT
+                || (o[0] == 100 && (o[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local
+                || (s[0] & 0xfe00) == 0xfc00
+                // Link local
+                || (s[0] & 0xffc0) == 0xfe80
+                // Documentation
+                || (s[0] == 0x2001 && s[1] == 0x0db8)
+                || ip.to_ipv4_mapped().is_some())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_report() {
+        let mut e = External::new();
+        assert!(!e.report(ip("192.168.1.2")));
+        assert!(!e.report(ip("100.64.0.1")));
+        assert!(!e.report(ip("fe80::1")));
+        assert!(e.report(ip("1.2.3.4")));
+        assert!(!e.report(ip("1.2.3.4")));
+        assert!(e.report(ip("2001:4860::1")));
+        assert_eq!(e.v4, Some(ip("1.2.3.4")));
+        assert_eq!(e.v6, Some(ip("2001:4860::1")));
+    }
+
+    #[test]
+    fn test_vote() {
+        let mut e = External::new();
+        let voter = |i: u8| IpAddr::from([10, 0, 0, i]);
+        // A peer voting repeatedly only counts once
+        for _ in 0..PEER_VOTES {
+            assert!(!e.vote(voter(0), ip("1.2.3.4")));
+        }
+        for i in 1..PEER_VOTES as u8 - 1 {
+            assert!(!e.vote(voter(i), ip("1.2.3.4")));
+        }
+        assert!(!e.vote(voter(10), ip("5.6.7.8")));
+        assert!(e.vote(voter(20), ip("1.2.3.4")));
+        assert_eq!(e.v4, Some(ip("1.2.3.4")));
+        assert_eq!(e.votes.len(), 1);
+    }
+}
@@ -0,0 +1,54 @@
+use std::mem;
+
+use crate::util::UHashSet;
+
+/// Tracks which connection limits were hit while peers were waiting
+/// to be connected to, so slots can be reclaimed for them.
+pub struct Governor {
+    /// Torrents which had peers waiting while at their peer limit
+    starved: UHashSet,
+    /// Whether peers were waiting while at the global connection limit
+    full: bool,
+}
+
+impl Governor {
+    pub fn new() -> Governor {
+        Governor {
+            starved: UHashSet::default(),
+            full: false,
+        }
+    }
+
+    pub fn starve(&mut self, tid: usize) {
+        self.starved.insert(tid);
+    }
+
+    pub fn set_full(&mut self) {
+        self.full = true;
+    }
+
+    pub fn take_starved(&mut self) -> UHashSet {
+        mem::take(&mut self.starved)
+    }
+
+    pub fn take_full(&mut self) -> bool {
+        mem::replace(&mut self.full, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let mut gov = Governor::new();
+        gov.starve(1);
+        gov.starve(1);
+        gov.set_full();
+        assert_eq!(gov.take_starved().len(), 1);
+        assert!(gov.take_starved().is_empty());
+        assert!(gov.take_full());
+        assert!(!gov.take_full());
+    }
+}
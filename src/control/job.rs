@@ -3,6 +3,7 @@ use std::net::SocketAddr;
 use std::time;
 
 use crate::control::cio;
+use crate::rpc::resource::PeerSource;
 use crate::torrent::Torrent;
 use crate::util::UHashMap;
 
@@ -16,6 +17,8 @@ impl<T: cio::CIO> Job<T> for TrackerUpdate {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
         for (_, torrent) in torrents.iter_mut() {
             torrent.try_update_tracker();
+            torrent.try_dht_announce();
+            torrent.try_scrape();
         }
     }
 }
@@ -30,27 +33,52 @@ impl<T: cio::CIO> Job<T> for UnchokeUpdate {
     }
 }
 
-pub struct SessionUpdate;
+pub struct RetryUpdate;
 
-impl<T: cio::CIO> Job<T> for SessionUpdate {
+impl<T: cio::CIO> Job<T> for RetryUpdate {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
         for (_, torrent) in torrents.iter_mut() {
-            if torrent.dirty() {
-                torrent.serialize();
-            }
+            torrent.try_retry();
+        }
+    }
+}
+
+pub struct StallUpdate;
+
+impl<T: cio::CIO> Job<T> for StallUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        let now = time::Instant::now();
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.check_stalled(now);
+        }
+    }
+}
+
+pub struct SeedUpdate {
+    secs: u64,
+}
+
+impl SeedUpdate {
+    pub fn new(secs: u64) -> SeedUpdate {
+        SeedUpdate { secs }
+    }
+}
+
+impl<T: cio::CIO> Job<T> for SeedUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.update_seeding(self.secs);
         }
     }
 }
 
 pub struct TorrentTxUpdate {
-    piece_update: time::Instant,
     active: UHashMap<bool>,
 }
 
 impl TorrentTxUpdate {
     pub fn new() -> TorrentTxUpdate {
         TorrentTxUpdate {
-            piece_update: time::Instant::now(),
             active: UHashMap::default(),
         }
     }
@@ -63,11 +91,6 @@ impl<T: cio::CIO> Job<T> for TorrentTxUpdate {
             if active {
                 torrent.update_rpc_transfer();
                 torrent.update_rpc_peers();
-                // TODO: consider making tick triggered by on the fly validation
-                if self.piece_update.elapsed() > time::Duration::from_secs(30) {
-                    torrent.rpc_update_pieces();
-                    self.piece_update = time::Instant::now();
-                }
             }
             if !torrent.complete() {
                 torrent.rank_peers();
@@ -79,7 +102,12 @@ impl<T: cio::CIO> Job<T> for TorrentTxUpdate {
             let prev = self.active.get_mut(id).unwrap();
             if *prev != active {
                 *prev = active;
-                torrent.announce_status();
+                if active {
+                    torrent.announce_status();
+                } else {
+                    // Clear the rates and estimates of the last update
+                    torrent.update_rpc_transfer();
+                }
             }
         }
         self.active.retain(|id, _| torrents.contains_key(id));
@@ -100,7 +128,10 @@ impl PEXUpdate {
 
 impl<T: cio::CIO> Job<T> for PEXUpdate {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
-        for (id, torrent) in torrents.iter_mut().filter(|&(_, ref t)| !t.info().private) {
+        for (id, torrent) in torrents
+            .iter_mut()
+            .filter(|&(_, ref t)| t.allows_source(PeerSource::Pex))
+        {
             if !self.peers.contains_key(id) {
                 self.peers.insert(*id, HashSet::new());
             }
@@ -116,6 +147,11 @@ impl<T: cio::CIO> Job<T> for PEXUpdate {
             };
             torrent.update_pex(&added, &removed);
         }
-        self.peers.retain(|id, _| torrents.contains_key(id));
+        self.peers.retain(|id, _| {
+            torrents
+                .get(id)
+                .map(|t| t.allows_source(PeerSource::Pex))
+                .unwrap_or(false)
+        });
     }
 }
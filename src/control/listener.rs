@@ -0,0 +1,154 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::util::{MHashMap, UHashMap};
+
+/// Time an incoming connection has to complete its handshake
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+/// Number of connections an address may open in quick succession
+/// before being held to the accept rate
+const ACCEPT_BURST: f64 = 5.;
+
+/// Screens incoming connections, limiting how many may be handshaking
+/// at once and how quickly a single address may open them, and tracks
+/// when those handshaking must complete by.
+pub struct Listener {
+    /// Deadlines of connections which haven't completed a handshake
+    pending: UHashMap<Instant>,
+    /// Connections left to each address which recently opened some
+    allowance: MHashMap<IpAddr, Allowance>,
+    max_half_open: usize,
+    /// Connections accepted per address per minute
+    rate: u32,
+}
+
+struct Allowance {
+    left: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    /// Too many connections are handshaking
+    HalfOpen,
+    /// The address is opening connections too quickly
+    Rate,
+}
+
+impl Listener {
+    pub fn new(max_half_open: usize, rate: u32) -> Listener {
+        Listener {
+            pending: UHashMap::default(),
+            allowance: MHashMap::default(),
+            max_half_open,
+            rate,
+        }
+    }
+
+    /// Checks whether a connection from an address may be accepted,
+    /// using up some of the address' allowance if so.
+    pub fn accept(&mut self, ip: IpAddr, now: Instant) -> Result<(), Refusal> {
+        if self.pending.len() >= self.max_half_open {
+            return Err(Refusal::HalfOpen);
+        }
+        let rate = self.rate;
+        let a = self.allowance.entry(ip).or_insert(Allowance {
+            left: ACCEPT_BURST,
+            updated: now,
+        });
+        a.refill(rate, now);
+        if a.left < 1. {
+            return Err(Refusal::Rate);
+        }
+        a.left -= 1.;
+        Ok(())
+    }
+
+    /// Records an accepted connection as handshaking.
+    pub fn add(&mut self, pid: usize, now: Instant) {
+        let deadline = now + Duration::from_secs(HANDSHAKE_TIMEOUT_SECS);
+        self.pending.insert(pid, deadline);
+    }
+
+    /// Stops tracking a connection, returning whether it was handshaking.
+    pub fn remove(&mut self, pid: usize) -> bool {
+        self.pending.remove(&pid).is_some()
+    }
+
+    /// Removes and returns the connections which missed their deadline,
+    /// and forgets addresses which regained their full allowance.
+    pub fn expire(&mut self, now: Instant) -> Vec<usize> {
+        let rate = self.rate;
+        self.allowance.retain(|_, a| {
+            a.refill(rate, now);
+            a.left < ACCEPT_BURST
+        });
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|&(_, &deadline)| deadline <= now)
+            .map(|(&pid, _)| pid)
+            .collect();
+        for pid in &expired {
+            self.pending.remove(pid);
+        }
+        expired
+    }
+
+    /// Connections which haven't completed a handshake.
+    pub fn pending(&self) -> impl Iterator<Item = &usize> {
+        self.pending.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl Allowance {
+    fn refill(&mut self, rate: u32, now: Instant) {
+        let secs = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.left = (self.left + secs * f64::from(rate) / 60.).min(ACCEPT_BURST);
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_rate() {
+        let mut l = Listener::new(100, 6);
+        let now = Instant::now();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        for _ in 0..5 {
+            assert_eq!(l.accept(ip, now), Ok(()));
+        }
+        assert_eq!(l.accept(ip, now), Err(Refusal::Rate));
+        assert_eq!(l.accept("1.2.3.5".parse().unwrap(), now), Ok(()));
+        // One connection is allowed every 10 seconds
+        let later = now + Duration::from_secs(10);
+        assert_eq!(l.accept(ip, later), Ok(()));
+        assert_eq!(l.accept(ip, later), Err(Refusal::Rate));
+        // Addresses are forgotten once their allowance is full again
+        l.expire(now + Duration::from_secs(60));
+        assert!(l.allowance.is_empty());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut l = Listener::new(2, 60);
+        let now = Instant::now();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        l.add(0, now);
+        l.add(1, now + Duration::from_secs(5));
+        assert_eq!(l.accept(ip, now), Err(Refusal::HalfOpen));
+        assert!(l.expire(now + Duration::from_secs(9)).is_empty());
+        assert_eq!(l.expire(now + Duration::from_secs(10)), vec![0]);
+        assert!(!l.remove(0));
+        assert!(l.remove(1));
+        assert_eq!(l.len(), 0);
+        assert_eq!(l.accept(ip, now), Ok(()));
+    }
+}
@@ -1,29 +1,43 @@
 use std::io::Read;
-use std::net::TcpStream;
-use std::path::PathBuf;
+use std::net::{IpAddr, TcpStream};
+use std::path::{Path, PathBuf};
 use std::sync::atomic;
 use std::{fs, io, mem, process, time};
 
-use chrono::Utc;
+use chrono::{DateTime, TimeZone, Utc};
 
+use crate::rpc::proto::message::{Directory, Resolution, Sample};
+use crate::rpc::resource::PeerSource;
 use crate::throttle::Throttler;
 use crate::torrent::{self, peer, Torrent};
 use crate::util::{
     self, hash_to_id, id_to_hash, io_err, io_err_val, random_string, FHashSet, MHashMap, UHashMap,
-    UHashSet,
 };
-use crate::{disk, rpc, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
+use crate::{
+    buffers, config, disk, geoip, rpc, session, stat, tracker, CONFIG, DL_TOKEN, LISTEN_PORT,
+    NET_DOWN, NET_STOPPED, RELOAD, SHUTDOWN,
+};
 
 pub mod acio;
 pub mod cio;
+mod collision;
+mod dialer;
+mod external;
+mod governor;
 mod job;
+mod listener;
+pub mod oneshot;
+mod placement;
+mod sanitizer;
 
 /// Tracker update job interval
 const TRK_JOB_SECS: u64 = 60;
 /// Unchoke rotation job interval
 const UNCHK_JOB_SECS: u64 = 15;
-/// Session serialization job interval
+/// Session serialization job interval, only changed torrents are saved
 const SES_JOB_SECS: u64 = 60;
+/// Interval between snapshots of every torrent
+const SNAPSHOT_JOB_SECS: u64 = 60 * 15;
 /// Interval to update RPC of transfer stats
 const TX_JOB_MS: u64 = 500;
 /// Interval to check space on disk
@@ -32,39 +46,93 @@ const SPACE_JOB_SECS: u64 = 10;
 const PEX_JOB_SECS: u64 = 60 * 5;
 /// Interval to enqueue new torrents
 const ENQUEUE_JOB_SECS: u64 = 5;
+/// Interval to check seeding goals
+const SEED_JOB_SECS: u64 = 10;
+/// Interval to retry torrents stopped by transient disk errors
+const RETRY_JOB_SECS: u64 = 5;
+/// Interval to check for stalled torrents
+const STALL_JOB_SECS: u64 = 10;
+/// Interval to record transfer history
+const HIST_JOB_SECS: u64 = 60;
+/// Interval to enforce connection limits
+const GOV_JOB_SECS: u64 = 5;
+/// Interval to release unused buffers and report buffer use
+const BUF_JOB_SECS: u64 = 30;
+/// Interval to report disk use
+const DISK_STATS_JOB_SECS: u64 = 10;
+/// Interval to check that the interface sockets are bound to is up
+const BIND_JOB_SECS: u64 = 2;
+/// Time an outgoing connection has to complete its handshake
+const HALF_OPEN_TIMEOUT_SECS: u64 = 20;
+/// Maximum number of peers disconnected to free slots per governor run
+const RECLAIM_GOAL: usize = 20;
 
 /// Interval to requery all jobs and execute if needed
 const JOB_INT_MS: usize = 500;
+/// Interval to check for peers whose IPv4 address is due to be dialed
+/// while their IPv6 address is
+const RACE_INT_MS: usize = 50;
 
 pub struct Control<T: cio::CIO> {
     throttler: Throttler,
     cio: T,
     tid_cnt: usize,
     job_timer: usize,
-    stat: stat::EMA,
+    race_timer: usize,
+    stat: stat::Rates,
+    history: stat::History,
+    /// Piece data and protocol overhead transferred this session by
+    /// torrents which have since been removed
+    removed_tx: stat::Transfer,
     jobs: JobManager<T>,
     torrents: UHashMap<Torrent<T>>,
     queue: Queue,
     peers: UHashMap<usize>,
-    incoming: UHashSet,
+    listener: listener::Listener,
     hash_idx: MHashMap<[u8; 20], usize>,
     data: ServerData,
+    labels: Vec<rpc::resource::Label>,
+    throttle_groups: Vec<rpc::resource::ThrottleGroup>,
     db: amy::Sender<disk::Request>,
+    governor: governor::Governor,
+    dialer: dialer::Dialer,
+    sanitizer: sanitizer::Sanitizer,
+    external: external::External,
+    /// Directory being moved, of which there may only be one at a time
+    moving: Option<DirMove>,
+    /// Disk use by kind of job, as last reported by the disk thread
+    disk_jobs: Vec<rpc::resource::DiskJobStats>,
+    /// Files the disk thread has written to but not synced
+    dirty_files: u64,
+    /// Tracker DNS lookups, as last reported by the tracker thread
+    dns: rpc::resource::DnsStats,
+    placer: placement::Placer,
+    /// Free space of storage profile directories, as last reported by
+    /// the disk thread
+    profile_space: MHashMap<String, u64>,
+}
+
+struct DirMove {
+    client: usize,
+    serial: u64,
+    /// Torrents whose data is being moved along with the download directory
+    torrents: Vec<usize>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
 struct ServerData {
     id: String,
     ul: u64,
     dl: u64,
-    #[serde(skip)]
     session_ul: u64,
-    #[serde(skip)]
     session_dl: u64,
-    #[serde(skip)]
     free_space: u64,
     throttle_ul: Option<i64>,
     throttle_dl: Option<i64>,
+    /// Uptime of all previous sessions, in seconds
+    uptime: u64,
+    started: DateTime<Utc>,
+    /// Time the last session snapshot was saved
+    last_snapshot: Option<DateTime<Utc>>,
 }
 
 struct Queue {
@@ -95,7 +163,6 @@ impl<T: cio::CIO> Control<T> {
     ) -> io::Result<Control<T>> {
         let torrents = UHashMap::default();
         let peers = UHashMap::default();
-        let incoming = UHashSet::default();
         let hash_idx = MHashMap::default();
         let mut jobs = JobManager::new();
 
@@ -104,7 +171,6 @@ impl<T: cio::CIO> Control<T> {
             job::UnchokeUpdate,
             time::Duration::from_secs(UNCHK_JOB_SECS),
         );
-        jobs.add_job(job::SessionUpdate, time::Duration::from_secs(SES_JOB_SECS));
         jobs.add_job(
             job::TorrentTxUpdate::new(),
             time::Duration::from_millis(TX_JOB_MS),
@@ -113,36 +179,95 @@ impl<T: cio::CIO> Control<T> {
             job::PEXUpdate::new(),
             time::Duration::from_secs(PEX_JOB_SECS),
         );
+        jobs.add_job(
+            job::SeedUpdate::new(SEED_JOB_SECS),
+            time::Duration::from_secs(SEED_JOB_SECS),
+        );
+        jobs.add_job(job::RetryUpdate, time::Duration::from_secs(RETRY_JOB_SECS));
+        jobs.add_job(job::StallUpdate, time::Duration::from_secs(STALL_JOB_SECS));
 
         jobs.add_cjob(SpaceUpdate, time::Duration::from_secs(SPACE_JOB_SECS));
         jobs.add_cjob(EnqueueUpdate, time::Duration::from_secs(ENQUEUE_JOB_SECS));
-        jobs.add_cjob(SerializeUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        jobs.add_cjob(
+            SerializeUpdate::new(),
+            time::Duration::from_secs(SES_JOB_SECS),
+        );
+        jobs.add_cjob(HistoryUpdate, time::Duration::from_secs(HIST_JOB_SECS));
+        jobs.add_cjob(GovernorUpdate, time::Duration::from_secs(GOV_JOB_SECS));
+        jobs.add_cjob(
+            BufferUpdate::default(),
+            time::Duration::from_secs(BUF_JOB_SECS),
+        );
+        jobs.add_cjob(BindUpdate, time::Duration::from_secs(BIND_JOB_SECS));
+        jobs.add_cjob(
+            DiskStatsUpdate,
+            time::Duration::from_secs(DISK_STATS_JOB_SECS),
+        );
+        if let Some(timeout) = util::systemd::watchdog() {
+            jobs.add_cjob(WatchdogUpdate, timeout / 2);
+        }
         let job_timer = cio
             .set_timer(JOB_INT_MS)
             .map_err(|_| io_err_val("timer failure!"))?;
+        let race_timer = cio
+            .set_timer(RACE_INT_MS)
+            .map_err(|_| io_err_val("timer failure!"))?;
         Ok(Control {
             throttler,
             cio,
             tid_cnt: 0,
             job_timer,
+            race_timer,
             jobs,
             torrents,
             peers,
-            incoming,
+            listener: listener::Listener::new(
+                CONFIG.peer.max_half_open_incoming,
+                CONFIG.peer.accept_rate,
+            ),
             hash_idx,
-            stat: stat::EMA::new(),
-            data: Default::default(),
+            stat: stat::Rates::new(),
+            history: stat::History::new(),
+            removed_tx: stat::Transfer::default(),
+            data: ServerData::new(),
+            labels: Vec::new(),
+            throttle_groups: Vec::new(),
             db,
             queue: Queue::new(),
+            governor: governor::Governor::new(),
+            dialer: dialer::Dialer::new(),
+            sanitizer: sanitizer::Sanitizer::new(),
+            external: external::External::new(),
+            moving: None,
+            disk_jobs: Vec::new(),
+            dirty_files: 0,
+            dns: rpc::resource::DnsStats::default(),
+            placer: placement::Placer::new(&CONFIG.disk.profiles),
+            profile_space: MHashMap::default(),
         })
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self, oneshot: Option<oneshot::OneShot>) {
         if self.deserialize().is_err() {
             error!("Session deserialization failed!");
         }
+        if let Some(o) = oneshot {
+            self.add_oneshot(o);
+        }
+        // Configured rate limits replace those set over RPC
+        if let Some(rate) = CONFIG.net.throttle_up {
+            self.data.throttle_ul = Some(rate);
+            self.throttler.set_ul_rate(Some(rate));
+        }
+        if let Some(rate) = CONFIG.net.throttle_down {
+            self.data.throttle_dl = Some(rate);
+            self.throttler.set_dl_rate(Some(rate));
+        }
         debug!("Initialized!");
+        // Profile directories are placed in by their free space
+        self.cio.msg_disk(disk::Request::FreeSpace);
         self.send_rpc_info();
+        util::systemd::notify("READY=1");
         let mut events = Vec::with_capacity(20);
         'outer: loop {
             if let Err(e) = self.cio.poll(&mut events) {
@@ -157,16 +282,32 @@ impl<T: cio::CIO> Control<T> {
             if SHUTDOWN.load(atomic::Ordering::SeqCst) {
                 break;
             }
+            if RELOAD.swap(false, atomic::Ordering::SeqCst) {
+                util::systemd::notify_reloading();
+                if let Err(e) = self.reload_config() {
+                    error!("Failed to reload config: {}", e);
+                }
+                util::systemd::notify("READY=1");
+            }
         }
-        self.serialize();
+        self.serialize(true);
     }
 
-    fn serialize(&mut self) {
-        let sd = &CONFIG.disk.session;
+    /// Saves the server session and snapshots of all torrents which changed
+    /// since they were last saved, or every torrent if `full` is set.
+    fn serialize(&mut self, full: bool) {
         debug!("Serializing server data!");
-        let mut path = PathBuf::from(sd);
-        path.push("syn_data");
-        match bincode::serialize(&self.data) {
+        let path = PathBuf::from("syn_data");
+        let session = session::server::Session {
+            id: self.data.id.clone(),
+            ul: self.data.ul,
+            dl: self.data.dl,
+            throttle_ul: self.data.throttle_ul,
+            throttle_dl: self.data.throttle_dl,
+            uptime: self.data.total_uptime(),
+            network_stopped: NET_STOPPED.load(atomic::Ordering::Relaxed),
+        };
+        match bincode::serialize(&session) {
             Ok(data) => {
                 self.db.send(disk::Request::WriteFile { path, data }).ok();
             }
@@ -174,9 +315,89 @@ impl<T: cio::CIO> Control<T> {
                 error!("Failed to serialize server data");
             }
         }
+        self.serialize_labels();
+        self.serialize_throttle_groups();
+        self.serialize_history();
         debug!("Serializing torrents!");
-        for torrent in self.torrents.values_mut() {
-            torrent.serialize();
+        let snapshots: Vec<_> = self
+            .torrents
+            .values_mut()
+            .filter(|t| full || t.dirty())
+            .map(|t| t.snapshot())
+            .collect();
+        self.cio.msg_disk(disk::Request::serialize_batch(snapshots));
+    }
+
+    fn serialize_history(&mut self) {
+        let path = PathBuf::from("syn_history");
+        let series = |s: &stat::Series| {
+            s.samples()
+                .map(|s| session::history::Sample {
+                    time: s.time,
+                    ul: s.ul,
+                    dl: s.dl,
+                })
+                .collect()
+        };
+        let history = session::history::Session {
+            minutes: series(&self.history.minutes),
+            hours: series(&self.history.hours),
+            days: series(&self.history.days),
+        };
+        match bincode::serialize(&history) {
+            Ok(data) => {
+                self.db.send(disk::Request::WriteFile { path, data }).ok();
+            }
+            Err(_) => {
+                error!("Failed to serialize transfer history");
+            }
+        }
+    }
+
+    fn serialize_labels(&mut self) {
+        let path = PathBuf::from("syn_labels");
+        let labels: Vec<_> = self
+            .labels
+            .iter()
+            .cloned()
+            .map(|l| session::labels::Label {
+                name: l.name,
+                path: l.path,
+                throttle_ul: l.throttle_up,
+                throttle_dl: l.throttle_down,
+                seed_ratio: l.seed_ratio,
+                seed_time: l.seed_time,
+            })
+            .collect();
+        match bincode::serialize(&labels) {
+            Ok(data) => {
+                self.db.send(disk::Request::WriteFile { path, data }).ok();
+            }
+            Err(_) => {
+                error!("Failed to serialize labels");
+            }
+        }
+    }
+
+    fn serialize_throttle_groups(&mut self) {
+        let path = PathBuf::from("syn_throttle_groups");
+        let groups: Vec<_> = self
+            .throttle_groups
+            .iter()
+            .cloned()
+            .map(|g| session::throttle_groups::ThrottleGroup {
+                name: g.name,
+                throttle_ul: g.throttle_up,
+                throttle_dl: g.throttle_down,
+            })
+            .collect();
+        match bincode::serialize(&groups) {
+            Ok(data) => {
+                self.db.send(disk::Request::WriteFile { path, data }).ok();
+            }
+            Err(_) => {
+                error!("Failed to serialize throttle groups");
+            }
         }
     }
 
@@ -185,8 +406,22 @@ impl<T: cio::CIO> Control<T> {
         debug!("Deserializing server data!");
         let mut pb = PathBuf::from(sd);
         pb.push("syn_data");
-        if let Ok(Ok(data)) = fs::File::open(pb).map(|mut f| bincode::deserialize_from(&mut f)) {
-            self.data = data;
+        let mut data = Vec::new();
+        let session = fs::File::open(pb)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .ok()
+            .and_then(|_| session::server::load(&data));
+        if let Some(s) = session {
+            self.data.id = s.id;
+            self.data.ul = s.ul;
+            self.data.dl = s.dl;
+            self.data.throttle_ul = s.throttle_ul;
+            self.data.throttle_dl = s.throttle_dl;
+            self.data.uptime = s.uptime;
+            if s.network_stopped {
+                info!("Network activity was stopped by a client, keeping it stopped");
+                NET_STOPPED.store(true, atomic::Ordering::Relaxed);
+            }
             self.throttler.set_ul_rate(self.data.throttle_ul);
             self.throttler.set_dl_rate(self.data.throttle_dl);
         } else {
@@ -194,6 +429,73 @@ impl<T: cio::CIO> Control<T> {
             self.data = ServerData::new();
         }
 
+        debug!("Deserializing labels!");
+        let mut pb = PathBuf::from(sd);
+        pb.push("syn_labels");
+        let mut data = Vec::new();
+        if fs::File::open(pb)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .is_ok()
+        {
+            self.labels = session::labels::load(&data)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|l| rpc::resource::Label {
+                    name: l.name,
+                    path: l.path,
+                    throttle_up: l.throttle_ul,
+                    throttle_down: l.throttle_dl,
+                    seed_ratio: l.seed_ratio,
+                    seed_time: l.seed_time,
+                })
+                .collect();
+        }
+
+        debug!("Deserializing throttle groups!");
+        let mut pb = PathBuf::from(sd);
+        pb.push("syn_throttle_groups");
+        let mut data = Vec::new();
+        if fs::File::open(pb)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .is_ok()
+        {
+            self.throttle_groups = session::throttle_groups::load(&data)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|g| rpc::resource::ThrottleGroup {
+                    name: g.name,
+                    throttle_up: g.throttle_ul,
+                    throttle_down: g.throttle_dl,
+                })
+                .collect();
+            for g in &self.throttle_groups {
+                self.throttler
+                    .set_group(&g.name, g.throttle_up, g.throttle_down);
+            }
+        }
+
+        debug!("Deserializing transfer history!");
+        let mut pb = PathBuf::from(sd);
+        pb.push("syn_history");
+        let mut data = Vec::new();
+        if fs::File::open(pb)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .is_ok()
+        {
+            if let Some(h) = session::history::load(&data) {
+                let samples = |v: Vec<session::history::Sample>| {
+                    v.into_iter().map(|s| stat::Sample {
+                        time: s.time,
+                        ul: s.ul,
+                        dl: s.dl,
+                    })
+                };
+                self.history.minutes.load(samples(h.minutes));
+                self.history.hours.load(samples(h.hours));
+                self.history.days.load(samples(h.days));
+            }
+        }
+
         debug!("Deserializing torrents!");
         for entry in fs::read_dir(sd)? {
             if self.deserialize_torrent(entry).is_err() {
@@ -204,6 +506,7 @@ impl<T: cio::CIO> Control<T> {
                 process::exit(1);
             }
         }
+        self.check_all_collisions();
         Ok(())
     }
 
@@ -222,12 +525,12 @@ impl<T: cio::CIO> Control<T> {
 
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
-        if let Some(t) = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle()) {
+        if let Some(mut t) = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle()) {
             trace!("Succesfully parsed torrent file {:?}", dir.path());
             self.hash_idx.insert(t.info().hash, tid);
             self.tid_cnt += 1;
             if t.status().leeching() {
-                self.queue.add(tid, t.priority());
+                t.set_queued(self.queue.add(tid, t.priority()));
             }
             self.torrents.insert(tid, t);
         } else {
@@ -272,11 +575,17 @@ impl<T: cio::CIO> Control<T> {
                     self.data.session_dl += dl;
                     self.stat.add_ul(ul);
                     self.stat.add_dl(dl);
+                    self.history.add_ul(ul);
+                    self.history.add_dl(dl);
                 } else if t == self.throttler.fid() {
                     self.flush_blocked_peers();
                 } else if t == self.job_timer {
                     self.update_jobs();
                     self.update_rpc_tx();
+                } else if t == self.race_timer {
+                    if self.dialer.fallback_due(time::Instant::now()) {
+                        self.connect_pending();
+                    }
                 } else {
                     error!("unknown timer id {} reported", t);
                 }
@@ -289,13 +598,16 @@ impl<T: cio::CIO> Control<T> {
     }
 
     fn handle_trk_ev(&mut self, tr: tracker::Response) {
-        let (id, peers) = match tr {
+        let (id, peers, source) = match tr {
             tracker::Response::Tracker { tid, url, resp } => {
                 debug!("Handling tracker response for {:?}", url);
                 if let Some(torrent) = self.torrents.get_mut(&tid) {
                     torrent.set_tracker_response(url.as_ref(), &resp);
                     if let Ok(r) = resp {
-                        (tid, r.peers)
+                        if let Some(ip) = r.external_ip {
+                            self.add_external_ip(ip, None);
+                        }
+                        (tid, r.peers, PeerSource::Tracker)
                     } else {
                         return;
                     }
@@ -303,20 +615,98 @@ impl<T: cio::CIO> Control<T> {
                     return;
                 }
             }
-            tracker::Response::DHT { tid, peers } | tracker::Response::PEX { tid, peers } => {
-                (tid, peers)
+            tracker::Response::Scrape { tid, url, resp } => {
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.set_scrape_response(url.as_ref(), &resp);
+                }
+                return;
+            }
+            tracker::Response::DHT { tid, peers } => (tid, peers, PeerSource::Dht),
+            tracker::Response::PEX { tid, peers } => (tid, peers, PeerSource::Pex),
+            tracker::Response::DHTItem {
+                client,
+                serial,
+                target,
+                item,
+            } => {
+                self.cio.msg_rpc(rpc::CtlMessage::DhtItem {
+                    client,
+                    serial,
+                    target,
+                    item,
+                });
+                return;
             }
-        };
-        for ip in &peers {
-            trace!("Adding peer({:?})!", ip);
-            match peer::PeerConn::new_outgoing(ip) {
-                Ok(peer) => {
-                  trace!("Added peer({:?})!", ip);
-                  self.add_peer(id, peer);
+            tracker::Response::ExternalIp { ip, voter } => {
+                self.add_external_ip(ip, voter);
+                return;
+            }
+            tracker::Response::DnsStats(stats) => {
+                self.dns = rpc::resource::DnsStats {
+                    hits: stats.hits,
+                    misses: stats.misses,
+                    cached: stats.cached,
+                };
+                self.update_rpc_dns();
+                return;
+            }
+            tracker::Response::DualStack { tid, v4, v6 } => {
+                // The other address was exchanged by the peer
+                match self.torrents.get(&tid) {
+                    Some(t) if t.allows_source(PeerSource::Pex) => {}
+                    _ => return,
+                }
+                if self.sanitizer.check(&v4) && self.sanitizer.check(&v6) {
+                    self.dialer.link(tid, v4, v6, PeerSource::Pex);
+                } else {
+                    self.update_rpc_filtered();
+                }
+                return;
+            }
+            tracker::Response::Listening { tid, pid, addr } => {
+                // The peer may have disconnected since
+                if self.peers.get(&pid) != Some(&tid) {
+                    return;
+                }
+                if self.sanitizer.check(&addr) {
+                    self.dialer.accepted(tid, addr, pid);
+                } else {
+                    self.update_rpc_filtered();
                 }
-                Err(e) => { trace!("Failed to add peer: {:?}", e); }
+                return;
+            }
+        };
+        match self.torrents.get(&id) {
+            Some(t) if t.allows_source(source) => {}
+            _ => return,
+        }
+        let mut filtered = false;
+        for ip in peers {
+            if self.sanitizer.check(&ip) {
+                self.dialer.add(id, ip, source);
+            } else {
+                filtered = true;
             }
         }
+        if filtered {
+            self.update_rpc_filtered();
+        }
+        self.connect_pending();
+    }
+
+    /// Records our external address, if a voter reported it only once
+    /// enough voters agree.
+    fn add_external_ip(&mut self, ip: IpAddr, voter: Option<IpAddr>) {
+        let changed = match voter {
+            Some(voter) => self.external.vote(voter, ip),
+            None => self.external.report(ip),
+        };
+        if voter.is_none() || changed {
+            self.sanitizer.add_own(ip);
+        }
+        if changed {
+            self.update_rpc_external_ip();
+        }
     }
 
     fn update_jobs(&mut self) {
@@ -327,21 +717,195 @@ impl<T: cio::CIO> Control<T> {
 
     fn handle_disk_ev(&mut self, resp: disk::Response) {
         trace!("Got disk response {:?}!", resp);
-        if let disk::Response::FreeSpace(space) = resp {
+        if let disk::Response::FreeSpace { space, profiles } = resp {
+            self.profile_space = profiles.into_iter().collect();
             if space / 1_000_000 != self.data.free_space / 1_000_000 {
+                if space > self.data.free_space {
+                    for torrent in self.torrents.values_mut() {
+                        torrent.space_freed();
+                    }
+                }
                 self.data.free_space = space;
                 self.update_rpc_space();
             }
+        } else if let disk::Response::Serialized { failed } = resp {
+            if failed == 0 {
+                self.data.last_snapshot = Some(Utc::now());
+                self.update_rpc_snapshot();
+            } else {
+                error!("Failed to save {} torrent snapshots", failed);
+            }
+        } else if let disk::Response::DirectoryMoving {
+            directory,
+            progress,
+        } = resp
+        {
+            if let Some(ref m) = self.moving {
+                self.cio.msg_rpc(rpc::CtlMessage::DirectoryMoving {
+                    client: m.client,
+                    serial: m.serial,
+                    directory,
+                    progress,
+                });
+            }
+        } else if let disk::Response::DirectoryMoved { directory, result } = resp {
+            if let Some(m) = self.moving.take() {
+                self.directory_moved(m, directory, result);
+            }
+        } else if let disk::Response::Stats {
+            torrents,
+            jobs,
+            dirty_files,
+        } = resp
+        {
+            self.update_disk_stats(torrents, jobs, dirty_files);
         } else if let Some(torrent) = self.torrents.get_mut(&resp.tid()) {
+            let tid = resp.tid();
             torrent.handle_disk_resp(resp);
+            self.check_relocated(tid);
+        }
+    }
+
+    fn update_disk_stats(
+        &mut self,
+        torrents: Vec<(usize, disk::IoStats)>,
+        jobs: Vec<(&'static str, disk::IoStats)>,
+        dirty_files: u64,
+    ) {
+        for (tid, stats) in torrents {
+            if let Some(torrent) = self.torrents.get_mut(&tid) {
+                torrent.set_disk_stats(stats);
+            }
+        }
+        let mut jobs: Vec<_> = jobs
+            .into_iter()
+            .map(|(kind, stats)| rpc::resource::DiskJobStats {
+                kind: kind.to_owned(),
+                read: stats.read,
+                written: stats.written,
+                time: stats.time.as_millis() as u64,
+                runs: stats.runs,
+            })
+            .collect();
+        jobs.sort_by(|a, b| a.kind.cmp(&b.kind));
+        if jobs != self.disk_jobs || dirty_files != self.dirty_files {
+            self.disk_jobs = jobs;
+            self.dirty_files = dirty_files;
+            self.update_rpc_disk();
+        }
+    }
+
+    fn move_directory(
+        &mut self,
+        client: usize,
+        serial: u64,
+        directory: Directory,
+        path: String,
+    ) -> Result<(), String> {
+        if self.moving.is_some() {
+            return Err("A directory is already being moved".to_owned());
+        }
+        let to: String = shellexpand::tilde(&path).into();
+        let from = match directory {
+            Directory::Session => config::settings().session.clone(),
+            Directory::Download => config::settings().directory.clone(),
+        };
+        if to.is_empty() {
+            return Err("The path must not be empty".to_owned());
+        }
+        if Path::new(&to).starts_with(&from) {
+            return Err(format!("{} is within {}", to, from));
+        }
+        let mut torrents = Vec::new();
+        let mut pending = Vec::new();
+        if directory == Directory::Download {
+            // Every torrent stops writing to the directory until the move is
+            // done, so none are allowed to be in the middle of another job
+            for torrent in self.torrents.values() {
+                if torrent.data_in(&from).is_some() && torrent.busy() {
+                    return Err(format!("{} is being checked or moved", torrent.rpc_id()));
+                }
+            }
+            for (id, torrent) in &mut self.torrents {
+                if let Some(name) = torrent.data_in(&from).map(PathBuf::from) {
+                    torrents.push(*id);
+                    torrent.set_moving(true);
+                    if !name.as_os_str().is_empty() && !pending.contains(&name) {
+                        pending.push(name);
+                    }
+                }
+            }
         }
+        info!("Moving {:?} directory from {} to {}", directory, from, to);
+        self.cio.msg_disk(disk::Request::MoveDirectory {
+            directory,
+            from,
+            to,
+            torrents: torrents.clone(),
+            pending,
+            moved: Vec::new(),
+        });
+        self.moving = Some(DirMove {
+            client,
+            serial,
+            torrents,
+        });
+        Ok(())
+    }
+
+    fn directory_moved(&mut self, m: DirMove, directory: Directory, result: io::Result<String>) {
+        let msg = match result {
+            Ok(path) => {
+                info!("Moved {:?} directory to {}", directory, path);
+                for id in m.torrents {
+                    if let Some(torrent) = self.torrents.get_mut(&id) {
+                        torrent.set_moved(path.clone());
+                    }
+                    self.check_relocated(id);
+                }
+                rpc::CtlMessage::DirectoryMoved {
+                    client: m.client,
+                    serial: m.serial,
+                    directory,
+                    path,
+                }
+            }
+            Err(e) => {
+                for id in m.torrents {
+                    if let Some(torrent) = self.torrents.get_mut(&id) {
+                        torrent.set_moving(false);
+                    }
+                }
+                rpc::CtlMessage::Error {
+                    reason: format!("Failed to move directory: {}", e),
+                    client: m.client,
+                    serial: m.serial,
+                }
+            }
+        };
+        self.cio.msg_rpc(msg);
     }
 
     fn handle_incoming_conn(&mut self, conn: TcpStream) {
+        if util::net::down() {
+            return;
+        }
+        if self.connections() >= config::settings().max_open_sockets {
+            debug!("Refusing incoming connection, at connection limit");
+            self.governor.set_full();
+            return;
+        }
+        let now = time::Instant::now();
+        if let Ok(addr) = conn.peer_addr() {
+            if let Err(r) = self.listener.accept(addr.ip(), now) {
+                debug!("Refusing incoming connection from {}: {:?}", addr, r);
+                return;
+            }
+        }
         match peer::PeerConn::new_incoming(conn) {
             Ok(pconn) => match self.cio.add_peer(pconn) {
                 Ok(pid) => {
-                    self.incoming.insert(pid);
+                    self.listener.add(pid, now);
                 }
                 Err(e) => {
                     error!("Failed to add peer connection: {:?}", e);
@@ -360,7 +924,8 @@ impl<T: cio::CIO> Control<T> {
     ) -> Result<(), ()> {
         match ev {
             Ok(msg) => match msg {
-                torrent::Message::Handshake { hash, id, rsv } => {
+                torrent::Message::Handshake(hs) => {
+                    let (hash, id, rsv) = (hs.hash, hs.id, hs.rsv);
                     debug!("Adding peer for torrent with hash {:?}!", hash_to_id(&hash));
                     if let Some(tid) = self.hash_idx.get(&hash).cloned() {
                         return self.add_inc_peer(tid, pid, id, rsv);
@@ -385,13 +950,21 @@ impl<T: cio::CIO> Control<T> {
 
         if let Some(&tid) = p.get(&pid) {
             let t = &mut self.torrents;
+            if let Ok(torrent::Message::Handshake { .. }) = ev {
+                if let Some(loser) = self.dialer.connected(pid) {
+                    debug!("Peer {} connected first, closing {}", pid, loser);
+                    self.cio.remove_peer(loser);
+                }
+            }
             if let Some(torrent) = t.get_mut(&tid) {
                 if torrent.peer_ev(pid, ev).is_err() {
                     p.remove(&pid);
+                    self.dialer.closed(pid);
                     torrent.update_rpc_peers();
                 }
             }
-        } else if self.incoming.remove(&pid) {
+            self.check_relocated(tid);
+        } else if self.listener.remove(pid) {
             if self.inc_handshake(pid, ev).is_err() {
                 self.cio.remove_peer(pid);
             }
@@ -406,15 +979,38 @@ impl<T: cio::CIO> Control<T> {
     fn add_torrent(
         &mut self,
         info: torrent::Info,
-        path: Option<String>,
-        start: bool,
-        import: bool,
+        options: rpc::AddOptions,
         client: usize,
         serial: u64,
     ) {
+        let rpc::AddOptions {
+            path,
+            start,
+            import,
+            seed_only,
+            label,
+            name,
+            priorities,
+            merge,
+            metadata_only,
+        } = options;
         debug!("Adding {:?}, start: {}!", info, start);
         let id = hash_to_id(&info.hash);
-        if self.hash_idx.contains_key(&info.hash) {
+        let existing = self.hash_idx.get(&info.hash).cloned();
+        if let Some(t) = existing
+            .filter(|_| merge)
+            .and_then(|tid| self.torrents.get_mut(&tid))
+        {
+            let trackers = t.merge_trackers(&info);
+            self.cio.msg_rpc(rpc::CtlMessage::Merged {
+                id,
+                trackers,
+                client,
+                serial,
+            });
+            return;
+        }
+        if existing.is_some() {
             debug!("Tried to add torrent that already exists!");
             self.cio.msg_rpc(rpc::CtlMessage::Error {
                 client,
@@ -423,9 +1019,44 @@ impl<T: cio::CIO> Control<T> {
             });
             return;
         }
+        let files = info.user_files().count();
+        if !priorities.is_empty() && priorities.len() != files {
+            self.cio.msg_rpc(rpc::CtlMessage::Error {
+                client,
+                serial,
+                reason: format!(
+                    "Expected {} file priorities, got {}",
+                    files,
+                    priorities.len()
+                ),
+            });
+            return;
+        }
+        let labels = &self.labels;
+        let label = label.and_then(|l| labels.iter().find(|x| x.name == l));
+        let path = match path.or_else(|| label.and_then(|l| l.path.clone())) {
+            Some(path) => Some(path),
+            None => {
+                let name = label.map(|l| l.name.as_str());
+                let space = &self.profile_space;
+                let labelled: Vec<_> = self
+                    .torrents
+                    .values()
+                    .filter(|t| name.is_some() && t.label() == name)
+                    .filter_map(|t| t.path())
+                    .collect();
+                self.placer.place(
+                    &CONFIG.disk.profiles,
+                    info.total_len,
+                    name,
+                    &labelled,
+                    |dir| space.get(dir).copied(),
+                )
+            }
+        };
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
-        let t = Torrent::new(
+        let mut t = Torrent::new(
             tid,
             path,
             info,
@@ -433,11 +1064,25 @@ impl<T: cio::CIO> Control<T> {
             self.cio.new_handle(),
             start,
             import,
+            seed_only,
         );
+        if label.is_some() {
+            t.set_label(label);
+        }
+        if name.is_some() {
+            t.set_metadata(Some(name), None, None);
+        }
+        if !priorities.is_empty() {
+            t.set_file_priorities(priorities);
+        }
+        if metadata_only {
+            t.set_metadata_only();
+        }
         self.hash_idx.insert(t.info().hash, tid);
         self.tid_cnt += 1;
-        self.queue.add(tid, t.priority());
+        t.set_queued(self.queue.add(tid, t.priority()));
         self.torrents.insert(tid, t);
+        self.check_collisions(tid);
         self.cio
             .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial })
     }
@@ -445,13 +1090,27 @@ impl<T: cio::CIO> Control<T> {
     fn handle_rpc_ev(&mut self, req: rpc::Message) -> bool {
         debug!("Handling rpc reqest!");
         match req {
-            rpc::Message::UpdateTorrent(u) => {
-                let hash_idx = &self.hash_idx;
+            rpc::Message::UpdateTorrent(mut u) => {
+                let tid = id_to_hash(&u.id).and_then(|d| self.hash_idx.get(d.as_ref()).cloned());
+                let collision = match (tid, &u.path) {
+                    (Some(tid), Some(path)) => self.move_collision(tid, path),
+                    _ => None,
+                };
                 let torrents = &mut self.torrents;
-                let res = id_to_hash(&u.id)
-                    .and_then(|d| hash_idx.get(d.as_ref()))
-                    .and_then(|i| torrents.get_mut(i));
-                if let Some(t) = res {
+                if let Some(t) = tid.and_then(|i| torrents.get_mut(&i)) {
+                    if let Some((file, _)) = collision {
+                        // Moving would overwrite another torrent's data
+                        t.refuse_move(&file);
+                        u.path = None;
+                    }
+                    if let Some(ref label) = u.label {
+                        let labels = &self.labels;
+                        t.set_label(
+                            label
+                                .as_ref()
+                                .and_then(|l| labels.iter().find(|x| &x.name == l)),
+                        );
+                    }
                     let old_pri = t.priority();
                     t.rpc_update(u);
                     let new_pri = t.priority();
@@ -460,12 +1119,54 @@ impl<T: cio::CIO> Control<T> {
             }
             rpc::Message::Torrent {
                 info,
-                path,
-                start,
-                import,
+                options,
                 client,
                 serial,
-            } => self.add_torrent(info, path, start, import, client, serial),
+            } => self.add_torrent(*info, options, client, serial),
+            rpc::Message::SetLabel(label) => {
+                for t in self.torrents.values_mut() {
+                    if t.label() == Some(&label.name) {
+                        t.set_label(Some(&label));
+                    }
+                }
+                match self.labels.iter_mut().find(|l| l.name == label.name) {
+                    Some(l) => *l = label,
+                    None => self.labels.push(label),
+                }
+                self.update_rpc_labels();
+            }
+            rpc::Message::RemoveLabel(name) => {
+                for t in self.torrents.values_mut() {
+                    if t.label() == Some(&name) {
+                        t.set_label(None);
+                    }
+                }
+                self.labels.retain(|l| l.name != name);
+                self.update_rpc_labels();
+            }
+            rpc::Message::SetThrottleGroup(group) => {
+                self.throttler
+                    .set_group(&group.name, group.throttle_up, group.throttle_down);
+                match self
+                    .throttle_groups
+                    .iter_mut()
+                    .find(|g| g.name == group.name)
+                {
+                    Some(g) => *g = group,
+                    None => self.throttle_groups.push(group),
+                }
+                self.update_rpc_throttle_groups();
+            }
+            rpc::Message::RemoveThrottleGroup(name) => {
+                for t in self.torrents.values_mut() {
+                    if t.throttle_group() == Some(&name) {
+                        t.set_throttle_group(None);
+                    }
+                }
+                self.throttler.remove_group(&name);
+                self.throttle_groups.retain(|g| g.name != name);
+                self.update_rpc_throttle_groups();
+            }
             rpc::Message::UpdateFile {
                 id,
                 torrent_id,
@@ -545,20 +1246,63 @@ impl<T: cio::CIO> Control<T> {
                 throttle_up,
                 throttle_down,
             } => {
-                let tu = throttle_up.unwrap_or_else(|| self.throttler.ul_rate());
-                let td = throttle_down.unwrap_or_else(|| self.throttler.dl_rate());
-                self.throttler.set_ul_rate(tu);
-                self.throttler.set_dl_rate(td);
-                self.data.throttle_ul = tu;
-                self.data.throttle_dl = td;
-                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                    rpc::resource::SResourceUpdate::Throttle {
-                        id,
-                        kind: rpc::resource::ResourceKind::Server,
-                        throttle_up: tu,
-                        throttle_down: td,
+                self.set_throttle(id, throttle_up, throttle_down);
+            }
+            rpc::Message::GetSetting {
+                client,
+                serial,
+                key,
+            } => {
+                let msg = match self.get_setting(&key) {
+                    Some(value) => rpc::CtlMessage::Setting {
+                        client,
+                        serial,
+                        key,
+                        value,
+                    },
+                    None => rpc::CtlMessage::Error {
+                        reason: format!("Unknown setting {}", key),
+                        client,
+                        serial,
+                    },
+                };
+                self.cio.msg_rpc(msg);
+            }
+            rpc::Message::SetSetting {
+                client,
+                serial,
+                key,
+                value,
+            } => {
+                let msg = match self.set_setting(&key, &value) {
+                    Ok(()) => rpc::CtlMessage::Setting {
+                        client,
+                        serial,
+                        value: self.get_setting(&key).unwrap_or(value),
+                        key,
+                    },
+                    Err(reason) => rpc::CtlMessage::Error {
+                        reason,
+                        client,
+                        serial,
+                    },
+                };
+                self.cio.msg_rpc(msg);
+            }
+            rpc::Message::ReloadConfig { client, serial } => {
+                let msg = match self.reload_config() {
+                    Ok(changed) => rpc::CtlMessage::ConfigReloaded {
+                        client,
+                        serial,
+                        changed,
+                    },
+                    Err(e) => rpc::CtlMessage::Error {
+                        reason: format!("Failed to reload config: {}", e),
+                        client,
+                        serial,
                     },
-                ]));
+                };
+                self.cio.msg_rpc(msg);
             }
             rpc::Message::RemoveTorrent {
                 id,
@@ -568,12 +1312,16 @@ impl<T: cio::CIO> Control<T> {
             } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
+                let removed_tx = &mut self.removed_tx;
                 let cio = &mut self.cio;
                 let reason = format!("Torrent {} does not exist", id);
                 id_to_hash(&id)
                     .and_then(|d| hash_idx.remove(d.as_ref()))
                     .and_then(|i| torrents.remove(&i))
-                    .map(|mut t| t.delete(artifacts))
+                    .map(|mut t| {
+                        *removed_tx += t.transfer();
+                        t.delete(artifacts)
+                    })
                     .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
@@ -583,6 +1331,21 @@ impl<T: cio::CIO> Control<T> {
                         })
                     });
             }
+            rpc::Message::CancelJobs { kind, id } => {
+                let job = match kind {
+                    rpc::resource::ResourceKind::Server => disk::JobId::MoveDirectory,
+                    rpc::resource::ResourceKind::Torrent => {
+                        let tid = id_to_hash(&id).and_then(|d| self.hash_idx.get(d.as_ref()));
+                        if let Some(&tid) = tid {
+                            self.cio
+                                .msg_disk(disk::Request::Cancel(disk::JobId::Validate(tid)));
+                        }
+                        disk::JobId::Transfer(id)
+                    }
+                    _ => disk::JobId::Transfer(id),
+                };
+                self.cio.msg_disk(disk::Request::Cancel(job));
+            }
             rpc::Message::Pause(id) => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
@@ -593,25 +1356,48 @@ impl<T: cio::CIO> Control<T> {
                     t.pause()
                 }
             }
+            rpc::Message::StopNetwork(stop) => {
+                if stop != NET_STOPPED.load(atomic::Ordering::Relaxed) {
+                    let was_down = util::net::down();
+                    NET_STOPPED.store(stop, atomic::Ordering::Relaxed);
+                    if stop {
+                        info!("Network activity stopped by a client");
+                    } else {
+                        info!("Network activity started by a client");
+                    }
+                    self.network_changed(was_down);
+                    self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                        rpc::resource::SResourceUpdate::ServerNetwork {
+                            id: self.data.id.clone(),
+                            kind: rpc::resource::ResourceKind::Server,
+                            network_stopped: stop,
+                        },
+                    ]));
+                }
+            }
             rpc::Message::Resume(id) => {
-                let hash_idx = &mut self.hash_idx;
-                let torrents = &mut self.torrents;
-                if let Some(t) = id_to_hash(&id)
-                    .and_then(|d| hash_idx.get(d.as_ref()))
-                    .and_then(|i| torrents.get_mut(i))
-                {
-                    t.resume();
+                let tid = id_to_hash(&id).and_then(|d| self.hash_idx.get(d.as_ref()).cloned());
+                if let Some(tid) = tid.filter(|&tid| !self.still_collides(tid)) {
+                    if let Some(t) = self.torrents.get_mut(&tid) {
+                        t.resume();
+                    }
+                }
+            }
+            rpc::Message::Bulk(msgs) => {
+                for msg in msgs {
+                    if self.handle_rpc_ev(msg) {
+                        return true;
+                    }
                 }
             }
             rpc::Message::Validate(ids) => {
-                let hash_idx = &mut self.hash_idx;
-                let torrents = &mut self.torrents;
                 for id in ids {
-                    if let Some(t) = id_to_hash(&id)
-                        .and_then(|d| hash_idx.get(d.as_ref()))
-                        .and_then(|i| torrents.get_mut(i))
-                    {
-                        t.validate();
+                    let tid = id_to_hash(&id).and_then(|d| self.hash_idx.get(d.as_ref()).cloned());
+                    // Validating would clear the error, letting it download
+                    if let Some(tid) = tid.filter(|&tid| !self.still_collides(tid)) {
+                        if let Some(t) = self.torrents.get_mut(&tid) {
+                            t.validate();
+                        }
                     }
                 }
             }
@@ -671,9 +1457,181 @@ impl<T: cio::CIO> Control<T> {
                     t.update_tracker_req(&id);
                 }
             }
+            rpc::Message::Scrape { id } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                if let Some(t) = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                {
+                    t.scrape();
+                }
+            }
+            rpc::Message::Reannounce(id) => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                if let Some(t) = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                {
+                    t.reannounce();
+                }
+            }
+            rpc::Message::RefreshDht(id) => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                if let Some(t) = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                {
+                    t.dht_announce();
+                }
+            }
+            rpc::Message::SetPieceDeadline {
+                id,
+                offset,
+                deadline,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                if let Some(t) = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                {
+                    t.set_piece_deadline(offset, deadline);
+                }
+            }
+            rpc::Message::CrossSeed { id } => {
+                let source = id_to_hash(&id)
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .and_then(|i| self.torrents.get(i))
+                    .filter(|t| t.complete())
+                    .map(|t| (t.id(), t.file_paths()));
+                if let Some((sid, files)) = source {
+                    for (_, t) in self.torrents.iter_mut().filter(|&(&tid, _)| tid != sid) {
+                        if t.cross_seed(&files) {
+                            info!("Cross seeding {} from {}", t.rpc_id(), id);
+                        }
+                    }
+                }
+            }
+            rpc::Message::FindData { id, path } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                if let Some(t) = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                {
+                    t.find_data(path);
+                }
+            }
+            rpc::Message::MoveDirectory {
+                client,
+                serial,
+                directory,
+                path,
+            } => {
+                if let Err(reason) = self.move_directory(client, serial, directory, path) {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        reason,
+                        client,
+                        serial,
+                    });
+                }
+            }
+            rpc::Message::SetPort {
+                client,
+                serial,
+                port,
+            } => {
+                let msg = match self.cio.listen(port) {
+                    Ok(port) => {
+                        // Let trackers and the DHT know of the new port
+                        for torrent in self.torrents.values_mut() {
+                            torrent.update_tracker();
+                        }
+                        self.update_rpc_port(port);
+                        rpc::CtlMessage::PortChanged {
+                            client,
+                            serial,
+                            port,
+                        }
+                    }
+                    Err(e) => rpc::CtlMessage::Error {
+                        // The IO error causing the failure says the most
+                        reason: format!(
+                            "Failed to listen on port {}: {}",
+                            port,
+                            e.iter().last().unwrap()
+                        ),
+                        client,
+                        serial,
+                    },
+                };
+                self.cio.msg_rpc(msg);
+            }
             rpc::Message::PurgeDNS => {
                 self.cio.msg_trk(tracker::Request::PurgeDNS);
             }
+            rpc::Message::GetDhtItem {
+                client,
+                serial,
+                target,
+            } => {
+                self.cio.msg_trk(tracker::Request::GetItem {
+                    client,
+                    serial,
+                    target,
+                });
+            }
+            rpc::Message::GetHistory {
+                client,
+                serial,
+                resolution,
+                start,
+                end,
+            } => {
+                let series = match resolution {
+                    Resolution::Minute => &self.history.minutes,
+                    Resolution::Hour => &self.history.hours,
+                    Resolution::Day => &self.history.days,
+                };
+                let start = start.map(|s| s.timestamp()).unwrap_or(i64::MIN);
+                let end = end.map(|e| e.timestamp()).unwrap_or(i64::MAX);
+                let samples = series
+                    .range(start, end)
+                    .filter_map(|s| {
+                        Some(Sample {
+                            time: Utc.timestamp_opt(s.time, 0).single()?,
+                            transferred_up: s.ul,
+                            transferred_down: s.dl,
+                        })
+                    })
+                    .collect();
+                self.cio.msg_rpc(rpc::CtlMessage::History {
+                    client,
+                    serial,
+                    resolution,
+                    samples,
+                });
+            }
+            rpc::Message::PutDhtItem {
+                client,
+                serial,
+                value,
+                mutable,
+                salt,
+                seq,
+            } => {
+                self.cio.msg_trk(tracker::Request::PutItem {
+                    client,
+                    serial,
+                    value,
+                    mutable,
+                    salt,
+                    seq,
+                });
+            }
         }
         false
     }
@@ -681,7 +1639,7 @@ impl<T: cio::CIO> Control<T> {
     fn add_peer_rpc(&mut self, id: usize, peer: peer::PeerConn) -> Option<String> {
         trace!("Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if let Some(pid) = torrent.add_peer(peer) {
+            if let Some(pid) = torrent.add_peer(peer, PeerSource::Manual) {
                 self.peers.insert(pid, id);
                 return Some(util::peer_rpc_id(&torrent.info().hash, pid as u64));
             }
@@ -689,16 +1647,119 @@ impl<T: cio::CIO> Control<T> {
         None
     }
 
-    fn add_peer(&mut self, id: usize, peer: peer::PeerConn) {
+    fn add_peer(&mut self, id: usize, peer: peer::PeerConn, source: PeerSource) -> Option<usize> {
         trace!("Adding peer to torrent {:?}!", id);
-        if let Some(torrent) = self.torrents.get_mut(&id) {
-            if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
-                self.queue.add(id, torrent.priority());
-                return;
+        let pid = self.torrents.get_mut(&id)?.add_peer(peer, source)?;
+        self.peers.insert(pid, id);
+        Some(pid)
+    }
+
+    /// Number of open peer connections, including those not yet
+    /// associated with a torrent.
+    fn connections(&self) -> usize {
+        self.peers.len() + self.listener.len()
+    }
+
+    /// Connects to waiting peers while under the connection limits.
+    fn connect_pending(&mut self) {
+        if util::net::down() {
+            return;
+        }
+        let (max_sockets, max_peers, max_half_open) = {
+            let settings = config::settings();
+            (
+                settings.max_open_sockets,
+                settings.max_peers,
+                settings.max_half_open,
+            )
+        };
+        let mut half_open: usize = self.torrents.values().map(Torrent::half_open).sum();
+        let mut open = self.connections();
+        let now = time::Instant::now();
+        // Ranking the candidates is skipped when none could be dialed
+        if half_open >= max_half_open {
+            return;
+        }
+        if open >= max_sockets {
+            if self.dialer.any_ready(now) {
+                self.governor.set_full();
             }
-            if let Some(pid) = torrent.add_peer(peer) {
-                self.peers.insert(pid, id);
+            return;
+        }
+        for (tid, addr, source) in self.dialer.ready(now) {
+            if half_open >= max_half_open {
+                break;
+            }
+            if open >= max_sockets {
+                self.governor.set_full();
+                break;
+            }
+            match self.torrents.get_mut(&tid) {
+                Some(t) if !self.queue.active_dl.contains(&tid) && !t.status().completed() => {
+                    t.set_queued(self.queue.add(tid, t.priority()));
+                    continue;
+                }
+                Some(t) if t.num_peers() >= max_peers => {
+                    self.governor.starve(tid);
+                    continue;
+                }
+                Some(_) => {}
+                None => continue,
+            }
+            match peer::PeerConn::new_outgoing(&addr) {
+                Ok(peer) => {
+                    if let Some(pid) = self.add_peer(tid, peer, source) {
+                        trace!("Added peer({:?})!", addr);
+                        self.dialer.dialing(tid, addr, pid);
+                        half_open += 1;
+                        open += 1;
+                    } else {
+                        self.dialer.failed(tid, addr);
+                    }
+                }
+                Err(e) => {
+                    trace!("Failed to add peer: {:?}", e);
+                    self.dialer.failed(tid, addr);
+                }
+            }
+        }
+    }
+
+    /// Drops stalled connections, then disconnects the least useful peers
+    /// of torrents or the whole client if waiting peers hit their limits.
+    fn govern(&mut self) {
+        let torrents = &self.torrents;
+        self.dialer.retain(|tid| torrents.contains_key(&tid));
+
+        let timeout = time::Duration::from_secs(HALF_OPEN_TIMEOUT_SECS);
+        for torrent in self.torrents.values_mut() {
+            torrent.prune_half_open(timeout);
+        }
+        for pid in self.listener.expire(time::Instant::now()) {
+            self.cio.remove_peer(pid);
+        }
+
+        // Slots freed by the last run are available now that the
+        // disconnections have been processed
+        self.connect_pending();
+
+        for tid in self.governor.take_starved() {
+            if let Some(torrent) = self.torrents.get_mut(&tid) {
+                torrent.reclaim_peers(RECLAIM_GOAL);
+            }
+        }
+        if self.governor.take_full() {
+            let mut tids: Vec<_> = self.torrents.keys().cloned().collect();
+            tids.sort_by_key(|tid| self.torrents[tid].num_peers());
+            let mut reclaimed = 0;
+            for tid in tids.into_iter().rev() {
+                if reclaimed >= RECLAIM_GOAL {
+                    break;
+                }
+                let torrent = self.torrents.get_mut(&tid).unwrap();
+                reclaimed += torrent.reclaim_peers(RECLAIM_GOAL - reclaimed);
             }
+            debug!("Reclaimed {} connection slots", reclaimed);
         }
     }
 
@@ -712,7 +1773,7 @@ impl<T: cio::CIO> Control<T> {
         trace!("Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
             if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
-                self.queue.add(id, torrent.priority());
+                torrent.set_queued(self.queue.add(id, torrent.priority()));
                 return Err(());
             }
             if let Some(pid) = torrent.add_inc_peer(pid, cid, rsv) {
@@ -733,10 +1794,103 @@ impl<T: cio::CIO> Control<T> {
         ]));
     }
 
+    fn update_rpc_snapshot(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerSnapshot {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                last_snapshot: self.data.last_snapshot,
+            },
+        ]));
+    }
+
+    fn update_rpc_filtered(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerFilteredPeers {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                filtered_peers: self.sanitizer.stats().clone(),
+            },
+        ]));
+    }
+
+    fn update_rpc_buffers(&mut self, buffers: rpc::resource::BufferStats) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerBuffers {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                buffers,
+            },
+        ]));
+    }
+
+    fn update_rpc_dns(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerDns {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                dns: self.dns.clone(),
+            },
+        ]));
+    }
+
+    fn update_rpc_disk(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerDisk {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                disk_jobs: self.disk_jobs.clone(),
+                dirty_files: self.dirty_files,
+            },
+        ]));
+    }
+
+    /// Disconnects every peer once network activity is paused, or finds
+    /// peers again once it resumes.
+    fn network_changed(&mut self, was_down: bool) {
+        let down = util::net::down();
+        if down == was_down {
+            return;
+        }
+        if down {
+            for &pid in self.peers.keys().chain(self.listener.pending()) {
+                self.cio.remove_peer(pid);
+            }
+        } else {
+            for torrent in self.torrents.values_mut() {
+                torrent.update_tracker();
+            }
+            self.connect_pending();
+        }
+    }
+
+    fn update_rpc_port(&mut self, port: u16) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerPort {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                port,
+            },
+        ]));
+    }
+
+    fn update_rpc_external_ip(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerExternalIp {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                external_ipv4: self.external.v4.map(|ip| ip.to_string()),
+                external_ipv6: self.external.v6.map(|ip| ip.to_string()),
+            },
+        ]));
+    }
+
     fn update_rpc_tx(&mut self) {
         self.stat.tick();
         if self.stat.active() {
-            let (ul, dl) = (self.stat.avg_ul(), self.stat.avg_dl());
+            let window = stat::Window::TenSeconds;
+            let (ul, dl) = (self.stat.ul(window), self.stat.dl(window));
+            let tx = self.transfer();
             self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
                 rpc::resource::SResourceUpdate::ServerTransfer {
                     id: self.data.id.clone(),
@@ -747,12 +1901,140 @@ impl<T: cio::CIO> Control<T> {
                     transferred_down: self.data.dl,
                     ses_transferred_up: self.data.session_ul,
                     ses_transferred_down: self.data.session_dl,
+                    ses_overhead_up: tx.overhead_ul,
+                    ses_overhead_down: tx.overhead_dl,
+                    ratio: self.data.ratio(),
+                    uptime: self.data.total_uptime(),
                 },
             ]));
         }
     }
 
+    fn update_rpc_labels(&mut self) {
+        self.serialize_labels();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerLabels {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                labels: self.labels.clone(),
+            },
+        ]));
+    }
+
+    fn update_rpc_throttle_groups(&mut self) {
+        self.serialize_throttle_groups();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerThrottleGroups {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                throttle_groups: self.throttle_groups.clone(),
+            },
+        ]));
+    }
+
+    /// Changes the server's rate limits, leaving those which are None.
+    fn set_throttle(&mut self, id: String, up: Option<Option<i64>>, down: Option<Option<i64>>) {
+        let tu = up.unwrap_or_else(|| self.throttler.ul_rate());
+        let td = down.unwrap_or_else(|| self.throttler.dl_rate());
+        self.throttler.set_ul_rate(tu);
+        self.throttler.set_dl_rate(td);
+        self.data.throttle_ul = tu;
+        self.data.throttle_dl = td;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::Throttle {
+                id,
+                kind: rpc::resource::ResourceKind::Server,
+                throttle_up: tu,
+                throttle_down: td,
+            },
+        ]));
+    }
+
+    fn get_setting(&mut self, key: &str) -> Option<serde_json::Value> {
+        match key {
+            "net.throttle_up" => Some(self.throttler.ul_rate().into()),
+            "net.throttle_down" => Some(self.throttler.dl_rate().into()),
+            _ => config::settings().get(key),
+        }
+    }
+
+    fn set_setting(&mut self, key: &str, value: &serde_json::Value) -> Result<(), String> {
+        // Rate limits are part of the server's state rather than settings
+        let rate = match value {
+            serde_json::Value::Null => Some(None),
+            v => v.as_i64().map(Some),
+        };
+        let id = self.data.id.clone();
+        match (key, rate) {
+            ("net.throttle_up", Some(rate)) => self.set_throttle(id, Some(rate), None),
+            ("net.throttle_down", Some(rate)) => self.set_throttle(id, None, Some(rate)),
+            ("net.throttle_up", None) | ("net.throttle_down", None) => {
+                return Err(format!("{} must be an integer or null", key));
+            }
+            _ => {
+                let mut settings = config::settings().clone();
+                settings.set(key, value)?;
+                self.update_settings(settings);
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies settings, returning the keys of those which changed.
+    fn update_settings(&mut self, settings: config::Settings) -> Vec<&'static str> {
+        let directory = config::settings().directory.clone();
+        // Only new torrents go to a changed download directory
+        if settings.directory != directory {
+            for torrent in self.torrents.values_mut() {
+                torrent.pin_path(&directory);
+            }
+        }
+        config::update_settings(settings)
+    }
+
+    /// Reloads the config file, applying settings and any rate limits it
+    /// contains. Returns the keys of the settings which changed.
+    fn reload_config(&mut self) -> Result<Vec<String>, String> {
+        let file = config::ConfigFile::load_file()
+            .map_err(|e| config::describe(&e))?
+            .ok_or("no config file found")?;
+        let mut settings = config::Settings::from_file(&file);
+        // The session directory only changes by being moved
+        settings.session = config::settings().session.clone();
+        let mut changed: Vec<_> = self
+            .update_settings(settings)
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        // The GeoIP database may have been updated since it was loaded
+        geoip::load();
+        let (tu, td) = (self.throttler.ul_rate(), self.throttler.dl_rate());
+        let up = file.net.throttle_up.filter(|&rate| tu != Some(rate));
+        let down = file.net.throttle_down.filter(|&rate| td != Some(rate));
+        if up.is_some() {
+            changed.push("net.throttle_up".to_owned());
+        }
+        if down.is_some() {
+            changed.push("net.throttle_down".to_owned());
+        }
+        if up.is_some() || down.is_some() {
+            let id = self.data.id.clone();
+            self.set_throttle(id, up.map(Some), down.map(Some));
+        }
+        info!("Reloaded config, changed settings: {:?}", changed);
+        Ok(changed)
+    }
+
+    /// Piece data and protocol overhead transferred with peers this
+    /// session.
+    fn transfer(&self) -> stat::Transfer {
+        let mut tx = self.removed_tx;
+        tx += self.torrents.values().map(|t| t.transfer()).sum();
+        tx
+    }
+
     fn send_rpc_info(&mut self) {
+        let tx = self.transfer();
         let res = rpc::resource::Resource::Server(rpc::resource::Server {
             id: self.data.id.clone(),
             rate_up: 0,
@@ -763,9 +2045,25 @@ impl<T: cio::CIO> Control<T> {
             transferred_down: self.data.dl,
             ses_transferred_up: self.data.session_ul,
             ses_transferred_down: self.data.session_dl,
+            ses_overhead_up: tx.overhead_ul,
+            ses_overhead_down: tx.overhead_dl,
             free_space: self.data.free_space,
-            started: Utc::now(),
+            ratio: self.data.ratio(),
+            uptime: self.data.total_uptime(),
+            started: self.data.started,
+            last_snapshot: self.data.last_snapshot,
+            filtered_peers: self.sanitizer.stats().clone(),
+            buffers: buffers::stats(),
+            port: LISTEN_PORT.load(atomic::Ordering::Relaxed),
+            network_stopped: NET_STOPPED.load(atomic::Ordering::Relaxed),
+            external_ipv4: self.external.v4.map(|ip| ip.to_string()),
+            external_ipv6: self.external.v6.map(|ip| ip.to_string()),
+            disk_jobs: self.disk_jobs.clone(),
+            dirty_files: self.dirty_files,
+            dns: self.dns.clone(),
             download_token: DL_TOKEN.clone(),
+            labels: self.labels.clone(),
+            throttle_groups: self.throttle_groups.clone(),
             ..Default::default()
         });
         self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![res]));
@@ -793,8 +2091,20 @@ impl ServerData {
             free_space: 0,
             throttle_ul: Some(-1),
             throttle_dl: Some(-1),
+            uptime: 0,
+            started: Utc::now(),
+            last_snapshot: None,
         }
     }
+
+    fn total_uptime(&self) -> u64 {
+        let session = Utc::now().signed_duration_since(self.started);
+        self.uptime + session.num_seconds().max(0) as u64
+    }
+
+    fn ratio(&self) -> f32 {
+        self.ul as f32 / self.dl.max(1) as f32
+    }
 }
 
 impl Queue {
@@ -814,7 +2124,7 @@ impl Queue {
     }
 
     fn dl_full(&self) -> bool {
-        self.active_dl.len() == CONFIG.max_dl as usize
+        self.active_dl.len() >= config::settings().max_dl as usize
     }
 
     fn modify_pri(&mut self, id: usize, pri: u8, old_pri: u8) {
@@ -824,12 +2134,16 @@ impl Queue {
         self.inactive_dl[pri].insert(id);
     }
 
-    fn add(&mut self, id: usize, pri: u8) {
+    /// Adds a torrent to the queue, returning whether it must wait for a
+    /// download slot.
+    fn add(&mut self, id: usize, pri: u8) -> bool {
         let pri = pri as usize;
         if self.dl_full() {
             self.inactive_dl[pri].insert(id);
+            true
         } else {
             self.active_dl.insert(id);
+            false
         }
     }
 
@@ -896,6 +2210,14 @@ impl<T: cio::CIO> CJob<T> for SpaceUpdate {
     }
 }
 
+pub struct DiskStatsUpdate;
+
+impl<T: cio::CIO> CJob<T> for DiskStatsUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.cio.msg_disk(disk::Request::Stats);
+    }
+}
+
 pub struct EnqueueUpdate;
 
 impl<T: cio::CIO> CJob<T> for EnqueueUpdate {
@@ -910,14 +2232,95 @@ impl<T: cio::CIO> CJob<T> for EnqueueUpdate {
         for q in &mut queue.inactive_dl {
             q.retain(|tid| torrents.contains_key(tid));
         }
-        queue.enqueue(|tid| torrents.get_mut(&tid).unwrap().update_tracker());
+        queue.enqueue(|tid| {
+            let t = torrents.get_mut(&tid).unwrap();
+            t.set_queued(false);
+            t.update_tracker();
+        });
     }
 }
 
-pub struct SerializeUpdate;
+/// Pauses network activity while the interface or address sockets are
+/// bound to is missing, so no traffic can leak through other routes.
+pub struct BindUpdate;
+
+impl<T: cio::CIO> CJob<T> for BindUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        let up = util::net::bind_available();
+        if up != NET_DOWN.load(atomic::Ordering::Relaxed) {
+            return;
+        }
+        let was_down = util::net::down();
+        NET_DOWN.store(!up, atomic::Ordering::Relaxed);
+        if up {
+            info!("Bound interface is available, resuming network activity");
+        } else {
+            error!("Bound interface is unavailable, pausing network activity");
+        }
+        control.network_changed(was_down);
+    }
+}
+
+/// Pings systemd's watchdog, which restarts synapse if the control
+/// thread stops running jobs.
+pub struct WatchdogUpdate;
+
+impl<T: cio::CIO> CJob<T> for WatchdogUpdate {
+    fn update(&mut self, _: &mut Control<T>) {
+        util::systemd::notify("WATCHDOG=1");
+    }
+}
+
+pub struct GovernorUpdate;
+
+impl<T: cio::CIO> CJob<T> for GovernorUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.govern();
+    }
+}
+
+#[derive(Default)]
+pub struct BufferUpdate {
+    last: rpc::resource::BufferStats,
+}
+
+impl<T: cio::CIO> CJob<T> for BufferUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        buffers::shrink();
+        let stats = buffers::stats();
+        if stats != self.last {
+            self.last = stats.clone();
+            control.update_rpc_buffers(stats);
+        }
+    }
+}
+
+pub struct HistoryUpdate;
+
+impl<T: cio::CIO> CJob<T> for HistoryUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.history.tick(Utc::now().timestamp());
+    }
+}
+
+pub struct SerializeUpdate {
+    last_full: time::Instant,
+}
+
+impl SerializeUpdate {
+    pub fn new() -> SerializeUpdate {
+        SerializeUpdate {
+            last_full: time::Instant::now(),
+        }
+    }
+}
 
 impl<T: cio::CIO> CJob<T> for SerializeUpdate {
     fn update(&mut self, control: &mut Control<T>) {
-        control.serialize();
+        let full = self.last_full.elapsed() >= time::Duration::from_secs(SNAPSHOT_JOB_SECS);
+        if full {
+            self.last_full = time::Instant::now();
+        }
+        control.serialize(full);
     }
 }
@@ -0,0 +1,192 @@
+//! A torrent added from the command line for a one-off download, after
+//! which synapse may exit rather than keep running as a daemon.
+
+use std::sync::atomic;
+use std::{env, fs, time};
+
+use super::{cio, CJob, Control};
+use crate::rpc::proto::resource::CResourceUpdate;
+use crate::torrent::Info;
+use crate::{args, bencode, rpc, SHUTDOWN};
+
+/// Interval to check whether a torrent run in the foreground is done
+const JOB_SECS: u64 = 1;
+/// Seconds a magnet run in the foreground may take to fetch its metadata
+/// if no timeout is given
+const MAGNET_TIMEOUT_SECS: u64 = 10 * 60;
+/// Client RPC messages about the torrent are addressed to, which never
+/// exists so they're dropped.
+const CLIENT: usize = usize::MAX;
+
+/// Set when a torrent run in the foreground failed, so synapse exits
+/// with an error.
+pub static FAILED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+pub struct OneShot {
+    info: Info,
+    path: Option<String>,
+    seed_ratio: Option<f32>,
+    foreground: bool,
+    magnet_timeout: time::Duration,
+}
+
+impl OneShot {
+    /// Reads the torrent file or parses the magnet given by `--add`.
+    pub fn load(add: args::Add) -> Result<OneShot, String> {
+        let info = if add.torrent.starts_with("magnet:") {
+            Info::from_magnet(&add.torrent).map_err(|e| format!("Invalid magnet: {}", e))?
+        } else {
+            let data = fs::read(&add.torrent)
+                .map_err(|e| format!("Failed to read {}: {}", add.torrent, e))?;
+            bencode::decode_ref_with(&data, &Info::LIMITS)
+                .map_err(|e| e.to_string())
+                .and_then(|b| Info::from_bencode(&b).map_err(|e| e.to_owned()))
+                .map_err(|e| format!("Invalid torrent {}: {}", add.torrent, e))?
+        };
+        // The path is kept in the session, so it mustn't depend on where
+        // synapse is started from
+        let path = match add.path {
+            Some(p) => Some(
+                env::current_dir()
+                    .map_err(|e| format!("Failed to get the working directory: {}", e))?
+                    .join(p)
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            None => None,
+        };
+        Ok(OneShot {
+            info,
+            path,
+            seed_ratio: add.seed_ratio,
+            foreground: add.foreground,
+            magnet_timeout: time::Duration::from_secs(
+                add.magnet_timeout.unwrap_or(MAGNET_TIMEOUT_SECS),
+            ),
+        })
+    }
+}
+
+impl<T: cio::CIO> Control<T> {
+    /// Adds the torrent given on the command line, or resumes it if a
+    /// previous run added it.
+    pub(super) fn add_oneshot(&mut self, o: OneShot) {
+        let hash = o.info.hash;
+        match self.hash_idx.get(&hash) {
+            Some(_) => info!("Torrent {} was already added", o.info.name),
+            None => self.add_torrent(
+                o.info,
+                rpc::AddOptions {
+                    path: o.path,
+                    start: true,
+                    ..Default::default()
+                },
+                CLIENT,
+                0,
+            ),
+        }
+        let torrents = &mut self.torrents;
+        let t = match self.hash_idx.get(&hash).and_then(|t| torrents.get_mut(t)) {
+            Some(t) => t,
+            None => {
+                if o.foreground {
+                    error!("Failed to add the torrent, shutting down");
+                    FAILED.store(true, atomic::Ordering::SeqCst);
+                    SHUTDOWN.store(true, atomic::Ordering::SeqCst);
+                }
+                return;
+            }
+        };
+        if o.seed_ratio.is_some() {
+            t.rpc_update(CResourceUpdate {
+                id: t.rpc_id(),
+                seed_ratio: Some(o.seed_ratio),
+                ..Default::default()
+            });
+        }
+        if !done(t.complete(), o.seed_ratio, t.seeding_goal_met()) {
+            t.resume();
+        }
+        if o.foreground {
+            self.jobs.add_cjob(
+                OneShotUpdate {
+                    hash,
+                    seed_ratio: o.seed_ratio,
+                    downloaded: false,
+                    deadline: time::Instant::now() + o.magnet_timeout,
+                },
+                time::Duration::from_secs(JOB_SECS),
+            );
+        }
+    }
+}
+
+/// Whether a one-off download is over: it's complete and, if a seed ratio
+/// was given, has been seeded up to it.
+fn done(complete: bool, seed_ratio: Option<f32>, goal_met: bool) -> bool {
+    complete && (seed_ratio.is_none() || goal_met)
+}
+
+/// Shuts synapse down once a torrent run in the foreground is done.
+pub struct OneShotUpdate {
+    hash: [u8; 20],
+    seed_ratio: Option<f32>,
+    downloaded: bool,
+    /// When to give up if the torrent is still a magnet
+    deadline: time::Instant,
+}
+
+impl<T: cio::CIO> CJob<T> for OneShotUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        if SHUTDOWN.load(atomic::Ordering::SeqCst) {
+            return;
+        }
+        let torrents = &control.torrents;
+        let t = match control
+            .hash_idx
+            .get(&self.hash)
+            .and_then(|t| torrents.get(t))
+        {
+            Some(t) => t,
+            None => {
+                info!("Torrent was removed, shutting down");
+                SHUTDOWN.store(true, atomic::Ordering::SeqCst);
+                return;
+            }
+        };
+        let failed = match t.status().error {
+            Some(ref e) if e.fatal() && !e.transient() => Some(e.to_string()),
+            _ if t.status().magnet() && time::Instant::now() > self.deadline => {
+                Some("timed out fetching the metadata".to_owned())
+            }
+            _ => None,
+        };
+        if let Some(reason) = failed {
+            error!("Failed {}: {}, shutting down", t.info().name, reason);
+            FAILED.store(true, atomic::Ordering::SeqCst);
+            SHUTDOWN.store(true, atomic::Ordering::SeqCst);
+            return;
+        }
+        if t.complete() && !self.downloaded {
+            self.downloaded = true;
+            info!("Downloaded {}", t.info().name);
+        }
+        if done(t.complete(), self.seed_ratio, t.seeding_goal_met()) {
+            info!("Finished {}, shutting down", t.info().name);
+            SHUTDOWN.store(true, atomic::Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_done() {
+        assert!(!done(false, None, false));
+        assert!(done(true, None, false));
+        assert!(!done(true, Some(1.), false));
+        assert!(done(true, Some(1.), true));
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use crate::config::{Placement, ProfileConfig};
+
+/// Chooses the download directories of torrents added without a path
+/// from the configured profiles.
+pub struct Placer {
+    /// Directory of each profile tried first by round robin placement
+    next: Vec<usize>,
+}
+
+impl Placer {
+    pub fn new(profiles: &[ProfileConfig]) -> Placer {
+        Placer {
+            next: vec![0; profiles.len()],
+        }
+    }
+
+    /// Directory for a torrent of a size and label, given the directories
+    /// of other torrents with the label and a way to get the free space
+    /// of a directory. None if no profile applies or no directory is
+    /// available, so the default directory is used.
+    pub fn place<F>(
+        &mut self,
+        profiles: &[ProfileConfig],
+        size: u64,
+        label: Option<&str>,
+        labelled: &[&str],
+        space: F,
+    ) -> Option<String>
+    where
+        F: Fn(&str) -> Option<u64>,
+    {
+        let labels = |p: &ProfileConfig| label.map(|l| p.labels.iter().any(|pl| pl == l));
+        let (i, profile) = profiles
+            .iter()
+            .enumerate()
+            .find(|(_, p)| labels(p).unwrap_or(false))
+            .or_else(|| {
+                profiles
+                    .iter()
+                    .enumerate()
+                    .find(|(_, p)| p.labels.is_empty())
+            })?;
+
+        // Directories which can't be checked, e.g. unmounted ones, are skipped
+        let free: Vec<_> = profile.directories.iter().map(|d| space(d)).collect();
+        let fits = |j: &usize| {
+            free[*j]
+                .map(|f| f >= size.saturating_add(profile.reserve))
+                .unwrap_or(false)
+        };
+        let n = free.len();
+        let most_free = (0..n).filter(fits).max_by_key(|&j| free[j]);
+        let choice = match profile.policy {
+            Placement::MostFree => most_free,
+            Placement::RoundRobin => {
+                let start = self.next[i];
+                let j = (0..n).map(|k| (start + k) % n).find(fits);
+                if let Some(j) = j {
+                    self.next[i] = (j + 1) % n;
+                }
+                j
+            }
+            Placement::Label => profile
+                .directories
+                .iter()
+                .position(|d| labelled.iter().any(|l| Path::new(l) == Path::new(d)))
+                .filter(fits)
+                .or(most_free),
+        };
+        let j = match choice {
+            Some(j) => j,
+            None => {
+                error!(
+                    "No directory of profile {} has {} bytes free, using the emptiest",
+                    profile.name, size
+                );
+                (0..n)
+                    .filter(|&j| free[j].is_some())
+                    .max_by_key(|&j| free[j])?
+            }
+        };
+        Some(profile.directories[j].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(policy: Placement, labels: &[&str]) -> ProfileConfig {
+        ProfileConfig {
+            name: "p".to_owned(),
+            directories: vec!["/a".to_owned(), "/b".to_owned(), "/c".to_owned()],
+            policy,
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            reserve: 10,
+        }
+    }
+
+    fn space(dir: &str) -> Option<u64> {
+        match dir {
+            "/a" => Some(100),
+            "/b" => Some(300),
+            "/c" => None,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_place() {
+        let profiles = vec![profile(Placement::RoundRobin, &["tv"])];
+        let mut placer = Placer::new(&profiles);
+        // Only torrents of the profile's labels are placed
+        assert_eq!(placer.place(&profiles, 0, None, &[], space), None);
+        let mut place = |size| placer.place(&profiles, size, Some("tv"), &[], space);
+        assert_eq!(place(50).unwrap(), "/a");
+        assert_eq!(place(50).unwrap(), "/b");
+        assert_eq!(place(50).unwrap(), "/a");
+        // The reserve must remain free
+        assert_eq!(place(95).unwrap(), "/b");
+
+        let profiles = vec![profile(Placement::MostFree, &[])];
+        let mut placer = Placer::new(&profiles);
+        assert_eq!(placer.place(&profiles, 0, None, &[], space).unwrap(), "/b");
+        // Without enough space anywhere, the emptiest directory is used
+        let big = placer.place(&profiles, 1000, Some("tv"), &[], space);
+        assert_eq!(big.unwrap(), "/b");
+
+        let profiles = vec![profile(Placement::Label, &[])];
+        let mut placer = Placer::new(&profiles);
+        let mut place = |size, labelled| placer.place(&profiles, size, Some("tv"), labelled, space);
+        assert_eq!(place(50, &["/a/"]).unwrap(), "/a");
+        assert_eq!(place(95, &["/a"]).unwrap(), "/b");
+        assert_eq!(place(50, &[]).unwrap(), "/b");
+    }
+}
@@ -0,0 +1,164 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic;
+use std::time::{Duration, Instant};
+
+use crate::rpc::resource::PeerFilterStats;
+use crate::util::FHashMap;
+use crate::LISTEN_PORT;
+
+/// Maximum number of our own addresses remembered
+const MAX_OWN: usize = 16;
+/// How long an address of ours is remembered after it was last reported
+const OWN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Drops peer addresses which can't or shouldn't be dialed before they
+/// reach the dialer, counting why each was dropped.
+pub struct Sanitizer {
+    /// Our own addresses, as reported by trackers or found by connecting,
+    /// and when they were last reported
+    own: FHashMap<IpAddr, Instant>,
+    stats: PeerFilterStats,
+}
+
+impl Sanitizer {
+    pub fn new() -> Sanitizer {
+        Sanitizer {
+            own: FHashMap::default(),
+            stats: PeerFilterStats::default(),
+        }
+    }
+
+    /// Remembers an address which belongs to us, forgetting the one
+    /// reported longest ago if too many are remembered.
+    pub fn add_own(&mut self, ip: IpAddr) {
+        self.own.retain(|_, seen| seen.elapsed() < OWN_TTL);
+        if self.own.len() >= MAX_OWN && !self.own.contains_key(&ip) {
+            let oldest = self.own.iter().min_by_key(|(_, seen)| **seen);
+            if let Some(oldest) = oldest.map(|(ip, _)| *ip) {
+                self.own.remove(&oldest);
+            }
+        }
+        self.own.insert(ip, Instant::now());
+    }
+
+    fn is_own(&self, ip: &IpAddr) -> bool {
+        self.own
+            .get(ip)
+            .map(|seen| seen.elapsed() < OWN_TTL)
+            .unwrap_or(false)
+    }
+
+    /// Whether an address may be dialed, counting it if not.
+    pub fn check(&mut self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip();
+        let counter = if addr.port() == 0 {
+            &mut self.stats.port
+        } else if is_multicast(&ip) {
+            &mut self.stats.multicast
+        } else if is_bogon(&ip) {
+            &mut self.stats.bogon
+        } else if self.is_own(&ip)
+            || (ip.is_loopback() && addr.port() == LISTEN_PORT.load(atomic::Ordering::Relaxed))
+        {
+            &mut self.stats.own
+        } else {
+            return true;
+        };
+        *counter += 1;
+        false
+    }
+
+    pub fn stats(&self) -> &PeerFilterStats {
+        &self.stats
+    }
+}
+
+fn is_multicast(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_multicast() || ip.is_broadcast(),
+        IpAddr::V6(ip) => ip.is_multicast(),
+    }
+}
+
+/// Addresses which are reserved and never assigned to hosts. Private
+/// and loopback addresses are allowed, as peers may share a network.
+fn is_bogon(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            o[0] == 0
+                // Reserved for future use
+                || o[0] >= 240
+                // Benchmarking
+                || (o[0] == 198 && (o[1] & 0xfe) == 18)
+                // Documentation
+                || (o[0] == 192 && o[1] == 0 && o[2] == 2)
+                || (o[0] == 198 && o[1] == 51 && o[2] == 100)
+                || (o[0] == 203 && o[1] == 0 && o[2] == 113)
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            ip.is_unspecified()
+                // Documentation
+                || (s[0] == 0x2001 && s[1] == 0x0db8)
+                || ip
+                    .to_ipv4_mapped()
+                    .map(|v4| is_bogon(&IpAddr::V4(v4)))
+                    .unwrap_or(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let mut s = Sanitizer::new();
+        assert!(s.check(&addr("1.2.3.4:6881")));
+        assert!(s.check(&addr("192.168.1.2:6881")));
+        assert!(s.check(&addr("[2001:4860::1]:6881")));
+        assert!(!s.check(&addr("1.2.3.4:0")));
+        assert!(!s.check(&addr("224.0.0.1:6881")));
+        assert!(!s.check(&addr("255.255.255.255:6881")));
+        assert!(!s.check(&addr("[ff02::1]:6881")));
+        assert!(!s.check(&addr("0.1.2.3:6881")));
+        assert!(!s.check(&addr("240.0.0.1:6881")));
+        assert!(!s.check(&addr("[2001:db8::1]:6881")));
+        s.add_own("5.6.7.8".parse().unwrap());
+        assert!(!s.check(&addr("5.6.7.8:1234")));
+        let stats = s.stats();
+        assert_eq!(
+            (stats.port, stats.multicast, stats.bogon, stats.own),
+            (1, 3, 3, 1)
+        );
+    }
+
+    #[test]
+    fn test_own_expiry() {
+        let mut s = Sanitizer::new();
+        let ip = |i| IpAddr::from([5, 6, 7, i]);
+        for i in 0..MAX_OWN as u8 {
+            s.add_own(ip(i));
+        }
+        // The address reported longest ago is forgotten first
+        let now = Instant::now();
+        s.own.insert(ip(3), now - Duration::from_secs(1));
+        s.add_own(ip(100));
+        assert_eq!(s.own.len(), MAX_OWN);
+        assert!(!s.is_own(&ip(3)));
+        assert!(s.is_own(&ip(100)));
+        // Addresses which weren't reported again expire
+        if let Some(seen) = now.checked_sub(OWN_TTL) {
+            s.own.insert(ip(1), seen);
+            assert!(s.check(&addr("5.6.7.1:6881")));
+            s.add_own(ip(0));
+            assert!(!s.own.contains_key(&ip(1)));
+        }
+    }
+}
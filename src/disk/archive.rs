@@ -0,0 +1,616 @@
+use std::{cmp, fs, io, path, time};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::FileCache;
+
+/// Size of the file data chunks read per call to `fill`.
+const CHUNK_LEN: u64 = 16_384;
+const TAR_BLOCK: u64 = 512;
+/// Largest size representable by the 11 octal digits of a ustar header.
+const TAR_MAX_SIZE: u64 = 0o777_7777_7777;
+const ZIP_LOCAL_LEN: u64 = 30;
+const ZIP_CENTRAL_LEN: u64 = 46;
+const ZIP_EOCD_LEN: u64 = 22;
+const ZIP64_EOCD_LEN: u64 = 56;
+const ZIP64_LOCATOR_LEN: u64 = 20;
+/// 1980-01-01 00:00:00 in MS-DOS date format.
+const DOS_DATE: u16 = 0x0021;
+const CRC_TABLE: [u32; 256] = crc_table();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveKind {
+    /// Uncompressed (store mode) zip
+    Zip,
+    /// POSIX ustar, with pax headers for long names and sizes
+    Tar,
+}
+
+/// An archive of a torrent's files which is generated on the fly.
+pub struct Archive {
+    kind: ArchiveKind,
+    base: String,
+    files: Vec<(String, u64)>,
+    zip64: bool,
+    state: State,
+    /// Index of the current file
+    idx: usize,
+    /// Offset into the current file
+    pos: u64,
+    /// Bytes of the archive generated so far
+    written: u64,
+    crc: u32,
+    /// CRC and offset of each zip entry written, for the central
+    /// directory
+    entries: Vec<(u32, u64)>,
+    /// Offset of the zip central directory
+    central_offset: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Header,
+    Data,
+    Central,
+    End,
+    Done,
+}
+
+impl ArchiveKind {
+    pub fn from_name(name: &str) -> Option<ArchiveKind> {
+        match name {
+            "zip" => Some(ArchiveKind::Zip),
+            "tar" => Some(ArchiveKind::Tar),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveKind::Zip => "zip",
+            ArchiveKind::Tar => "tar",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            ArchiveKind::Zip => "application/zip",
+            ArchiveKind::Tar => "application/x-tar",
+        }
+    }
+}
+
+impl Archive {
+    /// Creates an archive of files, given as paths relative to base
+    /// along with their sizes. Returns None if a name is too long to
+    /// be stored in a zip.
+    pub fn new(kind: ArchiveKind, base: String, files: Vec<(String, u64)>) -> Option<Archive> {
+        if kind == ArchiveKind::Zip && files.iter().any(|(name, _)| name.len() > 0xFFFF) {
+            return None;
+        }
+        let mut archive = Archive {
+            kind,
+            base,
+            files,
+            zip64: false,
+            state: State::Header,
+            idx: 0,
+            pos: 0,
+            written: 0,
+            crc: 0,
+            entries: Vec::new(),
+            central_offset: 0,
+        };
+        archive.zip64 = kind == ArchiveKind::Zip
+            && (archive.files.len() >= 0xFFFF
+                || archive.files.iter().any(|&(_, size)| size >= 0xFFFF_FFFF)
+                || archive.len() >= 0xFFFF_FFFF);
+        Some(archive)
+    }
+
+    pub fn kind(&self) -> ArchiveKind {
+        self.kind
+    }
+
+    /// Total length of the generated archive.
+    pub fn len(&self) -> u64 {
+        match self.kind {
+            ArchiveKind::Zip => {
+                let mut len = ZIP_EOCD_LEN;
+                if self.zip64 {
+                    len += ZIP64_EOCD_LEN + ZIP64_LOCATOR_LEN;
+                }
+                for &(ref name, size) in &self.files {
+                    let name = name.len() as u64;
+                    len += ZIP_LOCAL_LEN + name + self.zip_local_extra_len();
+                    len += size + self.zip_descriptor_len();
+                    len += ZIP_CENTRAL_LEN + name + self.zip_central_extra_len();
+                }
+                len
+            }
+            ArchiveKind::Tar => {
+                let mut len = 2 * TAR_BLOCK;
+                for &(ref name, size) in &self.files {
+                    len += tar_header(name, size).len() as u64 + tar_padded(size);
+                }
+                len
+            }
+        }
+    }
+
+    /// Latest modification time of the archived files.
+    pub fn modified(&self) -> Option<time::SystemTime> {
+        self.files
+            .iter()
+            .filter_map(|(name, _)| {
+                fs::metadata(path::Path::new(&self.base).join(name))
+                    .and_then(|m| m.modified())
+                    .ok()
+            })
+            .max()
+    }
+
+    /// Marks the archive as fully generated, used when only
+    /// the headers of a response should be sent.
+    pub fn finish(&mut self) {
+        self.state = State::Done;
+    }
+
+    /// Replaces the contents of buf with the next chunk of the archive,
+    /// returning false once the archive has been fully generated.
+    pub fn fill(&mut self, fc: &mut FileCache, buf: &mut Vec<u8>) -> io::Result<bool> {
+        buf.clear();
+        match self.state {
+            State::Done => return Ok(false),
+            State::Header if self.idx == self.files.len() => {
+                self.idx = 0;
+                self.central_offset = self.written;
+                self.state = match self.kind {
+                    ArchiveKind::Zip => State::Central,
+                    ArchiveKind::Tar => State::End,
+                };
+                return self.fill(fc, buf);
+            }
+            State::Header => {
+                let (ref name, size) = self.files[self.idx];
+                match self.kind {
+                    ArchiveKind::Zip => self.zip_local_header(buf),
+                    ArchiveKind::Tar => buf.extend(tar_header(name, size)),
+                }
+                self.pos = 0;
+                self.crc = 0;
+                self.state = State::Data;
+            }
+            State::Data => {
+                let size = self.files[self.idx].1;
+                if self.pos == size {
+                    match self.kind {
+                        ArchiveKind::Zip => self.zip_descriptor(buf),
+                        ArchiveKind::Tar => {
+                            buf.resize((tar_padded(size) - size) as usize, 0);
+                        }
+                    }
+                    self.idx += 1;
+                    self.state = State::Header;
+                } else {
+                    let len = cmp::min(CHUNK_LEN, size - self.pos);
+                    buf.resize(len as usize, 0);
+                    let mut pb = path::PathBuf::from(&self.base);
+                    pb.push(&self.files[self.idx].0);
+                    fc.read_file_range(&pb, self.pos, buf)?;
+                    if self.kind == ArchiveKind::Zip {
+                        self.crc = crc32_update(self.crc, buf);
+                    }
+                    self.pos += len;
+                }
+            }
+            State::Central if self.idx == self.files.len() => {
+                self.state = State::End;
+                return self.fill(fc, buf);
+            }
+            State::Central => {
+                while self.idx < self.files.len() && (buf.len() as u64) < CHUNK_LEN {
+                    self.zip_central_entry(buf);
+                    self.idx += 1;
+                }
+            }
+            State::End => {
+                match self.kind {
+                    ArchiveKind::Zip => self.zip_end(buf),
+                    ArchiveKind::Tar => buf.resize(2 * TAR_BLOCK as usize, 0),
+                }
+                self.state = State::Done;
+            }
+        }
+        self.written += buf.len() as u64;
+        Ok(true)
+    }
+
+    fn zip_local_extra_len(&self) -> u64 {
+        if self.zip64 {
+            20
+        } else {
+            0
+        }
+    }
+
+    fn zip_central_extra_len(&self) -> u64 {
+        if self.zip64 {
+            28
+        } else {
+            0
+        }
+    }
+
+    fn zip_descriptor_len(&self) -> u64 {
+        if self.zip64 {
+            24
+        } else {
+            16
+        }
+    }
+
+    fn zip_version(&self) -> u16 {
+        if self.zip64 {
+            45
+        } else {
+            20
+        }
+    }
+
+    fn zip_local_header(&mut self, buf: &mut Vec<u8>) {
+        let (ref name, size) = self.files[self.idx];
+        let offset = self.written;
+        let size32 = if self.zip64 { 0xFFFF_FFFF } else { size as u32 };
+        let version = self.zip_version();
+
+        put_u32(buf, 0x0403_4b50);
+        put_u16(buf, version);
+        // Data descriptor follows the data, name is UTF-8
+        put_u16(buf, 0x0808);
+        put_u16(buf, 0);
+        put_u16(buf, 0);
+        put_u16(buf, DOS_DATE);
+        put_u32(buf, 0);
+        put_u32(buf, size32);
+        put_u32(buf, size32);
+        put_u16(buf, name.len() as u16);
+        put_u16(buf, self.zip_local_extra_len() as u16);
+        buf.extend_from_slice(name.as_bytes());
+        if self.zip64 {
+            put_u16(buf, 0x0001);
+            put_u16(buf, 16);
+            put_u64(buf, size);
+            put_u64(buf, size);
+        }
+
+        self.entries.push((0, offset));
+    }
+
+    fn zip_descriptor(&mut self, buf: &mut Vec<u8>) {
+        let size = self.files[self.idx].1;
+        put_u32(buf, 0x0807_4b50);
+        put_u32(buf, self.crc);
+        if self.zip64 {
+            put_u64(buf, size);
+            put_u64(buf, size);
+        } else {
+            put_u32(buf, size as u32);
+            put_u32(buf, size as u32);
+        }
+        self.entries[self.idx].0 = self.crc;
+    }
+
+    fn zip_central_entry(&self, buf: &mut Vec<u8>) {
+        let (ref name, size) = self.files[self.idx];
+        let (crc, offset) = self.entries[self.idx];
+        let size32 = if self.zip64 { 0xFFFF_FFFF } else { size as u32 };
+        let version = self.zip_version();
+
+        put_u32(buf, 0x0201_4b50);
+        put_u16(buf, version);
+        put_u16(buf, version);
+        put_u16(buf, 0x0808);
+        put_u16(buf, 0);
+        put_u16(buf, 0);
+        put_u16(buf, DOS_DATE);
+        put_u32(buf, crc);
+        put_u32(buf, size32);
+        put_u32(buf, size32);
+        put_u16(buf, name.len() as u16);
+        put_u16(buf, self.zip_central_extra_len() as u16);
+        put_u16(buf, 0);
+        put_u16(buf, 0);
+        put_u16(buf, 0);
+        put_u32(buf, 0);
+        put_u32(
+            buf,
+            if self.zip64 {
+                0xFFFF_FFFF
+            } else {
+                offset as u32
+            },
+        );
+        buf.extend_from_slice(name.as_bytes());
+        if self.zip64 {
+            put_u16(buf, 0x0001);
+            put_u16(buf, 24);
+            put_u64(buf, size);
+            put_u64(buf, size);
+            put_u64(buf, offset);
+        }
+    }
+
+    fn zip_end(&mut self, buf: &mut Vec<u8>) {
+        let cd_offset = self.central_offset;
+        let cd_len = self.written - self.central_offset;
+        let entries = self.files.len() as u64;
+        if self.zip64 {
+            let eocd64_offset = cd_offset + cd_len;
+            put_u32(buf, 0x0606_4b50);
+            put_u64(buf, ZIP64_EOCD_LEN - 12);
+            put_u16(buf, 45);
+            put_u16(buf, 45);
+            put_u32(buf, 0);
+            put_u32(buf, 0);
+            put_u64(buf, entries);
+            put_u64(buf, entries);
+            put_u64(buf, cd_len);
+            put_u64(buf, cd_offset);
+
+            put_u32(buf, 0x0706_4b50);
+            put_u32(buf, 0);
+            put_u64(buf, eocd64_offset);
+            put_u32(buf, 1);
+        }
+        put_u32(buf, 0x0605_4b50);
+        put_u16(buf, 0);
+        put_u16(buf, 0);
+        let entries16 = if self.zip64 { 0xFFFF } else { entries as u16 };
+        put_u16(buf, entries16);
+        put_u16(buf, entries16);
+        if self.zip64 {
+            put_u32(buf, 0xFFFF_FFFF);
+            put_u32(buf, 0xFFFF_FFFF);
+        } else {
+            put_u32(buf, cd_len as u32);
+            put_u32(buf, cd_offset as u32);
+        }
+        put_u16(buf, 0);
+    }
+}
+
+/// Generates the header block(s) of a tar entry, prefixed by a pax
+/// extended header if the name or size don't fit in a ustar header.
+fn tar_header(name: &str, size: u64) -> Vec<u8> {
+    let mut records = String::new();
+    if name.len() > 100 {
+        records.push_str(&pax_record("path", name));
+    }
+    if size > TAR_MAX_SIZE {
+        records.push_str(&pax_record("size", &size.to_string()));
+    }
+
+    let mut header = Vec::new();
+    if !records.is_empty() {
+        header.extend(ustar_block("PaxHeaders/entry", records.len() as u64, b'x'));
+        let pad = tar_padded(records.len() as u64) as usize;
+        header.extend(records.into_bytes());
+        header.resize(pad + TAR_BLOCK as usize, 0);
+    }
+    let short = truncate(name, 100);
+    header.extend(ustar_block(short, cmp::min(size, TAR_MAX_SIZE), b'0'));
+    header
+}
+
+fn ustar_block(name: &str, size: u64, kind: u8) -> Vec<u8> {
+    let mut block = vec![0u8; TAR_BLOCK as usize];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    block[100..108].copy_from_slice(b"0000644\0");
+    block[108..116].copy_from_slice(b"0000000\0");
+    block[116..124].copy_from_slice(b"0000000\0");
+    block[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    block[136..148].copy_from_slice(b"00000000000\0");
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    // The checksum is computed with its own field set to spaces
+    block[148..156].copy_from_slice(b"        ");
+    let sum: u32 = block.iter().map(|&b| u32::from(b)).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    block
+}
+
+/// Formats a pax record, whose length prefix includes itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {}={}\n", key, value);
+    let mut len = body.len() + 1;
+    while len.to_string().len() + body.len() != len {
+        len += 1;
+    }
+    format!("{}{}", len, body)
+}
+
+fn tar_padded(size: u64) -> u64 {
+    (size + TAR_BLOCK - 1) & !(TAR_BLOCK - 1)
+}
+
+/// Truncates a string to at most len bytes on a char boundary.
+fn truncate(s: &str, len: usize) -> &str {
+    let mut end = cmp::min(len, s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    let mut b = [0u8; 2];
+    LittleEndian::write_u16(&mut b, v);
+    buf.extend_from_slice(&b);
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    let mut b = [0u8; 4];
+    LittleEndian::write_u32(&mut b, v);
+    buf.extend_from_slice(&b);
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    let mut b = [0u8; 8];
+    LittleEndian::write_u64(&mut b, v);
+    buf.extend_from_slice(&b);
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c = CRC_TABLE[((c ^ u32::from(b)) & 0xFF) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32_update(0, b"123456789"), 0xCBF4_3926);
+        let crc = crc32_update(0, b"12345");
+        assert_eq!(crc32_update(crc, b"6789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_pax_record() {
+        assert_eq!(pax_record("path", "abc"), "12 path=abc\n");
+        let rec = pax_record("path", &"a".repeat(95));
+        assert_eq!(rec.len(), 105);
+        assert!(rec.starts_with("105 path="));
+    }
+
+    #[test]
+    fn test_tar_header() {
+        let header = tar_header("foo/bar.txt", 1234);
+        assert_eq!(header.len(), 512);
+        assert_eq!(&header[..11], b"foo/bar.txt");
+        assert_eq!(&header[124..136], b"00000002322\0");
+        let sum: u32 = header[..148]
+            .iter()
+            .chain(b"        ".iter())
+            .chain(header[156..].iter())
+            .map(|&b| u32::from(b))
+            .sum();
+        assert_eq!(&header[148..156], format!("{:06o}\0 ", sum).as_bytes());
+
+        let long = "d/".repeat(60) + "file";
+        let header = tar_header(&long, 10);
+        assert_eq!(header.len(), 3 * 512);
+        assert_eq!(header[156], b'x');
+        assert_eq!(header[1024 + 156], b'0');
+    }
+
+    #[test]
+    fn test_archive_len() {
+        let files = vec![("a/one".to_owned(), 100), ("a/two".to_owned(), 0)];
+        let zip = Archive::new(ArchiveKind::Zip, String::new(), files.clone()).unwrap();
+        assert!(!zip.zip64);
+        assert_eq!(zip.len(), 2 * (30 + 5 + 16 + 46 + 5) + 100 + 22);
+        let tar = Archive::new(ArchiveKind::Tar, String::new(), files).unwrap();
+        assert_eq!(tar.len(), 512 + 512 + 512 + 1024);
+
+        let big = vec![("big".to_owned(), 5_000_000_000)];
+        let zip = Archive::new(ArchiveKind::Zip, String::new(), big).unwrap();
+        assert!(zip.zip64);
+        assert_eq!(
+            zip.len(),
+            30 + 3 + 20 + 5_000_000_000 + 24 + 46 + 3 + 28 + 56 + 20 + 22
+        );
+
+        // Zip headers can't hold names this long, while tar uses pax headers
+        let long = vec![("a".repeat(0x10000), 1)];
+        assert!(Archive::new(ArchiveKind::Zip, String::new(), long.clone()).is_none());
+        assert!(Archive::new(ArchiveKind::Tar, String::new(), long).is_some());
+    }
+
+    #[test]
+    fn test_archive_fill() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("t")).unwrap();
+        std::fs::write(dir.join("t/one"), vec![7u8; 40_000]).unwrap();
+        std::fs::write(dir.join("t/two"), b"hello").unwrap();
+        let files = vec![("t/one".to_owned(), 40_000), ("t/two".to_owned(), 5)];
+        let base = dir.to_string_lossy().into_owned();
+
+        let mut fc = FileCache::new();
+        for &kind in &[ArchiveKind::Zip, ArchiveKind::Tar] {
+            let mut archive = Archive::new(kind, base.clone(), files.clone()).unwrap();
+            let mut out = Vec::new();
+            let mut buf = Vec::new();
+            while archive.fill(&mut fc, &mut buf).unwrap() {
+                out.extend_from_slice(&buf);
+            }
+            assert_eq!(out.len() as u64, archive.len());
+            if kind == ArchiveKind::Zip {
+                let eocd = &out[out.len() - 22..];
+                assert_eq!(LittleEndian::read_u32(&eocd[0..4]), 0x0605_4b50);
+                assert_eq!(LittleEndian::read_u16(&eocd[10..12]), 2);
+                let cd = LittleEndian::read_u32(&eocd[16..20]) as usize;
+                assert_eq!(LittleEndian::read_u32(&out[cd + 16..cd + 20]), {
+                    crc32_update(0, &[7u8; 40_000])
+                });
+            } else {
+                assert_eq!(&out[512..516], &[7u8; 4]);
+                assert!(out[out.len() - 1024..].iter().all(|&b| b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_archive_central_chunks() {
+        let files: Vec<_> = (0..5000).map(|i| (format!("dir/file-{}", i), 0)).collect();
+        let mut fc = FileCache::new();
+        let mut archive = Archive::new(ArchiveKind::Zip, String::new(), files).unwrap();
+        let mut out = Vec::new();
+        let mut buf = Vec::new();
+        let mut longest = 0;
+        while archive.fill(&mut fc, &mut buf).unwrap() {
+            longest = cmp::max(longest, buf.len());
+            out.extend_from_slice(&buf);
+        }
+        assert_eq!(out.len() as u64, archive.len());
+        assert!(longest < 2 * CHUNK_LEN as usize);
+
+        let eocd = &out[out.len() - 22..];
+        assert_eq!(LittleEndian::read_u16(&eocd[10..12]), 5000);
+        let cd_len = LittleEndian::read_u32(&eocd[12..16]) as usize;
+        let cd = LittleEndian::read_u32(&eocd[16..20]) as usize;
+        assert_eq!(cd + cd_len, out.len() - 22);
+        assert_eq!(LittleEndian::read_u32(&out[cd..cd + 4]), 0x0201_4b50);
+        // The last entry points back at its local header
+        let last = out.len() - 22 - (46 + "dir/file-4999".len());
+        let offset = LittleEndian::read_u32(&out[last + 42..last + 46]) as usize;
+        assert_eq!(&out[offset + 30..offset + 43], b"dir/file-4999");
+    }
+}
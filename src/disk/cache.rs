@@ -1,28 +1,53 @@
+use std::collections::HashSet;
 use std::ffi::OsString;
+use std::sync::{atomic, Arc};
 use std::{fs, io, mem, path};
 
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
 
+use nix::libc;
+
+use crate::config;
 use crate::util::{native, MHashMap};
-use crate::CONFIG;
+use crate::{CONFIG, FD_LIMIT};
 
 const PB_LEN: usize = 256;
+/// Descriptors left for sockets, listeners and others besides those
+/// counted by max_open_sockets and max_open_announces
+const RESERVED_FDS: usize = 64;
+/// Files kept open however low the descriptor limit is
+const MIN_FILES: usize = 16;
 
+/// Path buffers reused across disk jobs.
 pub struct BufCache {
     path_a: OsString,
     path_b: OsString,
-    buf: Vec<u8>,
 }
 
 pub struct FileCache {
     files: MHashMap<path::PathBuf, Entry>,
+    /// Files closed while written to since their last sync, which are
+    /// synced by path
+    evicted: HashSet<path::PathBuf>,
+    /// Incremented on each access, ordering files by when they were used
+    clock: u64,
+    /// Bytes read and written since the cache was created
+    read: u64,
+    written: u64,
 }
 
 pub struct Entry {
-    used: bool,
+    /// Clock value of the last access
+    used: u64,
     alloc_failed: bool,
     sparse: bool,
-    file: fs::File,
+    /// Opened for reading only, and must be reopened to write
+    read_only: bool,
+    /// Written to since it was last synced
+    dirty: bool,
+    /// Shared with blocks waiting to be sent from the file
+    file: Arc<fs::File>,
 }
 
 pub struct TempPB<'a> {
@@ -30,20 +55,6 @@ pub struct TempPB<'a> {
     buf: &'a mut OsString,
 }
 
-pub struct TempBuf<'a> {
-    buf: &'a mut Vec<u8>,
-}
-
-impl<'a> TempBuf<'a> {
-    pub fn get(&mut self, len: usize) -> &mut [u8] {
-        self.buf.reserve(len);
-        if self.buf.len() < len {
-            self.buf.resize(len, 0u8);
-        }
-        &mut self.buf[..len]
-    }
-}
-
 fn get_pb(buf: &mut OsString) -> TempPB<'_> {
     debug_assert!(buf.capacity() >= PB_LEN);
     let path = mem::replace(buf, OsString::with_capacity(0)).into();
@@ -79,16 +90,11 @@ impl BufCache {
         BufCache {
             path_a: OsString::with_capacity(PB_LEN),
             path_b: OsString::with_capacity(PB_LEN),
-            buf: Vec::with_capacity(1_048_576),
         }
     }
 
-    pub fn data(&mut self) -> (TempBuf<'_>, TempPB<'_>, TempPB<'_>) {
-        (
-            TempBuf { buf: &mut self.buf },
-            get_pb(&mut self.path_a),
-            get_pb(&mut self.path_b),
-        )
+    pub fn data(&mut self) -> (TempPB<'_>, TempPB<'_>) {
+        (get_pb(&mut self.path_a), get_pb(&mut self.path_b))
     }
 }
 
@@ -96,6 +102,10 @@ impl FileCache {
     pub fn new() -> FileCache {
         FileCache {
             files: MHashMap::default(),
+            evicted: HashSet::new(),
+            clock: 0,
+            read: 0,
+            written: 0,
         }
     }
 
@@ -105,64 +115,137 @@ impl FileCache {
         offset: u64,
         buf: &mut [u8],
     ) -> io::Result<()> {
-        self.ensure_exists(path, Err(0))?;
-        let entry = self.files.get_mut(path).unwrap();
-        entry.file.seek(SeekFrom::Start(offset))?;
-        entry.file.read_exact(buf)?;
+        self.ensure_readable(path)?;
+        let mut file = &*self.files[path].file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)?;
+        self.read += buf.len() as u64;
         Ok(())
     }
 
+    /// A handle to a file which remains usable after the file is closed
+    /// by the cache.
+    pub fn file_handle(&mut self, path: &path::Path) -> io::Result<Arc<fs::File>> {
+        self.ensure_readable(path)?;
+        Ok(self.files[path].file.clone())
+    }
+
     pub fn write_file_range(
         &mut self,
         path: &path::Path,
         size: Result<u64, u64>,
+        executable: bool,
         offset: u64,
         buf: &[u8],
     ) -> io::Result<()> {
-        self.ensure_exists(path, size)?;
+        self.ensure_exists(path, size, executable)?;
         let entry = self.files.get_mut(path).unwrap();
-        entry.file.seek(SeekFrom::Start(offset))?;
-        entry.file.write_all(&buf)?;
+        entry.dirty = true;
+        let mut file = &*entry.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&buf)?;
+        self.written += buf.len() as u64;
         Ok(())
     }
 
+    /// Bytes read and written so far.
+    pub fn io_counts(&self) -> (u64, u64) {
+        (self.read, self.written)
+    }
+
+    /// Counts bytes read from a handle outside of the cache.
+    pub fn record_read(&mut self, amnt: u64) {
+        self.read += amnt;
+    }
+
     pub fn remove_file(&mut self, path: &path::Path) {
         self.files.remove(path);
+        self.evicted.remove(path);
+    }
+
+    /// Closes every open file in a directory, or the path itself.
+    pub fn remove_dir(&mut self, dir: &path::Path) {
+        self.files.retain(|path, _| !path.starts_with(dir));
+        self.evicted.retain(|path| !path.starts_with(dir));
     }
 
+    /// Syncs a file to disk if it was written to since its last sync.
     pub fn flush_file(&mut self, path: &path::Path) {
-        self.files.get_mut(path).map(|e| e.file.sync_all().ok());
+        if let Some(entry) = self.files.get_mut(path) {
+            entry.sync(path);
+        } else if self.evicted.remove(path) {
+            sync_path(path);
+        }
+    }
+
+    /// Syncs all files written to since their last sync to disk.
+    pub fn flush_all(&mut self) {
+        for (path, entry) in &mut self.files {
+            entry.sync(path);
+        }
+        for path in self.evicted.drain() {
+            sync_path(&path);
+        }
+    }
+
+    /// Number of files written to since their last sync.
+    pub fn dirty_files(&self) -> u64 {
+        (self.files.values().filter(|e| e.dirty).count() + self.evicted.len()) as u64
     }
 
-    fn ensure_exists(&mut self, path: &path::Path, len: Result<u64, u64>) -> io::Result<()> {
+    /// Opens a file for reading without creating or modifying it.
+    fn ensure_readable(&mut self, path: &path::Path) -> io::Result<()> {
+        self.clock += 1;
+        if let Some(entry) = self.files.get_mut(path) {
+            entry.used = self.clock;
+        } else {
+            self.make_room();
+            let file = self.open(|| fs::File::open(path))?;
+            self.files.insert(
+                path.to_path_buf(),
+                Entry {
+                    file: Arc::new(file),
+                    used: self.clock,
+                    sparse: false,
+                    alloc_failed: false,
+                    read_only: true,
+                    dirty: self.evicted.remove(path),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn ensure_exists(
+        &mut self,
+        path: &path::Path,
+        len: Result<u64, u64>,
+        executable: bool,
+    ) -> io::Result<()> {
         let len_val = if let Ok(v) = len {
             v
         } else {
             len.err().unwrap()
         };
+        if self.files.get(path).map(|e| e.read_only).unwrap_or(false) {
+            self.remove_file(path);
+        }
+        self.clock += 1;
         if !self.files.contains_key(path) {
-            if self.files.len() >= CONFIG.net.max_open_files {
-                let mut removal = None;
-                // We rely on random iteration order to prove us something close to a "clock hand"
-                // like algorithm
-                for (id, entry) in &mut self.files {
-                    if entry.used {
-                        entry.used = false;
-                    } else {
-                        removal = Some(id.clone());
-                    }
-                }
-                if let Some(f) = removal {
-                    self.remove_file(&f);
-                }
-            }
-
+            self.make_room();
             fs::create_dir_all(path.parent().unwrap())?;
-            let file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .read(true)
-                .open(path)?;
+            let file = self.open(|| {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .read(true)
+                    .open(path)
+            })?;
+            if executable {
+                let mut perms = file.metadata()?.permissions();
+                perms.set_mode(perms.mode() | 0o111);
+                file.set_permissions(perms)?;
+            }
 
             let alloc_failed = if len.is_ok() && file.metadata()?.len() != len.ok().unwrap() {
                 let res = !native::fallocate(&file, len.unwrap())?;
@@ -180,15 +263,18 @@ impl FileCache {
             self.files.insert(
                 path.to_path_buf(),
                 Entry {
-                    file,
-                    used: true,
+                    file: Arc::new(file),
+                    used: self.clock,
                     sparse,
                     alloc_failed,
+                    read_only: false,
+                    dirty: self.evicted.remove(path),
                 },
             );
-        } else if len.is_ok() {
+        } else {
             let entry = self.files.get_mut(path).unwrap();
-            if entry.sparse && !entry.alloc_failed {
+            entry.used = self.clock;
+            if len.is_ok() && entry.sparse && !entry.alloc_failed {
                 debug!("Attempting delayed falloc!");
                 let file = fs::OpenOptions::new().write(true).read(true).open(path)?;
                 entry.alloc_failed = !native::fallocate(&file, len_val)?;
@@ -199,13 +285,99 @@ impl FileCache {
         }
         Ok(())
     }
+
+    /// Closes the least recently used files until there's room to open
+    /// another.
+    fn make_room(&mut self) {
+        let limit = file_limit();
+        while self.files.len() >= limit && self.evict() {}
+    }
+
+    /// Opens a file, closing others while the descriptor limit is hit.
+    fn open<F: Fn() -> io::Result<fs::File>>(&mut self, open: F) -> io::Result<fs::File> {
+        loop {
+            match open() {
+                Err(ref e) if fd_exhausted(e) && self.evict() => {
+                    debug!("Out of file descriptors with {} open", self.files.len());
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Closes the least recently used file, returning whether there was one.
+    fn evict(&mut self) -> bool {
+        let lru = match self.files.iter().min_by_key(|(_, e)| e.used) {
+            Some((path, _)) => path.clone(),
+            None => return false,
+        };
+        let entry = self.files.remove(&lru).unwrap();
+        self.close(&lru, entry);
+        true
+    }
+
+    /// Closing a file doesn't sync it, so the policies which sync files do
+    /// so first, while otherwise it's remembered as still needing it.
+    fn close(&mut self, path: &path::Path, mut entry: Entry) {
+        if !entry.dirty {
+            return;
+        }
+        match CONFIG.disk.fsync {
+            config::Fsync::Piece | config::Fsync::Periodic => entry.sync(path),
+            config::Fsync::Never | config::Fsync::File => {
+                self.evicted.insert(path.to_path_buf());
+            }
+        }
+    }
+}
+
+/// Files the cache may keep open: the configured maximum, limited to the
+/// descriptors left by sockets and announces.
+pub fn file_limit() -> usize {
+    let settings = config::settings();
+    files_within(
+        FD_LIMIT.load(atomic::Ordering::Relaxed),
+        settings.max_open_files,
+        settings.max_open_sockets + settings.max_open_announces,
+    )
+}
+
+fn files_within(fd_limit: usize, max_files: usize, other: usize) -> usize {
+    if fd_limit == 0 {
+        return max_files;
+    }
+    let left = fd_limit.saturating_sub(other + RESERVED_FDS);
+    max_files.min(left.max(MIN_FILES))
+}
+
+fn fd_exhausted(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMFILE) || e.raw_os_error() == Some(libc::ENFILE)
+}
+
+impl Entry {
+    fn sync(&mut self, path: &path::Path) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        if let Err(e) = self.file.sync_all() {
+            error!("Failed to sync {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Syncs a file which isn't open.
+fn sync_path(path: &path::Path) {
+    match fs::File::open(path).and_then(|f| f.sync_all()) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("Failed to sync {:?}: {}", path, e),
+        Ok(()) => {}
+    }
 }
 
 impl Drop for FileCache {
     fn drop(&mut self) {
-        for (_, entry) in self.files.drain() {
-            entry.file.sync_all().ok();
-        }
+        self.flush_all();
     }
 }
 
@@ -214,13 +386,103 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_tempbuf() {
-        let mut data = vec![];
-        let mut buf = TempBuf { buf: &mut data };
-        assert_eq!(buf.get(10).len(), 10);
-        assert_eq!(buf.get(20).len(), 20);
-        assert_eq!(buf.get(10).len(), 10);
-        assert_eq!(buf.get(30).len(), 30);
-        assert_eq!(buf.get(10).len(), 10);
+    fn test_read_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        let path = dir.join("file");
+        let mut fc = FileCache::new();
+        let mut buf = [0u8; 4];
+        assert!(fc.read_file_range(&path, 0, &mut buf).is_err());
+        assert!(!dir.exists());
+
+        fc.write_file_range(&path, Err(4), false, 0, b"abcd")
+            .unwrap();
+        fc.read_file_range(&path, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+
+        // Files opened for reading are reopened before writing
+        let mut fc = FileCache::new();
+        fc.read_file_range(&path, 2, &mut buf[..2]).unwrap();
+        fc.write_file_range(&path, Err(4), false, 0, b"ef").unwrap();
+        fc.read_file_range(&path, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"efcd");
+    }
+
+    #[test]
+    fn test_io_counts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        let mut fc = FileCache::new();
+        let mut buf = [0u8; 3];
+        fc.write_file_range(&path, Err(4), false, 0, b"abcd")
+            .unwrap();
+        fc.read_file_range(&path, 1, &mut buf).unwrap();
+        // Failed reads aren't counted
+        assert!(fc.read_file_range(&path, 2, &mut buf).is_err());
+        fc.record_read(5);
+        assert_eq!(fc.io_counts(), (8, 4));
+    }
+
+    #[test]
+    fn test_dirty_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let mut fc = FileCache::new();
+        let mut buf = [0u8; 4];
+        fc.write_file_range(&a, Err(4), false, 0, b"abcd").unwrap();
+        fc.write_file_range(&b, Err(4), false, 0, b"abcd").unwrap();
+        assert_eq!(fc.dirty_files(), 2);
+        fc.flush_file(&a);
+        assert_eq!(fc.dirty_files(), 1);
+        // Reads don't need syncing
+        fc.read_file_range(&a, 0, &mut buf).unwrap();
+        assert_eq!(fc.dirty_files(), 1);
+        fc.flush_all();
+        assert_eq!(fc.dirty_files(), 0);
+
+        // Files closed before being synced are still counted, and are
+        // synced by path
+        fc.write_file_range(&a, Err(4), false, 0, b"efgh").unwrap();
+        assert!(fc.evict() && fc.evict());
+        assert_eq!(fc.dirty_files(), 1);
+        fc.flush_file(&a);
+        assert_eq!(fc.dirty_files(), 0);
+        // Reopening a file carries over that it needs syncing
+        fc.write_file_range(&b, Err(4), false, 0, b"efgh").unwrap();
+        assert!(fc.evict());
+        fc.read_file_range(&b, 0, &mut buf).unwrap();
+        assert_eq!(fc.dirty_files(), 1);
+        fc.flush_all();
+        assert_eq!(fc.dirty_files(), 0);
+    }
+
+    #[test]
+    fn test_lru() {
+        let tmp = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..3).map(|i| tmp.path().join(i.to_string())).collect();
+        let mut fc = FileCache::new();
+        let mut buf = [0u8; 1];
+        for path in &paths {
+            fc.write_file_range(path, Err(1), false, 0, b"a").unwrap();
+        }
+        fc.read_file_range(&paths[0], 0, &mut buf).unwrap();
+        assert!(fc.evict());
+        assert!(!fc.files.contains_key(&paths[1]));
+        assert!(fc.evict());
+        assert!(!fc.files.contains_key(&paths[2]));
+        // Closed files are reopened when used again
+        fc.read_file_range(&paths[1], 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"a");
+        assert_eq!(fc.files.len(), 2);
+        assert!(fc.evict() && fc.evict() && !fc.evict());
+    }
+
+    #[test]
+    fn test_files_within() {
+        assert_eq!(files_within(0, 500, 450), 500);
+        assert_eq!(files_within(1024, 500, 450), 500);
+        assert_eq!(files_within(1024, 600, 450), 510);
+        assert_eq!(files_within(256, 500, 450), MIN_FILES);
     }
 }
@@ -0,0 +1,142 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use crate::buffers::{PieceBuf, PieceErr};
+use crate::util::sha1_hash;
+use crate::CONFIG;
+
+/// Maximum number of pieces of a job being hashed at once, per thread
+const PENDING_PER_THREAD: usize = 2;
+
+lazy_static! {
+    static ref HASHER: Mutex<mpsc::Sender<Job>> = Mutex::new(start(CONFIG.disk.hash_threads));
+}
+
+struct Job {
+    piece: u32,
+    data: PieceBuf,
+    hash: Vec<u8>,
+    done: mpsc::Sender<Hashed>,
+}
+
+struct Hashed {
+    piece: u32,
+    valid: bool,
+}
+
+/// Pieces of a disk job being hashed on the hasher threads, so
+/// hashing doesn't hold up disk I/O.
+pub struct Batch {
+    tx: mpsc::Sender<Hashed>,
+    rx: mpsc::Receiver<Hashed>,
+    pending: usize,
+    max_pending: usize,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        let (tx, rx) = mpsc::channel();
+        Batch {
+            tx,
+            rx,
+            pending: 0,
+            max_pending: CONFIG.disk.hash_threads.max(1) * PENDING_PER_THREAD,
+        }
+    }
+
+    /// A buffer to read a piece into, unless too many pieces are
+    /// already being hashed or it couldn't be allocated.
+    pub fn buf(&mut self, len: usize) -> Result<PieceBuf, PieceErr> {
+        if self.pending >= self.max_pending {
+            return Err(PieceErr::Busy);
+        }
+        PieceBuf::get(len)
+    }
+
+    /// Hashes a piece read into a buffer, comparing it to hash.
+    pub fn submit(&mut self, piece: u32, data: PieceBuf, hash: &[u8]) {
+        self.pending += 1;
+        let job = Job {
+            piece,
+            data,
+            hash: hash.to_vec(),
+            done: self.tx.clone(),
+        };
+        if let Err(mpsc::SendError(job)) = HASHER.lock().unwrap().send(job) {
+            // The threads can only be gone if they panicked, so hash here
+            let valid = sha1_hash(&job.data)[..] == job.hash[..];
+            self.tx.send(Hashed { piece, valid }).ok();
+        }
+    }
+
+    /// A hashed piece and whether it matched its hash, if any are done.
+    pub fn try_recv(&mut self) -> Option<(u32, bool)> {
+        let h = self.rx.try_recv().ok()?;
+        self.pending -= 1;
+        Some((h.piece, h.valid))
+    }
+
+    /// Number of pieces still being hashed.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+fn start(threads: usize) -> mpsc::Sender<Job> {
+    let (tx, rx) = mpsc::channel::<Job>();
+    let rx = Arc::new(Mutex::new(rx));
+    for i in 0..threads.max(1) {
+        let rx = rx.clone();
+        thread::Builder::new()
+            .name(format!("hasher-{}", i))
+            .spawn(move || loop {
+                let job = match rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                let valid = sha1_hash(&job.data)[..] == job.hash[..];
+                job.done
+                    .send(Hashed {
+                        piece: job.piece,
+                        valid,
+                    })
+                    .ok();
+            })
+            .expect("Failed to spawn hasher thread");
+    }
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch() {
+        let mut b = Batch::new();
+        let mut sent = 0;
+        while let Ok(mut buf) = b.buf(3) {
+            buf.copy_from_slice(b"abc");
+            let hash = if sent % 2 == 0 {
+                sha1_hash(b"abc")
+            } else {
+                [0; 20]
+            };
+            b.submit(sent, buf, &hash);
+            sent += 1;
+        }
+        assert_eq!(b.pending(), sent as usize);
+        let mut results = vec![];
+        while b.pending() > 0 {
+            if let Some(r) = b.try_recv() {
+                results.push(r);
+            }
+        }
+        results.sort();
+        for (i, (piece, valid)) in results.into_iter().enumerate() {
+            assert_eq!(piece, i as u32);
+            assert_eq!(valid, i % 2 == 0);
+        }
+        assert!(b.buf(3).is_ok());
+    }
+}
@@ -1,21 +1,33 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
 use std::io::{self, Write};
+use std::os::unix;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, fs, path, time};
 
+use chrono::{Local, Timelike};
 use fs2;
 use http_range::HttpRange;
-use sha1::{Digest, Sha1};
 use sstream::SStream;
 
+use super::archive::Archive;
+use super::cache::TempPB;
+use super::hasher::Batch;
 use super::{BufCache, FileCache, JOB_TIME_SLICE};
-use crate::buffers::Buffer;
-use crate::torrent::{Info, LocIter};
-use crate::util::{hash_to_id, io_err};
+use crate::buffers::{Buffer, PieceBuf, PieceErr, BUF_SIZE};
+use crate::config;
+use crate::instance;
+use crate::rpc::proto::message::Directory;
+use crate::torrent::{self, Bitfield, Info, LocIter};
+use crate::util::{hash_to_id, http, io_err, io_err_val, native, sha1_hash, MHashMap};
 use crate::CONFIG;
 
 static MP_BOUNDARY: &str = "qxyllcqgNchqyob";
 const EXDEV: i32 = 18;
+/// Depth of subdirectories searched for a torrent's data
+const FIND_DATA_DEPTH: usize = 8;
 
 pub struct Location {
     /// Info file index
@@ -45,10 +57,24 @@ pub enum Request {
         context: Ctx,
         path: Option<String>,
     },
+    /// Prepares a block to be sent directly from its file, reading it
+    /// as usual if it spans several files or isn't in the page cache.
+    ReadFile {
+        locations: LocIter,
+        context: Ctx,
+        path: Option<String>,
+    },
+    /// Reads a request longer than a block into a piece buffer.
+    ReadRange {
+        locations: LocIter,
+        context: Ctx,
+        path: Option<String>,
+    },
+    /// Saves torrent session snapshots. Failures are reported to the
+    /// torrent if one is given, otherwise a `Serialized` response is sent.
     Serialize {
-        tid: usize,
-        data: Vec<u8>,
-        hash: [u8; 20],
+        tid: Option<usize>,
+        snapshots: Vec<Snapshot>,
     },
     Delete {
         tid: usize,
@@ -63,45 +89,208 @@ pub enum Request {
         to: String,
         target: String,
     },
+    /// Moves the entries of the session or download directory elsewhere one
+    /// at a time, then makes it the directory used. If any entry can't be
+    /// moved, those already moved are moved back.
+    MoveDirectory {
+        directory: Directory,
+        from: String,
+        to: String,
+        /// Torrents whose data is moved, whose jobs issued before the move
+        /// run first
+        torrents: Vec<usize>,
+        /// Entries left to move. The session directory is listed again when
+        /// these run out, to move files written to it in the meantime.
+        pending: Vec<PathBuf>,
+        moved: Vec<PathBuf>,
+    },
+    /// Searches a directory tree for the files of a torrent, one
+    /// directory at a time
+    FindData {
+        tid: usize,
+        directory: String,
+        /// Paths of the torrent's files by name and length
+        files: MHashMap<(OsString, u64), Vec<PathBuf>>,
+        /// Directories left to search, with their depth
+        pending: Vec<(PathBuf, usize)>,
+        /// Number of files found laid out under each directory
+        found: BTreeMap<PathBuf, usize>,
+    },
+    /// Links files of another torrent into place once the pieces they
+    /// hold are verified, a slice of pieces at a time. Files which can't
+    /// be fully verified are copied instead, so repairing them can never
+    /// change the other torrent's data.
+    Link {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        /// Source of each file to link, by file index
+        links: BTreeMap<usize, PathBuf>,
+        /// Next piece to verify
+        piece: u32,
+        /// Files holding a piece which failed verification
+        mismatched: BTreeSet<usize>,
+    },
+    /// Finds the files holding downloaded pieces which are missing or
+    /// resized, e.g. as they were changed while synapse wasn't running
+    CheckFiles {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        priorities: Arc<Vec<u8>>,
+        pieces: Bitfield,
+    },
+    /// Creates symlinks, as (target, link)
+    Symlink {
+        tid: usize,
+        links: Vec<(PathBuf, PathBuf)>,
+    },
     Validate {
         tid: usize,
         info: Arc<Info>,
         path: Option<String>,
         idx: u32,
         invalid: Vec<u32>,
+        batch: Batch,
+        /// Limits the read rate once validation starts
+        limit: Option<RateLimit>,
     },
     ValidatePiece {
         tid: usize,
         info: Arc<Info>,
         path: Option<String>,
         piece: u32,
+        batch: Option<Batch>,
     },
+    /// Atomically replaces a file, at a path relative to the session directory
     WriteFile {
         data: Vec<u8>,
         path: PathBuf,
     },
     Download {
         client: SStream,
+        /// Resource downloaded, if it may be cancelled
+        id: Option<String>,
         ranges: Vec<HttpRange>,
         multipart: bool,
         file_len: u64,
         file_path: String,
         buf: Vec<u8>,
         buf_idx: usize,
+        keep_alive: Option<(usize, Vec<u8>, amy::Sender<DlConn>)>,
+        limit: Option<RateLimit>,
+    },
+    Archive {
+        client: SStream,
+        id: Option<String>,
+        archive: Archive,
+        buf: Vec<u8>,
+        buf_idx: usize,
+        keep_alive: Option<(usize, Vec<u8>, amy::Sender<DlConn>)>,
+        limit: Option<RateLimit>,
     },
     FreeSpace,
+    /// Cancels the queued jobs with an id, which is done
+    /// between the time slices they run in
+    Cancel(JobId),
+    /// Reports the disk use accumulated so far
+    Stats,
     Ping,
     Shutdown,
 }
 
+/// Long running jobs which may be cancelled.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobId {
+    /// Full validation of a torrent
+    Validate(usize),
+    /// Downloads of a file or archives of a torrent, by resource id
+    Transfer(String),
+    MoveDirectory,
+}
+
+/// Disk use of a torrent or a kind of job.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoStats {
+    pub read: u64,
+    pub written: u64,
+    /// Time spent running jobs
+    pub time: time::Duration,
+    /// Number of time slices jobs ran for
+    pub runs: u64,
+}
+
+/// Serialized session state of a torrent.
+pub struct Snapshot {
+    pub hash: [u8; 20],
+    pub data: Vec<u8>,
+}
+
+/// HTTP parameters of a file download.
+pub struct DlParams {
+    /// Only the response headers should be sent
+    pub head: bool,
+    pub etag: String,
+    pub last_modified: Option<String>,
+    /// Resource downloaded
+    pub id: String,
+    /// Bandwidth cap in bytes per second
+    pub rate: Option<u64>,
+    /// Connection id, bytes received past the request, and channel used
+    /// to hand the connection back to the RPC server once the response
+    /// is complete
+    pub keep_alive: Option<(usize, Vec<u8>, amy::Sender<DlConn>)>,
+}
+
+/// A kept alive download connection returned to the RPC server.
+pub struct DlConn {
+    pub id: usize,
+    pub conn: SStream,
+    /// Bytes of requests pipelined after the one responded to
+    pub pipelined: Vec<u8>,
+}
+
+/// Bandwidth cap of a download or validation.
+pub struct RateLimit {
+    rate: u64,
+    start: time::Instant,
+    sent: u64,
+}
+
 pub enum Response {
     Read { context: Ctx, data: Buffer },
+    ReadFile { context: Ctx, file: Arc<fs::File>, offset: u64 },
+    ReadRange { context: Ctx, data: PieceBuf },
+    /// A read which was dropped as no buffer was available
+    ReadDropped { context: Ctx },
     ValidationComplete { tid: usize, invalid: Vec<u32> },
     PieceValidated { tid: usize, piece: u32, valid: bool },
     ValidationUpdate { tid: usize, percent: f32 },
+    ValidationCancelled { tid: usize },
     Moved { tid: usize, path: String },
-    FreeSpace(u64),
-    Error { tid: usize, err: io::Error },
+    /// Files of a torrent which must be validated, as they're damaged
+    FilesChecked { tid: usize, damaged: Vec<PathBuf> },
+    /// Free space of the download directory, and of each storage profile
+    /// directory which could be checked
+    FreeSpace { space: u64, profiles: Vec<(String, u64)> },
+    /// The directory holding the most files of a torrent found under
+    /// the searched directory, if any
+    DataFound { tid: usize, directory: String, path: Option<String> },
+    /// Fraction of the entries of a directory moved so far
+    DirectoryMoving { directory: Directory, progress: f32 },
+    /// A directory was moved to the given path, or failed to be and was
+    /// left where it was
+    DirectoryMoved { directory: Directory, result: io::Result<String> },
+    /// A batch of snapshots was saved, of which some may have failed
+    Serialized { failed: usize },
+    /// Disk use since startup by torrent id and kind of job, and the
+    /// number of files with writes which haven't been synced
+    Stats {
+        torrents: Vec<(usize, IoStats)>,
+        jobs: Vec<(&'static str, IoStats)>,
+        dirty_files: u64,
+    },
+    Error { tid: usize, err: torrent::Error },
 }
 
 pub struct Ctx {
@@ -117,6 +306,10 @@ pub enum JobRes {
     Update(Request, Response),
     Done,
     Paused(Request),
+    /// The job cannot progress until its rate limit allows it
+    Blocked(Request),
+    /// The job waits for the validation hours, set aside until then
+    Parked(Request),
 }
 
 impl Request {
@@ -138,8 +331,34 @@ impl Request {
         }
     }
 
-    pub fn serialize(tid: usize, data: Vec<u8>, hash: [u8; 20]) -> Request {
-        Request::Serialize { tid, data, hash }
+    pub fn read_file(context: Ctx, locations: LocIter, path: Option<String>) -> Request {
+        Request::ReadFile {
+            context,
+            locations,
+            path,
+        }
+    }
+
+    pub fn read_range(context: Ctx, locations: LocIter, path: Option<String>) -> Request {
+        Request::ReadRange {
+            context,
+            locations,
+            path,
+        }
+    }
+
+    pub fn serialize(tid: usize, snapshot: Snapshot) -> Request {
+        Request::Serialize {
+            tid: Some(tid),
+            snapshots: vec![snapshot],
+        }
+    }
+
+    pub fn serialize_batch(snapshots: Vec<Snapshot>) -> Request {
+        Request::Serialize {
+            tid: None,
+            snapshots,
+        }
     }
 
     pub fn validate(tid: usize, info: Arc<Info>, path: Option<String>) -> Request {
@@ -149,6 +368,44 @@ impl Request {
             path,
             idx: 0,
             invalid: Vec::new(),
+            batch: Batch::new(),
+            limit: None,
+        }
+    }
+
+    pub fn find_data(tid: usize, info: &Info, directory: String) -> Request {
+        let mut files: MHashMap<_, Vec<_>> = MHashMap::default();
+        for (_, f) in info.user_files() {
+            if f.length == 0 || f.symlink.is_some() {
+                continue;
+            }
+            if let Some(name) = f.path.file_name() {
+                let paths = files.entry((name.to_owned(), f.length)).or_default();
+                paths.push(f.path.clone());
+            }
+        }
+        Request::FindData {
+            tid,
+            pending: vec![(PathBuf::from(&directory), 0)],
+            directory,
+            files,
+            found: BTreeMap::new(),
+        }
+    }
+
+    pub fn link(
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        links: BTreeMap<usize, PathBuf>,
+    ) -> Request {
+        Request::Link {
+            tid,
+            info,
+            path,
+            links,
+            piece: 0,
+            mismatched: BTreeSet::new(),
         }
     }
 
@@ -163,6 +420,21 @@ impl Request {
             info,
             path,
             piece,
+            batch: None,
+        }
+    }
+
+    /// Points a job on a torrent's data at the directory the data was
+    /// moved to.
+    pub fn set_path(&mut self, to: Option<String>) {
+        match self {
+            Request::Write { path, .. }
+            | Request::Read { path, .. }
+            | Request::ReadFile { path, .. }
+            | Request::ReadRange { path, .. }
+            | Request::ValidatePiece { path, .. }
+            | Request::Link { path, .. } => *path = to,
+            _ => {}
         }
     }
 
@@ -182,27 +454,56 @@ impl Request {
         }
     }
 
+    /// Writes out a complete HTTP response held in memory.
+    pub fn respond(
+        client: SStream,
+        buf: Vec<u8>,
+        keep_alive: Option<(usize, Vec<u8>, amy::Sender<DlConn>)>,
+    ) -> Request {
+        Request::Download {
+            client,
+            id: None,
+            multipart: false,
+            ranges: vec![],
+            file_path: String::new(),
+            file_len: 0,
+            buf,
+            buf_idx: 0,
+            keep_alive,
+            limit: None,
+        }
+    }
+
     pub fn download(
         client: SStream,
         mut ranges: Vec<HttpRange>,
         file_path: String,
         file_len: u64,
+        params: DlParams,
     ) -> Request {
-        let http_lines = match ranges.len() {
+        let multipart = ranges.len() > 1;
+        let mime = http::mime_type(path::Path::new(&file_path));
+        // Multipart bodies are only delimited by the connection closing
+        let keep_alive = if multipart { None } else { params.keep_alive };
+        let mut http_lines = match ranges.len() {
             0 => vec![
                 format!("HTTP/1.1 200 OK"),
-                format!("Accept-Ranges: bytes"),
                 format!("Content-Length: {}", file_len),
-                format!("Content-Type: application/octet-stream"),
+                format!("Content-Type: {}", mime),
                 format!(
-                    "Content-Disposition: attachment; filename=\"{}\"",
-                    path::Path::new(&file_path)
-                        .file_name()
-                        .unwrap()
-                        .to_string_lossy()
+                    "Content-Disposition: {}",
+                    http::content_disposition(
+                        if http::mime_inline(mime) {
+                            "inline"
+                        } else {
+                            "attachment"
+                        },
+                        &path::Path::new(&file_path)
+                            .file_name()
+                            .unwrap()
+                            .to_string_lossy()
+                    )
                 ),
-                format!("Connection: Close"),
-                format!("\r\n"),
             ],
             1 => vec![
                 format!("HTTP/1.1 206 Partial Content"),
@@ -213,39 +514,51 @@ impl Request {
                     ranges[0].start + ranges[0].length - 1,
                     file_len
                 ),
-                format!("Accept-Ranges: bytes"),
-                format!("Content-Type: application/octet-stream"),
-                format!("Connection: Close"),
-                format!("\r\n"),
+                format!("Content-Type: {}", mime),
             ],
             _ => vec![
                 format!("HTTP/1.1 206 Partial Content"),
-                format!("Accept-Ranges: bytes"),
                 format!(
                     "Content-Type: {}; boundary={}",
                     "multipart/byteranges", MP_BOUNDARY
                 ),
-                format!("Connection: Close"),
-                // Add the first multipart boundary here manually.
-                // Because the job processing code only writes boundaries
-                // when ranges are complete we can either add a fake range
-                // which immediately triggers this write or we can manully
-                // add the boundary here since I find it less confusing.
-                format!("\r\n--{}", MP_BOUNDARY),
-                format!("Content-Type: application/octet-stream"),
-                format!(
-                    "Content-Range: bytes {}-{}/{}",
-                    ranges[0].start,
-                    ranges[0].start + ranges[0].length - 1,
-                    file_len
-                ),
-                format!("\r\n"),
             ],
         };
+        http_lines.push("Accept-Ranges: bytes".to_string());
+        // Browsers must not guess a type which can run scripts
+        http_lines.push("X-Content-Type-Options: nosniff".to_string());
+        http_lines.push(format!("ETag: {}", params.etag));
+        if let Some(date) = params.last_modified {
+            http_lines.push(format!("Last-Modified: {}", date));
+        }
+        if keep_alive.is_some() {
+            http_lines.push("Connection: keep-alive".to_string());
+        } else {
+            http_lines.push("Connection: Close".to_string());
+        }
+        if multipart && !params.head {
+            // Add the first multipart boundary here manually.
+            // Because the job processing code only writes boundaries
+            // when ranges are complete we can either add a fake range
+            // which immediately triggers this write or we can manully
+            // add the boundary here since I find it less confusing.
+            http_lines.push(format!("\r\n--{}", MP_BOUNDARY));
+            http_lines.push(format!("Content-Type: {}", mime));
+            http_lines.push(format!(
+                "Content-Range: bytes {}-{}/{}",
+                ranges[0].start,
+                ranges[0].start + ranges[0].length - 1,
+                file_len
+            ));
+        }
+        http_lines.push("\r\n".to_string());
         let buf = http_lines.join("\r\n").into_bytes();
-        // Add a single range containing the single file if this is
-        // a plain http request.
-        if ranges.is_empty() {
+        if params.head {
+            // HEAD responses consist solely of the headers
+            ranges.clear();
+        } else if ranges.is_empty() {
+            // Add a single range containing the single file if this is
+            // a plain http request.
             ranges = vec![HttpRange {
                 start: 0,
                 length: file_len,
@@ -257,12 +570,54 @@ impl Request {
         ranges.reverse();
         Request::Download {
             client,
-            multipart: ranges.len() > 1,
+            id: Some(params.id),
+            multipart,
             ranges,
             file_path,
             file_len,
             buf,
             buf_idx: 0,
+            keep_alive,
+            limit: params.rate.map(RateLimit::new),
+        }
+    }
+
+    pub fn archive(client: SStream, mut archive: Archive, name: &str, params: DlParams) -> Request {
+        let mut http_lines = vec![
+            format!("HTTP/1.1 200 OK"),
+            format!("Content-Length: {}", archive.len()),
+            format!("Content-Type: {}", archive.kind().mime()),
+            format!(
+                "Content-Disposition: {}",
+                http::content_disposition(
+                    "attachment",
+                    &format!("{}.{}", name, archive.kind().extension())
+                )
+            ),
+            format!("Accept-Ranges: none"),
+            format!("X-Content-Type-Options: nosniff"),
+            format!("ETag: {}", params.etag),
+        ];
+        if let Some(date) = params.last_modified {
+            http_lines.push(format!("Last-Modified: {}", date));
+        }
+        if params.keep_alive.is_some() {
+            http_lines.push("Connection: keep-alive".to_string());
+        } else {
+            http_lines.push("Connection: Close".to_string());
+        }
+        http_lines.push("\r\n".to_string());
+        if params.head {
+            archive.finish();
+        }
+        Request::Archive {
+            client,
+            id: Some(params.id),
+            archive,
+            buf: http_lines.join("\r\n").into_bytes(),
+            buf_idx: 0,
+            keep_alive: params.keep_alive,
+            limit: params.rate.map(RateLimit::new),
         }
     }
 
@@ -270,25 +625,125 @@ impl Request {
         Request::Shutdown
     }
 
+    /// The id the job may be cancelled by, if any.
+    pub fn job_id(&self) -> Option<JobId> {
+        match self {
+            Request::Validate { tid, .. } => Some(JobId::Validate(*tid)),
+            Request::Download { id, .. } | Request::Archive { id, .. } => {
+                id.clone().map(JobId::Transfer)
+            }
+            Request::MoveDirectory { .. } => Some(JobId::MoveDirectory),
+            _ => None,
+        }
+    }
+
+    /// Whether the job must wait for another queued before it to finish,
+    /// as a directory move does for jobs on the data it moves.
+    pub fn waits_for(&self, other: &Request) -> bool {
+        match self {
+            Request::MoveDirectory { torrents, .. } => {
+                other.tid().map(|t| torrents.contains(&t)).unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+
+    /// Abandons the job, returning the response to send for it.
+    pub fn cancel(self, fc: &mut FileCache) -> Option<Response> {
+        match self {
+            Request::Validate { tid, .. } => Some(Response::ValidationCancelled { tid }),
+            Request::MoveDirectory {
+                directory,
+                from,
+                to,
+                moved,
+                ..
+            } => {
+                move_back(fc, &from, &to, &moved);
+                Some(Response::DirectoryMoved {
+                    directory,
+                    result: Err(io::Error::new(io::ErrorKind::Interrupted, "Cancelled")),
+                })
+            }
+            // Dropping transfers closes their connections
+            _ => None,
+        }
+    }
+
+    /// Name of the kind of job, which disk use is grouped by.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Write { .. } => "write",
+            Request::Read { .. } | Request::ReadFile { .. } | Request::ReadRange { .. } => "read",
+            Request::Serialize { .. } | Request::WriteFile { .. } => "serialize",
+            Request::Delete { .. } => "delete",
+            Request::Move { .. } => "move",
+            Request::MoveDirectory { .. } => "move_directory",
+            Request::FindData { .. } => "find_data",
+            Request::CheckFiles { .. } => "check_files",
+            Request::Link { .. } | Request::Symlink { .. } => "link",
+            Request::Validate { .. } => "validate",
+            Request::ValidatePiece { .. } => "validate_piece",
+            Request::Download { .. } => "download",
+            Request::Archive { .. } => "archive",
+            Request::FreeSpace
+            | Request::Cancel(_)
+            | Request::Stats
+            | Request::Ping
+            | Request::Shutdown => "control",
+        }
+    }
+
     pub fn concurrent(&self) -> bool {
         match self {
-            Request::Validate { .. } => false,
+            // Linking runs before the validation following it
+            Request::Validate { .. } | Request::Link { .. } => false,
             _ => true,
         }
     }
 
+    /// Whether the job must run to completion before shutdown, as abandoning
+    /// it would lose data. Reads, validation and transfers are dropped.
+    pub fn completes_on_shutdown(&self) -> bool {
+        !matches!(
+            self,
+            Request::Read { .. }
+                | Request::ReadFile { .. }
+                | Request::ReadRange { .. }
+                | Request::Validate { .. }
+                | Request::ValidatePiece { .. }
+                | Request::FindData { .. }
+                | Request::CheckFiles { .. }
+                | Request::Link { .. }
+                | Request::Download { .. }
+                | Request::Archive { .. }
+                | Request::FreeSpace
+                | Request::Cancel(_)
+                | Request::Stats
+                | Request::Ping
+                | Request::Shutdown
+        )
+    }
+
     pub fn execute(self, fc: &mut FileCache, bc: &mut BufCache) -> io::Result<JobRes> {
-        let sd = &CONFIG.disk.session;
-        let dd = &CONFIG.disk.directory;
-        let (mut tb, mut tpb, mut tpb2) = bc.data();
+        let (mut tpb, mut tpb2) = bc.data();
         match self {
             Request::Ping => {}
             Request::FreeSpace => {
-                let free_space = fs2::available_space(dd.as_str())?;
-                return Ok(JobRes::Resp(Response::FreeSpace(free_space)));
+                let space = fs2::available_space(&config::settings().directory)?;
+                // Directories which can't be checked, e.g. unmounted ones, are left out
+                let profiles = CONFIG
+                    .disk
+                    .profiles
+                    .iter()
+                    .flat_map(|p| &p.directories)
+                    .filter_map(|d| Some((d.clone(), fs2::available_space(d).ok()?)))
+                    .collect();
+                return Ok(JobRes::Resp(Response::FreeSpace { space, profiles }));
             }
             Request::WriteFile { path, data } => {
-                let p = tpb.get(path.iter());
+                let path = Path::new(&config::settings().session).join(path);
+                let p = tpb.get(&path);
                 p.set_extension("temp");
                 let res = fs::OpenOptions::new()
                     .write(true)
@@ -314,8 +769,9 @@ impl Request {
                 path,
                 ..
             } => {
-                for loc in locations {
-                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                let dir = torrent_dir(&path);
+                for loc in locations.filter(|loc| !loc.padding()) {
+                    let pb = tpb.get(&*dir);
                     pb.push(loc.path());
                     fc.write_file_range(
                         &pb,
@@ -324,11 +780,12 @@ impl Request {
                         } else {
                             Err(loc.file_len)
                         },
+                        loc.executable(),
                         loc.offset,
                         &data[loc.start..loc.end],
                     )?;
-                    if loc.end - loc.start != 16_384 {
-                        fc.flush_file(&pb);
+                    if CONFIG.disk.fsync == config::Fsync::File && loc.end - loc.start != BUF_SIZE {
+                        fc.flush_file(pb);
                     }
                 }
             }
@@ -339,13 +796,58 @@ impl Request {
                 path,
                 ..
             } => {
+                let dir = torrent_dir(&path);
                 for loc in locations {
-                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                    pb.push(loc.path());
-                    fc.read_file_range(&pb, loc.offset, &mut data[loc.start..loc.end])?;
+                    read_loc(fc, &mut tpb, &dir, &loc, &mut data)?;
+                }
+                return Ok(JobRes::Resp(Response::read(context, data)));
+            }
+            Request::ReadFile {
+                context,
+                locations,
+                path,
+            } => {
+                let locs: Vec<_> = locations.collect();
+                let dir = torrent_dir(&path);
+                // Padding is never on disk, so it's read into a buffer as zeros.
+                // Sending a block which isn't cached would block the peer's
+                // event loop on disk, so those are read here as usual.
+                if let [loc] = &locs[..] {
+                    if !loc.padding() {
+                        let pb = tpb.get(&*dir);
+                        pb.push(loc.path());
+                        let file = fc.file_handle(pb)?;
+                        let len = (loc.end - loc.start) as u64;
+                        if native::is_cached(&file, loc.offset, len) {
+                            // The block is read when it's sent
+                            fc.record_read(len);
+                            return Ok(JobRes::Resp(Response::ReadFile {
+                                context,
+                                file,
+                                offset: loc.offset,
+                            }));
+                        }
+                    }
+                }
+                if context.length as usize > BUF_SIZE {
+                    return read_range(fc, &mut tpb, &dir, context, locs.into_iter());
+                }
+                let mut data = match Buffer::get() {
+                    Some(data) => data,
+                    None => return Ok(JobRes::Resp(Response::ReadDropped { context })),
+                };
+                for loc in locs {
+                    read_loc(fc, &mut tpb, &dir, &loc, &mut data)?;
                 }
                 return Ok(JobRes::Resp(Response::read(context, data)));
             }
+            Request::ReadRange {
+                context,
+                locations,
+                path,
+            } => {
+                return read_range(fc, &mut tpb, &torrent_dir(&path), context, locations);
+            }
             Request::Move {
                 tid,
                 from,
@@ -356,39 +858,328 @@ impl Request {
                 let tp = tpb2.get(&to);
                 fp.push(target.clone());
                 tp.push(target);
-                match fs::rename(&fp, &tp) {
-                    Ok(_) => {}
-                    // Cross filesystem move, try to copy then delete
-                    Err(ref e) if e.raw_os_error() == Some(EXDEV) => {
-                        match fs_extra::dir::copy(&fp, &tp, &fs_extra::dir::CopyOptions::new()) {
-                            Ok(_) => {
-                                fs::remove_dir_all(&fp)?;
-                            }
-                            Err(e) => {
-                                fs::remove_dir_all(&tp)?;
-                                error!("FS copy failed: {:?}", e);
-                                return io_err("Failed to copy directory across filesystems!");
+                if let Err(e) = move_path(fp, tp) {
+                    error!("FS move failed: {:?}", e);
+                    return Err(e);
+                }
+                return Ok(JobRes::Resp(Response::moved(tid, to)));
+            }
+            Request::MoveDirectory {
+                directory,
+                from,
+                to,
+                torrents,
+                mut pending,
+                mut moved,
+            } => {
+                if pending.is_empty() && directory == Directory::Session {
+                    // The lock is moved once everything else has been
+                    let entries = fs::read_dir(&from).and_then(|dir| {
+                        dir.map(|e| e.map(|e| PathBuf::from(e.file_name())))
+                            .filter(|e| {
+                                e.as_ref()
+                                    .map(|e| e.as_os_str() != instance::LOCK_FILE)
+                                    .unwrap_or(true)
+                            })
+                            .collect()
+                    });
+                    match entries {
+                        Ok(entries) => pending = entries,
+                        Err(e) => {
+                            error!("Failed to list {}: {}", from, e);
+                            move_back(fc, &from, &to, &moved);
+                            return Ok(JobRes::Resp(Response::DirectoryMoved {
+                                directory,
+                                result: Err(e),
+                            }));
+                        }
+                    }
+                }
+                let entry = match pending.pop() {
+                    Some(entry) => entry,
+                    None => {
+                        let mut settings = config::settings().clone();
+                        match directory {
+                            Directory::Session => {
+                                // Without this the next start would find no
+                                // sessions
+                                if let Err(e) = config::persist_session(&to) {
+                                    error!("Failed to record the session directory: {}", e);
+                                    move_back(fc, &from, &to, &moved);
+                                    return Ok(JobRes::Resp(Response::DirectoryMoved {
+                                        directory,
+                                        result: Err(e),
+                                    }));
+                                }
+                                if let Err(e) = instance::relocate(Path::new(&to)) {
+                                    error!("Failed to move the session lock to {}: {}", to, e);
+                                }
+                                settings.session = to.clone();
+                                fs::remove_dir(&from).ok();
                             }
+                            Directory::Download => settings.directory = to.clone(),
                         }
+                        config::update_settings(settings);
+                        return Ok(JobRes::Resp(Response::DirectoryMoved {
+                            directory,
+                            result: Ok(to),
+                        }));
                     }
-                    Err(e) => {
-                        error!("FS rename failed: {:?}", e);
-                        return Err(e);
+                };
+                let fp = Path::new(&from).join(&entry);
+                let tp = Path::new(&to).join(&entry);
+                // Nothing may have been downloaded yet
+                if fs::symlink_metadata(&fp).is_err() {
+                    return Ok(JobRes::Paused(Request::MoveDirectory {
+                        directory,
+                        from,
+                        to,
+                        torrents,
+                        pending,
+                        moved,
+                    }));
+                }
+                fc.remove_dir(&fp);
+                let res = if moved.contains(&entry) {
+                    // Written again since it was moved, so replace it
+                    remove_path(&tp).and_then(|_| move_path(&fp, &tp))
+                } else if fs::symlink_metadata(&tp).is_ok() {
+                    Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} already exists", tp.display()),
+                    ))
+                } else {
+                    fs::create_dir_all(&to).and_then(|_| move_path(&fp, &tp))
+                };
+                if let Err(e) = res {
+                    error!("Failed to move {}: {}", fp.display(), e);
+                    move_back(fc, &from, &to, &moved);
+                    return Ok(JobRes::Resp(Response::DirectoryMoved {
+                        directory,
+                        result: Err(e),
+                    }));
+                }
+                if !moved.contains(&entry) {
+                    moved.push(entry);
+                }
+                let progress = moved.len() as f32 / (moved.len() + pending.len()) as f32;
+                return Ok(JobRes::Update(
+                    Request::MoveDirectory {
+                        directory,
+                        from,
+                        to,
+                        torrents,
+                        pending,
+                        moved,
+                    },
+                    Response::DirectoryMoving {
+                        directory,
+                        progress,
+                    },
+                ));
+            }
+            Request::FindData {
+                tid,
+                directory,
+                files,
+                mut pending,
+                mut found,
+            } => {
+                let (dir, depth) = match pending.pop() {
+                    Some(entry) => entry,
+                    None => {
+                        // Only a directory holding every file is used, so
+                        // files of the same name elsewhere can't be mixed in
+                        let total: usize = files.values().map(Vec::len).sum();
+                        let path = found
+                            .into_iter()
+                            .find(|&(_, n)| n == total)
+                            .map(|(path, _)| path.to_string_lossy().into_owned());
+                        return Ok(JobRes::Resp(Response::DataFound {
+                            tid,
+                            directory,
+                            path,
+                        }));
+                    }
+                };
+                // Unreadable directories are skipped
+                for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                    let (path, kind) = match entry.file_type() {
+                        Ok(kind) => (entry.path(), kind),
+                        Err(_) => continue,
+                    };
+                    if kind.is_dir() && depth < FIND_DATA_DEPTH {
+                        pending.push((path, depth + 1));
+                        continue;
+                    }
+                    let len = match entry.metadata() {
+                        Ok(ref m) if kind.is_file() => m.len(),
+                        _ => continue,
+                    };
+                    let paths = match files.get(&(entry.file_name(), len)) {
+                        Some(paths) => paths,
+                        None => continue,
+                    };
+                    for p in paths.iter().filter(|p| path.ends_with(p)) {
+                        if let Some(base) = path.ancestors().nth(p.components().count()) {
+                            *found.entry(base.to_path_buf()).or_insert(0) += 1;
+                        }
                     }
                 }
-                return Ok(JobRes::Resp(Response::moved(tid, to)));
+                return Ok(JobRes::Paused(Request::FindData {
+                    tid,
+                    directory,
+                    files,
+                    pending,
+                    found,
+                }));
             }
-            Request::Serialize { data, hash, .. } => {
-                let temp = tpb.get(sd);
-                temp.push(hash_to_id(&hash) + ".temp");
-                let mut f = fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(&temp)?;
-                f.write_all(&data)?;
-                let actual = tpb2.get(sd);
-                actual.push(hash_to_id(&hash));
-                fs::rename(temp, actual)?;
+            Request::Link {
+                tid,
+                info,
+                path,
+                links,
+                mut piece,
+                mut mismatched,
+            } => {
+                let dir = torrent_dir(&path);
+                let start = time::Instant::now();
+                let mut buf = Vec::new();
+                while piece < info.pieces()
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                {
+                    let locs: Vec<_> = Info::piece_disk_locs(&info, piece).collect();
+                    piece += 1;
+                    // Only pieces which could still rule out a file are read
+                    let unverified = |f: &usize| links.contains_key(f) && !mismatched.contains(f);
+                    if !locs.iter().any(|loc| unverified(&loc.file)) {
+                        continue;
+                    }
+                    buf.resize(info.piece_len(piece - 1) as usize, 0);
+                    let read = locs.iter().all(|loc| match links.get(&loc.file) {
+                        Some(src) => fc
+                            .read_file_range(src, loc.offset, &mut buf[loc.start..loc.end])
+                            .is_ok(),
+                        None => read_loc(fc, &mut tpb, &dir, loc, &mut buf).is_ok(),
+                    });
+                    if !read || sha1_hash(&buf)[..] != info.hashes[piece as usize - 1][..] {
+                        mismatched.extend(
+                            locs.iter()
+                                .map(|loc| loc.file)
+                                .filter(|f| links.contains_key(f)),
+                        );
+                    }
+                }
+                if piece < info.pieces() {
+                    return Ok(JobRes::Paused(Request::Link {
+                        tid,
+                        info,
+                        path,
+                        links,
+                        piece,
+                        mismatched,
+                    }));
+                }
+                for (file, src) in links {
+                    let dst = Path::new(&*dir).join(&info.files[file].path);
+                    // Never clobber data the torrent may already have
+                    if dst.exists() {
+                        continue;
+                    }
+                    if let Some(dir) = dst.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    fc.remove_file(&dst);
+                    // Hard links can't cross filesystems, fall back to a copy
+                    let copy = || fs::copy(&src, &dst).map(|_| ());
+                    let res = if mismatched.contains(&file) {
+                        copy()
+                    } else {
+                        fs::hard_link(&src, &dst).or_else(|_| copy())
+                    };
+                    if let Err(e) = res {
+                        info!("Failed to link {:?} to {:?}: {}", src, dst, e);
+                    }
+                }
+            }
+            Request::CheckFiles {
+                tid,
+                info,
+                path,
+                priorities,
+                pieces,
+            } => {
+                let dir = torrent_dir(&path);
+                let dir = Path::new(&*dir);
+                let damaged = torrent::damaged_files(&info, &priorities, &pieces, dir);
+                return Ok(JobRes::Resp(Response::FilesChecked { tid, damaged }));
+            }
+            Request::Symlink { links, .. } => {
+                for (target, link) in links {
+                    if fs::symlink_metadata(&link).is_ok() {
+                        continue;
+                    }
+                    if let Some(dir) = link.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    if let Err(e) = unix::fs::symlink(&target, &link) {
+                        info!("Failed to symlink {:?} to {:?}: {}", link, target, e);
+                    }
+                }
+            }
+            Request::Serialize { tid, snapshots } => {
+                let sd = config::settings().session.clone();
+                let mut failed = 0;
+                let mut fail = |id: &str, e: io::Error| {
+                    error!("Failed to save snapshot of {}: {}", id, e);
+                    failed += 1;
+                };
+                // Every snapshot is written and then synced before any is
+                // renamed, so a crash never leaves a truncated session file
+                // and the syncs are issued together rather than one per write.
+                let mut written = Vec::with_capacity(snapshots.len());
+                for snapshot in snapshots {
+                    let id = hash_to_id(&snapshot.hash);
+                    let temp = tpb.get(&sd);
+                    temp.push(id.clone() + ".temp");
+                    let res = fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&temp)
+                        .and_then(|mut f| f.write_all(&snapshot.data).map(|_| f));
+                    match res {
+                        Ok(f) => written.push((id, f)),
+                        Err(e) if tid.is_some() => return Err(e),
+                        Err(e) => fail(&id, e),
+                    }
+                }
+                let mut synced = Vec::with_capacity(written.len());
+                for (id, f) in written {
+                    match f.sync_data() {
+                        Ok(()) => synced.push(id),
+                        Err(e) if tid.is_some() => return Err(e),
+                        Err(e) => fail(&id, e),
+                    }
+                }
+                for id in &synced {
+                    let temp = tpb.get(&sd);
+                    temp.push(id.clone() + ".temp");
+                    let actual = tpb2.get(&sd);
+                    actual.push(id);
+                    match fs::rename(&temp, &actual) {
+                        Ok(()) => {}
+                        Err(e) if tid.is_some() => return Err(e),
+                        Err(e) => fail(id, e),
+                    }
+                }
+                // Make the renames themselves durable
+                if !synced.is_empty() {
+                    fs::File::open(&sd).and_then(|d| d.sync_all()).ok();
+                }
+                if tid.is_none() {
+                    return Ok(JobRes::Resp(Response::Serialized { failed }));
+                }
             }
             Request::Delete {
                 hash,
@@ -398,15 +1189,16 @@ impl Request {
                 ..
             } => {
                 {
-                    let spb = tpb.get(sd);
+                    let spb = tpb.get(&config::settings().session);
                     spb.push(hash_to_id(&hash));
                     fs::remove_file(&spb).ok();
                     spb.set_extension("torrent");
                     fs::remove_file(&spb).ok();
                 }
 
+                let dir = torrent_dir(&path);
                 for file in &files {
-                    let pb = tpb2.get(path.as_ref().unwrap_or(dd));
+                    let pb = tpb2.get(&*dir);
                     pb.push(&file);
                     fc.remove_file(&pb);
                     if artifacts {
@@ -419,7 +1211,7 @@ impl Request {
                 if let Some(p) = files.get(0) {
                     let comp = p.components().next().unwrap();
                     let dirp: &Path = comp.as_os_str().as_ref();
-                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    let pb = tpb.get(&*dir);
                     pb.push(&dirp);
                     fs::remove_dir(&pb).ok();
                 }
@@ -429,22 +1221,57 @@ impl Request {
                 info,
                 path,
                 piece,
+                batch: None,
             } => {
-                let buf = tb.get(info.piece_len as usize);
-                let mut ctx = Sha1::new();
-                let locs = Info::piece_disk_locs(&info, piece);
-                for loc in locs {
-                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                    pb.push(loc.path());
-                    fc.read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
-                        .map(|_| ctx.update(&buf[loc.start..loc.end]))
-                        .ok();
+                let mut batch = Batch::new();
+                let len = info.piece_len(piece) as usize;
+                let mut buf = match batch.buf(len) {
+                    Ok(buf) => buf,
+                    Err(PieceErr::Alloc) => return io_err("Failed to allocate piece buffer"),
+                    Err(PieceErr::Busy) => {
+                        return Ok(JobRes::Blocked(Request::ValidatePiece {
+                            tid,
+                            info,
+                            path,
+                            piece,
+                            batch: None,
+                        }))
+                    }
+                };
+                read_piece(fc, &mut tpb, &torrent_dir(&path), &info, piece, &mut buf);
+                batch.submit(piece, buf, &info.hashes[piece as usize]);
+                return Ok(JobRes::Blocked(Request::ValidatePiece {
+                    tid,
+                    info,
+                    path,
+                    piece,
+                    batch: Some(batch),
+                }));
+            }
+            Request::ValidatePiece {
+                tid,
+                info,
+                path,
+                piece,
+                batch: Some(mut batch),
+            } => {
+                if let Some((_, valid)) = batch.try_recv() {
+                    if valid && CONFIG.disk.fsync == config::Fsync::Piece {
+                        let dir = torrent_dir(&path);
+                        for loc in Info::piece_disk_locs(&info, piece).filter(|l| !l.padding()) {
+                            let pb = tpb.get(&*dir);
+                            pb.push(loc.path());
+                            fc.flush_file(pb);
+                        }
+                    }
+                    return Ok(JobRes::Resp(Response::PieceValidated { tid, piece, valid }));
                 }
-                let digest = ctx.finalize();
-                return Ok(JobRes::Resp(Response::PieceValidated {
+                return Ok(JobRes::Blocked(Request::ValidatePiece {
                     tid,
+                    info,
+                    path,
                     piece,
-                    valid: digest[..] == info.hashes[piece as usize][..],
+                    batch: Some(batch),
                 }));
             }
             Request::Validate {
@@ -453,63 +1280,96 @@ impl Request {
                 path,
                 mut idx,
                 mut invalid,
+                mut batch,
+                mut limit,
             } => {
-                let buf = tb.get(info.piece_len as usize);
+                let hour = Local::now().hour();
+                if idx == 0 && !CONFIG.disk.validation_hour(hour) {
+                    return Ok(JobRes::Parked(Request::Validate {
+                        tid,
+                        info,
+                        path,
+                        idx,
+                        invalid,
+                        batch,
+                        limit,
+                    }));
+                }
+                if let Some(rate) = CONFIG.disk.validate_rate {
+                    limit.get_or_insert_with(|| RateLimit::new(rate.saturating_mul(1024 * 1024)));
+                }
+                let dir = torrent_dir(&path);
                 let start = time::Instant::now();
-
-                while idx < info.pieces()
-                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
-                {
-                    let mut valid = true;
-                    let mut ctx = Sha1::new();
-                    let locs = Info::piece_disk_locs(&info, idx);
-                    for loc in locs {
+                // Read pieces while the hasher threads keep up, letting
+                // other jobs run while waiting on them
+                let mut progressed = false;
+                while start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
+                    while let Some((piece, valid)) = batch.try_recv() {
+                        progressed = true;
                         if !valid {
-                            break;
+                            invalid.push(piece);
                         }
-                        let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                        pb.push(loc.path());
-                        valid &= fc
-                            .read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
-                            .map(|_| ctx.update(&buf[loc.start..loc.end]))
-                            .is_ok();
                     }
-                    let digest = ctx.finalize();
-                    if !valid || digest[..] != info.hashes[idx as usize][..] {
+                    if idx == info.pieces() {
+                        break;
+                    }
+                    if limit.as_ref().map(|l| l.allowance() == 0).unwrap_or(false) {
+                        break;
+                    }
+                    let len = info.piece_len(idx) as usize;
+                    let mut buf = match batch.buf(len) {
+                        Ok(buf) => buf,
+                        Err(PieceErr::Alloc) => return io_err("Failed to allocate piece buffer"),
+                        Err(PieceErr::Busy) => break,
+                    };
+                    if let Some(ref mut l) = limit {
+                        l.consume(len as u64);
+                    }
+                    progressed = true;
+                    if read_piece(fc, &mut tpb, &dir, &info, idx, &mut buf) {
+                        batch.submit(idx, buf, &info.hashes[idx as usize]);
+                    } else {
                         invalid.push(idx);
                     }
-
                     idx += 1;
                 }
-                if idx == info.pieces() {
+                if idx == info.pieces() && batch.pending() == 0 {
+                    invalid.sort_unstable();
                     return Ok(JobRes::Resp(Response::validation_complete(tid, invalid)));
-                } else {
-                    let pieces = info.pieces();
-                    return Ok(JobRes::Update(
-                        Request::Validate {
-                            tid,
-                            info,
-                            path,
-                            idx,
-                            invalid,
-                        },
-                        Response::ValidationUpdate {
-                            tid,
-                            percent: idx as f32 / pieces as f32,
-                        },
-                    ));
                 }
+                let pieces = info.pieces();
+                let percent = (idx as usize - batch.pending()) as f32 / pieces as f32;
+                let job = Request::Validate {
+                    tid,
+                    info,
+                    path,
+                    idx,
+                    invalid,
+                    batch,
+                    limit,
+                };
+                if !progressed {
+                    return Ok(JobRes::Blocked(job));
+                }
+                return Ok(JobRes::Update(
+                    job,
+                    Response::ValidationUpdate { tid, percent },
+                ));
             }
             Request::Download {
                 mut client,
+                id,
                 file_path,
                 file_len,
                 mut ranges,
                 mut buf,
                 mut buf_idx,
                 multipart,
+                keep_alive,
+                mut limit,
             } => {
                 let start = time::Instant::now();
+                let mut blocked = false;
                 'outer: while start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
                     // First write out all remaining data in buf
                     while buf_idx != buf.len() {
@@ -528,16 +1388,34 @@ impl Request {
 
                     // If we've run out of ranges to write out, we're done
                     if ranges.is_empty() {
+                        if let Some((id, pipelined, tx)) = keep_alive {
+                            tx.send(DlConn {
+                                id,
+                                conn: client,
+                                pipelined,
+                            })
+                            .ok();
+                        }
                         return Ok(JobRes::Done);
                     }
                     // Now try to read out the next chunk of the current range, updating
                     // buf and the current range appropriately
                     let cur_range = ranges.last_mut().unwrap();
                     // Either read 128 KiB or the rest of the range
-                    let chunk_len = cmp::min(1024 * 128, cur_range.length) as usize;
-                    buf.resize(chunk_len, 0);
+                    let mut chunk_len = cmp::min(1024 * 128, cur_range.length);
+                    if let Some(ref limit) = limit {
+                        chunk_len = cmp::min(chunk_len, limit.allowance());
+                        if chunk_len == 0 {
+                            blocked = true;
+                            break 'outer;
+                        }
+                    }
+                    buf.resize(chunk_len as usize, 0);
                     buf_idx = 0;
                     fc.read_file_range(path::Path::new(&file_path), cur_range.start, &mut buf)?;
+                    if let Some(ref mut limit) = limit {
+                        limit.consume(chunk_len);
+                    }
                     cur_range.length -= buf.len() as u64;
                     cur_range.start += buf.len() as u64;
 
@@ -550,7 +1428,10 @@ impl Request {
                             let http_lines = match ranges.last() {
                                 Some(cur_range) => vec![
                                     format!("\r\n--{}", MP_BOUNDARY),
-                                    format!("Content-Type: application/octet-stream"),
+                                    format!(
+                                        "Content-Type: {}",
+                                        http::mime_type(path::Path::new(&file_path))
+                                    ),
                                     format!(
                                         "Content-Range: bytes {}-{}/{}",
                                         cur_range.start,
@@ -566,28 +1447,96 @@ impl Request {
                         }
                     }
                 }
-                return Ok(JobRes::Paused(Request::Download {
+                let job = Request::Download {
                     client,
+                    id,
                     file_path,
                     file_len,
                     ranges,
                     buf,
                     buf_idx,
                     multipart,
-                }));
+                    keep_alive,
+                    limit,
+                };
+                return Ok(if blocked {
+                    JobRes::Blocked(job)
+                } else {
+                    JobRes::Paused(job)
+                });
             }
-            Request::Shutdown => unreachable!(),
+            Request::Archive {
+                mut client,
+                id,
+                mut archive,
+                mut buf,
+                mut buf_idx,
+                keep_alive,
+                mut limit,
+            } => {
+                let start = time::Instant::now();
+                let mut blocked = false;
+                'archive: while start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
+                    while buf_idx != buf.len() {
+                        match client.write(&buf[buf_idx..]) {
+                            Ok(n) => buf_idx += n,
+                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(ref e)
+                                if e.kind() == io::ErrorKind::WouldBlock
+                                    || e.kind() == io::ErrorKind::TimedOut =>
+                            {
+                                break 'archive
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+
+                    if limit.as_ref().map(|l| l.allowance() == 0).unwrap_or(false) {
+                        blocked = true;
+                        break;
+                    }
+                    buf_idx = 0;
+                    let more = archive.fill(fc, &mut buf)?;
+                    if let Some(ref mut limit) = limit {
+                        limit.consume(buf.len() as u64);
+                    }
+                    if !more {
+                        if let Some((id, pipelined, tx)) = keep_alive {
+                            tx.send(DlConn {
+                                id,
+                                conn: client,
+                                pipelined,
+                            })
+                            .ok();
+                        }
+                        return Ok(JobRes::Done);
+                    }
+                }
+                let job = Request::Archive {
+                    client,
+                    id,
+                    archive,
+                    buf,
+                    buf_idx,
+                    keep_alive,
+                    limit,
+                };
+                return Ok(if blocked {
+                    JobRes::Blocked(job)
+                } else {
+                    JobRes::Paused(job)
+                });
+            }
+            Request::Cancel(_) | Request::Stats | Request::Shutdown => unreachable!(),
         }
         Ok(JobRes::Done)
     }
 
     pub fn setup(&mut self) -> io::Result<()> {
         match *self {
-            Request::Download { ref mut client, .. } => {
-                client.get_stream().set_nonblocking(false)?;
-                client
-                    .get_stream()
-                    .set_write_timeout(Some(time::Duration::from_millis(JOB_TIME_SLICE)))
+            Request::Download { ref mut client, .. } | Request::Archive { ref mut client, .. } => {
+                client.set_nonblocking(false)?;
+                client.set_write_timeout(Some(time::Duration::from_millis(JOB_TIME_SLICE)))
             }
             _ => Ok(()),
         }
@@ -595,22 +1544,137 @@ impl Request {
 
     pub fn tid(&self) -> Option<usize> {
         match *self {
-            Request::Read { ref context, .. } => Some(context.tid),
-            Request::Serialize { tid, .. }
-            | Request::Validate { tid, .. }
+            Request::Read { ref context, .. }
+            | Request::ReadFile { ref context, .. }
+            | Request::ReadRange { ref context, .. } => Some(context.tid),
+            Request::Serialize { tid, .. } => tid,
+            Request::Validate { tid, .. }
             | Request::ValidatePiece { tid, .. }
             | Request::Delete { tid, .. }
             | Request::Move { tid, .. }
+            | Request::FindData { tid, .. }
+            | Request::CheckFiles { tid, .. }
+            | Request::Link { tid, .. }
+            | Request::Symlink { tid, .. }
             | Request::Write { tid, .. } => Some(tid),
             Request::WriteFile { .. }
+            | Request::MoveDirectory { .. }
             | Request::Download { .. }
+            | Request::Archive { .. }
             | Request::Shutdown
             | Request::Ping
-            | Request::FreeSpace => None,
+            | Request::FreeSpace
+            | Request::Cancel(_)
+            | Request::Stats => None,
         }
     }
 }
 
+/// Moves a file or directory, copying it and removing the original when
+/// moving across filesystems.
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref e) if e.raw_os_error() == Some(EXDEV) => {}
+        res => return res,
+    }
+    let copied = if fs::symlink_metadata(from)?.is_dir() {
+        let mut opts = fs_extra::dir::CopyOptions::new();
+        opts.copy_inside = true;
+        fs_extra::dir::copy(from, to, &opts)
+            .map(|_| ())
+            .map_err(|e| {
+                error!("FS copy failed: {:?}", e);
+                io_err_val("Failed to copy directory across filesystems!")
+            })
+    } else {
+        fs::copy(from, to).map(|_| ())
+    };
+    match copied {
+        Ok(()) => remove_path(from),
+        Err(e) => {
+            remove_path(to).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Undoes a partial directory move, moving entries back where they were.
+fn move_back(fc: &mut FileCache, from: &str, to: &str, moved: &[PathBuf]) {
+    for entry in moved.iter().rev() {
+        let (fp, tp) = (Path::new(from).join(entry), Path::new(to).join(entry));
+        fc.remove_dir(&tp);
+        if let Err(e) = move_path(&tp, &fp) {
+            error!("Failed to move {} back: {}", tp.display(), e);
+        }
+    }
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// The directory of a torrent's files, which is the download directory
+/// unless it was given one.
+fn torrent_dir(path: &Option<String>) -> Cow<'_, str> {
+    match path {
+        Some(p) => Cow::Borrowed(p),
+        None => Cow::Owned(config::settings().directory.clone()),
+    }
+}
+
+/// Reads a piece into buf, returning whether all of it could be read.
+fn read_piece(
+    fc: &mut FileCache,
+    tpb: &mut TempPB<'_>,
+    dir: &str,
+    info: &Arc<Info>,
+    piece: u32,
+    buf: &mut [u8],
+) -> bool {
+    Info::piece_disk_locs(info, piece).all(|loc| read_loc(fc, tpb, dir, &loc, buf).is_ok())
+}
+
+/// Reads the locations of a request into a piece buffer, dropping the
+/// request if none is available.
+fn read_range<I: Iterator<Item = Location>>(
+    fc: &mut FileCache,
+    tpb: &mut TempPB<'_>,
+    dir: &str,
+    context: Ctx,
+    locations: I,
+) -> io::Result<JobRes> {
+    let mut data = match PieceBuf::get(context.length as usize) {
+        Ok(data) => data,
+        Err(_) => return Ok(JobRes::Resp(Response::ReadDropped { context })),
+    };
+    for loc in locations {
+        read_loc(fc, tpb, dir, &loc, &mut data)?;
+    }
+    Ok(JobRes::Resp(Response::ReadRange { context, data }))
+}
+
+/// Reads a location into its part of buf, filling in padding with zeros.
+fn read_loc(
+    fc: &mut FileCache,
+    tpb: &mut TempPB<'_>,
+    dir: &str,
+    loc: &Location,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let data = &mut buf[loc.start..loc.end];
+    if loc.padding() {
+        data.iter_mut().for_each(|b| *b = 0);
+        return Ok(());
+    }
+    let pb = tpb.get(dir);
+    pb.push(loc.path());
+    fc.read_file_range(pb, loc.offset, data)
+}
+
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "disk::Request")
@@ -641,6 +1705,24 @@ impl Location {
     pub fn path(&self) -> &Path {
         &self.info.files[self.file].path
     }
+
+    /// Whether the location is in a padding file, which is never stored.
+    pub fn padding(&self) -> bool {
+        self.info.files[self.file].is_padding()
+    }
+
+    pub fn executable(&self) -> bool {
+        self.info.files[self.file].is_executable()
+    }
+}
+
+impl IoStats {
+    pub fn add(&mut self, other: &IoStats) {
+        self.read += other.read;
+        self.written += other.written;
+        self.time += other.time;
+        self.runs += other.runs;
+    }
 }
 
 impl fmt::Debug for Location {
@@ -653,13 +1735,44 @@ impl fmt::Debug for Location {
     }
 }
 
+impl fmt::Debug for DlConn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "disk::DlConn {{ id: {} }}", self.id)
+    }
+}
+
+impl RateLimit {
+    fn new(rate: u64) -> RateLimit {
+        RateLimit {
+            rate,
+            start: time::Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Number of bytes which may currently be sent, allowing
+    /// for a burst of a quarter second worth of data.
+    fn allowance(&self) -> u64 {
+        let elapsed = self.start.elapsed().as_millis();
+        let allowed = u128::from(self.rate) * elapsed / 1000 + u128::from(self.rate / 4);
+        (allowed as u64).saturating_sub(self.sent)
+    }
+
+    fn consume(&mut self, amnt: u64) {
+        self.sent += amnt;
+    }
+}
+
 impl Response {
     pub fn read(context: Ctx, data: Buffer) -> Response {
         Response::Read { context, data }
     }
 
     pub fn error(tid: usize, err: io::Error) -> Response {
-        Response::Error { tid, err }
+        Response::Error {
+            tid,
+            err: err.into(),
+        }
     }
 
     pub fn moved(tid: usize, path: String) -> Response {
@@ -672,13 +1785,23 @@ impl Response {
 
     pub fn tid(&self) -> usize {
         match *self {
-            Response::Read { ref context, .. } => context.tid,
+            Response::Read { ref context, .. }
+            | Response::ReadFile { ref context, .. }
+            | Response::ReadRange { ref context, .. }
+            | Response::ReadDropped { ref context } => context.tid,
             Response::ValidationComplete { tid, .. }
             | Response::Moved { tid, .. }
+            | Response::DataFound { tid, .. }
+            | Response::FilesChecked { tid, .. }
             | Response::ValidationUpdate { tid, .. }
+            | Response::ValidationCancelled { tid }
             | Response::PieceValidated { tid, .. }
             | Response::Error { tid, .. } => tid,
-            Response::FreeSpace(_) => unreachable!(),
+            Response::FreeSpace { .. }
+            | Response::Serialized { .. }
+            | Response::Stats { .. }
+            | Response::DirectoryMoving { .. }
+            | Response::DirectoryMoved { .. } => unreachable!(),
         }
     }
 }
@@ -1,20 +1,32 @@
+mod archive;
 mod cache;
+mod hasher;
 mod job;
 
+pub use self::archive::{Archive, ArchiveKind};
+pub use self::cache::file_limit;
 pub use self::job::Ctx;
+pub use self::job::IoStats;
+pub use self::job::JobId;
 pub use self::job::Location;
 pub use self::job::Request;
 pub use self::job::Response;
+pub use self::job::Snapshot;
+pub use self::job::{DlConn, DlParams};
 
 use std::collections::VecDeque;
-use std::{fs, io, thread};
+use std::{fs, io, mem, thread, time};
+
+use chrono::{Local, Timelike};
 
 use self::cache::{BufCache, FileCache};
 use self::job::JobRes;
-use crate::{handle, CONFIG};
+use crate::util::{MHashMap, UHashMap};
+use crate::{config, handle, CONFIG};
 
 const POLL_INT_MS: usize = 1000;
 const JOB_TIME_SLICE: u64 = 150;
+const BLOCKED_WAIT_MS: usize = 10;
 
 pub struct Disk {
     poll: amy::Poller,
@@ -23,7 +35,19 @@ pub struct Disk {
     files: FileCache,
     active: VecDeque<Request>,
     sequential: VecDeque<Request>,
+    /// Validations waiting for the validation hours
+    parked: Vec<Request>,
     bufs: BufCache,
+    stats: Stats,
+    /// Last time written files were synced by the periodic fsync policy
+    last_sync: time::Instant,
+}
+
+/// Disk use accumulated by torrent and kind of job.
+#[derive(Default)]
+struct Stats {
+    torrents: UHashMap<IoStats>,
+    jobs: MHashMap<&'static str, IoStats>,
 }
 
 impl Disk {
@@ -40,6 +64,9 @@ impl Disk {
             bufs: BufCache::new(),
             active: VecDeque::new(),
             sequential: VecDeque::new(),
+            parked: Vec::new(),
+            stats: Stats::default(),
+            last_sync: time::Instant::now(),
         }
     }
 
@@ -58,17 +85,51 @@ impl Disk {
                     error!("Failed to poll for events: {}", e);
                 }
             }
+            self.unpark();
             if !self.active.is_empty() && self.handle_active() {
                 break;
             }
+            self.sync_periodic();
         }
 
-        // Try to finish up remaining jobs
-        for job in self.active.drain(..) {
-            if job.concurrent() {
-                job.execute(&mut self.files, &mut self.bufs).ok();
+        // Finish remaining jobs which would otherwise lose data, then make
+        // sure everything written is on disk
+        for mut job in self.active.drain(..).chain(self.sequential.drain(..)) {
+            if !job.completes_on_shutdown() {
+                continue;
+            }
+            loop {
+                match job.execute(&mut self.files, &mut self.bufs) {
+                    Ok(JobRes::Update(j, _)) | Ok(JobRes::Paused(j)) => job = j,
+                    Ok(_) => break,
+                    Err(e) => {
+                        error!("Disk job failed during shutdown: {}", e);
+                        break;
+                    }
+                }
             }
         }
+        self.files.flush_all();
+    }
+
+    /// Syncs written files if the periodic fsync policy is due.
+    fn sync_periodic(&mut self) {
+        let interval = time::Duration::from_secs(CONFIG.disk.fsync_interval);
+        if CONFIG.disk.fsync != config::Fsync::Periodic || self.last_sync.elapsed() < interval {
+            return;
+        }
+        self.last_sync = time::Instant::now();
+        self.files.flush_all();
+    }
+
+    /// Requeues parked validations once they may run.
+    fn unpark(&mut self) {
+        if self.parked.is_empty() || !CONFIG.disk.validation_hour(Local::now().hour()) {
+            return;
+        }
+        for req in mem::take(&mut self.parked) {
+            self.enqueue_req(req);
+        }
     }
 
     fn enqueue_req(&mut self, req: Request) {
@@ -81,11 +142,38 @@ impl Disk {
 
     fn handle_active(&mut self) -> bool {
         let mut rotate = 1;
+        // Number of consecutive jobs which could not progress
+        let mut blocked = 0;
         while let Some(j) = self.active.pop_front() {
             let tid = j.tid();
             let seq = !j.concurrent();
+            let kind = j.kind();
+            let deleted = matches!(j, Request::Delete { .. });
             let mut done = false;
-            match j.execute(&mut self.files, &mut self.bufs) {
+            let waiting = self
+                .active
+                .iter()
+                .chain(self.sequential.iter())
+                .any(|r| j.waits_for(r));
+            let res = if waiting {
+                Ok(JobRes::Blocked(j))
+            } else {
+                let counts = self.files.io_counts();
+                let start = time::Instant::now();
+                let res = j.execute(&mut self.files, &mut self.bufs);
+                self.stats
+                    .record(tid, kind, start.elapsed(), counts, &self.files);
+                res
+            };
+            if let (true, Some(t)) = (deleted, tid) {
+                self.stats.torrents.remove(&t);
+            }
+            if let Ok(JobRes::Blocked(_)) = res {
+                blocked += 1;
+            } else {
+                blocked = 0;
+            }
+            match res {
                 Ok(JobRes::Resp(r)) => {
                     done = true;
                     self.ch.send(r).ok();
@@ -105,6 +193,14 @@ impl Disk {
                         self.active.push_front(s);
                     }
                 }
+                Ok(JobRes::Blocked(s)) => {
+                    self.active.push_back(s);
+                }
+                Ok(JobRes::Parked(s)) => {
+                    // Let the next sequential job run in the meantime
+                    done = true;
+                    self.parked.push(s);
+                }
                 Ok(JobRes::Done) => {
                     done = true;
                 }
@@ -122,7 +218,14 @@ impl Disk {
                     self.active.push_back(r);
                 }
             }
-            match self.poll.wait(0) {
+            // Avoid spinning when every active job is waiting on its rate limit
+            let timeout = if blocked > 0 && blocked >= self.active.len() {
+                blocked = 0;
+                BLOCKED_WAIT_MS
+            } else {
+                0
+            };
+            match self.poll.wait(timeout) {
                 Ok(_) => {
                     if self.handle_events() {
                         return true;
@@ -132,16 +235,25 @@ impl Disk {
                     error!("Failed to poll for events: {:?}", e);
                 }
             }
+            self.sync_periodic();
+            self.unpark();
             rotate += 1;
         }
         false
     }
 
+    /// Enqueues all pending requests, returning whether a shutdown was requested.
+    /// Requests sent before the shutdown are still enqueued so they can be completed.
     pub fn handle_events(&mut self) -> bool {
+        let mut shutdown = false;
         loop {
             match self.ch.recv() {
                 Ok(Request::Shutdown) => {
-                    return true;
+                    shutdown = true;
+                }
+                Ok(Request::Cancel(id)) => self.cancel(&id),
+                Ok(Request::Stats) => {
+                    self.ch.send(self.stats.response(&self.files)).ok();
                 }
                 Ok(mut r) => {
                     let tid = r.tid();
@@ -156,12 +268,76 @@ impl Disk {
             }
         }
         while let Ok(mut r) = self.jobs.try_recv() {
+            if let Request::Cancel(id) = r {
+                self.cancel(&id);
+                continue;
+            }
             if r.setup().is_err() {
                 continue;
             }
             self.enqueue_req(r);
         }
-        false
+        shutdown
+    }
+
+    /// Cancels jobs with an id. Jobs are only queued between time
+    /// slices, so this takes effect at their next checkpoint.
+    fn cancel(&mut self, id: &JobId) {
+        let matches = |r: &Request| r.job_id().as_ref() == Some(id);
+        let (cancelled, active): (VecDeque<_>, _) = self.active.drain(..).partition(matches);
+        let (cancelled_seq, sequential) = self.sequential.drain(..).partition(matches);
+        let (cancelled_parked, parked): (Vec<_>, _) = self.parked.drain(..).partition(matches);
+        self.active = active;
+        self.sequential = sequential;
+        self.parked = parked;
+        for job in cancelled
+            .into_iter()
+            .chain(cancelled_seq)
+            .chain(cancelled_parked)
+        {
+            debug!("Cancelled disk job {:?}", id);
+            if let Some(r) = job.cancel(&mut self.files) {
+                self.ch.send(r).ok();
+            }
+        }
+        // Start the next sequential job if the running one was cancelled
+        if self.active.iter().all(Request::concurrent) {
+            if let Some(r) = self.sequential.pop_front() {
+                self.active.push_back(r);
+            }
+        }
+    }
+}
+
+impl Stats {
+    /// Adds a run of a job, given the cache's byte counts from before it ran.
+    fn record(
+        &mut self,
+        tid: Option<usize>,
+        kind: &'static str,
+        time: time::Duration,
+        (read, written): (u64, u64),
+        files: &FileCache,
+    ) {
+        let (now_read, now_written) = files.io_counts();
+        let run = IoStats {
+            read: now_read - read,
+            written: now_written - written,
+            time,
+            runs: 1,
+        };
+        if let Some(t) = tid {
+            self.torrents.entry(t).or_default().add(&run);
+        }
+        self.jobs.entry(kind).or_default().add(&run);
+    }
+
+    fn response(&self, files: &FileCache) -> Response {
+        Response::Stats {
+            torrents: self.torrents.iter().map(|(t, s)| (*t, *s)).collect(),
+            jobs: self.jobs.iter().map(|(k, s)| (*k, *s)).collect(),
+            dirty_files: files.dirty_files(),
+        }
     }
 }
 
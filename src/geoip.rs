@@ -0,0 +1,51 @@
+//! Countries of peers, looked up in a MaxMind database if one is
+//! configured. The database is opened on its own thread at startup and
+//! again after the config is reloaded, so that neither the peer nor the
+//! control thread waits on it, and it can be updated while running.
+
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::thread;
+
+use maxminddb::{geoip2, Reader};
+
+use crate::CONFIG;
+
+lazy_static! {
+    static ref DB: RwLock<Option<Reader<Vec<u8>>>> = RwLock::new(None);
+}
+
+/// ISO 3166 code of the country an address is in, if known. Nothing is
+/// known until the database has been loaded.
+pub fn country(ip: IpAddr) -> Option<String> {
+    CONFIG.peer.geoip_db.as_ref()?;
+    let db = DB.read().unwrap();
+    lookup(db.as_ref()?, ip)
+}
+
+/// Opens the database in the background, replacing the loaded one once
+/// done. A database which fails to open keeps the previous one in use.
+pub fn load() {
+    let path = match CONFIG.peer.geoip_db {
+        Some(ref path) => shellexpand::tilde(path).into_owned(),
+        None => return,
+    };
+    let res = thread::Builder::new()
+        .name("geoip".to_owned())
+        .spawn(move || match Reader::open_readfile(&path) {
+            Ok(reader) => {
+                info!("Loaded GeoIP database {}", path);
+                *DB.write().unwrap() = Some(reader);
+            }
+            Err(e) => error!("Failed to load GeoIP database {}: {}", path, e),
+        });
+    if let Err(e) = res {
+        error!("Failed to spawn the GeoIP thread: {}", e);
+    }
+}
+
+fn lookup(reader: &Reader<Vec<u8>>, ip: IpAddr) -> Option<String> {
+    let record: geoip2::Country<'_> = reader.lookup(ip).ok()?;
+    let country = record.country.or(record.registered_country)?;
+    country.iso_code.map(str::to_owned)
+}
@@ -2,10 +2,11 @@ use std::sync::{atomic, mpsc};
 use std::{io, process, thread};
 
 use ctrlc;
+use nix::sys::signal::{SigSet, Signal};
 
 use crate::control::acio;
-use crate::{args, control, disk, log, rpc, throttle, tracker};
-use crate::{CONFIG, SHUTDOWN, THROT_TOKS};
+use crate::{args, control, disk, geoip, instance, log, rpc, throttle, tracker, util};
+use crate::{CONFIG, FD_LIMIT, RELOAD, SHUTDOWN, THROT_TOKS};
 
 pub fn init(args: args::Args) -> Result<(), ()> {
     if let Some(level) = args.level {
@@ -17,10 +18,38 @@ pub fn init(args: args::Args) -> Result<(), ()> {
     }
 
     info!("Initializing");
+    util::systemd::init();
 
     // Since the config is lazy loaded, dereference now to check it.
     CONFIG.port;
+    if let Err(e) = util::net::check_mark() {
+        error!("Failed to set net.mark: {}", e);
+        return Err(());
+    }
+
+    if let Err(e) = instance::lock(&CONFIG.disk.session, args.config.as_deref()) {
+        error!("Failed to lock the session directory: {}", e);
+        return Err(());
+    }
+
+    match util::native::raise_fd_limit() {
+        Ok(limit) => {
+            info!("Open file limit is {}", limit);
+            FD_LIMIT.store(limit as usize, atomic::Ordering::Relaxed);
+            let files = disk::file_limit();
+            if files < CONFIG.net.max_open_files {
+                info!("Keeping at most {} files open to stay within it", files);
+            }
+        }
+        Err(e) => error!("Failed to get the open file limit: {}", e),
+    }
+
+    geoip::load();
 
+    if let Err(e) = init_reload() {
+        error!("Failed to initialize SIGHUP handling: {}", e);
+        return Err(());
+    }
     if let Err(e) = init_signals() {
         error!("Failed to initialize signal handlers: {}", e);
         return Err(());
@@ -28,8 +57,15 @@ pub fn init(args: args::Args) -> Result<(), ()> {
     Ok(())
 }
 
-pub fn run() -> Result<(), ()> {
-    match init_threads() {
+pub fn run(add: Option<args::Add>) -> Result<(), ()> {
+    let oneshot = match add.map(control::oneshot::OneShot::load).transpose() {
+        Ok(oneshot) => oneshot,
+        Err(e) => {
+            error!("{}", e);
+            return Err(());
+        }
+    };
+    match init_threads(oneshot) {
         Ok(threads) => {
             for thread in threads {
                 if thread.join().is_err() {
@@ -37,7 +73,11 @@ pub fn run() -> Result<(), ()> {
                     return Err(());
                 }
             }
+            instance::unlock();
             info!("Shutdown complete");
+            if control::oneshot::FAILED.load(atomic::Ordering::SeqCst) {
+                return Err(());
+            }
             Ok(())
         }
         Err(e) => {
@@ -47,7 +87,9 @@ pub fn run() -> Result<(), ()> {
     }
 }
 
-fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
+fn init_threads(
+    oneshot: Option<control::oneshot::OneShot>,
+) -> io::Result<Vec<thread::JoinHandle<()>>> {
     let cpoll = amy::Poller::new()?;
     let mut creg = cpoll.get_registrar();
     let (dh, disk_broadcast, dhj) = disk::start(&mut creg)?;
@@ -71,7 +113,7 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
             match control::Control::new(acio, throttler, cdb) {
                 Ok(mut c) => {
                     tx.send(Ok(())).unwrap();
-                    c.run();
+                    c.run(oneshot);
                 }
                 Err(e) => {
                     tx.send(Err(e)).unwrap();
@@ -80,6 +122,7 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
         })
         .unwrap();
     rx.recv().unwrap()?;
+    util::systemd::close_unused();
 
     Ok(vec![chj, dhj, rhj, thj])
 }
@@ -91,7 +134,26 @@ fn init_signals() -> Result<(), ctrlc::Error> {
             process::abort();
         } else {
             info!("Shutting down cleanly. Interrupt again to shut down immediately.");
+            util::systemd::notify("STOPPING=1");
             SHUTDOWN.store(true, atomic::Ordering::SeqCst);
         }
     })
 }
+
+/// Reloads the config on SIGHUP. The signal is blocked before any other
+/// thread is spawned and waited for on its own thread, so that it never
+/// interrupts the others.
+fn init_reload() -> io::Result<()> {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGHUP);
+    set.thread_block()?;
+    thread::Builder::new()
+        .name("signal".to_owned())
+        .spawn(move || loop {
+            if set.wait().is_ok() {
+                info!("Reloading config");
+                RELOAD.store(true, atomic::Ordering::SeqCst);
+            }
+        })?;
+    Ok(())
+}
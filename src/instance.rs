@@ -0,0 +1,164 @@
+//! Lock held on the session directory, so that only one instance of
+//! synapse uses it at a time. The lock file also describes the running
+//! instance, letting clients find its RPC endpoints.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+
+pub const LOCK_FILE: &str = "synapse.lock";
+
+lazy_static! {
+    static ref LOCK: Mutex<Option<Lock>> = Mutex::new(None);
+}
+
+struct Lock {
+    path: PathBuf,
+    file: File,
+    instance: Instance,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Instance {
+    pid: u32,
+    /// Config file given on the command line, if any
+    config: Option<String>,
+    /// URLs the RPC can be reached at
+    rpc: Vec<String>,
+}
+
+/// Locks the session directory, failing if another instance holds it.
+pub fn lock(session: &str, config: Option<&str>) -> io::Result<()> {
+    let config = config.map(|c| {
+        fs::canonicalize(c)
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| c.to_owned())
+    });
+    let instance = Instance {
+        pid: process::id(),
+        config,
+        rpc: Vec::new(),
+    };
+    let lock = acquire(Path::new(session), instance)?;
+    *LOCK.lock().unwrap() = Some(lock);
+    Ok(())
+}
+
+/// Records the URLs the RPC is reachable at.
+pub fn set_rpc(urls: Vec<String>) {
+    if let Some(ref mut lock) = *LOCK.lock().unwrap() {
+        lock.instance.rpc = urls;
+        if let Err(e) = lock.write() {
+            error!("Failed to update {}: {}", lock.path.display(), e);
+        }
+    }
+}
+
+/// Moves the lock into the directory the session was moved to.
+pub fn relocate(session: &Path) -> io::Result<()> {
+    let mut held = LOCK.lock().unwrap();
+    if let Some(old) = held.take() {
+        match acquire(session, old.instance.clone()) {
+            Ok(lock) => {
+                fs::remove_file(&old.path).ok();
+                *held = Some(lock);
+            }
+            Err(e) => {
+                *held = Some(old);
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the lock file on shutdown.
+pub fn unlock() {
+    if let Some(lock) = LOCK.lock().unwrap().take() {
+        fs::remove_file(&lock.path).ok();
+    }
+}
+
+fn acquire(session: &Path, instance: Instance) -> io::Result<Lock> {
+    fs::create_dir_all(session)?;
+    let path = session.join(LOCK_FILE);
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        // The holder's details are read if it's locked
+        .truncate(false)
+        .open(&path)?;
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => {}
+        Err(Errno::EWOULDBLOCK) => {
+            let mut data = String::new();
+            file.read_to_string(&mut data).ok();
+            let holder = match serde_json::from_str::<Instance>(&data) {
+                Ok(Instance {
+                    pid,
+                    config: Some(config),
+                    ..
+                }) => format!("pid {} with config {}", pid, config),
+                Ok(Instance { pid, .. }) => format!("pid {}", pid),
+                Err(_) => "another process".to_owned(),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "session directory {} is in use by {}",
+                    session.display(),
+                    holder
+                ),
+            ));
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let lock = Lock {
+        path,
+        file,
+        instance,
+    };
+    lock.write()?;
+    Ok(lock)
+}
+
+impl Lock {
+    fn write(&self) -> io::Result<()> {
+        let data = serde_json::to_vec(&self.instance)?;
+        let mut file = &self.file;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (dir, dir2) = (tmp.path().join("a"), tmp.path().join("b"));
+        let instance = Instance {
+            pid: 5,
+            config: Some("a.toml".to_owned()),
+            rpc: vec!["ws://127.0.0.1:8412".to_owned()],
+        };
+        let lock = acquire(&dir, instance.clone()).unwrap();
+        // Locks are held by the open file, so a second open conflicts
+        let err = acquire(&dir, Instance::default()).err().unwrap();
+        assert!(err.to_string().ends_with("pid 5 with config a.toml"));
+        assert!(acquire(&dir2, instance).is_ok());
+        drop(lock);
+        assert!(acquire(&dir, Instance::default()).is_ok());
+    }
+}
@@ -35,8 +35,10 @@ mod buffers;
 mod config;
 mod control;
 mod disk;
+mod geoip;
 mod handle;
 mod init;
+mod instance;
 mod rpc;
 mod socket;
 mod stat;
@@ -46,26 +48,41 @@ mod tracker;
 
 use ip_network_table::IpNetworkTable;
 use std::process;
-use std::sync::atomic;
+use std::sync::{atomic, RwLock};
 
 pub use crate::protocol::DHT_EXT;
 pub use crate::protocol::EXT_PROTO;
-pub use crate::protocol::UT_META_ID;
-pub use crate::protocol::UT_PEX_ID;
 
 /// Throttler max token amount
 pub const THROT_TOKS: usize = 2 * 1024 * 1024;
 
 pub static SHUTDOWN: atomic::AtomicBool = atomic::AtomicBool::new(false);
+/// Set when the config should be reloaded
+pub static RELOAD: atomic::AtomicBool = atomic::AtomicBool::new(false);
+/// Port peers connect to, announced to trackers and the DHT
+pub static LISTEN_PORT: atomic::AtomicU16 = atomic::AtomicU16::new(0);
+/// Set while the interface or address sockets are bound to is missing,
+/// pausing network activity
+pub static NET_DOWN: atomic::AtomicBool = atomic::AtomicBool::new(false);
+/// Set while a client has stopped all network activity
+pub static NET_STOPPED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+/// Limit on open file descriptors found at startup, or 0 if unknown
+pub static FD_LIMIT: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
 
 lazy_static! {
     pub static ref CONFIG: config::Config = config::Config::load();
+    pub static ref SETTINGS: RwLock<config::Settings> = RwLock::new(config::Settings::new(&CONFIG));
     pub static ref PEER_ID: [u8; 20] = {
         use rand::Rng;
 
         let mut pid = [0u8; 20];
-        let prefix = b"-SY0010-";
-        pid[..prefix.len()].clone_from_slice(&prefix[..]);
+        let prefix = CONFIG
+            .trk
+            .peer_id_prefix
+            .as_ref()
+            .map(String::as_bytes)
+            .unwrap_or(b"-SY0010-");
+        pid[..prefix.len()].clone_from_slice(prefix);
 
         let mut rng = rand::thread_rng();
         for p in pid.iter_mut().skip(prefix.len()) {
@@ -89,7 +106,18 @@ lazy_static! {
 }
 
 fn main() {
-    let args = args::args();
+    let mut args = args::args();
+    if args.dump_config {
+        match config::dump() {
+            Ok(config) => print!("{}", config),
+            Err(e) => {
+                eprintln!("Failed to load config: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let add = args.add.take();
     match init::init(args) {
         Ok(()) => {}
         Err(()) => {
@@ -98,7 +126,7 @@ fn main() {
         }
     }
     info!("Initialized, starting!");
-    match init::run() {
+    match init::run(add) {
         Ok(()) => process::exit(0),
         Err(()) => process::exit(1),
     }
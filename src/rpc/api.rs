@@ -0,0 +1,549 @@
+//! A plain HTTP JSON interface to the RPC resources, allowing simple
+//! scripts to query and modify resources without implementing the
+//! websocket subscription protocol.
+
+use serde::Serialize;
+use serde_json as json;
+
+use super::auth::Access;
+use super::processor::{client_view, Processor, TransferKind};
+use super::proto::message::{CMessage, SMessage};
+use super::proto::resource::{CResourceUpdate, ResourceKind};
+use super::Message;
+use crate::bencode;
+use crate::torrent::info::Info;
+use crate::util::hash_to_id;
+use crate::CONFIG;
+
+/// Maximum accepted size of a request body
+pub const MAX_BODY: usize = 16 * 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ApiMethod {
+    Get,
+    Post,
+    Patch,
+}
+
+pub enum ApiEndpoint {
+    /// All resources of a kind, or a single resource
+    Resources {
+        kind: ResourceKind,
+        id: Option<String>,
+    },
+    /// Transmission compatible RPC, along with the client's session ID
+    Transmission { session: Option<String> },
+    /// qBittorrent compatible WebUI API method, e.g. torrents/info
+    QBittorrent {
+        path: String,
+        query: String,
+        content_type: Option<String>,
+        /// Whether a browser sent the request from a page of another site
+        cross_origin: bool,
+    },
+}
+
+/// An authenticated request to the API.
+pub struct ApiRequest {
+    pub method: ApiMethod,
+    pub endpoint: ApiEndpoint,
+    /// Only absent for endpoints which handle authentication themselves
+    pub access: Option<Access>,
+    pub keep_alive: bool,
+    /// Origin of a browser request sent from a page of another site
+    pub origin: Option<String>,
+    /// Size of the body, as given by Content-Length
+    pub size: usize,
+    pub body: Vec<u8>,
+}
+
+pub struct ApiResponse {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<String>,
+    body: Option<String>,
+}
+
+/// Body of a POST to /torrents. Exactly one of uri or torrent must be given.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewTorrent {
+    /// Magnet URI
+    pub uri: Option<String>,
+    /// Base64 encoded torrent file
+    pub torrent: Option<String>,
+    pub path: Option<String>,
+    #[serde(default = "default_true")]
+    pub start: bool,
+    #[serde(default)]
+    pub import: bool,
+    #[serde(default)]
+    pub seed_only: bool,
+    pub label: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub priorities: Vec<u8>,
+    #[serde(default)]
+    pub merge: bool,
+    #[serde(default)]
+    pub metadata_only: bool,
+}
+
+/// A failed request, along with the status it should be reported with.
+#[derive(Debug)]
+pub struct Failure {
+    pub status: u16,
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct Added {
+    id: String,
+}
+
+impl Failure {
+    pub fn new<S: Into<String>>(status: u16, reason: S) -> Failure {
+        Failure {
+            status,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl From<Failure> for ApiResponse {
+    fn from(f: Failure) -> ApiResponse {
+        ApiResponse::error(f.status, f.reason)
+    }
+}
+
+impl ApiResponse {
+    pub fn json<T: Serialize>(status: u16, body: &T) -> ApiResponse {
+        ApiResponse {
+            status,
+            content_type: "application/json",
+            headers: vec![],
+            body: Some(json::to_string(body).unwrap()),
+        }
+    }
+
+    pub fn text<S: Into<String>>(status: u16, body: S) -> ApiResponse {
+        ApiResponse {
+            status,
+            content_type: "text/plain; charset=UTF-8",
+            headers: vec![],
+            body: Some(body.into()),
+        }
+    }
+
+    pub fn error<S: Into<String>>(status: u16, reason: S) -> ApiResponse {
+        ApiResponse::json(
+            status,
+            &ApiError {
+                reason: reason.into(),
+            },
+        )
+    }
+
+    fn accepted() -> ApiResponse {
+        ApiResponse {
+            status: 202,
+            content_type: "application/json",
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    pub fn header(mut self, header: String) -> ApiResponse {
+        self.headers.push(header);
+        self
+    }
+
+    pub fn into_bytes(self, keep_alive: bool) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        };
+        let body = self.body.unwrap_or_default();
+        let mut lines = vec![
+            format!("HTTP/1.1 {} {}", self.status, reason),
+            format!("Content-Length: {}", body.len()),
+        ];
+        if !body.is_empty() {
+            lines.push(format!("Content-Type: {}", self.content_type));
+        }
+        lines.extend(self.headers);
+        if keep_alive {
+            lines.push("Connection: keep-alive".to_string());
+        } else {
+            lines.push("Connection: Close".to_string());
+        }
+        lines.push("\r\n".to_string());
+        let mut data = lines.join("\r\n").into_bytes();
+        data.extend(body.into_bytes());
+        data
+    }
+}
+
+/// Whether a site is allowed to use the API from a browser.
+fn allowed_origin(origin: &str, allowed: &[String]) -> bool {
+    let origin = origin.trim_end_matches('/');
+    allowed
+        .iter()
+        .any(|o| o.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+/// Handles an API request on behalf of the given connection, returning
+/// the response and any message which should be passed on to control.
+pub fn handle(
+    processor: &mut Processor,
+    client: usize,
+    mut req: ApiRequest,
+) -> (ApiResponse, Option<Message>) {
+    let origin = match req.origin.take() {
+        Some(o) if allowed_origin(&o, &CONFIG.rpc.cors_origins) => Some(o),
+        // Browsers send requests from pages of any site, only
+        // letting the page read the response if it's allowed to
+        Some(_) if req.method != ApiMethod::Get => {
+            return (ApiResponse::error(403, "Origin not allowed"), None);
+        }
+        _ => None,
+    };
+    let (resp, msg) = handle_resource(processor, client, req);
+    match origin {
+        Some(o) => (
+            resp.header(format!("Access-Control-Allow-Origin: {}", o))
+                .header("Vary: Origin".to_owned()),
+            msg,
+        ),
+        None => (resp, msg),
+    }
+}
+
+fn handle_resource(
+    processor: &mut Processor,
+    client: usize,
+    req: ApiRequest,
+) -> (ApiResponse, Option<Message>) {
+    let ApiRequest {
+        method,
+        endpoint,
+        access,
+        body,
+        ..
+    } = req;
+    let (kind, id) = match endpoint {
+        ApiEndpoint::Resources { kind, id } => (kind, id),
+        _ => unreachable!("compatibility endpoints are handled separately"),
+    };
+    let access = match access {
+        Some(access) => access,
+        None => return (ApiResponse::error(401, "Unauthorized"), None),
+    };
+    match (method, id) {
+        (ApiMethod::Get, None) => {
+            let resources: Vec<_> = processor
+                .get_resources(&access, kind)
+                .into_iter()
+                .map(|r| client_view(&access, r))
+                .collect();
+            (ApiResponse::json(200, &resources), None)
+        }
+        (ApiMethod::Get, Some(id)) => match processor.get_resource(&access, &id) {
+            Some(r) if r.kind() == kind => (ApiResponse::json(200, &client_view(&access, r)), None),
+            _ => (
+                ApiResponse::error(404, format!("unknown resource id {}", id)),
+                None,
+            ),
+        },
+        (ApiMethod::Post, None) if kind == ResourceKind::Torrent => match json::from_slice(&body) {
+            Ok(t) => {
+                processor.add_client(client, access);
+                let res = add_torrent(processor, client, t);
+                processor.remove_client(client);
+                match res {
+                    Ok(msg) => {
+                        let id = match msg {
+                            Message::Torrent { ref info, .. } => hash_to_id(&info.hash),
+                            _ => unreachable!(),
+                        };
+                        (ApiResponse::json(202, &Added { id }), Some(msg))
+                    }
+                    Err(err) => (err.into(), None),
+                }
+            }
+            Err(e) => (ApiResponse::error(400, e.to_string()), None),
+        },
+        (ApiMethod::Patch, Some(id)) => {
+            let resource = json::from_slice(&body).and_then(|mut update: json::Value| {
+                if let Some(obj) = update.as_object_mut() {
+                    obj.insert("id".to_owned(), json::Value::String(id.clone()));
+                }
+                json::from_value::<CResourceUpdate>(update)
+            });
+            let resource = match resource {
+                Ok(r) => r,
+                Err(e) => return (ApiResponse::error(400, e.to_string()), None),
+            };
+            match processor.get_resource(&access, &id) {
+                Some(r) if r.kind() == kind => {}
+                _ => {
+                    return (
+                        ApiResponse::error(404, format!("unknown resource id {}", id)),
+                        None,
+                    )
+                }
+            }
+            processor.add_client(client, access);
+            let (resp, rmsg) = processor.handle_client(
+                client,
+                CMessage::UpdateResource {
+                    serial: 0,
+                    resource,
+                },
+            );
+            let res = match error(resp) {
+                Some(err) => (err.into(), None),
+                None => (ApiResponse::accepted(), rmsg),
+            };
+            processor.remove_client(client);
+            res
+        }
+        _ => (ApiResponse::error(405, "Unsupported method"), None),
+    }
+}
+
+/// Adds a torrent on behalf of a client, returning the message which
+/// should be passed on to control.
+pub fn add_torrent(
+    processor: &mut Processor,
+    client: usize,
+    t: NewTorrent,
+) -> Result<Message, Failure> {
+    match (t.uri, t.torrent) {
+        (Some(_), _) if t.import || t.seed_only || !t.priorities.is_empty() => Err(Failure::new(
+            400,
+            "Magnets cannot be imported, seed only or given file priorities",
+        )),
+        (None, Some(_)) if t.metadata_only => {
+            Err(Failure::new(400, "Only magnets can be metadata only"))
+        }
+        (Some(uri), None) => {
+            let (resp, rmsg) = processor.handle_client(
+                client,
+                CMessage::UploadMagnet {
+                    serial: 0,
+                    uri,
+                    path: t.path,
+                    start: t.start,
+                    label: t.label,
+                    name: t.name,
+                    merge: t.merge,
+                    metadata_only: t.metadata_only,
+                },
+            );
+            match (error(resp), rmsg) {
+                (Some(err), _) => Err(err),
+                (None, Some(msg)) => Ok(msg),
+                (None, None) => Err(Failure::new(400, "Invalid magnet")),
+            }
+        }
+        (None, Some(torrent)) => {
+            let data = base64::decode(&torrent).map_err(|e| Failure::new(400, e.to_string()))?;
+            let info = bencode::decode_ref_with(&data, &Info::LIMITS)
+                .map_err(|e| e.to_string())
+                .and_then(|b| Info::from_bencode(&b).map_err(|e| e.to_string()))
+                .map_err(|e| Failure::new(400, format!("Invalid torrent file, {}", e)))?;
+            // Validate the upload as if it was a transfer, then
+            // immediately complete it with the supplied data
+            let (resp, _) = processor.handle_client(
+                client,
+                CMessage::UploadTorrent {
+                    serial: 0,
+                    size: data.len() as u64,
+                    path: t.path,
+                    start: t.start,
+                    import: t.import,
+                    seed_only: t.seed_only,
+                    label: t.label,
+                    name: t.name,
+                    priorities: t.priorities,
+                    merge: t.merge,
+                },
+            );
+            let token = resp.iter().find_map(|m| match m {
+                SMessage::TransferOffer { token, .. } => Some(token.clone()),
+                _ => None,
+            });
+            if let Some(err) = error(resp) {
+                return Err(err);
+            }
+            match token.and_then(|token| processor.take_transfer(&token)) {
+                Some(TransferKind::UploadTorrent { mut options, .. }) => {
+                    options.merge &= processor.may_merge(client, &info.hash);
+                    processor.claim(client, &info.hash);
+                    Ok(Message::Torrent {
+                        info: Box::new(info),
+                        options,
+                        client,
+                        serial: 0,
+                    })
+                }
+                _ => Err(Failure::new(400, "Invalid torrent upload")),
+            }
+        }
+        _ => Err(Failure::new(
+            400,
+            "Exactly one of uri or torrent must be given",
+        )),
+    }
+}
+
+/// Converts the first error reported by the processor into a failure.
+pub fn error(resp: Vec<SMessage<'_>>) -> Option<Failure> {
+    resp.iter().find_map(failure)
+}
+
+/// Converts a message into a failure, if it reports an error.
+pub fn failure(msg: &SMessage<'_>) -> Option<Failure> {
+    match msg {
+        SMessage::UnknownResource(e) => Some(Failure::new(404, e.reason.clone())),
+        SMessage::PermissionDenied(e) => Some(Failure::new(403, e.reason.clone())),
+        SMessage::InvalidResource(e)
+        | SMessage::InvalidSchema(e)
+        | SMessage::InvalidRequest(e)
+        | SMessage::TransferFailed(e) => Some(Failure::new(400, e.reason.clone())),
+        _ => None,
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::proto::resource::{Resource, Tracker};
+    use crate::rpc::CtlMessage;
+    use chrono::Utc;
+
+    const ID: &str = "tracker";
+
+    fn processor() -> Processor {
+        let poller = amy::Poller::new().unwrap();
+        let (tx, _) = poller.get_registrar().channel().unwrap();
+        let mut p = Processor::new(tx);
+        p.handle_ctl(CtlMessage::Extant(vec![Resource::Tracker(Tracker {
+            id: ID.to_owned(),
+            torrent_id: "torrent".to_owned(),
+            url: "http://tracker.example/announce".parse().unwrap(),
+            last_report: Utc::now(),
+            next_report: None,
+            error: None,
+            warning: None,
+            peers: 0,
+            user_data: json::Value::Null,
+        })]));
+        p
+    }
+
+    fn request(method: ApiMethod, kind: ResourceKind, id: Option<&str>, body: &str) -> ApiRequest {
+        ApiRequest {
+            method,
+            endpoint: ApiEndpoint::Resources {
+                kind,
+                id: id.map(str::to_owned),
+            },
+            access: Some(Access::admin()),
+            keep_alive: false,
+            origin: None,
+            size: body.len(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_get() {
+        let mut p = processor();
+        let req = request(ApiMethod::Get, ResourceKind::Tracker, None, "");
+        let (resp, msg) = handle(&mut p, 1, req);
+        assert_eq!(resp.status, 200);
+        let trackers: json::Value = json::from_str(&resp.body.unwrap()).unwrap();
+        assert_eq!(trackers[0]["id"], ID);
+        assert!(msg.is_none());
+
+        let req = request(ApiMethod::Get, ResourceKind::Tracker, Some(ID), "");
+        assert_eq!(handle(&mut p, 1, req).0.status, 200);
+        // Resources are only found under their own kind
+        let req = request(ApiMethod::Get, ResourceKind::Torrent, Some(ID), "");
+        assert_eq!(handle(&mut p, 1, req).0.status, 404);
+    }
+
+    #[test]
+    fn test_update() {
+        let mut p = processor();
+        let body = r#"{"user_data": {"a": 1}}"#;
+        let req = request(ApiMethod::Patch, ResourceKind::Tracker, Some(ID), body);
+        assert_eq!(handle(&mut p, 1, req).0.status, 202);
+        let req = request(ApiMethod::Get, ResourceKind::Tracker, Some(ID), "");
+        let tracker: json::Value = json::from_str(&handle(&mut p, 1, req).0.body.unwrap()).unwrap();
+        assert_eq!(tracker["user_data"]["a"], 1);
+
+        let req = request(ApiMethod::Patch, ResourceKind::Tracker, Some(ID), "{");
+        assert_eq!(handle(&mut p, 1, req).0.status, 400);
+        let req = request(ApiMethod::Patch, ResourceKind::Tracker, Some("x"), body);
+        assert_eq!(handle(&mut p, 1, req).0.status, 404);
+        let req = request(ApiMethod::Post, ResourceKind::Tracker, None, body);
+        assert_eq!(handle(&mut p, 1, req).0.status, 405);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut p = processor();
+        let req = request(ApiMethod::Post, ResourceKind::Torrent, None, "{}");
+        assert_eq!(handle(&mut p, 1, req).0.status, 400);
+        let body = r#"{"torrent": "ZDQ6aW5mb2Rl", "metadata_only": true}"#;
+        let req = request(ApiMethod::Post, ResourceKind::Torrent, None, body);
+        assert_eq!(handle(&mut p, 1, req).0.status, 400);
+    }
+
+    #[test]
+    fn test_unauthorized() {
+        let mut p = processor();
+        let mut req = request(ApiMethod::Get, ResourceKind::Tracker, None, "");
+        req.access = None;
+        assert_eq!(handle(&mut p, 1, req).0.status, 401);
+    }
+
+    #[test]
+    fn test_origin() {
+        let mut p = processor();
+        let mut req = request(ApiMethod::Get, ResourceKind::Tracker, Some(ID), "");
+        req.origin = Some("https://other.example".to_owned());
+        let (resp, _) = handle(&mut p, 1, req);
+        assert_eq!(resp.status, 200);
+        assert!(resp.headers.is_empty());
+
+        let body = r#"{"user_data": null}"#;
+        let mut req = request(ApiMethod::Patch, ResourceKind::Tracker, Some(ID), body);
+        req.origin = Some("https://other.example".to_owned());
+        assert_eq!(handle(&mut p, 1, req).0.status, 403);
+
+        let allowed = vec!["https://site.example/".to_owned()];
+        assert!(allowed_origin("https://Site.example", &allowed));
+        assert!(!allowed_origin("https://other.example", &allowed));
+        assert!(!allowed_origin("null", &allowed));
+    }
+}
@@ -0,0 +1,190 @@
+use ring::hmac;
+use ring::rand::SystemRandom;
+
+use crate::config::Scope;
+use crate::util::{hash_to_id, sha1_hash};
+use crate::{CONFIG, DL_TOKEN};
+
+lazy_static! {
+    /// Key signing download links and session cookies. Unlike the
+    /// download token it's never sent to clients, so they can't forge them.
+    static ref KEY: hmac::Key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+        .expect("Failed to generate signing key");
+}
+
+/// Permissions granted to an RPC client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Access {
+    pub scope: Scope,
+    /// User which torrents added by the client are attributed to
+    pub user: Option<String>,
+    /// Whether only torrents attributed to the user are visible
+    pub restricted: bool,
+}
+
+impl Access {
+    pub fn admin() -> Access {
+        Access {
+            scope: Scope::Admin,
+            user: None,
+            restricted: false,
+        }
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scope >= scope
+    }
+}
+
+/// Determines the access granted by a password or token. When auth is
+/// disabled every client is granted full access.
+pub fn authenticate(secret: Option<&str>) -> Option<Access> {
+    if !CONFIG.rpc.auth {
+        return Some(Access::admin());
+    }
+    let secret = secret?;
+    if secret == CONFIG.rpc.password {
+        return Some(Access::admin());
+    }
+    CONFIG
+        .rpc
+        .tokens
+        .iter()
+        .find(|t| t.token == secret)
+        .map(|t| Access {
+            scope: t.scope,
+            // Avoid persisting the token itself as an owner
+            user: t
+                .user
+                .clone()
+                .or_else(|| Some(hash_to_id(&sha1_hash(t.token.as_bytes())))),
+            restricted: t.restricted,
+        })
+}
+
+/// Extracts the password or token from the value of an Authorization
+/// header, using either Basic auth with any username or a Bearer token.
+pub fn authorization_secret(value: &str) -> Option<String> {
+    if value.to_lowercase().starts_with("basic ") {
+        let (_, auth) = value.split_at(6);
+        base64::decode(auth)
+            .ok()
+            .and_then(|auth| String::from_utf8(auth).ok())
+            .and_then(|auth| auth.split_terminator(':').next_back().map(str::to_owned))
+    } else if value.to_lowercase().starts_with("bearer ") {
+        let (_, tok) = value.split_at(7);
+        Some(tok.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Derives the session cookie handed to clients which log in to the
+/// qBittorrent compatible API with a password or token.
+pub fn session_id(secret: &str) -> String {
+    let tag = hmac::sign(&KEY, format!("session:{}", secret).as_bytes());
+    base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Determines the access granted by a session cookie, which remains
+/// valid until synapse restarts.
+pub fn authenticate_session(sid: &str) -> Option<Access> {
+    if !CONFIG.rpc.auth {
+        return Some(Access::admin());
+    }
+    let tag = base64::decode_config(sid, base64::URL_SAFE_NO_PAD).ok()?;
+    let secret = Some(&CONFIG.rpc.password)
+        .into_iter()
+        .chain(CONFIG.rpc.tokens.iter().map(|t| &t.token))
+        .find(|secret| {
+            hmac::verify(&KEY, format!("session:{}", secret).as_bytes(), &tag).is_ok()
+        })?;
+    authenticate(Some(secret))
+}
+
+/// Returns the token a client uses to authorize downloads. Restricted
+/// clients get one tied to their user rather than the server wide token,
+/// so their downloads are limited to the torrents they can see.
+pub fn download_token(access: &Access) -> String {
+    if !access.restricted {
+        return DL_TOKEN.clone();
+    }
+    let user = access.user.as_deref().unwrap_or("");
+    let tag = hmac::sign(&KEY, format!("download:{}", user).as_bytes());
+    base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Determines the access granted by the hash of a resource id and
+/// download token, as sent in a download URL.
+pub fn authenticate_download(id: &str, hash: &[u8]) -> Option<Access> {
+    let matches = |token: &str| hash == &sha1_hash(format!("{}{}", id, token).as_bytes())[..];
+    if matches(&DL_TOKEN) {
+        return Some(Access::admin());
+    }
+    CONFIG
+        .rpc
+        .tokens
+        .iter()
+        .filter_map(|t| authenticate(Some(&t.token)))
+        .find(|a| a.restricted && matches(&download_token(a)))
+}
+
+/// Signs a download link for a resource, restricted to the
+/// given expiration timestamp and bandwidth cap.
+pub fn sign_link(id: &str, expires: Option<i64>, rate: Option<u64>) -> String {
+    let tag = hmac::sign(&KEY, link_data(id, expires, rate).as_bytes());
+    base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks the signature of a download link, without
+/// regard for whether or not it has expired.
+pub fn verify_link(id: &str, expires: Option<i64>, rate: Option<u64>, sig: &str) -> bool {
+    match base64::decode_config(sig, base64::URL_SAFE_NO_PAD) {
+        Ok(tag) => hmac::verify(&KEY, link_data(id, expires, rate).as_bytes(), &tag).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn link_data(id: &str, expires: Option<i64>, rate: Option<u64>) -> String {
+    let field = |v: Option<String>| v.unwrap_or_default();
+    format!(
+        "{}:{}:{}",
+        id,
+        field(expires.map(|e| e.to_string())),
+        field(rate.map(|r| r.to_string()))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_signature() {
+        let sig = sign_link("abc", Some(1000), Some(512));
+        assert!(verify_link("abc", Some(1000), Some(512), &sig));
+        assert!(!verify_link("abc", Some(1000), None, &sig));
+        assert!(!verify_link("abc", Some(2000), Some(512), &sig));
+        assert!(!verify_link("abd", Some(1000), Some(512), &sig));
+        assert!(!verify_link("abc", Some(1000), Some(512), "garbage"));
+    }
+
+    #[test]
+    fn test_download_token() {
+        let user = |name: &str| Access {
+            scope: Scope::Read,
+            user: Some(name.to_owned()),
+            restricted: true,
+        };
+        assert_eq!(download_token(&Access::admin()), *DL_TOKEN);
+        let tok = download_token(&user("a"));
+        assert_ne!(tok, *DL_TOKEN);
+        assert_ne!(tok, download_token(&user("b")));
+
+        let hash = sha1_hash(format!("abc{}", *DL_TOKEN).as_bytes());
+        assert_eq!(authenticate_download("abc", &hash), Some(Access::admin()));
+        assert_eq!(authenticate_download("abd", &hash), None);
+        let hash = sha1_hash(format!("abc{}", tok).as_bytes());
+        assert_eq!(authenticate_download("abc", &hash), None);
+    }
+}
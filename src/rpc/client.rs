@@ -1,17 +1,21 @@
 use std::io::{self, Write};
-use std::{mem, result, str, time};
+use std::{cmp, mem, result, str, time};
 
+use chrono::{DateTime, Utc};
 use sstream::SStream;
 use url::Url;
 
+use super::api::{ApiEndpoint, ApiMethod, ApiRequest, MAX_BODY};
+use super::auth::{self, Access};
 use super::proto::message::{SMessage, Version};
+use super::proto::resource::ResourceKind;
 use super::proto::ws::{Frame, Message, Opcode};
 use super::reader::Reader;
 use super::writer::Writer;
 use super::{ErrorKind, Result, ResultExt};
 use super::{EMPTY_HTTP_RESP, UNAUTH_HTTP_RESP};
-use crate::util::{aread, sha1_hash, IOR};
-use crate::{CONFIG, DL_TOKEN};
+use crate::util::{aread, http, sha1_hash, IOR};
+use crate::CONFIG;
 
 pub struct Client {
     pub conn: SStream,
@@ -31,9 +35,29 @@ pub struct Incoming {
 
 pub enum IncomingStatus {
     Incomplete,
-    Upgrade,
+    Upgrade(Access),
     Transfer { data: Vec<u8>, token: String },
-    DL { id: String, range: Option<String> },
+    DL(DlRequest),
+    Api { req: ApiRequest, data: Vec<u8> },
+}
+
+/// A request for a file over HTTP.
+pub struct DlRequest {
+    pub id: String,
+    /// Bytes of requests pipelined after this one
+    pub pipelined: Vec<u8>,
+    /// Access granted by the download's token or signature
+    pub access: Access,
+    /// Archive format requested for a torrent download
+    pub format: Option<String>,
+    /// Bandwidth cap in bytes per second
+    pub rate: Option<u64>,
+    pub head: bool,
+    pub keep_alive: bool,
+    pub range: Option<String>,
+    pub if_range: Option<String>,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
 }
 
 enum FragBuf {
@@ -165,10 +189,19 @@ impl Into<SStream> for Incoming {
 
 impl Incoming {
     pub fn new(conn: SStream) -> Incoming {
+        Incoming::with_pipelined(conn, &[])
+    }
+
+    /// Creates an Incoming for a kept alive connection, starting
+    /// with requests that were read along with the last one.
+    pub fn with_pipelined(conn: SStream, pipelined: &[u8]) -> Incoming {
+        let mut buf = [0; 1024];
+        let pos = cmp::min(pipelined.len(), buf.len());
+        buf[..pos].copy_from_slice(&pipelined[..pos]);
         Incoming {
             conn,
-            buf: [0; 1024],
-            pos: 0,
+            buf,
+            pos,
             last_action: time::Instant::now(),
             key: None,
         }
@@ -178,6 +211,12 @@ impl Incoming {
     /// valid to be upgraded into a Client
     pub fn readable(&mut self) -> io::Result<IncomingStatus> {
         self.last_action = time::Instant::now();
+        // Pipelined requests may already be complete
+        if self.pos > 0 {
+            if let Some(r) = self.process_incoming()? {
+                return Ok(r);
+            }
+        }
         loop {
             match aread(&mut self.buf[self.pos..], &mut self.conn) {
                 // TODO: Consider more
@@ -212,14 +251,20 @@ impl Incoming {
         match req.parse(&self.buf[..self.pos]) {
             Ok(httparse::Status::Partial) => Ok(None),
             Ok(httparse::Status::Complete(idx)) => {
+                let pipelined = self.buf[idx..self.pos].to_owned();
                 if req.method == Some("HEAD") {
+                    if let Some(dl) = validate_dl(&req) {
+                        return Ok(Some(IncomingStatus::DL(DlRequest { pipelined, ..dl })));
+                    }
                     self.conn.write(&EMPTY_HTTP_RESP).ok();
                     return Err(io::ErrorKind::InvalidData.into());
                 }
-                match validate_upgrade(&req) {
-                    Ok(k) => {
+                // Access to the unix socket is controlled by its permissions
+                let local = self.conn.is_unix();
+                match validate_upgrade(&req, local) {
+                    Ok((k, access)) => {
                         self.key = Some(k);
-                        return Ok(Some(IncomingStatus::Upgrade));
+                        return Ok(Some(IncomingStatus::Upgrade(access)));
                     }
                     Err(true) => {
                         self.conn.write(&UNAUTH_HTTP_RESP).ok();
@@ -227,13 +272,26 @@ impl Incoming {
                     }
                     Err(false) => {}
                 }
+                match validate_api(&req, local) {
+                    Some(Ok(api)) => {
+                        return Ok(Some(IncomingStatus::Api {
+                            req: api,
+                            data: self.buf[idx..self.pos].to_owned(),
+                        }));
+                    }
+                    Some(Err(())) => {
+                        self.conn.write_all(&UNAUTH_HTTP_RESP).ok();
+                        return Err(io::ErrorKind::InvalidData.into());
+                    }
+                    None => {}
+                }
                 if let Some(token) = validate_tx(&req) {
                     Ok(Some(IncomingStatus::Transfer {
                         data: self.buf[idx..self.pos].to_owned(),
                         token,
                     }))
-                } else if let Some((id, range)) = validate_dl(&req) {
-                    Ok(Some(IncomingStatus::DL { id, range }))
+                } else if let Some(dl) = validate_dl(&req) {
+                    Ok(Some(IncomingStatus::DL(DlRequest { pipelined, ..dl })))
                 } else {
                     // Ignore error, we're DCing anyways
                     self.conn.write(&EMPTY_HTTP_RESP).ok();
@@ -245,6 +303,46 @@ impl Incoming {
     }
 }
 
+impl DlRequest {
+    /// Whether the client's cached copy, as identified by the
+    /// conditional headers, is still valid.
+    pub fn not_modified(&self, etag: &str, mtime: Option<DateTime<Utc>>) -> bool {
+        if let Some(ref tags) = self.if_none_match {
+            return tags
+                .split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == etag);
+        }
+        match (
+            self.if_modified_since
+                .as_ref()
+                .and_then(|d| http::parse_date(d)),
+            mtime,
+        ) {
+            (Some(since), Some(mtime)) => mtime.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    /// The range to be served, which is discarded if If-Range
+    /// indicates the client's partial copy is stale.
+    pub fn range(&self, etag: &str, mtime: Option<DateTime<Utc>>) -> Option<&str> {
+        let valid = match self.if_range {
+            None => true,
+            Some(ref v) if v.trim().starts_with('"') => v.trim() == etag,
+            Some(ref v) => match (http::parse_date(v), mtime) {
+                (Some(date), Some(mtime)) => mtime.timestamp() == date.timestamp(),
+                _ => false,
+            },
+        };
+        if valid {
+            self.range.as_deref()
+        } else {
+            None
+        }
+    }
+}
+
 impl FragBuf {
     fn process(&mut self, msg: Message) -> Result<Option<Frame>> {
         let fin = msg.fin();
@@ -287,50 +385,236 @@ impl FragBuf {
     }
 }
 
-fn validate_dl(req: &httparse::Request<'_, '_>) -> Option<(String, Option<String>)> {
-    req.path
-        .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok())
+fn validate_dl(req: &httparse::Request<'_, '_>) -> Option<DlRequest> {
+    let url = req
+        .path
+        .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok())?;
+    let query = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let format = query("format");
+    let expires = query("expires").and_then(|e| e.parse::<i64>().ok());
+    let rate = query("rate")
+        .and_then(|r| r.parse::<u64>().ok())
+        .filter(|&r| r > 0);
+    Some(&url)
         .and_then(|url| {
             let id = if url.path().contains("/dl/") {
                 url.path_segments().unwrap().last().map(|v| v.to_owned())
             } else {
                 return None;
             };
-            if CONFIG.rpc.auth {
-                let pw = url
-                    .query_pairs()
-                    .find(|&(ref k, _)| k == "token")
-                    .map(|(_, v)| format!("{}", v))
-                    .and_then(|p| base64::decode(&p).ok())
-                    .map(|p| {
-                        p.as_ref()
-                            == sha1_hash(
-                                format!(
-                                    "{}{}",
-                                    id.as_ref().map(|s| s.as_str()).unwrap_or(""),
-                                    *DL_TOKEN
-                                )
-                                .as_bytes(),
-                            )
-                    })
-                    .unwrap_or(false);
-                if !pw {
+            let access = if let Some(sig) = query("sig") {
+                // Signed links are valid regardless of auth, until they expire
+                let id = id.as_deref().unwrap_or("");
+                if !auth::verify_link(id, expires, rate, &sig)
+                    || expires
+                        .map(|e| e <= Utc::now().timestamp())
+                        .unwrap_or(false)
+                {
                     return None;
                 }
-            }
-            id
+                Access::admin()
+            } else if CONFIG.rpc.auth {
+                let hash = query("token").and_then(|p| base64::decode(&p).ok())?;
+                auth::authenticate_download(id.as_deref().unwrap_or(""), &hash)?
+            } else {
+                Access::admin()
+            };
+            id.map(|id| (id, access))
         })
-        .map(|id| {
-            let range = req
-                .headers
-                .iter()
-                .find(|header| header.name.to_lowercase() == "range")
-                .and_then(|header| str::from_utf8(header.value).ok())
-                .map(str::to_owned);
-            (id, range)
+        .map(|(id, access)| {
+            let header = |name: &str| {
+                req.headers
+                    .iter()
+                    .find(|header| header.name.to_lowercase() == name)
+                    .and_then(|header| str::from_utf8(header.value).ok())
+                    .map(str::to_owned)
+            };
+            DlRequest {
+                id,
+                pipelined: Vec::new(),
+                access,
+                format,
+                rate,
+                head: req.method == Some("HEAD"),
+                keep_alive: keep_alive(req),
+                range: header("range"),
+                if_range: header("if-range"),
+                if_none_match: header("if-none-match"),
+                if_modified_since: header("if-modified-since"),
+            }
         })
 }
 
+/// Whether the connection should be kept open after responding.
+fn keep_alive(req: &httparse::Request<'_, '_>) -> bool {
+    let connection = req
+        .headers
+        .iter()
+        .find(|header| header.name.to_lowercase() == "connection")
+        .and_then(|header| str::from_utf8(header.value).ok())
+        .map(str::to_lowercase);
+    // HTTP/1.1 connections are persistent unless otherwise specified
+    match connection.as_deref() {
+        Some("close") => false,
+        Some("keep-alive") => true,
+        _ => req.version == Some(1),
+    }
+}
+
+/// Returns None if the request does not target the API, or an error if
+/// the client failed to authenticate.
+fn validate_api(
+    req: &httparse::Request<'_, '_>,
+    local: bool,
+) -> Option<result::Result<ApiRequest, ()>> {
+    let method = match req.method? {
+        "GET" => ApiMethod::Get,
+        "POST" => ApiMethod::Post,
+        "PATCH" => ApiMethod::Patch,
+        _ => return None,
+    };
+    let url = req
+        .path
+        .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok())?;
+    let header = |name: &str| {
+        req.headers
+            .iter()
+            .find(|header| header.name.to_lowercase() == name)
+            .and_then(|header| str::from_utf8(header.value).ok())
+    };
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+    let kind = match segments.next()? {
+        "server" => ResourceKind::Server,
+        "torrents" => ResourceKind::Torrent,
+        "peers" => ResourceKind::Peer,
+        "files" => ResourceKind::File,
+        "pieces" => ResourceKind::Piece,
+        "trackers" => ResourceKind::Tracker,
+        "transmission" if CONFIG.rpc.transmission => {
+            if segments.next() != Some("rpc") || segments.next().is_some() {
+                return None;
+            }
+            return validate_api_request(
+                req,
+                method,
+                ApiEndpoint::Transmission {
+                    session: header("x-transmission-session-id").map(str::to_owned),
+                },
+                local,
+            );
+        }
+        "api" if CONFIG.rpc.qbittorrent => {
+            let (group, name) = match (segments.next(), segments.next(), segments.next()) {
+                (Some("v2"), Some(group), Some(name)) => (group, name),
+                _ => return None,
+            };
+            if segments.next().is_some() {
+                return None;
+            }
+            // qBittorrent clients log in to obtain a session cookie, and
+            // expect a 403 rather than a 401 when it is missing or invalid
+            let access = header("cookie")
+                .and_then(|cookies| {
+                    cookies
+                        .split(';')
+                        .find_map(|c| c.trim().strip_prefix("SID="))
+                })
+                .and_then(auth::authenticate_session)
+                .or_else(|| authenticate(req, local));
+            let endpoint = ApiEndpoint::QBittorrent {
+                path: format!("{}/{}", group, name),
+                query: url.query().unwrap_or("").to_owned(),
+                content_type: header("content-type").map(str::to_owned),
+                cross_origin: cross_origin(
+                    header("host"),
+                    header("origin").or_else(|| header("referer")),
+                ),
+            };
+            return api_request(req, method, endpoint, access).map(Ok);
+        }
+        _ => return None,
+    };
+    let id = segments.next().map(str::to_owned);
+    if segments.next().is_some() {
+        return None;
+    }
+    // Any client could make changes if they were allowed without auth
+    if method != ApiMethod::Get && !local && !CONFIG.rpc.auth {
+        return Some(Err(()));
+    }
+    validate_api_request(req, method, ApiEndpoint::Resources { kind, id }, local)
+}
+
+/// Whether the origin or referer browsers send with a request names
+/// another host than the request was sent to. Requests without either
+/// don't come from browsers.
+fn cross_origin(host: Option<&str>, origin: Option<&str>) -> bool {
+    let origin = match origin {
+        Some(origin) => Url::parse(origin).ok(),
+        None => return false,
+    };
+    let origin = origin.as_ref().and_then(|url| {
+        let port = url.port().map(|p| format!(":{}", p)).unwrap_or_default();
+        url.host_str().map(|h| format!("{}{}", h, port))
+    });
+    match (host, origin) {
+        (Some(host), Some(origin)) => !host.eq_ignore_ascii_case(&origin),
+        _ => true,
+    }
+}
+
+fn validate_api_request(
+    req: &httparse::Request<'_, '_>,
+    method: ApiMethod,
+    endpoint: ApiEndpoint,
+    local: bool,
+) -> Option<result::Result<ApiRequest, ()>> {
+    let access = authenticate(req, local);
+    let api = api_request(req, method, endpoint, access)?;
+    if api.access.is_none() {
+        return Some(Err(()));
+    }
+    Some(Ok(api))
+}
+
+fn api_request(
+    req: &httparse::Request<'_, '_>,
+    method: ApiMethod,
+    endpoint: ApiEndpoint,
+    access: Option<Access>,
+) -> Option<ApiRequest> {
+    let size = req
+        .headers
+        .iter()
+        .find(|header| header.name.to_lowercase() == "content-length")
+        .and_then(|header| str::from_utf8(header.value).ok())
+        .map(|len| len.trim().parse::<usize>().ok())
+        .unwrap_or(Some(0))
+        .filter(|&len| len <= MAX_BODY)?;
+    let header = |name: &str| {
+        req.headers
+            .iter()
+            .find(|header| header.name.to_lowercase() == name)
+            .and_then(|header| str::from_utf8(header.value).ok())
+    };
+    let origin = header("origin")
+        .filter(|&o| cross_origin(header("host"), Some(o)))
+        .map(str::to_owned);
+    Some(ApiRequest {
+        method,
+        endpoint,
+        access,
+        keep_alive: keep_alive(req),
+        origin,
+        size,
+        body: Vec::new(),
+    })
+}
+
 // TODO: We're not really checking HTTP semantics here, might be worth
 // considering.
 fn validate_tx(req: &httparse::Request<'_, '_>) -> Option<String> {
@@ -349,7 +633,10 @@ fn validate_tx(req: &httparse::Request<'_, '_>) -> Option<String> {
     None
 }
 
-fn validate_upgrade(req: &httparse::Request<'_, '_>) -> result::Result<String, bool> {
+fn validate_upgrade(
+    req: &httparse::Request<'_, '_>,
+    local: bool,
+) -> result::Result<(String, Access), bool> {
     if !req.method.map(|m| m == "GET").unwrap_or(false) {
         return Err(false);
     }
@@ -378,46 +665,73 @@ fn validate_upgrade(req: &httparse::Request<'_, '_>) -> result::Result<String, b
         return Err(false);
     }
 
-    if CONFIG.rpc.auth {
-        let auth = req
-            .path
-            .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok())
-            .and_then(|url| {
-                url.query_pairs()
-                    .find(|&(ref k, _)| k == "password")
-                    .map(|(_, v)| format!("{}", v))
-                    .map(|p| p == CONFIG.rpc.password)
-            })
-            .or_else(|| {
-                req.headers
-                    .iter()
-                    .find(|header| header.name.to_lowercase() == "authorization")
-                    .and_then(|header| str::from_utf8(header.value).ok())
-                    .and_then(|value| {
-                        if value.to_lowercase().starts_with("basic ") {
-                            let (_, auth) = value.split_at(6);
-                            Some(auth)
-                        } else {
-                            None
-                        }
-                    })
-                    .and_then(|auth| base64::decode(auth).ok())
-                    .and_then(|auth| String::from_utf8(auth).ok())
-                    .and_then(|auth| {
-                        auth.split_terminator(':')
-                            .last()
-                            .map(|password| password == CONFIG.rpc.password)
-                    })
-            })
-            .unwrap_or(false);
-        if !auth {
-            return Err(true);
-        }
-    }
+    let access = match authenticate(req, local) {
+        Some(access) => access,
+        None => return Err(true),
+    };
 
     if let Some(k) = key {
-        Ok(k.to_owned())
+        Ok((k.to_owned(), access))
     } else {
         Err(false)
     }
 }
+
+/// Determines the access granted to a request, which is unrestricted for
+/// local connections.
+fn authenticate(req: &httparse::Request<'_, '_>, local: bool) -> Option<Access> {
+    if local {
+        return Some(Access::admin());
+    }
+    auth::authenticate(request_secret(req).as_deref())
+}
+
+/// Extracts the password or token a client authenticated with.
+fn request_secret(req: &httparse::Request<'_, '_>) -> Option<String> {
+    req.path
+        .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok())
+        .and_then(|url| {
+            url.query_pairs()
+                .find(|&(ref k, _)| k == "password")
+                .map(|(_, v)| format!("{}", v))
+        })
+        .or_else(|| {
+            req.headers
+                .iter()
+                .find(|header| header.name.to_lowercase() == "authorization")
+                .and_then(|header| str::from_utf8(header.value).ok())
+                .and_then(auth::authorization_secret)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_pipelined_dl() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let req = |id: &str| {
+            let sig = auth::sign_link(id, None, None);
+            format!("GET /dl/{}?sig={} HTTP/1.1\r\n\r\n", id, sig)
+        };
+        b.write_all(format!("{}{}", req("abc"), req("def")).as_bytes())
+            .unwrap();
+
+        let mut i = Incoming::new(SStream::from_unix(a).unwrap());
+        let dl = match i.readable().unwrap() {
+            IncomingStatus::DL(dl) => dl,
+            _ => panic!("expected a download request"),
+        };
+        assert_eq!(dl.id, "abc");
+        assert_eq!(dl.pipelined, req("def").into_bytes());
+
+        // The next request is handled without waiting for more data
+        let mut i = Incoming::with_pipelined(i.conn, &dl.pipelined);
+        match i.readable().unwrap() {
+            IncomingStatus::DL(dl) => assert_eq!(dl.id, "def"),
+            _ => panic!("expected a download request"),
+        }
+    }
+}
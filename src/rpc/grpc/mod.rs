@@ -0,0 +1,500 @@
+//! gRPC interface to the RPC resources, described by proto/synapse.proto.
+//! The service runs on its own thread, and each call acts as a virtual
+//! client of the processor on the RPC thread, exchanging the same messages
+//! as websocket clients do.
+
+// Every call returns a tonic::Status on failure, however large it is
+#![allow(clippy::result_large_err)]
+
+mod proto;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{io, thread};
+
+use serde_json as json;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status};
+
+use self::proto::add_torrent_request::Source;
+use self::proto::resource_event::Event;
+use self::proto::{
+    AddTorrentRequest, AddTorrentResponse, Empty, GetResourcesRequest, ListResourcesRequest,
+    RemoveResourceRequest, ResourceEvent, ResourceKind, Resources, TorrentRequest,
+};
+use self::synapse_server::{Synapse, SynapseServer};
+use super::api::{self, Failure, NewTorrent};
+use super::auth::{self, Access};
+use super::processor::Processor;
+use super::proto::criterion::{Criterion, Operation, Value};
+use super::proto::message::{CMessage, SMessage};
+use super::proto::resource::{Resource, SResourceUpdate};
+use super::Message;
+use crate::util::{hash_to_id, UHashMap};
+
+include!(concat!(env!("OUT_DIR"), "/synapse.Synapse.rs"));
+
+/// Virtual clients are allocated IDs from the upper half of the ID space,
+/// so as not to collide with connections registered with the poller.
+static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(usize::MAX / 2 + 1);
+
+/// Number of events buffered for a watching client
+const WATCH_BUF: usize = 64;
+
+/// Number of replies buffered for a call. Calls which fall this far
+/// behind are dropped rather than letting replies pile up on the RPC thread.
+const REPLY_BUF: usize = 1024;
+
+/// Sent by a call to the RPC thread on behalf of its virtual client.
+#[derive(Debug)]
+pub enum Command {
+    Connect(Access, mpsc::Sender<Reply>),
+    Request(CMessage),
+    Add(NewTorrent),
+    Disconnect,
+}
+
+/// Sent by the RPC thread to a call.
+#[derive(Debug)]
+pub enum Reply {
+    /// A message, serialized as it would be for a websocket client
+    Message(String),
+    Added(String),
+    Failed(Failure),
+    /// Every immediate reply to a command has been sent
+    Done,
+}
+
+/// The RPC thread's side of the gRPC interface.
+pub struct Bridge {
+    rx: amy::Receiver<(usize, Command)>,
+    clients: UHashMap<mpsc::Sender<Reply>>,
+}
+
+impl Bridge {
+    /// Starts serving gRPC on the given address.
+    pub fn start(reg: &mut amy::Registrar, addr: SocketAddr) -> io::Result<Bridge> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let (tx, rx) = reg.channel()?;
+        thread::Builder::new()
+            .name("grpc".to_owned())
+            .spawn(move || serve(listener, tx))?;
+        Ok(Bridge {
+            rx,
+            clients: UHashMap::default(),
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.rx.get_id()
+    }
+
+    /// Handles pending commands, returning any messages which should be
+    /// passed on to control.
+    pub fn handle(&mut self, processor: &mut Processor) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        while let Ok((client, cmd)) = self.rx.try_recv() {
+            match cmd {
+                Command::Connect(access, tx) => {
+                    processor.add_client(client, access);
+                    self.clients.insert(client, tx);
+                    continue;
+                }
+                Command::Disconnect => {
+                    processor.remove_client(client);
+                    self.clients.remove(&client);
+                    continue;
+                }
+                Command::Request(msg) => {
+                    let (resp, rmsg) = processor.handle_client(client, msg);
+                    let replies: Vec<_> = resp
+                        .iter()
+                        .map(|m| match api::failure(m) {
+                            Some(f) => Reply::Failed(f),
+                            None => Reply::Message(json::to_string(m).unwrap()),
+                        })
+                        .collect();
+                    for reply in replies {
+                        self.reply(client, reply);
+                    }
+                    msgs.extend(rmsg);
+                }
+                Command::Add(t) => match api::add_torrent(processor, client, t) {
+                    Ok(msg) => {
+                        if let Message::Torrent { ref info, .. } = msg {
+                            self.reply(client, Reply::Added(hash_to_id(&info.hash)));
+                        }
+                        msgs.push(msg);
+                    }
+                    Err(f) => self.reply(client, Reply::Failed(f)),
+                },
+            }
+            self.reply(client, Reply::Done);
+        }
+        msgs
+    }
+
+    /// Sends a message to a virtual client, returning false if the
+    /// client is not one of ours.
+    pub fn send(&mut self, client: usize, msg: String) -> bool {
+        if !self.clients.contains_key(&client) {
+            return false;
+        }
+        self.reply(client, Reply::Message(msg));
+        true
+    }
+
+    /// Sends a reply to a virtual client, dropping the call if it
+    /// isn't keeping up. Its session then disconnects the client.
+    fn reply(&mut self, client: usize, reply: Reply) {
+        let full = match self.clients.get(&client).map(|tx| tx.try_send(reply)) {
+            Some(Err(mpsc::error::TrySendError::Full(_))) => true,
+            // Other failures are handled once the call disconnects
+            _ => false,
+        };
+        if full {
+            debug!("Dropping gRPC call of client {} which fell behind", client);
+            self.clients.remove(&client);
+        }
+    }
+}
+
+fn serve(listener: TcpListener, tx: amy::Sender<(usize, Command)>) {
+    let rt = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(e) => {
+            error!("Failed to start gRPC runtime: {}", e);
+            return;
+        }
+    };
+    rt.block_on(async move {
+        let listener = match tokio::net::TcpListener::from_std(listener) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Failed to listen for gRPC connections: {}", e);
+                return;
+            }
+        };
+        let res = tonic::transport::Server::builder()
+            .add_service(SynapseServer::new(Service { tx }))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await;
+        if let Err(e) = res {
+            error!("gRPC server failed: {}", e);
+        }
+    });
+}
+
+struct Service {
+    tx: amy::Sender<(usize, Command)>,
+}
+
+#[tonic::async_trait]
+impl Synapse for Service {
+    type WatchResourcesStream = ReceiverStream<Result<ResourceEvent, Status>>;
+
+    async fn get_resources(
+        &self,
+        req: Request<GetResourcesRequest>,
+    ) -> Result<Response<Resources>, Status> {
+        let mut session = Session::connect(&self.tx, req.metadata())?;
+        let resources = session.subscribe(req.into_inner().ids).await?;
+        Ok(Response::new(Resources {
+            resources: resources.iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn list_resources(
+        &self,
+        req: Request<ListResourcesRequest>,
+    ) -> Result<Response<Resources>, Status> {
+        let mut session = Session::connect(&self.tx, req.metadata())?;
+        let msgs = session.request(filter(req.get_ref())?).await?;
+        let ids = msgs
+            .into_iter()
+            .flat_map(|m| match m {
+                SMessage::ResourcesExtant { ids, .. } => ids,
+                _ => vec![],
+            })
+            .map(|id| id.into_owned())
+            .collect();
+        let resources = session.subscribe(ids).await?;
+        Ok(Response::new(Resources {
+            resources: resources.iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn watch_resources(
+        &self,
+        req: Request<ListResourcesRequest>,
+    ) -> Result<Response<Self::WatchResourcesStream>, Status> {
+        let session = Session::connect(&self.tx, req.metadata())?;
+        let filter = filter(req.get_ref())?;
+        let (tx, rx) = mpsc::channel(WATCH_BUF);
+        tokio::spawn(session.watch(filter, tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn add_torrent(
+        &self,
+        req: Request<AddTorrentRequest>,
+    ) -> Result<Response<AddTorrentResponse>, Status> {
+        let mut session = Session::connect(&self.tx, req.metadata())?;
+        let req = req.into_inner();
+        let mut t = NewTorrent {
+            path: req.path,
+            start: !req.paused,
+            import: req.import,
+            seed_only: req.seed_only,
+            label: req.label,
+            ..Default::default()
+        };
+        match req.source {
+            Some(Source::Magnet(uri)) => t.uri = Some(uri),
+            Some(Source::Torrent(data)) => t.torrent = Some(base64::encode(&data)),
+            None => return Err(Status::invalid_argument("No magnet or torrent given")),
+        }
+        let id = session
+            .call(Command::Add(t))
+            .await?
+            .into_iter()
+            .find_map(|r| match r {
+                Reply::Added(id) => Some(id),
+                _ => None,
+            })
+            .ok_or_else(|| Status::internal("Torrent was not added"))?;
+        Ok(Response::new(AddTorrentResponse { id }))
+    }
+
+    async fn pause_torrent(&self, req: Request<TorrentRequest>) -> Result<Response<Empty>, Status> {
+        let mut session = Session::connect(&self.tx, req.metadata())?;
+        let id = req.into_inner().id;
+        session
+            .request(CMessage::PauseTorrent { serial: 0, id })
+            .await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn resume_torrent(
+        &self,
+        req: Request<TorrentRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let mut session = Session::connect(&self.tx, req.metadata())?;
+        let id = req.into_inner().id;
+        session
+            .request(CMessage::ResumeTorrent { serial: 0, id })
+            .await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn remove_resource(
+        &self,
+        req: Request<RemoveResourceRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let mut session = Session::connect(&self.tx, req.metadata())?;
+        let req = req.into_inner();
+        session
+            .request(CMessage::RemoveResource {
+                serial: 0,
+                id: req.id,
+                artifacts: Some(req.artifacts),
+            })
+            .await?;
+        Ok(Response::new(Empty {}))
+    }
+}
+
+/// A virtual client of the processor, which lasts for the duration of a call.
+struct Session {
+    id: usize,
+    tx: amy::Sender<(usize, Command)>,
+    rx: mpsc::Receiver<Reply>,
+}
+
+impl Session {
+    fn connect(
+        tx: &amy::Sender<(usize, Command)>,
+        metadata: &MetadataMap,
+    ) -> Result<Session, Status> {
+        let secret = metadata
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(auth::authorization_secret);
+        let access = auth::authenticate(secret.as_deref())
+            .ok_or_else(|| Status::unauthenticated("Invalid credentials"))?;
+        let (rtx, rx) = mpsc::channel(REPLY_BUF);
+        let session = Session {
+            id: NEXT_CLIENT.fetch_add(1, Ordering::Relaxed),
+            tx: tx.clone(),
+            rx,
+        };
+        session.send(Command::Connect(access, rtx))?;
+        Ok(session)
+    }
+
+    fn send(&self, cmd: Command) -> Result<(), Status> {
+        self.tx
+            .send((self.id, cmd))
+            .map_err(|_| Status::unavailable("Synapse is shutting down"))
+    }
+
+    /// Sends a command, returning its immediate replies.
+    async fn call(&mut self, cmd: Command) -> Result<Vec<Reply>, Status> {
+        self.send(cmd)?;
+        let mut replies = Vec::new();
+        let mut failure = None;
+        loop {
+            match self.rx.recv().await {
+                Some(Reply::Done) => break,
+                Some(Reply::Failed(f)) => {
+                    failure.get_or_insert(f);
+                }
+                Some(reply) => replies.push(reply),
+                None => return Err(Status::unavailable("Call was dropped by synapse")),
+            }
+        }
+        match failure {
+            Some(f) => Err(status(f)),
+            None => Ok(replies),
+        }
+    }
+
+    /// Sends a request, returning the messages sent in response.
+    async fn request(&mut self, msg: CMessage) -> Result<Vec<SMessage<'static>>, Status> {
+        Ok(self
+            .call(Command::Request(msg))
+            .await?
+            .into_iter()
+            .filter_map(|r| match r {
+                Reply::Message(m) => json::from_str(&m).ok(),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Subscribes to resources, returning their current state.
+    async fn subscribe(&mut self, ids: Vec<String>) -> Result<Vec<Resource>, Status> {
+        let msgs = self.request(CMessage::Subscribe { serial: 0, ids }).await?;
+        Ok(msgs
+            .into_iter()
+            .flat_map(|m| match m {
+                SMessage::UpdateResources { resources, .. } => resources,
+                _ => vec![],
+            })
+            .filter_map(|u| match u {
+                SResourceUpdate::Resource(r) => Some(r.into_owned()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Streams events for resources matching a filter until the call ends.
+    /// The full state of each resource is tracked so that partial updates
+    /// can be sent as complete resources.
+    async fn watch(mut self, filter: CMessage, tx: mpsc::Sender<Result<ResourceEvent, Status>>) {
+        if self.send(Command::Request(filter)).is_err() {
+            return;
+        }
+        let mut resources: HashMap<String, Resource> = HashMap::new();
+        while let Some(reply) = self.rx.recv().await {
+            let msg = match reply {
+                Reply::Message(m) => match json::from_str(&m) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                },
+                Reply::Failed(f) => {
+                    tx.send(Err(status(f))).await.ok();
+                    return;
+                }
+                Reply::Added(_) | Reply::Done => continue,
+            };
+            let mut events = Vec::new();
+            match msg {
+                SMessage::ResourcesExtant { ids, .. } => {
+                    let ids = ids.into_iter().map(|id| id.into_owned()).collect();
+                    if self
+                        .send(Command::Request(CMessage::Subscribe { serial: 0, ids }))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                SMessage::ResourcesRemoved { ids, .. } => {
+                    for id in ids {
+                        resources.remove(&id);
+                        events.push(Event::Removed(id));
+                    }
+                }
+                SMessage::UpdateResources {
+                    resources: updates, ..
+                } => {
+                    for update in updates {
+                        let id = update.id().to_owned();
+                        match update {
+                            SResourceUpdate::Resource(r) => {
+                                resources.insert(id.clone(), r.into_owned());
+                            }
+                            update => {
+                                if let Some(r) = resources.get_mut(&id) {
+                                    r.update(update);
+                                }
+                            }
+                        }
+                        if let Some(r) = resources.get(&id) {
+                            events.push(Event::Updated(r.into()));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            for event in events {
+                let event = ResourceEvent { event: Some(event) };
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.send(Command::Disconnect).ok();
+    }
+}
+
+/// Builds the filter for a list or watch request.
+fn filter(req: &ListResourcesRequest) -> Result<CMessage, Status> {
+    let kind = ResourceKind::try_from(req.kind)
+        .map_err(|_| Status::invalid_argument("Unknown resource kind"))?;
+    let criteria = req
+        .torrent_id
+        .iter()
+        .map(|id| Criterion {
+            field: "torrent_id".to_owned(),
+            op: Operation::Eq,
+            value: Value::S(id.clone()),
+        })
+        .collect();
+    Ok(CMessage::FilterSubscribe {
+        serial: 0,
+        kind: kind.into(),
+        criteria,
+    })
+}
+
+fn status(f: Failure) -> Status {
+    match f.status {
+        403 => Status::permission_denied(f.reason),
+        404 => Status::not_found(f.reason),
+        _ => Status::invalid_argument(f.reason),
+    }
+}
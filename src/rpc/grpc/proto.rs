@@ -0,0 +1,961 @@
+//! Message types of the gRPC interface. These mirror proto/synapse.proto,
+//! and must be kept in sync with it by hand.
+
+use super::super::proto::resource as rpc;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum ResourceKind {
+    Server = 0,
+    Torrent = 1,
+    Peer = 2,
+    File = 3,
+    Piece = 4,
+    Tracker = 5,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetResourcesRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub ids: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListResourcesRequest {
+    #[prost(enumeration = "ResourceKind", tag = "1")]
+    pub kind: i32,
+    #[prost(string, optional, tag = "2")]
+    pub torrent_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Resources {
+    #[prost(message, repeated, tag = "1")]
+    pub resources: Vec<Resource>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ResourceEvent {
+    #[prost(oneof = "resource_event::Event", tags = "1, 2")]
+    pub event: Option<resource_event::Event>,
+}
+
+pub mod resource_event {
+    #[allow(clippy::large_enum_variant)]
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Event {
+        #[prost(message, tag = "1")]
+        Updated(super::Resource),
+        #[prost(string, tag = "2")]
+        Removed(String),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AddTorrentRequest {
+    #[prost(oneof = "add_torrent_request::Source", tags = "1, 2")]
+    pub source: Option<add_torrent_request::Source>,
+    #[prost(string, optional, tag = "3")]
+    pub path: Option<String>,
+    #[prost(bool, tag = "4")]
+    pub paused: bool,
+    #[prost(bool, tag = "5")]
+    pub import: bool,
+    #[prost(bool, tag = "6")]
+    pub seed_only: bool,
+    #[prost(string, optional, tag = "7")]
+    pub label: Option<String>,
+}
+
+pub mod add_torrent_request {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Source {
+        #[prost(string, tag = "1")]
+        Magnet(String),
+        #[prost(bytes = "vec", tag = "2")]
+        Torrent(Vec<u8>),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AddTorrentResponse {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TorrentRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RemoveResourceRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(bool, tag = "2")]
+    pub artifacts: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Resource {
+    #[prost(oneof = "resource::Resource", tags = "1, 2, 3, 4, 5, 6")]
+    pub resource: Option<resource::Resource>,
+}
+
+pub mod resource {
+    #[allow(clippy::large_enum_variant)]
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Resource {
+        #[prost(message, tag = "1")]
+        Server(super::Server),
+        #[prost(message, tag = "2")]
+        Torrent(super::Torrent),
+        #[prost(message, tag = "3")]
+        Peer(super::Peer),
+        #[prost(message, tag = "4")]
+        File(super::File),
+        #[prost(message, tag = "5")]
+        Piece(super::Piece),
+        #[prost(message, tag = "6")]
+        Tracker(super::Tracker),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, optional, tag = "2")]
+    pub path: Option<String>,
+    #[prost(int64, optional, tag = "3")]
+    pub throttle_up: Option<i64>,
+    #[prost(int64, optional, tag = "4")]
+    pub throttle_down: Option<i64>,
+    #[prost(float, optional, tag = "5")]
+    pub seed_ratio: Option<f32>,
+    #[prost(uint64, optional, tag = "6")]
+    pub seed_time: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ThrottleGroup {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(int64, optional, tag = "2")]
+    pub throttle_up: Option<i64>,
+    #[prost(int64, optional, tag = "3")]
+    pub throttle_down: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Server {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub download_token: String,
+    #[prost(uint64, tag = "3")]
+    pub rate_up: u64,
+    #[prost(uint64, tag = "4")]
+    pub rate_down: u64,
+    #[prost(int64, optional, tag = "5")]
+    pub throttle_up: Option<i64>,
+    #[prost(int64, optional, tag = "6")]
+    pub throttle_down: Option<i64>,
+    #[prost(uint64, tag = "7")]
+    pub transferred_up: u64,
+    #[prost(uint64, tag = "8")]
+    pub transferred_down: u64,
+    #[prost(uint64, tag = "9")]
+    pub ses_transferred_up: u64,
+    #[prost(uint64, tag = "10")]
+    pub ses_transferred_down: u64,
+    #[prost(float, tag = "11")]
+    pub ratio: f32,
+    #[prost(uint64, tag = "12")]
+    pub uptime: u64,
+    #[prost(uint64, tag = "13")]
+    pub free_space: u64,
+    #[prost(string, tag = "14")]
+    pub started: String,
+    #[prost(message, repeated, tag = "15")]
+    pub labels: Vec<Label>,
+    #[prost(string, optional, tag = "16")]
+    pub last_snapshot: Option<String>,
+    #[prost(message, optional, tag = "17")]
+    pub filtered_peers: Option<PeerFilterStats>,
+    #[prost(message, optional, tag = "18")]
+    pub buffers: Option<BufferStats>,
+    #[prost(uint32, tag = "19")]
+    pub port: u32,
+    #[prost(string, optional, tag = "20")]
+    pub external_ipv4: Option<String>,
+    #[prost(string, optional, tag = "21")]
+    pub external_ipv6: Option<String>,
+    #[prost(message, repeated, tag = "22")]
+    pub disk_jobs: Vec<DiskJobStats>,
+    #[prost(message, repeated, tag = "23")]
+    pub throttle_groups: Vec<ThrottleGroup>,
+    #[prost(bool, tag = "24")]
+    pub network_stopped: bool,
+    #[prost(uint64, tag = "25")]
+    pub dirty_files: u64,
+    #[prost(uint64, tag = "26")]
+    pub ses_overhead_up: u64,
+    #[prost(uint64, tag = "27")]
+    pub ses_overhead_down: u64,
+    #[prost(message, optional, tag = "28")]
+    pub dns: Option<DnsStats>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PeerFilterStats {
+    #[prost(uint64, tag = "1")]
+    pub bogon: u64,
+    #[prost(uint64, tag = "2")]
+    pub own: u64,
+    #[prost(uint64, tag = "3")]
+    pub multicast: u64,
+    #[prost(uint64, tag = "4")]
+    pub port: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BufferStats {
+    #[prost(uint64, tag = "1")]
+    pub blocks_used: u64,
+    #[prost(uint64, tag = "2")]
+    pub blocks_free: u64,
+    #[prost(uint64, tag = "3")]
+    pub pieces_used: u64,
+    #[prost(uint64, tag = "4")]
+    pub pieces_free: u64,
+    #[prost(uint64, tag = "5")]
+    pub piece_bytes_used: u64,
+    #[prost(uint64, tag = "6")]
+    pub piece_bytes_free: u64,
+    #[prost(uint64, tag = "7")]
+    pub alloc_failures: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DiskJobStats {
+    #[prost(string, tag = "1")]
+    pub kind: String,
+    #[prost(uint64, tag = "2")]
+    pub read: u64,
+    #[prost(uint64, tag = "3")]
+    pub written: u64,
+    #[prost(uint64, tag = "4")]
+    pub time: u64,
+    #[prost(uint64, tag = "5")]
+    pub runs: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DnsStats {
+    #[prost(uint64, tag = "1")]
+    pub hits: u64,
+    #[prost(uint64, tag = "2")]
+    pub misses: u64,
+    #[prost(uint64, tag = "3")]
+    pub cached: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum TorrentStatus {
+    Pending = 0,
+    Magnet = 1,
+    Paused = 2,
+    Leeching = 3,
+    Idle = 4,
+    Seeding = 5,
+    Hashing = 6,
+    Error = 7,
+    Stalled = 8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum TorrentState {
+    CheckingQueued = 0,
+    Checking = 1,
+    DownloadingMetadata = 2,
+    Downloading = 3,
+    Seeding = 4,
+    Paused = 5,
+    Queued = 6,
+    Error = 7,
+    Moving = 8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum Strategy {
+    Rarest = 0,
+    Sequential = 1,
+    RandomFirst = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Torrent {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, optional, tag = "2")]
+    pub name: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub creator: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub comment: Option<String>,
+    #[prost(bool, tag = "5")]
+    pub private: bool,
+    #[prost(string, tag = "6")]
+    pub path: String,
+    #[prost(string, tag = "7")]
+    pub created: String,
+    #[prost(string, tag = "8")]
+    pub modified: String,
+    #[prost(enumeration = "TorrentStatus", tag = "9")]
+    pub status: i32,
+    #[prost(string, optional, tag = "10")]
+    pub error: Option<String>,
+    #[prost(uint32, tag = "11")]
+    pub priority: u32,
+    #[prost(float, tag = "12")]
+    pub progress: f32,
+    #[prost(float, tag = "13")]
+    pub availability: f32,
+    #[prost(enumeration = "Strategy", tag = "14")]
+    pub strategy: i32,
+    #[prost(uint64, tag = "15")]
+    pub rate_up: u64,
+    #[prost(uint64, tag = "16")]
+    pub rate_down: u64,
+    #[prost(int64, optional, tag = "17")]
+    pub throttle_up: Option<i64>,
+    #[prost(int64, optional, tag = "18")]
+    pub throttle_down: Option<i64>,
+    #[prost(uint64, tag = "19")]
+    pub transferred_up: u64,
+    #[prost(uint64, tag = "20")]
+    pub transferred_down: u64,
+    #[prost(uint32, tag = "21")]
+    pub peers: u32,
+    #[prost(uint32, tag = "22")]
+    pub trackers: u32,
+    #[prost(string, repeated, tag = "23")]
+    pub tracker_urls: Vec<String>,
+    #[prost(uint64, optional, tag = "24")]
+    pub size: Option<u64>,
+    #[prost(uint64, optional, tag = "25")]
+    pub pieces: Option<u64>,
+    #[prost(uint32, optional, tag = "26")]
+    pub piece_size: Option<u32>,
+    #[prost(string, tag = "27")]
+    pub piece_field: String,
+    #[prost(uint32, optional, tag = "28")]
+    pub files: Option<u32>,
+    #[prost(string, optional, tag = "29")]
+    pub label: Option<String>,
+    #[prost(float, optional, tag = "30")]
+    pub seed_ratio: Option<f32>,
+    #[prost(uint64, optional, tag = "31")]
+    pub seed_time: Option<u64>,
+    #[prost(message, repeated, tag = "32")]
+    pub peer_sources: Vec<PeerSourceStats>,
+    #[prost(uint32, optional, tag = "33")]
+    pub seeders: Option<u32>,
+    #[prost(uint32, optional, tag = "34")]
+    pub leechers: Option<u32>,
+    #[prost(uint32, optional, tag = "35")]
+    pub completed: Option<u32>,
+    #[prost(bool, tag = "36")]
+    pub dht: bool,
+    #[prost(bool, tag = "37")]
+    pub pex: bool,
+    #[prost(string, optional, tag = "38")]
+    pub notes: Option<String>,
+    #[prost(uint64, tag = "39")]
+    pub disk_read: u64,
+    #[prost(uint64, tag = "40")]
+    pub disk_written: u64,
+    #[prost(uint64, tag = "41")]
+    pub disk_time: u64,
+    #[prost(string, optional, tag = "42")]
+    pub error_kind: Option<String>,
+    #[prost(message, repeated, tag = "43")]
+    pub hash_failures: Vec<HashFailure>,
+    #[prost(string, optional, tag = "44")]
+    pub throttle_group: Option<String>,
+    #[prost(enumeration = "TorrentState", tag = "45")]
+    pub state: i32,
+    #[prost(uint64, optional, tag = "46")]
+    pub wanted_size: Option<u64>,
+    #[prost(uint64, optional, tag = "47")]
+    pub eta: Option<u64>,
+    #[prost(bool, tag = "48")]
+    pub first_last: bool,
+    #[prost(string, tag = "49")]
+    pub piece_availability: String,
+    #[prost(uint64, tag = "50")]
+    pub overhead_up: u64,
+    #[prost(uint64, tag = "51")]
+    pub overhead_down: u64,
+    #[prost(message, repeated, tag = "52")]
+    pub countries: Vec<CountryStats>,
+    #[prost(string, optional, tag = "53")]
+    pub last_dht_announce: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HashFailure {
+    #[prost(uint32, tag = "1")]
+    pub piece: u32,
+    #[prost(string, tag = "2")]
+    pub time: String,
+    #[prost(message, repeated, tag = "3")]
+    pub blocks: Vec<BlockSource>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BlockSource {
+    #[prost(uint32, tag = "1")]
+    pub offset: u32,
+    #[prost(string, tag = "2")]
+    pub peer: String,
+    #[prost(string, tag = "3")]
+    pub ip: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PeerSourceStats {
+    #[prost(string, tag = "1")]
+    pub source: String,
+    #[prost(uint32, tag = "2")]
+    pub peers: u32,
+    #[prost(uint64, tag = "3")]
+    pub transferred_up: u64,
+    #[prost(uint64, tag = "4")]
+    pub transferred_down: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CountryStats {
+    #[prost(string, tag = "1")]
+    pub country: String,
+    #[prost(uint32, tag = "2")]
+    pub peers: u32,
+    #[prost(uint64, tag = "3")]
+    pub transferred_up: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Peer {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub torrent_id: String,
+    #[prost(string, tag = "3")]
+    pub client_id: String,
+    #[prost(string, optional, tag = "4")]
+    pub client_name: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub client_version: Option<String>,
+    #[prost(string, tag = "6")]
+    pub ip: String,
+    #[prost(uint64, tag = "7")]
+    pub rate_up: u64,
+    #[prost(uint64, tag = "8")]
+    pub rate_down: u64,
+    #[prost(float, tag = "9")]
+    pub availability: f32,
+    #[prost(string, tag = "10")]
+    pub source: String,
+    #[prost(uint64, tag = "11")]
+    pub transferred_up: u64,
+    #[prost(uint64, tag = "12")]
+    pub transferred_down: u64,
+    #[prost(uint64, tag = "13")]
+    pub overhead_up: u64,
+    #[prost(uint64, tag = "14")]
+    pub overhead_down: u64,
+    #[prost(string, optional, tag = "15")]
+    pub country: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct File {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub torrent_id: String,
+    #[prost(string, tag = "3")]
+    pub path: String,
+    #[prost(float, tag = "4")]
+    pub progress: f32,
+    #[prost(float, tag = "5")]
+    pub availability: f32,
+    #[prost(uint32, tag = "6")]
+    pub priority: u32,
+    #[prost(uint64, tag = "7")]
+    pub size: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Piece {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub torrent_id: String,
+    #[prost(bool, tag = "3")]
+    pub available: bool,
+    #[prost(bool, tag = "4")]
+    pub downloaded: bool,
+    #[prost(uint32, tag = "5")]
+    pub index: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Tracker {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub torrent_id: String,
+    #[prost(string, tag = "3")]
+    pub url: String,
+    #[prost(string, tag = "4")]
+    pub last_report: String,
+    #[prost(string, optional, tag = "5")]
+    pub error: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub next_report: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub warning: Option<String>,
+    #[prost(uint32, tag = "8")]
+    pub peers: u32,
+}
+
+impl From<ResourceKind> for rpc::ResourceKind {
+    fn from(kind: ResourceKind) -> rpc::ResourceKind {
+        match kind {
+            ResourceKind::Server => rpc::ResourceKind::Server,
+            ResourceKind::Torrent => rpc::ResourceKind::Torrent,
+            ResourceKind::Peer => rpc::ResourceKind::Peer,
+            ResourceKind::File => rpc::ResourceKind::File,
+            ResourceKind::Piece => rpc::ResourceKind::Piece,
+            ResourceKind::Tracker => rpc::ResourceKind::Tracker,
+        }
+    }
+}
+
+impl<'a> From<&'a rpc::Resource> for Resource {
+    fn from(r: &'a rpc::Resource) -> Resource {
+        let r = match r {
+            rpc::Resource::Server(s) => resource::Resource::Server(Server {
+                id: s.id.clone(),
+                download_token: s.download_token.clone(),
+                rate_up: s.rate_up,
+                rate_down: s.rate_down,
+                throttle_up: s.throttle_up,
+                throttle_down: s.throttle_down,
+                transferred_up: s.transferred_up,
+                transferred_down: s.transferred_down,
+                ses_transferred_up: s.ses_transferred_up,
+                ses_transferred_down: s.ses_transferred_down,
+                ses_overhead_up: s.ses_overhead_up,
+                ses_overhead_down: s.ses_overhead_down,
+                ratio: s.ratio,
+                uptime: s.uptime,
+                free_space: s.free_space,
+                started: s.started.to_rfc3339(),
+                last_snapshot: s.last_snapshot.map(|d| d.to_rfc3339()),
+                filtered_peers: Some(PeerFilterStats {
+                    bogon: s.filtered_peers.bogon,
+                    own: s.filtered_peers.own,
+                    multicast: s.filtered_peers.multicast,
+                    port: s.filtered_peers.port,
+                }),
+                buffers: Some(BufferStats {
+                    blocks_used: s.buffers.blocks_used,
+                    blocks_free: s.buffers.blocks_free,
+                    pieces_used: s.buffers.pieces_used,
+                    pieces_free: s.buffers.pieces_free,
+                    piece_bytes_used: s.buffers.piece_bytes_used,
+                    piece_bytes_free: s.buffers.piece_bytes_free,
+                    alloc_failures: s.buffers.alloc_failures,
+                }),
+                port: u32::from(s.port),
+                network_stopped: s.network_stopped,
+                external_ipv4: s.external_ipv4.clone(),
+                external_ipv6: s.external_ipv6.clone(),
+                disk_jobs: s
+                    .disk_jobs
+                    .iter()
+                    .map(|j| DiskJobStats {
+                        kind: j.kind.clone(),
+                        read: j.read,
+                        written: j.written,
+                        time: j.time,
+                        runs: j.runs,
+                    })
+                    .collect(),
+                dirty_files: s.dirty_files,
+                dns: Some(DnsStats {
+                    hits: s.dns.hits,
+                    misses: s.dns.misses,
+                    cached: s.dns.cached,
+                }),
+                labels: s
+                    .labels
+                    .iter()
+                    .map(|l| Label {
+                        name: l.name.clone(),
+                        path: l.path.clone(),
+                        throttle_up: l.throttle_up,
+                        throttle_down: l.throttle_down,
+                        seed_ratio: l.seed_ratio,
+                        seed_time: l.seed_time,
+                    })
+                    .collect(),
+                throttle_groups: s
+                    .throttle_groups
+                    .iter()
+                    .map(|g| ThrottleGroup {
+                        name: g.name.clone(),
+                        throttle_up: g.throttle_up,
+                        throttle_down: g.throttle_down,
+                    })
+                    .collect(),
+            }),
+            rpc::Resource::Torrent(t) => resource::Resource::Torrent(Torrent {
+                id: t.id.clone(),
+                name: t.name.clone(),
+                creator: t.creator.clone(),
+                comment: t.comment.clone(),
+                notes: t.notes.clone(),
+                private: t.private,
+                path: t.path.clone(),
+                created: t.created.to_rfc3339(),
+                modified: t.modified.to_rfc3339(),
+                status: TorrentStatus::from(t.status) as i32,
+                error: t.error.clone(),
+                error_kind: t.error_kind.map(|k| k.as_str().to_owned()),
+                priority: u32::from(t.priority),
+                progress: t.progress,
+                availability: t.availability,
+                strategy: Strategy::from(t.strategy) as i32,
+                rate_up: t.rate_up,
+                rate_down: t.rate_down,
+                throttle_up: t.throttle_up,
+                throttle_down: t.throttle_down,
+                transferred_up: t.transferred_up,
+                transferred_down: t.transferred_down,
+                overhead_up: t.overhead_up,
+                overhead_down: t.overhead_down,
+                peers: u32::from(t.peers),
+                trackers: u32::from(t.trackers),
+                tracker_urls: t.tracker_urls.clone(),
+                size: t.size,
+                pieces: t.pieces,
+                piece_size: t.piece_size,
+                piece_field: t.piece_field.clone(),
+                piece_availability: t.piece_availability.clone(),
+                files: t.files,
+                label: t.label.clone(),
+                seed_ratio: t.seed_ratio,
+                seed_time: t.seed_time,
+                throttle_group: t.throttle_group.clone(),
+                state: TorrentState::from(t.state) as i32,
+                wanted_size: t.wanted_size,
+                eta: t.eta,
+                first_last: t.first_last,
+                seeders: t.seeders,
+                leechers: t.leechers,
+                completed: t.completed,
+                disk_read: t.disk_read,
+                disk_written: t.disk_written,
+                disk_time: t.disk_time,
+                dht: t.dht,
+                pex: t.pex,
+                last_dht_announce: t.last_dht_announce.map(|d| d.to_rfc3339()),
+                peer_sources: t
+                    .peer_sources
+                    .iter()
+                    .map(|s| PeerSourceStats {
+                        source: s.source.as_str().to_owned(),
+                        peers: u32::from(s.peers),
+                        transferred_up: s.transferred_up,
+                        transferred_down: s.transferred_down,
+                    })
+                    .collect(),
+                countries: t
+                    .countries
+                    .iter()
+                    .map(|c| CountryStats {
+                        country: c.country.clone(),
+                        peers: u32::from(c.peers),
+                        transferred_up: c.transferred_up,
+                    })
+                    .collect(),
+                hash_failures: t
+                    .hash_failures
+                    .iter()
+                    .map(|f| HashFailure {
+                        piece: f.piece,
+                        time: f.time.to_rfc3339(),
+                        blocks: f
+                            .blocks
+                            .iter()
+                            .map(|b| BlockSource {
+                                offset: b.offset,
+                                peer: b.peer.clone(),
+                                ip: b.ip.clone(),
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            }),
+            rpc::Resource::Peer(p) => resource::Resource::Peer(Peer {
+                id: p.id.clone(),
+                torrent_id: p.torrent_id.clone(),
+                client_id: p.client_id.clone(),
+                client_name: p.client_name.clone(),
+                client_version: p.client_version.clone(),
+                ip: p.ip.clone(),
+                country: p.country.clone(),
+                rate_up: p.rate_up,
+                rate_down: p.rate_down,
+                transferred_up: p.transferred_up,
+                transferred_down: p.transferred_down,
+                overhead_up: p.overhead_up,
+                overhead_down: p.overhead_down,
+                availability: p.availability,
+                source: p.source.as_str().to_owned(),
+            }),
+            rpc::Resource::File(f) => resource::Resource::File(File {
+                id: f.id.clone(),
+                torrent_id: f.torrent_id.clone(),
+                path: f.path.clone(),
+                progress: f.progress,
+                availability: f.availability,
+                priority: u32::from(f.priority),
+                size: f.size,
+            }),
+            rpc::Resource::Piece(p) => resource::Resource::Piece(Piece {
+                id: p.id.clone(),
+                torrent_id: p.torrent_id.clone(),
+                available: p.available,
+                downloaded: p.downloaded,
+                index: p.index,
+            }),
+            rpc::Resource::Tracker(t) => resource::Resource::Tracker(Tracker {
+                id: t.id.clone(),
+                torrent_id: t.torrent_id.clone(),
+                url: t.url.to_string(),
+                last_report: t.last_report.to_rfc3339(),
+                error: t.error.clone(),
+                next_report: t.next_report.map(|d| d.to_rfc3339()),
+                warning: t.warning.clone(),
+                peers: t.peers,
+            }),
+        };
+        Resource { resource: Some(r) }
+    }
+}
+
+impl From<rpc::Status> for TorrentStatus {
+    fn from(status: rpc::Status) -> TorrentStatus {
+        match status {
+            rpc::Status::Pending => TorrentStatus::Pending,
+            rpc::Status::Magnet => TorrentStatus::Magnet,
+            rpc::Status::Paused => TorrentStatus::Paused,
+            rpc::Status::Leeching => TorrentStatus::Leeching,
+            rpc::Status::Idle => TorrentStatus::Idle,
+            rpc::Status::Seeding => TorrentStatus::Seeding,
+            rpc::Status::Hashing => TorrentStatus::Hashing,
+            rpc::Status::Error => TorrentStatus::Error,
+            rpc::Status::Stalled => TorrentStatus::Stalled,
+        }
+    }
+}
+
+impl From<rpc::TorrentState> for TorrentState {
+    fn from(state: rpc::TorrentState) -> TorrentState {
+        match state {
+            rpc::TorrentState::CheckingQueued => TorrentState::CheckingQueued,
+            rpc::TorrentState::Checking => TorrentState::Checking,
+            rpc::TorrentState::DownloadingMetadata => TorrentState::DownloadingMetadata,
+            rpc::TorrentState::Downloading => TorrentState::Downloading,
+            rpc::TorrentState::Seeding => TorrentState::Seeding,
+            rpc::TorrentState::Paused => TorrentState::Paused,
+            rpc::TorrentState::Queued => TorrentState::Queued,
+            rpc::TorrentState::Error => TorrentState::Error,
+            rpc::TorrentState::Moving => TorrentState::Moving,
+        }
+    }
+}
+
+impl From<rpc::Strategy> for Strategy {
+    fn from(strategy: rpc::Strategy) -> Strategy {
+        match strategy {
+            rpc::Strategy::Rarest => Strategy::Rarest,
+            rpc::Strategy::Sequential => Strategy::Sequential,
+            rpc::Strategy::RandomFirst => Strategy::RandomFirst,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    /// Type, label and tag of a field or enum value, keyed by the name of
+    /// the message or enum and its own name.
+    type Fields = BTreeMap<(String, String), (String, String, String)>;
+
+    fn snake(name: &str) -> String {
+        let mut s = String::new();
+        for (i, c) in name.chars().enumerate() {
+            if c.is_ascii_uppercase() && i > 0 {
+                s.push('_');
+            }
+            s.push(c.to_ascii_lowercase());
+        }
+        s
+    }
+
+    fn camel(name: &str) -> String {
+        name.split('_')
+            .map(|w| {
+                let mut c = w.chars();
+                c.next()
+                    .map(|f| f.to_ascii_uppercase().to_string() + c.as_str())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Reads the fields described by the prost attributes of this file.
+    fn rust_fields() -> Fields {
+        let src = include_str!("proto.rs");
+        let src = &src[..src.find("#[cfg(test)]").unwrap()];
+        let mut fields = Fields::new();
+        let mut module = None;
+        let mut ty = None;
+        let mut enumeration = false;
+        let mut attr: Option<&str> = None;
+        for line in src.lines() {
+            let l = line.trim();
+            if line == "}" {
+                module = None;
+                ty = None;
+            } else if let Some(m) = l.strip_prefix("pub mod ") {
+                module = Some(camel(m.trim_end_matches(" {")));
+            } else if l.starts_with("#[derive") {
+                enumeration = l.contains("prost::Enumeration");
+            } else if l.starts_with("pub struct ") || l.starts_with("pub enum ") {
+                let name = l.split_whitespace().nth(2).unwrap();
+                ty = Some(module.clone().unwrap_or_else(|| name.to_owned()));
+            } else if l.starts_with("impl") {
+                ty = None;
+            } else if l.starts_with("#[prost(") && !l.contains("oneof") {
+                attr = Some(l);
+            } else if let (Some(ty), Some(a)) = (ty.as_ref(), attr.take()) {
+                let name = l.trim_start_matches("pub ");
+                let name = name.split([':', '(']).next().unwrap();
+                let a = &a["#[prost(".len()..a.len() - 2];
+                let parts: Vec<_> = a.split(", ").collect();
+                let kind = parts[0].split(' ').next().unwrap().to_owned();
+                let label = match parts[1] {
+                    "optional" | "repeated" => parts[1].to_owned(),
+                    _ if kind == "message" => "optional".to_owned(),
+                    _ => String::new(),
+                };
+                let tag = parts.last().unwrap();
+                let tag = tag["tag = \"".len()..tag.len() - 1].to_owned();
+                fields.insert((ty.clone(), snake(name)), (kind, label, tag));
+            } else if let (Some(ty), true) = (ty.as_ref(), enumeration) {
+                if let Some((name, tag)) = l.trim_end_matches(',').split_once(" = ") {
+                    let v = ("value".to_owned(), String::new(), tag.to_owned());
+                    fields.insert((ty.clone(), snake(name)), v);
+                }
+            }
+        }
+        fields
+    }
+
+    /// Reads the fields of the messages and enums of proto/synapse.proto.
+    fn proto_fields() -> Fields {
+        let src = include_str!("../../../proto/synapse.proto");
+        let lines: Vec<_> = src
+            .lines()
+            .map(|l| l.split("//").next().unwrap().trim())
+            .filter(|l| !l.is_empty())
+            .collect();
+        let types = |kw: &str| -> Vec<String> {
+            lines
+                .iter()
+                .filter_map(|l| l.strip_prefix(kw))
+                .map(|l| l.split_whitespace().next().unwrap().to_owned())
+                .collect()
+        };
+        let (messages, enums) = (types("message "), types("enum "));
+        let mut fields = Fields::new();
+        let mut ty: Option<(String, bool)> = None;
+        for l in lines {
+            if let Some(m) = l.strip_prefix("message ") {
+                let name = m.split_whitespace().next().unwrap().to_owned();
+                ty = Some((name, false)).filter(|_| !l.ends_with("{}"));
+            } else if let Some(e) = l.strip_prefix("enum ") {
+                ty = Some((e.trim_end_matches(" {").to_owned(), true));
+            } else if let (Some((name, true)), Some((value, tag))) = (&ty, l.split_once(" = ")) {
+                let prefix = snake(name).to_uppercase() + "_";
+                let value = value.trim_start_matches(&prefix).to_lowercase();
+                let tag = tag.trim_end_matches(';').to_owned();
+                fields.insert(
+                    (name.clone(), value),
+                    ("value".to_owned(), String::new(), tag),
+                );
+            } else if let (Some((name, false)), Some((decl, tag))) = (&ty, l.split_once(" = ")) {
+                let parts: Vec<_> = decl.split_whitespace().collect();
+                let (label, kind, field) = match parts[..] {
+                    [label, kind, field] => (label.to_owned(), kind, field),
+                    [kind, field] => (String::new(), kind, field),
+                    _ => panic!("Unexpected field {}", l),
+                };
+                let (kind, label) = if messages.iter().any(|m| m == kind) {
+                    let label = if label.is_empty() {
+                        "optional".to_owned()
+                    } else {
+                        label
+                    };
+                    ("message".to_owned(), label)
+                } else if enums.iter().any(|e| e == kind) {
+                    ("enumeration".to_owned(), label)
+                } else {
+                    (kind.to_owned(), label)
+                };
+                let tag = tag.trim_end_matches(';').to_owned();
+                fields.insert((name.clone(), field.to_owned()), (kind, label, tag));
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn test_matches_proto() {
+        let rust = rust_fields();
+        let proto = proto_fields();
+        assert!(proto.len() > 100);
+        for (field, desc) in &proto {
+            assert_eq!(rust.get(field), Some(desc), "{:?} differs", field);
+        }
+        for field in rust.keys() {
+            assert!(proto.contains_key(field), "{:?} is not in the proto", field);
+        }
+    }
+}
@@ -1,33 +1,49 @@
+mod api;
+mod auth;
 mod client;
 mod errors;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod notify;
 mod processor;
 pub mod proto;
+mod qbittorrent;
 mod reader;
+mod tls;
 mod transfer;
+mod transmission;
 mod writer;
 
 use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
 use std::sync::Arc;
-use std::{fs, io, result, str, thread};
+use std::{fs, io, result, str, thread, time};
+
+use chrono::{DateTime, Utc};
 
 use http_range::HttpRange;
 use rustls;
 use sstream::SStream;
 use url::Url;
 
-use self::client::{Client, Incoming, IncomingStatus};
+use self::api::{ApiEndpoint, ApiRequest};
+use self::client::{Client, DlRequest, Incoming, IncomingStatus};
 pub use self::errors::{Error, ErrorKind, Result, ResultExt};
 use self::processor::{Processor, TransferKind};
 use self::proto::message::{self, SMessage};
 pub use self::proto::resource;
 use self::proto::ws;
+use self::qbittorrent::QBittorrent;
 use self::transfer::{TransferResult, Transfers};
-use crate::bencode;
+use self::transmission::Transmission;
 use crate::disk;
 use crate::handle;
+use crate::instance;
 use crate::torrent;
-use crate::util::UHashMap;
+use crate::tracker;
+use crate::util::{http, systemd, UHashMap};
 use crate::CONFIG;
 
 const POLL_INT_MS: usize = 1000;
@@ -39,7 +55,10 @@ lazy_static! {
             format!("HTTP/1.1 {} {}", 200, "OK"),
             format!("Connection: {}", "Close"),
             format!("Access-Control-Allow-Origin: {}", "*"),
-            format!("Access-Control-Allow-Methods: {}", "OPTIONS, POST, GET"),
+            format!(
+                "Access-Control-Allow-Methods: {}",
+                "OPTIONS, POST, GET, PATCH"
+            ),
             format!("Accept-Ranges: {}", "bytes"),
             format!(
                 "Access-Control-Allow-Headers: {}, {}, {}, {}, {}, {}, {}, {}",
@@ -90,6 +109,13 @@ pub enum CtlMessage {
         client: usize,
         serial: u64,
     },
+    /// Trackers were merged into a torrent which already existed
+    Merged {
+        id: String,
+        trackers: Vec<String>,
+        client: usize,
+        serial: u64,
+    },
     Error {
         reason: String,
         client: usize,
@@ -100,10 +126,79 @@ pub enum CtlMessage {
         client: usize,
         serial: u64,
     },
+    DhtItem {
+        client: usize,
+        serial: u64,
+        target: [u8; 20],
+        item: Option<tracker::Item>,
+    },
+    History {
+        client: usize,
+        serial: u64,
+        resolution: message::Resolution,
+        samples: Vec<message::Sample>,
+    },
+    Setting {
+        client: usize,
+        serial: u64,
+        key: String,
+        value: serde_json::Value,
+    },
+    ConfigReloaded {
+        client: usize,
+        serial: u64,
+        changed: Vec<String>,
+    },
+    PortChanged {
+        client: usize,
+        serial: u64,
+        port: u16,
+    },
+    DirectoryMoving {
+        client: usize,
+        serial: u64,
+        directory: message::Directory,
+        progress: f32,
+    },
+    DirectoryMoved {
+        client: usize,
+        serial: u64,
+        directory: message::Directory,
+        path: String,
+    },
+    /// An error stopped a torrent, to be sent to every client
+    Alert {
+        id: String,
+        error_kind: resource::ErrorKind,
+        error: String,
+        retry: Option<DateTime<Utc>>,
+    },
+    /// Something to be recorded in the event log
+    Event {
+        kind: message::EventKind,
+        id: Option<String>,
+        message: String,
+    },
     Ping,
     Shutdown,
 }
 
+/// Options a torrent is added with.
+#[derive(Debug, Clone, Default)]
+pub struct AddOptions {
+    pub path: Option<String>,
+    pub start: bool,
+    pub import: bool,
+    pub seed_only: bool,
+    pub label: Option<String>,
+    pub name: Option<String>,
+    pub priorities: Vec<u8>,
+    /// Whether the trackers of a torrent already added are merged into it
+    pub merge: bool,
+    /// Whether a magnet stops once its metadata is downloaded
+    pub metadata_only: bool,
+}
+
 #[derive(Debug)]
 pub enum Message {
     UpdateTorrent(resource::CResourceUpdate),
@@ -125,7 +220,11 @@ pub enum Message {
     },
     Pause(String),
     Resume(String),
+    /// Stops or starts all network activity
+    StopNetwork(bool),
     Validate(Vec<String>),
+    /// Messages resulting from a single bulk request
+    Bulk(Vec<Message>),
     AddPeer {
         id: String,
         client: usize,
@@ -148,6 +247,23 @@ pub enum Message {
         id: String,
         torrent_id: String,
     },
+    Scrape {
+        id: String,
+    },
+    Reannounce(String),
+    RefreshDht(String),
+    SetPieceDeadline {
+        id: String,
+        offset: u64,
+        deadline: time::Duration,
+    },
+    CrossSeed {
+        id: String,
+    },
+    FindData {
+        id: String,
+        path: String,
+    },
     RemoveTracker {
         id: String,
         torrent_id: String,
@@ -155,12 +271,64 @@ pub enum Message {
         serial: u64,
     },
     Torrent {
-        info: torrent::Info,
+        info: Box<torrent::Info>,
+        client: usize,
+        serial: u64,
+        options: AddOptions,
+    },
+    SetLabel(resource::Label),
+    RemoveLabel(String),
+    SetThrottleGroup(resource::ThrottleGroup),
+    RemoveThrottleGroup(String),
+    GetDhtItem {
+        client: usize,
+        serial: u64,
+        target: [u8; 20],
+    },
+    PutDhtItem {
         client: usize,
         serial: u64,
-        path: Option<String>,
-        start: bool,
-        import: bool,
+        value: Vec<u8>,
+        mutable: bool,
+        salt: Vec<u8>,
+        seq: Option<i64>,
+    },
+    GetHistory {
+        client: usize,
+        serial: u64,
+        resolution: message::Resolution,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    },
+    GetSetting {
+        client: usize,
+        serial: u64,
+        key: String,
+    },
+    SetSetting {
+        client: usize,
+        serial: u64,
+        key: String,
+        value: serde_json::Value,
+    },
+    ReloadConfig {
+        client: usize,
+        serial: u64,
+    },
+    SetPort {
+        client: usize,
+        serial: u64,
+        port: u16,
+    },
+    MoveDirectory {
+        client: usize,
+        serial: u64,
+        directory: message::Directory,
+        path: String,
+    },
+    CancelJobs {
+        kind: resource::ResourceKind,
+        id: String,
     },
     PurgeDNS,
 }
@@ -169,46 +337,23 @@ pub struct RPC {
     poll: amy::Poller,
     reg: amy::Registrar,
     ch: handle::Handle<CtlMessage, Message>,
-    listener: TcpListener,
+    listener: Option<TcpListener>,
+    unix: Option<UnixListener>,
     config: Option<Arc<rustls::ServerConfig>>,
-    lid: usize,
+    lid: Option<usize>,
+    uid: Option<usize>,
     cleanup: usize,
     processor: Processor,
+    transmission: Transmission,
+    qbittorrent: QBittorrent,
+    #[cfg(feature = "grpc")]
+    grpc: Option<grpc::Bridge>,
     transfers: Transfers,
     clients: UHashMap<Client>,
     incoming: UHashMap<Incoming>,
     disk: amy::Sender<disk::Request>,
-}
-
-fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
-    let certfile = fs::File::open(filename)?;
-    let mut reader = io::BufReader::new(certfile);
-    Ok(rustls::internal::pemfile::certs(&mut reader).expect("Invalid cert file"))
-}
-
-fn load_private_key(filename: &str) -> io::Result<rustls::PrivateKey> {
-    let rsa_keys = {
-        let keyfile = fs::File::open(filename)?;
-        let mut reader = io::BufReader::new(keyfile);
-        rustls::internal::pemfile::rsa_private_keys(&mut reader).expect("Invalid private key")
-    };
-
-    let pkcs8_keys = {
-        let keyfile = fs::File::open(filename)?;
-        let mut reader = io::BufReader::new(keyfile);
-        rustls::internal::pemfile::pkcs8_private_keys(&mut reader).expect("Invalid private key")
-    };
-
-    // prefer to load pkcs8 keys
-    if !pkcs8_keys.is_empty() {
-        Ok(pkcs8_keys[0].clone())
-    } else {
-        assert!(
-            !rsa_keys.is_empty(),
-            "SSL private key must be non empty and decrypted!"
-        );
-        Ok(rsa_keys[0].clone())
-    }
+    dl_tx: amy::Sender<disk::DlConn>,
+    dl_rx: amy::Receiver<disk::DlConn>,
 }
 
 impl RPC {
@@ -221,34 +366,61 @@ impl RPC {
         let cleanup = reg.set_interval(CLEANUP_INT_MS)?;
         let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
 
-        let ip = if CONFIG.rpc.local {
-            Ipv4Addr::new(127, 0, 0, 1)
-        } else {
-            Ipv4Addr::new(0, 0, 0, 0)
+        let ip = match CONFIG.rpc.listen {
+            Some(ip) => ip,
+            None if CONFIG.rpc.local => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            None => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
         };
         let port = CONFIG.rpc.port;
-        let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
-        listener.set_nonblocking(true)?;
-        let lid = reg.register(&listener, amy::Event::Both)?;
-
-        let disk = db.clone();
+        // Sockets passed by systemd are used in place of the configured ones
+        let listener = match systemd::take_tcp("rpc")? {
+            Some(listener) => Some(listener),
+            None if CONFIG.rpc.tcp => {
+                let listener = TcpListener::bind(SocketAddr::new(ip, port))?;
+                listener.set_nonblocking(true)?;
+                Some(listener)
+            }
+            None => None,
+        };
+        let lid = match listener {
+            Some(ref l) => Some(reg.register(l, amy::Event::Both)?),
+            None => None,
+        };
+        let unix = match (systemd::take_unix("rpc")?, &CONFIG.rpc.socket) {
+            (Some(listener), _) => Some(listener),
+            (None, Some(path)) => Some(bind_unix(path)?),
+            (None, None) => None,
+        };
+        let uid = match unix {
+            Some(ref l) => Some(reg.register(l, amy::Event::Both)?),
+            None => None,
+        };
+        if listener.is_none() && unix.is_none() {
+            error!("RPC is not listening on TCP or a unix socket, and will be unreachable!");
+        }
+        let config = tls::server_config()?;
+        instance::set_rpc(endpoints(&listener, &unix, config.is_some())?);
+        let (dl_tx, dl_rx) = reg.channel()?;
 
-        let config = match (CONFIG.rpc.ssl_cert.as_str(), CONFIG.rpc.ssl_key.as_str()) {
-            ("", "") => {
-                info!("RPC SSL parameters not specified, using insecure connections!");
+        #[cfg(feature = "grpc")]
+        let grpc = match CONFIG.rpc.grpc_port {
+            // The gRPC server can't use the RPC certificate, and would
+            // otherwise expose credentials over plaintext
+            Some(_) if config.is_some() => {
+                error!("gRPC does not support TLS, not serving it since RPC TLS is enabled!");
                 None
             }
-            (cert_file, key_file) => {
-                let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-                let certs = load_certs(cert_file)?;
-                let key = load_private_key(key_file)?;
-                config
-                    .set_single_cert(certs, key)
-                    .expect("Invalid ssl_cert and ssl_key");
-                info!("SSL initialized!");
-                Some(Arc::new(config))
-            }
+            Some(port) => Some(grpc::Bridge::start(&mut reg, SocketAddr::new(ip, port))?),
+            None => None,
         };
+        #[cfg(not(feature = "grpc"))]
+        {
+            if CONFIG.rpc.grpc_port.is_some() {
+                error!("Synapse was built without gRPC support, ignoring grpc_port!");
+            }
+        }
+
+        let disk = db.clone();
 
         let th = dh.run("rpc", move |ch| {
             RPC {
@@ -257,13 +429,21 @@ impl RPC {
                 poll,
                 reg,
                 listener,
+                unix,
                 lid,
+                uid,
                 cleanup,
                 clients: UHashMap::default(),
                 incoming: UHashMap::default(),
                 processor: Processor::new(db),
+                transmission: Transmission::new(),
+                qbittorrent: QBittorrent::new(),
+                #[cfg(feature = "grpc")]
+                grpc,
                 transfers: Transfers::new(),
                 config,
+                dl_tx,
+                dl_rx,
             }
             .run()
         })?;
@@ -282,14 +462,23 @@ impl RPC {
             };
             for not in res {
                 match not.id {
-                    id if id == self.lid => self.handle_accept(),
+                    id if Some(id) == self.lid => self.handle_accept(),
+                    id if Some(id) == self.uid => self.handle_unix_accept(),
                     id if id == self.ch.rx.get_id() => {
                         if self.handle_ctl() {
+                            if let (Some(_), Some(path)) = (&self.unix, &CONFIG.rpc.socket) {
+                                fs::remove_file(path).ok();
+                            }
                             return;
                         }
                     }
                     id if self.incoming.contains_key(&id) => self.handle_incoming(id),
                     id if id == self.cleanup => self.cleanup(),
+                    id if id == self.dl_rx.get_id() => self.handle_dl_conns(),
+                    #[cfg(feature = "grpc")]
+                    id if self.grpc.as_ref().map(grpc::Bridge::id) == Some(id) => {
+                        self.handle_grpc()
+                    }
                     id if self.transfers.contains(id) => self.handle_transfer(id),
                     _ => self.handle_conn(not),
                 }
@@ -301,7 +490,10 @@ impl RPC {
         while let Ok(m) = self.ch.recv() {
             match m {
                 CtlMessage::Ping => continue,
-                CtlMessage::Shutdown => return true,
+                CtlMessage::Shutdown => {
+                    self.processor.flush_events();
+                    return true;
+                }
                 m => {
                     let msgs: Vec<_> = {
                         self.processor
@@ -313,6 +505,10 @@ impl RPC {
                     for (c, m) in msgs {
                         let res = match self.clients.get_mut(&c) {
                             Some(client) => client.send(ws::Frame::Text(m)),
+                            #[cfg(feature = "grpc")]
+                            None if self.grpc.as_mut().is_some_and(|g| g.send(c, m.clone())) => {
+                                Ok(())
+                            }
                             None => {
                                 debug!("Processor referenced a nonexistent client!");
                                 Ok(())
@@ -329,69 +525,58 @@ impl RPC {
         false
     }
 
+    #[cfg(feature = "grpc")]
+    fn handle_grpc(&mut self) {
+        let msgs = match self.grpc {
+            Some(ref mut grpc) => grpc.handle(&mut self.processor),
+            None => return,
+        };
+        for msg in msgs {
+            if self.ch.send(msg).is_err() {
+                error!("Failed to pass message to ctrl!");
+            }
+        }
+    }
+
     fn handle_transfer(&mut self, id: usize) {
         match self.transfers.ready(id) {
             TransferResult::Incomplete => {}
             TransferResult::Torrent {
                 conn,
                 data,
-                path,
                 client,
                 serial,
-                start,
-                import,
+                mut options,
             } => {
                 debug!("Got torrent via HTTP transfer!");
                 if self.reg.deregister(&conn).is_err() {
                     error!("Poll IO failure, dropping HTTP transfer!");
                     return;
                 }
-                match bencode::decode_buf(&data) {
-                    Ok(b) => match torrent::info::Info::from_bencode(b) {
-                        Ok(i) => {
-                            if self
-                                .ch
-                                .send(Message::Torrent {
-                                    info: i,
-                                    path,
-                                    start,
-                                    import,
-                                    client,
-                                    serial,
-                                })
-                                .is_err()
-                            {
-                                error!("Failed to pass message to ctrl!");
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to parse torrent data: {}!", e);
-                            self.clients.get_mut(&client).map(|c| {
-                                c.send(ws::Frame::Text(
-                                    serde_json::to_string(&SMessage::TransferFailed(
-                                        message::Error {
-                                            serial: Some(serial),
-                                            reason: format!(
-                                                "Invalid torrent file uploaded, {}.",
-                                                e
-                                            ),
-                                        },
-                                    ))
-                                    .unwrap(),
-                                ))
-                            });
+                match processor::parse_torrent(&data) {
+                    Ok(i) => {
+                        options.merge &= self.processor.may_merge(client, &i.hash);
+                        self.processor.claim(client, &i.hash);
+                        if self
+                            .ch
+                            .send(Message::Torrent {
+                                info: Box::new(i),
+                                options,
+                                client,
+                                serial,
+                            })
+                            .is_err()
+                        {
+                            error!("Failed to pass message to ctrl!");
                         }
-                    },
-                    Err(e) => {
-                        error!("Failed to decode BE data: {}!", e);
+                    }
+                    Err(reason) => {
+                        error!("Failed to parse torrent data: {}", reason);
                         self.clients.get_mut(&client).map(|c| {
                             c.send(ws::Frame::Text(
                                 serde_json::to_string(&SMessage::TransferFailed(message::Error {
                                     serial: Some(serial),
-                                    reason: format!(
-                                        "Invalid torrent file uploaded, bad bencoded data: {}.",
-                                        e
-                                    ),
+                                    reason,
                                 }))
                                 .unwrap(),
                             ))
@@ -399,6 +584,9 @@ impl RPC {
                     }
                 }
             }
+            TransferResult::Api { conn, req } => {
+                self.handle_api(id, conn, req, Vec::new());
+            }
             TransferResult::Error {
                 err, client: id, ..
             } => {
@@ -420,8 +608,12 @@ impl RPC {
     }
 
     fn handle_accept(&mut self) {
+        let listener = match self.listener {
+            Some(ref listener) => listener,
+            None => return,
+        };
         loop {
-            match self.listener.accept() {
+            match listener.accept() {
                 Ok((conn, ip)) => {
                     debug!("Accepted new connection from {:?}!", ip);
                     let id = self.reg.register(&conn, amy::Event::Both);
@@ -444,11 +636,36 @@ impl RPC {
         }
     }
 
+    fn handle_unix_accept(&mut self) {
+        let listener = match self.unix {
+            Some(ref listener) => listener,
+            None => return,
+        };
+        loop {
+            match listener.accept() {
+                Ok((conn, _)) => {
+                    debug!("Accepted new unix socket connection!");
+                    let id = self.reg.register(&conn, amy::Event::Both);
+                    if let (Ok(id), Ok(conn)) = (id, SStream::from_unix(conn)) {
+                        self.incoming.insert(id, Incoming::new(conn));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to accept unix socket conn: {}", e);
+                }
+            }
+        }
+    }
+
     fn handle_incoming(&mut self, id: usize) {
         if let Some(mut i) = self.incoming.remove(&id) {
             match i.readable() {
-                Ok(IncomingStatus::Upgrade) => {
+                Ok(IncomingStatus::Upgrade(access)) => {
                     debug!("Succesfully upgraded conn");
+                    self.processor.add_client(id, access);
                     self.clients.insert(id, i.into());
                 }
                 Ok(IncomingStatus::Incomplete) => {
@@ -457,28 +674,10 @@ impl RPC {
                 Ok(IncomingStatus::Transfer { data, token }) => {
                     debug!("File transfer requested, validating");
                     match self.processor.get_transfer(token) {
-                        Some((
-                            client,
-                            serial,
-                            TransferKind::UploadTorrent {
-                                path,
-                                size,
-                                start,
-                                import,
-                            },
-                        )) => {
+                        Some((client, serial, kind @ TransferKind::UploadTorrent { .. })) => {
                             debug!("Torrent transfer initiated");
-                            self.transfers.add_torrent(
-                                id,
-                                client,
-                                serial,
-                                i.into(),
-                                data,
-                                path,
-                                size,
-                                start,
-                                import,
-                            );
+                            self.transfers
+                                .add_torrent(id, client, serial, i.into(), data, kind);
                             // Since a succesful result means the buffer hasn't been flushed,
                             // immediatly attempt to handle the transfer as if it was ready
                             self.handle_transfer(id);
@@ -492,31 +691,20 @@ impl RPC {
                         }
                     }
                 }
-                Ok(IncomingStatus::DL { id, range }) => {
-                    debug!("Attempting DL of {}", id);
-                    let mut conn: SStream = i.into();
-                    if let Some((path, size)) = self.processor.get_dl(&id) {
-                        if size == 0 {
-                            conn.write(&EMPTY_HTTP_RESP).ok();
-                            return;
-                        }
-
-                        let ranges = match range.map(|r| HttpRange::parse(&r, size)) {
-                            Some(Ok(parsed_ranges)) => parsed_ranges,
-                            Some(Err(_)) => {
-                                debug!("Ranges {} invalid, stopping DL", id);
-                                conn.write(&BAD_HTTP_RANGE).ok();
-                                return;
-                            }
-                            None => vec![],
-                        };
-                        debug!("Initiating DL");
-                        self.disk
-                            .send(disk::Request::download(conn, ranges, path, size))
-                            .ok();
+                Ok(IncomingStatus::DL(dl)) => {
+                    debug!("Attempting DL of {}", dl.id);
+                    self.handle_dl(id, i.into(), dl);
+                }
+                Ok(IncomingStatus::Api { mut req, data }) => {
+                    debug!("Handling API request");
+                    if data.len() >= req.size {
+                        req.body = data;
+                        let pipelined = req.body.split_off(req.size);
+                        self.handle_api(id, i.into(), req, pipelined);
                     } else {
-                        debug!("ID {} invalid, stopping DL", id);
-                        conn.write(&EMPTY_HTTP_RESP).ok();
+                        self.transfers.add_api(id, i.into(), req, data);
+                        // The rest of the body may have already arrived
+                        self.handle_transfer(id);
                     }
                 }
                 Err(e) => {
@@ -526,6 +714,171 @@ impl RPC {
         }
     }
 
+    fn handle_api(&mut self, id: usize, conn: SStream, req: ApiRequest, pipelined: Vec<u8>) {
+        let keep_alive = req.keep_alive;
+        let (resp, msg) = match req.endpoint {
+            ApiEndpoint::Transmission { .. } => {
+                self.transmission.handle(&mut self.processor, id, req)
+            }
+            ApiEndpoint::QBittorrent { .. } => {
+                self.qbittorrent.handle(&mut self.processor, id, req)
+            }
+            ApiEndpoint::Resources { .. } => api::handle(&mut self.processor, id, req),
+        };
+        if let Some(msg) = msg {
+            if self.ch.send(msg).is_err() {
+                error!("Failed to pass message to ctrl!");
+            }
+        }
+        let keep_alive = if keep_alive {
+            Some((id, pipelined, self.dl_tx.clone()))
+        } else {
+            None
+        };
+        self.disk
+            .send(disk::Request::respond(
+                conn,
+                resp.into_bytes(keep_alive.is_some()),
+                keep_alive,
+            ))
+            .ok();
+    }
+
+    fn handle_dl(&mut self, id: usize, mut conn: SStream, dl: DlRequest) {
+        if let Some(ref format) = dl.format {
+            match disk::ArchiveKind::from_name(format) {
+                Some(kind) => self.handle_archive(id, conn, &dl, kind),
+                None => {
+                    debug!("Archive format {} invalid, stopping DL", format);
+                    conn.write_all(&EMPTY_HTTP_RESP).ok();
+                }
+            }
+            return;
+        }
+        let (path, size) = match self.processor.get_dl(&dl.access, &dl.id) {
+            Some(f) => f,
+            None => {
+                debug!("ID {} invalid, stopping DL", dl.id);
+                conn.write_all(&EMPTY_HTTP_RESP).ok();
+                return;
+            }
+        };
+        if size == 0 {
+            conn.write_all(&EMPTY_HTTP_RESP).ok();
+            return;
+        }
+
+        let mtime = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(DateTime::<Utc>::from);
+        let etag = format!(
+            "\"{:x}-{:x}\"",
+            size,
+            mtime.map(|t| t.timestamp_millis()).unwrap_or(0)
+        );
+        let last_modified = mtime.map(http::format_date);
+
+        if dl.not_modified(&etag, mtime) {
+            let mut lines = vec![
+                format!("HTTP/1.1 {} {}", 304, "Not Modified"),
+                format!("ETag: {}", etag),
+            ];
+            if let Some(ref date) = last_modified {
+                lines.push(format!("Last-Modified: {}", date));
+            }
+            let connection = if dl.keep_alive { "keep-alive" } else { "Close" };
+            lines.push(format!("Connection: {}", connection));
+            lines.push("\r\n".to_string());
+            if conn.write_all(lines.join("\r\n").as_bytes()).is_ok() && dl.keep_alive {
+                self.incoming
+                    .insert(id, Incoming::with_pipelined(conn, &dl.pipelined));
+                self.handle_incoming(id);
+            }
+            return;
+        }
+
+        let ranges = match dl.range(&etag, mtime).map(|r| HttpRange::parse(r, size)) {
+            Some(Ok(parsed_ranges)) => parsed_ranges,
+            Some(Err(_)) => {
+                debug!("Ranges for {} invalid, stopping DL", dl.id);
+                conn.write_all(&BAD_HTTP_RANGE).ok();
+                return;
+            }
+            None => vec![],
+        };
+        let params = disk::DlParams {
+            head: dl.head,
+            rate: dl.rate,
+            etag,
+            last_modified,
+            id: dl.id.clone(),
+            keep_alive: if dl.keep_alive {
+                Some((id, dl.pipelined.clone(), self.dl_tx.clone()))
+            } else {
+                None
+            },
+        };
+        debug!("Initiating DL");
+        self.disk
+            .send(disk::Request::download(conn, ranges, path, size, params))
+            .ok();
+    }
+
+    fn handle_archive(
+        &mut self,
+        id: usize,
+        mut conn: SStream,
+        dl: &DlRequest,
+        kind: disk::ArchiveKind,
+    ) {
+        let (name, archive) = match self.processor.get_archive(&dl.access, &dl.id, kind) {
+            Some(t) => t,
+            None => {
+                debug!("ID {} invalid, stopping archive DL", dl.id);
+                conn.write_all(&EMPTY_HTTP_RESP).ok();
+                return;
+            }
+        };
+        let mtime = archive.modified().map(DateTime::<Utc>::from);
+        let etag = format!(
+            "\"{}-{:x}-{:x}\"",
+            kind.extension(),
+            archive.len(),
+            mtime.map(|t| t.timestamp_millis()).unwrap_or(0)
+        );
+        let params = disk::DlParams {
+            head: dl.head,
+            rate: dl.rate,
+            etag,
+            last_modified: mtime.map(http::format_date),
+            id: dl.id.clone(),
+            keep_alive: if dl.keep_alive {
+                Some((id, dl.pipelined.clone(), self.dl_tx.clone()))
+            } else {
+                None
+            },
+        };
+        debug!("Initiating archive DL");
+        self.disk
+            .send(disk::Request::archive(conn, archive, &name, params))
+            .ok();
+    }
+
+    /// Resumes reading requests from kept alive download connections.
+    fn handle_dl_conns(&mut self) {
+        while let Ok(dl) = self.dl_rx.try_recv() {
+            if dl.conn.set_nonblocking(true).is_err() || dl.conn.set_write_timeout(None).is_err() {
+                continue;
+            }
+            self.incoming
+                .insert(dl.id, Incoming::with_pipelined(dl.conn, &dl.pipelined));
+            // Any request sent while the disk thread owned the connection
+            // will not trigger another notification, so check immediately.
+            self.handle_incoming(dl.id);
+        }
+    }
+
     fn handle_conn(&mut self, not: amy::Notification) {
         if let Some(mut c) = self.clients.remove(&not.id) {
             if not.event.readable() {
@@ -614,6 +967,8 @@ impl RPC {
 
     fn cleanup(&mut self) {
         self.processor.remove_expired_tokens();
+        self.processor.remove_expired_events();
+        self.processor.flush_events();
         let processor = &mut self.processor;
         self.clients.retain(|id, client| {
             let res = client.timed_out();
@@ -637,3 +992,45 @@ impl RPC {
         self.processor.remove_client(id);
     }
 }
+
+/// URLs clients can reach the RPC at through its listeners.
+fn endpoints(
+    listener: &Option<TcpListener>,
+    unix: &Option<UnixListener>,
+    tls: bool,
+) -> io::Result<Vec<String>> {
+    let mut urls = Vec::new();
+    if let Some(listener) = listener {
+        let mut addr = listener.local_addr()?;
+        match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+            _ => {}
+        }
+        urls.push(format!("{}://{}", if tls { "wss" } else { "ws" }, addr));
+    }
+    if let Some(unix) = unix {
+        if let Some(path) = unix.local_addr()?.as_pathname() {
+            let path = fs::canonicalize(path)?;
+            urls.push(format!("unix://{}", path.display()));
+        }
+    }
+    Ok(urls)
+}
+
+/// Binds the RPC unix socket, replacing any left over by a previous run.
+fn bind_unix(path: &str) -> io::Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(CONFIG.rpc.socket_mode))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
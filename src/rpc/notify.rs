@@ -0,0 +1,318 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::{cmp, mem, thread};
+
+use serde_json as json;
+use sstream::SStream;
+use url::Url;
+
+use super::proto::message::Event;
+use crate::config::{EmailConfig, WebhookConfig};
+use crate::tracker::dns;
+use crate::util::http;
+use crate::CONFIG;
+
+const TIMEOUT_SECS: u64 = 10;
+/// Delay before retrying a failed notification, doubling with each
+/// attempt up to an hour
+const RETRY_BASE_SECS: u64 = 10;
+const RETRY_MAX_SECS: u64 = 60 * 60;
+const MAX_RESPONSE_LEN: usize = 16 * 1024;
+
+/// Delivers events to the webhooks and email addresses in the config
+/// from its own thread, so slow endpoints don't hold up the RPC.
+pub struct Notifier {
+    tx: mpsc::Sender<Event>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    Webhook(usize),
+    Email(usize),
+}
+
+struct Delivery {
+    target: Target,
+    event: Event,
+    attempts: u32,
+    at: Instant,
+}
+
+impl Notifier {
+    /// Starts the notifier, unless there's nothing to notify.
+    pub fn start() -> io::Result<Option<Notifier>> {
+        if CONFIG.notify.webhooks.is_empty() && CONFIG.notify.emails.is_empty() {
+            return Ok(None);
+        }
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("notify".to_owned())
+            .spawn(move || run(rx))?;
+        Ok(Some(Notifier { tx }))
+    }
+
+    pub fn notify(&self, event: &Event) {
+        self.tx.send(event.clone()).ok();
+    }
+}
+
+fn run(rx: mpsc::Receiver<Event>) {
+    let mut pending: Vec<Delivery> = Vec::new();
+    loop {
+        let next = pending.iter().map(|d| d.at).min();
+        let res = match next {
+            Some(at) => rx.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match res {
+            Ok(event) => {
+                let now = Instant::now();
+                pending.extend(targets(&event).map(|target| Delivery {
+                    target,
+                    event: event.clone(),
+                    attempts: 0,
+                    at: now,
+                }));
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        let (due, rest): (Vec<_>, Vec<_>) = mem::take(&mut pending)
+            .into_iter()
+            .partition(|d| d.at <= now);
+        pending = rest;
+        for mut d in due {
+            let res = match d.target {
+                Target::Webhook(i) => post(&CONFIG.notify.webhooks[i], &d.event),
+                Target::Email(i) => email(&CONFIG.notify.emails[i], &d.event),
+            };
+            if let Err(e) = res {
+                d.attempts += 1;
+                if d.attempts >= CONFIG.notify.attempts {
+                    error!(
+                        "Failed to notify {:?} of event {}: {}",
+                        d.target, d.event.id, e
+                    );
+                } else {
+                    debug!("Failed to notify {:?}, retrying: {}", d.target, e);
+                    let delay = cmp::min(
+                        RETRY_BASE_SECS << cmp::min(d.attempts - 1, 9),
+                        RETRY_MAX_SECS,
+                    );
+                    d.at = now + Duration::from_secs(delay);
+                    pending.push(d);
+                }
+            }
+        }
+    }
+}
+
+/// Webhooks and emails subscribed to the kind of event.
+fn targets(event: &Event) -> impl Iterator<Item = Target> + '_ {
+    let wanted = move |events: &Vec<_>| events.is_empty() || events.contains(&event.kind);
+    let hooks = CONFIG.notify.webhooks.iter().enumerate();
+    let emails = CONFIG.notify.emails.iter().enumerate();
+    hooks
+        .filter(move |(_, h)| wanted(&h.events))
+        .map(|(i, _)| Target::Webhook(i))
+        .chain(
+            emails
+                .filter(move |(_, e)| wanted(&e.events))
+                .map(|(i, _)| Target::Email(i)),
+        )
+}
+
+fn post(hook: &WebhookConfig, event: &Event) -> io::Result<()> {
+    let body = match hook.template {
+        Some(ref template) => render(template, event, true),
+        None => json::to_string(event)?,
+    };
+    let url = Url::parse(&hook.url).map_err(|e| io::Error::other(e.to_string()))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| io::Error::other("webhook url has no host"))?;
+    let https = url.scheme() == "https";
+    let port = url.port_or_known_default().unwrap_or(80);
+    // IPv6 literals are bracketed in URLs
+    let ip = dns::resolve(host.trim_start_matches('[').trim_end_matches(']'))?;
+    let addr = SocketAddr::new(ip, port);
+
+    let timeout = Duration::from_secs(TIMEOUT_SECS);
+    let conn = TcpStream::connect_timeout(&addr, timeout)?;
+    conn.set_read_timeout(Some(timeout))?;
+    conn.set_write_timeout(Some(timeout))?;
+    let mut conn = SStream::new(conn, if https { Some(host.to_owned()) } else { None })?;
+    conn.set_nonblocking(false)?;
+
+    let len = body.len().to_string();
+    let mut req = http::RequestBuilder::new("POST", url.path(), url.query());
+    req.header("Host", host)
+        .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
+        .header("Content-Type", "application/json")
+        .header("Content-Length", &len)
+        .header("Connection", "close");
+    for (name, value) in &hook.headers {
+        req.header(name, value);
+    }
+    let mut data = Vec::new();
+    req.encode(&mut data);
+    data.extend_from_slice(body.as_bytes());
+    conn.write_all(&data)?;
+    conn.flush()?;
+
+    let mut resp = Vec::new();
+    let mut buf = [0u8; 1024];
+    while resp.len() < MAX_RESPONSE_LEN {
+        let n = conn.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        resp.extend_from_slice(&buf[..n]);
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Response::new(&mut headers);
+        if let Ok(httparse::Status::Complete(_)) = parsed.parse(&resp) {
+            return match parsed.code {
+                Some(code) if (200..300).contains(&code) => Ok(()),
+                code => Err(io::Error::other(format!(
+                    "webhook responded with status {}",
+                    code.unwrap_or(0)
+                ))),
+            };
+        }
+    }
+    Err(io::Error::other("webhook sent no response"))
+}
+
+fn email(cfg: &EmailConfig, event: &Event) -> io::Result<()> {
+    let message = email_message(cfg, event);
+    let mut child = Command::new(&CONFIG.notify.sendmail)
+        .arg("-t")
+        // Don't end the message early at a line with a lone dot
+        .arg("-i")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("sendmail exited with {}", status)))
+    }
+}
+
+/// Builds the email sent for an event. Line breaks in the subject are
+/// replaced, as they would otherwise start new headers.
+fn email_message(cfg: &EmailConfig, event: &Event) -> String {
+    let subject = render(&cfg.subject, event, false);
+    format!(
+        "To: {}\nSubject: {}\n\n{}\n",
+        cfg.to,
+        subject.replace(['\r', '\n'], " "),
+        render(&cfg.template, event, false)
+    )
+}
+
+/// Replaces each {{field}} in a template with the event's field,
+/// escaping it for a JSON string when `json` is set. Unknown fields are
+/// left as they are.
+fn render(template: &str, event: &Event, json: bool) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after
+            .find("}}")
+            .and_then(|end| field(event, after[..end].trim()).map(|v| (end, v)));
+        match value {
+            Some((end, value)) if json => {
+                let quoted = json::to_string(&value).unwrap();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+                rest = &after[end + 2..];
+            }
+            Some((end, value)) => {
+                out.push_str(&value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn field(event: &Event, name: &str) -> Option<String> {
+    Some(match name {
+        "id" => event.id.to_string(),
+        "time" => event.time.to_rfc3339(),
+        "kind" => event.kind.as_str().to_owned(),
+        "torrent_id" => event.torrent_id.clone().unwrap_or_default(),
+        "name" => event.name.clone().unwrap_or_default(),
+        "message" => event.message.clone(),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::proto::message::EventKind;
+    use chrono::Utc;
+
+    #[test]
+    fn test_render() {
+        let event = Event {
+            id: 3,
+            time: Utc::now(),
+            kind: EventKind::TorrentFinished,
+            torrent_id: Some("AB".to_owned()),
+            name: Some("a \"b\"".to_owned()),
+            message: "Download complete".to_owned(),
+        };
+        assert_eq!(
+            render(
+                r#"{"text": "{{ name }} {{kind}}", "n": {{id}}}"#,
+                &event,
+                true
+            ),
+            r#"{"text": "a \"b\" torrent_finished", "n": 3}"#
+        );
+        assert_eq!(
+            render("{{name}}: {{message}} {{other}} {{", &event, false),
+            "a \"b\": Download complete {{other}} {{"
+        );
+    }
+
+    #[test]
+    fn test_email_message() {
+        let event = Event {
+            id: 3,
+            time: Utc::now(),
+            kind: EventKind::TorrentFinished,
+            torrent_id: Some("AB".to_owned()),
+            name: Some("a\r\nBcc: x@example.com".to_owned()),
+            message: "Download complete".to_owned(),
+        };
+        let cfg = EmailConfig {
+            to: "me@example.com".to_owned(),
+            events: Vec::new(),
+            subject: "{{name}}".to_owned(),
+            template: "{{message}}".to_owned(),
+        };
+        assert_eq!(
+            email_message(&cfg, &event),
+            "To: me@example.com\nSubject: a  Bcc: x@example.com\n\nDownload complete\n"
+        );
+    }
+}
@@ -1,25 +1,36 @@
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time;
 
 use crate::rpc_lib;
 use chrono::{DateTime, Duration, Utc};
 use serde_json as json;
-use url::Url;
+use url::{form_urlencoded, Url};
 
+use super::auth::{self, Access};
+use super::notify::Notifier;
 use super::proto::criterion::{self, Criterion, Operation};
-use super::proto::message::{CMessage, Error, SMessage};
-use super::proto::resource::{merge_json, Resource, ResourceKind, SResourceUpdate};
-use super::{CtlMessage, Message};
+use super::proto::message::{Action, CMessage, Error, Event, EventKind, SMessage};
+use super::proto::resource::{merge_json, Resource, ResourceKind, SResourceUpdate, Server};
+use super::{AddOptions, CtlMessage, Message};
+use crate::bencode;
+use crate::config::Scope;
 use crate::disk;
 use crate::torrent::info::Info;
-use crate::util::{random_string, FHashMap, FHashSet, MHashSet, SHashMap};
+use crate::tracker;
+use crate::util::{
+    hash_to_id, hex_to_bytes, id_to_hash, random_string, FHashMap, FHashSet, MHashSet, SHashMap,
+    UHashMap,
+};
 use crate::CONFIG;
 
 const USER_DATA_FILE: &str = "rpc_user_data";
+const OWNERS_FILE: &str = "rpc_owners";
+const EVENTS_FILE: &str = "rpc_events";
 type RpcDiskFmt = SHashMap<Vec<u8>>;
 
 // TODO: Figure out a way to reduce allocations
@@ -38,6 +49,17 @@ pub struct Processor {
     tokens: SHashMap<BearerToken>,
     db: amy::Sender<disk::Request>,
     user_data: SHashMap<json::Value>,
+    clients: UHashMap<Access>,
+    // Users which torrents are attributed to, by torrent ID
+    owners: SHashMap<String>,
+    // Event log, oldest first, and the ID of the next event
+    events: VecDeque<Event>,
+    next_event: u64,
+    // Whether events were logged since the log was last written
+    events_dirty: bool,
+    // Serial and kinds of the events each client is subscribed to
+    event_subs: UHashMap<(u64, Vec<EventKind>)>,
+    notifier: Option<Notifier>,
 }
 
 struct Filter {
@@ -50,20 +72,14 @@ struct BearerToken {
     client: usize,
     serial: u64,
     kind: TransferKind,
+    /// Bytes of a torrent uploaded in chunks so far
+    data: Vec<u8>,
 }
 
 #[derive(Clone)]
 pub enum TransferKind {
-    UploadTorrent {
-        size: u64,
-        path: Option<String>,
-        start: bool,
-        import: bool,
-    },
-    UploadFiles {
-        size: u64,
-        path: String,
-    },
+    UploadTorrent { size: u64, options: AddOptions },
+    UploadFiles { size: u64, path: String },
 }
 
 const EXPIRATION_DUR: i64 = 120;
@@ -106,7 +122,34 @@ impl Processor {
             .filter_map(|(k, v)| json::from_slice(&v).ok().map(|j| (k, j)))
             .collect();
 
-        Processor {
+        let owners = OpenOptions::new()
+            .read(true)
+            .open(Path::new(&CONFIG.disk.session[..]).join(OWNERS_FILE))
+            .ok()
+            .and_then(|mut f| {
+                let mut data = Vec::new();
+                f.read_to_end(&mut data).ok()?;
+                bincode::deserialize(&data).ok()
+            })
+            .unwrap_or_default();
+
+        let (next_event, events) = OpenOptions::new()
+            .read(true)
+            .open(Path::new(&CONFIG.disk.session[..]).join(EVENTS_FILE))
+            .ok()
+            .and_then(|mut f| {
+                let mut data = Vec::new();
+                f.read_to_end(&mut data).ok()?;
+                bincode::deserialize(&data).ok()
+            })
+            .unwrap_or_default();
+
+        let notifier = Notifier::start().unwrap_or_else(|e| {
+            error!("Failed to start notifier: {}", e);
+            None
+        });
+
+        let mut p = Processor {
             subs: SHashMap::default(),
             filter_subs: FHashMap::default(),
             resources: SHashMap::default(),
@@ -115,15 +158,70 @@ impl Processor {
             kinds: vec![MHashSet::default(); 6],
             db,
             user_data,
+            clients: UHashMap::default(),
+            owners,
+            events,
+            next_event,
+            events_dirty: false,
+            event_subs: UHashMap::default(),
+            notifier,
+        };
+        p.remove_expired_events();
+        p
+    }
+
+    pub fn add_client(&mut self, client: usize, access: Access) {
+        self.clients.insert(client, access);
+    }
+
+    /// Attributes a torrent which a client is about to add to the client's user.
+    pub fn claim(&mut self, client: usize, hash: &[u8; 20]) {
+        let user = match self.clients.get(&client).and_then(|a| a.user.clone()) {
+            Some(user) => user,
+            None => return,
+        };
+        let id = hash_to_id(hash);
+        // Don't steal torrents which already exist
+        if self.resources.contains_key(&id) {
+            return;
         }
+        self.owners.insert(id, user);
+        self.serialize_owners();
+    }
+
+    /// Whether a client may merge trackers into the torrent with the
+    /// given hash, should it already exist.
+    pub fn may_merge(&self, client: usize, hash: &[u8; 20]) -> bool {
+        self.clients
+            .get(&client)
+            .map(|a| a.allows(Scope::Admin) && self.visible(a, &hash_to_id(hash)))
+            .unwrap_or(false)
     }
 
     pub fn remove_expired_tokens(&mut self) {
         self.tokens.retain(|_, tok| tok.expiration > Utc::now())
     }
 
-    pub fn get_dl(&self, id: &str) -> Option<(String, u64)> {
-        match self.resources.get(id) {
+    /// Removes events beyond the log's size and age limits.
+    pub fn remove_expired_events(&mut self) {
+        while self.events.len() > CONFIG.rpc.max_events {
+            self.events.pop_front();
+        }
+        if CONFIG.rpc.event_age != 0 {
+            let oldest = Utc::now() - Duration::seconds(CONFIG.rpc.event_age as i64);
+            while self
+                .events
+                .front()
+                .map(|e| e.time < oldest)
+                .unwrap_or(false)
+            {
+                self.events.pop_front();
+            }
+        }
+    }
+
+    pub fn get_dl(&self, access: &Access, id: &str) -> Option<(String, u64)> {
+        match self.get_resource(access, id) {
             Some(&Resource::File(ref f)) => match self.resources.get(&f.torrent_id) {
                 Some(&Resource::Torrent(ref t)) => Some((t.path.clone() + "/" + &f.path, f.size)),
                 _ => None,
@@ -132,6 +230,32 @@ impl Processor {
         }
     }
 
+    /// Returns the name of a torrent and an archive of its files.
+    pub fn get_archive(
+        &self,
+        access: &Access,
+        id: &str,
+        kind: disk::ArchiveKind,
+    ) -> Option<(String, disk::Archive)> {
+        let t = match self.get_resource(access, id) {
+            Some(Resource::Torrent(t)) => t,
+            _ => return None,
+        };
+        let mut files: Vec<_> = self
+            .torrent_idx
+            .get(id)?
+            .iter()
+            .filter_map(|rid| match self.resources.get(rid) {
+                Some(Resource::File(f)) => Some((f.path.clone(), f.size)),
+                _ => None,
+            })
+            .collect();
+        files.sort();
+        let name = t.name.clone().unwrap_or_else(|| id.to_owned());
+        let archive = disk::Archive::new(kind, t.path.clone(), files)?;
+        Some((name, archive))
+    }
+
     pub fn get_transfer(&mut self, tok: String) -> Option<(usize, u64, TransferKind)> {
         let mut res = None;
         let rem = match self.tokens.get(&tok) {
@@ -154,6 +278,26 @@ impl Processor {
         res
     }
 
+    /// Removes a pending transfer, returning its kind.
+    pub fn take_transfer(&mut self, tok: &str) -> Option<TransferKind> {
+        self.tokens.remove(tok).map(|t| t.kind)
+    }
+
+    /// Returns all resources of a kind visible to a client.
+    pub fn get_resources(&self, access: &Access, kind: ResourceKind) -> Vec<&Resource> {
+        self.kinds[kind as usize]
+            .iter()
+            .filter_map(|id| self.resources.get(id))
+            .filter(|r| visible(access, &self.owners, r))
+            .collect()
+    }
+
+    pub fn get_resource(&self, access: &Access, id: &str) -> Option<&Resource> {
+        self.resources
+            .get(id)
+            .filter(|r| visible(access, &self.owners, r))
+    }
+
     pub fn handle_client(
         &mut self,
         client: usize,
@@ -161,12 +305,98 @@ impl Processor {
     ) -> (Vec<SMessage<'_>>, Option<Message>) {
         let mut resp = Vec::new();
         let mut rmsg = None;
+        let access = match self.clients.get(&client) {
+            Some(access) => access.clone(),
+            None => return (resp, rmsg),
+        };
+        let required = match msg {
+            CMessage::GetResources { .. }
+            | CMessage::Subscribe { .. }
+            | CMessage::Unsubscribe { .. }
+            | CMessage::FilterSubscribe { .. }
+            | CMessage::FilterUnsubscribe { .. }
+            | CMessage::CreateDownloadLink { .. }
+            | CMessage::GetMagnet { .. }
+            | CMessage::GetDhtItem { .. }
+            | CMessage::GetHistory { .. }
+            | CMessage::GetEvents { .. }
+            | CMessage::SubscribeEvents { .. }
+            | CMessage::UnsubscribeEvents { .. } => Scope::Read,
+            CMessage::UploadTorrent { .. }
+            | CMessage::UploadChunk { .. }
+            | CMessage::UploadMagnet { .. } => Scope::Add,
+            _ => Scope::Admin,
+        };
+        // Restricted clients may not perform server wide actions
+        let global = match msg {
+            CMessage::UploadFiles { .. }
+            | CMessage::PurgeDns { .. }
+            | CMessage::CrossSeed { .. }
+            | CMessage::FindData { .. }
+            | CMessage::SetLabel { .. }
+            | CMessage::RemoveLabel { .. }
+            | CMessage::SetThrottleGroup { .. }
+            | CMessage::RemoveThrottleGroup { .. }
+            | CMessage::PutDhtItem { .. }
+            | CMessage::GetHistory { .. }
+            | CMessage::GetSetting { .. }
+            | CMessage::SetSetting { .. }
+            | CMessage::ReloadConfig { .. }
+            | CMessage::SetPort { .. }
+            | CMessage::StopNetwork { .. }
+            | CMessage::StartNetwork { .. }
+            | CMessage::MoveDirectory { .. } => true,
+            CMessage::UpdateResource { ref resource, .. } => {
+                matches!(self.resources.get(&resource.id), Some(&Resource::Server(_)))
+            }
+            CMessage::CancelJobs { ref id, .. } => {
+                matches!(self.resources.get(id), Some(&Resource::Server(_)))
+            }
+            _ => false,
+        };
+        if !access.allows(required) || (global && access.restricted) {
+            resp.push(SMessage::PermissionDenied(Error {
+                serial: Some(msg.serial()),
+                reason: "Insufficient permissions for request".to_owned(),
+            }));
+            return (resp, rmsg);
+        }
+        let target = match msg {
+            CMessage::UpdateResource { ref resource, .. } => Some(&resource.id),
+            CMessage::RemoveResource { ref id, .. }
+            | CMessage::PauseTorrent { ref id, .. }
+            | CMessage::ResumeTorrent { ref id, .. }
+            | CMessage::UpdateTracker { ref id, .. }
+            | CMessage::ScrapeTorrent { ref id, .. }
+            | CMessage::ReannounceTorrent { ref id, .. }
+            | CMessage::RefreshDht { ref id, .. }
+            | CMessage::AddTracker { ref id, .. }
+            | CMessage::AddPeer { ref id, .. }
+            | CMessage::SetPieceDeadline { ref id, .. }
+            | CMessage::CrossSeed { ref id, .. }
+            | CMessage::FindData { ref id, .. }
+            | CMessage::CreateDownloadLink { ref id, .. }
+            | CMessage::GetMagnet { ref id, .. }
+            | CMessage::CancelJobs { ref id, .. } => Some(id),
+            _ => None,
+        };
+        if let Some(id) = target {
+            if !self.visible(&access, id) {
+                resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(msg.serial()),
+                    reason: format!("unknown resource id {}", id),
+                }));
+                return (resp, rmsg);
+            }
+        }
+
         match msg {
             CMessage::GetResources { serial, ids } => {
                 let mut resources = Vec::new();
                 for id in ids {
-                    if let Some(r) = self.resources.get(&id) {
-                        resources.push(SResourceUpdate::Resource(Cow::Borrowed(r)));
+                    let r = self.resources.get(&id);
+                    if let Some(r) = r.filter(|r| visible(&access, &self.owners, r)) {
+                        resources.push(SResourceUpdate::Resource(client_view(&access, r)));
                     } else {
                         resp.push(SMessage::UnknownResource(Error {
                             serial: Some(serial),
@@ -182,8 +412,9 @@ impl Processor {
             CMessage::Subscribe { serial, ids } => {
                 let mut resources = Vec::new();
                 for id in ids {
-                    if let Some(r) = self.resources.get(&id) {
-                        resources.push(SResourceUpdate::Resource(Cow::Borrowed(r)));
+                    let r = self.resources.get(&id);
+                    if let Some(r) = r.filter(|r| visible(&access, &self.owners, r)) {
+                        resources.push(SResourceUpdate::Resource(client_view(&access, r)));
                         self.subs.get_mut(&id).map(|s| s.insert(client));
                     } else {
                         resp.push(SMessage::UnknownResource(Error {
@@ -231,7 +462,21 @@ impl Processor {
 
                 match self.resources.get(&resource.id) {
                     Some(&Resource::Torrent(_)) => {
-                        rmsg = Some(Message::UpdateTorrent(resource));
+                        match (&resource.label, &resource.throttle_group) {
+                            (Some(Some(l)), _) if !self.label_exists(l) => {
+                                resp.push(SMessage::InvalidRequest(Error {
+                                    serial: Some(serial),
+                                    reason: format!("Unknown label {}", l),
+                                }))
+                            }
+                            (_, Some(Some(g))) if !self.throttle_group_exists(g) => {
+                                resp.push(SMessage::InvalidRequest(Error {
+                                    serial: Some(serial),
+                                    reason: format!("Unknown throttle group {}", g),
+                                }))
+                            }
+                            _ => rmsg = Some(Message::UpdateTorrent(resource)),
+                        }
                     }
                     Some(&Resource::File(ref f)) => {
                         // TODO: Validate other fields(make sure they're not present)
@@ -310,6 +555,8 @@ impl Processor {
                 let kinds = &self.kinds;
                 let rkind = &self.kinds[kind as usize];
                 let resources = &self.resources;
+                let owners = &self.owners;
+                let access = &access;
 
                 let get_matching = |f: &Filter| {
                     let mut added = HashSet::new();
@@ -326,14 +573,18 @@ impl Processor {
                     if let Some(t) = crit_res {
                         for id in rkind.intersection(t) {
                             let r = resources.get(id).unwrap();
-                            if f.matches(r, torrent_idx, kinds, resources) {
+                            if visible(access, owners, r)
+                                && f.matches(r, torrent_idx, kinds, resources)
+                            {
                                 added.insert(Cow::Borrowed(r.id()));
                             }
                         }
                     } else {
                         for id in rkind.iter() {
                             let r = resources.get(id).unwrap();
-                            if f.matches(r, torrent_idx, kinds, resources) {
+                            if visible(access, owners, r)
+                                && f.matches(r, torrent_idx, kinds, resources)
+                            {
                                 added.insert(Cow::Borrowed(r.id()));
                             }
                         }
@@ -370,6 +621,29 @@ impl Processor {
             CMessage::FilterUnsubscribe { filter_serial, .. } => {
                 self.filter_subs.remove(&(client, filter_serial));
             }
+            CMessage::GetEvents {
+                serial,
+                after,
+                kinds,
+                torrent_id,
+            } => {
+                let events = self
+                    .events
+                    .iter()
+                    .filter(|e| after.map(|a| e.id > a).unwrap_or(true))
+                    .filter(|e| kinds.is_empty() || kinds.contains(&e.kind))
+                    .filter(|e| torrent_id.is_none() || e.torrent_id == torrent_id)
+                    .filter(|e| event_visible(&access, &self.owners, e))
+                    .cloned()
+                    .collect();
+                resp.push(SMessage::Events { serial, events });
+            }
+            CMessage::SubscribeEvents { serial, kinds } => {
+                self.event_subs.insert(client, (serial, kinds));
+            }
+            CMessage::UnsubscribeEvents { .. } => {
+                self.event_subs.remove(&client);
+            }
 
             CMessage::PauseTorrent { serial, id } => match self.resources.get(&id) {
                 Some(&Resource::Torrent(_)) => rmsg = Some(Message::Pause(id)),
@@ -382,6 +656,29 @@ impl Processor {
                     reason: format!("Unknown resource {}", id),
                 })),
             },
+            CMessage::PauseAll { .. } => {
+                let ids = self.visible_torrents(&access);
+                rmsg = Some(Message::Bulk(ids.map(Message::Pause).collect()));
+            }
+            CMessage::ResumeAll { .. } => {
+                let ids = self.visible_torrents(&access);
+                rmsg = Some(Message::Bulk(ids.map(Message::Resume).collect()));
+            }
+            CMessage::CancelJobs { serial, id } => match self.resources.get(&id) {
+                Some(r @ &Resource::Server(_))
+                | Some(r @ &Resource::Torrent(_))
+                | Some(r @ &Resource::File(_)) => {
+                    rmsg = Some(Message::CancelJobs { kind: r.kind(), id })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "Only servers, torrents and files have jobs".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
             CMessage::ResumeTorrent { serial, id } => match self.resources.get(&id) {
                 Some(&Resource::Torrent(_)) => rmsg = Some(Message::Resume(id)),
                 Some(_) => resp.push(SMessage::InvalidResource(Error {
@@ -441,6 +738,106 @@ impl Processor {
                     reason: format!("Unknown resource {}", id),
                 })),
             },
+            CMessage::SetPieceDeadline {
+                serial,
+                id,
+                offset,
+                deadline,
+            } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(ref t)) if offset < t.size.unwrap_or(0) => {
+                    rmsg = Some(Message::SetPieceDeadline {
+                        id,
+                        offset,
+                        deadline: time::Duration::from_millis(deadline),
+                    })
+                }
+                Some(&Resource::Torrent(_)) => resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("Offset {} is outside of the torrent", offset),
+                })),
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "SET_PIECE_DEADLINE not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::CrossSeed { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(ref t)) if t.progress >= 1.0 => {
+                    rmsg = Some(Message::CrossSeed { id })
+                }
+                Some(&Resource::Torrent(_)) => resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: "Only completed torrents can be cross seeded".to_owned(),
+                })),
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "CROSS_SEED not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::FindData { serial, id, path } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) if !Path::new(&path).is_absolute() => {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "FIND_DATA path must be absolute".to_owned(),
+                    }))
+                }
+                Some(&Resource::Torrent(_)) => rmsg = Some(Message::FindData { id, path }),
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "FIND_DATA not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::SetLabel { serial, label } => {
+                if label.name.is_empty() {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Labels must have a name".to_owned(),
+                    }));
+                } else {
+                    rmsg = Some(Message::SetLabel(label));
+                }
+            }
+            CMessage::RemoveLabel { serial, name } => {
+                if self.label_exists(&name) {
+                    rmsg = Some(Message::RemoveLabel(name));
+                } else {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: format!("Unknown label {}", name),
+                    }));
+                }
+            }
+            CMessage::SetThrottleGroup { serial, group } => {
+                if group.name.is_empty() {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Throttle groups must have a name".to_owned(),
+                    }));
+                } else {
+                    rmsg = Some(Message::SetThrottleGroup(group));
+                }
+            }
+            CMessage::RemoveThrottleGroup { serial, name } => {
+                if self.throttle_group_exists(&name) {
+                    rmsg = Some(Message::RemoveThrottleGroup(name));
+                } else {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: format!("Unknown throttle group {}", name),
+                    }));
+                }
+            }
             CMessage::UpdateTracker { serial, id } => match self.resources.get(&id) {
                 Some(&Resource::Tracker(ref t)) => {
                     rmsg = Some(Message::UpdateTracker {
@@ -457,8 +854,42 @@ impl Processor {
                     reason: format!("Unknown resource {}", id),
                 })),
             },
+            CMessage::ScrapeTorrent { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => rmsg = Some(Message::Scrape { id }),
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "SCRAPE_TORRENT not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::ReannounceTorrent { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => rmsg = Some(Message::Reannounce(id)),
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "REANNOUNCE_TORRENT not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::RefreshDht { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => rmsg = Some(Message::RefreshDht(id)),
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "REFRESH_DHT not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
             CMessage::ValidateResources { serial, mut ids } => {
-                ids.retain(|id| match self.resources.get(id) {
+                let owned = |id: &String| self.visible(&access, id);
+                ids.retain(|id| match self.resources.get(id).filter(|_| owned(id)) {
                     Some(&Resource::Torrent(_)) => true,
                     Some(_) => {
                         resp.push(SMessage::InvalidResource(Error {
@@ -477,36 +908,145 @@ impl Processor {
                 });
                 rmsg = Some(Message::Validate(ids));
             }
+            CMessage::BulkAction {
+                serial,
+                criteria,
+                action,
+                artifacts,
+            } => {
+                let filter = Filter {
+                    kind: ResourceKind::Torrent,
+                    criteria,
+                };
+                let ids = self.kinds[ResourceKind::Torrent as usize]
+                    .iter()
+                    .filter_map(|id| self.resources.get(id))
+                    .filter(|r| visible(&access, &self.owners, r))
+                    .filter(|r| filter.matches(r, &self.torrent_idx, &self.kinds, &self.resources))
+                    .map(|r| r.id().to_owned());
+                rmsg = Some(match action {
+                    Action::Pause => Message::Bulk(ids.map(Message::Pause).collect()),
+                    Action::Resume => Message::Bulk(ids.map(Message::Resume).collect()),
+                    Action::Validate => Message::Validate(ids.collect()),
+                    Action::Reannounce => Message::Bulk(ids.map(Message::Reannounce).collect()),
+                    Action::DhtRefresh => Message::Bulk(ids.map(Message::RefreshDht).collect()),
+                    Action::Remove => Message::Bulk(
+                        ids.map(|id| Message::RemoveTorrent {
+                            id,
+                            client,
+                            serial,
+                            artifacts,
+                        })
+                        .collect(),
+                    ),
+                });
+            }
+            CMessage::UploadTorrent {
+                serial,
+                path: None,
+                seed_only: true,
+                ..
+            } => resp.push(SMessage::InvalidRequest(Error {
+                serial: Some(serial),
+                reason: "Seed only torrents require a path".to_owned(),
+            })),
+            CMessage::UploadTorrent {
+                serial,
+                label: Some(ref l),
+                ..
+            }
+            | CMessage::UploadMagnet {
+                serial,
+                label: Some(ref l),
+                ..
+            } if !self.label_exists(l) => resp.push(SMessage::InvalidRequest(Error {
+                serial: Some(serial),
+                reason: format!("Unknown label {}", l),
+            })),
             CMessage::UploadTorrent {
                 serial,
                 size,
                 path,
                 start,
                 import,
+                seed_only,
+                label,
+                name,
+                priorities,
+                merge,
             } => {
                 resp.push(self.new_transfer(
                     client,
                     serial,
                     TransferKind::UploadTorrent {
                         size,
-                        path,
-                        start,
-                        import,
+                        options: AddOptions {
+                            path,
+                            start,
+                            import,
+                            seed_only,
+                            label,
+                            name,
+                            priorities,
+                            merge,
+                            metadata_only: false,
+                        },
                     },
                 ));
             }
+            CMessage::UploadChunk {
+                serial,
+                token,
+                offset,
+                data,
+            } => match self.upload_chunk(client, &token, offset, &data) {
+                Ok((received, size)) => {
+                    resp.push(SMessage::TransferProgress {
+                        serial,
+                        token: token.clone(),
+                        received,
+                        size,
+                    });
+                    if received == size {
+                        let tok = self.tokens.remove(&token).unwrap();
+                        match parse_torrent(&tok.data) {
+                            Ok(info) => rmsg = Some(self.uploaded(client, tok, info)),
+                            Err(reason) => resp.push(SMessage::TransferFailed(Error {
+                                serial: Some(tok.serial),
+                                reason,
+                            })),
+                        }
+                    }
+                }
+                Err(reason) => resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason,
+                })),
+            },
             CMessage::UploadMagnet {
                 serial,
                 uri,
                 path,
                 start,
+                label,
+                name,
+                merge,
+                metadata_only,
             } => match Info::from_magnet(&uri) {
                 Ok(info) => {
+                    let merge = merge && self.may_merge(client, &info.hash);
+                    self.claim(client, &info.hash);
                     rmsg = Some(Message::Torrent {
-                        info,
-                        path,
-                        start,
-                        import: false,
+                        info: Box::new(info),
+                        options: AddOptions {
+                            path,
+                            start,
+                            label,
+                            name,
+                            merge,
+                            metadata_only,
+                            ..Default::default()
+                        },
                         client,
                         serial,
                     })
@@ -528,6 +1068,200 @@ impl Processor {
             CMessage::PurgeDns { .. } => {
                 rmsg = Some(Message::PurgeDNS);
             }
+            CMessage::CreateDownloadLink {
+                serial,
+                id,
+                expires,
+                rate,
+            } => match self.resources.get(&id) {
+                Some(&Resource::File(_)) | Some(&Resource::Torrent(_)) if rate == Some(0) => resp
+                    .push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Download rate must be positive".to_owned(),
+                    })),
+                Some(&Resource::File(_)) | Some(&Resource::Torrent(_)) => {
+                    let ts = expires.map(|e| e.timestamp());
+                    let mut path = format!("/dl/{}?", id);
+                    if let Some(ts) = ts {
+                        path.push_str(&format!("expires={}&", ts));
+                    }
+                    if let Some(rate) = rate {
+                        path.push_str(&format!("rate={}&", rate));
+                    }
+                    path.push_str("sig=");
+                    path.push_str(&auth::sign_link(&id, ts, rate));
+                    resp.push(SMessage::DownloadLink {
+                        serial,
+                        path,
+                        expires,
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "Only files and torrents can be downloaded".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetMagnet { serial, id } => match self.resources.get(&id) {
+                Some(Resource::Torrent(t)) => {
+                    let mut trackers: Vec<_> = self
+                        .torrent_idx
+                        .get(&id)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|id| match self.resources.get(id) {
+                            Some(Resource::Tracker(t)) => Some(t.url.as_str()),
+                            _ => None,
+                        })
+                        .collect();
+                    trackers.sort_unstable();
+                    let peers = match self.server() {
+                        Some(s) => external_addrs(s),
+                        None => Vec::new(),
+                    };
+                    resp.push(SMessage::Magnet {
+                        serial,
+                        uri: magnet_uri(&id, t.name.as_deref(), &trackers, &peers),
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "Magnets can only be made for torrents".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetDhtItem {
+                serial,
+                target,
+                key,
+                salt,
+            } => {
+                let salt = salt.unwrap_or_default();
+                let target = match (target, key) {
+                    (Some(target), None) => id_to_hash(&target),
+                    (None, Some(key)) => hex_to_bytes(&key)
+                        .filter(|k| k.len() == 32)
+                        .map(|k| tracker::mutable_target(&k, salt.as_bytes())),
+                    _ => None,
+                };
+                match target {
+                    Some(target) => {
+                        rmsg = Some(Message::GetDhtItem {
+                            client,
+                            serial,
+                            target,
+                        })
+                    }
+                    None => resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Either a valid target or public key must be specified".to_owned(),
+                    })),
+                }
+            }
+            CMessage::GetHistory {
+                serial,
+                resolution,
+                start,
+                end,
+            } => match (start, end) {
+                (Some(s), Some(e)) if s > e => resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: "History start must not be after its end".to_owned(),
+                })),
+                _ => {
+                    rmsg = Some(Message::GetHistory {
+                        client,
+                        serial,
+                        resolution,
+                        start,
+                        end,
+                    })
+                }
+            },
+            CMessage::GetSetting { serial, key } => {
+                rmsg = Some(Message::GetSetting {
+                    client,
+                    serial,
+                    key,
+                })
+            }
+            CMessage::SetSetting { serial, key, value } => {
+                rmsg = Some(Message::SetSetting {
+                    client,
+                    serial,
+                    key,
+                    value,
+                })
+            }
+            CMessage::ReloadConfig { serial } => {
+                rmsg = Some(Message::ReloadConfig { client, serial })
+            }
+            CMessage::StopNetwork { .. } => rmsg = Some(Message::StopNetwork(true)),
+            CMessage::StartNetwork { .. } => rmsg = Some(Message::StopNetwork(false)),
+            CMessage::SetPort { serial, port } => {
+                rmsg = Some(Message::SetPort {
+                    client,
+                    serial,
+                    port,
+                })
+            }
+            CMessage::MoveDirectory {
+                serial,
+                directory,
+                path,
+            } => {
+                rmsg = Some(Message::MoveDirectory {
+                    client,
+                    serial,
+                    directory,
+                    path,
+                })
+            }
+            CMessage::PutDhtItem {
+                serial,
+                value,
+                mutable,
+                salt,
+                seq,
+            } => {
+                let salt = salt.unwrap_or_default().into_bytes();
+                let value = base64::decode(&value)
+                    .ok()
+                    .filter(|v| bencode::decode_buf(v).is_ok());
+                match value {
+                    Some(value) if value.len() <= tracker::MAX_VALUE_LEN => {
+                        if salt.len() > tracker::MAX_SALT_LEN {
+                            resp.push(SMessage::InvalidRequest(Error {
+                                serial: Some(serial),
+                                reason: "Item salt is too long".to_owned(),
+                            }));
+                        } else {
+                            rmsg = Some(Message::PutDhtItem {
+                                client,
+                                serial,
+                                value,
+                                mutable,
+                                salt,
+                                seq,
+                            });
+                        }
+                    }
+                    Some(_) => resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Item value is too large".to_owned(),
+                    })),
+                    None => resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Item value must be base64 encoded bencoded data".to_owned(),
+                    })),
+                }
+            }
         }
         (resp, rmsg)
     }
@@ -619,6 +1353,9 @@ impl Processor {
                     if self.user_data.remove(&id).is_some() {
                         self.serialize();
                     }
+                    if self.owners.remove(&id).is_some() {
+                        self.serialize_owners();
+                    }
                     self.kinds[r.kind() as usize].remove(&id);
                     // If this resource is part of a torrent, remove from index,
                     // if we haven't removed the entire torrent already.
@@ -650,6 +1387,11 @@ impl Processor {
                 ));
             }
             CtlMessage::Uploaded { id, serial, client } => {
+                let message = match self.owners.get(&id) {
+                    Some(user) => format!("Added by {}", user),
+                    None => "Added".to_owned(),
+                };
+                msgs.extend(self.log_event(EventKind::TorrentAdded, Some(id.clone()), message));
                 if let Some(r) = self.resources.get(&id) {
                     msgs.push((
                         client,
@@ -662,6 +1404,20 @@ impl Processor {
                     debug!("Failed to get resource uploaded: {}!", id);
                 }
             }
+            CtlMessage::Merged {
+                id,
+                trackers,
+                client,
+                serial,
+            } => {
+                let ids = Some(id)
+                    .into_iter()
+                    .chain(trackers)
+                    .filter(|id| self.resources.contains_key(id))
+                    .map(Cow::Owned)
+                    .collect();
+                msgs.push((client, SMessage::ResourcesExtant { serial, ids }))
+            }
             CtlMessage::Error {
                 reason,
                 serial,
@@ -678,6 +1434,122 @@ impl Processor {
             CtlMessage::Pending { id, serial, client } => {
                 msgs.push((client, SMessage::ResourcePending { serial, id }));
             }
+            CtlMessage::DhtItem {
+                client,
+                serial,
+                target,
+                item,
+            } => {
+                let mutable = item.as_ref().and_then(|i| i.mutable.as_ref());
+                msgs.push((
+                    client,
+                    SMessage::DhtItem {
+                        serial,
+                        target: hash_to_id(&target),
+                        value: item.as_ref().map(|i| base64::encode(&i.value)),
+                        key: mutable.map(|m| hash_to_id(&m.key)),
+                        salt: mutable
+                            .filter(|m| !m.salt.is_empty())
+                            .map(|m| String::from_utf8_lossy(&m.salt).into_owned()),
+                        seq: mutable.map(|m| m.seq),
+                    },
+                ));
+            }
+            CtlMessage::History {
+                client,
+                serial,
+                resolution,
+                samples,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::History {
+                        serial,
+                        resolution,
+                        samples,
+                    },
+                ));
+            }
+            CtlMessage::Setting {
+                client,
+                serial,
+                key,
+                value,
+            } => {
+                msgs.push((client, SMessage::Setting { serial, key, value }));
+            }
+            CtlMessage::ConfigReloaded {
+                client,
+                serial,
+                changed,
+            } => {
+                msgs.push((client, SMessage::ConfigReloaded { serial, changed }));
+            }
+            CtlMessage::PortChanged {
+                client,
+                serial,
+                port,
+            } => {
+                msgs.push((client, SMessage::PortChanged { serial, port }));
+            }
+            CtlMessage::DirectoryMoving {
+                client,
+                serial,
+                directory,
+                progress,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::DirectoryMoving {
+                        serial,
+                        directory,
+                        progress,
+                    },
+                ));
+            }
+            CtlMessage::DirectoryMoved {
+                client,
+                serial,
+                directory,
+                path,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::DirectoryMoved {
+                        serial,
+                        directory,
+                        path,
+                    },
+                ));
+            }
+            CtlMessage::Alert {
+                id,
+                error_kind,
+                error,
+                retry,
+            } => {
+                let message = match retry {
+                    Some(time) => format!("{}, retrying at {}", error, time.to_rfc3339()),
+                    None => error.clone(),
+                };
+                msgs.extend(self.log_event(EventKind::DiskAlert, Some(id.clone()), message));
+                for (&client, access) in &self.clients {
+                    if self.visible(access, &id) {
+                        msgs.push((
+                            client,
+                            SMessage::Alert {
+                                id: id.clone(),
+                                error_kind,
+                                error: error.clone(),
+                                retry,
+                            },
+                        ));
+                    }
+                }
+            }
+            CtlMessage::Event { kind, id, message } => {
+                msgs.extend(self.log_event(kind, id, message));
+            }
             CtlMessage::Ping => unreachable!("ping must be handled before rpc processor"),
             CtlMessage::Shutdown => unreachable!("shutdown must be handled before rpc processor"),
         }
@@ -689,6 +1561,68 @@ impl Processor {
             sub.remove(&client);
         }
         self.filter_subs.retain(|&(c, _), _| c != client);
+        self.event_subs.remove(&client);
+        self.clients.remove(&client);
+    }
+
+    /// Records an event, returning it for each client subscribed to it.
+    fn log_event(
+        &mut self,
+        kind: EventKind,
+        torrent_id: Option<String>,
+        message: String,
+    ) -> Vec<(usize, SMessage<'static>)> {
+        let name = match torrent_id.as_ref().and_then(|id| self.resources.get(id)) {
+            Some(Resource::Torrent(t)) => t.name.clone(),
+            _ => None,
+        };
+        let event = Event {
+            id: self.next_event,
+            time: Utc::now(),
+            kind,
+            torrent_id,
+            name,
+            message,
+        };
+        self.next_event += 1;
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify(&event);
+        }
+        let msgs = self
+            .event_subs
+            .iter()
+            .filter(|&(_, (_, kinds))| kinds.is_empty() || kinds.contains(&kind))
+            .filter(|&(client, _)| {
+                self.clients
+                    .get(client)
+                    .map(|a| event_visible(a, &self.owners, &event))
+                    .unwrap_or(false)
+            })
+            .map(|(&client, &(serial, _))| {
+                let event = event.clone();
+                (client, SMessage::Event { serial, event })
+            })
+            .collect();
+        self.events.push_back(event);
+        self.remove_expired_events();
+        self.events_dirty = true;
+        msgs
+    }
+
+    /// IDs of the torrents a client can see.
+    fn visible_torrents<'a>(&'a self, access: &'a Access) -> impl Iterator<Item = String> + 'a {
+        self.kinds[ResourceKind::Torrent as usize]
+            .iter()
+            .filter_map(move |id| self.resources.get(id))
+            .filter(move |r| visible(access, &self.owners, r))
+            .map(|r| r.id().to_owned())
+    }
+
+    fn visible(&self, access: &Access, id: &str) -> bool {
+        self.resources
+            .get(id)
+            .map(|r| visible(access, &self.owners, r))
+            .unwrap_or(true)
     }
 
     /// Produces a map of the form Map<(Client ID, Serial), messages)>.
@@ -707,7 +1641,13 @@ impl Processor {
                 continue;
             };
             for (k, f) in self.filter_subs.iter() {
-                if f.kind == res.kind() && f.matches(&res, torrent_idx, rkind, resources) {
+                let visible = self
+                    .clients
+                    .get(&k.0)
+                    .map(|a| visible(a, &self.owners, res))
+                    .unwrap_or(false);
+                if visible && f.kind == res.kind() && f.matches(&res, torrent_idx, rkind, resources)
+                {
                     if !matched.contains_key(k) {
                         matched.insert(k.clone(), Vec::new());
                     }
@@ -728,6 +1668,7 @@ impl Processor {
                 kind,
                 serial,
                 client,
+                data: Vec::new(),
             },
         );
         SMessage::TransferOffer {
@@ -739,6 +1680,99 @@ impl Processor {
         }
     }
 
+    /// Adds a chunk of a torrent file uploaded over the websocket, giving
+    /// the bytes received so far and the file's size. The upload moves to
+    /// the client sending the chunk, so it can resume on a new connection.
+    fn upload_chunk(
+        &mut self,
+        client: usize,
+        token: &str,
+        offset: u64,
+        data: &str,
+    ) -> Result<(u64, u64), String> {
+        let tok = match self.tokens.get_mut(token) {
+            Some(tok) => tok,
+            None => return Err("Unknown or expired transfer token".to_owned()),
+        };
+        let size = match tok.kind {
+            TransferKind::UploadTorrent { size, .. } => size,
+            _ => return Err("Only torrents may be uploaded in chunks".to_owned()),
+        };
+        let chunk = base64::decode(data).map_err(|e| format!("Invalid chunk data: {}", e))?;
+        add_chunk(&mut tok.data, size, offset, &chunk)?;
+        tok.client = client;
+        tok.expiration = Utc::now() + Duration::seconds(EXPIRATION_DUR);
+        Ok((tok.data.len() as u64, size))
+    }
+
+    /// Message adding a torrent uploaded in chunks.
+    fn uploaded(&mut self, client: usize, tok: BearerToken, info: Info) -> Message {
+        match tok.kind {
+            TransferKind::UploadTorrent { mut options, .. } => {
+                options.merge &= self.may_merge(client, &info.hash);
+                self.claim(client, &info.hash);
+                Message::Torrent {
+                    info: Box::new(info),
+                    options,
+                    client,
+                    serial: tok.serial,
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn server(&self) -> Option<&Server> {
+        self.kinds[ResourceKind::Server as usize]
+            .iter()
+            .filter_map(|id| self.resources.get(id))
+            .find_map(|r| match r {
+                Resource::Server(s) => Some(s),
+                _ => None,
+            })
+    }
+
+    /// Whether or not the server has a label with the given name.
+    fn label_exists(&self, name: &str) -> bool {
+        self.kinds[ResourceKind::Server as usize]
+            .iter()
+            .filter_map(|id| self.resources.get(id))
+            .any(|r| match r {
+                Resource::Server(s) => s.labels.iter().any(|l| l.name == name),
+                _ => false,
+            })
+    }
+
+    fn throttle_group_exists(&self, name: &str) -> bool {
+        self.kinds[ResourceKind::Server as usize]
+            .iter()
+            .filter_map(|id| self.resources.get(id))
+            .any(|r| match r {
+                Resource::Server(s) => s.throttle_groups.iter().any(|g| g.name == name),
+                _ => false,
+            })
+    }
+
+    fn serialize_owners(&self) {
+        if let Ok(data) = bincode::serialize(&self.owners) {
+            let path = PathBuf::from(OWNERS_FILE);
+            self.db.send(disk::Request::WriteFile { data, path }).ok();
+        }
+    }
+
+    /// Writes the event log if events were logged since it was last
+    /// written. Called periodically rather than per event, as the whole
+    /// log is rewritten each time.
+    pub fn flush_events(&mut self) {
+        if !mem::replace(&mut self.events_dirty, false) {
+            return;
+        }
+        if let Ok(data) = bincode::serialize(&(self.next_event, &self.events)) {
+            let path = PathBuf::from(EVENTS_FILE);
+            self.db.send(disk::Request::WriteFile { data, path }).ok();
+        }
+    }
+
     fn serialize(&self) {
         let json_data: RpcDiskFmt = self
             .user_data
@@ -746,13 +1780,52 @@ impl Processor {
             .map(|(k, v)| (k.to_owned(), json::to_vec(v).unwrap()))
             .collect();
         if let Ok(data) = bincode::serialize(&json_data) {
-            let path = Path::new(&CONFIG.disk.session[..]).join(USER_DATA_FILE);
-
+            let path = PathBuf::from(USER_DATA_FILE);
             self.db.send(disk::Request::WriteFile { data, path }).ok();
         }
     }
 }
 
+/// Whether a resource is visible to a client, i.e. it
+/// belongs to a torrent attributed to the client's user.
+fn visible(access: &Access, owners: &SHashMap<String>, r: &Resource) -> bool {
+    if !access.restricted {
+        return true;
+    }
+    let tid = match r {
+        Resource::Server(_) => return true,
+        Resource::Torrent(t) => t.id.as_str(),
+        _ => match r.torrent_id() {
+            Some(tid) => tid,
+            None => return true,
+        },
+    };
+    owners.get(tid).is_some() && owners.get(tid) == access.user.as_ref()
+}
+
+/// A resource as sent to a client, with the server's download
+/// token replaced by the one issued to the client.
+pub fn client_view<'a>(access: &Access, r: &'a Resource) -> Cow<'a, Resource> {
+    match r {
+        Resource::Server(s) if access.restricted => Cow::Owned(Resource::Server(Server {
+            download_token: auth::download_token(access),
+            ..s.clone()
+        })),
+        _ => Cow::Borrowed(r),
+    }
+}
+
+/// Whether an event is visible to a client, i.e. it concerns the
+/// server or a torrent attributed to the client's user.
+fn event_visible(access: &Access, owners: &SHashMap<String>, e: &Event) -> bool {
+    match e.torrent_id {
+        Some(ref tid) if access.restricted => {
+            owners.get(tid).is_some() && owners.get(tid) == access.user.as_ref()
+        }
+        _ => true,
+    }
+}
+
 impl Filter {
     pub fn matches(
         &self,
@@ -771,6 +1844,10 @@ impl Filter {
         // Proxy queryable implementation to redirect subresource requests
         impl<'a> rpc_lib::criterion::Queryable for QueryProxy<'a> {
             fn field(&self, field: &str) -> Option<rpc_lib::criterion::Field<'_>> {
+                // Fields of a resource's torrent, e.g. torrent/label
+                if let (true, Some(tid)) = (field.starts_with("torrent/"), self.r.torrent_id()) {
+                    return self.resources.get(tid).and_then(|t| t.field(&field[8..]));
+                }
                 self.r.field(field).map(|f| match f {
                     rpc_lib::criterion::Field::R(k) => {
                         let torrent_resources = self.tidx.get(self.r.id()).unwrap();
@@ -800,3 +1877,105 @@ impl Filter {
         })
     }
 }
+
+/// Parses an uploaded torrent file.
+pub fn parse_torrent(data: &[u8]) -> Result<Info, String> {
+    let b = bencode::decode_ref_with(data, &Info::LIMITS)
+        .map_err(|e| format!("Invalid torrent file uploaded, bad bencoded data: {}.", e))?;
+    Info::from_bencode(&b).map_err(|e| format!("Invalid torrent file uploaded, {}.", e))
+}
+
+/// Addresses peers can reach us at, from our external IPs.
+fn external_addrs(s: &Server) -> Vec<String> {
+    let v4 = s
+        .external_ipv4
+        .iter()
+        .map(|ip| format!("{}:{}", ip, s.port));
+    let v6 = s
+        .external_ipv6
+        .iter()
+        .map(|ip| format!("[{}]:{}", ip, s.port));
+    v4.chain(v6).collect()
+}
+
+/// Magnet link of a torrent by its id, the hex encoded info hash.
+fn magnet_uri(id: &str, name: Option<&str>, trackers: &[&str], peers: &[String]) -> String {
+    let encode = |v: &str| form_urlencoded::byte_serialize(v.as_bytes()).collect::<String>();
+    let mut uri = format!("magnet:?xt=urn:btih:{}", id);
+    if let Some(name) = name {
+        uri.push_str(&format!("&dn={}", encode(name)));
+    }
+    for tracker in trackers {
+        uri.push_str(&format!("&tr={}", encode(tracker)));
+    }
+    for peer in peers {
+        uri.push_str(&format!("&x.pe={}", encode(peer)));
+    }
+    uri
+}
+
+/// Appends a chunk at an offset to the bytes of an upload of a size.
+/// Chunks may overlap those already received, e.g. when resent after
+/// a dropped connection, but can't leave gaps.
+fn add_chunk(buf: &mut Vec<u8>, size: u64, offset: u64, chunk: &[u8]) -> Result<(), String> {
+    let received = buf.len() as u64;
+    if offset > received {
+        return Err(format!(
+            "Chunk at {} leaves a gap after the {} bytes received",
+            offset, received
+        ));
+    }
+    let end = offset + chunk.len() as u64;
+    if end > size {
+        return Err(format!(
+            "Chunk ending at {} exceeds the upload's size of {}",
+            end, size
+        ));
+    }
+    if end > received {
+        buf.extend_from_slice(&chunk[(received - offset) as usize..]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magnet_uri() {
+        let id = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(
+            magnet_uri(id, None, &[], &[]),
+            format!("magnet:?xt=urn:btih:{}", id)
+        );
+        let uri = magnet_uri(
+            id,
+            Some("a b&c"),
+            &["udp://t.example:80/announce"],
+            &["[::1]:16493".to_owned()],
+        );
+        assert_eq!(
+            uri,
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=a+b%26c&tr=udp%3A%2F%2Ft.example%3A80%2Fannounce&x.pe=%5B%3A%3A1%5D%3A16493",
+                id
+            )
+        );
+        let info = Info::from_magnet(&uri).unwrap();
+        assert_eq!(info.name, "a b&c");
+        assert_eq!(info.url_list.len(), 1);
+    }
+
+    #[test]
+    fn test_add_chunk() {
+        let mut buf = Vec::new();
+        add_chunk(&mut buf, 6, 0, b"abc").unwrap();
+        assert!(add_chunk(&mut buf, 6, 4, b"e").is_err());
+        add_chunk(&mut buf, 6, 2, b"cde").unwrap();
+        assert_eq!(buf, b"abcde");
+        assert!(add_chunk(&mut buf, 6, 5, b"fg").is_err());
+        add_chunk(&mut buf, 6, 5, b"f").unwrap();
+        assert_eq!(buf, b"abcdef");
+    }
+}
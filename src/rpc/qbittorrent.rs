@@ -0,0 +1,523 @@
+//! A compatibility layer implementing the most used methods of the
+//! qBittorrent WebUI API (v2), so that software written to manage
+//! qBittorrent can target synapse directly.
+
+use std::time::{Duration, Instant};
+
+use serde_json::{self as json, json};
+use url::form_urlencoded;
+
+use super::api::{self, ApiEndpoint, ApiMethod, ApiRequest, ApiResponse, NewTorrent};
+use super::auth::{self, Access};
+use super::processor::Processor;
+use super::proto::message::CMessage;
+use super::proto::resource::{Label, Resource, ResourceKind, Status, Torrent};
+use super::Message;
+use crate::config;
+use crate::util::hash_to_id;
+
+/// Versions of qBittorrent and its API which are emulated
+const VERSION: &str = "v4.3.9";
+const API_VERSION: &str = "2.8.3";
+/// qBittorrent's representation of an unknown ETA
+const ETA_INFINITE: u64 = 8_640_000;
+/// Failed logins after which logging in is refused for LOGIN_LOCK_SECS
+const MAX_LOGIN_FAILURES: u32 = 5;
+const LOGIN_LOCK_SECS: u64 = 300;
+
+/// Tracks failed logins, so the password can't be guessed by brute force.
+pub struct QBittorrent {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Request parameters, taken from the query string and form body.
+struct Params(Vec<(String, Vec<u8>)>);
+
+impl Params {
+    fn parse(query: &str, content_type: Option<&str>, body: &[u8]) -> Params {
+        let mut params: Vec<_> = form_urlencoded::parse(query.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned().into_bytes()))
+            .collect();
+        let boundary = content_type
+            .filter(|ct| ct.starts_with("multipart/form-data"))
+            .and_then(|ct| {
+                ct.split(';')
+                    .find_map(|p| p.trim().strip_prefix("boundary="))
+            })
+            .map(|b| b.trim_matches('"'));
+        match boundary {
+            Some(boundary) => params.extend(multipart(body, boundary)),
+            None => params.extend(
+                form_urlencoded::parse(body)
+                    .map(|(k, v)| (k.into_owned(), v.into_owned().into_bytes())),
+            ),
+        }
+        Params(params)
+    }
+
+    fn get<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.all(name)
+            .next()
+            .and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.get(name) == Some("true")
+    }
+}
+
+impl QBittorrent {
+    pub fn new() -> QBittorrent {
+        QBittorrent {
+            failures: 0,
+            locked_until: None,
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        processor: &mut Processor,
+        client: usize,
+        req: ApiRequest,
+    ) -> (ApiResponse, Option<Message>) {
+        let (path, params, cross_origin) = match req.endpoint {
+            ApiEndpoint::QBittorrent {
+                path,
+                query,
+                content_type,
+                cross_origin,
+            } => (
+                path,
+                Params::parse(&query, content_type.as_deref(), &req.body),
+                cross_origin,
+            ),
+            _ => unreachable!("only qBittorrent requests are handled here"),
+        };
+        // Pages of other sites must not drive the API through a browser
+        // which has the session cookie, as qBittorrent's CSRF check
+        if cross_origin && req.method == ApiMethod::Post {
+            return (ApiResponse::text(401, "Unauthorized"), None);
+        }
+
+        if path == "auth/login" {
+            return (self.login(params.get("password").unwrap_or("")), None);
+        }
+        let access = match req.access {
+            Some(access) => access,
+            None => return (ApiResponse::text(403, "Forbidden"), None),
+        };
+        let post_only = match path.as_str() {
+            "auth/logout" | "torrents/add" | "torrents/createCategory" | "torrents/delete" => true,
+            p => torrent_action(p).is_some(),
+        };
+        if post_only && req.method != ApiMethod::Post {
+            return (ApiResponse::text(405, "Method Not Allowed"), None);
+        }
+
+        let mut msgs = Vec::new();
+        processor.add_client(client, access.clone());
+        let resp = call(processor, client, &access, &path, &params, &mut msgs);
+        processor.remove_client(client);
+        let msg = match msgs.len() {
+            0 => None,
+            1 => msgs.pop(),
+            _ => Some(Message::Bulk(msgs)),
+        };
+        (resp, msg)
+    }
+
+    /// Sets the session cookie if the password is correct, refusing any
+    /// attempt for a while after too many failed ones.
+    fn login(&mut self, password: &str) -> ApiResponse {
+        if let Some(until) = self.locked_until {
+            if Instant::now() < until {
+                return ApiResponse::text(403, "Too many failed login attempts.");
+            }
+            self.locked_until = None;
+            self.failures = 0;
+        }
+        match auth::authenticate(Some(password)) {
+            Some(_) => {
+                self.failures = 0;
+                ApiResponse::text(200, "Ok.").header(format!(
+                    "Set-Cookie: SID={}; HttpOnly; SameSite=Strict; path=/",
+                    auth::session_id(password)
+                ))
+            }
+            None => {
+                self.failures += 1;
+                if self.failures >= MAX_LOGIN_FAILURES {
+                    info!("Refusing qBittorrent logins after {} failures", self.failures);
+                    self.locked_until =
+                        Some(Instant::now() + Duration::from_secs(LOGIN_LOCK_SECS));
+                }
+                ApiResponse::text(200, "Fails.")
+            }
+        }
+    }
+}
+
+fn call(
+    processor: &mut Processor,
+    client: usize,
+    access: &Access,
+    path: &str,
+    params: &Params,
+    msgs: &mut Vec<Message>,
+) -> ApiResponse {
+    match path {
+        // Sessions are stateless, so logging out only clears the cookie
+        "auth/logout" => {
+            let cookie = "Set-Cookie: SID=; HttpOnly; SameSite=Strict; Max-Age=0; path=/";
+            ApiResponse::text(200, "").header(cookie.to_owned())
+        }
+        "app/version" => ApiResponse::text(200, VERSION),
+        "app/webapiVersion" => ApiResponse::text(200, API_VERSION),
+        "app/preferences" => ApiResponse::json(
+            200,
+            &json!({
+                "save_path": config::settings().directory,
+                "max_ratio_enabled": false,
+                "max_ratio": -1,
+                "max_seeding_time_enabled": false,
+                "max_seeding_time": -1,
+                "queueing_enabled": false,
+            }),
+        ),
+        "torrents/info" => {
+            let filter = params.get("filter").unwrap_or("all");
+            let category = params.get("category");
+            let torrents: Vec<_> = select(processor, access, params.get("hashes"))
+                .into_iter()
+                .filter(|t| matches_filter(t, filter))
+                .filter(|t| category.is_none_or(|c| t.label.as_deref().unwrap_or("") == c))
+                .map(info)
+                .collect();
+            ApiResponse::json(200, &torrents)
+        }
+        "torrents/categories" => {
+            let categories: json::Map<_, _> = labels(processor, access)
+                .iter()
+                .map(|l| {
+                    let category = json!({
+                        "name": l.name,
+                        "savePath": l.path.as_deref().unwrap_or(""),
+                    });
+                    (l.name.clone(), category)
+                })
+                .collect();
+            ApiResponse::json(200, &categories)
+        }
+        "torrents/createCategory" => {
+            let name = match params.get("category") {
+                Some(name) if !name.is_empty() => name.to_owned(),
+                _ => return ApiResponse::text(400, "Invalid category name"),
+            };
+            if labels(processor, access).iter().any(|l| l.name == name) {
+                return ApiResponse::text(409, "Category already exists");
+            }
+            let label = Label {
+                name,
+                path: params
+                    .get("savePath")
+                    .filter(|p| !p.is_empty())
+                    .map(str::to_owned),
+                ..Default::default()
+            };
+            send(
+                processor,
+                client,
+                vec![CMessage::SetLabel { serial: 0, label }],
+                msgs,
+            )
+        }
+        "torrents/add" => {
+            let template = NewTorrent {
+                path: params
+                    .get("savepath")
+                    .filter(|p| !p.is_empty())
+                    .map(str::to_owned),
+                start: !params.flag("paused") && !params.flag("stopped"),
+                label: params
+                    .get("category")
+                    .filter(|c| !c.is_empty())
+                    .map(str::to_owned),
+                ..Default::default()
+            };
+            let mut torrents = Vec::new();
+            for url in params
+                .get("urls")
+                .unwrap_or("")
+                .lines()
+                .map(str::trim)
+                .filter(|u| !u.is_empty())
+            {
+                // Torrent files are not fetched on behalf of clients
+                if !url.starts_with("magnet:") {
+                    return ApiResponse::text(200, "Fails.");
+                }
+                torrents.push(NewTorrent {
+                    uri: Some(url.to_owned()),
+                    ..template.clone()
+                });
+            }
+            for data in params.all("torrents") {
+                torrents.push(NewTorrent {
+                    torrent: Some(base64::encode(data)),
+                    ..template.clone()
+                });
+            }
+            if torrents.is_empty() {
+                return ApiResponse::text(200, "Fails.");
+            }
+            for t in torrents {
+                let msg = match api::add_torrent(processor, client, t) {
+                    Ok(msg) => msg,
+                    Err(_) => return ApiResponse::text(200, "Fails."),
+                };
+                let duplicate = match msg {
+                    Message::Torrent { ref info, .. } => processor
+                        .get_resource(access, &hash_to_id(&info.hash))
+                        .is_some(),
+                    _ => false,
+                };
+                if !duplicate {
+                    msgs.push(msg);
+                }
+            }
+            ApiResponse::text(200, "Ok.")
+        }
+        "torrents/delete" => {
+            let artifacts = params.flag("deleteFiles");
+            let reqs = select(processor, access, params.get("hashes"))
+                .into_iter()
+                .map(|t| CMessage::RemoveResource {
+                    serial: 0,
+                    id: t.id.clone(),
+                    artifacts: Some(artifacts),
+                })
+                .collect();
+            send(processor, client, reqs, msgs)
+        }
+        p => match torrent_action(p) {
+            Some(action) => {
+                let reqs = select(processor, access, params.get("hashes"))
+                    .into_iter()
+                    .map(|t| action(t.id.clone()))
+                    .collect();
+                send(processor, client, reqs, msgs)
+            }
+            None => ApiResponse::text(404, "Not Found"),
+        },
+    }
+}
+
+/// Returns the request for the action performed on each torrent by a
+/// torrents/* method, if it is one.
+fn torrent_action(path: &str) -> Option<fn(String) -> CMessage> {
+    match path.strip_prefix("torrents/")? {
+        // qBittorrent 5 renamed pausing and resuming to stopping and starting
+        "pause" | "stop" => Some(|id| CMessage::PauseTorrent { serial: 0, id }),
+        "resume" | "start" => Some(|id| CMessage::ResumeTorrent { serial: 0, id }),
+        "recheck" => Some(|id| CMessage::ValidateResources {
+            serial: 0,
+            ids: vec![id],
+        }),
+        _ => None,
+    }
+}
+
+/// Passes requests to the processor, stopping at the first error.
+fn send(
+    processor: &mut Processor,
+    client: usize,
+    reqs: Vec<CMessage>,
+    msgs: &mut Vec<Message>,
+) -> ApiResponse {
+    for req in reqs {
+        let (resp, rmsg) = processor.handle_client(client, req);
+        if let Some(err) = api::error(resp) {
+            return ApiResponse::text(err.status, err.reason);
+        }
+        msgs.extend(rmsg);
+    }
+    ApiResponse::text(200, "")
+}
+
+/// Returns the torrents referred to by a hashes parameter, which is
+/// either "all" or a list of hashes separated by |.
+fn select<'a>(processor: &'a Processor, access: &Access, hashes: Option<&str>) -> Vec<&'a Torrent> {
+    let torrents = processor
+        .get_resources(access, ResourceKind::Torrent)
+        .into_iter()
+        .filter_map(|r| match r {
+            Resource::Torrent(t) => Some(t),
+            _ => None,
+        });
+    match hashes {
+        None | Some("all") => torrents.collect(),
+        Some(hashes) => torrents
+            .filter(|t| hashes.split('|').any(|h| t.id.eq_ignore_ascii_case(h)))
+            .collect(),
+    }
+}
+
+fn labels<'a>(processor: &'a Processor, access: &Access) -> &'a [Label] {
+    match processor.get_resources(access, ResourceKind::Server).pop() {
+        Some(Resource::Server(s)) => &s.labels,
+        _ => &[],
+    }
+}
+
+fn matches_filter(t: &Torrent, filter: &str) -> bool {
+    let complete = t.progress >= 1.;
+    match filter {
+        "downloading" => !complete,
+        "seeding" | "completed" => complete,
+        "paused" | "stopped" => t.status == Status::Paused,
+        "resumed" | "running" => t.status != Status::Paused,
+        "active" => t.rate_up > 0 || t.rate_down > 0,
+        "inactive" => t.rate_up == 0 && t.rate_down == 0,
+        "stalled" => t.status == Status::Idle || t.status == Status::Stalled,
+        "errored" => t.status == Status::Error,
+        _ => true,
+    }
+}
+
+/// Converts a torrent to qBittorrent's representation.
+fn info(t: &Torrent) -> json::Value {
+    let size = t.size.unwrap_or(0);
+    let left = (size as f64 * (1. - f64::from(t.progress))) as u64;
+    let name = t.name.as_ref().unwrap_or(&t.id);
+    json!({
+        "hash": t.id.to_lowercase(),
+        "name": name,
+        "state": state(t),
+        "size": size,
+        "total_size": size,
+        "progress": t.progress,
+        "amount_left": left,
+        "dlspeed": t.rate_down,
+        "upspeed": t.rate_up,
+        "downloaded": t.transferred_down,
+        "uploaded": t.transferred_up,
+        "ratio": t.transferred_up as f64 / size.max(1) as f64,
+        "eta": match t.rate_down {
+            0 => ETA_INFINITE,
+            rate => left / rate,
+        },
+        "category": t.label.as_deref().unwrap_or(""),
+        "tags": "",
+        "save_path": t.path,
+        "content_path": format!("{}/{}", t.path.trim_end_matches('/'), name),
+        "added_on": t.created.timestamp(),
+        "last_activity": t.modified.timestamp(),
+        "num_leechs": t.peers,
+        "tracker": t.tracker_urls.first().map(String::as_str).unwrap_or(""),
+        // -2 defers to the global limit, which is always unlimited
+        "ratio_limit": t.seed_ratio.map_or(-2., f64::from),
+        "seeding_time_limit": t.seed_time.map_or(-2, |s| (s / 60) as i64),
+    })
+}
+
+fn state(t: &Torrent) -> &'static str {
+    let complete = t.progress >= 1.;
+    match t.status {
+        Status::Error => "error",
+        Status::Pending => "queuedDL",
+        Status::Magnet => "metaDL",
+        Status::Leeching => "downloading",
+        Status::Seeding => "uploading",
+        Status::Paused if complete => "pausedUP",
+        Status::Paused => "pausedDL",
+        Status::Idle if complete => "stalledUP",
+        Status::Idle | Status::Stalled => "stalledDL",
+        Status::Hashing if complete => "checkingUP",
+        Status::Hashing => "checkingDL",
+    }
+}
+
+/// Parses the named fields of a multipart/form-data body.
+fn multipart(body: &[u8], boundary: &str) -> Vec<(String, Vec<u8>)> {
+    let delim = format!("--{}", boundary).into_bytes();
+    let mut fields = Vec::new();
+    let mut rest = match find(body, &delim) {
+        Some(idx) => &body[idx + delim.len()..],
+        None => return fields,
+    };
+    // Each part is terminated by a CRLF before the next delimiter, and
+    // the final delimiter is followed by --
+    while let Some(part) = rest.strip_prefix(b"\r\n") {
+        let end = match find(part, &delim) {
+            Some(end) => end,
+            None => break,
+        };
+        let (headers, content) = match find(&part[..end], b"\r\n\r\n") {
+            Some(idx) => (&part[..idx], &part[idx + 4..end]),
+            None => break,
+        };
+        let name = std::str::from_utf8(headers)
+            .ok()
+            .and_then(|h| {
+                h.lines()
+                    .find(|l| l.to_lowercase().starts_with("content-disposition:"))
+            })
+            .and_then(|h| h.split(';').find_map(|p| p.trim().strip_prefix("name=")))
+            .map(|n| n.trim_matches('"').to_owned());
+        if let Some(name) = name {
+            let content = content.strip_suffix(b"\r\n").unwrap_or(content);
+            fields.push((name, content.to_vec()));
+        }
+        rest = &part[end + delim.len()..];
+    }
+    fields
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multipart() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"urls\"\r\n\r\n\
+            magnet:?xt=1\nmagnet:?xt=2\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"torrents\"; filename=\"a.torrent\"\r\n\
+            Content-Type: application/x-bittorrent\r\n\r\n\
+            d4:infoe\r\n\
+            --XyZ--\r\n";
+        let params = Params::parse(
+            "category=tv",
+            Some("multipart/form-data; boundary=XyZ"),
+            body,
+        );
+        assert_eq!(params.get("urls"), Some("magnet:?xt=1\nmagnet:?xt=2"));
+        assert_eq!(
+            params.all("torrents").collect::<Vec<_>>(),
+            vec![&b"d4:infoe"[..]]
+        );
+        assert_eq!(params.get("category"), Some("tv"));
+        assert_eq!(params.get("savepath"), None);
+    }
+
+    #[test]
+    fn test_urlencoded() {
+        let params = Params::parse("", None, b"hashes=a%7Cb&deleteFiles=true");
+        assert_eq!(params.get("hashes"), Some("a|b"));
+        assert!(params.flag("deleteFiles"));
+        assert!(!params.flag("paused"));
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fs, io};
+
+use chrono::{Duration, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{ClientHello, ResolvesServerCert};
+
+use crate::util::io_err_val;
+use crate::CONFIG;
+
+const SELF_SIGNED_CERT: &str = "rpc_cert.pem";
+const SELF_SIGNED_KEY: &str = "rpc_key.pem";
+
+const OID_ECDSA_SHA256: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+
+/// Serves the certificate matching the SNI hostname of
+/// a connection, falling back to the default certificate.
+struct SniResolver {
+    default: Option<CertifiedKey>,
+    hosts: HashMap<String, CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<CertifiedKey> {
+        hello
+            .server_name()
+            .and_then(|name| {
+                let name: &str = name.into();
+                self.hosts.get(&name.to_lowercase())
+            })
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Builds the TLS configuration of the RPC server, returning None
+/// if connections should not be encrypted.
+pub fn server_config() -> io::Result<Option<Arc<rustls::ServerConfig>>> {
+    let rpc = &CONFIG.rpc;
+    let default = match (rpc.ssl_cert.as_str(), rpc.ssl_key.as_str()) {
+        ("", "") if rpc.ssl_self_signed => {
+            let (cert, key) = self_signed()?;
+            Some(certified_key(&cert, &key)?)
+        }
+        ("", "") => None,
+        (cert_file, key_file) => Some(certified_key(cert_file, key_file)?),
+    };
+    let mut hosts = HashMap::new();
+    for (host, c) in &rpc.ssl_sni {
+        hosts.insert(host.to_lowercase(), certified_key(&c.cert, &c.key)?);
+    }
+    if default.is_none() && hosts.is_empty() {
+        info!("RPC SSL parameters not specified, using insecure connections!");
+        return Ok(None);
+    }
+
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config.cert_resolver = Arc::new(SniResolver { default, hosts });
+    info!("SSL initialized!");
+    Ok(Some(Arc::new(config)))
+}
+
+fn certified_key(cert_file: &str, key_file: &str) -> io::Result<CertifiedKey> {
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| io_err_val("Unsupported SSL private key type"))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
+    let certfile = fs::File::open(filename)?;
+    let mut reader = io::BufReader::new(certfile);
+    Ok(rustls::internal::pemfile::certs(&mut reader).expect("Invalid cert file"))
+}
+
+fn load_private_key(filename: &str) -> io::Result<rustls::PrivateKey> {
+    let rsa_keys = {
+        let keyfile = fs::File::open(filename)?;
+        let mut reader = io::BufReader::new(keyfile);
+        rustls::internal::pemfile::rsa_private_keys(&mut reader).expect("Invalid private key")
+    };
+
+    let pkcs8_keys = {
+        let keyfile = fs::File::open(filename)?;
+        let mut reader = io::BufReader::new(keyfile);
+        rustls::internal::pemfile::pkcs8_private_keys(&mut reader).expect("Invalid private key")
+    };
+
+    // prefer to load pkcs8 keys
+    if !pkcs8_keys.is_empty() {
+        Ok(pkcs8_keys[0].clone())
+    } else {
+        assert!(
+            !rsa_keys.is_empty(),
+            "SSL private key must be non empty and decrypted!"
+        );
+        Ok(rsa_keys[0].clone())
+    }
+}
+
+/// Returns the paths of the self signed certificate and key in the
+/// session directory, generating them if needed or if the certificate
+/// isn't valid for the configured hostnames.
+fn self_signed() -> io::Result<(String, String)> {
+    let sd = Path::new(&CONFIG.disk.session);
+    let cert_path = sd.join(SELF_SIGNED_CERT);
+    let key_path = sd.join(SELF_SIGNED_KEY);
+    let current = key_path.exists()
+        && fs::read(&cert_path)
+            .ok()
+            .and_then(|pem| rustls::internal::pemfile::certs(&mut &pem[..]).ok())
+            .and_then(|certs| certs.into_iter().next())
+            .map(|cert| has_hostnames(&cert.0, &CONFIG.rpc.ssl_hostnames))
+            .unwrap_or(false);
+    if !current {
+        info!("Generating self signed RPC certificate");
+        let (cert, key) = generate_self_signed(&CONFIG.rpc.ssl_hostnames)?;
+        fs::create_dir_all(sd)?;
+        fs::write(&cert_path, pem("CERTIFICATE", &cert))?;
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&key_path)?;
+        f.write_all(pem("PRIVATE KEY", &key).as_bytes())?;
+    }
+    Ok((
+        cert_path.to_string_lossy().into_owned(),
+        key_path.to_string_lossy().into_owned(),
+    ))
+}
+
+/// Generates a DER encoded self signed ECDSA P-256 certificate,
+/// valid for the given hostnames, and its PKCS8 private key.
+pub fn generate_self_signed(hostnames: &[String]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let rng = SystemRandom::new();
+    fn err<E>(_: E) -> io::Error {
+        io_err_val("Failed to generate self signed certificate")
+    }
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).map_err(err)?;
+    let key_pair =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).map_err(err)?;
+
+    let mut serial = [0u8; 16];
+    rng.fill(&mut serial).map_err(err)?;
+    // Serial numbers must be positive
+    serial[0] &= 0x7F;
+
+    let name = der(
+        0x30,
+        &der(
+            0x31,
+            &der(
+                0x30,
+                &[der(0x06, OID_COMMON_NAME), der(0x0C, b"synapse")].concat(),
+            ),
+        ),
+    );
+    let now = Utc::now();
+    let validity = der(
+        0x30,
+        &[
+            der(
+                0x17,
+                (now - Duration::days(1))
+                    .format("%y%m%d%H%M%SZ")
+                    .to_string()
+                    .as_bytes(),
+            ),
+            der(
+                0x17,
+                (now + Duration::days(3650))
+                    .format("%y%m%d%H%M%SZ")
+                    .to_string()
+                    .as_bytes(),
+            ),
+        ]
+        .concat(),
+    );
+    let spki = der(
+        0x30,
+        &[
+            der(
+                0x30,
+                &[der(0x06, OID_EC_PUBLIC_KEY), der(0x06, OID_PRIME256V1)].concat(),
+            ),
+            bit_string(key_pair.public_key().as_ref()),
+        ]
+        .concat(),
+    );
+    let san = subject_alt_name(hostnames);
+    let alg = der(0x30, &der(0x06, OID_ECDSA_SHA256));
+    let tbs = der(
+        0x30,
+        &[
+            der(0xA0, &der(0x02, &[2])),
+            der(0x02, &serial),
+            alg.clone(),
+            name.clone(),
+            validity,
+            name,
+            spki,
+            der(0xA3, &der(0x30, &san)),
+        ]
+        .concat(),
+    );
+    let sig = key_pair.sign(&rng, &tbs).map_err(err)?;
+    let cert = der(0x30, &[tbs, alg, bit_string(sig.as_ref())].concat());
+    Ok((cert, pkcs8.as_ref().to_vec()))
+}
+
+/// Encodes the subject alternative name extension of a certificate
+/// valid for the given hostnames.
+fn subject_alt_name(hostnames: &[String]) -> Vec<u8> {
+    let alt_names: Vec<u8> = hostnames
+        .iter()
+        .flat_map(|h| match h.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => der(0x87, &ip.octets()),
+            Ok(IpAddr::V6(ip)) => der(0x87, &ip.octets()),
+            Err(_) => der(0x82, h.as_bytes()),
+        })
+        .collect();
+    der(
+        0x30,
+        &[
+            der(0x06, OID_SUBJECT_ALT_NAME),
+            der(0x04, &der(0x30, &alt_names)),
+        ]
+        .concat(),
+    )
+}
+
+/// Whether a certificate we generated is valid for exactly the given
+/// hostnames, i.e. contains the same encoded extension.
+fn has_hostnames(cert: &[u8], hostnames: &[String]) -> bool {
+    let san = subject_alt_name(hostnames);
+    cert.windows(san.len()).any(|w| w == &san[..])
+}
+
+/// Encodes a DER TLV.
+fn der(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+fn bit_string(data: &[u8]) -> Vec<u8> {
+    der(0x03, &[&[0u8][..], data].concat())
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let mut out = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        out.push_str(&String::from_utf8_lossy(line));
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_der_len() {
+        assert_eq!(der(0x04, &[1, 2]), vec![0x04, 2, 1, 2]);
+        let long = der(0x04, &[0u8; 300]);
+        assert_eq!(&long[..4], &[0x04, 0x82, 0x01, 0x2C]);
+        assert_eq!(long.len(), 304);
+    }
+
+    #[test]
+    fn test_self_signed_loads() {
+        let hosts = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+        let (cert, key) = generate_self_signed(&hosts).unwrap();
+        assert!(sign::any_supported_type(&rustls::PrivateKey(key)).is_ok());
+
+        let pem = pem("CERTIFICATE", &cert);
+        let certs = rustls::internal::pemfile::certs(&mut pem.as_bytes()).unwrap();
+        assert_eq!(certs, vec![rustls::Certificate(cert)]);
+    }
+
+    #[test]
+    fn test_has_hostnames() {
+        let hosts = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+        let (cert, _) = generate_self_signed(&hosts).unwrap();
+        assert!(has_hostnames(&cert, &hosts));
+        assert!(!has_hostnames(&cert, &hosts[..1]));
+        assert!(!has_hostnames(&cert, &["example.com".to_owned()]));
+    }
+}
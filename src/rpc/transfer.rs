@@ -3,24 +3,29 @@ use std::time;
 
 use sstream::SStream;
 
+use super::api::ApiRequest;
+use super::processor::TransferKind;
 use super::proto::message::Error;
-use super::EMPTY_HTTP_RESP;
+use super::{AddOptions, EMPTY_HTTP_RESP};
 
 use crate::util::{aread, UHashMap, IOR};
 
 pub struct Transfers {
     torrents: UHashMap<TorrentTx>,
+    api: UHashMap<ApiTx>,
 }
 
 pub enum TransferResult {
     Torrent {
         conn: SStream,
-        start: bool,
-        import: bool,
         data: Vec<u8>,
-        path: Option<String>,
         client: usize,
         serial: u64,
+        options: AddOptions,
+    },
+    Api {
+        conn: SStream,
+        req: ApiRequest,
     },
     Error {
         conn: SStream,
@@ -36,9 +41,14 @@ struct TorrentTx {
     serial: u64,
     pos: usize,
     buf: Vec<u8>,
-    start: bool,
-    import: bool,
-    path: Option<String>,
+    options: AddOptions,
+    last_action: time::Instant,
+}
+
+struct ApiTx {
+    conn: SStream,
+    req: ApiRequest,
+    pos: usize,
     last_action: time::Instant,
 }
 
@@ -48,9 +58,12 @@ impl Transfers {
     pub fn new() -> Transfers {
         Transfers {
             torrents: UHashMap::default(),
+            api: UHashMap::default(),
         }
     }
 
+    /// Reads the remainder of an uploaded torrent file, of which `data`
+    /// has already been received.
     pub fn add_torrent(
         &mut self,
         id: usize,
@@ -58,11 +71,12 @@ impl Transfers {
         serial: u64,
         conn: SStream,
         mut data: Vec<u8>,
-        path: Option<String>,
-        size: u64,
-        start: bool,
-        import: bool,
+        kind: TransferKind,
     ) {
+        let (size, options) = match kind {
+            TransferKind::UploadTorrent { size, options } => (size, options),
+            _ => unreachable!(),
+        };
         let pos = data.len();
         // Given that this requires an authenticated connection
         // we can safely assume this won't be abused
@@ -75,19 +89,50 @@ impl Transfers {
                 conn,
                 pos,
                 buf: data,
-                path,
-                start,
-                import,
+                options,
+                last_action: time::Instant::now(),
+            },
+        );
+    }
+
+    /// Reads the remainder of an API request's body, of which `data`
+    /// has already been received.
+    pub fn add_api(&mut self, id: usize, conn: SStream, mut req: ApiRequest, data: Vec<u8>) {
+        let pos = data.len();
+        req.body = data;
+        req.body.resize(req.size, 0u8);
+        self.api.insert(
+            id,
+            ApiTx {
+                conn,
+                req,
+                pos,
                 last_action: time::Instant::now(),
             },
         );
     }
 
     pub fn contains(&self, id: usize) -> bool {
-        self.torrents.contains_key(&id)
+        self.torrents.contains_key(&id) || self.api.contains_key(&id)
     }
 
     pub fn ready(&mut self, id: usize) -> TransferResult {
+        if let Some(tx) = self.api.get_mut(&id) {
+            return match tx.readable() {
+                Ok(true) => {
+                    let tx = self.api.remove(&id).unwrap();
+                    TransferResult::Api {
+                        conn: tx.conn,
+                        req: tx.req,
+                    }
+                }
+                Ok(false) => TransferResult::Incomplete,
+                Err(_) => {
+                    self.api.remove(&id);
+                    TransferResult::Incomplete
+                }
+            };
+        }
         match self.torrents.get_mut(&id).map(|tx| tx.readable()) {
             Some(Ok(true)) => {
                 let mut tx = self.torrents.remove(&id).unwrap();
@@ -98,11 +143,9 @@ impl Transfers {
                 TransferResult::Torrent {
                     conn: tx.conn,
                     data: tx.buf,
-                    path: tx.path,
                     client: tx.client,
                     serial: tx.serial,
-                    start: tx.start,
-                    import: tx.import,
+                    options: tx.options,
                 }
             }
             Some(Ok(false)) => TransferResult::Incomplete,
@@ -122,6 +165,7 @@ impl Transfers {
     }
 
     pub fn cleanup(&mut self) -> Vec<(SStream, usize, Error)> {
+        self.api.retain(|_, tx| !tx.timed_out());
         let mut res = Vec::new();
         let ids: Vec<usize> = self
             .torrents
@@ -162,3 +206,22 @@ impl TorrentTx {
         self.last_action.elapsed().as_secs() > CONN_TIMEOUT
     }
 }
+
+impl ApiTx {
+    pub fn readable(&mut self) -> Result<bool, &'static str> {
+        self.last_action = time::Instant::now();
+        loop {
+            match aread(&mut self.req.body[self.pos..], &mut self.conn) {
+                IOR::Complete => return Ok(true),
+                IOR::Incomplete(a) => self.pos += a,
+                IOR::Blocked => return Ok(false),
+                IOR::EOF => return Err("Unexpected EOF!"),
+                IOR::Err(_) => return Err("IO error!"),
+            }
+        }
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.last_action.elapsed().as_secs() > CONN_TIMEOUT
+    }
+}
@@ -0,0 +1,403 @@
+//! A compatibility shim which speaks the Transmission RPC protocol, mapped
+//! onto synapse's resources, so that existing Transmission clients and
+//! integrations can be used unchanged.
+
+use chrono::Utc;
+use serde_json::{self as json, json};
+
+use super::api::{self, ApiEndpoint, ApiMethod, ApiRequest, ApiResponse, NewTorrent};
+use super::auth::Access;
+use super::processor::Processor;
+use super::proto::message::CMessage;
+use super::proto::resource::{
+    ErrorKind, Resource, ResourceKind, Server, Status, Torrent, TorrentState,
+};
+use super::Message;
+use crate::config;
+use crate::util::{hash_to_id, random_string, SHashMap};
+
+/// Version of the Transmission RPC protocol which is emulated
+const RPC_VERSION: u64 = 15;
+const VERSION: &str = "2.94 (synapse)";
+
+/// Transmission identifies torrents by integer IDs, which are assigned
+/// to torrents as they are first seen and are only valid for the session.
+pub struct Transmission {
+    session: String,
+    ids: SHashMap<u64>,
+    next_id: u64,
+}
+
+#[derive(Deserialize)]
+struct TrRequest {
+    method: String,
+    #[serde(default)]
+    arguments: json::Value,
+    tag: Option<json::Value>,
+}
+
+#[derive(Serialize)]
+struct TrResponse {
+    result: String,
+    arguments: json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<json::Value>,
+}
+
+impl Transmission {
+    pub fn new() -> Transmission {
+        Transmission {
+            session: random_string(48),
+            ids: SHashMap::default(),
+            next_id: 1,
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        processor: &mut Processor,
+        client: usize,
+        req: ApiRequest,
+    ) -> (ApiResponse, Option<Message>) {
+        let header = format!("X-Transmission-Session-Id: {}", self.session);
+        match req.endpoint {
+            ApiEndpoint::Transmission { ref session }
+                if session.as_ref() == Some(&self.session) => {}
+            _ => {
+                return (
+                    ApiResponse::error(409, "Invalid session ID").header(header),
+                    None,
+                )
+            }
+        }
+        if req.method != ApiMethod::Post {
+            return (
+                ApiResponse::error(405, "Unsupported method").header(header),
+                None,
+            );
+        }
+        let r: TrRequest = match json::from_slice(&req.body) {
+            Ok(r) => r,
+            Err(e) => return (ApiResponse::error(400, e.to_string()).header(header), None),
+        };
+
+        let access = match req.access {
+            Some(access) => access,
+            None => return (ApiResponse::error(401, "Unauthorized"), None),
+        };
+
+        self.prune(processor);
+        let mut msgs = Vec::new();
+        processor.add_client(client, access.clone());
+        let res = self.call(
+            processor,
+            client,
+            &access,
+            &r.method,
+            &r.arguments,
+            &mut msgs,
+        );
+        processor.remove_client(client);
+        let (result, arguments) = match res {
+            Ok(args) => ("success".to_owned(), args),
+            Err(reason) => (reason, json!({})),
+        };
+        let resp = TrResponse {
+            result,
+            arguments,
+            tag: r.tag,
+        };
+        let msg = match msgs.len() {
+            0 => None,
+            1 => msgs.pop(),
+            _ => Some(Message::Bulk(msgs)),
+        };
+        (ApiResponse::json(200, &resp).header(header), msg)
+    }
+
+    fn call(
+        &mut self,
+        processor: &mut Processor,
+        client: usize,
+        access: &Access,
+        method: &str,
+        args: &json::Value,
+        msgs: &mut Vec<Message>,
+    ) -> Result<json::Value, String> {
+        match method {
+            "session-get" => {
+                let server = server(processor, access)?;
+                let limit = |t: Option<i64>| t.filter(|&t| t >= 0);
+                Ok(json!({
+                    "version": VERSION,
+                    "rpc-version": RPC_VERSION,
+                    "rpc-version-minimum": 1,
+                    "session-id": self.session,
+                    "download-dir": config::settings().directory,
+                    "speed-limit-up": limit(server.throttle_up).unwrap_or(0) / 1024,
+                    "speed-limit-up-enabled": limit(server.throttle_up).is_some(),
+                    "speed-limit-down": limit(server.throttle_down).unwrap_or(0) / 1024,
+                    "speed-limit-down-enabled": limit(server.throttle_down).is_some(),
+                    "seedRatioLimit": 0,
+                    "seedRatioLimited": false,
+                }))
+            }
+            "session-stats" => {
+                let server = server(processor, access)?;
+                let torrents = processor.get_resources(access, ResourceKind::Torrent);
+                let paused = torrents
+                    .iter()
+                    .filter(|r| match r {
+                        Resource::Torrent(t) => t.status == Status::Paused,
+                        _ => false,
+                    })
+                    .count();
+                let session = Utc::now().signed_duration_since(server.started);
+                Ok(json!({
+                    "torrentCount": torrents.len(),
+                    "activeTorrentCount": torrents.len() - paused,
+                    "pausedTorrentCount": paused,
+                    "downloadSpeed": server.rate_down,
+                    "uploadSpeed": server.rate_up,
+                    "cumulative-stats": {
+                        "uploadedBytes": server.transferred_up,
+                        "downloadedBytes": server.transferred_down,
+                        "secondsActive": server.uptime,
+                        "filesAdded": torrents.len(),
+                        "sessionCount": 1,
+                    },
+                    "current-stats": {
+                        "uploadedBytes": server.ses_transferred_up,
+                        "downloadedBytes": server.ses_transferred_down,
+                        "secondsActive": session.num_seconds().max(0),
+                        "filesAdded": 0,
+                        "sessionCount": 1,
+                    },
+                }))
+            }
+            "torrent-get" => {
+                let fields: Vec<&str> = args["fields"]
+                    .as_array()
+                    .map(|f| f.iter().filter_map(|f| f.as_str()).collect())
+                    .unwrap_or_default();
+                let torrents: Vec<_> = self
+                    .select(processor, access, &args["ids"])
+                    .into_iter()
+                    .map(|(id, t)| {
+                        let obj: json::Map<_, _> = fields
+                            .iter()
+                            .filter_map(|&f| field(id, t, f).map(|v| (f.to_owned(), v)))
+                            .collect();
+                        json::Value::Object(obj)
+                    })
+                    .collect();
+                let mut resp = json!({ "torrents": torrents });
+                if args["ids"] == "recently-active" {
+                    resp["removed"] = json!([]);
+                }
+                Ok(resp)
+            }
+            "torrent-add" => {
+                let mut t = NewTorrent {
+                    path: args["download-dir"].as_str().map(str::to_owned),
+                    start: !args["paused"].as_bool().unwrap_or(false),
+                    label: args["labels"][0].as_str().map(str::to_owned),
+                    ..Default::default()
+                };
+                match (args["metainfo"].as_str(), args["filename"].as_str()) {
+                    (Some(metainfo), _) => t.torrent = Some(metainfo.to_owned()),
+                    (None, Some(f)) if f.starts_with("magnet:") => t.uri = Some(f.to_owned()),
+                    (None, Some(_)) => return Err("only magnet filenames are supported".to_owned()),
+                    (None, None) => return Err("no filename or metainfo specified".to_owned()),
+                }
+                let msg = api::add_torrent(processor, client, t).map_err(|f| f.reason)?;
+                let (hash, name) = match msg {
+                    Message::Torrent { ref info, .. } => {
+                        let hash = hash_to_id(&info.hash);
+                        let name = if info.name.is_empty() {
+                            hash.clone()
+                        } else {
+                            info.name.clone()
+                        };
+                        (hash, name)
+                    }
+                    _ => unreachable!(),
+                };
+                let added = json!({
+                    "id": self.id(&hash),
+                    "name": name,
+                    "hashString": hash.to_lowercase(),
+                });
+                if processor.get_resource(access, &hash).is_some() {
+                    Ok(json!({ "torrent-duplicate": added }))
+                } else {
+                    msgs.push(msg);
+                    Ok(json!({ "torrent-added": added }))
+                }
+            }
+            "torrent-remove" | "torrent-start" | "torrent-start-now" | "torrent-stop"
+            | "torrent-verify" => {
+                let ids: Vec<String> = self
+                    .select(processor, access, &args["ids"])
+                    .into_iter()
+                    .map(|(_, t)| t.id.clone())
+                    .collect();
+                let artifacts = args["delete-local-data"].as_bool().unwrap_or(false);
+                let reqs: Vec<_> = match method {
+                    "torrent-verify" => vec![CMessage::ValidateResources { serial: 0, ids }],
+                    _ => ids
+                        .into_iter()
+                        .map(|id| match method {
+                            "torrent-remove" => CMessage::RemoveResource {
+                                serial: 0,
+                                id,
+                                artifacts: Some(artifacts),
+                            },
+                            "torrent-stop" => CMessage::PauseTorrent { serial: 0, id },
+                            _ => CMessage::ResumeTorrent { serial: 0, id },
+                        })
+                        .collect(),
+                };
+                for req in reqs {
+                    let (resp, rmsg) = processor.handle_client(client, req);
+                    if let Some(err) = api::error(resp) {
+                        return Err(err.reason);
+                    }
+                    msgs.extend(rmsg);
+                }
+                Ok(json!({}))
+            }
+            _ => Err("method name not recognized".to_owned()),
+        }
+    }
+
+    /// Returns the torrents referred to by a Transmission ids argument,
+    /// along with their integer IDs.
+    fn select<'a>(
+        &mut self,
+        processor: &'a Processor,
+        access: &Access,
+        ids: &json::Value,
+    ) -> Vec<(u64, &'a Torrent)> {
+        let torrents = processor
+            .get_resources(access, ResourceKind::Torrent)
+            .into_iter()
+            .filter_map(|r| match r {
+                Resource::Torrent(t) => Some(t),
+                _ => None,
+            })
+            .map(|t| (self.id(&t.id), t));
+        let matches = |(id, t): &(u64, &Torrent), v: &json::Value| match v {
+            json::Value::Number(n) => n.as_u64() == Some(*id),
+            json::Value::String(s) => t.id.eq_ignore_ascii_case(s),
+            _ => false,
+        };
+        match ids {
+            json::Value::Array(ids) => torrents
+                .filter(|t| ids.iter().any(|v| matches(t, v)))
+                .collect(),
+            json::Value::Number(_) => torrents.filter(|t| matches(t, ids)).collect(),
+            // Both recently active and unspecified ids select every torrent
+            json::Value::String(s) if s != "recently-active" => {
+                torrents.filter(|t| matches(t, ids)).collect()
+            }
+            _ => torrents.collect(),
+        }
+    }
+
+    fn id(&mut self, hash: &str) -> u64 {
+        if let Some(&id) = self.ids.get(hash) {
+            return id;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(hash.to_owned(), id);
+        id
+    }
+
+    /// Forgets the IDs of torrents which were removed. Their IDs aren't
+    /// reused, so they can't come to refer to other torrents.
+    fn prune(&mut self, processor: &Processor) {
+        let admin = Access::admin();
+        self.ids
+            .retain(|hash, _| processor.get_resource(&admin, hash).is_some());
+    }
+}
+
+fn server<'a>(processor: &'a Processor, access: &Access) -> Result<&'a Server, String> {
+    processor
+        .get_resources(access, ResourceKind::Server)
+        .pop()
+        .and_then(|r| match r {
+            Resource::Server(s) => Some(s),
+            _ => None,
+        })
+        .ok_or_else(|| "server unavailable".to_owned())
+}
+
+/// Converts a field of a torrent to its Transmission representation.
+fn field(id: u64, t: &Torrent, name: &str) -> Option<json::Value> {
+    let size = t.size.unwrap_or(0);
+    let wanted = t.wanted_size.unwrap_or(0);
+    let left = (wanted as f64 * (1. - f64::from(t.progress))) as u64;
+    Some(match name {
+        "id" => json!(id),
+        "hashString" => json!(t.id.to_lowercase()),
+        "name" => json!(t.name.as_ref().unwrap_or(&t.id)),
+        "status" => json!(status(t)),
+        // Tracker errors are 2 and local errors 3
+        "error" => json!(match t.error_kind {
+            Some(ErrorKind::TrackerFailure) => 2,
+            Some(_) => 3,
+            None => 0,
+        }),
+        "errorString" => json!(t.error.as_deref().unwrap_or("")),
+        "downloadDir" => json!(t.path),
+        "totalSize" => json!(size),
+        "sizeWhenDone" => json!(wanted),
+        "leftUntilDone" => json!(left),
+        "percentDone" => json!(t.progress),
+        "rateDownload" => json!(t.rate_down),
+        "rateUpload" => json!(t.rate_up),
+        "downloadedEver" => json!(t.transferred_down),
+        "uploadedEver" => json!(t.transferred_up),
+        "uploadRatio" => json!(t.transferred_up as f64 / size.max(1) as f64),
+        "eta" => json!(t.eta.map(|e| e as i64).unwrap_or(-1)),
+        "isFinished" => json!(t.progress >= 1. && t.status == Status::Paused),
+        "addedDate" => json!(t.created.timestamp()),
+        "activityDate" => json!(t.modified.timestamp()),
+        "peersConnected" => json!(t.peers),
+        "isPrivate" => json!(t.private),
+        "comment" => json!(t.comment.as_deref().unwrap_or("")),
+        "creator" => json!(t.creator.as_deref().unwrap_or("")),
+        "fileCount" => json!(t.files.unwrap_or(0)),
+        "pieceCount" => json!(t.pieces.unwrap_or(0)),
+        "pieceSize" => json!(t.piece_size.unwrap_or(0)),
+        "labels" => json!(t.label.iter().collect::<Vec<_>>()),
+        "seedRatioLimit" => json!(t.seed_ratio.unwrap_or(0.)),
+        // Synapse has no global seed ratio, so torrents are either
+        // limited individually or unlimited
+        "seedRatioMode" => json!(if t.seed_ratio.is_some() { 1 } else { 2 }),
+        "trackers" => json!(t
+            .tracker_urls
+            .iter()
+            .enumerate()
+            .map(|(i, url)| json!({ "id": i, "announce": url, "tier": 0 }))
+            .collect::<Vec<_>>()),
+        _ => return None,
+    })
+}
+
+/// Transmission's status codes: stopped, queued to check, checking, queued
+/// to download, downloading, queued to seed and seeding.
+fn status(t: &Torrent) -> u8 {
+    match t.state {
+        TorrentState::Paused | TorrentState::Error => 0,
+        TorrentState::CheckingQueued => 1,
+        TorrentState::Checking => 2,
+        TorrentState::Queued => 3,
+        TorrentState::DownloadingMetadata | TorrentState::Downloading => 4,
+        TorrentState::Moving if t.progress < 1. => 4,
+        TorrentState::Moving | TorrentState::Seeding => 6,
+    }
+}
@@ -1,11 +1,14 @@
-use std::io::{self, ErrorKind};
-use std::net::{SocketAddr, TcpStream};
+use std::fs::File;
+use std::io::{self, ErrorKind, IoSlice};
+use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::os::unix::io::{AsRawFd, RawFd};
 
-use net2::{TcpBuilder, TcpStreamExt};
+use net2::TcpStreamExt;
 use nix::errno::Errno::EINPROGRESS;
 
 use crate::throttle::Throttle;
+use crate::torrent::peer::writer::FileWrite;
+use crate::util::net;
 
 /// Wrapper type over Mio sockets, allowing for use of UDP/TCP, encryption,
 /// rate limiting, etc.
@@ -17,11 +20,9 @@ pub struct Socket {
 
 impl Socket {
     pub fn new(addr: &SocketAddr) -> io::Result<Socket> {
-        let sock = (match *addr {
-            SocketAddr::V4(..) => TcpBuilder::new_v4(),
-            SocketAddr::V6(..) => TcpBuilder::new_v6(),
-        })?;
-        let conn = sock.to_tcp_stream()?;
+        let conn = net::tcp_stream(addr.is_ipv6())?;
+        net::classify(&conn, addr.is_ipv6())?;
+        net::tune(&conn)?;
         conn.set_nonblocking(true)?;
         if let Err(e) = conn.connect(addr) {
             // OSX gives the AddrNotAvailable error sometimes, and generic
@@ -41,7 +42,7 @@ impl Socket {
 
     #[cfg(test)]
     pub fn empty() -> Socket {
-        let conn = TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
+        let conn = net2::TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
         Socket {
             conn,
             throttle: None,
@@ -53,7 +54,22 @@ impl Socket {
         self.addr
     }
 
+    /// Shuts down the write half of the connection and discards any
+    /// unread input, which would otherwise cause a reset on close.
+    pub fn shutdown(&mut self) {
+        if self.conn.shutdown(Shutdown::Write).is_err() {
+            return;
+        }
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = io::Read::read(&mut self.conn, &mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+
     pub fn from_stream(conn: TcpStream) -> io::Result<Socket> {
+        net::classify(&conn, conn.local_addr()?.is_ipv6())?;
         conn.set_nonblocking(true)?;
         let addr = conn.peer_addr()?;
         Ok(Socket {
@@ -120,7 +136,34 @@ impl io::Write for Socket {
         }
     }
 
+    /// Only used for batches of small messages, which aren't rate limited.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.conn.write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.conn.flush()
     }
 }
+
+impl FileWrite for Socket {
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &File, offset: u64, len: usize) -> io::Result<usize> {
+        if let Some(ref mut t) = self.throttle {
+            if t.get_bytes_ul(len).is_err() {
+                return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+            }
+        }
+        let mut off = offset as libc::off_t;
+        let res = unsafe { libc::sendfile(self.conn.as_raw_fd(), file.as_raw_fd(), &mut off, len) };
+        let amnt = if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res as usize)
+        };
+        if let Some(ref mut t) = self.throttle {
+            t.restore_bytes_ul(len - *amnt.as_ref().unwrap_or(&0));
+        }
+        amnt
+    }
+}
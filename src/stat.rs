@@ -1,39 +1,294 @@
-use std::time;
+use std::cmp;
+use std::collections::VecDeque;
+use std::{iter, ops, time};
 
-const ALPHA: f64 = 0.8;
+/// Seconds of transfer kept per rate, the longest window
+const WINDOW_SECS: u64 = 60;
+/// Weight of each second in smoothed rates, which follow roughly the
+/// last ten seconds
+const SMOOTH_ALPHA: f64 = 0.1;
 
+/// Number of per minute samples retained (one day)
+const MINUTE_SAMPLES: usize = 60 * 24;
+/// Number of hourly samples retained (thirty days)
+const HOUR_SAMPLES: usize = 24 * 30;
+/// Number of daily samples retained (one year)
+const DAY_SAMPLES: usize = 365;
+
+/// Windows over which transfer rates are measured. Each use of a rate
+/// sticks to one, so the rates acted on agree with those shown. Rate
+/// limits aren't enforced with these, but by the token buckets of
+/// `throttle`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    /// The last second, for request pipelining which must adapt quickly
+    Second,
+    /// The last ten seconds, for display and choking
+    TenSeconds,
+    /// The last minute, for estimates such as the time left
+    Minute,
+}
+
+impl Window {
+    fn secs(self) -> u64 {
+        match self {
+            Window::Second => 1,
+            Window::TenSeconds => 10,
+            Window::Minute => WINDOW_SECS,
+        }
+    }
+}
+
+/// Bytes transferred in each second of the last minute.
+#[derive(Debug)]
+struct Rate {
+    buckets: [u64; WINDOW_SECS as usize],
+    /// Second of the bucket being filled, counted from the start
+    second: u64,
+}
+
+impl Rate {
+    fn new() -> Rate {
+        Rate {
+            buckets: [0; WINDOW_SECS as usize],
+            second: 0,
+        }
+    }
+
+    fn bucket(&self, second: u64) -> u64 {
+        self.buckets[(second % WINDOW_SECS) as usize]
+    }
+
+    fn add(&mut self, amnt: u64) {
+        self.buckets[(self.second % WINDOW_SECS) as usize] += amnt;
+    }
+
+    /// Moves on to the bucket of a later second, clearing those skipped.
+    fn roll(&mut self, second: u64) {
+        if second <= self.second {
+            return;
+        }
+        for s in self.second + 1..=cmp::min(second, self.second + WINDOW_SECS) {
+            self.buckets[(s % WINDOW_SECS) as usize] = 0;
+        }
+        self.second = second;
+    }
+
+    /// Bytes/sec over the complete seconds of a window. Until the window
+    /// has passed once, the seconds so far are averaged.
+    fn rate(&self, window: Window) -> u64 {
+        let secs = cmp::min(window.secs(), self.second);
+        if secs == 0 {
+            return 0;
+        }
+        let total: u64 = (1..=secs).map(|i| self.bucket(self.second - i)).sum();
+        total / secs
+    }
+
+    /// Whether anything was transferred within a window as of this or the
+    /// last second, so that the last rate of an active window is zero.
+    fn active(&self, window: Window) -> bool {
+        let secs = cmp::min(window.secs() + 1, self.second);
+        (0..=secs).any(|i| self.bucket(self.second - i) != 0)
+    }
+
+    /// Exponentially weighted average of the complete seconds of a window
+    /// in bytes/sec. The weights are normalized, so the average isn't
+    /// biased towards zero before the window has passed.
+    fn smoothed(&self, window: Window) -> u64 {
+        let secs = cmp::min(window.secs(), self.second);
+        if secs == 0 {
+            return 0;
+        }
+        let (mut total, mut weights, mut weight) = (0., 0., SMOOTH_ALPHA);
+        for i in 1..=secs {
+            total += weight * self.bucket(self.second - i) as f64;
+            weights += weight;
+            weight *= 1. - SMOOTH_ALPHA;
+        }
+        (total / weights) as u64
+    }
+}
+
+/// Upload and download rates of a peer, a torrent or the whole client,
+/// measured over rolling windows.
+#[derive(Debug)]
+pub struct Rates {
+    ul: Rate,
+    dl: Rate,
+    start: time::Instant,
+}
+
+impl Rates {
+    pub fn new() -> Rates {
+        Rates {
+            ul: Rate::new(),
+            dl: Rate::new(),
+            start: time::Instant::now(),
+        }
+    }
+
+    /// Rates with a second of transfer already measured.
+    #[cfg(test)]
+    pub fn test(ul: u64, dl: u64) -> Rates {
+        let mut r = Rates::new();
+        r.add_ul(ul);
+        r.add_dl(dl);
+        r.roll(1);
+        r
+    }
+
+    /// Whether anything was transferred within the window rates are shown
+    /// for, so that they're updated until they drop to zero.
+    pub fn active(&self) -> bool {
+        self.ul.active(Window::TenSeconds) || self.dl.active(Window::TenSeconds)
+    }
+
+    pub fn ul(&self, window: Window) -> u64 {
+        self.ul.rate(window)
+    }
+
+    pub fn dl(&self, window: Window) -> u64 {
+        self.dl.rate(window)
+    }
+
+    /// Download rate averaged over the last minute, weighted so it
+    /// mostly follows the last ten seconds. It's steadier for estimates
+    /// such as the time left.
+    pub fn smooth_dl(&self) -> u64 {
+        self.dl.smoothed(Window::Minute)
+    }
+
+    pub fn add_ul(&mut self, amnt: u64) {
+        self.ul.add(amnt);
+    }
+
+    pub fn add_dl(&mut self, amnt: u64) {
+        self.dl.add(amnt);
+    }
+
+    /// Moves the windows up to the current second. Transfer since the last
+    /// tick counts towards the second of that tick, so this should be
+    /// called more often than every second.
+    pub fn tick(&mut self) {
+        let second = self.start.elapsed().as_secs();
+        self.roll(second);
+    }
+
+    fn roll(&mut self, second: u64) {
+        self.ul.roll(second);
+        self.dl.roll(second);
+    }
+}
+
+/// Bytes of piece data sent and received, and of the protocol overhead
+/// around it: handshakes, bitfields, haves, requests and piece headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Transfer {
+    pub ul: u64,
+    pub dl: u64,
+    pub overhead_ul: u64,
+    pub overhead_dl: u64,
+}
+
+impl ops::AddAssign for Transfer {
+    fn add_assign(&mut self, other: Transfer) {
+        self.ul += other.ul;
+        self.dl += other.dl;
+        self.overhead_ul += other.overhead_ul;
+        self.overhead_dl += other.overhead_dl;
+    }
+}
+
+impl iter::Sum for Transfer {
+    fn sum<I: Iterator<Item = Transfer>>(iter: I) -> Transfer {
+        iter.fold(Transfer::default(), |mut total, t| {
+            total += t;
+            total
+        })
+    }
+}
+
+/// Bytes transferred within the period starting at `time`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub time: i64,
+    pub ul: u64,
+    pub dl: u64,
+}
+
+/// Bounded series of samples, each covering `period` seconds.
 #[derive(Debug)]
-pub struct EMA {
+pub struct Series {
+    period: i64,
+    cap: usize,
+    samples: VecDeque<Sample>,
+}
+
+/// Transfer history, recorded per minute and rolled into hourly and daily
+/// aggregates.
+#[derive(Debug)]
+pub struct History {
+    pub minutes: Series,
+    pub hours: Series,
+    pub days: Series,
     ul: u64,
     dl: u64,
-    accum_ul: f64,
-    accum_dl: f64,
-    accum_time: f64,
-    updated: time::Instant,
 }
 
-impl EMA {
-    pub fn new() -> EMA {
-        EMA {
-            ul: 0,
-            dl: 0,
-            accum_ul: 0.,
-            accum_dl: 0.,
-            accum_time: 1.,
-            updated: time::Instant::now(),
+impl Series {
+    fn new(period: i64, cap: usize) -> Series {
+        Series {
+            period,
+            cap,
+            samples: VecDeque::with_capacity(cap),
         }
     }
 
-    pub fn active(&self) -> bool {
-        self.accum_ul > 0.1 || self.accum_dl > 0.1
+    fn add(&mut self, time: i64, ul: u64, dl: u64) {
+        let time = time - time.rem_euclid(self.period);
+        match self.samples.back_mut() {
+            Some(s) if s.time == time => {
+                s.ul += ul;
+                s.dl += dl;
+                return;
+            }
+            _ => {}
+        }
+        if self.samples.len() == self.cap {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample { time, ul, dl });
     }
 
-    pub fn avg_ul(&self) -> u64 {
-        (1000.0 * self.accum_ul / self.accum_time) as u64
+    /// Returns all samples whose periods overlap `[start, end]`.
+    pub fn range(&self, start: i64, end: i64) -> impl Iterator<Item = &Sample> {
+        let period = self.period;
+        self.samples
+            .iter()
+            .filter(move |s| s.time + period > start && s.time <= end)
     }
 
-    pub fn avg_dl(&self) -> u64 {
-        (1000.0 * self.accum_dl / self.accum_time) as u64
+    pub fn samples(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter()
+    }
+
+    pub fn load(&mut self, samples: impl Iterator<Item = Sample>) {
+        for s in samples {
+            self.add(s.time, s.ul, s.dl);
+        }
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            minutes: Series::new(60, MINUTE_SAMPLES),
+            hours: Series::new(60 * 60, HOUR_SAMPLES),
+            days: Series::new(60 * 60 * 24, DAY_SAMPLES),
+            ul: 0,
+            dl: 0,
+        }
     }
 
     pub fn add_ul(&mut self, amnt: u64) {
@@ -44,40 +299,150 @@ impl EMA {
         self.dl += amnt;
     }
 
-    pub fn tick(&mut self) {
-        self.accum_ul = (ALPHA * self.ul as f64) + (1.0 - ALPHA) * self.accum_ul;
-        self.accum_dl = (ALPHA * self.dl as f64) + (1.0 - ALPHA) * self.accum_dl;
+    /// Records the amount transferred since the last tick into the sample
+    /// containing `time`, a unix timestamp.
+    pub fn tick(&mut self, time: i64) {
+        let (ul, dl) = (self.ul, self.dl);
+        self.minutes.add(time, ul, dl);
+        self.hours.add(time, ul, dl);
+        self.days.add(time, ul, dl);
         self.ul = 0;
         self.dl = 0;
-        // Put everything in terms of milliseconds
-        let elapsed = self.updated.elapsed();
-        let dur =
-            (elapsed.as_secs() * 1000) as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000.0;
-        self.accum_time = (ALPHA * dur) + (1.0 - ALPHA) * self.accum_time;
-        self.updated = time::Instant::now();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
 
     #[test]
-    fn test_ema() {
-        let mut s = EMA::new();
-        s.add_ul(1000);
-        thread::sleep(time::Duration::from_millis(50));
-        s.tick();
+    fn test_rate() {
+        let mut r = Rate::new();
+        r.add(1000);
+        // The current second isn't complete, so doesn't count yet
+        assert_eq!(r.rate(Window::Second), 0);
+        assert!(r.active(Window::Second));
+        r.roll(1);
+        assert_eq!(r.rate(Window::Second), 1000);
+        // Only the seconds so far are averaged over
+        assert_eq!(r.rate(Window::TenSeconds), 1000);
+        r.add(3000);
+        r.roll(2);
+        assert_eq!(r.rate(Window::Second), 3000);
+        assert_eq!(r.rate(Window::TenSeconds), 2000);
+        // Rolling backwards is ignored
+        r.roll(1);
+        assert_eq!(r.second, 2);
+        r.roll(11);
+        assert_eq!(r.rate(Window::Second), 0);
+        assert_eq!(r.rate(Window::TenSeconds), 300);
+        assert_eq!(r.rate(Window::Minute), 4000 / 11);
+        r.roll(12);
+        assert_eq!(r.rate(Window::TenSeconds), 0);
+        assert!(r.active(Window::TenSeconds));
+        r.roll(13);
+        assert!(!r.active(Window::TenSeconds));
+        assert!(r.active(Window::Minute));
+    }
 
-        s.add_ul(0);
-        thread::sleep(time::Duration::from_millis(50));
-        s.tick();
+    #[test]
+    fn test_rate_rollover() {
+        let mut r = Rate::new();
+        for s in 0..WINDOW_SECS + 5 {
+            r.add(s);
+            r.roll(s + 1);
+        }
+        // Buckets are reused once the oldest leave the window
+        let total: u64 = (5..WINDOW_SECS + 5).sum();
+        assert_eq!(r.rate(Window::Minute), total / WINDOW_SECS);
+        assert_eq!(r.rate(Window::Second), WINDOW_SECS + 4);
+        // Skipping past the whole window clears it
+        r.add(10);
+        r.roll(1000);
+        assert_eq!(r.rate(Window::Minute), 0);
+        assert!(!r.active(Window::Minute));
+        r.add(60);
+        r.roll(1001);
+        assert_eq!(r.rate(Window::Minute), 1);
+    }
+
+    #[test]
+    fn test_smooth_dl() {
+        let mut r = Rates::new();
+        assert_eq!(r.smooth_dl(), 0);
+        r.add_dl(1000);
+        r.roll(1);
+        // A single second gives its own rate rather than a fraction of it
+        assert_eq!(r.smooth_dl(), 1000);
+        for s in 2..40 {
+            r.add_dl(3000);
+            r.roll(s);
+        }
+        assert!(r.smooth_dl() > 2900 && r.smooth_dl() <= 3000);
+        // A momentary stall barely moves it, unlike the last second's rate
+        let before = r.smooth_dl();
+        r.roll(40);
+        assert_eq!(r.dl(Window::Second), 0);
+        assert!(r.smooth_dl() * 10 > before * 8);
+        // Seconds past the window no longer count
+        r.roll(200);
+        assert_eq!(r.smooth_dl(), 0);
+    }
 
-        s.add_ul(500);
-        thread::sleep(time::Duration::from_millis(50));
-        s.tick();
+    #[test]
+    fn test_transfer() {
+        let t = Transfer {
+            ul: 1,
+            dl: 2,
+            overhead_ul: 3,
+            overhead_dl: 4,
+        };
+        let total: Transfer = vec![t, t, Transfer::default()].into_iter().sum();
+        assert_eq!(
+            total,
+            Transfer {
+                ul: 2,
+                dl: 4,
+                overhead_ul: 6,
+                overhead_dl: 8,
+            }
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let mut h = History::new();
+        h.add_ul(10);
+        h.tick(30);
+        h.add_dl(5);
+        h.tick(59);
+        h.add_ul(1);
+        h.tick(60 * 60 + 10);
+
+        let minutes: Vec<_> = h.minutes.samples().cloned().collect();
+        assert_eq!(
+            minutes,
+            vec![
+                Sample {
+                    time: 0,
+                    ul: 10,
+                    dl: 5
+                },
+                Sample {
+                    time: 60 * 60,
+                    ul: 1,
+                    dl: 0
+                },
+            ]
+        );
+        assert_eq!(h.hours.samples().count(), 2);
+        assert_eq!(h.days.samples().count(), 1);
+        assert_eq!(h.days.samples().next().unwrap().ul, 11);
+        assert_eq!(h.minutes.range(60, 60 * 60).count(), 1);
 
-        assert!((s.avg_ul() as i64 - 10000).abs() < 8000);
+        for i in 0..MINUTE_SAMPLES as i64 + 5 {
+            h.tick(2 * 60 * 60 + i * 60);
+        }
+        assert_eq!(h.minutes.samples().count(), MINUTE_SAMPLES);
     }
 }
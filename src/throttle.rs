@@ -1,6 +1,6 @@
 use amy::Registrar;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 /// Creates a throttler from which sub throttles may be created.
@@ -12,6 +12,15 @@ pub struct Throttler {
     fid: usize,
     dl_data: Rc<RefCell<ThrottleData>>,
     ul_data: Rc<RefCell<ThrottleData>>,
+    groups: Rc<RefCell<HashMap<String, Group>>>,
+}
+
+/// Rate limits shared by all torrents in a named group, applied
+/// between the global and per torrent limits.
+#[derive(Clone)]
+struct Group {
+    ul: Rc<RefCell<ThrottleData>>,
+    dl: Rc<RefCell<ThrottleData>>,
 }
 
 const URATE: usize = 15;
@@ -35,12 +44,17 @@ impl Throttler {
             fid,
             ul_data: Rc::new(RefCell::new(ut)),
             dl_data: Rc::new(RefCell::new(dt)),
+            groups: Rc::new(RefCell::new(HashMap::new())),
         })
     }
 
     pub fn update(&self) -> (u64, u64) {
         let ul = self.ul_data.borrow_mut().add_tokens();
         let dl = self.dl_data.borrow_mut().add_tokens();
+        for group in self.groups.borrow().values() {
+            group.ul.borrow_mut().add_tokens();
+            group.dl.borrow_mut().add_tokens();
+        }
         (ul, dl)
     }
 
@@ -56,10 +70,29 @@ impl Throttler {
                 None,
                 self.dl_data.borrow().max_tokens,
             ))),
+            groups: self.groups.clone(),
             id,
         }
     }
 
+    /// Creates a throttle group or changes its rates.
+    pub fn set_group(&mut self, name: &str, ul_rate: Option<i64>, dl_rate: Option<i64>) {
+        let max_tokens = self.ul_data.borrow().max_tokens;
+        let mut groups = self.groups.borrow_mut();
+        let group = groups.entry(name.to_owned()).or_insert_with(|| Group {
+            ul: Rc::new(RefCell::new(ThrottleData::new(None, max_tokens))),
+            dl: Rc::new(RefCell::new(ThrottleData::new(None, max_tokens))),
+        });
+        group.ul.borrow_mut().rate = ul_rate;
+        group.dl.borrow_mut().rate = dl_rate;
+    }
+
+    /// Removes a throttle group, which throttles already in it
+    /// remain limited by until they leave it.
+    pub fn remove_group(&mut self, name: &str) {
+        self.groups.borrow_mut().remove(name);
+    }
+
     pub fn ul_rate(&mut self) -> Option<i64> {
         self.ul_data.borrow().rate
     }
@@ -104,6 +137,8 @@ struct ThrottleData {
     max_tokens: usize,
     last_used: u64,
     throttled: HashSet<usize>,
+    /// Data of the group a torrent's tier is in
+    group: Option<Rc<RefCell<ThrottleData>>>,
 }
 
 /// Throttle mechanism based on the token bucket algorithm.
@@ -116,6 +151,7 @@ pub struct Throttle {
     dl_tier: Rc<RefCell<ThrottleData>>,
     ul_data: Rc<RefCell<ThrottleData>>,
     dl_data: Rc<RefCell<ThrottleData>>,
+    groups: Rc<RefCell<HashMap<String, Group>>>,
 }
 
 impl Throttle {
@@ -125,10 +161,23 @@ impl Throttle {
             ul_tier: self.ul_tier.clone(),
             dl_data: self.dl_data.clone(),
             dl_tier: self.dl_tier.clone(),
+            groups: self.groups.clone(),
             id,
         }
     }
 
+    /// Moves the throttle and its siblings into a group, or out of
+    /// their group if None. Fails if the group doesn't exist.
+    pub fn set_group(&mut self, name: Option<&str>) -> Result<(), ()> {
+        let group = match name {
+            Some(n) => Some(self.groups.borrow().get(n).cloned().ok_or(())?),
+            None => None,
+        };
+        self.ul_tier.borrow_mut().group = group.as_ref().map(|g| g.ul.clone());
+        self.dl_tier.borrow_mut().group = group.map(|g| g.dl);
+        Ok(())
+    }
+
     pub fn get_bytes_dl(&mut self, amnt: usize) -> Result<(), ()> {
         while self.dl_tier.borrow().epoch != self.dl_data.borrow().epoch {
             self.dl_tier.borrow_mut().add_tokens();
@@ -144,9 +193,21 @@ impl Throttle {
             return Err(());
         }
 
+        let group = self.dl_tier.borrow().group.clone();
+        if let Some(ref group) = group {
+            if group.borrow_mut().get_tokens(amnt).is_err() {
+                self.dl_data.borrow_mut().restore_tokens(amnt);
+                self.dl_data.borrow_mut().throttled.insert(self.id);
+                return Err(());
+            }
+        }
+
         let res = self.dl_tier.borrow_mut().get_tokens(amnt);
         if res.is_err() {
             self.dl_data.borrow_mut().restore_tokens(amnt);
+            if let Some(group) = group {
+                group.borrow_mut().restore_tokens(amnt);
+            }
             self.dl_data.borrow_mut().throttled.insert(self.id);
             return Err(());
         }
@@ -168,9 +229,21 @@ impl Throttle {
             return Err(());
         }
 
+        let group = self.ul_tier.borrow().group.clone();
+        if let Some(ref group) = group {
+            if group.borrow_mut().get_tokens(amnt).is_err() {
+                self.ul_data.borrow_mut().restore_tokens(amnt);
+                self.ul_data.borrow_mut().throttled.insert(self.id);
+                return Err(());
+            }
+        }
+
         let res = self.ul_tier.borrow_mut().get_tokens(amnt);
         if res.is_err() {
             self.ul_data.borrow_mut().restore_tokens(amnt);
+            if let Some(group) = group {
+                group.borrow_mut().restore_tokens(amnt);
+            }
             self.ul_data.borrow_mut().throttled.insert(self.id);
             return Err(());
         }
@@ -199,11 +272,19 @@ impl Throttle {
 
     pub fn restore_bytes_dl(&mut self, amnt: usize) {
         self.dl_data.borrow_mut().restore_tokens(amnt);
+        let group = self.dl_tier.borrow().group.clone();
+        if let Some(group) = group {
+            group.borrow_mut().restore_tokens(amnt);
+        }
         self.dl_tier.borrow_mut().restore_tokens(amnt);
     }
 
     pub fn restore_bytes_ul(&mut self, amnt: usize) {
         self.ul_data.borrow_mut().restore_tokens(amnt);
+        let group = self.ul_tier.borrow().group.clone();
+        if let Some(group) = group {
+            group.borrow_mut().restore_tokens(amnt);
+        }
         self.ul_tier.borrow_mut().restore_tokens(amnt);
     }
 }
@@ -225,6 +306,7 @@ impl ThrottleData {
             throttled: HashSet::with_capacity(0),
             last_used: 0,
             epoch: 0,
+            group: None,
         }
     }
 
@@ -278,3 +360,38 @@ impl ThrottleData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amy::Poller;
+
+    #[test]
+    fn test_group() {
+        let poller = Poller::new().unwrap();
+        let reg = poller.get_registrar();
+        let mut throttler = Throttler::new(None, None, 1000, &reg).unwrap();
+        // 150 bytes are added to the group every update
+        throttler.set_group("g", Some(10_000), None);
+        let mut a = throttler.get_throttle(0);
+        let mut b = throttler.get_throttle(1);
+        let mut c = throttler.get_throttle(2);
+        assert!(a.set_group(Some("h")).is_err());
+        a.set_group(Some("g")).unwrap();
+        b.set_group(Some("g")).unwrap();
+        throttler.update();
+
+        assert!(a.get_bytes_ul(100).is_ok());
+        assert!(b.get_bytes_ul(100).is_err());
+        assert!(b.get_bytes_ul(50).is_ok());
+        assert!(b.new_sibling(3).get_bytes_ul(1).is_err());
+        assert!(c.get_bytes_ul(1000).is_ok());
+        assert!(a.get_bytes_dl(1000).is_ok());
+        b.restore_bytes_ul(50);
+        assert!(a.get_bytes_ul(50).is_ok());
+
+        b.set_group(None).unwrap();
+        assert!(b.get_bytes_ul(1000).is_ok());
+        assert!(a.get_bytes_ul(1).is_err());
+    }
+}
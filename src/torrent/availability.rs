@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use crate::torrent::Bitfield;
+
+/// Minimum time between piece maps sent to clients. Maps can change with
+/// every have message or written piece, and the whole map is sent each time.
+const SEND_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Tracks whether a piece map changed, letting it be sent at most once
+/// per send interval.
+#[derive(Default)]
+pub struct Changes {
+    changed: bool,
+    /// When the map was last sent
+    sent: Option<Instant>,
+}
+
+impl Changes {
+    pub fn mark(&mut self) {
+        self.changed = true;
+    }
+
+    /// Whether the map should be sent now, in which case it's
+    /// considered sent.
+    pub fn take(&mut self, now: Instant) -> bool {
+        let due = self.sent.is_none_or(|t| now >= t + SEND_INTERVAL);
+        if !self.changed || !due {
+            return false;
+        }
+        self.changed = false;
+        self.sent = Some(now);
+        true
+    }
+}
+
+/// Number of connected peers which have each piece, sent to clients as
+/// a compact map of the torrent's pieces.
+pub struct Availability {
+    counts: Vec<u16>,
+    changes: Changes,
+}
+
+impl Availability {
+    pub fn new(pieces: u32) -> Availability {
+        let mut changes = Changes::default();
+        changes.mark();
+        Availability {
+            counts: vec![0; pieces as usize],
+            changes,
+        }
+    }
+
+    /// Counts the pieces of a peer.
+    pub fn add(&mut self, pieces: &Bitfield) {
+        for piece in pieces.iter() {
+            self.have(piece as u32);
+        }
+    }
+
+    /// Stops counting the pieces of a peer which was removed.
+    pub fn remove(&mut self, pieces: &Bitfield) {
+        for piece in pieces.iter() {
+            if let Some(c) = self.counts.get_mut(piece as usize) {
+                *c = c.saturating_sub(1);
+                self.changes.mark();
+            }
+        }
+    }
+
+    /// Counts a piece a peer announced it has.
+    pub fn have(&mut self, piece: u32) {
+        if let Some(c) = self.counts.get_mut(piece as usize) {
+            *c = c.saturating_add(1);
+            self.changes.mark();
+        }
+    }
+
+    /// A byte per piece holding the number of peers with it, up to 255,
+    /// in base64.
+    pub fn b64(&self) -> String {
+        let map: Vec<u8> = self.counts.iter().map(|&c| c.min(255) as u8).collect();
+        base64::encode(&map)
+    }
+
+    /// The map if it changed since it was last taken, at most once
+    /// per send interval.
+    pub fn take_changed(&mut self, now: Instant) -> Option<String> {
+        if self.changes.take(now) {
+            Some(self.b64())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_availability() {
+        let mut a = Availability::new(3);
+        let mut b = Bitfield::new(3);
+        b.set_bit(0);
+        b.set_bit(2);
+        a.add(&b);
+        a.add(&Bitfield::from(&[0xff], 3));
+        a.have(1);
+        let now = Instant::now();
+        assert_eq!(a.take_changed(now), Some(base64::encode(&[2, 2, 2])));
+        assert_eq!(a.take_changed(now), None);
+        a.remove(&b);
+        // Changes are held back until the interval passes
+        assert_eq!(a.take_changed(now + Duration::from_secs(1)), None);
+        let later = now + SEND_INTERVAL;
+        assert_eq!(a.take_changed(later), Some(base64::encode(&[1, 2, 1])));
+        for _ in 0..300 {
+            a.have(0);
+        }
+        assert_eq!(a.b64(), base64::encode(&[255, 2, 1]));
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut c = Changes::default();
+        let now = Instant::now();
+        assert!(!c.take(now));
+        c.mark();
+        assert!(c.take(now));
+        c.mark();
+        assert!(!c.take(now + Duration::from_secs(1)));
+        assert!(c.take(now + SEND_INTERVAL));
+        assert!(!c.take(now + SEND_INTERVAL * 2));
+    }
+}
@@ -288,11 +288,28 @@ impl<'a> Iterator for BitfieldIter<'a> {
     type Item = u64;
 
     fn next(&mut self) -> Option<u64> {
-        while self.idx < self.pf.len() {
-            self.idx += 1;
-            if self.pf.has_bit(self.idx - 1) {
+        let len = self.pf.len();
+        let data = match self.pf {
+            Bitfield::I { data, .. } => data,
+            Bitfield::C { .. } if self.idx < len => {
+                self.idx += 1;
                 return Some(self.idx - 1);
             }
+            Bitfield::C { .. } => return None,
+        };
+        // Skip over unset bits a byte at a time
+        while self.idx < len {
+            let block_pos = self.idx / 8;
+            let block = data[block_pos as usize] & (0xff >> (self.idx % 8));
+            if block == 0 {
+                self.idx = (block_pos + 1) * 8;
+                continue;
+            }
+            let pos = block_pos * 8 + u64::from(block.leading_zeros());
+            self.idx = pos + 1;
+            if pos < len {
+                return Some(pos);
+            }
         }
         None
     }
@@ -312,6 +329,16 @@ mod tests {
         assert_eq!(pf.iter().count() as u64, pf.len());
     }
 
+    #[test]
+    fn test_iter_sparse() {
+        let mut pf = Bitfield::new(20);
+        for i in &[0, 7, 8, 13, 19] {
+            pf.set_bit(*i);
+        }
+        assert_eq!(pf.iter().collect::<Vec<_>>(), vec![0, 7, 8, 13, 19]);
+        assert_eq!(Bitfield::from(&[0x00, 0xff], 12).iter().count(), 4);
+    }
+
     #[test]
     fn test_create() {
         let pf = Bitfield::new(10);
@@ -1,13 +1,23 @@
+use std::cmp;
 use std::time::{Duration, Instant};
 
 use crate::control::cio;
 use crate::torrent::Peer;
 use crate::util::{random_sample, FHashSet, UHashMap};
 
+const MIN_SLOTS: usize = 5;
+const MAX_SLOTS: usize = 32;
+/// Decay of the upload capacity estimate per slot update
+const CAPACITY_DECAY: f64 = 0.9;
+
 pub struct Choker {
     unchoked: Vec<usize>,
     interested: FHashSet<usize>,
     last_updated: Instant,
+    /// Number of peers which may be unchoked at once
+    slots: usize,
+    /// Decaying peak of the observed upload rate, in bytes/sec
+    capacity: u64,
 }
 
 #[derive(Debug, PartialEq)]
@@ -19,16 +29,17 @@ pub struct SwapRes {
 impl Choker {
     pub fn new() -> Choker {
         Choker {
-            unchoked: Vec::with_capacity(5),
+            unchoked: Vec::with_capacity(MIN_SLOTS),
             interested: FHashSet::default(),
             last_updated: Instant::now(),
+            slots: MIN_SLOTS,
+            capacity: 0,
         }
     }
 
     pub fn add_peer<T: cio::CIO>(&mut self, peer: &mut Peer<T>) {
-        if self.unchoked.len() < 5 {
+        if self.unchoked.len() < self.slots {
             self.unchoked.push(peer.id());
-            peer.flush();
             peer.unchoke();
         } else {
             self.interested.insert(peer.id());
@@ -65,9 +76,24 @@ impl Choker {
         }
     }
 
+    /// Sizes the upload slots from the observed upload capacity,
+    /// unchoking or choking peers to fill the new slot count.
+    pub fn update_slots<T: cio::CIO>(&mut self, rate: u64, peers: &mut UHashMap<Peer<T>>) {
+        self.capacity = cmp::max(rate, (self.capacity as f64 * CAPACITY_DECAY) as u64);
+        self.slots = slots_for(self.capacity);
+        while self.unchoked.len() > self.slots {
+            let id = self.unchoked.remove(0);
+            if let Some(peer) = peers.get_mut(&id) {
+                peer.choke();
+                self.interested.insert(id);
+            }
+        }
+        while self.unchoked.len() < self.slots && self.unchoke_random(peers).is_some() {}
+    }
+
     fn update_timer(&mut self) -> Result<(), ()> {
         if self.last_updated.elapsed() < Duration::from_secs(10)
-            || self.unchoked.len() < 5
+            || self.unchoked.len() < self.slots
             || self.interested.is_empty()
         {
             Err(())
@@ -84,13 +110,7 @@ impl Choker {
         if self.interested.is_empty() {
             return None;
         }
-        let (slowest, _) = self.unchoked.iter().enumerate().fold(
-            (0, std::u32::MAX),
-            |(slowest, min), (idx, id)| match peers.get_mut(id).map(Peer::flush) {
-                Some((ul, _)) if ul < min => (idx, ul),
-                _ => (slowest, min),
-            },
-        );
+        let slowest = self.slowest(peers, |p| p.get_tx_rates().0);
         self.swap_peer(slowest, peers)
     }
 
@@ -102,16 +122,24 @@ impl Choker {
             return None;
         }
 
-        let (slowest, _) = self.unchoked.iter().enumerate().fold(
-            (0, std::u32::MAX),
-            |(slowest, min), (idx, id)| match peers.get_mut(id).map(Peer::flush) {
-                Some((_, dl)) if dl < min => (idx, dl),
-                _ => (slowest, min),
-            },
-        );
+        let slowest = self.slowest(peers, |p| p.get_tx_rates().1);
         self.swap_peer(slowest, peers)
     }
 
+    /// Index of the unchoked peer with the lowest rate.
+    fn slowest<T: cio::CIO, F: Fn(&Peer<T>) -> u64>(
+        &self,
+        peers: &UHashMap<Peer<T>>,
+        rate: F,
+    ) -> usize {
+        self.unchoked
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, id)| peers.get(id).map(&rate).unwrap_or(u64::MAX))
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
+    }
+
     fn swap_peer<T: cio::CIO>(
         &mut self,
         idx: usize,
@@ -133,9 +161,16 @@ impl Choker {
     }
 }
 
+/// Upload slots for an upload capacity in bytes/sec, using
+/// Azureus' heuristic of sqrt(0.6 * KiB/s).
+fn slots_for(capacity: u64) -> usize {
+    let slots = (capacity as f64 / 1024. * 0.6).sqrt() as usize;
+    slots.clamp(MIN_SLOTS, MAX_SLOTS)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Choker, SwapRes};
+    use super::{slots_for, Choker, SwapRes, MAX_SLOTS, MIN_SLOTS};
     use crate::torrent::{Bitfield, Peer};
     use crate::util::UHashMap;
     use std::time::{Duration, Instant};
@@ -182,7 +217,7 @@ mod tests {
         let mut h = UHashMap::default();
         assert_eq!(c.update_upload(&mut h).is_none(), true);
         for i in 0..6 {
-            let mut p = Peer::test_from_stats(i, i as u32, 6 - i as u32);
+            let mut p = Peer::test_from_stats(i, i as u64, 6 - i as u64);
             c.add_peer(&mut p);
             h.insert(i, p);
         }
@@ -199,7 +234,7 @@ mod tests {
         let mut h = UHashMap::default();
         assert_eq!(c.update_download(&mut h).is_none(), true);
         for i in 0..6 {
-            let mut p = Peer::test_from_stats(i, 6 - i as u32, i as u32);
+            let mut p = Peer::test_from_stats(i, 6 - i as u64, i as u64);
             c.add_peer(&mut p);
             h.insert(i, p);
         }
@@ -209,4 +244,33 @@ mod tests {
         assert_eq!(res.choked, 0);
         assert_eq!(res.unchoked, 5);
     }
+
+    #[test]
+    fn test_slots_for() {
+        assert_eq!(slots_for(0), MIN_SLOTS);
+        assert_eq!(slots_for(1000 * 1024), 24);
+        assert_eq!(slots_for(100 * 1024 * 1024), MAX_SLOTS);
+    }
+
+    #[test]
+    fn test_update_slots() {
+        let mut c = Choker::new();
+        let mut h = UHashMap::default();
+        for i in 0..10 {
+            let mut p = Peer::test_from_stats(i, 0, 0);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        assert_eq!(c.unchoked.len(), 5);
+        // 78 KiB/s supports 6 slots
+        c.update_slots(78 * 1024, &mut h);
+        assert_eq!(c.unchoked.len(), 6);
+        assert_eq!(c.interested.len(), 4);
+        // Capacity decays once the rate drops
+        for _ in 0..5 {
+            c.update_slots(0, &mut h);
+        }
+        assert_eq!(c.unchoked.len(), 5);
+        assert_eq!(c.interested.len(), 5);
+    }
 }
@@ -0,0 +1,103 @@
+use std::{fmt, io};
+
+use crate::rpc::resource::ErrorKind;
+
+const EIO: i32 = 5;
+const EAGAIN: i32 = 11;
+const ENOSPC: i32 = 28;
+const EDQUOT: i32 = 122;
+
+/// Latest error of a torrent, which is cleared once resolved.
+/// Disk errors stop the torrent until then.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// No space or quota left for the torrent's data
+    DiskFull(String),
+    PermissionDenied(String),
+    /// A file of the torrent's data is missing
+    MissingFile(String),
+    /// A disk error which may not recur, such as EIO or EAGAIN
+    Transient(String),
+    /// Any other disk error
+    Io(String),
+    /// A downloaded piece failed its hash check
+    HashFailure(u32),
+    /// No tracker could be announced to
+    TrackerFailure(String),
+    /// Another torrent has a file at the same path with different data
+    Collision(String),
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::DiskFull(_) => ErrorKind::DiskFull,
+            Error::PermissionDenied(_) => ErrorKind::PermissionDenied,
+            Error::MissingFile(_) => ErrorKind::MissingFile,
+            Error::Transient(_) | Error::Io(_) => ErrorKind::Io,
+            Error::HashFailure(_) => ErrorKind::HashFailure,
+            Error::TrackerFailure(_) => ErrorKind::TrackerFailure,
+            Error::Collision(_) => ErrorKind::FileCollision,
+        }
+    }
+
+    /// Whether the torrent is stopped until the error is resolved.
+    pub fn fatal(&self) -> bool {
+        !matches!(self, Error::HashFailure(_) | Error::TrackerFailure(_))
+    }
+
+    /// Whether the failed jobs should be retried after a while.
+    pub fn transient(&self) -> bool {
+        matches!(self, Error::DiskFull(_) | Error::Transient(_))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        let msg = err.to_string();
+        match err.raw_os_error() {
+            Some(ENOSPC) | Some(EDQUOT) => return Error::DiskFull(msg),
+            Some(EIO) | Some(EAGAIN) => return Error::Transient(msg),
+            _ => {}
+        }
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(msg),
+            io::ErrorKind::NotFound => Error::MissingFile(msg),
+            _ => Error::Io(msg),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DiskFull(msg)
+            | Error::PermissionDenied(msg)
+            | Error::MissingFile(msg)
+            | Error::Transient(msg)
+            | Error::Io(msg)
+            | Error::TrackerFailure(msg)
+            | Error::Collision(msg) => write!(f, "{}", msg),
+            Error::HashFailure(piece) => write!(f, "Piece {} failed its hash check", piece),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io() {
+        let full = io::Error::from_raw_os_error(ENOSPC);
+        assert_eq!(Error::from(full).kind(), ErrorKind::DiskFull);
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(Error::from(denied).kind(), ErrorKind::PermissionDenied);
+        let missing = io::Error::from_raw_os_error(2);
+        assert_eq!(Error::from(missing).kind(), ErrorKind::MissingFile);
+        let failed = Error::from(io::Error::from_raw_os_error(EIO));
+        assert!(failed.transient() && failed.fatal());
+        let other = io::Error::other("bad");
+        assert_eq!(Error::from(other), Error::Io("bad".to_owned()));
+    }
+}
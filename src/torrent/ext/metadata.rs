@@ -0,0 +1,209 @@
+//! Metadata exchange (BEP 9), used to download the info dict of magnets.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::{Dict, Extension, LIMITS};
+use crate::bencode;
+use crate::control::cio;
+use crate::torrent::{Info, Message, Peer, Torrent, MAX_INFO_BYTES};
+
+pub const NAME: &str = "ut_metadata";
+
+pub fn extension<T: cio::CIO>() -> Extension<T> {
+    Extension {
+        name: NAME,
+        enabled: |_| true,
+        handshake: Some(handshake),
+        handler: handle,
+    }
+}
+
+fn handshake<T: cio::CIO>(t: &mut Torrent<T>, peer: &mut Peer<T>, d: &Dict<'_>) -> Result<(), ()> {
+    let size = d
+        .get(b"metadata_size".as_ref())
+        .and_then(bencode::BEncodeRef::as_int)
+        .ok_or(())?;
+    if let Some(std::usize::MAX) = t.info_idx {
+        if size == 0 {
+            debug!("UT metadata size is 0");
+            return Err(());
+        }
+        if size % 16_384 == 0 {
+            t.info_idx = Some(size as usize / 16_384 - 1);
+        } else {
+            t.info_idx = Some(size as usize / 16_384);
+        }
+        if size > MAX_INFO_BYTES {
+            debug!("UT metadata too large, {} MBs", size / (1000 * 1000));
+            return Err(());
+        }
+        t.info_bytes.resize(size as usize, 0u8);
+    }
+    if !t.info.complete() {
+        // Request the first index chunk to see if they have it
+        let mut respb = BTreeMap::new();
+        respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
+        respb.insert(b"piece".to_vec(), bencode::BEncode::Int(0));
+        let payload = bencode::BEncode::Dict(respb).encode_to_buf();
+        let utm_id = if let Some(i) = peer.exts().get(NAME) {
+            i
+        } else {
+            return Err(());
+        };
+        peer.send_message(Message::Extension {
+            id: utm_id,
+            payload: payload.into(),
+        });
+    }
+    Ok(())
+}
+
+fn handle<T: cio::CIO>(t: &mut Torrent<T>, peer: &mut Peer<T>, payload: Vec<u8>) -> Result<(), ()> {
+    let utm_id = if let Some(i) = peer.exts().get(NAME) {
+        i
+    } else {
+        return Ok(());
+    };
+    // Pieces of metadata follow the dict
+    let (buf, data_idx) = bencode::decode_ref_prefix(&payload, &LIMITS).map_err(|_| ())?;
+    let dict = buf.as_dict().ok_or(())?;
+    let msg = dict
+        .get(b"msg_type".as_ref())
+        .and_then(bencode::BEncodeRef::as_int)
+        .ok_or(())?;
+    let piece_len = dict
+        .get(b"piece".as_ref())
+        .and_then(bencode::BEncodeRef::as_int)
+        .ok_or(())? as usize;
+    if piece_len * 16_384 >= t.info_bytes.len() {
+        return Err(());
+    }
+    // Our metadata request strategy is as follows: after requesting the first
+    // index chunk, we attempt to request every single subsequent chunk from
+    // a peer which responds succesfully. This is slightly wasteful, but
+    // simplifies logic (since we don't have to do "index piece picking").
+    match msg {
+        0 => {
+            let mut respb = BTreeMap::new();
+            if t.info_idx.is_none() {
+                respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(1));
+                respb.insert(b"piece".to_vec(), bencode::BEncode::Int(piece_len as i64));
+                let size = if t.info_bytes.len() / 16_384 == piece_len {
+                    t.info_bytes.len() % 16_384
+                } else {
+                    16_384
+                };
+                let total_size = t.info_bytes.len() as i64;
+                respb.insert(b"total_size".to_vec(), bencode::BEncode::Int(total_size));
+                let mut payload = bencode::BEncode::Dict(respb).encode_to_buf();
+                let s = piece_len * 16_384;
+                payload.extend_from_slice(&t.info_bytes[s..s + size]);
+                peer.send_message(Message::Extension {
+                    id: utm_id,
+                    payload: payload.into(),
+                });
+            } else {
+                respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(2));
+                respb.insert(b"piece".to_vec(), bencode::BEncode::Int(piece_len as i64));
+                let payload = bencode::BEncode::Dict(respb).encode_to_buf();
+                peer.send_message(Message::Extension {
+                    id: utm_id,
+                    payload: payload.into(),
+                });
+            }
+        }
+        1 => {
+            if let Some(last_idx) = t.info_idx {
+                if payload.len() - data_idx > t.info_bytes.len() - piece_len * 16_384 {
+                    debug!(
+                        "Metadata bounds invalid, goes to: {}, ibl: {}",
+                        payload.len() - data_idx,
+                        t.info_bytes.len() - piece_len * 16_384,
+                    );
+                    return Err(());
+                }
+                let total_size = dict
+                    .get(b"total_size".as_ref())
+                    .and_then(bencode::BEncodeRef::as_int)
+                    .ok_or(())? as usize;
+                if total_size != t.info_bytes.len() {
+                    if total_size > MAX_INFO_BYTES as usize {
+                        debug!("UT metadata too large, {} MBs", total_size / (1000 * 1000));
+                        return Err(());
+                    };
+                    // To be safe, we should ensure that the new total_size matches the number of index pieces.
+                    // Otherwise we may request the wrong number of pieces.
+                    let implied_last_info_idx = if total_size % 16_384 == 0 {
+                        total_size / 16_384 - 1
+                    } else {
+                        total_size / 16_384
+                    };
+                    if implied_last_info_idx != last_idx {
+                        debug!(
+                            "metadata_size {} and total_size {} differ in count of index pieces",
+                            t.info_bytes.len(),
+                            total_size
+                        );
+                        return Err(());
+                    }
+                    debug!(
+                        "metadata_size {} != total_size {} will use total_size",
+                        t.info_bytes.len(),
+                        total_size
+                    );
+                    t.info_bytes.resize(total_size, 0);
+                }
+                let size = if piece_len == last_idx {
+                    if total_size > 16_384 {
+                        total_size % 16_384
+                    } else {
+                        total_size
+                    }
+                } else {
+                    16_384
+                };
+                (&mut t.info_bytes[piece_len * 16_384..piece_len * 16_384 + size])
+                    .copy_from_slice(&payload[data_idx..]);
+                if piece_len == last_idx {
+                    let ni = {
+                        let bni = bencode::decode_ref_with(&t.info_bytes, &Info::LIMITS)
+                            .map_err(|_| ())?;
+                        let announce = t.info.announce.as_ref().map(|u| u.as_str());
+                        let mut b = BTreeMap::new();
+                        b.insert(
+                            b"announce".as_ref(),
+                            bencode::BEncodeRef::String(announce.unwrap_or("").as_bytes()),
+                        );
+                        b.insert(b"info".as_ref(), bni);
+                        Info::from_bencode(&bencode::BEncodeRef::Dict(b)).map_err(|_| ())?
+                    };
+                    if ni.hash == t.info.hash {
+                        debug!("Magnet file acquired succesfully!");
+                        t.info_idx = None;
+                        t.info = Arc::new(ni);
+                        t.magnet_complete();
+                    } else {
+                        return Err(());
+                    }
+                } else if piece_len == 0 {
+                    for i in 1..=last_idx {
+                        let mut respb = BTreeMap::new();
+                        respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
+                        respb.insert(b"piece".to_vec(), bencode::BEncode::Int(i as i64));
+                        let payload = bencode::BEncode::Dict(respb).encode_to_buf();
+                        peer.send_message(Message::Extension {
+                            id: utm_id,
+                            payload: payload.into(),
+                        });
+                    }
+                }
+            }
+        }
+        2 => {}
+        i => {
+            debug!("Got unknown ut_meta id: {}", i);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,378 @@
+//! Extension messages (BEP 10). Each extension is registered under the
+//! name it's advertised with in the extension handshake, and is assigned
+//! the next free ID, which peers send its messages to us with.
+//!
+//! Extensions which don't need access to the torrent are added by
+//! implementing `ExtensionHandler` and passing it to `register` before
+//! torrents are loaded. They're assigned IDs after the built-in extensions,
+//! in the order they're registered.
+
+mod metadata;
+pub mod pex;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::bencode::{self, BEncode, BEncodeRef};
+use crate::control::cio;
+use crate::torrent::{Info, Message, Peer, Torrent};
+
+pub type Dict<'a> = BTreeMap<&'a [u8], BEncodeRef<'a>>;
+pub type Handshake<T> = fn(&mut Torrent<T>, &mut Peer<T>, &Dict<'_>) -> Result<(), ()>;
+
+/// Limits on decoding extension messages, none of which nest deeply.
+pub const LIMITS: bencode::Limits = bencode::Limits {
+    depth: 4,
+    items: 1024,
+    string_len: 16_384,
+};
+pub type Handler<T> = fn(&mut Torrent<T>, &mut Peer<T>, Vec<u8>) -> Result<(), ()>;
+
+lazy_static! {
+    static ref HANDLERS: Mutex<Vec<Arc<dyn ExtensionHandler>>> = Mutex::new(Vec::new());
+}
+
+/// Extension implemented outside of the torrent module. Handlers are
+/// shared by every torrent, and only see the peer through a `Context`.
+pub trait ExtensionHandler: Send + Sync {
+    /// Name used in the m dict of the extension handshake
+    fn name(&self) -> &'static str;
+
+    /// Whether the extension is advertised to the peers of a torrent.
+    fn enabled(&self, _info: &Info) -> bool {
+        true
+    }
+
+    /// Called with the extension handshake of peers supporting the
+    /// extension. An error disconnects the peer.
+    fn handshake(&self, _ctx: &mut Context, _handshake: &Dict<'_>) -> Result<(), ()> {
+        Ok(())
+    }
+
+    /// Handles a message, an error disconnecting the peer.
+    fn handle(&self, ctx: &mut Context, payload: &[u8]) -> Result<(), ()>;
+}
+
+/// Peer an `ExtensionHandler` is called for.
+pub struct Context {
+    pub info: Arc<Info>,
+    pub addr: SocketAddr,
+    /// ID the peer assigned the extension, if it supports it
+    pub id: Option<u8>,
+    out: Vec<Vec<u8>>,
+}
+
+/// Names of the built-in extensions, which can't be registered.
+const BUILTIN: [&str; 2] = [metadata::NAME, pex::NAME];
+
+/// Adds an extension to the torrents loaded afterwards, failing if its
+/// name is taken by a built-in or previously registered extension.
+pub fn register(handler: Arc<dyn ExtensionHandler>) -> Result<(), String> {
+    insert(&mut HANDLERS.lock().unwrap(), handler)
+}
+
+fn insert(
+    handlers: &mut Vec<Arc<dyn ExtensionHandler>>,
+    handler: Arc<dyn ExtensionHandler>,
+) -> Result<(), String> {
+    let name = handler.name();
+    if BUILTIN.contains(&name) || handlers.iter().any(|h| h.name() == name) {
+        return Err(format!("extension {} is already registered", name));
+    }
+    handlers.push(handler);
+    Ok(())
+}
+
+pub struct Extension<T: cio::CIO> {
+    /// Name used in the m dict of the extension handshake
+    pub name: &'static str,
+    /// Whether the extension is advertised to the peers of a torrent
+    pub enabled: fn(&Torrent<T>) -> bool,
+    /// Called with the extension handshake of peers supporting the
+    /// extension, after the m dict is removed from it
+    pub handshake: Option<Handshake<T>>,
+    /// Handles a message, an error disconnecting the peer
+    pub handler: Handler<T>,
+}
+
+enum Entry<T: cio::CIO> {
+    Builtin(Extension<T>),
+    Custom(Arc<dyn ExtensionHandler>),
+}
+
+pub struct Registry<T: cio::CIO> {
+    exts: Vec<Entry<T>>,
+}
+
+impl<T: cio::CIO> Registry<T> {
+    /// Creates a registry of the built-in and registered extensions.
+    pub fn new() -> Registry<T> {
+        let mut r = Registry { exts: Vec::new() };
+        r.register(metadata::extension());
+        r.register(pex::extension());
+        for handler in HANDLERS.lock().unwrap().iter() {
+            r.add(handler.clone());
+        }
+        r
+    }
+
+    /// Registers an extension, returning its ID.
+    pub fn register(&mut self, ext: Extension<T>) -> u8 {
+        self.push(Entry::Builtin(ext))
+    }
+
+    /// Registers a custom extension, returning its ID.
+    pub fn add(&mut self, handler: Arc<dyn ExtensionHandler>) -> u8 {
+        self.push(Entry::Custom(handler))
+    }
+
+    fn push(&mut self, entry: Entry<T>) -> u8 {
+        assert!(self.exts.len() < 255, "Extension IDs exhausted");
+        self.exts.push(entry);
+        self.exts.len() as u8
+    }
+
+    fn get(&self, id: u8) -> Option<&Entry<T>> {
+        self.exts.get((id as usize).checked_sub(1)?)
+    }
+
+    /// Whether an extension of a name is registered
+    pub fn contains(&self, name: &str) -> bool {
+        self.exts.iter().any(|ext| ext.name() == name)
+    }
+
+    /// Number of registered extensions, which have IDs 1 through it
+    pub fn count(&self) -> u8 {
+        self.exts.len() as u8
+    }
+
+    /// The m dict of the extension handshake sent to a torrent's peers.
+    pub fn advertised(&self, t: &Torrent<T>) -> BTreeMap<Vec<u8>, BEncode> {
+        self.exts
+            .iter()
+            .zip(1..)
+            .filter(|(ext, _)| match ext {
+                Entry::Builtin(ext) => (ext.enabled)(t),
+                Entry::Custom(handler) => handler.enabled(&t.info),
+            })
+            .map(|(ext, id)| (ext.name().as_bytes().to_vec(), BEncode::Int(id)))
+            .collect()
+    }
+}
+
+impl<T: cio::CIO> Entry<T> {
+    fn name(&self) -> &'static str {
+        match self {
+            Entry::Builtin(ext) => ext.name,
+            Entry::Custom(handler) => handler.name(),
+        }
+    }
+}
+
+impl Context {
+    fn new<T: cio::CIO>(t: &Torrent<T>, peer: &Peer<T>, name: &str) -> Context {
+        Context {
+            info: t.info.clone(),
+            addr: peer.addr(),
+            id: peer.exts().get(name),
+            out: Vec::new(),
+        }
+    }
+
+    /// Queues a message of the extension to the peer, which is dropped if
+    /// the peer doesn't support the extension.
+    pub fn send(&mut self, payload: Vec<u8>) {
+        self.out.push(payload);
+    }
+
+    fn flush<T: cio::CIO>(self, peer: &mut Peer<T>) {
+        if let Some(id) = self.id {
+            for payload in self.out {
+                peer.send_message(Message::Extension {
+                    id,
+                    payload: payload.into(),
+                });
+            }
+        }
+    }
+}
+
+/// Passes the extension handshake of a peer, without its m dict, to the
+/// extensions it supports. Only the IDs of registered extensions are kept.
+pub fn handshake<T: cio::CIO>(
+    t: &mut Torrent<T>,
+    peer: &mut Peer<T>,
+    m: &Dict<'_>,
+    d: &Dict<'_>,
+) -> Result<(), ()> {
+    peer.exts_mut().retain(|name| t.exts.contains(name));
+    for id in 1..=t.exts.count() {
+        match t.exts.get(id) {
+            Some(Entry::Builtin(ext)) if m.contains_key(ext.name.as_bytes()) => {
+                if let Some(handshake) = ext.handshake {
+                    handshake(t, peer, d)?;
+                }
+            }
+            Some(Entry::Custom(handler))
+                if m.contains_key(handler.name().as_bytes()) && handler.enabled(&t.info) =>
+            {
+                let handler = handler.clone();
+                let mut ctx = Context::new(t, peer, handler.name());
+                let res = handler.handshake(&mut ctx, d);
+                ctx.flush(peer);
+                res?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Handles a message sent with one of our extension IDs.
+pub fn handle<T: cio::CIO>(
+    t: &mut Torrent<T>,
+    peer: &mut Peer<T>,
+    id: u8,
+    payload: Vec<u8>,
+) -> Result<(), ()> {
+    match t.exts.get(id) {
+        Some(Entry::Builtin(ext)) => {
+            let handler = ext.handler;
+            handler(t, peer, payload)
+        }
+        Some(Entry::Custom(handler)) if handler.enabled(&t.info) => {
+            let handler = handler.clone();
+            let mut ctx = Context::new(t, peer, handler.name());
+            let res = handler.handle(&mut ctx, &payload);
+            ctx.flush(peer);
+            res
+        }
+        Some(Entry::Custom(handler)) => {
+            debug!("Got message of disabled extension {}", handler.name());
+            Ok(())
+        }
+        None => {
+            debug!("Got unknown extension id: {}", id);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{handle, insert, Context, Extension, ExtensionHandler, Registry};
+    use crate::control::cio::test::TCIO;
+    use crate::control::cio::CIO;
+    use crate::throttle::Throttler;
+    use crate::torrent::{Info, Message, Peer, Torrent};
+
+    struct Auth;
+
+    impl ExtensionHandler for Auth {
+        fn name(&self) -> &'static str {
+            "x_auth"
+        }
+
+        fn enabled(&self, info: &Info) -> bool {
+            !info.private
+        }
+
+        fn handle(&self, ctx: &mut Context, payload: &[u8]) -> Result<(), ()> {
+            ctx.send(payload.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let mut r = Registry::<TCIO>::new();
+        let name = |r: &Registry<TCIO>, id| r.get(id).map(|ext| ext.name());
+        assert_eq!(name(&r, 1), Some("ut_metadata"));
+        assert_eq!(name(&r, 2), Some("ut_pex"));
+        let id = r.register(Extension {
+            name: "lt_donthave",
+            enabled: |_| true,
+            handshake: None,
+            handler: |_, _, _| Ok(()),
+        });
+        assert_eq!(id, 3);
+        assert_eq!(r.add(Arc::new(Auth)), 4);
+        assert_eq!(r.count(), 4);
+        assert_eq!(name(&r, 3), Some("lt_donthave"));
+        assert_eq!(name(&r, 4), Some("x_auth"));
+        assert!(r.get(0).is_none());
+        assert!(r.get(5).is_none());
+        assert!(r.contains("x_auth"));
+        assert!(!r.contains("lt_tex"));
+    }
+
+    struct Named(&'static str);
+
+    impl ExtensionHandler for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn handle(&self, _: &mut Context, _: &[u8]) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_register_collision() {
+        let mut handlers = Vec::new();
+        assert!(insert(&mut handlers, Arc::new(Named("ut_pex"))).is_err());
+        assert!(insert(&mut handlers, Arc::new(Named("ut_metadata"))).is_err());
+        assert!(insert(&mut handlers, Arc::new(Auth)).is_ok());
+        assert!(insert(&mut handlers, Arc::new(Named("x_auth"))).is_err());
+        assert!(insert(&mut handlers, Arc::new(Named("lt_donthave"))).is_ok());
+        assert_eq!(handlers.len(), 2);
+    }
+
+    #[test]
+    fn test_custom_handler() {
+        let poller = amy::Poller::new().unwrap();
+        let reg = poller.get_registrar();
+        let throttler = Throttler::new(None, None, 1000, &reg).unwrap();
+        let tcio = TCIO::new();
+        let torrent = |private| {
+            let mut info = Info::with_pieces(4);
+            info.piece_idx = Info::generate_piece_idx(4, 16_384, &info.files);
+            info.private = private;
+            let throttle = throttler.get_throttle(0);
+            Torrent::new(
+                0,
+                None,
+                info,
+                throttle,
+                tcio.new_handle(),
+                false,
+                false,
+                false,
+            )
+        };
+        let mut t = torrent(false);
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let id = t.exts.add(Arc::new(Auth));
+        peer.exts_mut().insert("x_auth", 7);
+        handle(&mut t, &mut peer, id, b"hi".to_vec()).unwrap();
+        let replies: Vec<_> = tcio
+            .peer_msgs()
+            .into_iter()
+            .filter_map(|(_, msg)| match msg {
+                Message::Extension { id, payload } => Some((id, payload.to_vec())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(replies, vec![(7, b"hi".to_vec())]);
+
+        // Messages of extensions disabled for a torrent aren't handled
+        let mut t = torrent(true);
+        let id = t.exts.add(Arc::new(Auth));
+        handle(&mut t, &mut peer, id, b"hi".to_vec()).unwrap();
+        assert_eq!(tcio.peer_msgs().len(), 1);
+    }
+}
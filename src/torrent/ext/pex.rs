@@ -0,0 +1,69 @@
+//! Peer exchange (BEP 11), disabled for private torrents.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::Extension;
+use crate::bencode;
+use crate::control::cio;
+use crate::rpc::resource::PeerSource;
+use crate::torrent::{Peer, Torrent};
+use crate::tracker;
+
+pub const NAME: &str = "ut_pex";
+
+pub fn extension<T: cio::CIO>() -> Extension<T> {
+    Extension {
+        name: NAME,
+        enabled: |t| t.allows_source(PeerSource::Pex),
+        handshake: None,
+        handler: handle,
+    }
+}
+
+fn handle<T: cio::CIO>(t: &mut Torrent<T>, peer: &mut Peer<T>, payload: Vec<u8>) -> Result<(), ()> {
+    const PEX_SEED: u8 = 0x02;
+    const PEX_OUTGOING: u8 = 0x10;
+    if peer.exts().get(NAME).is_none() {
+        return Ok(());
+    }
+    if t.info.private {
+        return Err(());
+    }
+    if !t.pex {
+        return Ok(());
+    }
+    let b = bencode::decode_ref_with(&payload, &super::LIMITS).map_err(|_| ())?;
+    let d = b.as_dict().ok_or(())?;
+    let mut peers = vec![];
+    let flags = d
+        .get(b"added.f".as_ref())
+        .and_then(bencode::BEncodeRef::as_bytes)
+        .unwrap_or(&[0; 50]);
+    if let Some(data) = d
+        .get(b"added".as_ref())
+        .and_then(bencode::BEncodeRef::as_bytes)
+    {
+        for (p, &flag) in data.chunks(6).zip(flags) {
+            if (flag & PEX_SEED != 0) && t.complete() {
+                continue;
+            }
+            if flag & PEX_OUTGOING == 0 {
+                continue;
+            }
+
+            let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
+            let socket = SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..]));
+            peers.push(SocketAddr::V4(socket));
+        }
+    }
+    if !peers.is_empty() {
+        t.cio
+            .propagate(cio::Event::Tracker(Ok(tracker::Response::PEX {
+                tid: t.id,
+                peers,
+            })));
+    }
+    Ok(())
+}
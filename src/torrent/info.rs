@@ -1,12 +1,12 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, mem};
 
 use rand::{self, Rng};
 use url::Url;
 
-use crate::bencode::BEncode;
+use crate::bencode::{self, BEncode, BEncodeRef};
 use crate::disk;
 use crate::util::{hash_to_id, id_to_hash, sha1_hash};
 
@@ -54,34 +54,66 @@ impl fmt::Debug for Info {
 pub struct File {
     pub path: PathBuf,
     pub length: u64,
+    /// BEP 47 attributes, e.g. "p" for padding or "x" for executable
+    pub attr: Option<String>,
+    /// Target of a symlink file, relative to the torrent's root
+    pub symlink: Option<PathBuf>,
 }
 
 impl File {
-    fn from_bencode(data: BEncode) -> Result<File, &'static str> {
-        let mut d = data.into_dict().ok_or("File must be a dictionary type!")?;
-        match (
-            d.remove(b"name".as_ref()),
-            d.remove(b"path".as_ref()),
-            d.remove(b"length".as_ref()),
-        ) {
+    /// Whether the file only pads the next file to a piece boundary,
+    /// in which case it is all zeros and never stored on disk.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    fn has_attr(&self, attr: char) -> bool {
+        self.attr
+            .as_ref()
+            .map(|a| a.contains(attr))
+            .unwrap_or(false)
+    }
+
+    fn from_bencode(data: &BEncodeRef<'_>) -> Result<File, &'static str> {
+        File::from_dict(data.as_dict().ok_or("File must be a dictionary type!")?)
+    }
+
+    fn from_dict(d: &BTreeMap<&[u8], BEncodeRef<'_>>) -> Result<File, &'static str> {
+        let attr = d
+            .get(b"attr".as_ref())
+            .and_then(BEncodeRef::as_str)
+            .map(str::to_owned);
+        let symlink = match d.get(b"symlink path".as_ref()) {
+            Some(path) if attr.as_ref().map(|a| a.contains('l')).unwrap_or(false) => {
+                Some(parse_path(path).map_err(|_| "Symlink path must be a valid path")?)
+            }
+            _ => None,
+        };
+        let length = d
+            .get(b"length".as_ref())
+            .map(|l| l.as_int().filter(|&l| l >= 0))
+            .map(|l| l.ok_or("File length must be a valid int"))
+            .transpose()?;
+        match (d.get(b"name".as_ref()), d.get(b"path".as_ref()), length) {
             (Some(v), None, Some(l)) => {
                 let f = File {
-                    path: PathBuf::from(v.into_string().ok_or("Path must be a valid string.")?),
-                    length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    path: PathBuf::from(parse_name(v)?),
+                    length: l as u64,
+                    attr,
+                    symlink,
                 };
                 Ok(f)
             }
             (None, Some(path), Some(l)) => {
-                let mut p = PathBuf::new();
-                for dir in path.into_list().ok_or("File path should be a list")? {
-                    p.push(
-                        dir.into_string()
-                            .ok_or("File path parts should be strings")?,
-                    );
-                }
                 let f = File {
-                    path: p,
-                    length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    path: parse_path(path)?,
+                    length: l as u64,
+                    attr,
+                    symlink,
                 };
                 Ok(f)
             }
@@ -91,6 +123,14 @@ impl File {
 }
 
 impl Info {
+    /// Limits on decoding metainfo, in which only the file list and the
+    /// paths of files nest.
+    pub const LIMITS: bencode::Limits = bencode::Limits {
+        depth: 32,
+        items: 1 << 22,
+        string_len: 1 << 27,
+    };
+
     pub fn from_magnet(data: &str) -> Result<Info, &'static str> {
         let url = match Url::parse(data) {
             Ok(u) => u,
@@ -187,6 +227,12 @@ impl Info {
                 b"length".to_vec(),
                 BEncode::Int(self.files[0].length as i64),
             );
+            if let Some(ref a) = self.files[0].attr {
+                info.insert(b"attr".to_vec(), BEncode::String(a.clone().into_bytes()));
+            }
+            if let Some(ref s) = self.files[0].symlink {
+                info.insert(b"symlink path".to_vec(), symlink_bencode(s));
+            }
         } else {
             let files = self
                 .files
@@ -194,6 +240,12 @@ impl Info {
                 .map(|f| {
                     let mut fb = BTreeMap::new();
                     fb.insert(b"length".to_vec(), BEncode::Int(f.length as i64));
+                    if let Some(ref a) = f.attr {
+                        fb.insert(b"attr".to_vec(), BEncode::String(a.clone().into_bytes()));
+                    }
+                    if let Some(ref s) = f.symlink {
+                        fb.insert(b"symlink path".to_vec(), symlink_bencode(s));
+                    }
                     fb.insert(
                         b"path".to_vec(),
                         BEncode::String(
@@ -213,130 +265,102 @@ impl Info {
         BEncode::Dict(info)
     }
 
-    pub fn from_bencode(data: BEncode) -> Result<Info, &'static str> {
-        data.into_dict()
-            .and_then(|mut d| {
-                d.remove(b"info".as_ref())
-                    .and_then(|i| i.into_dict())
-                    .map(|i| (d, i))
-            })
-            .ok_or("invalid info field")
-            .and_then(|(mut d, mut i)| {
-                let mut info_bytes = Vec::new();
-                BEncode::Dict(i.clone()).encode(&mut info_bytes).unwrap();
-                let hash = sha1_hash(&info_bytes);
-
-                let announce = d
-                    .remove(b"announce".as_ref())
-                    .and_then(BEncode::into_string)
-                    .and_then(|a| Url::parse(&a).ok().map(Arc::new));
-                let comment = d.remove(b"comment".as_ref()).and_then(|b| b.into_string());
-                let creator = d
-                    .remove(b"created by".as_ref())
-                    .and_then(|b| b.into_string());
-                let pl = i
-                    .remove(b"piece length".as_ref())
-                    .and_then(|i| i.into_int())
-                    .ok_or("Info must specify piece length")? as u64;
-                let hashes = i
-                    .remove(b"pieces".as_ref())
-                    .and_then(|p| p.into_bytes())
-                    .and_then(|p| {
-                        let mut v = Vec::new();
-                        let mut s = &p[..];
-                        while s.len() >= 20 {
-                            let mut next = vec![0u8; 20];
-                            next.clone_from_slice(&s[..20]);
-                            v.push(next);
-                            s = &s[20..];
-                        }
-                        if !s.is_empty() {
-                            return None;
-                        }
-                        Some(v)
-                    })
-                    .ok_or("Info must provide valid hashes")?;
-
-                let private = if let Some(v) = i.remove(b"private".as_ref()) {
-                    v.into_int()
-                        .and_then(|p| {
-                            if p == 0 {
-                                Some(false)
-                            } else if p == 1 {
-                                Some(true)
-                            } else {
-                                None
-                            }
-                        })
-                        .ok_or("private key must be an integer equal to 0 or 1 if present!")?
-                } else {
-                    false
-                };
-
-                let be_name = if let Some(v) = i.get(b"name".as_ref()).cloned() {
-                    Some(v.into_bytes().ok_or("name field must be a bitstring!")?)
-                } else {
-                    None
-                };
-
-                let files = parse_bencode_files(i)?;
-                let name = if files.is_empty() {
-                    files[0]
-                        .path
-                        .clone()
-                        .into_os_string()
-                        .into_string()
-                        .map_err(|_| "Only UTF8 paths are accepted")?
-                } else if !files[0].path.has_root() {
-                    let mut piter = files[0].path.components();
-                    piter
-                        .next()
-                        .unwrap()
-                        .as_os_str()
-                        .to_os_string()
-                        .into_string()
-                        .map_err(|_| "Only UTF8 paths are accepted")?
-                } else {
-                    unreachable!()
-                };
+    pub fn from_bencode(data: &BEncodeRef<'_>) -> Result<Info, &'static str> {
+        let d = data.as_dict().ok_or("invalid info field")?;
+        let info = d.get(b"info".as_ref()).ok_or("invalid info field")?;
+        let i = info.as_dict().ok_or("invalid info field")?;
+        let hash = sha1_hash(&info.encode_to_buf());
+
+        let announce = d
+            .get(b"announce".as_ref())
+            .and_then(BEncodeRef::as_str)
+            .and_then(|a| Url::parse(a).ok().map(Arc::new));
+        let comment = d
+            .get(b"comment".as_ref())
+            .and_then(BEncodeRef::as_str)
+            .map(str::to_owned);
+        let creator = d
+            .get(b"created by".as_ref())
+            .and_then(BEncodeRef::as_str)
+            .map(str::to_owned);
+        let pl = i
+            .get(b"piece length".as_ref())
+            .and_then(BEncodeRef::as_int)
+            .ok_or("Info must specify piece length")? as u64;
+        let hashes = i
+            .get(b"pieces".as_ref())
+            .and_then(BEncodeRef::as_bytes)
+            .filter(|p| p.len() % 20 == 0)
+            .map(|p| p.chunks(20).map(<[u8]>::to_vec).collect::<Vec<_>>())
+            .ok_or("Info must provide valid hashes")?;
+
+        let private = match i.get(b"private".as_ref()) {
+            Some(v) => match v.as_int() {
+                Some(0) => false,
+                Some(1) => true,
+                _ => return Err("private key must be an integer equal to 0 or 1 if present!"),
+            },
+            None => false,
+        };
 
-                let total_len = files.iter().map(|f| f.length).sum();
-                let piece_idx = Info::generate_piece_idx(hashes.len(), pl, &files);
+        let be_name = match i.get(b"name".as_ref()) {
+            Some(v) => Some(
+                v.as_bytes()
+                    .ok_or("name field must be a bitstring!")?
+                    .to_vec(),
+            ),
+            None => None,
+        };
 
-                let url_list: Vec<_> = d
-                    .remove(b"announce-list".as_ref())
-                    .and_then(BEncode::into_list)
-                    .unwrap_or_else(Vec::new)
-                    .into_iter()
-                    .map(|l| {
-                        let mut l: Vec<_> = l
-                            .into_list()
-                            .unwrap_or_else(Vec::new)
-                            .into_iter()
-                            .filter_map(BEncode::into_string)
-                            .filter_map(|s| Url::parse(&s).ok().map(Arc::new))
-                            .collect();
-                        rand::thread_rng().shuffle(&mut l[..]);
-                        l
-                    })
+        let files = parse_bencode_files(i)?;
+        // Paths are sanitized, so the first component is the torrent's
+        // file or directory
+        let name = files
+            .first()
+            .and_then(|f| f.path.components().next())
+            .ok_or("Torrent must contain files")?
+            .as_os_str()
+            .to_string_lossy()
+            .into_owned();
+
+        let total_len = files.iter().map(|f| f.length).sum();
+        let piece_idx = Info::generate_piece_idx(hashes.len(), pl, &files);
+
+        let url_list: Vec<_> = d
+            .get(b"announce-list".as_ref())
+            .and_then(BEncodeRef::as_list)
+            .map(|tiers| &tiers[..])
+            .unwrap_or(&[])
+            .iter()
+            .map(|l| {
+                let mut l: Vec<_> = l
+                    .as_list()
+                    .map(|l| &l[..])
+                    .unwrap_or(&[])
+                    .iter()
+                    .filter_map(BEncodeRef::as_str)
+                    .filter_map(|s| Url::parse(s).ok().map(Arc::new))
                     .collect();
-
-                Ok(Info {
-                    name,
-                    comment,
-                    creator,
-                    announce,
-                    piece_len: pl as u32,
-                    hashes,
-                    hash,
-                    files,
-                    total_len,
-                    private,
-                    be_name,
-                    piece_idx,
-                    url_list,
-                })
+                rand::thread_rng().shuffle(&mut l[..]);
+                l
             })
+            .collect();
+
+        Ok(Info {
+            name,
+            comment,
+            creator,
+            announce,
+            piece_len: pl as u32,
+            hashes,
+            hash,
+            files,
+            total_len,
+            private,
+            be_name,
+            piece_idx,
+            url_list,
+        })
     }
 
     pub fn generate_piece_idx(pieces: usize, pl: u64, files: &[File]) -> Vec<(usize, u64)> {
@@ -369,6 +393,8 @@ impl Info {
                 File {
                     path: PathBuf::new(),
                     length: 16_384 * pieces as u64,
+                    attr: None,
+                    symlink: None,
                 };
                 1
             ],
@@ -429,9 +455,39 @@ impl Info {
         self.hashes.len() as u32
     }
 
-    /// Calculates the file offsets for a given block at index/begin
-    pub fn block_disk_locs(info: &Arc<Info>, index: u32, begin: u32) -> LocIter {
-        let len = info.block_len(index, begin);
+    /// Files other than padding, along with their index.
+    pub fn user_files(&self) -> impl Iterator<Item = (usize, &File)> {
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| !f.is_padding())
+    }
+
+    /// Hashes of the pieces lying wholly within a file, and the offset in
+    /// the file of the first of them.
+    pub fn file_hashes(&self, file: usize) -> (u64, &[Vec<u8>]) {
+        let start: u64 = self.files[..file].iter().map(|f| f.length).sum();
+        let end = start + self.files[file].length;
+        let pl = u64::from(self.piece_len);
+        let first = start.div_ceil(pl);
+        // The last piece is shorter, ending with the torrent
+        let last = if end == self.total_len {
+            u64::from(self.pieces())
+        } else {
+            end / pl
+        };
+        if first >= last {
+            return (0, &[]);
+        }
+        (
+            first * pl - start,
+            &self.hashes[first as usize..last as usize],
+        )
+    }
+
+    /// Calculates the file offsets of len bytes of a piece, which may
+    /// span several blocks
+    pub fn range_disk_locs(info: &Arc<Info>, index: u32, begin: u32, len: u32) -> LocIter {
         LocIter::new(info.clone(), None, index, begin, len)
     }
 
@@ -556,15 +612,89 @@ impl Iterator for LocIter {
     }
 }
 
-fn parse_bencode_files(mut data: BTreeMap<Vec<u8>, BEncode>) -> Result<Vec<File>, &'static str> {
-    match data.remove(b"files".as_ref()).and_then(|l| l.into_list()) {
+/// Longest file name most filesystems accept, in bytes.
+const MAX_COMPONENT_LEN: usize = 255;
+
+/// Device names which can't be used as file names on Windows.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Parses a torrent or file name, which must be a single usable component.
+fn parse_name(name: &BEncodeRef<'_>) -> Result<String, &'static str> {
+    let name = name.as_str().ok_or("Name must be a valid string")?;
+    sanitize_component(name.to_owned())?.ok_or("Name must not be empty")
+}
+
+/// Parses a list of path components into a relative path within the torrent.
+fn parse_path(path: &BEncodeRef<'_>) -> Result<PathBuf, &'static str> {
+    let mut p = PathBuf::new();
+    for part in path.as_list().ok_or("File path should be a list")? {
+        let part = part.as_str().ok_or("File path parts should be strings")?;
+        if let Some(part) = sanitize_component(part.to_owned())? {
+            p.push(part);
+        }
+    }
+    if p.as_os_str().is_empty() {
+        return Err("File path must not be empty");
+    }
+    Ok(p)
+}
+
+/// Makes a path component from the metainfo safe to use on disk. Parent
+/// components are rejected, empty and current directory ones are dropped,
+/// separators and control characters are replaced, reserved names are
+/// prefixed and over-long names are shortened, keeping their extension.
+fn sanitize_component(part: String) -> Result<Option<String>, &'static str> {
+    if part == ".." {
+        return Err("File paths must not contain parent components");
+    }
+    if part.is_empty() || part == "." {
+        return Ok(None);
+    }
+    let mut part: String = part
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let stem = part.split('.').next().unwrap_or("");
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        part.insert(0, '_');
+    }
+    if part.len() > MAX_COMPONENT_LEN {
+        let ext = match part.rfind('.') {
+            Some(i) if part.len() - i <= 16 => part[i..].to_owned(),
+            _ => String::new(),
+        };
+        let mut end = MAX_COMPONENT_LEN - ext.len();
+        while !part.is_char_boundary(end) {
+            end -= 1;
+        }
+        part.truncate(end);
+        part.push_str(&ext);
+    }
+    Ok(Some(part))
+}
+
+fn symlink_bencode(path: &Path) -> BEncode {
+    BEncode::List(
+        path.iter()
+            .map(|p| BEncode::String(p.to_string_lossy().into_owned().into_bytes()))
+            .collect(),
+    )
+}
+
+fn parse_bencode_files(data: &BTreeMap<&[u8], BEncodeRef<'_>>) -> Result<Vec<File>, &'static str> {
+    match data.get(b"files".as_ref()).and_then(BEncodeRef::as_list) {
         Some(fs) => {
-            let mut path = PathBuf::new();
-            path.push(
-                data.remove(b"name".as_ref())
-                    .and_then(|v| v.into_string())
+            let path = PathBuf::from(parse_name(
+                data.get(b"name".as_ref())
                     .ok_or("Multifile mode must have a name field")?,
-            );
+            )?);
             let mut files = Vec::new();
             for f in fs {
                 let mut file = File::from_bencode(f)?;
@@ -573,7 +703,7 @@ fn parse_bencode_files(mut data: BTreeMap<Vec<u8>, BEncode>) -> Result<Vec<File>
             }
             Ok(files)
         }
-        None => File::from_bencode(BEncode::Dict(data)).map(|f| vec![f]),
+        None => File::from_dict(data).map(|f| vec![f]),
     }
 }
 
@@ -600,6 +730,161 @@ mod tests {
         assert_eq!(info.block_len(pieces, 16_384), (end % 16_384) as u32);
     }
 
+    #[test]
+    fn padding_files() {
+        let file = |path: &str, length: i64, attr: Option<&str>| {
+            let mut f = BTreeMap::new();
+            f.insert(
+                b"path".to_vec(),
+                BEncode::List(vec![BEncode::String(path.as_bytes().to_vec())]),
+            );
+            f.insert(b"length".to_vec(), BEncode::Int(length));
+            if let Some(a) = attr {
+                f.insert(b"attr".to_vec(), BEncode::String(a.as_bytes().to_vec()));
+            }
+            BEncode::Dict(f)
+        };
+        let mut i = BTreeMap::new();
+        i.insert(b"name".to_vec(), BEncode::String(b"t".to_vec()));
+        i.insert(b"piece length".to_vec(), BEncode::Int(16_384));
+        i.insert(b"pieces".to_vec(), BEncode::String(vec![0; 40]));
+        i.insert(
+            b"files".to_vec(),
+            BEncode::List(vec![
+                file("a", 10_000, None),
+                file("pad", 6_384, Some("p")),
+                file("b", 10_000, Some("x")),
+            ]),
+        );
+        let mut t = BTreeMap::new();
+        t.insert(b"info".to_vec(), BEncode::Dict(i));
+        let data = BEncode::Dict(t).encode_to_buf();
+        let info = Info::from_bencode(&bencode::decode_ref(&data).unwrap()).unwrap();
+
+        assert!(!info.files[0].is_padding());
+        assert!(info.files[1].is_padding());
+        assert!(!info.files[2].is_padding());
+        assert_eq!(info.files[2].attr.as_deref(), Some("x"));
+        let files: Vec<_> = info.user_files().map(|(i, _)| i).collect();
+        assert_eq!(files, vec![0, 2]);
+        assert_eq!(info.total_len, 26_384);
+        assert!(info.file_hashes(0).1.is_empty());
+        assert_eq!(info.file_hashes(2), (0, &info.hashes[1..]));
+    }
+
+    #[test]
+    fn symlink_files() {
+        let file = |attr: &str, target: &[&str]| {
+            let mut f = BTreeMap::new();
+            f.insert(b"name".to_vec(), BEncode::String(b"l".to_vec()));
+            f.insert(b"length".to_vec(), BEncode::Int(0));
+            f.insert(b"attr".to_vec(), BEncode::String(attr.as_bytes().to_vec()));
+            f.insert(
+                b"symlink path".to_vec(),
+                BEncode::List(
+                    target
+                        .iter()
+                        .map(|p| BEncode::String(p.as_bytes().to_vec()))
+                        .collect(),
+                ),
+            );
+            File::from_bencode(&bencode::decode_ref(&BEncode::Dict(f).encode_to_buf()).unwrap())
+        };
+
+        let f = file("l", &["d", "a"]).unwrap();
+        assert_eq!(f.symlink, Some(PathBuf::from("d/a")));
+        assert!(!f.is_executable());
+        assert_eq!(file("x", &["d", "a"]).unwrap().symlink, None);
+        assert!(file("x", &["d", "a"]).unwrap().is_executable());
+        assert!(file("l", &["..", "a"]).is_err());
+        assert_eq!(
+            file("l", &["d/../../a"]).unwrap().symlink,
+            Some(PathBuf::from("d_.._.._a"))
+        );
+    }
+
+    fn torrent(name: &str, files: Option<Vec<Vec<&str>>>) -> Result<Info, &'static str> {
+        let mut i = BTreeMap::new();
+        i.insert(b"name".to_vec(), BEncode::String(name.as_bytes().to_vec()));
+        i.insert(b"piece length".to_vec(), BEncode::Int(16_384));
+        i.insert(b"pieces".to_vec(), BEncode::String(vec![0; 20]));
+        match files {
+            Some(files) => {
+                let files = files
+                    .into_iter()
+                    .map(|path| {
+                        let mut f = BTreeMap::new();
+                        f.insert(b"length".to_vec(), BEncode::Int(1));
+                        f.insert(
+                            b"path".to_vec(),
+                            BEncode::List(
+                                path.iter()
+                                    .map(|p| BEncode::String(p.as_bytes().to_vec()))
+                                    .collect(),
+                            ),
+                        );
+                        BEncode::Dict(f)
+                    })
+                    .collect();
+                i.insert(b"files".to_vec(), BEncode::List(files));
+            }
+            None => {
+                i.insert(b"length".to_vec(), BEncode::Int(1));
+            }
+        }
+        let mut t = BTreeMap::new();
+        t.insert(b"info".to_vec(), BEncode::Dict(i));
+        let data = BEncode::Dict(t).encode_to_buf();
+        Info::from_bencode(&bencode::decode_ref(&data).unwrap())
+    }
+
+    #[test]
+    fn malicious_paths() {
+        let paths =
+            |info: Info| -> Vec<PathBuf> { info.files.into_iter().map(|f| f.path).collect() };
+
+        assert!(torrent("t", Some(vec![vec!["..", "etc", "passwd"]])).is_err());
+        assert!(torrent("..", Some(vec![vec!["a"]])).is_err());
+        assert!(torrent("..", None).is_err());
+        assert!(torrent("", Some(vec![vec!["a"]])).is_err());
+        assert!(torrent("t", Some(vec![vec!["", "."]])).is_err());
+        assert!(torrent("t", Some(vec![])).is_err());
+
+        let info = torrent("/etc/passwd", None).unwrap();
+        assert_eq!(info.name, "_etc_passwd");
+        assert_eq!(paths(info), vec![PathBuf::from("_etc_passwd")]);
+
+        let info = torrent(
+            "t",
+            Some(vec![
+                vec!["/abs", "x"],
+                vec!["", ".", "a", "b"],
+                vec!["CON.txt"],
+                vec!["lpt1"],
+                vec!["a\\b\n"],
+            ]),
+        )
+        .unwrap();
+        assert_eq!(info.name, "t");
+        assert_eq!(
+            paths(info),
+            vec![
+                PathBuf::from("t/_abs/x"),
+                PathBuf::from("t/a/b"),
+                PathBuf::from("t/_CON.txt"),
+                PathBuf::from("t/_lpt1"),
+                PathBuf::from("t/a_b_"),
+            ]
+        );
+
+        let long = format!("{}.mkv", "é".repeat(200));
+        let info = torrent("t", Some(vec![vec![&long]])).unwrap();
+        let name = info.files[0].path.file_name().unwrap().to_str().unwrap();
+        assert!(name.len() <= MAX_COMPONENT_LEN);
+        assert!(name.starts_with("éé"));
+        assert!(name.ends_with(".mkv"));
+    }
+
     #[test]
     fn loc_iter_bounds() {
         let mut info = Info::with_pieces(4);
@@ -607,16 +892,20 @@ mod tests {
         info.files.push(File {
             path: PathBuf::from(""),
             length: 40000,
+            attr: None,
+            symlink: None,
         });
         info.files.push(File {
             path: PathBuf::from(""),
             length: 10000,
+            attr: None,
+            symlink: None,
         });
         info.total_len = 50000;
         info.piece_idx =
             Info::generate_piece_idx(info.hashes.len(), info.piece_len as u64, &info.files);
         let info = Arc::new(info);
-        let mut locs = Info::block_disk_locs(&info, 0, 0);
+        let mut locs = Info::range_disk_locs(&info, 0, 0, info.block_len(0, 0));
         let n = locs.next().unwrap();
         assert_eq!(n.start, 0);
         assert_eq!(n.end, 16384);
@@ -624,7 +913,7 @@ mod tests {
         assert_eq!(n.offset, 0);
         assert_eq!(locs.next().is_none(), true);
 
-        let mut locs = Info::block_disk_locs(&info, 1, 0);
+        let mut locs = Info::range_disk_locs(&info, 1, 0, info.block_len(1, 0));
         let n = locs.next().unwrap();
         assert_eq!(n.start, 0);
         assert_eq!(n.end, 16384);
@@ -632,7 +921,7 @@ mod tests {
         assert_eq!(n.offset, 16384);
         assert_eq!(locs.next().is_none(), true);
 
-        let mut locs = Info::block_disk_locs(&info, 2, 0);
+        let mut locs = Info::range_disk_locs(&info, 2, 0, info.block_len(2, 0));
         let n = locs.next().unwrap();
         assert_eq!(n.start, 0);
         assert_eq!(n.end, 7232);
@@ -645,7 +934,7 @@ mod tests {
         assert_eq!(n.file, 1);
         assert_eq!(n.offset, 0);
 
-        let mut locs = Info::block_disk_locs(&info, 3, 0);
+        let mut locs = Info::range_disk_locs(&info, 3, 0, info.block_len(3, 0));
         let n = locs.next().unwrap();
         assert_eq!(n.start, 0);
         assert_eq!(n.end, 848);
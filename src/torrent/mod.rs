@@ -1,41 +1,68 @@
+mod availability;
 pub mod bitfield;
 mod choker;
+mod error;
+pub mod ext;
 pub mod info;
 pub mod peer;
 mod picker;
 
 use std::borrow::Cow;
+use std::cmp;
 use std::collections::{BTreeMap, VecDeque};
 use std::fmt;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{atomic, Arc};
 use std::time::{Duration, Instant};
 
-use crate::bencode::BEncode;
-use byteorder::{BigEndian, ByteOrder};
+use crate::bencode::{BEncode, BEncodeRef};
 use chrono::{DateTime, Utc};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::SocketAddr;
 use url::Url;
 
 pub use self::bitfield::Bitfield;
+pub use self::error::Error;
 pub use self::info::{Info, LocIter};
 pub use self::peer::Message;
 pub use self::peer::{Peer, PeerConn};
 pub use self::picker::Block;
 
+use self::availability::{Availability, Changes};
+use self::peer::client;
+use self::peer::writer::{FilePiece, RangePiece};
 use self::picker::Picker;
-use crate::buffers::Buffer;
+use crate::buffers::{Buffer, BUF_SIZE};
+use crate::config::ClientPolicy;
 use crate::control::cio;
-use crate::rpc::resource::{self, Resource, SResourceUpdate};
+use crate::rpc::proto::message::EventKind;
+use crate::rpc::resource::{self, PeerSource, Resource, SResourceUpdate, TorrentState};
+use crate::session;
 use crate::session::torrent::current::Session;
+use crate::stat::{self, Window};
 use crate::throttle::Throttle;
-use crate::tracker::{self, TrackerResponse};
-use crate::util::{FHashSet, UHashMap};
-use crate::{bencode, disk, rpc, util, CONFIG, EXT_PROTO, UT_META_ID, UT_PEX_ID};
-use crate::{session, stat};
+use crate::tracker::{self, ScrapeResponse, TrackerResponse};
+use crate::util::{FHashMap, FHashSet, UHashMap};
+use crate::{bencode, config, disk, rpc, util, CONFIG, EXT_PROTO, LISTEN_PORT};
 
 const MAX_INFO_BYTES: i64 = 100 * 1000 * 1000;
-const MAX_PEERS: usize = 50;
+/// Number of requests from each peer we advertise we'll queue
+const EXT_REQQ: i64 = 250;
+/// Delay before the first retry after a transient disk error, doubling
+/// with each retry which fails again
+const RETRY_BASE_SECS: u64 = 30;
+const RETRY_MAX_SECS: u64 = 60 * 60;
+/// Number of hash failures kept for diagnosis
+const MAX_HASH_FAILURES: usize = 10;
+/// Seconds between announces to the DHT, which forgets peers
+/// after 30 minutes
+const DHT_ANNOUNCE_SECS: i64 = 15 * 60;
+/// Seeding time which is saved even if nothing else about a torrent
+/// changes. It's always saved on shutdown.
+const SEEDED_SAVE_SECS: u64 = 60 * 60;
+/// Seconds between updates of peers' transfer sent to clients
+const PEER_TX_SECS: u64 = 5;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TrackerStatus {
@@ -48,6 +75,14 @@ pub enum TrackerStatus {
     Failure(String),
 }
 
+/// Swarm size as last reported by trackers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Swarm {
+    seeders: Option<u32>,
+    leechers: Option<u32>,
+    completed: Option<u32>,
+}
+
 pub struct Torrent<T: cio::CIO> {
     id: usize,
     pieces: Bitfield,
@@ -57,19 +92,81 @@ pub struct Torrent<T: cio::CIO> {
     uploaded: u64,
     downloaded: u64,
     wasted: u64,
-    stat: stat::EMA,
+    /// Bytes uploaded and downloaded this session, by where peers were found
+    source_tx: [(u64, u64); PeerSource::ALL.len()],
+    /// Piece data and protocol overhead transferred this session with
+    /// peers which have since been removed
+    closed_tx: stat::Transfer,
+    /// Bytes uploaded this session by the country of peers
+    country_tx: FHashMap<String, u64>,
+    /// Country stats last sent to clients
+    countries_sent: Vec<resource::CountryStats>,
+    stat: stat::Rates,
     files: Files,
     priority: u8,
     priorities: Arc<Vec<u8>>,
     throttle: Throttle,
     trackers: VecDeque<Tracker>,
+    swarm: Swarm,
+    /// Disk use this session
+    disk: disk::IoStats,
+    /// Retries after transient disk errors since the torrent last
+    /// made progress, and when to retry next
+    retries: u32,
+    retry_at: Option<Instant>,
+    /// Peers which supplied the blocks of pieces being downloaded,
+    /// by piece then block offset
+    block_sources: FHashMap<u32, Vec<(u32, usize, SocketAddr)>>,
+    /// Latest pieces which failed validation, and their block sources
+    hash_failures: VecDeque<resource::HashFailure>,
+    next_scrape: Option<Instant>,
+    /// Bytes downloaded when the torrent last made progress, and when
+    last_progress: (u64, Instant),
+    /// When the torrent was last announced to the DHT
+    dht_announced: Option<DateTime<Utc>>,
+    exts: ext::Registry<T>,
     peers: UHashMap<Peer<T>>,
     leechers: FHashSet<usize>,
     picker: Picker,
+    /// Peers having each piece, and whether our pieces changed since
+    /// clients were last sent them
+    piece_avail: Availability,
+    pieces_changed: Changes,
+    /// When peers' transfer was last sent to clients
+    peer_tx_sent: Option<Instant>,
     status: Status,
     choker: choker::Choker,
     dirty: bool,
     path: Option<String>,
+    // Seed existing data at path, never downloading or writing to it
+    seed_only: bool,
+    // Stop once the metadata of a magnet is fetched, creating no files
+    metadata_only: bool,
+    // Files other torrents also have, which must validate before being
+    // written to
+    shared: Vec<usize>,
+    // Disk jobs on the data issued while it's moved, sent once it's in
+    // place
+    held: Vec<disk::Request>,
+    // Whether files moved or metadata was fetched since collisions with
+    // other torrents were checked
+    relocated: bool,
+    label: Option<String>,
+    // Upload ratio and seconds of seeding after which the torrent is paused
+    seed_ratio: Option<f32>,
+    seed_time: Option<u64>,
+    // Group whose rate limits are shared with its other torrents
+    throttle_group: Option<String>,
+    // Seconds spent seeding
+    seeded: u64,
+    // Whether peers are looked up in the DHT and exchanged with PEX
+    dht: bool,
+    pex: bool,
+    // Name and comment shown in place of the info's
+    name: Option<String>,
+    comment: Option<String>,
+    // Notes left by users
+    notes: Option<String>,
     // Stored info-dictionary bytes for use in BEP9 (Peer metadata transfer)
     info_bytes: Vec<u8>,
     // The maximum index of 16KiB pieces used in peer metadata transfer.
@@ -85,9 +182,27 @@ pub struct Torrent<T: cio::CIO> {
 #[derive(Clone, Debug)]
 pub struct Status {
     pub paused: bool,
-    pub validating: Option<f32>,
-    pub error: Option<String>,
+    /// Disk work on the torrent's data, which it waits on if validating
+    pub job: Option<Job>,
+    pub error: Option<Error>,
     pub state: StatusState,
+    /// Downloading, but nothing has been for a while
+    pub stalled: bool,
+    /// Waiting for a download slot, so no peers are connected
+    pub queued: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Job {
+    /// Validation was requested but hasn't started reading data
+    CheckQueued,
+    /// Validation is in progress, holding the fraction done
+    Checking(f32),
+    /// Files are checked for changes made while synapse wasn't running,
+    /// deciding whether validation is needed
+    CheckingFiles,
+    /// Data is being moved to another directory
+    Moving,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -103,6 +218,10 @@ pub struct Tracker {
     pub status: TrackerStatus,
     pub last_announce: DateTime<Utc>,
     pub update: Option<Instant>,
+    /// Number of peers the last successful announce returned
+    pub peers: usize,
+    /// Warning message sent with the last successful announce
+    pub warning: Option<String>,
 }
 
 struct Files {
@@ -110,6 +229,26 @@ struct Files {
     dirty: FHashSet<usize>,
 }
 
+impl Tracker {
+    fn new(url: Arc<Url>) -> Tracker {
+        Tracker {
+            url,
+            status: TrackerStatus::Updating,
+            last_announce: Utc::now(),
+            update: None,
+            peers: 0,
+            warning: None,
+        }
+    }
+
+    fn error(&self) -> Option<String> {
+        match self.status {
+            TrackerStatus::Failure(ref r) => Some(r.clone()),
+            _ => None,
+        }
+    }
+}
+
 impl Status {
     pub fn magnet(&self) -> bool {
         match self.state {
@@ -126,34 +265,78 @@ impl Status {
     }
 
     pub fn stopped(&self) -> bool {
-        self.paused || self.error.is_some()
+        self.paused || self.failed()
+    }
+
+    /// Whether the torrent's data is being validated, or waiting to be.
+    pub fn validating(&self) -> bool {
+        matches!(self.job, Some(Job::CheckQueued) | Some(Job::Checking(_)))
+    }
+
+    /// Whether the torrent's data is being validated or its files are
+    /// checked, so it may not be in place.
+    pub fn checking(&self) -> bool {
+        self.validating() || self.job == Some(Job::CheckingFiles)
+    }
+
+    /// Whether an error stopped the torrent.
+    pub fn failed(&self) -> bool {
+        self.error.as_ref().map(Error::fatal).unwrap_or(false)
     }
 
     pub fn completed(&self) -> bool {
         match self.state {
-            StatusState::Complete => self.validating.is_none(),
+            StatusState::Complete => !self.checking(),
             _ => false,
         }
     }
 
     pub fn should_dl(&self) -> bool {
-        self.leeching() && !self.stopped() && self.validating.is_none()
+        // Blocks requested while the data is moved would be held in memory
+        self.leeching() && !self.stopped() && !self.checking() && self.job != Some(Job::Moving)
+    }
+
+    /// The lifecycle state of the torrent, in which ongoing disk work
+    /// takes precedence, then being stopped by an error or a client.
+    pub fn torrent_state(&self) -> TorrentState {
+        match self.job {
+            Some(Job::Moving) => return TorrentState::Moving,
+            Some(Job::CheckQueued) | Some(Job::CheckingFiles) => {
+                return TorrentState::CheckingQueued
+            }
+            Some(Job::Checking(_)) => return TorrentState::Checking,
+            None => {}
+        }
+        if self.failed() {
+            return TorrentState::Error;
+        }
+        if self.paused {
+            return TorrentState::Paused;
+        }
+        match self.state {
+            StatusState::Complete => TorrentState::Seeding,
+            _ if self.queued => TorrentState::Queued,
+            StatusState::Magnet => TorrentState::DownloadingMetadata,
+            StatusState::Incomplete | StatusState::Import => TorrentState::Downloading,
+        }
     }
 
     pub fn as_rpc(&self, ul: u64, dl: u64) -> rpc::resource::Status {
         if self.paused {
             return rpc::resource::Status::Paused;
         }
-        if self.validating.is_some() {
+        if self.checking() {
             return rpc::resource::Status::Hashing;
         }
-        if self.error.is_some() {
+        if self.failed() {
             return rpc::resource::Status::Error;
         }
 
         match self.state {
             StatusState::Incomplete | StatusState::Import => {
-                if dl == 0 {
+                if self.stalled {
+                    rpc::resource::Status::Stalled
+                } else if dl == 0 {
                     rpc::resource::Status::Pending
                 } else {
                     rpc::resource::Status::Leeching
@@ -204,6 +387,19 @@ impl Files {
         }
     }
 
+    /// Bytes of the files which aren't skipped that are done, and their
+    /// total size.
+    fn wanted(&self, info: &Info, priorities: &[u8]) -> (u64, u64) {
+        info.files
+            .iter()
+            .zip(&self.done)
+            .zip(priorities)
+            .filter(|((f, _), &p)| p != 0 && !f.is_padding())
+            .fold((0, 0), |(done, total), ((f, &d), _)| {
+                (done + d, total + f.length)
+            })
+    }
+
     fn flush(&mut self) -> Vec<(usize, u64)> {
         let mut res = Vec::with_capacity(self.dirty.len());
         for idx in self.dirty.drain() {
@@ -222,6 +418,7 @@ impl<T: cio::CIO> Torrent<T> {
         cio: T,
         start: bool,
         import: bool,
+        seed_only: bool,
     ) -> Torrent<T> {
         debug!("Creating {:?}", info);
         let peers = UHashMap::default();
@@ -229,13 +426,15 @@ impl<T: cio::CIO> Torrent<T> {
         let leechers = FHashSet::default();
         let mut status = Status {
             paused: !start,
-            validating: None,
+            job: None,
             error: None,
             state: if import {
                 StatusState::Import
             } else {
                 StatusState::Incomplete
             },
+            stalled: false,
+            queued: false,
         };
         let priorities = Arc::new(vec![3; info.files.len()]);
         let info_idx = if info.complete() {
@@ -251,27 +450,18 @@ impl<T: cio::CIO> Torrent<T> {
         };
         let info = Arc::new(info);
         let picker = Picker::new(&info, &pieces, &priorities);
+        let piece_avail = Availability::new(info.pieces());
 
         let mut trackers = VecDeque::with_capacity(1);
         if !info.url_list.is_empty() {
             for (i, list) in info.url_list.iter().enumerate() {
                 for (j, _) in list.iter().enumerate() {
-                    let tracker = Tracker {
-                        status: TrackerStatus::Updating,
-                        update: None,
-                        last_announce: Utc::now(),
-                        url: Arc::clone(&info.url_list[i][j]),
-                    };
+                    let tracker = Tracker::new(Arc::clone(&info.url_list[i][j]));
                     trackers.push_back(tracker);
                 }
             }
         } else if let Some(ref announce) = info.announce {
-            let tracker = Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: announce.clone(),
-            };
+            let tracker = Tracker::new(announce.clone());
             trackers.push_back(tracker);
         }
 
@@ -285,13 +475,20 @@ impl<T: cio::CIO> Torrent<T> {
             pieces,
             validating: FHashSet::default(),
             picker,
+            piece_avail,
+            pieces_changed: Default::default(),
+            peer_tx_sent: None,
             priority: 3,
             priorities,
             uploaded: 0,
             downloaded: 0,
             wasted: 0,
+            source_tx: Default::default(),
+            closed_tx: stat::Transfer::default(),
+            country_tx: FHashMap::default(),
+            countries_sent: Vec::new(),
             files,
-            stat: stat::EMA::new(),
+            stat: stat::Rates::new(),
             cio,
             leechers,
             throttle,
@@ -299,12 +496,39 @@ impl<T: cio::CIO> Torrent<T> {
             choker: choker::Choker::new(),
             dirty: true,
             status,
+            seed_only,
+            metadata_only: false,
+            shared: Vec::new(),
+            held: Vec::new(),
+            relocated: false,
+            label: None,
+            seed_ratio: None,
+            seed_time: None,
+            throttle_group: None,
+            seeded: 0,
+            dht: true,
+            pex: true,
+            name: None,
+            comment: None,
+            notes: None,
             info_bytes,
             info_idx,
             created: Utc::now(),
+            swarm: Swarm::default(),
+            disk: disk::IoStats::default(),
+            retries: 0,
+            retry_at: None,
+            block_sources: FHashMap::default(),
+            hash_failures: VecDeque::new(),
+            next_scrape: None,
+            last_progress: (0, Instant::now()),
+            dht_announced: None,
+            exts: ext::Registry::new(),
         };
         t.start(true);
-        if import {
+        if seed_only {
+            t.validate();
+        } else if import {
             t.cio.msg_disk(disk::Request::validate_piece(
                 t.id,
                 t.info.clone(),
@@ -351,6 +575,8 @@ impl<T: cio::CIO> Torrent<T> {
                 .map(|f| info::File {
                     path: f.path,
                     length: f.length,
+                    attr: f.attr,
+                    symlink: f.symlink,
                 })
                 .collect(),
             private: d.info.private,
@@ -371,29 +597,24 @@ impl<T: cio::CIO> Torrent<T> {
         };
         let pieces = Bitfield::from(&d.pieces.data, d.pieces.len);
         let picker = picker::Picker::new(&info, &pieces, &d.priorities);
+        let piece_avail = Availability::new(info.pieces());
         throttle.set_ul_rate(d.throttle_ul);
         throttle.set_dl_rate(d.throttle_dl);
+        // Torrents leave groups which no longer exist
+        let throttle_group = d
+            .throttle_group
+            .filter(|g| throttle.set_group(Some(g)).is_ok());
 
         let mut trackers: VecDeque<_> = d
             .trackers
             .into_iter()
             .filter_map(|url| Url::parse(&url).ok())
-            .map(|url| Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: Arc::new(url),
-            })
+            .map(|url| Tracker::new(Arc::new(url)))
             .collect();
 
         if trackers.is_empty() {
             if let Some(ref announce) = info.announce {
-                let tracker = Tracker {
-                    status: TrackerStatus::Updating,
-                    update: None,
-                    last_announce: Utc::now(),
-                    url: announce.clone(),
-                };
+                let tracker = Tracker::new(announce.clone());
                 trackers.push_back(tracker);
             }
         }
@@ -407,11 +628,18 @@ impl<T: cio::CIO> Torrent<T> {
             pieces,
             validating: FHashSet::default(),
             picker,
+            piece_avail,
+            pieces_changed: Default::default(),
+            peer_tx_sent: None,
             uploaded: d.uploaded,
             downloaded: d.downloaded,
             wasted: 0,
+            source_tx: Default::default(),
+            closed_tx: stat::Transfer::default(),
+            country_tx: FHashMap::default(),
+            countries_sent: Vec::new(),
             files,
-            stat: stat::EMA::new(),
+            stat: stat::Rates::new(),
             priorities: Arc::new(d.priorities),
             priority: d.priority,
             cio,
@@ -422,30 +650,109 @@ impl<T: cio::CIO> Torrent<T> {
             dirty: false,
             status: Status {
                 paused: d.status.paused,
-                validating: None,
-                error: d.status.error,
+                job: None,
+                // Errors are only kept for the session they occur in
+                error: None,
                 state: match d.status.state {
                     session::torrent::current::StatusState::Magnet => StatusState::Magnet,
                     session::torrent::current::StatusState::Incomplete => StatusState::Incomplete,
                     session::torrent::current::StatusState::Complete => StatusState::Complete,
                 },
+                stalled: false,
+                queued: false,
             },
             path: d.path,
+            seed_only: d.seed_only,
+            metadata_only: d.metadata_only,
+            shared: Vec::new(),
+            held: Vec::new(),
+            relocated: false,
+            label: d.label,
+            seed_ratio: d.seed_ratio,
+            seed_time: d.seed_time,
+            throttle_group,
+            seeded: d.seeded,
+            dht: d.dht,
+            pex: d.pex,
+            name: d.name,
+            comment: d.comment,
+            notes: d.notes,
             info_bytes,
             info_idx,
             created: d.created,
+            swarm: Swarm::default(),
+            disk: disk::IoStats::default(),
+            retries: 0,
+            retry_at: None,
+            block_sources: FHashMap::default(),
+            hash_failures: VecDeque::new(),
+            next_scrape: None,
+            last_progress: (0, Instant::now()),
+            dht_announced: None,
+            exts: ext::Registry::new(),
         };
-        t.status.error = None;
         t.start(false);
         if d.status.validating {
             t.validate();
-        } else {
+            return Some(t);
+        }
+        if t.status.magnet() {
             t.announce_start();
+        } else {
+            t.check_files();
         }
         Some(t)
     }
 
+    /// Has the disk thread look for files holding downloaded pieces which
+    /// are missing or don't have the length they're allocated with, e.g.
+    /// as they were deleted while synapse wasn't running.
+    fn check_files(&mut self) {
+        self.cio.msg_disk(disk::Request::CheckFiles {
+            tid: self.id,
+            info: self.info.clone(),
+            path: self.path.clone(),
+            priorities: self.priorities.clone(),
+            pieces: self.pieces.clone(),
+        });
+        self.status.job = Some(Job::CheckingFiles);
+    }
+
+    /// Validates the torrent if files were found damaged on startup, or
+    /// starts announcing it otherwise.
+    fn files_checked(&mut self, damaged: Vec<PathBuf>) {
+        if self.status.job != Some(Job::CheckingFiles) {
+            return;
+        }
+        self.status.job = None;
+        if damaged.is_empty() {
+            debug!(
+                "Files of {:?} are intact, skipping validation",
+                self.rpc_id()
+            );
+            self.announce_status();
+            self.announce_start();
+            return;
+        }
+        let files: Vec<_> = damaged.iter().map(|f| f.to_string_lossy()).collect();
+        let msg = format!(
+            "Validating, as files are missing or resized: {}",
+            files.join(", ")
+        );
+        info!("{:?}: {}", self.rpc_id(), msg);
+        self.log_event(EventKind::TorrentChecked, msg);
+        self.validate();
+    }
+
     pub fn serialize(&mut self) {
+        debug!("Sending serialization request!");
+        let snapshot = self.snapshot();
+        self.cio
+            .msg_disk(disk::Request::serialize(self.id, snapshot));
+    }
+
+    /// Captures the session state of the torrent, clearing its dirty flag.
+    pub fn snapshot(&mut self) -> disk::Snapshot {
         let d = Session {
             info: session::torrent::current::Info {
                 name: self.info.name.clone(),
@@ -464,6 +771,8 @@ impl<T: cio::CIO> Torrent<T> {
                     .map(|f| session::torrent::current::File {
                         path: f.path,
                         length: f.length,
+                        attr: f.attr,
+                        symlink: f.symlink,
                     })
                     .collect(),
                 private: self.info.private,
@@ -478,8 +787,8 @@ impl<T: cio::CIO> Torrent<T> {
             downloaded: self.downloaded,
             status: session::torrent::current::Status {
                 paused: self.status.paused,
-                validating: self.status.validating.is_some(),
-                error: self.status.error.clone(),
+                validating: self.status.validating(),
+                error: self.error(),
                 state: match self.status.state {
                     StatusState::Magnet => session::torrent::current::StatusState::Magnet,
                     StatusState::Incomplete | StatusState::Import => {
@@ -499,12 +808,25 @@ impl<T: cio::CIO> Torrent<T> {
                 .iter()
                 .map(|trk| trk.url.as_str().to_owned())
                 .collect(),
+            seed_only: self.seed_only,
+            label: self.label.clone(),
+            seed_ratio: self.seed_ratio,
+            seed_time: self.seed_time,
+            seeded: self.seeded,
+            dht: self.dht,
+            pex: self.pex,
+            name: self.name.clone(),
+            comment: self.comment.clone(),
+            notes: self.notes.clone(),
+            throttle_group: self.throttle_group.clone(),
+            metadata_only: self.metadata_only,
         };
         let data = bincode::serialize(&d).expect("Serialization failed!");
-        debug!("Sending serialization request!");
-        self.cio
-            .msg_disk(disk::Request::serialize(self.id, data, self.info.hash));
         self.dirty = false;
+        disk::Snapshot {
+            hash: self.info.hash,
+            data,
+        }
     }
 
     pub fn rpc_id(&self) -> String {
@@ -514,7 +836,7 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn delete(&mut self, artifacts: bool) {
         debug!("Sending file deletion request!");
         let mut files = Vec::new();
-        for file in &self.info.files {
+        for (_, file) in self.info.user_files() {
             files.push(file.path.clone());
         }
         self.cio.msg_disk(disk::Request::delete(
@@ -522,7 +844,7 @@ impl<T: cio::CIO> Torrent<T> {
             self.info.hash,
             files,
             self.path.clone(),
-            artifacts,
+            artifacts && !self.seed_only,
         ));
     }
 
@@ -553,9 +875,25 @@ impl<T: cio::CIO> Torrent<T> {
                     };
                     tracker.update = Some(time);
                     tracker.last_announce = Utc::now();
+                    tracker.peers = r.peers.len();
+                    let warning = match r.warning {
+                        Some(ref warning) if r.warning != tracker.warning => {
+                            Some(format!("{}: {}", url, warning))
+                        }
+                        _ => None,
+                    };
+                    tracker.warning = r.warning.clone();
+                    if let Some(warning) = warning {
+                        self.log_event(EventKind::TrackerWarning, warning);
+                    }
                     if r.peers.is_empty() {
                         empty = true;
                     }
+                    self.set_swarm(Swarm {
+                        seeders: Some(r.seeders),
+                        leechers: Some(r.leechers),
+                        ..self.swarm
+                    });
                 }
             }
             Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
@@ -580,6 +918,11 @@ impl<T: cio::CIO> Torrent<T> {
             }
         }
 
+        if resp.is_ok() {
+            self.clear_error(|e| e.kind() == resource::ErrorKind::TrackerFailure);
+        } else if let Some(reason) = self.trackers_failed() {
+            self.set_error(Error::TrackerFailure(reason));
+        }
         if (resp.is_err() || empty) && self.trackers.iter().any(|t| &*t.url == url) {
             if let Some(front) = self.trackers.pop_front() {
                 self.trackers.push_back(front);
@@ -589,6 +932,85 @@ impl<T: cio::CIO> Torrent<T> {
         self.update_rpc_tracker();
     }
 
+    pub fn set_scrape_response(&mut self, url: &Url, resp: &tracker::Result<ScrapeResponse>) {
+        match *resp {
+            Ok(ref r) => self.set_swarm(Swarm {
+                seeders: Some(r.seeders),
+                leechers: Some(r.leechers),
+                completed: Some(r.completed),
+            }),
+            Err(ref e) => debug!("Failed to scrape {}: {}", url, e),
+        }
+    }
+
+    fn set_swarm(&mut self, swarm: Swarm) {
+        if swarm != self.swarm {
+            self.swarm = swarm;
+            self.update_rpc_swarm();
+        }
+    }
+
+    fn record_hash_failure(&mut self, piece: u32, mut sources: Vec<(u32, usize, SocketAddr)>) {
+        sources.sort_unstable_by_key(|&(offset, _, _)| offset);
+        let blocks = sources
+            .into_iter()
+            .map(|(offset, pid, addr)| resource::BlockSource {
+                offset,
+                peer: util::peer_rpc_id(&self.info.hash, pid as u64),
+                ip: addr.to_string(),
+            })
+            .collect();
+        if self.hash_failures.len() == MAX_HASH_FAILURES {
+            self.hash_failures.pop_front();
+        }
+        self.hash_failures.push_back(resource::HashFailure {
+            piece,
+            time: Utc::now(),
+            blocks,
+        });
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentHashFailures {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                hash_failures: self.hash_failures.iter().cloned().collect(),
+            },
+        ]));
+    }
+
+    pub fn set_disk_stats(&mut self, stats: disk::IoStats) {
+        if stats != self.disk {
+            self.disk = stats;
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                SResourceUpdate::TorrentDisk {
+                    id: self.rpc_id(),
+                    kind: resource::ResourceKind::Torrent,
+                    disk_read: stats.read,
+                    disk_written: stats.written,
+                    disk_time: stats.time.as_millis() as u64,
+                },
+            ]));
+        }
+    }
+
+    /// Scrapes the primary tracker if the scrape interval has passed.
+    pub fn try_scrape(&mut self) {
+        if CONFIG.trk.scrape_interval == 0 {
+            return;
+        }
+        match self.next_scrape {
+            Some(next) if Instant::now() < next => {}
+            _ => self.scrape(),
+        }
+    }
+
+    pub fn scrape(&mut self) {
+        let interval = Duration::from_secs(CONFIG.trk.scrape_interval);
+        self.next_scrape = Some(Instant::now() + interval);
+        if let Some(req) = tracker::Request::scrape(self) {
+            self.cio.msg_trk(req);
+        }
+    }
+
     pub fn try_update_tracker(&mut self) {
         if self.status.stopped() {
             return;
@@ -597,21 +1019,62 @@ impl<T: cio::CIO> Torrent<T> {
             debug!("Updating tracker at interval!");
             let cur = Instant::now();
             if cur >= end {
-                self.update_tracker();
+                self.reannounce();
             }
         } else {
-            self.update_tracker();
+            self.reannounce();
+        }
+    }
+
+    /// Announces to the DHT again once the last announce is old enough.
+    pub fn try_dht_announce(&mut self) {
+        let due = self
+            .dht_announced
+            .map(|t| Utc::now().signed_duration_since(t).num_seconds() >= DHT_ANNOUNCE_SECS)
+            .unwrap_or(true);
+        if due {
+            self.dht_announce();
+        }
+    }
+
+    /// Marks the torrent stalled once it has downloaded nothing for a
+    /// while when it should be, announcing again to find more peers.
+    /// Announces are repeated after each further timeout until it recovers.
+    pub fn check_stalled(&mut self, now: Instant) {
+        let timeout = CONFIG.trk.stall_timeout;
+        if timeout == 0 || !self.status.should_dl() || self.downloaded != self.last_progress.0 {
+            self.last_progress = (self.downloaded, now);
+            if self.status.stalled {
+                self.status.stalled = false;
+                self.announce_status();
+            }
+            return;
+        }
+        if now.duration_since(self.last_progress.1) < Duration::from_secs(timeout) {
+            return;
+        }
+        self.last_progress.1 = now;
+        if !self.status.stalled {
+            info!("{} has stalled, announcing again", self.rpc_id());
+            self.status.stalled = true;
+            self.announce_status();
         }
+        self.update_tracker();
     }
 
     pub fn update_tracker(&mut self) {
+        self.reannounce();
+        self.dht_announce();
+    }
+
+    /// Announces to the primary tracker now rather than at its interval.
+    pub fn reannounce(&mut self) {
         if self.status.stopped() {
             return;
         }
         if let Some(req) = tracker::Request::interval(self) {
             self.cio.msg_trk(req);
         }
-        self.dht_announce();
     }
 
     pub fn remove_peer(&mut self, rpc_id: &str) {
@@ -628,28 +1091,40 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn add_tracker(&mut self, url: Url) -> String {
         let id = util::trk_rpc_id(&self.info.hash, url.as_str());
-        self.trackers.push_front(Tracker {
-            status: TrackerStatus::Updating,
-            update: None,
-            last_announce: Utc::now(),
-            url: Arc::new(url),
-        });
-        {
-            let trk = &self.trackers[0];
-            let res = vec![resource::Resource::Tracker(resource::Tracker {
-                id: id.clone(),
-                torrent_id: self.rpc_id(),
-                url: trk.url.as_ref().clone(),
-                last_report: trk.last_announce,
-                error: None,
-                ..Default::default()
-            })];
-            self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
-        }
+        self.trackers.push_front(Tracker::new(Arc::new(url)));
+        self.dirty = true;
+        let res = vec![resource::Resource::Tracker(self.rpc_tracker(0))];
+        self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
         self.announce_start();
         id
     }
 
+    /// Adds the trackers of another copy of the torrent which it doesn't
+    /// have yet, after its own, returning the RPC ids of those added.
+    pub fn merge_trackers(&mut self, info: &Info) -> Vec<String> {
+        let urls: Vec<_> = if info.url_list.is_empty() {
+            info.announce.iter().cloned().collect()
+        } else {
+            info.url_list.iter().flatten().cloned().collect()
+        };
+        let start = self.trackers.len();
+        for url in urls {
+            if self.trackers.iter().all(|t| t.url != url) {
+                self.trackers.push_back(Tracker::new(url));
+            }
+        }
+        let added: Vec<_> = (start..self.trackers.len())
+            .map(|i| self.rpc_tracker(i))
+            .collect();
+        let ids = added.iter().map(|t| t.id.clone()).collect();
+        if !added.is_empty() {
+            self.dirty = true;
+            let res = added.into_iter().map(resource::Resource::Tracker).collect();
+            self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
+        }
+        ids
+    }
+
     pub fn remove_tracker(&mut self, rpc_id: &str) {
         let ih = &self.info.hash;
         let mut res = None;
@@ -664,6 +1139,7 @@ impl<T: cio::CIO> Torrent<T> {
 
         if let Some(idx) = res {
             self.trackers.remove(idx);
+            self.dirty = true;
         }
     }
 
@@ -678,6 +1154,200 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Sets a deadline for the piece containing the given byte offset,
+    /// so it is requested ahead of other pieces from our fastest peers.
+    pub fn set_piece_deadline(&mut self, offset: u64, deadline: Duration) {
+        if self.info_idx.is_some() || offset >= self.info.total_len {
+            return;
+        }
+        let piece = (offset / u64::from(self.info.piece_len)) as u32;
+        if self.pieces.has_bit(u64::from(piece)) {
+            return;
+        }
+        self.picker.set_deadline(piece, Instant::now() + deadline);
+        self.request_all();
+    }
+
+    /// Absolute paths and lengths of the torrent's files.
+    pub fn file_paths(&self) -> Vec<(PathBuf, u64)> {
+        let settings = config::settings();
+        let base = data_dir(&self.path, &settings.directory);
+        self.info
+            .user_files()
+            .map(|(_, f)| (base.join(&f.path), f.length))
+            .collect()
+    }
+
+    /// Links any files of a completed torrent which match ours into place
+    /// and revalidates. A file matches if it is the only source file of
+    /// its size, or has the same size and name, and is only linked once
+    /// the pieces it holds are verified. Returns whether or not any files
+    /// matched.
+    pub fn cross_seed(&mut self, source: &[(PathBuf, u64)]) -> bool {
+        if self.info_idx.is_some() || self.seed_only || self.complete() || self.status.checking() {
+            return false;
+        }
+        let links: BTreeMap<_, _> = self
+            .info
+            .user_files()
+            .filter(|(_, f)| f.length != 0 && f.symlink.is_none())
+            .filter_map(|(i, f)| {
+                let len = f.length;
+                let mut sized = source.iter().filter(|&&(_, l)| l == len);
+                let src = match (sized.next(), sized.next()) {
+                    (Some((p, _)), None) => p,
+                    (Some(_), Some(_)) => {
+                        &source
+                            .iter()
+                            .find(|(p, l)| *l == len && p.file_name() == f.path.file_name())?
+                            .0
+                    }
+                    _ => return None,
+                };
+                Some((i, src.clone()))
+            })
+            .collect();
+        if links.is_empty() {
+            return false;
+        }
+        self.cio.msg_disk(disk::Request::link(
+            self.id,
+            self.info.clone(),
+            self.path.clone(),
+            links,
+        ));
+        self.validate();
+        true
+    }
+
+    /// Searches a directory for the torrent's files, e.g. after they were
+    /// moved by hand, using the directory they're found in and validating.
+    pub fn find_data(&mut self, directory: String) {
+        if self.info_idx.is_some() {
+            return;
+        }
+        self.cio
+            .msg_disk(disk::Request::find_data(self.id, &self.info, directory));
+    }
+
+    /// Places the torrent's symlink files, pointing relative to each link.
+    fn create_symlinks(&mut self) {
+        let settings = config::settings();
+        let base = Path::new(self.path.as_ref().unwrap_or(&settings.directory));
+        let links: Vec<_> = self
+            .info
+            .files
+            .iter()
+            .filter_map(|f| {
+                let target = f.symlink.as_ref()?;
+                // Targets are relative to the torrent's root, which is
+                // the first component of multi file paths
+                let mut rel = PathBuf::new();
+                for _ in 0..f.path.components().count().saturating_sub(2) {
+                    rel.push("..");
+                }
+                rel.push(target);
+                Some((rel, base.join(&f.path)))
+            })
+            .collect();
+        if !links.is_empty() {
+            self.cio.msg_disk(disk::Request::Symlink {
+                tid: self.id,
+                links,
+            });
+        }
+    }
+
+    /// Download directory, if not the default one.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Keeps the torrent's data where it is when the default download
+    /// directory changes.
+    pub fn pin_path(&mut self, dir: &str) {
+        if self.path.is_none() {
+            self.path = Some(dir.to_owned());
+            self.dirty = true;
+        }
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Assigns a label to the torrent, which inherits its rate limits
+    /// and seeding goals. Removing the label keeps them as they are.
+    pub fn set_label(&mut self, label: Option<&resource::Label>) {
+        self.label = label.map(|l| l.name.clone());
+        if let Some(l) = label {
+            self.set_throttle(l.throttle_up, l.throttle_down);
+            self.seed_ratio = l.seed_ratio;
+            self.seed_time = l.seed_time;
+        }
+        self.dirty = true;
+        self.announce_label();
+    }
+
+    /// Accounts for time spent seeding, pausing the torrent once
+    /// either of its seeding goals has been met.
+    pub fn update_seeding(&mut self, secs: u64) {
+        if !self.complete() || self.status.paused {
+            return;
+        }
+        let before = self.seeded;
+        self.seeded += secs;
+        if self.seeded / SEEDED_SAVE_SECS != before / SEEDED_SAVE_SECS {
+            self.dirty = true;
+        }
+        if self.seeding_goal_met() {
+            debug!("Seeding goal met, pausing torrent!");
+            self.pause();
+        }
+    }
+
+    pub fn seeding_goal_met(&self) -> bool {
+        let ratio = self.uploaded as f32 / self.info.total_len.max(1) as f32;
+        self.seed_ratio.map(|r| ratio >= r).unwrap_or(false)
+            || self.seed_time.map(|t| self.seeded >= t).unwrap_or(false)
+    }
+
+    fn announce_label(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentLabel {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                label: self.label.clone(),
+                seed_ratio: self.seed_ratio,
+                seed_time: self.seed_time,
+            },
+        ]));
+    }
+
+    pub fn throttle_group(&self) -> Option<&str> {
+        self.throttle_group.as_deref()
+    }
+
+    /// Moves the torrent into a throttle group, sharing its rate limits
+    /// with the group's other torrents, or out of its group.
+    pub fn set_throttle_group(&mut self, group: Option<String>) {
+        if self.throttle.set_group(group.as_deref()).is_err() {
+            error!("Unknown throttle group {:?}", group);
+            return;
+        }
+        self.throttle_group = group;
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentThrottleGroup {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                throttle_group: self.throttle_group.clone(),
+            },
+        ]));
+    }
+
     pub fn get_throttle(&self, id: usize) -> Throttle {
         self.throttle.new_sibling(id)
     }
@@ -698,10 +1368,46 @@ impl<T: cio::CIO> Torrent<T> {
         self.downloaded
     }
 
+    /// Piece data and protocol overhead transferred this session.
+    pub fn transfer(&self) -> stat::Transfer {
+        let mut tx = self.closed_tx;
+        tx += self.peers.values().map(|p| p.transfer()).sum();
+        tx
+    }
+
     pub fn info(&self) -> &Info {
         &self.info
     }
 
+    /// Whether peers from a source may be used. Private torrents only
+    /// use peers from their trackers or added by clients.
+    pub fn allows_source(&self, source: PeerSource) -> bool {
+        match source {
+            PeerSource::Dht => self.dht && !self.info.private,
+            PeerSource::Pex => self.pex && !self.info.private,
+            _ => true,
+        }
+    }
+
+    fn set_network(&mut self, dht: Option<bool>, pex: Option<bool>) {
+        let prev_dht = self.dht;
+        self.dht = dht.unwrap_or(self.dht);
+        self.pex = pex.unwrap_or(self.pex);
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentNetwork {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                dht: self.dht,
+                pex: self.pex,
+            },
+        ]));
+        if self.dht && !prev_dht {
+            self.dht_announce();
+        }
+    }
+
     pub fn trackers(&self) -> &VecDeque<Tracker> {
         &self.trackers
     }
@@ -711,27 +1417,82 @@ impl<T: cio::CIO> Torrent<T> {
             disk::Response::Read { context, data } => {
                 trace!("Received piece from disk, uploading!");
                 if let Some(peer) = self.peers.get_mut(&context.pid) {
+                    peer.finish_read();
                     let p = Message::piece(context.idx, context.begin, context.length, data);
                     // This may not be 100% accurate, but close enough for now.
                     self.uploaded += u64::from(context.length);
+                    self.source_tx[peer.source() as usize].0 += u64::from(context.length);
+                    add_country_ul(&mut self.country_tx, peer.country(), context.length);
                     self.stat.add_ul(u64::from(context.length));
                     self.dirty = true;
                     peer.send_message(p);
                 }
+                self.retries = 0;
+            }
+            disk::Response::ReadFile {
+                context,
+                file,
+                offset,
+            } => {
+                trace!("Received file block from disk, uploading!");
+                if let Some(peer) = self.peers.get_mut(&context.pid) {
+                    peer.finish_read();
+                    self.uploaded += u64::from(context.length);
+                    self.source_tx[peer.source() as usize].0 += u64::from(context.length);
+                    add_country_ul(&mut self.country_tx, peer.country(), context.length);
+                    self.stat.add_ul(u64::from(context.length));
+                    self.dirty = true;
+                    peer.send_file(FilePiece {
+                        index: context.idx,
+                        begin: context.begin,
+                        length: context.length,
+                        file,
+                        offset,
+                    });
+                }
+                self.retries = 0;
+            }
+            disk::Response::ReadRange { context, data } => {
+                trace!("Received range from disk, uploading!");
+                if let Some(peer) = self.peers.get_mut(&context.pid) {
+                    peer.finish_read();
+                    self.uploaded += u64::from(context.length);
+                    self.source_tx[peer.source() as usize].0 += u64::from(context.length);
+                    add_country_ul(&mut self.country_tx, peer.country(), context.length);
+                    self.stat.add_ul(u64::from(context.length));
+                    self.dirty = true;
+                    peer.send_range(RangePiece {
+                        index: context.idx,
+                        begin: context.begin,
+                        data,
+                    });
+                }
+                self.retries = 0;
+            }
+            disk::Response::ReadDropped { context } => {
+                if let Some(peer) = self.peers.get_mut(&context.pid) {
+                    peer.finish_read();
+                }
+            }
+            disk::Response::FilesChecked { damaged, .. } => {
+                self.files_checked(damaged);
             }
             disk::Response::Moved { path, .. } => {
                 debug!("Moved torrent!");
-                let id = self.rpc_id();
-                self.path = Some(path.clone());
-                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                    resource::SResourceUpdate::TorrentPath {
-                        id,
-                        kind: resource::ResourceKind::Torrent,
-                        path,
-                    },
-                ]));
+                self.set_moved(path);
             }
-            disk::Response::PieceValidated { piece, valid, .. } => {
+            disk::Response::DataFound {
+                path: Some(path), ..
+            } => {
+                info!("Found data of {:?} in {}", self.rpc_id(), path);
+                self.set_moved(path);
+                self.validate();
+            }
+            disk::Response::DataFound { directory, .. } => {
+                let msg = format!("No data found in {}", directory);
+                self.log_event(EventKind::TorrentError, msg);
+            }
+            disk::Response::PieceValidated { piece, valid, .. } => {
                 self.validating.remove(&piece);
                 if let StatusState::Import = self.status.state {
                     self.status.state = StatusState::Incomplete;
@@ -749,42 +1510,87 @@ impl<T: cio::CIO> Torrent<T> {
                     self.update_rpc_transfer();
                     return;
                 }
+                let sources = self.block_sources.remove(&piece).unwrap_or_default();
                 if valid {
                     self.pieces.set_bit(u64::from(piece));
+                    self.pieces_changed.mark();
                     // Tell all relevant peers we got the piece
-                    let m = Message::Have(piece);
                     for pid in &self.leechers {
                         if let Some(peer) = self.peers.get_mut(pid) {
-                            if !peer.pieces().has_bit(u64::from(piece)) {
-                                peer.send_message(m.clone());
-                            }
+                            peer.have(piece);
                         }
                     }
                     self.files.update(&self.info, piece);
                     self.check_complete();
+                    self.retries = 0;
+                    self.clear_error(|e| *e == Error::HashFailure(piece));
                 } else {
-                    // TODO: trace down the bad peer and block it
                     debug!("Invalid piece downloaded!");
-                    self.picker.invalidate_piece(piece);
+                    self.set_error(Error::HashFailure(piece));
+                    let mut suppliers: Vec<_> = sources.iter().map(|&(_, pid, _)| pid).collect();
+                    suppliers.sort_unstable();
+                    suppliers.dedup();
+                    self.record_hash_failure(piece, sources);
+                    self.picker.piece_failed(piece, suppliers);
                     if !self.stat.active() {
                         self.request_all();
                     }
                 }
             }
             disk::Response::ValidationUpdate { percent, .. } => {
-                self.status.validating = Some(percent);
+                let started = self.status.job == Some(Job::CheckQueued);
+                self.status.job = Some(Job::Checking(percent));
+                if started {
+                    self.announce_status();
+                }
                 self.update_rpc_transfer();
             }
+            disk::Response::ValidationCancelled { .. } => {
+                debug!("Validation cancelled!");
+                // Pieces keep the state they had, and the torrent starts
+                // as it would have once validated
+                self.status.job = None;
+                self.announce_start();
+                self.announce_status();
+            }
             disk::Response::ValidationComplete { mut invalid, .. } => {
                 debug!("Validation completed!");
-                self.status.validating = None;
+                self.status.job = None;
+                self.check_shared(&invalid);
+                if self.collided() {
+                    // Nothing is written until the collision is resolved
+                    self.announce_status();
+                    return;
+                }
                 // Ignore invalid pieces which are
                 // part of an invalid file(none of the disk locations
-                // refer to files which aren't being downloaded(pri. 1)
+                // refer to files which aren't being downloaded(pri. 1),
+                // unless seeding only, where they must not be advertised
+                let seed_only = self.seed_only;
                 invalid.retain(|i| {
-                    Info::piece_disk_locs(&self.info, *i).any(|loc| self.priorities[loc.file] != 0)
+                    seed_only
+                        || Info::piece_disk_locs(&self.info, *i)
+                            .any(|loc| self.priorities[loc.file] != 0)
                 });
-                if invalid.is_empty() {
+                if seed_only {
+                    // Seed whatever is present, the rest is never downloaded
+                    for i in 0..self.pieces.len() {
+                        self.pieces.set_bit(i);
+                    }
+                    for piece in invalid {
+                        self.pieces.unset_bit(u64::from(piece));
+                    }
+                    self.picker.done();
+                    self.status.state = StatusState::Complete;
+                    for pid in &self.leechers {
+                        if let Some(peer) = self.peers.get_mut(pid) {
+                            for i in self.pieces.iter() {
+                                peer.have(i as u32);
+                            }
+                        }
+                    }
+                    self.announce_start();
+                } else if invalid.is_empty() {
                     debug!("Torrent succesfully validated!");
                     if !self.complete() {
                         for i in 0..self.pieces.len() {
@@ -816,8 +1622,8 @@ impl<T: cio::CIO> Torrent<T> {
                             for piece in invalid {
                                 self.pieces.unset_bit(u64::from(piece));
                             }
-                            let seq = self.picker.is_sequential();
-                            self.change_picker(seq);
+                            let strategy = self.picker.strategy();
+                            self.change_picker(strategy);
                         }
                         self.announce_start();
                     } else {
@@ -829,6 +1635,7 @@ impl<T: cio::CIO> Torrent<T> {
                     }
                     self.status.state = StatusState::Incomplete;
                 }
+                self.dirty = true;
                 // update the RPC stats once done
                 self.files.rebuild(&self.info, &self.pieces);
                 self.update_rpc_transfer();
@@ -837,18 +1644,28 @@ impl<T: cio::CIO> Torrent<T> {
             }
             disk::Response::Error { err, .. } => {
                 error!("Disk error: {:?}", err);
-                self.status.error = Some(format!("{}", err));
-                self.announce_status();
+                // A failed move leaves the data where it was
+                self.set_moving(false);
+                self.set_error(err);
                 for piece in self.validating.drain() {
+                    self.block_sources.remove(&piece);
                     self.picker.invalidate_piece(piece);
                     self.pieces.unset_bit(u64::from(piece));
+                    self.pieces_changed.mark();
                 }
             }
-            disk::Response::FreeSpace(_) => unreachable!(),
+            disk::Response::FreeSpace { .. }
+            | disk::Response::Serialized { .. }
+            | disk::Response::Stats { .. }
+            | disk::Response::DirectoryMoving { .. }
+            | disk::Response::DirectoryMoved { .. } => unreachable!(),
         }
     }
 
     fn check_complete(&mut self) {
+        if self.seed_only {
+            return;
+        }
         let mut complete = true;
         for piece in 0..self.pieces.len() {
             let no_dl = Info::piece_disk_locs(&self.info, piece as u32)
@@ -870,9 +1687,12 @@ impl<T: cio::CIO> Torrent<T> {
             }
         } else if self.status.state == StatusState::Complete {
             self.status.state = StatusState::Incomplete;
-            let seq = self.picker.is_sequential();
+            self.dirty = true;
+            let strategy = self.picker.strategy();
+            let first_last = self.picker.first_last();
             self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
-            self.change_picker(seq);
+            self.picker.set_first_last(first_last);
+            self.change_picker(strategy);
             self.announce_status();
             self.announce_start();
             self.request_all();
@@ -881,6 +1701,7 @@ impl<T: cio::CIO> Torrent<T> {
     /// Signal that we've downloaded and verified the torrent
     fn set_finished(&mut self) {
         info!("Torrent {} completed!", self.rpc_id());
+        self.log_event(EventKind::TorrentFinished, "Download complete".to_owned());
         debug!("Wasted: {} MiB", (self.wasted * 16_384) / (1024 * 1024));
         if let Some(req) = tracker::Request::completed(self) {
             self.cio.msg_trk(req);
@@ -890,6 +1711,7 @@ impl<T: cio::CIO> Torrent<T> {
         self.update_rpc_transfer();
         self.status.state = StatusState::Complete;
         self.announce_status();
+        self.create_symlinks();
 
         // Remove all seeding peers.
         let leechers = &self.leechers;
@@ -897,20 +1719,29 @@ impl<T: cio::CIO> Torrent<T> {
             let seeders = self
                 .peers
                 .iter()
-                .filter(|&(id, _)| !leechers.contains(id))
+                .filter(|&(id, p)| !leechers.contains(id) || p.upload_only())
                 .map(|(id, _)| *id);
             for seeder in seeders {
                 self.cio.remove_peer(seeder);
             }
         }
 
-        // Due to how we do validation updates, we should tell peers we now have every single piece
+        // Due to how we do validation updates, we should tell peers we now
+        // have every piece, other than those only in skipped files
         for pid in leechers {
             if let Some(peer) = self.peers.get_mut(pid) {
-                for i in 0..self.pieces.len() {
-                    if !peer.pieces().has_bit(i) {
-                        peer.send_message(Message::Have(i as u32));
-                    }
+                for i in self.pieces.iter() {
+                    peer.have(i as u32);
+                }
+            }
+        }
+
+        // Let peers know we're now upload only
+        for pid in leechers {
+            if let Some(peer) = self.peers.get(pid).filter(|p| p.ext_protocol()) {
+                let msg = self.ext_handshake(peer);
+                if let Some(peer) = self.peers.get_mut(pid) {
+                    peer.send_message(msg);
                 }
             }
         }
@@ -921,7 +1752,12 @@ impl<T: cio::CIO> Torrent<T> {
         let mut peer = self.peers.remove(&pid).ok_or(())?;
         match evt {
             Ok(mut msg) => {
-                if peer.handle_msg(&mut msg).is_ok() && self.handle_msg(msg, &mut peer).is_ok() {
+                let res = peer.handle_msg(&mut msg);
+                if let Err(peer::Error(peer::ErrorKind::ClientRefused(ref client), _)) = res {
+                    let msg = format!("Refused peer {} running {}", peer.addr(), client);
+                    self.log_event(EventKind::PeerBanned, msg);
+                }
+                if res.is_ok() && self.handle_msg(msg, &mut peer).is_ok() {
                     self.peers.insert(pid, peer);
                     return Ok(());
                 } else {
@@ -950,41 +1786,24 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn handle_msg(&mut self, msg: Message, peer: &mut Peer<T>) -> Result<(), ()> {
         trace!("Received {:?} from peer", msg);
         match msg {
-            Message::Handshake { rsv, .. } => {
-                if (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 {
-                    let mut ed = BTreeMap::new();
-                    let mut m = BTreeMap::new();
-
-                    m.insert(
-                        b"ut_metadata".to_vec(),
-                        bencode::BEncode::Int(i64::from(UT_META_ID)),
-                    );
-                    if !self.info.private {
-                        m.insert(
-                            b"ut_pex".to_vec(),
-                            bencode::BEncode::Int(i64::from(UT_PEX_ID)),
-                        );
-                    }
-
-                    ed.insert(b"m".to_vec(), bencode::BEncode::Dict(m));
-                    ed.insert(
-                        b"metadata_size".to_vec(),
-                        bencode::BEncode::Int(self.info_bytes.len() as i64),
-                    );
-                    let payload = bencode::BEncode::Dict(ed).encode_to_buf();
-
-                    peer.send_message(Message::Extension { id: 0, payload });
+            Message::Handshake(hs) => {
+                if (hs.rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 {
+                    let msg = self.ext_handshake(peer);
+                    peer.send_message(msg);
                 }
             }
             Message::Extension { id, payload } => {
-                self.handle_ext(id, payload, peer)?;
+                self.handle_ext(id, payload.into_vec(), peer)?;
             }
-            Message::Bitfield(_) => {
-                if self.pieces.usable(peer.pieces()) && self.status.validating.is_none() {
+            Message::Bitfield(ref prev) => {
+                if self.pieces.usable(peer.pieces()) && !self.status.checking() && !self.seed_only {
                     peer.interested();
                 }
                 if self.info.complete() {
                     self.picker.add_peer(peer);
+                    // The peer's previous pieces were swapped into the message
+                    self.piece_avail.remove(prev);
+                    self.piece_avail.add(peer.pieces());
                 }
                 if !peer.pieces().complete() {
                     self.leechers.insert(peer.id());
@@ -996,6 +1815,7 @@ impl<T: cio::CIO> Torrent<T> {
             Message::Have(idx) => {
                 if self.info.complete() {
                     self.picker.piece_available(idx);
+                    self.piece_avail.have(idx);
                 }
                 if peer.pieces().complete() {
                     self.leechers.remove(&peer.id());
@@ -1004,7 +1824,7 @@ impl<T: cio::CIO> Torrent<T> {
                         return Err(());
                     }
                 }
-                if self.pieces.usable(peer.pieces()) {
+                if self.pieces.usable(peer.pieces()) && !self.seed_only {
                     peer.interested();
                 }
             }
@@ -1048,11 +1868,7 @@ impl<T: cio::CIO> Torrent<T> {
 
                     picker.completed(Block::new(index, begin), |pid| {
                         if let Some(p) = peers.get_mut(&pid) {
-                            p.send_message(Message::Cancel {
-                                index,
-                                begin,
-                                length,
-                            })
+                            p.cancel_block(index, begin);
                         }
                     })
                 };
@@ -1064,12 +1880,16 @@ impl<T: cio::CIO> Torrent<T> {
 
                 self.dirty = true;
                 self.write_piece(index, begin, data);
+                let sources = self.block_sources.entry(index).or_default();
+                sources.retain(|&(offset, _, _)| offset != begin);
+                sources.push((begin, peer.id(), peer.addr()));
 
                 self.downloaded += u64::from(length);
+                self.source_tx[peer.source() as usize].1 += u64::from(length);
                 self.stat.add_dl(u64::from(length));
 
                 if piece_done {
-                    self.cio.msg_disk(disk::Request::validate_piece(
+                    self.msg_data(disk::Request::validate_piece(
                         self.id,
                         self.info.clone(),
                         self.path.clone(),
@@ -1090,20 +1910,39 @@ impl<T: cio::CIO> Torrent<T> {
                 if !self.pieces.has_bit(u64::from(index)) {
                     return Err(());
                 }
-                if length != self.info.block_len(index, begin) {
+                let end = begin.checked_add(length).ok_or(())?;
+                if length == 0
+                    || length > CONFIG.peer.max_request_len
+                    || end > self.info.piece_len(index)
+                {
                     return Err(());
                 }
                 if !self.status.stopped() {
+                    if !peer.start_read() {
+                        debug!("Dropping request beyond the queue size of {:?}", peer);
+                        return Ok(());
+                    }
+                    if CONFIG.net.sendfile && cfg!(target_os = "linux") {
+                        self.request_read_file(peer.id(), index, begin, length);
+                        return Ok(());
+                    }
+                    if length as usize > BUF_SIZE {
+                        self.request_read_range(peer.id(), index, begin, length);
+                        return Ok(());
+                    }
                     if let Some(buf) = Buffer::get() {
-                        self.request_read(peer.id(), index, begin, buf);
+                        self.request_read(peer.id(), index, begin, length, buf);
                         return Ok(());
                     }
+                    peer.finish_read();
                 }
 
                 // TODO: add this to a queue to fulfill later
             }
             Message::Interested => {
-                self.choker.add_peer(peer);
+                if peer.can_upload() {
+                    self.choker.add_peer(peer);
+                }
             }
             Message::Uninterested => {
                 self.choker.remove_peer(peer, &mut self.peers);
@@ -1116,246 +1955,70 @@ impl<T: cio::CIO> Torrent<T> {
         Ok(())
     }
 
-    fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
-        if id == 0 {
-            let b = bencode::decode_buf(&payload).map_err(|_| ())?;
-            let mut d = b.into_dict().ok_or(())?;
-            let m = d
-                .remove(b"m".as_ref())
-                .and_then(|v| v.into_dict())
-                .ok_or(())?;
-            if m.contains_key(b"ut_metadata".as_ref()) {
-                let size = d
-                    .remove(b"metadata_size".as_ref())
-                    .and_then(|v| v.into_int())
-                    .ok_or(())?;
-                if let Some(std::usize::MAX) = self.info_idx {
-                    if size == 0 {
-                        debug!("UT metadata size is 0");
-                        return Err(());
-                    }
-                    if size % 16_384 == 0 {
-                        self.info_idx = Some(size as usize / 16_384 - 1);
-                    } else {
-                        self.info_idx = Some(size as usize / 16_384);
-                    }
-                    if size > MAX_INFO_BYTES {
-                        debug!("UT metadata too large, {} MBs", size / (1000 * 1000));
-                        return Err(());
-                    }
-                    self.info_bytes.resize(size as usize, 0u8);
-                }
-                if !self.info.complete() {
-                    // Request the first index chunk to see if they have it
-                    let mut respb = BTreeMap::new();
-                    respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
-                    respb.insert(b"piece".to_vec(), bencode::BEncode::Int(0));
-                    let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                    let utm_id = if let Some(i) = peer.exts().ut_meta {
-                        i
-                    } else {
-                        return Err(());
-                    };
-                    peer.send_message(Message::Extension {
-                        id: utm_id,
-                        payload,
-                    });
-                }
-            }
-        } else if id == UT_META_ID {
-            let utm_id = if let Some(i) = peer.exts().ut_meta {
-                i
-            } else {
-                return Ok(());
-            };
-            let buf = bencode::decode_buf_first(&payload).map_err(|_| ())?;
-            let mut dict = buf.into_dict().ok_or(())?;
-            let msg = dict
-                .remove(b"msg_type".as_ref())
-                .and_then(|v| v.into_int())
-                .ok_or(())?;
-            let piece_len = dict
-                .remove(b"piece".as_ref())
-                .and_then(|v| v.into_int())
-                .ok_or(())? as usize;
-            if piece_len * 16_384 >= self.info_bytes.len() {
-                return Err(());
-            }
-            // Our metadata request strategy is as follows: after requesting the first
-            // index chunk, we attempt to request every single subsequent chunk from
-            // a peer which responds succesfully. This is slightly wasteful, but
-            // simplifies logic (since we don't have to do "index piece picking").
-            match msg {
-                0 => {
-                    let mut respb = BTreeMap::new();
-                    if self.info_idx.is_none() {
-                        respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(1));
-                        respb.insert(b"piece".to_vec(), bencode::BEncode::Int(piece_len as i64));
-                        let size = if self.info_bytes.len() / 16_384 == piece_len {
-                            self.info_bytes.len() % 16_384
-                        } else {
-                            16_384
-                        };
-                        let total_size = self.info_bytes.len() as i64;
-                        respb.insert(b"total_size".to_vec(), bencode::BEncode::Int(total_size));
-                        let mut payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                        let s = piece_len * 16_384;
-                        payload.extend_from_slice(&self.info_bytes[s..s + size]);
-                        peer.send_message(Message::Extension {
-                            id: utm_id,
-                            payload,
-                        });
-                    } else {
-                        respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(2));
-                        respb.insert(b"piece".to_vec(), bencode::BEncode::Int(piece_len as i64));
-                        let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                        peer.send_message(Message::Extension {
-                            id: utm_id,
-                            payload,
-                        });
-                    }
-                }
-                1 => {
-                    if let Some(last_idx) = self.info_idx {
-                        let data_idx = util::find_subseq(&payload[..], b"ee").unwrap() + 2;
-                        if payload.len() - data_idx > self.info_bytes.len() - piece_len * 16_384 {
-                            debug!(
-                                "Metadata bounds invalid, goes to: {}, ibl: {}",
-                                payload.len() - data_idx,
-                                self.info_bytes.len() - piece_len * 16_384,
-                            );
-                            return Err(());
-                        }
-                        let total_size = dict
-                            .remove(b"total_size".as_ref())
-                            .and_then(|v| v.into_int())
-                            .ok_or(())? as usize;
-                        if total_size != self.info_bytes.len() {
-                            if total_size > MAX_INFO_BYTES as usize {
-                                debug!("UT metadata too large, {} MBs", total_size / (1000 * 1000));
-                                return Err(());
-                            };
-                            // To be safe, we should ensure that the new total_size matches the number of index pieces.
-                            // Otherwise we may request the wrong number of pieces.
-                            let implied_last_info_idx = if total_size % 16_384 == 0 {
-                                total_size / 16_384 - 1
-                            } else {
-                                total_size / 16_384
-                            };
-                            if implied_last_info_idx != last_idx {
-                                debug!("metadata_size {} and total_size {} differ in count of index pieces",
-                                self.info_bytes.len(), total_size);
-                                return Err(());
-                            }
-                            debug!(
-                                "metadata_size {} != total_size {} will use total_size",
-                                self.info_bytes.len(),
-                                total_size
-                            );
-                            self.info_bytes.resize(total_size, 0);
-                        }
-                        let size = if piece_len == last_idx {
-                            if total_size > 16_384 {
-                                total_size % 16_384
-                            } else {
-                                total_size
-                            }
-                        } else {
-                            16_384
-                        };
-                        (&mut self.info_bytes[piece_len * 16_384..piece_len * 16_384 + size])
-                            .copy_from_slice(&payload[data_idx..]);
-                        if piece_len == last_idx {
-                            let mut b = BTreeMap::new();
-                            let bni = bencode::decode_buf(&self.info_bytes).map_err(|_| ())?;
-                            b.insert(
-                                b"announce".to_vec(),
-                                bencode::BEncode::String(
-                                    self.info
-                                        .announce
-                                        .as_ref()
-                                        .map(|u| u.as_str())
-                                        .unwrap_or("")
-                                        .as_bytes()
-                                        .to_vec(),
-                                ),
-                            );
-                            b.insert(b"info".to_vec(), bni);
-                            let ni =
-                                Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
-                            if ni.hash == self.info.hash {
-                                debug!("Magnet file acquired succesfully!");
-                                self.info_idx = None;
-                                self.info = Arc::new(ni);
-                                self.magnet_complete();
-                            } else {
-                                return Err(());
-                            }
-                        } else if piece_len == 0 {
-                            for i in 1..=last_idx {
-                                let mut respb = BTreeMap::new();
-                                respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
-                                respb.insert(b"piece".to_vec(), bencode::BEncode::Int(i as i64));
-                                let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                                peer.send_message(Message::Extension {
-                                    id: utm_id,
-                                    payload,
-                                });
-                            }
-                        }
-                    }
-                }
-                2 => {}
-                i => {
-                    debug!("Got unknown ut_meta id: {}", i);
-                }
-            }
-        } else if id == UT_PEX_ID {
-            const PEX_SEED: u8 = 0x02;
-            const PEX_OUTGOING: u8 = 0x10;
-            if peer.exts().ut_pex.is_none() {
-                return Ok(());
-            }
-            if self.info.private {
-                return Err(());
-            }
-            let b = bencode::decode_buf(&payload).map_err(|_| ())?;
-            let mut d = b.into_dict().ok_or(())?;
-            let mut peers = vec![];
-            let flags = d
-                .remove(b"added.f".as_ref())
-                .and_then(bencode::BEncode::into_bytes)
-                .unwrap_or_else(|| vec![0; 50]);
-            if let Some(bencode::BEncode::String(ref data)) = d.remove(b"added".as_ref()) {
-                for (p, flag) in data.chunks(6).zip(flags) {
-                    if (flag & PEX_SEED != 0) && self.complete() {
-                        continue;
-                    }
-                    if flag & PEX_OUTGOING == 0 {
-                        continue;
-                    }
+    /// Our extended handshake, which is sent again once we become
+    /// upload only.
+    fn ext_handshake(&self, peer: &Peer<T>) -> Message {
+        let mut ed = BTreeMap::new();
+        let m = self.exts.advertised(self);
+        ed.insert(b"m".to_vec(), bencode::BEncode::Dict(m));
+        ed.insert(
+            b"metadata_size".to_vec(),
+            bencode::BEncode::Int(self.info_bytes.len() as i64),
+        );
+        ed.insert(
+            b"v".to_vec(),
+            bencode::BEncode::String(concat!("Synapse ", env!("CARGO_PKG_VERSION")).into()),
+        );
+        ed.insert(b"reqq".to_vec(), bencode::BEncode::Int(EXT_REQQ));
+        ed.insert(
+            b"p".to_vec(),
+            bencode::BEncode::Int(i64::from(LISTEN_PORT.load(atomic::Ordering::Relaxed))),
+        );
+        ed.insert(
+            b"yourip".to_vec(),
+            bencode::BEncode::String(util::ip_to_bytes(&peer.addr().ip())),
+        );
+        if let Some(ip) = util::local_ipv6() {
+            ed.insert(
+                b"ipv6".to_vec(),
+                bencode::BEncode::String(ip.octets().to_vec()),
+            );
+        }
+        let upload_only = self.complete() || self.seed_only;
+        ed.insert(
+            b"upload_only".to_vec(),
+            bencode::BEncode::Int(i64::from(upload_only)),
+        );
+        let payload = bencode::BEncode::Dict(ed).encode_to_buf();
+        Message::Extension {
+            id: 0,
+            payload: payload.into(),
+        }
+    }
 
-                    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-                    let socket = SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..]));
-                    peers.push(SocketAddr::V4(socket));
-                }
-            }
-            if !peers.is_empty() {
-                self.cio
-                    .propagate(cio::Event::Tracker(Ok(tracker::Response::PEX {
-                        tid: self.id,
-                        peers,
-                    })));
-            }
-        } else {
-            debug!("Got unknown extension id: {}", id);
+    fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
+        if id != 0 {
+            return ext::handle(self, peer, id, payload);
+        }
+        if peer.upload_only() && self.complete() {
+            // Neither side would download anything
+            return Err(());
+        }
+        let mut d = match bencode::decode_ref_with(&payload, &ext::LIMITS) {
+            Ok(BEncodeRef::Dict(d)) => d,
+            _ => return Err(()),
+        };
+        match d.remove(b"m".as_ref()) {
+            Some(BEncodeRef::Dict(m)) => ext::handshake(self, peer, &m, &d),
+            _ => Err(()),
         }
-        Ok(())
     }
 
     /// Periodically called to update peers, choking the slowest one and
     /// optimistically unchoking a new peer
     pub fn update_unchoked(&mut self) {
+        self.choker
+            .update_slots(self.stat.ul(Window::TenSeconds), &mut self.peers);
         if self.complete() {
             self.choker.update_download(&mut self.peers)
         } else {
@@ -1378,10 +2041,31 @@ impl<T: cio::CIO> Torrent<T> {
             self.set_priority(p);
         }
 
-        match u.strategy {
-            Some(resource::Strategy::Rarest) => self.change_picker(false),
-            Some(resource::Strategy::Sequential) => self.change_picker(true),
-            None => {}
+        if let Some(strategy) = u.strategy {
+            self.change_picker(strategy);
+        }
+
+        if let Some(first_last) = u.first_last {
+            self.set_first_last(first_last);
+        }
+
+        if u.dht.is_some() || u.pex.is_some() {
+            self.set_network(u.dht, u.pex);
+        }
+
+        if u.name.is_some() || u.comment.is_some() || u.notes.is_some() {
+            self.set_metadata(u.name, u.comment, u.notes);
+        }
+
+        if u.seed_ratio.is_some() || u.seed_time.is_some() {
+            self.seed_ratio = u.seed_ratio.unwrap_or(self.seed_ratio);
+            self.seed_time = u.seed_time.unwrap_or(self.seed_time);
+            self.dirty = true;
+            self.announce_label();
+        }
+
+        if let Some(group) = u.throttle_group {
+            self.set_throttle_group(group);
         }
 
         if let Some(user_data) = u.user_data {
@@ -1396,6 +2080,50 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Sets or clears the name and comment shown in place of the torrent's
+    /// own and its notes, leaving those not given unchanged.
+    pub fn set_metadata(
+        &mut self,
+        name: Option<Option<String>>,
+        comment: Option<Option<String>>,
+        notes: Option<Option<String>>,
+    ) {
+        if let Some(name) = name {
+            self.name = name;
+        }
+        if let Some(comment) = comment {
+            self.comment = comment;
+        }
+        if let Some(notes) = notes {
+            self.notes = notes;
+        }
+        self.dirty = true;
+        let update = self.rpc_info();
+        self.cio
+            .msg_rpc(rpc::CtlMessage::Update(vec![SResourceUpdate::Resource(
+                Cow::Owned(update),
+            )]));
+    }
+
+    /// Sets the priority of every file other than padding, given in order.
+    pub fn set_file_priorities(&mut self, priorities: Vec<u8>) {
+        let mut updates = Vec::new();
+        let all = Arc::make_mut(&mut self.priorities);
+        for ((i, f), &priority) in self.info.user_files().zip(priorities.iter()) {
+            all[i] = priority;
+            updates.push(resource::SResourceUpdate::FilePriority {
+                id: util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref()),
+                kind: resource::ResourceKind::File,
+                priority,
+            });
+        }
+        self.picker.set_priorities(&self.priorities, &self.info);
+        self.clear_piece_cache();
+        self.check_complete();
+        self.dirty = true;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+    }
+
     pub fn rpc_update_file(&mut self, id: String, priority: u8) {
         for (i, f) in self.info.files.iter().enumerate() {
             let fid =
@@ -1462,19 +2190,28 @@ impl<T: cio::CIO> Torrent<T> {
         self.dht_announce();
     }
 
-    fn dht_announce(&mut self) {
-        if self.status.stopped() {
+    /// Announces to the DHT and looks up peers in it again.
+    pub fn dht_announce(&mut self) {
+        if self.status.stopped()
+            || !self.allows_source(PeerSource::Dht)
+            || CONFIG.dht.bootstrap_node.is_none()
+        {
             return;
         }
-        if !self.info.private {
-            let mut req = tracker::Request::DHTAnnounce(self.info.hash);
-            self.cio.msg_trk(req);
-            req = tracker::Request::GetPeers(tracker::GetPeers {
+        self.cio
+            .msg_trk(tracker::Request::DHTAnnounce(tracker::GetPeers {
                 id: self.id,
                 hash: self.info.hash,
-            });
-            self.cio.msg_trk(req);
-        }
+            }));
+        self.dht_announced = Some(Utc::now());
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentDht {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                last_dht_announce: self.dht_announced,
+            },
+        ]));
     }
 
     pub fn complete(&self) -> bool {
@@ -1484,6 +2221,7 @@ impl<T: cio::CIO> Torrent<T> {
     fn set_throttle(&mut self, ul: Option<i64>, dl: Option<i64>) {
         self.throttle.set_ul_rate(ul);
         self.throttle.set_dl_rate(dl);
+        self.dirty = true;
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             resource::SResourceUpdate::Throttle {
@@ -1497,8 +2235,7 @@ impl<T: cio::CIO> Torrent<T> {
 
     fn dump_torrent_file(&mut self) {
         let data = self.info.to_torrent_bencode().encode_to_buf();
-        let mut path = PathBuf::from(&CONFIG.disk.session);
-        path.push(&util::hash_to_id(&self.info.hash));
+        let mut path = PathBuf::from(util::hash_to_id(&self.info.hash));
         path.set_extension("torrent");
         self.cio.msg_disk(disk::Request::WriteFile { data, path });
     }
@@ -1509,7 +2246,10 @@ impl<T: cio::CIO> Torrent<T> {
         self.pieces = Bitfield::new(u64::from(self.info.pieces()));
         self.priorities = Arc::new(vec![3; self.info.files.len()]);
         for peer in self.peers.values_mut() {
-            if peer.magnet_complete(&self.info).is_err() {
+            // Peers of private torrents may only come from their trackers
+            let public =
+                self.info.private && matches!(peer.source(), PeerSource::Dht | PeerSource::Pex);
+            if public || peer.magnet_complete(&self.info).is_err() {
                 self.cio.remove_peer(peer.id());
             }
         }
@@ -1523,19 +2263,114 @@ impl<T: cio::CIO> Torrent<T> {
             )]));
         self.serialize();
 
-        let seq = self.picker.is_sequential();
+        let strategy = self.picker.strategy();
+        let first_last = self.picker.first_last();
         self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
-        self.change_picker(seq);
+        self.picker.set_first_last(first_last);
+        self.change_picker(strategy);
+        self.piece_avail = Availability::new(self.info.pieces());
+        for peer in self.peers.values() {
+            self.piece_avail.add(peer.pieces());
+        }
         self.files = Files::new(&self.info, &self.pieces);
-        self.validate();
         self.dump_torrent_file();
+        self.relocated = true;
+        if self.metadata_only {
+            self.stop_metadata_only();
+        } else {
+            self.validate();
+        }
+    }
+
+    /// Marks a magnet to be stopped once its metadata is fetched, leaving
+    /// only the torrent file in the session directory.
+    pub fn set_metadata_only(&mut self) {
+        if self.status.state == StatusState::Magnet {
+            self.metadata_only = true;
+            self.dirty = true;
+        }
+    }
+
+    fn stop_metadata_only(&mut self) {
+        let msg = "Metadata fetched, stopping".to_owned();
+        self.log_event(EventKind::MetadataFetched, msg);
+        self.pause();
+        for pid in self.peers.keys() {
+            self.cio.remove_peer(*pid);
+        }
+    }
+
+    /// Name of the torrent's data if it's stored in dir and may be moved.
+    pub fn data_in(&self, dir: &str) -> Option<&str> {
+        let settings = config::settings();
+        if self.seed_only || data_dir(&self.path, &settings.directory) != Path::new(dir) {
+            None
+        } else {
+            Some(&self.info.name)
+        }
+    }
+
+    /// Whether the torrent's data is being checked or moved.
+    pub fn busy(&self) -> bool {
+        self.status.job.is_some()
+    }
+
+    /// Marks the torrent's data as being moved, or no longer being.
+    pub fn set_moving(&mut self, moving: bool) {
+        if moving && self.status.job.is_none() {
+            self.status.job = Some(Job::Moving);
+        } else if !moving && self.status.job == Some(Job::Moving) {
+            self.status.job = None;
+            // Point held jobs at wherever the data ended up
+            for mut req in mem::take(&mut self.held) {
+                req.set_path(self.path.clone());
+                self.cio.msg_disk(req);
+            }
+            self.request_all();
+        } else {
+            return;
+        }
+        self.announce_status();
+    }
+
+    /// Moves the torrent into or out of the queue of torrents waiting for
+    /// a download slot.
+    pub fn set_queued(&mut self, queued: bool) {
+        if self.status.queued != queued {
+            self.status.queued = queued;
+            self.announce_status();
+        }
+    }
+
+    /// Records the torrent's data having been moved to path.
+    pub fn set_moved(&mut self, path: String) {
+        // Data in the download directory keeps following it
+        if self.path.is_some() || path != config::settings().directory {
+            self.path = Some(path.clone());
+        }
+        self.set_moving(false);
+        let id = self.rpc_id();
+        self.log_event(EventKind::TorrentMoved, format!("Moved to {}", path));
+        self.dirty = true;
+        self.relocated = true;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentPath {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                path,
+            },
+        ]));
     }
 
     fn set_path(&mut self, path: String) {
+        if self.seed_only {
+            debug!("Not moving seed only torrent data!");
+            return;
+        }
         let from = if let Some(ref p) = self.path {
             p.clone()
         } else {
-            CONFIG.disk.directory.clone()
+            config::settings().directory.clone()
         };
         self.cio.msg_disk(disk::Request::Move {
             tid: self.id,
@@ -1543,10 +2378,12 @@ impl<T: cio::CIO> Torrent<T> {
             to: path,
             target: self.info.name.clone(),
         });
+        self.set_moving(true);
     }
 
     fn set_priority(&mut self, priority: u8) {
         self.priority = priority;
+        self.dirty = true;
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             resource::SResourceUpdate::TorrentPriority {
@@ -1564,7 +2401,7 @@ impl<T: cio::CIO> Torrent<T> {
                 Some(self.info.total_len),
                 Some(u64::from(self.info.pieces())),
                 Some(self.info.piece_len),
-                Some(self.info.files.len() as u32),
+                Some(self.info.user_files().count() as u32),
             )
         } else {
             let name = if self.info.name == "" {
@@ -1574,39 +2411,63 @@ impl<T: cio::CIO> Torrent<T> {
             };
             (name, None, None, None, None)
         };
+        let name = self.name.clone().or(name);
+        let (rate_up, rate_down) = self.get_last_tx_rate();
         Resource::Torrent(resource::Torrent {
             id: self.rpc_id(),
             name,
             size,
+            wanted_size: self.wanted_size(),
             // TODO: Properly add this
-            path: self.path.as_ref().unwrap_or(&CONFIG.disk.directory).clone(),
+            path: self
+                .path
+                .clone()
+                .unwrap_or_else(|| config::settings().directory.clone()),
             created: self.created,
             modified: Utc::now(),
-            status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
+            status: self.status.as_rpc(rate_up, rate_down),
+            state: self.status.torrent_state(),
             error: self.error(),
+            error_kind: self.status.error.as_ref().map(Error::kind),
             priority: self.priority,
             progress: self.progress(),
+            eta: self.eta(),
             availability: self.availability(),
-            strategy: if self.sequential() {
-                resource::Strategy::Sequential
-            } else {
-                resource::Strategy::Rarest
-            },
-            rate_up: 0,
-            rate_down: 0,
+            strategy: self.picker.strategy(),
+            first_last: self.picker.first_last(),
+            rate_up,
+            rate_down,
             throttle_up: self.throttle.ul_rate(),
             throttle_down: self.throttle.dl_rate(),
             transferred_up: self.uploaded,
             transferred_down: self.downloaded,
             peers: 0,
+            peer_sources: self.peer_sources(),
+            countries: self.countries(),
             trackers: self.trackers.len() as u8,
             pieces,
             piece_size,
             piece_field: self.pieces.b64(),
+            piece_availability: self.piece_avail.b64(),
             private: self.info.private,
             creator: self.info.creator.clone(),
-            comment: self.info.comment.clone(),
+            comment: self.comment.clone().or_else(|| self.info.comment.clone()),
+            notes: self.notes.clone(),
             files,
+            label: self.label.clone(),
+            seed_ratio: self.seed_ratio,
+            seed_time: self.seed_time,
+            throttle_group: self.throttle_group.clone(),
+            seeders: self.swarm.seeders,
+            leechers: self.swarm.leechers,
+            completed: self.swarm.completed,
+            disk_read: self.disk.read,
+            disk_written: self.disk.written,
+            disk_time: self.disk.time.as_millis() as u64,
+            hash_failures: self.hash_failures.iter().cloned().collect(),
+            dht: self.dht,
+            pex: self.pex,
+            last_dht_announce: self.dht_announced,
             ..Default::default()
         })
     }
@@ -1625,6 +2486,9 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         for (i, (done, total)) in files.into_iter().enumerate() {
+            if self.info.files[i].is_padding() {
+                continue;
+            }
             let id = util::file_rpc_id(
                 &self.info.hash,
                 self.info.files[i].path.to_string_lossy().as_ref(),
@@ -1653,27 +2517,52 @@ impl<T: cio::CIO> Torrent<T> {
         let mut seen_urls = FHashSet::default();
         self.trackers
             .iter()
-            .filter_map(|trk| {
+            .enumerate()
+            .filter_map(|(i, trk)| {
                 if seen_urls.contains(trk.url.as_str()) {
                     return None;
                 }
                 seen_urls.insert(trk.url.as_str());
-                Some(resource::Resource::Tracker(resource::Tracker {
-                    id: util::trk_rpc_id(&self.info.hash, trk.url.as_str()),
-                    torrent_id: self.rpc_id(),
-                    url: trk.url.as_ref().clone(),
-                    last_report: trk.last_announce,
-                    error: None,
-                    ..Default::default()
-                }))
+                Some(resource::Resource::Tracker(self.rpc_tracker(i)))
             })
             .collect()
     }
 
+    fn rpc_tracker(&self, idx: usize) -> resource::Tracker {
+        let trk = &self.trackers[idx];
+        resource::Tracker {
+            id: util::trk_rpc_id(&self.info.hash, trk.url.as_str()),
+            torrent_id: self.rpc_id(),
+            url: trk.url.as_ref().clone(),
+            last_report: trk.last_announce,
+            next_report: self.next_report(idx),
+            error: trk.error(),
+            warning: trk.warning.clone(),
+            peers: trk.peers as u32,
+            ..Default::default()
+        }
+    }
+
+    /// When the tracker at an index will next be announced to. Only the
+    /// primary tracker of a running torrent is announced to at intervals.
+    fn next_report(&self, idx: usize) -> Option<DateTime<Utc>> {
+        if idx != 0 || self.status.stopped() {
+            return None;
+        }
+        let wait = self.trackers[0]
+            .update
+            .map(|t| t.saturating_duration_since(Instant::now()))
+            .unwrap_or_default();
+        Some(
+            Utc::now()
+                + chrono::Duration::from_std(wait).unwrap_or_else(|_| chrono::Duration::zero()),
+        )
+    }
+
     pub fn send_rpc_removal(&mut self) {
         let mut r = Vec::new();
         r.push(self.rpc_id());
-        for f in &self.info.files {
+        for (_, f) in self.info.user_files() {
             let id =
                 util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
             r.push(id)
@@ -1690,21 +2579,133 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn error(&self) -> Option<String> {
-        self.status.error.clone()
+        self.status.error.as_ref().map(Error::to_string)
+    }
+
+    /// Records an error, unless it would replace one which stopped the torrent.
+    /// Errors which stop the torrent are sent to clients as alerts, and
+    /// transient ones are retried with a backoff.
+    fn set_error(&mut self, err: Error) {
+        if self.status.failed() && !err.fatal() {
+            return;
+        }
+        if self.status.error.as_ref() == Some(&err) {
+            return;
+        }
+        if err.fatal() {
+            let retry = if err.transient() {
+                let delay = cmp::min(RETRY_BASE_SECS << cmp::min(self.retries, 7), RETRY_MAX_SECS);
+                self.retries += 1;
+                self.retry_at = Some(Instant::now() + Duration::from_secs(delay));
+                info!("Retrying {:?} in {} seconds: {}", self.rpc_id(), delay, err);
+                Some(Utc::now() + chrono::Duration::seconds(delay as i64))
+            } else {
+                None
+            };
+            self.cio.msg_rpc(rpc::CtlMessage::Alert {
+                id: self.rpc_id(),
+                error_kind: err.kind(),
+                error: err.to_string(),
+                retry,
+            });
+        } else {
+            let kind = match err {
+                Error::TrackerFailure(_) => EventKind::TrackerWarning,
+                _ => EventKind::TorrentError,
+            };
+            self.log_event(kind, err.to_string());
+        }
+        self.status.error = Some(err);
+        self.announce_status();
+    }
+
+    /// Records an event concerning the torrent in the RPC event log.
+    fn log_event(&mut self, kind: EventKind, message: String) {
+        self.cio.msg_rpc(rpc::CtlMessage::Event {
+            kind,
+            id: Some(self.rpc_id()),
+            message,
+        });
+    }
+
+    /// Clears the current error if it was resolved.
+    fn clear_error<F: FnOnce(&Error) -> bool>(&mut self, resolved: F) {
+        if self.status.error.as_ref().map(resolved).unwrap_or(false) {
+            self.status.error = None;
+            self.announce_status();
+        }
+    }
+
+    /// The failure of the primary tracker, if every tracker failed.
+    fn trackers_failed(&self) -> Option<String> {
+        if self.trackers.iter().all(|t| t.error().is_some()) {
+            self.trackers.front().and_then(Tracker::error)
+        } else {
+            None
+        }
+    }
+
+    /// Resumes the torrent if it was stopped for lack of space.
+    pub fn space_freed(&mut self) {
+        if let Some(Error::DiskFull(_)) = self.status.error {
+            info!("Retrying {:?} now that space was freed", self.rpc_id());
+            self.retry();
+        }
     }
 
-    fn sequential(&self) -> bool {
-        self.picker.is_sequential()
+    /// Retries the jobs which failed with a transient error once due.
+    pub fn try_retry(&mut self) {
+        if self
+            .retry_at
+            .map(|at| Instant::now() >= at)
+            .unwrap_or(false)
+        {
+            debug!("Retrying {:?} after a transient error", self.rpc_id());
+            self.retry();
+        }
+    }
+
+    fn retry(&mut self) {
+        self.retry_at = None;
+        self.status.error = None;
+        self.request_all();
+        self.announce_status();
     }
 
     fn progress(&self) -> f32 {
         if self.status.magnet() {
             return 0.0;
         }
-        if let Some(amnt) = self.status.validating {
+        if let Some(Job::Checking(amnt)) = self.status.job {
             amnt
+        } else if self.status.job == Some(Job::CheckQueued) {
+            0.0
         } else {
-            self.pieces.iter().count() as f32 / self.info.pieces() as f32
+            match self.files.wanted(&self.info, &self.priorities) {
+                (_, 0) => 1.0,
+                (done, total) => done as f32 / total as f32,
+            }
+        }
+    }
+
+    fn wanted_size(&self) -> Option<u64> {
+        if self.status.magnet() {
+            return None;
+        }
+        Some(self.files.wanted(&self.info, &self.priorities).1)
+    }
+
+    /// Seconds left to download the files which aren't skipped at the
+    /// smoothed rate, if they're being downloaded.
+    fn eta(&self) -> Option<u64> {
+        if self.status.magnet() || !self.status.should_dl() || self.stat.dl(Window::TenSeconds) == 0
+        {
+            return None;
+        }
+        let (done, total) = self.files.wanted(&self.info, &self.priorities);
+        match self.stat.smooth_dl() {
+            0 => None,
+            rate => Some(total.saturating_sub(done) / rate),
         }
     }
 
@@ -1741,7 +2742,8 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn get_last_tx_rate(&self) -> (u64, u64) {
-        (self.stat.avg_ul(), self.stat.avg_dl())
+        let window = Window::TenSeconds;
+        (self.stat.ul(window), self.stat.dl(window))
     }
 
     /// Writes a piece of torrent info, with piece index idx,
@@ -1749,21 +2751,42 @@ impl<T: cio::CIO> Torrent<T> {
     /// The disk send handle is also provided.
     fn write_piece(&mut self, index: u32, begin: u32, data: Buffer) {
         let locs = Info::block_disk_locs_pri(&self.info, &self.priorities, index, begin);
-        self.cio
-            .msg_disk(disk::Request::write(self.id, data, locs, self.path.clone()));
+        self.msg_data(disk::Request::write(self.id, data, locs, self.path.clone()));
     }
 
     /// Issues a read request of the given torrent
-    fn request_read(&mut self, id: usize, index: u32, begin: u32, data: Buffer) {
-        let locs = Info::block_disk_locs(&self.info, index, begin);
-        let len = self.info.block_len(index, begin);
+    fn request_read(&mut self, id: usize, index: u32, begin: u32, len: u32, data: Buffer) {
+        let locs = Info::range_disk_locs(&self.info, index, begin, len);
         let ctx = disk::Ctx::new(id, self.id, index, begin, len);
-        self.cio
-            .msg_disk(disk::Request::read(ctx, data, locs, self.path.clone()));
+        self.msg_data(disk::Request::read(ctx, data, locs, self.path.clone()));
+    }
+
+    /// Issues a read request which sends the block from its file if possible
+    fn request_read_file(&mut self, id: usize, index: u32, begin: u32, len: u32) {
+        let locs = Info::range_disk_locs(&self.info, index, begin, len);
+        let ctx = disk::Ctx::new(id, self.id, index, begin, len);
+        self.msg_data(disk::Request::read_file(ctx, locs, self.path.clone()));
+    }
+
+    /// Issues a read request longer than a block
+    fn request_read_range(&mut self, id: usize, index: u32, begin: u32, len: u32) {
+        let locs = Info::range_disk_locs(&self.info, index, begin, len);
+        let ctx = disk::Ctx::new(id, self.id, index, begin, len);
+        self.msg_data(disk::Request::read_range(ctx, locs, self.path.clone()));
+    }
+
+    /// Sends a job on the torrent's data to the disk, holding it while
+    /// the data is moved so it doesn't recreate files where it was.
+    fn msg_data(&mut self, req: disk::Request) {
+        if self.status.job == Some(Job::Moving) {
+            self.held.push(req);
+        } else {
+            self.cio.msg_disk(req);
+        }
     }
 
     fn make_requests_pid(&mut self, pid: usize) {
-        if self.status.should_dl() {
+        if self.status.should_dl() && !self.seed_only {
             let peer = self
                 .peers
                 .get_mut(&pid)
@@ -1774,31 +2797,52 @@ impl<T: cio::CIO> Torrent<T> {
 
     fn make_requests(peer: &mut Peer<T>, picker: &mut Picker, info: &Info) {
         if let Some(m) = peer.queue_reqs() {
+            // Contiguous blocks of a piece are requested together, up to
+            // the request size
+            let mut req: Option<(u32, u32, u32)> = None;
             for _ in 0..(m) {
-                if let Some(block) = picker.pick(peer) {
-                    peer.request_piece(
-                        block.index,
-                        block.offset,
-                        info.block_len(block.index, block.offset),
-                    );
-                } else {
-                    break;
+                let block = match picker.pick(peer) {
+                    Some(block) => block,
+                    None => break,
+                };
+                let len = info.block_len(block.index, block.offset);
+                match req {
+                    Some((index, begin, ref mut rlen))
+                        if index == block.index
+                            && begin + *rlen == block.offset
+                            && *rlen + len <= CONFIG.peer.request_size =>
+                    {
+                        *rlen += len;
+                    }
+                    _ => {
+                        if let Some((index, begin, rlen)) = req {
+                            peer.request_piece(index, begin, rlen);
+                        }
+                        req = Some((block.index, block.offset, len));
+                    }
                 }
             }
+            if let Some((index, begin, rlen)) = req {
+                peer.request_piece(index, begin, rlen);
+            }
         } else {
             peer.interested();
         }
     }
 
-    pub fn add_peer(&mut self, conn: PeerConn) -> Option<usize> {
-        if self.peers.len() >= MAX_PEERS {
+    pub fn add_peer(&mut self, conn: PeerConn, source: PeerSource) -> Option<usize> {
+        // Peers requested by a client are dialed even when at the limit
+        if source != PeerSource::Manual && self.peers.len() >= config::settings().max_peers {
+            return None;
+        }
+        if !self.allows_source(source) {
             return None;
         }
         if self.peers.values().any(|p| p.addr() == conn.sock().addr()) {
             return None;
         }
         if let Ok(pid) = self.cio.add_peer(conn) {
-            if let Ok(p) = Peer::new(pid, self, None, None) {
+            if let Ok(p) = Peer::new(pid, self, None, None, source) {
                 if self.info_idx.is_none() {
                     self.picker.add_peer(&p);
                 }
@@ -1810,12 +2854,21 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn add_inc_peer(&mut self, pid: usize, id: [u8; 20], rsv: [u8; 8]) -> Option<usize> {
+        if self.peers.len() >= config::settings().max_peers {
+            return None;
+        }
+        if let Some(c) = client::identify(&id) {
+            if client::policy(&c) == Some(ClientPolicy::Refuse) {
+                debug!("Refusing incoming peer using client {}", c.name);
+                return None;
+            }
+        }
         if let Some(addr) = self.cio.get_peer(pid, |pconn| pconn.sock().addr()) {
             if self.peers.values().any(|p| p.addr() == addr) {
                 return None;
             }
         }
-        if let Ok(p) = Peer::new(pid, self, Some(id), Some(rsv)) {
+        if let Ok(p) = Peer::new(pid, self, Some(id), Some(rsv), PeerSource::Incoming) {
             debug!("{:?}: Adding peer {:?}!", self.rpc_id(), pid);
             if self.info_idx.is_none() {
                 self.picker.add_peer(&p);
@@ -1828,12 +2881,15 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn announce_status(&mut self) {
         let id = self.rpc_id();
+        let (rate_up, rate_down) = self.get_last_tx_rate();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             SResourceUpdate::TorrentStatus {
                 id,
                 kind: resource::ResourceKind::Torrent,
-                error: self.status.error.clone(),
-                status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
+                error: self.error(),
+                error_kind: self.status.error.as_ref().map(Error::kind),
+                status: self.status.as_rpc(rate_up, rate_down),
+                state: self.status.torrent_state(),
             },
         ]));
     }
@@ -1841,13 +2897,31 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn update_rpc_peers(&mut self) {
         let availability = self.availability();
         let id = self.rpc_id();
-        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+        let updates = vec![
             SResourceUpdate::TorrentPeers {
-                id,
+                id: id.clone(),
                 kind: resource::ResourceKind::Torrent,
                 peers: self.peers.len() as u16,
                 availability,
             },
+            SResourceUpdate::TorrentPeerSources {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                peer_sources: self.peer_sources(),
+            },
+        ];
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+    }
+
+    fn update_rpc_swarm(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentSwarm {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                seeders: self.swarm.seeders,
+                leechers: self.swarm.leechers,
+                completed: self.swarm.completed,
+            },
         ]));
     }
 
@@ -1855,18 +2929,15 @@ impl<T: cio::CIO> Torrent<T> {
         let updates = self
             .trackers
             .iter()
-            .map(|tracker| {
-                let id = util::trk_rpc_id(&self.info.hash, tracker.url.as_str());
-                let error = match tracker.status {
-                    TrackerStatus::Failure(ref r) => Some(r.clone()),
-                    _ => None,
-                };
-                SResourceUpdate::TrackerStatus {
-                    id,
-                    kind: resource::ResourceKind::Tracker,
-                    last_report: tracker.last_announce,
-                    error,
-                }
+            .enumerate()
+            .map(|(i, tracker)| SResourceUpdate::TrackerStatus {
+                id: util::trk_rpc_id(&self.info.hash, tracker.url.as_str()),
+                kind: resource::ResourceKind::Tracker,
+                last_report: tracker.last_announce,
+                next_report: self.next_report(i),
+                error: tracker.error(),
+                warning: tracker.warning.clone(),
+                peers: tracker.peers as u32,
             })
             .collect();
         self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
@@ -1876,6 +2947,7 @@ impl<T: cio::CIO> Torrent<T> {
         let progress = self.progress();
         let (rate_up, rate_down) = self.get_last_tx_rate();
         let id = self.rpc_id();
+        let tx = self.transfer();
         let mut updates = Vec::new();
         updates.push(SResourceUpdate::TorrentTransfer {
             id,
@@ -1884,16 +2956,69 @@ impl<T: cio::CIO> Torrent<T> {
             rate_down,
             transferred_up: self.uploaded,
             transferred_down: self.downloaded,
+            overhead_up: tx.overhead_ul,
+            overhead_down: tx.overhead_dl,
             progress,
+            wanted_size: self.wanted_size(),
+            eta: self.eta(),
         });
+        updates.push(SResourceUpdate::TorrentPeerSources {
+            id: self.rpc_id(),
+            kind: resource::ResourceKind::Torrent,
+            peer_sources: self.peer_sources(),
+        });
+        if CONFIG.peer.geoip_db.is_some() {
+            let countries = self.countries();
+            if countries != self.countries_sent {
+                self.countries_sent = countries.clone();
+                updates.push(SResourceUpdate::TorrentCountries {
+                    id: self.rpc_id(),
+                    kind: resource::ResourceKind::Torrent,
+                    countries,
+                });
+            }
+        }
+        let now = Instant::now();
+        if self.pieces_changed.take(now) {
+            updates.push(SResourceUpdate::TorrentPieces {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                piece_field: self.pieces.b64(),
+            });
+        }
+        if let Some(piece_availability) = self.piece_avail.take_changed(now) {
+            updates.push(SResourceUpdate::TorrentPieceAvailability {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                piece_availability,
+            });
+        }
 
+        let send_tx = self
+            .peer_tx_sent
+            .is_none_or(|t| now >= t + Duration::from_secs(PEER_TX_SECS));
+        if send_tx {
+            self.peer_tx_sent = Some(now);
+        }
         for (pid, p) in &mut self.peers {
             if !p.active() {
                 continue;
             }
             let (rate_up, rate_down) = p.get_tx_rates();
+            let id = util::peer_rpc_id(&self.info.hash, *pid as u64);
+            // Only transfers which changed are sent, at most every few seconds
+            if let Some(tx) = send_tx.then(|| p.take_transfer()).flatten() {
+                updates.push(SResourceUpdate::PeerTransfer {
+                    id: id.clone(),
+                    kind: resource::ResourceKind::Peer,
+                    transferred_up: tx.ul,
+                    transferred_down: tx.dl,
+                    overhead_up: tx.overhead_ul,
+                    overhead_down: tx.overhead_dl,
+                });
+            }
             updates.push(SResourceUpdate::Rate {
-                id: util::peer_rpc_id(&self.info.hash, *pid as u64),
+                id,
                 kind: resource::ResourceKind::Peer,
                 rate_up,
                 rate_down,
@@ -1901,6 +3026,9 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         for (idx, done) in self.files.flush() {
+            if self.info.files[idx].is_padding() {
+                continue;
+            }
             let id = util::file_rpc_id(
                 &self.info.hash,
                 self.info.files[idx].path.to_string_lossy().as_ref(),
@@ -1918,9 +3046,11 @@ impl<T: cio::CIO> Torrent<T> {
     fn cleanup_peer(&mut self, peer: &mut Peer<T>) {
         trace!("Removing {:?}!", peer);
         self.choker.remove_peer(peer, &mut self.peers);
+        self.closed_tx += peer.transfer();
         self.leechers.remove(&peer.id());
         if self.info.complete() {
             self.picker.remove_peer(peer);
+            self.piece_avail.remove(peer.pieces());
         }
     }
 
@@ -1932,15 +3062,23 @@ impl<T: cio::CIO> Torrent<T> {
                 self.cio.msg_trk(req);
             }
             self.status.paused = true;
+            self.dirty = true;
             self.announce_status();
         }
     }
 
     pub fn resume(&mut self) {
         debug!("Resuming torrent!");
+        if self.metadata_only && self.status.state != StatusState::Magnet {
+            // Resuming downloads the data, finding what's already there
+            self.metadata_only = false;
+            self.validate();
+        }
         if self.status.error.is_some() || self.status.paused {
             if self.status.error.is_some() {
                 self.status.error = None;
+                self.retries = 0;
+                self.retry_at = None;
             }
             if self.status.paused {
                 debug!("Sending started request to trk");
@@ -1949,19 +3087,75 @@ impl<T: cio::CIO> Torrent<T> {
                 }
                 self.status.paused = false;
             }
+            self.dirty = true;
             self.request_all();
             self.announce_status();
             self.dht_announce();
         }
     }
 
+    /// Stops the torrent, as a file of another is at the same path.
+    pub fn set_collision(&mut self, reason: String) {
+        self.set_error(Error::Collision(reason));
+    }
+
+    /// Whether the torrent was stopped by a collision with another's files.
+    pub fn collided(&self) -> bool {
+        matches!(self.status.error, Some(Error::Collision(_)))
+    }
+
+    /// Validates the torrent before it writes to files other torrents
+    /// also have, whose data may already be in place to seed. It's
+    /// stopped by a collision if they differ.
+    pub fn verify_shared(&mut self, files: Vec<usize>) {
+        self.shared = files;
+        if !self.status.validating() {
+            self.validate();
+        }
+    }
+
+    /// Stops the torrent if a shared file has pieces validation found
+    /// invalid. Pieces also covering other files may be invalid as those
+    /// are missing, so only pieces wholly within shared files count.
+    fn check_shared(&mut self, invalid: &[u32]) {
+        let shared = mem::take(&mut self.shared);
+        let differs = invalid
+            .iter()
+            .map(|&i| Info::piece_disk_locs(&self.info, i).collect::<Vec<_>>())
+            .find(|locs| locs.iter().all(|loc| shared.contains(&loc.file)))
+            .and_then(|locs| locs.into_iter().next());
+        if let Some(loc) = differs {
+            let reason = format!(
+                "{} differs from the same file of another torrent",
+                self.info.files[loc.file].path.display()
+            );
+            info!("Stopping {}: {}", self.info.name, reason);
+            self.set_collision(reason);
+        }
+    }
+
+    /// Whether the torrent's files must be checked for collisions again,
+    /// resetting it.
+    pub fn take_relocated(&mut self) -> bool {
+        mem::replace(&mut self.relocated, false)
+    }
+
+    /// Records a move being refused, as it would overwrite file.
+    pub fn refuse_move(&mut self, file: &Path) {
+        let msg = format!("Not moving, {} is a file of another torrent", file.display());
+        self.log_event(EventKind::TorrentError, msg);
+    }
+
     pub fn validate(&mut self) {
+        // Validation finds what's missing from disk, resolving disk errors
+        self.status.error = None;
+        self.retry_at = None;
         self.cio.msg_disk(disk::Request::validate(
             self.id,
             self.info.clone(),
             self.path.clone(),
         ));
-        self.status.validating = Some(0.0);
+        self.status.job = Some(Job::CheckQueued);
         self.announce_status();
     }
 
@@ -1973,6 +3167,72 @@ impl<T: cio::CIO> Torrent<T> {
         &self.peers
     }
 
+    /// Connected peers and data transferred for each source of peers.
+    pub fn peer_sources(&self) -> Vec<resource::PeerSourceStats> {
+        PeerSource::ALL
+            .iter()
+            .map(|&source| {
+                let (up, down) = self.source_tx[source as usize];
+                resource::PeerSourceStats {
+                    source,
+                    peers: self.peers.values().filter(|p| p.source() == source).count() as u16,
+                    transferred_up: up,
+                    transferred_down: down,
+                }
+            })
+            .collect()
+    }
+
+    /// Connected peers and data uploaded for each country of peers.
+    pub fn countries(&self) -> Vec<resource::CountryStats> {
+        country_stats(
+            self.peers.values().filter_map(|p| p.country()),
+            &self.country_tx,
+        )
+    }
+
+    /// Number of outgoing connections which haven't completed a handshake.
+    pub fn half_open(&self) -> usize {
+        self.peers.values().filter(|p| !p.ready()).count()
+    }
+
+    /// Disconnects peers which haven't completed a handshake within the timeout.
+    pub fn prune_half_open(&mut self, timeout: Duration) {
+        let cio = &mut self.cio;
+        for (pid, _) in self.peers.iter().filter(|(_, p)| !p.ready()) {
+            if cio.get_peer(*pid, |pconn| pconn.last_action().elapsed() > timeout) == Some(true) {
+                debug!("Pruning half open connection {}", pid);
+                cio.remove_peer(*pid);
+            }
+        }
+    }
+
+    /// Disconnects up to `count` of the least useful peers to make room
+    /// for others: those yet to complete a handshake, then those idle for
+    /// longer than the prune timeout. Returns the number disconnected.
+    pub fn reclaim_peers(&mut self, count: usize) -> usize {
+        let prune_timeout = Duration::from_secs(config::settings().prune_timeout);
+        let cio = &mut self.cio;
+        let mut candidates: Vec<_> = self
+            .peers
+            .iter()
+            .filter_map(|(pid, p)| {
+                let idle = cio.get_peer(*pid, |pconn| pconn.last_action().elapsed())?;
+                if !p.ready() || idle > prune_timeout {
+                    Some((p.ready(), idle, *pid))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        candidates.truncate(count);
+        for &(_, _, pid) in &candidates {
+            cio.remove_peer(pid);
+        }
+        candidates.len()
+    }
+
     pub fn update_pex(&mut self, added: &[SocketAddr], removed: &[SocketAddr]) {
         let mut a = vec![];
         let mut a6 = vec![];
@@ -2003,10 +3263,10 @@ impl<T: cio::CIO> Torrent<T> {
         dict.insert(b"added6".to_vec(), BEncode::String(a6));
         dict.insert(b"removed".to_vec(), BEncode::String(r));
         dict.insert(b"removed6".to_vec(), BEncode::String(r6));
-        let payload = BEncode::Dict(dict).encode_to_buf();
+        let payload: Box<[u8]> = BEncode::Dict(dict).encode_to_buf().into();
 
         for peer in self.peers.values_mut() {
-            if let Some(id) = peer.exts().ut_pex {
+            if let Some(id) = peer.exts().get(ext::pex::NAME) {
                 peer.send_message(Message::Extension {
                     id,
                     payload: payload.clone(),
@@ -2043,30 +3303,41 @@ impl<T: cio::CIO> Torrent<T> {
         self.peers.keys().cloned().collect()
     }
 
-    pub fn change_picker(&mut self, sequential: bool) {
+    pub fn change_picker(&mut self, strategy: resource::Strategy) {
         debug!("Swapping pickers!");
-        let prev_seq = self.picker.is_sequential();
-        self.picker.change_picker(sequential);
+        let prev = self.picker.strategy();
+        self.picker.change_picker(strategy);
         for peer in self.peers.values() {
             self.picker.add_peer(peer);
         }
         self.picker.set_priorities(&self.priorities, &self.info);
-        let id = self.rpc_id();
-        let sequential = self.picker.is_sequential();
         self.clear_piece_cache();
-        if prev_seq != sequential {
-            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                SResourceUpdate::TorrentPicker {
-                    id,
-                    kind: resource::ResourceKind::Torrent,
-                    strategy: if sequential {
-                        resource::Strategy::Sequential
-                    } else {
-                        resource::Strategy::Rarest
-                    },
-                },
-            ]));
+        if prev != strategy {
+            self.update_rpc_picker();
+        }
+    }
+
+    /// Sets whether the first and last pieces of each file are downloaded
+    /// before the others, whatever the picking strategy.
+    pub fn set_first_last(&mut self, first_last: bool) {
+        if first_last == self.picker.first_last() {
+            return;
         }
+        self.picker.set_first_last(first_last);
+        self.picker.set_priorities(&self.priorities, &self.info);
+        self.clear_piece_cache();
+        self.update_rpc_picker();
+    }
+
+    fn update_rpc_picker(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentPicker {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                strategy: self.picker.strategy(),
+                first_last: self.picker.first_last(),
+            },
+        ]));
     }
 
     fn clear_piece_cache(&mut self) {
@@ -2082,6 +3353,81 @@ impl<T: cio::CIO> fmt::Debug for Torrent<T> {
     }
 }
 
+/// Directory a torrent's data is in, given its own path, if it has one,
+/// and the download directory.
+fn data_dir<'a>(path: &'a Option<String>, default: &'a str) -> &'a Path {
+    Path::new(path.as_deref().unwrap_or(default))
+}
+
+/// Files in dir holding pieces which are missing or have the wrong length.
+pub fn damaged_files(
+    info: &Info,
+    priorities: &[u8],
+    pieces: &Bitfield,
+    dir: &Path,
+) -> Vec<PathBuf> {
+    let piece_len = u64::from(info.piece_len);
+    let mut start = 0;
+    let mut damaged = Vec::new();
+    for (i, f) in info.files.iter().enumerate() {
+        let range = start / piece_len..(start + f.length).div_ceil(piece_len);
+        start += f.length;
+        if f.length == 0 || f.is_padding() || f.symlink.is_some() || priorities[i] == 0 {
+            continue;
+        }
+        if !range.into_iter().any(|p| pieces.has_bit(p)) {
+            continue;
+        }
+        match fs::metadata(dir.join(&f.path)) {
+            Ok(ref m) if m.len() == f.length => {}
+            _ => damaged.push(f.path.clone()),
+        }
+    }
+    damaged
+}
+
+/// Counts an upload to a peer towards its country.
+fn add_country_ul(country_tx: &mut FHashMap<String, u64>, country: Option<&str>, len: u32) {
+    let country = match country {
+        Some(c) => c,
+        None => return,
+    };
+    match country_tx.get_mut(country) {
+        Some(up) => *up += u64::from(len),
+        None => {
+            country_tx.insert(country.to_owned(), u64::from(len));
+        }
+    }
+}
+
+/// Stats of each country peers are connected from or were uploaded to,
+/// ordered by country.
+fn country_stats<'a, I>(
+    peers: I,
+    country_tx: &'a FHashMap<String, u64>,
+) -> Vec<resource::CountryStats>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut stats: BTreeMap<&str, (u16, u64)> = country_tx
+        .iter()
+        .map(|(c, &up)| (c.as_str(), (0, up)))
+        .collect();
+    for c in peers {
+        stats.entry(c).or_default().0 += 1;
+    }
+    stats
+        .into_iter()
+        .map(
+            |(country, (peers, transferred_up))| resource::CountryStats {
+                country: country.to_owned(),
+                peers,
+                transferred_up,
+            },
+        )
+        .collect()
+}
+
 impl<T: cio::CIO> fmt::Display for Torrent<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Torrent {}", util::hash_to_id(&self.info.hash[..]))
@@ -2095,10 +3441,124 @@ impl<T: cio::CIO> Drop for Torrent<T> {
             self.leechers.remove(&id);
         }
         if !self.status.paused {
-            if let Some(msg) = tracker::Request::stopped(self) {
+            for msg in tracker::Request::stopped_all(self) {
                 self.cio.msg_trk(msg);
             }
         }
         self.send_rpc_removal();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_torrent_state() {
+        let mut s = Status {
+            paused: false,
+            job: None,
+            error: None,
+            state: StatusState::Magnet,
+            stalled: false,
+            queued: true,
+        };
+        assert_eq!(s.torrent_state(), TorrentState::Queued);
+        s.queued = false;
+        assert_eq!(s.torrent_state(), TorrentState::DownloadingMetadata);
+        s.state = StatusState::Incomplete;
+        assert_eq!(s.torrent_state(), TorrentState::Downloading);
+        s.paused = true;
+        assert_eq!(s.torrent_state(), TorrentState::Paused);
+        // Disk errors take precedence over pausing, but others don't
+        s.error = Some(Error::HashFailure(0));
+        assert_eq!(s.torrent_state(), TorrentState::Paused);
+        s.error = Some(Error::DiskFull(String::new()));
+        assert_eq!(s.torrent_state(), TorrentState::Error);
+        s.job = Some(Job::CheckQueued);
+        assert_eq!(s.torrent_state(), TorrentState::CheckingQueued);
+        s.job = Some(Job::Checking(0.5));
+        assert_eq!(s.torrent_state(), TorrentState::Checking);
+        s.state = StatusState::Complete;
+        assert!(!s.completed());
+        s.job = None;
+        s.error = None;
+        s.paused = false;
+        s.queued = true;
+        assert_eq!(s.torrent_state(), TorrentState::Seeding);
+        s.job = Some(Job::Moving);
+        assert_eq!(s.torrent_state(), TorrentState::Moving);
+    }
+
+    #[test]
+    fn test_files_wanted() {
+        let mut info = Info::with_pieces(4);
+        let file = |length, attr: Option<&str>| info::File {
+            path: PathBuf::new(),
+            length,
+            attr: attr.map(|a| a.to_owned()),
+            symlink: None,
+        };
+        info.files = vec![
+            file(24_576, None),
+            file(8_192, Some("p")),
+            file(32_768, None),
+        ];
+        info.piece_idx = Info::generate_piece_idx(4, 16_384, &info.files);
+        let info = Arc::new(info);
+        let mut pieces = Bitfield::new(4);
+        pieces.set_bit(0);
+        pieces.set_bit(1);
+        let mut files = Files::new(&info, &pieces);
+        // Padding is never counted, even when not skipped
+        assert_eq!(files.wanted(&info, &[3, 3, 3]), (24_576, 57_344));
+        assert_eq!(files.wanted(&info, &[3, 3, 0]), (24_576, 24_576));
+        assert_eq!(files.wanted(&info, &[0, 0, 3]), (0, 32_768));
+        files.update(&info, 2);
+        assert_eq!(files.wanted(&info, &[0, 0, 3]), (16_384, 32_768));
+        assert_eq!(files.wanted(&info, &[0, 0, 0]), (0, 0));
+    }
+
+    #[test]
+    fn test_damaged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut info = Info::with_pieces(3);
+        let file = |path: &str, length| info::File {
+            path: PathBuf::from(path),
+            length,
+            attr: None,
+            symlink: None,
+        };
+        info.files = vec![file("a", 16_384), file("b", 16_384), file("c", 16_384)];
+        let mut pieces = Bitfield::new(3);
+        pieces.set_bit(0);
+        pieces.set_bit(1);
+        fs::write(dir.path().join("a"), vec![0; 16_384]).unwrap();
+        fs::write(dir.path().join("b"), vec![0; 100]).unwrap();
+        // c has no pieces yet, so it's fine for it to be missing
+        let damaged = damaged_files(&info, &[3, 3, 3], &pieces, dir.path());
+        assert_eq!(damaged, vec![PathBuf::from("b")]);
+        assert!(damaged_files(&info, &[3, 0, 3], &pieces, dir.path()).is_empty());
+
+        // Torrents without a path of their own are in the download directory
+        let default = dir.path().to_str().unwrap();
+        assert_eq!(data_dir(&None, default), dir.path());
+        let path = Some("/elsewhere".to_owned());
+        assert_eq!(data_dir(&path, default), Path::new("/elsewhere"));
+    }
+
+    #[test]
+    fn test_country_stats() {
+        let mut country_tx = FHashMap::default();
+        add_country_ul(&mut country_tx, Some("DE"), 100);
+        add_country_ul(&mut country_tx, Some("DE"), 50);
+        add_country_ul(&mut country_tx, None, 10);
+        let stats = country_stats(vec!["US", "US", "DE"].into_iter(), &country_tx);
+        let stat = |country: &str, peers, transferred_up| resource::CountryStats {
+            country: country.to_owned(),
+            peers,
+            transferred_up,
+        };
+        assert_eq!(stats, vec![stat("DE", 1, 150), stat("US", 2, 0)]);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::ClientPolicy;
+use crate::CONFIG;
+
+/// A BitTorrent client identified by its peer ID.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Client {
+    pub name: &'static str,
+    pub version: String,
+}
+
+/// Identifies the client which generated a peer ID, supporting
+/// Azureus style (-AB1234-) and Mainline style (M1-2-3--) IDs.
+pub fn identify(id: &[u8; 20]) -> Option<Client> {
+    if id[0] == b'-' && id[7] == b'-' {
+        let name = azureus_name(&id[1..3])?;
+        let version = id[3..7]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .map(|&c| (c as char).to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        return Some(Client { name, version });
+    }
+    let name = match id[0] {
+        b'M' => "Mainline",
+        b'Q' => "Queen Bee",
+        _ => return None,
+    };
+    let end = id.windows(2).position(|w| w == b"--")?;
+    let version = &id[1..end];
+    if version.is_empty() || !version.iter().all(|&c| c.is_ascii_digit() || c == b'-') {
+        return None;
+    }
+    Some(Client {
+        name,
+        version: String::from_utf8_lossy(version).replace('-', "."),
+    })
+}
+
+/// Splits a client string sent in an extension handshake, such as
+/// "qBittorrent/4.3.1" or "Transmission 3.00", into its name and version.
+pub fn split_version(v: &str) -> (String, Option<String>) {
+    if let Some(i) = v.rfind([' ', '/']) {
+        let version = v[i + 1..].trim_start_matches('v');
+        if version.starts_with(|c: char| c.is_ascii_digit()) {
+            return (v[..i].trim().to_owned(), Some(version.to_owned()));
+        }
+    }
+    (v.trim().to_owned(), None)
+}
+
+/// The configured policy for a client, if any.
+pub fn policy(client: &Client) -> Option<ClientPolicy> {
+    CONFIG
+        .peer
+        .client_policy
+        .iter()
+        .find(|p| {
+            p.client.eq_ignore_ascii_case(client.name)
+                && p.version
+                    .as_ref()
+                    .map(|v| client.version.starts_with(v.as_str()))
+                    .unwrap_or(true)
+        })
+        .map(|p| p.policy)
+}
+
+fn azureus_name(code: &[u8]) -> Option<&'static str> {
+    Some(match code {
+        b"7T" => "aTorrent",
+        b"AG" | b"A~" => "Ares",
+        b"AZ" => "Vuze",
+        b"BC" => "BitComet",
+        b"BI" => "BiglyBT",
+        b"BN" => "Baidu Netdisk",
+        b"BT" => "BitTorrent",
+        b"DE" => "Deluge",
+        b"FD" => "Free Download Manager",
+        b"FG" => "FlashGet",
+        b"FW" => "FrostWire",
+        b"HL" => "Halite",
+        b"KT" => "KTorrent",
+        b"LT" => "libtorrent",
+        b"lt" => "rTorrent",
+        b"LW" => "LimeWire",
+        b"PI" => "PicoTorrent",
+        b"qB" => "qBittorrent",
+        b"QD" => "QQDownload",
+        b"SD" => "Thunder",
+        b"SY" => "Synapse",
+        b"TL" => "Tribler",
+        b"TR" => "Transmission",
+        b"TT" => "TuoTu",
+        b"UM" => "µTorrent Mac",
+        b"UT" => "µTorrent",
+        b"UW" => "µTorrent Web",
+        b"WW" => "WebTorrent",
+        b"XF" => "Xfplay",
+        b"XL" => "Xunlei",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(prefix: &[u8]) -> [u8; 20] {
+        let mut id = [b'x'; 20];
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    #[test]
+    fn test_identify() {
+        let c = identify(&id(b"-qB4250-")).unwrap();
+        assert_eq!(c.name, "qBittorrent");
+        assert_eq!(c.version, "4.2.5.0");
+
+        let c = identify(&id(b"-UT355W-")).unwrap();
+        assert_eq!(c.name, "µTorrent");
+        assert_eq!(c.version, "3.5.5");
+
+        let c = identify(&id(b"M4-20-8--")).unwrap();
+        assert_eq!(c.name, "Mainline");
+        assert_eq!(c.version, "4.20.8");
+
+        assert_eq!(identify(&id(b"-ZZ1000-")), None);
+        assert_eq!(identify(&[0u8; 20]), None);
+    }
+
+    #[test]
+    fn test_split_version() {
+        assert_eq!(
+            split_version("qBittorrent/4.3.1"),
+            ("qBittorrent".to_owned(), Some("4.3.1".to_owned()))
+        );
+        assert_eq!(
+            split_version("Deluge 2.0.3"),
+            ("Deluge".to_owned(), Some("2.0.3".to_owned()))
+        );
+        assert_eq!(
+            split_version("libtorrent v1.2"),
+            ("libtorrent".to_owned(), Some("1.2".to_owned()))
+        );
+        assert_eq!(
+            split_version("Some Client"),
+            ("Some Client".to_owned(), None)
+        );
+    }
+}
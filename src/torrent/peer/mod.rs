@@ -1,23 +1,31 @@
+pub mod client;
 pub mod reader;
 pub mod writer;
 
-use std::net::SocketAddr;
+use std::collections::VecDeque;
 use std::net::TcpStream;
-use std::{cmp, fmt, io, mem, time};
+use std::net::{IpAddr, SocketAddr};
+use std::{cmp, fmt, io, mem, str, time};
 
+use rand::{self, Rng};
+
+use self::client::Client;
 pub use self::message::Message;
 use self::reader::{RRes, Reader};
-use self::writer::Writer;
-use crate::bencode;
+use self::writer::{FilePiece, RangePiece, Writer};
+use crate::bencode::{self, BEncodeRef};
+use crate::buffers::BUF_SIZE;
+use crate::config::ClientPolicy;
 use crate::control::cio;
+use crate::geoip;
 use crate::rpc::{self, resource};
 use crate::socket::Socket;
 use crate::stat;
 use crate::throttle::Throttle;
-use crate::torrent::{Bitfield, Info, Torrent};
+use crate::torrent::{ext, Bitfield, Info, Torrent, EXT_REQQ};
 use crate::tracker;
-use crate::util;
-use crate::{CONFIG, DHT_EXT, IP_FILTER, PEER_ID};
+use crate::util::{self, FHashMap};
+use crate::{config, CONFIG, DHT_EXT, EXT_PROTO, IP_FILTER, PEER_ID};
 
 error_chain! {
     errors {
@@ -25,12 +33,31 @@ error_chain! {
             description("Peer did not conform to the bittorrent protocol")
                 display("Peer protocol error: {:?}", r)
         }
+
+        ClientRefused(c: String) {
+            description("Peer client refused by policy")
+                display("Peer client {} refused by policy", c)
+        }
+
+        SelfConnection {
+            description("Connected to ourselves")
+                display("Connected to ourselves")
+        }
     }
 }
 
 const INIT_MAX_QUEUE: u16 = 5;
 const MAX_QUEUE_CAP: u16 = 600;
+/// Rate at which the round trip estimate rises towards slower samples
+const RTT_RISE: f64 = 0.01;
+/// Multiple of the bandwidth-delay product kept in flight, so the
+/// pipeline doesn't limit the rate from growing
+const PIPELINE_HEADROOM: f64 = 2.0;
 const IP_FILTER_BLOCK: u8 = 0;
+/// Maximum number of pieces withheld from a lazy bitfield
+const LAZY_PIECES: u64 = 8;
+/// Bytes of a piece message preceding its data
+const PIECE_HEADER: u64 = 13;
 
 pub mod message {
     use crate::buffers;
@@ -54,23 +81,50 @@ pub struct Peer<T: cio::CIO> {
     /// Maximum number of requests that can be queued
     /// at a time.
     max_queue: u16,
+    /// Outstanding block requests by piece index and offset, along
+    /// with when they were sent, oldest first
+    requested: VecDeque<(u32, u32, time::Instant)>,
+    /// Outstanding requests by piece index, offset and length, each
+    /// covering one or more of the requested blocks
+    ranges: Vec<(u32, u32, u32)>,
+    /// Estimated round trip time of requests in milliseconds
+    rtt: Option<f64>,
     pieces_updated: bool,
     tid: usize,
-    downloaded: u32,
-    uploaded: u32,
-    stat: stat::EMA,
+    stat: stat::Rates,
+    /// Piece data and protocol overhead sent and received
+    tx: stat::Transfer,
+    /// Transfer last sent to clients
+    tx_sent: stat::Transfer,
     addr: SocketAddr,
+    /// Country of the peer's address, if a GeoIP database has it
+    country: Option<String>,
     t_hash: [u8; 20],
+    /// Whether our DHT node is advertised to the peer, which it isn't
+    /// if the DHT is disabled or not used by the torrent
+    dht: bool,
     cid: Option<[u8; 20]>,
+    client: Option<Client>,
+    policy: Option<ClientPolicy>,
     rsv: Option<[u8; 8]>,
     ext_ids: ExtIDs,
+    /// Number of extensions the torrent registered, which peers may
+    /// send messages for
+    local_exts: u8,
+    /// Client name sent in the extension handshake
+    ext_client: Option<String>,
+    /// Maximum number of requests the peer will queue
+    reqq: u16,
+    /// Whether the peer won't download, only upload
+    upload_only: bool,
+    /// Requests of the peer being read from disk
+    reading: u16,
+    source: resource::PeerSource,
     pub rank: usize,
 }
 
-pub struct ExtIDs {
-    pub ut_meta: Option<u8>,
-    pub ut_pex: Option<u8>,
-}
+/// IDs the peer assigned to the extensions it supports
+pub struct ExtIDs(FHashMap<String, u8>);
 
 #[derive(Debug)]
 pub struct Status {
@@ -160,13 +214,37 @@ impl PeerConn {
         self.reader.readable(&mut self.sock)
     }
 
-    pub fn write_message(&mut self, msg: Message) -> io::Result<()> {
-        self.writer.write_message(msg, &mut self.sock)
+    /// Queues a message to be written on the next flush.
+    pub fn queue_message(&mut self, msg: Message) {
+        self.writer.queue_message(msg)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush(&mut self.sock)
+    }
+
+    pub fn write_file(&mut self, piece: FilePiece) -> io::Result<()> {
+        self.writer.write_file(piece, &mut self.sock)
+    }
+
+    pub fn write_range(&mut self, piece: RangePiece) -> io::Result<()> {
+        self.writer.write_range(piece, &mut self.sock)
+    }
+
+    pub fn queued_pieces(&self) -> usize {
+        self.writer.queued_pieces()
     }
 
     pub fn set_throttle(&mut self, throt: Throttle) {
         self.sock.throttle = Some(throt);
     }
+
+    /// Writes out any pending messages and shuts down the connection,
+    /// so the peer sees an orderly close rather than a reset.
+    pub fn close(&mut self) {
+        self.writable().ok();
+        self.sock.shutdown();
+    }
 }
 
 impl Status {
@@ -182,8 +260,8 @@ impl Status {
 impl Peer<cio::test::TCIO> {
     pub fn test(
         id: usize,
-        uploaded: u32,
-        downloaded: u32,
+        uploaded: u64,
+        downloaded: u64,
         queued: u16,
         pieces: Bitfield,
     ) -> Peer<cio::test::TCIO> {
@@ -192,21 +270,34 @@ impl Peer<cio::test::TCIO> {
             id,
             remote_status: Status::new(),
             local_status: Status::new(),
-            uploaded,
-            downloaded,
-            stat: stat::EMA::new(),
+            stat: stat::Rates::test(uploaded, downloaded),
+            tx: stat::Transfer::default(),
+            tx_sent: stat::Transfer::default(),
             addr: "127.0.0.1:0".parse().unwrap(),
+            country: None,
             cio: cio::test::TCIO::new(),
             queued,
             max_queue: queued,
+            requested: VecDeque::new(),
+            ranges: Vec::new(),
+            rtt: None,
             pieces,
             piece_cache: Vec::new(),
             piece_count,
             tid: 0,
             t_hash: [0u8; 20],
+            dht: false,
             rsv: None,
             cid: None,
+            client: None,
+            policy: None,
             ext_ids: ExtIDs::new(),
+            local_exts: 2,
+            ext_client: None,
+            reqq: MAX_QUEUE_CAP,
+            upload_only: false,
+            reading: 0,
+            source: resource::PeerSource::Tracker,
             pieces_updated: false,
             rank: 0,
         }
@@ -216,7 +307,7 @@ impl Peer<cio::test::TCIO> {
         Peer::test(id, 0, 0, 0, pieces)
     }
 
-    pub fn test_from_stats(id: usize, ul: u32, dl: u32) -> Peer<cio::test::TCIO> {
+    pub fn test_from_stats(id: usize, ul: u64, dl: u64) -> Peer<cio::test::TCIO> {
         Peer::test(id, ul, dl, 0, Bitfield::new(4))
     }
 
@@ -237,34 +328,57 @@ impl<T: cio::CIO> Peer<T> {
         t: &mut Torrent<T>,
         cid: Option<[u8; 20]>,
         rsv: Option<[u8; 8]>,
+        source: resource::PeerSource,
     ) -> cio::Result<Peer<T>> {
         let throttle = t.get_throttle(0);
         let addr = Peer::setup_conn(&mut t.cio, id, throttle)?;
+        let client = cid.as_ref().and_then(client::identify);
+        let policy = client.as_ref().and_then(client::policy);
         let mut p = Peer {
             id,
             addr,
+            country: geoip::country(addr.ip()),
             remote_status: Status::new(),
             local_status: Status::new(),
-            uploaded: 0,
-            downloaded: 0,
-            stat: stat::EMA::new(),
+            stat: stat::Rates::new(),
+            tx: stat::Transfer::default(),
+            tx_sent: stat::Transfer::default(),
             cio: t.cio.new_handle(),
             queued: 0,
             max_queue: INIT_MAX_QUEUE,
+            requested: VecDeque::new(),
+            ranges: Vec::new(),
+            rtt: None,
             pieces: Bitfield::new(t.info.hashes.len() as u64),
             piece_cache: Vec::new(),
             piece_count: 0,
             tid: t.id,
             t_hash: t.info.hash,
+            dht: t.allows_source(resource::PeerSource::Dht) && CONFIG.dht.bootstrap_node.is_some(),
             rsv,
             cid,
+            client,
+            policy,
             ext_ids: ExtIDs::new(),
+            local_exts: t.exts.count(),
+            ext_client: None,
+            reqq: MAX_QUEUE_CAP,
+            upload_only: false,
+            reading: 0,
+            source,
             pieces_updated: false,
             rank: t.num_peers(),
         };
+        if rsv.is_some() {
+            // The handshake of an incoming peer was read before it was added
+            p.tx.overhead_dl += 68;
+        }
         p.send_message(Message::handshake(&*PEER_ID, &t.info.hash));
+        if let Some(rsv) = rsv {
+            p.send_port(rsv);
+        }
         if t.info.complete() {
-            p.send_message(Message::Bitfield(t.pieces.clone()));
+            p.send_bitfield(&t.pieces, config::settings().lazy_bitfield);
         }
         p.send_rpc_info();
         Ok(p)
@@ -288,6 +402,7 @@ impl<T: cio::CIO> Peer<T> {
         } else if !self.pieces.cap(u64::from(info.pieces())) {
             return Err(ErrorKind::ProtocolError("Invalid pieces size").into());
         }
+        self.dht &= !info.private;
         Ok(())
     }
 
@@ -296,10 +411,51 @@ impl<T: cio::CIO> Peer<T> {
         self.cid.is_some()
     }
 
+    /// Whether or not the peer's client may be uploaded to.
+    pub fn can_upload(&self) -> bool {
+        self.policy != Some(ClientPolicy::NoUpload)
+    }
+
+    /// Starts reading a request of the peer from disk, unless the
+    /// requests being read or queued to be sent already fill the queue
+    /// size we advertised, in which case the request is dropped.
+    pub fn start_read(&mut self) -> bool {
+        let queued = self
+            .cio
+            .get_peer(self.id, |conn| conn.queued_pieces())
+            .unwrap_or(0);
+        if usize::from(self.reading) + queued >= EXT_REQQ as usize {
+            return false;
+        }
+        self.reading += 1;
+        true
+    }
+
+    /// Records that a request of the peer was read, or couldn't be.
+    pub fn finish_read(&mut self) {
+        self.reading = self.reading.saturating_sub(1);
+    }
+
     pub fn exts(&self) -> &ExtIDs {
         &self.ext_ids
     }
 
+    pub fn exts_mut(&mut self) -> &mut ExtIDs {
+        &mut self.ext_ids
+    }
+
+    /// Whether the peer supports the extension protocol
+    pub fn ext_protocol(&self) -> bool {
+        self.rsv
+            .map(|r| (r[EXT_PROTO.0] & EXT_PROTO.1) != 0)
+            .unwrap_or(false)
+    }
+
+    /// Whether the peer announced it won't download any pieces.
+    pub fn upload_only(&self) -> bool {
+        self.upload_only
+    }
+
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
@@ -321,11 +477,13 @@ impl<T: cio::CIO> Peer<T> {
         self.id
     }
 
-    pub fn flush(&mut self) -> (u32, u32) {
-        (
-            mem::replace(&mut self.uploaded, 0),
-            mem::replace(&mut self.downloaded, 0),
-        )
+    /// Where the peer's address was found.
+    pub fn source(&self) -> resource::PeerSource {
+        self.source
+    }
+
+    pub fn country(&self) -> Option<&str> {
+        self.country.as_deref()
     }
 
     pub fn active(&self) -> bool {
@@ -337,17 +495,26 @@ impl<T: cio::CIO> Peer<T> {
         if !self.stat.active() {
             return false;
         }
-        let dl = self.stat.avg_dl();
-        let rate = (dl / 1024) as u16;
-        // Taken from rtorrent's pipeline calculation
-        let nmq = if rate < 20 { rate + 2 } else { rate / 5 + 18 };
+        let dl = self.stat.dl(stat::Window::Second);
+        let nmq = match self.rtt {
+            Some(rtt) => pipeline_depth(dl, rtt),
+            None => {
+                let rate = (dl / 1024) as u16;
+                // Taken from rtorrent's pipeline calculation
+                if rate < 20 {
+                    rate + 2
+                } else {
+                    rate / 5 + 18
+                }
+            }
+        };
         // Clamp between -15 / +50 for queue len changes
         self.max_queue = cmp::min(
             cmp::max(nmq, self.max_queue.saturating_sub(15)),
             self.max_queue + 50,
         );
-        // Keep it under the max cap
-        self.max_queue = cmp::min(self.max_queue, MAX_QUEUE_CAP);
+        // Keep it under the max cap and what the peer will queue
+        self.max_queue = cmp::min(self.max_queue, self.reqq);
         if self.pieces_updated {
             self.pieces_updated = false;
             self.send_rpc_update();
@@ -355,8 +522,25 @@ impl<T: cio::CIO> Peer<T> {
         true
     }
 
+    /// Piece data and protocol overhead transferred with the peer.
+    pub fn transfer(&self) -> stat::Transfer {
+        self.tx
+    }
+
+    /// The peer's transfer, if it changed since it was last taken to be
+    /// sent to clients.
+    pub fn take_transfer(&mut self) -> Option<stat::Transfer> {
+        if self.tx == self.tx_sent {
+            return None;
+        }
+        self.tx_sent = self.tx;
+        Some(self.tx)
+    }
+
+    /// Upload and download rates over the window shown and choked on.
     pub fn get_tx_rates(&self) -> (u64, u64) {
-        (self.stat.avg_ul(), self.stat.avg_dl())
+        let window = stat::Window::TenSeconds;
+        (self.stat.ul(window), self.stat.dl(window))
     }
 
     pub fn queue_reqs(&mut self) -> Option<u16> {
@@ -369,19 +553,39 @@ impl<T: cio::CIO> Peer<T> {
     }
 
     pub fn handle_msg(&mut self, msg: &mut Message) -> Result<()> {
+        self.tx.overhead_dl += msg.overhead() as u64;
         match *msg {
-            Message::Handshake { rsv, id, .. } => {
-                if (rsv[DHT_EXT.0] & DHT_EXT.1) != 0 {
-                    self.send_message(Message::Port(CONFIG.dht.port));
+            Message::Handshake(ref hs) => {
+                let (rsv, id) = (hs.rsv, hs.id);
+                if id == *PEER_ID {
+                    // The address we dialed is one of ours
+                    self.report_ip(self.addr.ip(), None);
+                    return Err(ErrorKind::SelfConnection.into());
+                }
+                self.client = client::identify(&id);
+                self.policy = self.client.as_ref().and_then(client::policy);
+                if self.policy == Some(ClientPolicy::Refuse) {
+                    let name = self.client.as_ref().map(|c| c.name).unwrap_or_default();
+                    return Err(ErrorKind::ClientRefused(name.to_owned()).into());
                 }
+                self.send_port(rsv);
                 self.rsv = Some(rsv);
                 self.cid = Some(id);
                 self.send_rpc_info();
             }
-            Message::Piece { length, .. } => {
+            Message::Piece {
+                index,
+                begin,
+                length,
+                ..
+            } => {
                 self.stat.add_dl(u64::from(length));
-                self.downloaded += 1;
+                self.tx.dl += u64::from(length);
                 self.queued -= 1;
+                if let Some(sent) = self.unrequest(index, begin) {
+                    self.sample_rtt(sent.elapsed());
+                }
+                self.range_done(index, begin);
             }
             Message::Request { .. } => {
                 if self.local_status.choked {
@@ -391,6 +595,9 @@ impl<T: cio::CIO> Peer<T> {
             }
             Message::Choke => {
                 self.remote_status.choked = true;
+                // Outstanding requests are discarded by the peer
+                self.requested.clear();
+                self.ranges.clear();
             }
             Message::Unchoke => {
                 self.remote_status.choked = false;
@@ -420,7 +627,7 @@ impl<T: cio::CIO> Peer<T> {
                 if self.pieces.len() > 0 && !pieces.cap(self.pieces.len()) {
                     return Err(ErrorKind::ProtocolError("Invalid pieces size").into());
                 }
-                mem::swap(pieces, &mut self.pieces);
+                mem::swap(&mut **pieces, &mut self.pieces);
                 self.piece_count = self.pieces.iter().count();
                 self.send_rpc_update();
             }
@@ -438,6 +645,12 @@ impl<T: cio::CIO> Peer<T> {
                         }
                         true
                     });
+                    conn.writer
+                        .file_queue
+                        .retain(|p| !(p.index == index && p.begin == begin));
+                    conn.writer
+                        .range_queue
+                        .retain(|p| !(p.index == index && p.begin == begin));
                 });
             }
             Message::Port(p) => {
@@ -446,39 +659,194 @@ impl<T: cio::CIO> Peer<T> {
                 self.cio.msg_trk(tracker::Request::AddNode(s));
             }
             Message::Extension { id, ref payload } => {
+                if !self.ext_protocol() {
+                    return Err(ErrorKind::ProtocolError("Extension used without support").into());
+                }
+                if id > self.local_exts {
+                    return Err(ErrorKind::ProtocolError("Unknown extension ID used").into());
+                }
                 if id == 0 {
-                    let b = bencode::decode_buf(payload).map_err(|_| {
+                    let b = bencode::decode_ref_with(payload, &ext::LIMITS).map_err(|_| {
                         ErrorKind::ProtocolError("Invalid bencode in ext handshake")
                     })?;
-                    let mut d = b.into_dict().ok_or_else(|| {
+                    let d = b.as_dict().ok_or_else(|| {
                         ErrorKind::ProtocolError("Invalid bencode type in ext handshake")
                     })?;
-                    let mut m = d
-                        .remove(b"m".as_ref())
-                        .and_then(|v| v.into_dict())
+                    let m = d
+                        .get(b"m".as_ref())
+                        .and_then(BEncodeRef::as_dict)
                         .ok_or_else(|| {
                             ErrorKind::ProtocolError("Invalid metadata in in ext handshake")
                         })?;
-                    self.ext_ids.ut_meta = m
-                        .remove(b"ut_metadata".as_ref())
-                        .and_then(|v| v.into_int())
-                        .map(|v| v as u8);
-                    self.ext_ids.ut_pex = m
-                        .remove(b"ut_pex".as_ref())
-                        .and_then(|v| v.into_int())
-                        .map(|v| v as u8);
+                    // An ID of 0 disables the extension
+                    self.ext_ids.0 = m
+                        .iter()
+                        .filter_map(|(name, v)| {
+                            let id = v.as_int().filter(|&v| v > 0 && v <= 255)?;
+                            Some((str::from_utf8(name).ok()?.to_owned(), id as u8))
+                        })
+                        .collect();
+                    if let Some(reqq) = d.get(b"reqq".as_ref()).and_then(BEncodeRef::as_int) {
+                        self.reqq = cmp::min(cmp::max(reqq, 1), i64::from(MAX_QUEUE_CAP)) as u16;
+                        self.max_queue = cmp::min(self.max_queue, self.reqq);
+                    }
+                    self.upload_only = d
+                        .get(b"upload_only".as_ref())
+                        .and_then(BEncodeRef::as_int)
+                        .map(|v| v != 0)
+                        .unwrap_or(false);
+                    if let Some(v) = d.get(b"v".as_ref()).and_then(BEncodeRef::as_bytes) {
+                        self.ext_client = Some(String::from_utf8_lossy(v).into_owned());
+                        if self.client.is_none() {
+                            self.send_rpc_client();
+                        }
+                    }
+                    let port = d.get(b"p".as_ref()).and_then(BEncodeRef::as_int);
+                    let yourip = d
+                        .get(b"yourip".as_ref())
+                        .and_then(BEncodeRef::as_bytes)
+                        .and_then(util::bytes_to_ip);
+                    let ipv4 = d
+                        .get(b"ipv4".as_ref())
+                        .and_then(BEncodeRef::as_bytes)
+                        .and_then(util::bytes_to_ip);
+                    let ipv6 = d
+                        .get(b"ipv6".as_ref())
+                        .and_then(BEncodeRef::as_bytes)
+                        .and_then(util::bytes_to_ip);
+                    debug!(
+                        "Peer {} listens on port {:?}, sees us as {:?}, has addresses {:?} {:?}",
+                        self.addr, port, yourip, ipv4, ipv6
+                    );
+                    if let Some(ip) = yourip {
+                        self.report_ip(ip, Some(self.addr.ip()));
+                    }
+                    let port = port.filter(|&p| p > 0 && p <= 65_535).map(|p| p as u16);
+                    self.report_listen_port(port);
+                    self.report_dual_stack(port, ipv4, ipv6);
                 }
             }
         }
         Ok(())
     }
 
+    /// Passes on our external address as seen by a voter, or
+    /// as known for sure if there is none.
+    fn report_ip(&mut self, ip: IpAddr, voter: Option<IpAddr>) {
+        self.cio
+            .propagate(cio::Event::Tracker(Ok(tracker::Response::ExternalIp {
+                ip,
+                voter,
+            })));
+    }
+
+    /// Passes on the address an incoming peer listens on, so that it can
+    /// be dialed once it disconnects.
+    fn report_listen_port(&mut self, port: Option<u16>) {
+        let port = match port {
+            Some(port) if self.source == resource::PeerSource::Incoming => port,
+            _ => return,
+        };
+        self.cio
+            .propagate(cio::Event::Tracker(Ok(tracker::Response::Listening {
+                tid: self.tid,
+                pid: self.id,
+                addr: SocketAddr::new(self.addr.ip(), port),
+            })));
+    }
+
+    /// Passes on the address of the other IP version a peer has, so that
+    /// both can be raced when reconnecting to it.
+    fn report_dual_stack(&mut self, port: Option<u16>, ipv4: Option<IpAddr>, ipv6: Option<IpAddr>) {
+        // The port of incoming peers isn't the one they listen on
+        let port = match port {
+            _ if self.source != resource::PeerSource::Incoming => self.addr.port(),
+            Some(port) => port,
+            None => return,
+        };
+        let addr = SocketAddr::new(self.addr.ip(), port);
+        let (v4, v6) = match (self.addr.ip(), ipv4, ipv6) {
+            (IpAddr::V4(_), _, Some(ip @ IpAddr::V6(_))) => (addr, SocketAddr::new(ip, port)),
+            (IpAddr::V6(_), Some(ip @ IpAddr::V4(_)), _) => (SocketAddr::new(ip, port), addr),
+            _ => return,
+        };
+        self.cio
+            .propagate(cio::Event::Tracker(Ok(tracker::Response::DualStack {
+                tid: self.tid,
+                v4,
+                v6,
+            })));
+    }
+
+    /// Requests len bytes of a piece, which the reader splits back
+    /// into blocks as they arrive.
     pub fn request_piece(&mut self, idx: u32, offset: u32, len: u32) {
         let m = Message::request(idx, offset, len);
-        self.queued += 1;
+        let blocks = (len as usize).div_ceil(BUF_SIZE);
+        let now = time::Instant::now();
+        self.queued += blocks as u16;
+        self.ranges.push((idx, offset, len));
+        self.requested.extend(
+            (offset..offset + len)
+                .step_by(BUF_SIZE)
+                .map(|begin| (idx, begin, now)),
+        );
         self.send_message(m);
     }
 
+    /// Stops waiting on a block received from another peer. Requests
+    /// must be cancelled exactly as they were sent, so one covering
+    /// several blocks is only cancelled once none of them are wanted.
+    pub fn cancel_block(&mut self, idx: u32, begin: u32) {
+        self.unrequest(idx, begin);
+        if let Some((index, begin, length)) = self.range_done(idx, begin) {
+            self.send_message(Message::Cancel {
+                index,
+                begin,
+                length,
+            });
+        }
+    }
+
+    /// Removes the request covering a block if none of its blocks are
+    /// outstanding anymore, returning it.
+    fn range_done(&mut self, idx: u32, begin: u32) -> Option<(u32, u32, u32)> {
+        let covers = |b: u32, len: u32, o: u32| b <= o && o < b + len;
+        let pos = self
+            .ranges
+            .iter()
+            .position(|&(i, b, len)| i == idx && covers(b, len, begin))?;
+        let (_, b, len) = self.ranges[pos];
+        if self
+            .requested
+            .iter()
+            .any(|&(i, o, _)| i == idx && covers(b, len, o))
+        {
+            return None;
+        }
+        Some(self.ranges.swap_remove(pos))
+    }
+
+    /// Removes an outstanding block request, returning when it was sent.
+    fn unrequest(&mut self, idx: u32, begin: u32) -> Option<time::Instant> {
+        let pos = self
+            .requested
+            .iter()
+            .position(|&(i, b, _)| i == idx && b == begin)?;
+        self.requested.remove(pos).map(|(_, _, sent)| sent)
+    }
+
+    /// Updates the round trip estimate. Since queueing at the peer
+    /// inflates samples, the estimate tracks the minimum, only
+    /// slowly rising when samples are consistently higher.
+    fn sample_rtt(&mut self, elapsed: time::Duration) {
+        let sample = elapsed.as_secs() as f64 * 1000. + f64::from(elapsed.subsec_millis());
+        self.rtt = Some(match self.rtt {
+            Some(rtt) if rtt < sample => rtt + (sample - rtt) * RTT_RISE,
+            _ => sample,
+        });
+    }
+
     pub fn choke(&mut self) {
         if !self.local_status.choked {
             self.local_status.choked = true;
@@ -500,14 +868,90 @@ impl<T: cio::CIO> Peer<T> {
         }
     }
 
+    /// Tells the peer we have a piece, unless it already
+    /// has the piece and haves are being suppressed.
+    pub fn have(&mut self, piece: u32) {
+        if config::settings().suppress_have && self.pieces.has_bit(u64::from(piece)) {
+            return;
+        }
+        self.send_message(Message::Have(piece));
+    }
+
+    /// Sends our pieces to the peer. Lazy bitfields withhold some
+    /// random pieces, which are then sent as haves.
+    pub fn send_bitfield(&mut self, pieces: &Bitfield, lazy: bool) {
+        let mut pieces = pieces.clone();
+        let mut withheld = Vec::new();
+        if lazy && pieces.len() > 0 {
+            let mut rng = rand::thread_rng();
+            for _ in 0..cmp::min(LAZY_PIECES, pieces.len()) {
+                let i = rng.gen_range(0, pieces.len());
+                if pieces.has_bit(i) {
+                    pieces.unset_bit(i);
+                    withheld.push(i as u32);
+                }
+            }
+        }
+        self.send_message(Message::Bitfield(Box::new(pieces)));
+        for i in withheld {
+            self.send_message(Message::Have(i));
+        }
+    }
+
+    /// Tells the peer our DHT port once its handshake is received, if it
+    /// supports the DHT.
+    fn send_port(&mut self, rsv: [u8; 8]) {
+        if self.dht && (rsv[DHT_EXT.0] & DHT_EXT.1) != 0 {
+            self.send_message(Message::Port(CONFIG.dht.port));
+        }
+    }
+
     pub fn send_message(&mut self, msg: Message) {
-        if let Message::Piece { length, .. } = msg {
-            self.uploaded += 1;
-            self.stat.add_ul(u64::from(length));
+        match msg {
+            Message::Piece { length, .. } => {
+                self.stat.add_ul(u64::from(length));
+                self.tx.ul += u64::from(length);
+            }
+            // Cancelled blocks may never arrive, so they're no longer timed
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                for b in (begin..begin + length).step_by(BUF_SIZE) {
+                    self.unrequest(index, b);
+                }
+            }
+            _ => {}
         }
+        self.tx.overhead_ul += msg.overhead() as u64;
         self.cio.msg_peer(self.id, msg);
     }
 
+    /// Sends a piece message whose block is sent straight from its file.
+    pub fn send_file(&mut self, piece: FilePiece) {
+        self.stat.add_ul(u64::from(piece.length));
+        self.tx.ul += u64::from(piece.length);
+        self.tx.overhead_ul += PIECE_HEADER;
+        let res = self.cio.get_peer(self.id, |conn| conn.write_file(piece));
+        if let Some(Err(e)) = res {
+            debug!("Failed to send piece to peer: {}", e);
+            self.cio.remove_peer(self.id);
+        }
+    }
+
+    /// Sends a piece message answering a request longer than a block.
+    pub fn send_range(&mut self, piece: RangePiece) {
+        self.stat.add_ul(piece.data.len() as u64);
+        self.tx.ul += piece.data.len() as u64;
+        self.tx.overhead_ul += PIECE_HEADER;
+        let res = self.cio.get_peer(self.id, |conn| conn.write_range(piece));
+        if let Some(Err(e)) = res {
+            debug!("Failed to send piece to peer: {}", e);
+            self.cio.remove_peer(self.id);
+        }
+    }
+
     fn send_rpc_info(&mut self) {
         if let Some(cid) = self.cid {
             let id = util::peer_rpc_id(&self.t_hash, self.id as u64);
@@ -517,7 +961,11 @@ impl<T: cio::CIO> Peer<T> {
                         id,
                         torrent_id: util::hash_to_id(&self.t_hash[..]),
                         client_id: util::hash_to_id(&cid[..]),
+                        client_name: self.client.as_ref().map(|c| c.name.to_owned()),
+                        client_version: self.client.as_ref().map(|c| c.version.clone()),
                         ip: self.addr.to_string(),
+                        country: self.country.clone(),
+                        source: self.source,
                         rate_up: 0,
                         rate_down: 0,
                         availability: self.piece_count as f32 / self.pieces.len() as f32,
@@ -540,6 +988,23 @@ impl<T: cio::CIO> Peer<T> {
         }
     }
 
+    /// Names the peer's client from its extension handshake, for
+    /// clients which couldn't be identified from their peer ID.
+    fn send_rpc_client(&mut self) {
+        if let (Some(_), Some(v)) = (self.cid, self.ext_client.as_ref()) {
+            let id = util::peer_rpc_id(&self.t_hash, self.id as u64);
+            let (client_name, client_version) = client::split_version(v);
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                resource::SResourceUpdate::PeerClient {
+                    id,
+                    kind: resource::ResourceKind::Peer,
+                    client_name: Some(client_name),
+                    client_version,
+                },
+            ]));
+        }
+    }
+
     pub fn send_rpc_removal(&mut self) {
         if self.ready() {
             self.cio
@@ -551,6 +1016,15 @@ impl<T: cio::CIO> Peer<T> {
     }
 }
 
+/// Number of block requests needed to cover the bandwidth-delay
+/// product of a connection, given its rate in bytes/sec and round
+/// trip time in milliseconds.
+fn pipeline_depth(rate: u64, rtt: f64) -> u16 {
+    let bdp = rate as f64 * rtt / 1000. * PIPELINE_HEADROOM;
+    let depth = (bdp / BUF_SIZE as f64).ceil() as u16;
+    cmp::max(depth, INIT_MAX_QUEUE)
+}
+
 impl<T: cio::CIO> Drop for Peer<T> {
     fn drop(&mut self) {
         self.send_rpc_removal();
@@ -569,19 +1043,34 @@ impl<T: cio::CIO> fmt::Debug for Peer<T> {
 
 impl ExtIDs {
     fn new() -> ExtIDs {
-        ExtIDs {
-            ut_meta: None,
-            ut_pex: None,
-        }
+        ExtIDs(FHashMap::default())
+    }
+
+    pub fn get(&self, name: &str) -> Option<u8> {
+        self.0.get(name).cloned()
+    }
+
+    #[cfg(test)]
+    pub fn insert(&mut self, name: &str, id: u8) {
+        self.0.insert(name.to_owned(), id);
+    }
+
+    /// Forgets the IDs of extensions which aren't kept.
+    pub fn retain<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        self.0.retain(|name, _| keep(name));
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Peer;
-    use crate::buffers::Buffer;
+    use super::{pipeline_depth, Peer, INIT_MAX_QUEUE};
+    use crate::bencode::BEncode;
+    use crate::buffers::{Buffer, BUF_SIZE};
     use crate::control::cio::{test, CIO};
-    use crate::torrent::Message;
+    use crate::torrent::{Bitfield, Message, EXT_REQQ};
+    use crate::{CONFIG, DHT_EXT, EXT_PROTO};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
 
     #[test]
     fn test_cancel() {
@@ -619,8 +1108,201 @@ mod tests {
         let wq = tcio
             .get_peer(peer.id, |p| p.writer.write_queue.clone())
             .unwrap();
-        assert_eq!(wq.len(), 2);
-        assert_eq!(wq[0], p1);
-        assert_eq!(wq[1], p3);
+        assert_eq!(wq.len(), 3);
+        assert_eq!(wq[0], Message::KeepAlive);
+        assert_eq!(wq[1], p1);
+        assert_eq!(wq[2], p3);
+    }
+
+    #[test]
+    fn test_message_size() {
+        assert_eq!(std::mem::size_of::<Message>(), 24);
+    }
+
+    #[test]
+    fn test_have_suppression() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.pieces.set_bit(0);
+        peer.have(0);
+        peer.have(1);
+        let wq: Vec<_> = tcio.peer_msgs().into_iter().map(|(_, m)| m).collect();
+        assert_eq!(wq.len(), 1);
+        assert_eq!(wq[0], Message::Have(1));
+    }
+
+    #[test]
+    fn test_lazy_bitfield() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let mut pieces = Bitfield::new(64);
+        for i in 0..64 {
+            pieces.set_bit(i);
+        }
+
+        peer.send_bitfield(&pieces, false);
+        let wq: Vec<_> = tcio.peer_msgs().into_iter().map(|(_, m)| m).collect();
+        assert_eq!(wq.len(), 1);
+        match wq[0] {
+            Message::Bitfield(ref b) => assert!(b.complete()),
+            _ => panic!("Expected bitfield"),
+        }
+
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.send_bitfield(&pieces, true);
+        let wq: Vec<_> = tcio.peer_msgs().into_iter().map(|(_, m)| m).collect();
+        let sent = match wq[0] {
+            Message::Bitfield(ref b) => b.clone(),
+            _ => panic!("Expected bitfield first"),
+        };
+        let withheld = 64 - sent.iter().count();
+        assert!(withheld > 0);
+        assert_eq!(wq.len(), 1 + withheld);
+        for m in wq.iter().skip(1) {
+            match *m {
+                Message::Have(i) => assert!(!sent.has_bit(u64::from(i))),
+                _ => panic!("Expected haves after bitfield"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pipeline_depth() {
+        // 1 MiB/s at 100ms is ~6.4 blocks in flight, doubled for headroom
+        assert_eq!(pipeline_depth(1024 * 1024, 100.), 13);
+        assert_eq!(pipeline_depth(10 * 1024 * 1024, 100.), 128);
+        assert_eq!(pipeline_depth(1024, 100.), INIT_MAX_QUEUE);
+    }
+
+    #[test]
+    fn test_rtt_estimate() {
+        let mut peer = Peer::test_from_stats(0, 0, 0);
+        peer.sample_rtt(Duration::from_millis(100));
+        assert_eq!(peer.rtt, Some(100.));
+        // Queueing delays only slowly raise the estimate
+        peer.sample_rtt(Duration::from_millis(1100));
+        assert_eq!(peer.rtt, Some(110.));
+        peer.sample_rtt(Duration::from_millis(50));
+        assert_eq!(peer.rtt, Some(50.));
+    }
+
+    #[test]
+    fn test_requested_blocks() {
+        let mut peer = Peer::test_from_stats(0, 0, 0);
+        peer.request_piece(1, 0, 2 * BUF_SIZE as u32);
+        assert_eq!(peer.requested.len(), 2);
+        // Cancelled blocks are no longer timed
+        peer.send_message(Message::Cancel {
+            index: 1,
+            begin: 0,
+            length: 2 * BUF_SIZE as u32,
+        });
+        assert!(peer.requested.is_empty());
+        assert_eq!(peer.unrequest(1, 0), None);
+    }
+
+    #[test]
+    fn test_cancel_block() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let len = 2 * BUF_SIZE as u32;
+        peer.request_piece(1, 0, len);
+        let cancels = || {
+            tcio.peer_msgs()
+                .into_iter()
+                .filter(|(_, m)| matches!(m, Message::Cancel { .. }))
+                .map(|(_, m)| m)
+                .collect::<Vec<_>>()
+        };
+        // The request still has a wanted block, so it isn't cancelled
+        peer.cancel_block(1, BUF_SIZE as u32);
+        assert!(cancels().is_empty());
+        peer.cancel_block(1, 0);
+        let cancel = Message::Cancel {
+            index: 1,
+            begin: 0,
+            length: len,
+        };
+        assert_eq!(cancels(), vec![cancel]);
+        assert!(peer.requested.is_empty());
+        assert!(peer.ranges.is_empty());
+    }
+
+    #[test]
+    fn test_send_port() {
+        let mut rsv = [0u8; 8];
+        rsv[DHT_EXT.0] |= DHT_EXT.1;
+
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.send_port([0u8; 8]);
+        peer.send_port(rsv);
+        assert!(tcio.peer_msgs().is_empty());
+
+        peer.dht = true;
+        peer.send_port([0u8; 8]);
+        peer.send_port(rsv);
+        let wq: Vec<_> = tcio.peer_msgs().into_iter().map(|(_, m)| m).collect();
+        assert_eq!(wq, vec![Message::Port(CONFIG.dht.port)]);
+    }
+
+    #[test]
+    fn test_read_limit() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        for _ in 0..EXT_REQQ {
+            assert!(peer.start_read());
+        }
+        assert!(!peer.start_read());
+        peer.finish_read();
+        assert!(peer.start_read());
+    }
+
+    #[test]
+    fn test_transfer() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.handle_msg(&mut Message::Interested).unwrap();
+        peer.send_message(Message::Unchoke);
+        peer.send_message(Message::Have(1));
+        let tx = peer.transfer();
+        assert_eq!((tx.ul, tx.dl), (0, 0));
+        assert_eq!((tx.overhead_ul, tx.overhead_dl), (14, 5));
+    }
+
+    #[test]
+    fn test_ext_handshake() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let mut rsv = [0; 8];
+        rsv[EXT_PROTO.0] |= EXT_PROTO.1;
+        let ext = |payload: Vec<u8>| Message::Extension {
+            id: 0,
+            payload: payload.into(),
+        };
+        // Extensions may only be used once the handshake advertised them
+        assert!(peer.handle_msg(&mut ext(Vec::new())).is_err());
+        peer.rsv = Some(rsv);
+        let mut m = BTreeMap::new();
+        m.insert(b"ut_pex".to_vec(), BEncode::Int(2));
+        m.insert(b"ut_metadata".to_vec(), BEncode::Int(300));
+        let mut d = BTreeMap::new();
+        d.insert(b"m".to_vec(), BEncode::Dict(m));
+        d.insert(b"reqq".to_vec(), BEncode::Int(10));
+        d.insert(b"upload_only".to_vec(), BEncode::Int(1));
+        d.insert(b"v".to_vec(), BEncode::String(b"Foo 1.0".to_vec()));
+        let payload = BEncode::Dict(d).encode_to_buf();
+        peer.handle_msg(&mut ext(payload)).unwrap();
+        assert_eq!(peer.exts().get("ut_pex"), Some(2));
+        assert_eq!(peer.exts().get("ut_metadata"), None);
+        assert_eq!(peer.reqq, 10);
+        assert!(peer.upload_only());
+        assert_eq!(peer.ext_client.as_deref(), Some("Foo 1.0"));
+        let mut unknown = Message::Extension {
+            id: 42,
+            payload: Vec::new().into(),
+        };
+        assert!(peer.handle_msg(&mut unknown).is_err());
     }
 }
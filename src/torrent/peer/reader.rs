@@ -4,11 +4,16 @@ use std::mem;
 use byteorder::{BigEndian, ByteOrder};
 
 use crate::buffers::{Buffer, BUF_SIZE};
+use crate::protocol::Handshake;
 use crate::torrent::peer::Message;
 use crate::torrent::Bitfield;
 use crate::util::{aread, io_err_val, IOR};
 
-const MAX_EXT_MSG_BYTES: u32 = 100 * 1000 * 1000;
+/// Longest extension message accepted, well above the largest metadata
+/// block or PEX message
+const MAX_EXT_MSG_BYTES: u32 = 1024 * 1024;
+/// Longest piece message accepted, the most the request size may be
+const MAX_PIECE_BYTES: u32 = 131_072;
 
 pub struct Reader {
     state: State,
@@ -25,7 +30,8 @@ enum State {
     Port,
     Handshake { data: [u8; 68] },
     PiecePrefix,
-    Piece { data: Option<Buffer>, len: u32 },
+    /// A block of a piece, with rest bytes of the piece following it
+    Piece { data: Option<Buffer>, len: u32, rest: u32 },
     Bitfield { data: Vec<u8> },
     ExtensionID,
     Extension { id: u8, payload: Vec<u8> },
@@ -61,7 +67,15 @@ impl Reader {
     pub fn readable<R: Read>(&mut self, conn: &mut R) -> RRes {
         let res = self.readable_(conn);
         if let RRes::Success(_) = &res {
-            self.state = State::Len;
+            self.state = match self.state {
+                // Pieces longer than a block are returned a block at a time
+                State::Piece { rest, .. } if rest > 0 => {
+                    let begin = BigEndian::read_u32(&self.prefix[9..13]);
+                    BigEndian::write_u32(&mut self.prefix[9..13], begin + BUF_SIZE as u32);
+                    State::piece(rest)
+                }
+                _ => State::Len,
+            };
             self.idx = 0;
         }
         res
@@ -73,7 +87,7 @@ impl Reader {
             match self.state {
                 State::Handshake { ref mut data } => match aread(&mut data[self.idx..len], conn) {
                     IOR::Complete => {
-                        if &data[1..20] != b"BitTorrent protocol" {
+                        if data[0] != 19 || &data[1..20] != b"BitTorrent protocol" {
                             return RRes::Err(io_err_val(
                                 "Handshake was not for 'BitTorrent protocol'",
                             ));
@@ -85,7 +99,11 @@ impl Reader {
                         let mut id = [0; 20];
                         id.clone_from_slice(&data[48..68]);
 
-                        return RRes::Success(Message::Handshake { rsv, hash, id });
+                        return RRes::Success(Message::Handshake(Box::new(Handshake {
+                            rsv,
+                            hash,
+                            id,
+                        })));
                     }
                     IOR::Incomplete(a) => self.idx += a,
                     IOR::Blocked => return RRes::Blocked,
@@ -110,6 +128,13 @@ impl Reader {
                 State::ID => match aread(&mut self.prefix[self.idx..len], conn) {
                     IOR::Complete => {
                         self.idx = 5;
+                        let mlen = BigEndian::read_u32(&self.prefix[0..4]);
+                        if !valid_len(self.prefix[4], mlen) {
+                            return RRes::Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("Invalid length {} of message {}", mlen, self.prefix[4]),
+                            ));
+                        }
                         match self.prefix[4] {
                             0..=3 => {
                                 let id = self.prefix[4];
@@ -126,14 +151,8 @@ impl Reader {
                             }
                             4 => self.state = State::Have,
                             5 => {
-                                let mlen = BigEndian::read_u32(&self.prefix[0..4]);
-                                if mlen as usize > BUF_SIZE {
-                                    // we'll check the exact length later
-                                    return RRes::Err(io::Error::new(
-                                        io::ErrorKind::Other,
-                                        format!("Invalid bitfield length {}", mlen),
-                                    ));
-                                }
+                                // The exact length is checked against the
+                                // piece count by the peer
                                 self.idx = 0;
                                 self.state = State::Bitfield {
                                     data: vec![0u8; mlen as usize - 1],
@@ -166,7 +185,7 @@ impl Reader {
                     IOR::Complete => {
                         let d = mem::replace(data, vec![]).into_boxed_slice();
                         let bf = Bitfield::from(&d, len as u64 * 8);
-                        return RRes::Success(Message::Bitfield(bf));
+                        return RRes::Success(Message::Bitfield(Box::new(bf)));
                     }
                     IOR::Incomplete(a) => self.idx += a,
                     IOR::Blocked => return RRes::Blocked,
@@ -192,17 +211,8 @@ impl Reader {
                 State::PiecePrefix => match aread(&mut self.prefix[self.idx..len], conn) {
                     IOR::Complete => {
                         let plen = BigEndian::read_u32(&self.prefix[0..4]) - 9;
-                        if plen as usize > BUF_SIZE {
-                            return RRes::Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("Invalid pieces length {}", plen),
-                            ));
-                        }
                         self.idx = 0;
-                        self.state = State::Piece {
-                            data: Buffer::get(),
-                            len: plen,
-                        };
+                        self.state = State::piece(plen);
                     }
                     IOR::Incomplete(a) => self.idx += a,
                     IOR::Blocked => return RRes::Blocked,
@@ -212,6 +222,7 @@ impl Reader {
                 State::Piece {
                     ref mut data,
                     len: length,
+                    ..
                 } => {
                     if data.is_none() {
                         if let Some(buf) = Buffer::get() {
@@ -268,9 +279,6 @@ impl Reader {
                         let id = self.prefix[5];
                         self.idx = 0;
                         let plen = BigEndian::read_u32(&self.prefix[0..4]) - 2;
-                        if plen > MAX_EXT_MSG_BYTES {
-                            return RRes::Err(io_err_val("Ext message too large"));
-                        }
                         let payload = vec![0u8; plen as usize];
                         self.state = State::Extension { id, payload };
                     }
@@ -285,7 +293,10 @@ impl Reader {
                 } => match aread(&mut payload[self.idx..len], conn) {
                     IOR::Complete => {
                         let p = mem::replace(payload, Vec::with_capacity(0));
-                        return RRes::Success(Message::Extension { id, payload: p });
+                        return RRes::Success(Message::Extension {
+                            id,
+                            payload: p.into(),
+                        });
                     }
                     IOR::Incomplete(a) => self.idx += a,
                     IOR::Blocked => return RRes::Blocked,
@@ -297,7 +308,32 @@ impl Reader {
     }
 }
 
+/// Whether a message of an id may have a length prefix, so a malformed
+/// or oversized message is rejected before anything is allocated for it.
+fn valid_len(id: u8, len: u32) -> bool {
+    match id {
+        0..=3 => len == 1,
+        4 => len == 5,
+        5 => len >= 1 && len as usize <= BUF_SIZE + 1,
+        6 | 8 => len == 13,
+        7 => (9..=MAX_PIECE_BYTES + 9).contains(&len),
+        9 => len == 3,
+        20 => (2..=MAX_EXT_MSG_BYTES + 2).contains(&len),
+        _ => true,
+    }
+}
+
 impl State {
+    /// Reads the first block of len bytes of a piece.
+    fn piece(len: u32) -> State {
+        let block = len.min(BUF_SIZE as u32);
+        State::Piece {
+            data: Buffer::get(),
+            len: block,
+            rest: len - block,
+        }
+    }
+
     fn len(&self) -> usize {
         match *self {
             State::Len => 4,
@@ -319,6 +355,8 @@ impl State {
 mod tests {
     use super::*;
     use crate::torrent::peer::Message;
+    use rand::prng::XorShiftRng;
+    use rand::{Rng, SeedableRng};
     use std::io::{self, Read};
 
     /// Cursor to emulate a mio socket using readv.
@@ -482,6 +520,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_read_long_piece() {
+        let mut r = Reader::new();
+        r.state = State::Len;
+        let mut v = vec![0u8, 0, 0x80, 0x09, 7, 0, 0, 0, 1, 0, 0, 0x40, 0];
+        v.extend(vec![1u8; 16_384]);
+        v.extend(vec![2u8; 16_384]);
+        v.extend(vec![0u8, 0, 0, 5, 4, 0, 0, 0, 1]);
+        let mut data = Cursor::new(&v);
+        // Pieces longer than a block are split into blocks
+        for (i, begin) in [(1, 16_384), (2, 32_768)] {
+            match r.readable(&mut data) {
+                RRes::Success(Message::Piece {
+                    index: 1,
+                    begin: b,
+                    length: 16_384,
+                    ref data,
+                }) if b == begin => assert!(data.iter().all(|d| *d == i)),
+                res => panic!("Failed to get piece: {:?}", res),
+            }
+        }
+        assert_eq!(r.readable(&mut data).unwrap().unwrap(), Message::Have(1));
+    }
+
     #[test]
     fn test_read_cancel() {
         let mut r = Reader::new();
@@ -515,16 +577,89 @@ mod tests {
 
     #[test]
     fn test_read_handshake() {
+        use crate::protocol::Handshake;
         use crate::PEER_ID;
         let mut r = Reader::new();
-        let m = Message::Handshake {
+        let m = Message::Handshake(Box::new(Handshake {
             rsv: [0; 8],
             hash: [0; 20],
             id: *PEER_ID,
-        };
+        }));
         let mut data = vec![0; 68];
         m.encode(&mut data[..]).unwrap();
         let mut c = Cursor::new(&data);
         assert_eq!(r.readable(&mut c).unwrap().unwrap(), m);
     }
+
+    #[test]
+    fn test_read_invalid_len() {
+        let invalid = vec![
+            vec![0u8, 0, 0, 2, 0, 0],
+            vec![0u8, 0, 0, 4, 4, 0, 0, 0],
+            vec![0u8, 0, 0, 12, 6, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0],
+            vec![0u8, 0, 0, 8, 7, 0, 0, 0, 1, 0, 0, 0],
+            vec![0u8, 0x02, 0, 0x0A, 7, 0, 0, 0, 1, 0, 0, 0, 0],
+            vec![0u8, 0, 0, 1, 20],
+            vec![0xffu8, 0xff, 0xff, 0xff, 20, 0],
+            vec![0u8, 0x10, 0, 0, 5],
+        ];
+        for v in invalid {
+            let mut r = Reader::new();
+            r.state = State::Len;
+            match r.readable(&mut Cursor::new(&v)) {
+                RRes::Err(_) => {}
+                res => panic!("Accepted {:?}: {:?}", v, res),
+            }
+        }
+        let mut r = Reader::new();
+        let mut data = vec![0; 68];
+        data[0] = 18;
+        data[1..20].copy_from_slice(b"BitTorrent protocol");
+        assert!(matches!(r.readable(&mut Cursor::new(&data)), RRes::Err(_)));
+    }
+
+    /// Feeds streams of messages with random ids, length prefixes and
+    /// contents, split at random points, which must be read or rejected
+    /// without panicking.
+    #[test]
+    fn test_read_fuzz() {
+        let mut rng = XorShiftRng::from_seed(*b"synapse peer msg");
+        let mut rand = move |n: u64| rng.gen_range(0, n);
+        for _ in 0..2000 {
+            let mut v = Vec::new();
+            for _ in 0..rand(8) {
+                let len = match rand(4) {
+                    0 => rand(u64::from(u32::MAX)) as u32,
+                    1 => MAX_PIECE_BYTES + 9 - rand(2) as u32,
+                    _ => rand(24) as u32,
+                };
+                let mut prefix = [0u8; 4];
+                BigEndian::write_u32(&mut prefix, len);
+                v.extend_from_slice(&prefix);
+                let id = match rand(3) {
+                    0 => rand(256) as u8,
+                    _ => [0, 4, 5, 6, 7, 9, 20][rand(7) as usize],
+                };
+                v.push(id);
+                v.extend((0..rand(40)).map(|_| rand(256) as u8));
+            }
+            let mut r = Reader::new();
+            if rand(4) != 0 {
+                r.state = State::Len;
+            }
+            let mut start = 0;
+            'read: while start < v.len() {
+                let end = (start + 1 + rand(32) as usize).min(v.len());
+                let mut c = Cursor::new(&v[start..end]);
+                loop {
+                    match r.readable(&mut c) {
+                        RRes::Success(_) => {}
+                        RRes::Blocked => break,
+                        RRes::Err(_) | RRes::Stalled => break 'read,
+                    }
+                }
+                start = end;
+            }
+        }
+    }
 }
@@ -1,15 +1,27 @@
 use std::collections::VecDeque;
-use std::io::{self, ErrorKind, Write};
+use std::fs::File;
+use std::io::{self, ErrorKind, IoSlice, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
 
-use crate::buffers::Buffer;
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::buffers::{Buffer, PieceBuf, BUF_SIZE};
 use crate::torrent::peer::Message;
 use crate::util::io_err;
 
+/// Maximum number of small messages written with one vectored write
+const MAX_BATCH: usize = 64;
+
 pub struct Writer {
     // Needed so that the peer can filter out cancel'd messages.
     // The state of this isn't critical to any invariants of the Writer
     // so it shouldn't be an issue
     pub write_queue: VecDeque<Message>,
+    /// Blocks to be sent from their files once other messages are written
+    pub file_queue: VecDeque<FilePiece>,
+    /// Requests longer than a block, sent once other messages are written
+    pub range_queue: VecDeque<RangePiece>,
     blocks_written: usize,
     writable: bool,
     state: WriteState,
@@ -17,10 +29,11 @@ pub struct Writer {
 
 enum WriteState {
     Idle,
-    WritingMsg {
-        data: [u8; 17],
-        len: u8,
-        idx: u8,
+    /// Small messages written together with a vectored write
+    WritingMsgs {
+        msgs: Vec<([u8; 17], u8)>,
+        len: usize,
+        idx: usize,
     },
     WritingOther {
         data: Vec<u8>,
@@ -29,8 +42,52 @@ enum WriteState {
     WritingPiece {
         prefix: [u8; 17],
         data: Buffer,
+        len: u16,
         idx: u16,
     },
+    SendingFile {
+        prefix: [u8; 13],
+        piece: FilePiece,
+        idx: u32,
+    },
+    WritingRange {
+        prefix: [u8; 13],
+        piece: RangePiece,
+        idx: usize,
+    },
+}
+
+/// A piece message whose block is sent straight from the file holding it.
+#[derive(Clone)]
+pub struct FilePiece {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+    pub file: Arc<File>,
+    pub offset: u64,
+}
+
+/// A piece message answering a request longer than a block, whose data
+/// was read into a piece buffer.
+pub struct RangePiece {
+    pub index: u32,
+    pub begin: u32,
+    pub data: PieceBuf,
+}
+
+/// A connection which can be written to from a file.
+pub trait FileWrite: Write {
+    /// Writes up to len bytes of file starting at offset, returning how
+    /// many were written. By default this reads the range and writes it.
+    fn send_file(&mut self, file: &File, offset: u64, len: usize) -> io::Result<usize> {
+        let mut buf = [0; BUF_SIZE];
+        let len = len.min(BUF_SIZE);
+        let amnt = file.read_at(&mut buf[..len], offset)?;
+        if amnt == 0 {
+            return Ok(0);
+        }
+        self.write(&buf[..amnt])
+    }
 }
 
 impl Writer {
@@ -38,22 +95,36 @@ impl Writer {
         Writer {
             writable: true,
             write_queue: VecDeque::new(),
+            file_queue: VecDeque::new(),
+            range_queue: VecDeque::new(),
             state: WriteState::Idle,
             blocks_written: 0,
         }
     }
 
-    pub fn writable<W: Write>(&mut self, conn: &mut W) -> io::Result<()> {
+    pub fn writable<W: FileWrite>(&mut self, conn: &mut W) -> io::Result<()> {
         self.writable = true;
         self.write(conn)
     }
 
-    pub fn write_message<W: Write>(&mut self, msg: Message, conn: &mut W) -> io::Result<()> {
-        if let WriteState::Idle = self.state {
-            self.setup_write(msg);
-        } else {
-            self.write_queue.push_back(msg);
-        }
+    /// Number of piece messages queued to be written.
+    pub fn queued_pieces(&self) -> usize {
+        let msgs = self
+            .write_queue
+            .iter()
+            .filter(|m| matches!(m, Message::Piece { .. }))
+            .count();
+        msgs + self.file_queue.len() + self.range_queue.len()
+    }
+
+    /// Queues a message without writing it, so that it may be written
+    /// along with others on the next flush.
+    pub fn queue_message(&mut self, msg: Message) {
+        self.write_queue.push_back(msg);
+    }
+
+    /// Writes queued messages, if the connection is writable.
+    pub fn flush<W: FileWrite>(&mut self, conn: &mut W) -> io::Result<()> {
         if self.writable {
             self.write(conn)
         } else {
@@ -61,6 +132,45 @@ impl Writer {
         }
     }
 
+    pub fn write_file<W: FileWrite>(&mut self, piece: FilePiece, conn: &mut W) -> io::Result<()> {
+        self.file_queue.push_back(piece);
+        self.flush(conn)
+    }
+
+    pub fn write_range<W: FileWrite>(&mut self, piece: RangePiece, conn: &mut W) -> io::Result<()> {
+        self.range_queue.push_back(piece);
+        self.flush(conn)
+    }
+
+    /// Sets up the next queued write, returning false if there is none.
+    fn next(&mut self) -> bool {
+        if let Some(msg) = self.write_queue.pop_front() {
+            self.setup_write(msg);
+        } else if let Some(piece) = self.file_queue.pop_front() {
+            self.setup_file(piece);
+        } else if let Some(piece) = self.range_queue.pop_front() {
+            let prefix = piece_prefix(piece.index, piece.begin, piece.data.len() as u32);
+            self.state = WriteState::WritingRange {
+                prefix,
+                piece,
+                idx: 0,
+            };
+        } else {
+            self.state = WriteState::Idle;
+            return false;
+        }
+        true
+    }
+
+    fn setup_file(&mut self, piece: FilePiece) {
+        let prefix = piece_prefix(piece.index, piece.begin, piece.length);
+        self.state = WriteState::SendingFile {
+            prefix,
+            piece,
+            idx: 0,
+        };
+    }
+
     fn setup_write(&mut self, msg: Message) {
         self.state = if !msg.is_special() {
             let mut buf = [0; 17];
@@ -68,16 +178,26 @@ impl Writer {
             // Should never go wrong
             msg.encode(&mut buf).unwrap();
             match msg {
-                Message::Piece { data, .. } => WriteState::WritingPiece {
+                Message::Piece { data, length, .. } => WriteState::WritingPiece {
                     prefix: buf,
                     data,
+                    len: length as u16,
                     idx: 0,
                 },
-                _ => WriteState::WritingMsg {
-                    data: buf,
-                    len: len as u8,
-                    idx: 0,
-                },
+                _ => {
+                    let mut msgs = vec![(buf, len as u8)];
+                    let mut len = len;
+                    while msgs.len() < MAX_BATCH
+                        && self.write_queue.front().map(is_small).unwrap_or(false)
+                    {
+                        let msg = self.write_queue.pop_front().unwrap();
+                        let mut buf = [0; 17];
+                        msg.encode(&mut buf).unwrap();
+                        msgs.push((buf, msg.len() as u8));
+                        len += msg.len();
+                    }
+                    WriteState::WritingMsgs { msgs, len, idx: 0 }
+                }
             }
         } else {
             // TODO: Acquire from buffer
@@ -88,17 +208,16 @@ impl Writer {
         };
     }
 
-    fn write<W: Write>(&mut self, conn: &mut W) -> io::Result<()> {
+    fn write<W: FileWrite>(&mut self, conn: &mut W) -> io::Result<()> {
         if let WriteState::Idle = self.state {
-            return Ok(());
+            if !self.next() {
+                return Ok(());
+            }
         }
         loop {
             match self.write_(conn) {
                 Ok(true) => {
-                    if let Some(msg) = self.write_queue.pop_back() {
-                        self.setup_write(msg);
-                    } else {
-                        self.state = WriteState::Idle;
+                    if !self.next() {
                         break;
                     }
                 }
@@ -118,20 +237,33 @@ impl Writer {
         Ok(())
     }
 
-    fn write_<W: Write>(&mut self, conn: &mut W) -> io::Result<bool> {
+    fn write_<W: FileWrite>(&mut self, conn: &mut W) -> io::Result<bool> {
         match self.state {
             WriteState::Idle => Ok(false),
-            WriteState::WritingMsg {
-                ref data,
-                ref len,
+            WriteState::WritingMsgs {
+                ref msgs,
+                len,
                 ref mut idx,
             } => {
-                let amnt = conn.write(&data[(*idx as usize)..(*len as usize)])?;
+                let mut slices = [IoSlice::new(&[]); MAX_BATCH];
+                let mut n = 0;
+                let mut skip = *idx;
+                for (data, l) in msgs {
+                    let l = *l as usize;
+                    if skip >= l {
+                        skip -= l;
+                        continue;
+                    }
+                    slices[n] = IoSlice::new(&data[skip..l]);
+                    skip = 0;
+                    n += 1;
+                }
+                let amnt = conn.write_vectored(&slices[..n])?;
                 if amnt == 0 {
                     return io_err("EOF");
                 }
-                *idx += amnt as u8;
-                if idx == len {
+                *idx += amnt;
+                if *idx == len {
                     Ok(true)
                 } else {
                     self.writable = false;
@@ -141,6 +273,7 @@ impl Writer {
             WriteState::WritingPiece {
                 ref prefix,
                 ref data,
+                len,
                 ref mut idx,
             } => {
                 if *idx < 13 as u16 {
@@ -155,13 +288,72 @@ impl Writer {
                     }
                 }
 
-                let amnt = conn.write(&data[(*idx as usize - 13)..])?;
+                // Buffers are reused, so only the block's length is sent
+                let amnt = conn.write(&data[(*idx as usize - 13)..len as usize])?;
                 if amnt == 0 {
                     return io_err("EOF");
                 }
                 // piece should never exceed u16 size
                 *idx += amnt as u16;
-                if *idx == (13 + data.len()) as u16 {
+                if *idx == 13 + len {
+                    self.blocks_written += 1;
+                    Ok(true)
+                } else {
+                    self.writable = false;
+                    Ok(false)
+                }
+            }
+            WriteState::SendingFile {
+                ref prefix,
+                ref piece,
+                ref mut idx,
+            } => {
+                if *idx < 13 {
+                    let amnt = conn.write(&prefix[(*idx as usize)..])? as u32;
+                    if amnt == 0 {
+                        return io_err("EOF");
+                    }
+                    *idx += amnt;
+                    if *idx != 13 {
+                        self.writable = false;
+                        return Ok(false);
+                    }
+                }
+
+                let sent = *idx - 13;
+                let amnt = conn.send_file(
+                    &piece.file,
+                    piece.offset + u64::from(sent),
+                    (piece.length - sent) as usize,
+                )?;
+                if amnt == 0 {
+                    return io_err("EOF");
+                }
+                *idx += amnt as u32;
+                if *idx == 13 + piece.length {
+                    self.blocks_written += 1;
+                    Ok(true)
+                } else {
+                    self.writable = false;
+                    Ok(false)
+                }
+            }
+            WriteState::WritingRange {
+                ref prefix,
+                ref piece,
+                ref mut idx,
+            } => {
+                let amnt = if *idx < 13 {
+                    let slices = [IoSlice::new(&prefix[*idx..]), IoSlice::new(&piece.data)];
+                    conn.write_vectored(&slices)?
+                } else {
+                    conn.write(&piece.data[*idx - 13..])?
+                };
+                if amnt == 0 {
+                    return io_err("EOF");
+                }
+                *idx += amnt;
+                if *idx == 13 + piece.data.len() {
                     self.blocks_written += 1;
                     Ok(true)
                 } else {
@@ -189,18 +381,68 @@ impl Writer {
     }
 }
 
+/// Header of a piece message with a block of len bytes.
+fn piece_prefix(index: u32, begin: u32, len: u32) -> [u8; 13] {
+    let mut prefix = [0; 13];
+    BigEndian::write_u32(&mut prefix[0..4], 9 + len);
+    prefix[4] = 7;
+    BigEndian::write_u32(&mut prefix[5..9], index);
+    BigEndian::write_u32(&mut prefix[9..13], begin);
+    prefix
+}
+
+/// Whether a message is written as part of a batch.
+fn is_small(msg: &Message) -> bool {
+    !msg.is_special() && !matches!(msg, Message::Piece { .. })
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Writer;
-    use crate::buffers::Buffer;
+    use super::{FilePiece, FileWrite, RangePiece, Writer};
+    use crate::buffers::{Buffer, PieceBuf};
     use crate::torrent::peer::Message;
+    use std::io::{self, Cursor, IoSlice, Write};
+
+    impl FileWrite for &mut [u8] {}
+    impl FileWrite for Cursor<&mut [u8]> {}
+
+    /// Accepts at most limit bytes per write, counting writes.
+    struct Counting {
+        data: Vec<u8>,
+        limit: usize,
+        writes: usize,
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.writes += 1;
+            let mut amnt = 0;
+            for buf in bufs {
+                let n = buf.len().min(self.limit - amnt);
+                self.data.extend_from_slice(&buf[..n]);
+                amnt += n;
+            }
+            Ok(amnt)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl FileWrite for Counting {}
 
     #[test]
     fn test_write_keepalive() {
         let mut w = Writer::new();
         let mut buf = [1u8; 4];
         let m = Message::KeepAlive;
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         w.writable(&mut &mut buf[..]).unwrap();
         assert_eq!(buf, [0u8; 4])
     }
@@ -210,7 +452,8 @@ mod tests {
         let mut w = Writer::new();
         let mut buf = [0u8; 5];
         let m = Message::Choke;
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         w.writable(&mut &mut buf[..]).unwrap();
         assert_eq!(buf, [0, 0, 0, 1, 0])
     }
@@ -220,7 +463,8 @@ mod tests {
         let mut w = Writer::new();
         let mut buf = [0u8; 5];
         let m = Message::Unchoke;
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         w.writable(&mut &mut buf[..]).unwrap();
         assert_eq!(buf, [0, 0, 0, 1, 1])
     }
@@ -230,7 +474,8 @@ mod tests {
         let mut w = Writer::new();
         let mut buf = [0u8; 5];
         let m = Message::Interested;
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         assert_eq!(buf, [0, 0, 0, 1, 2]);
         // test split write
         w.writable(&mut &mut buf[0..1]).unwrap();
@@ -244,7 +489,8 @@ mod tests {
         let mut w = Writer::new();
         let mut buf = [0u8; 9];
         let m = Message::Have(1);
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         w.writable(&mut &mut buf[..]).unwrap();
         assert_eq!(buf, [0, 0, 0, 5, 4, 0, 0, 0, 1])
     }
@@ -258,8 +504,9 @@ mod tests {
         for i in 0..32 {
             pf.set_bit(i);
         }
-        let m = Message::Bitfield(pf);
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        let m = Message::Bitfield(Box::new(pf));
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         w.writable(&mut &mut buf[..]).unwrap();
         assert_eq!(buf, [0, 0, 0, 5, 5, 0xff, 0xff, 0xff, 0xff])
     }
@@ -273,14 +520,14 @@ mod tests {
             begin: 1,
             length: 1,
         };
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         w.writable(&mut &mut buf[..]).unwrap();
         assert_eq!(buf, [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1])
     }
 
     #[test]
     fn test_write_piece() {
-        use std::io::Cursor;
         let mut w = Writer::new();
         let mut piece = Buffer::get().expect("buffers should be present in tests");
         for i in 0..piece.len() {
@@ -294,7 +541,8 @@ mod tests {
             length: 16_384,
             data: piece,
         };
-        w.write_message(m, &mut buf).unwrap();
+        w.queue_message(m);
+        w.flush(&mut buf).unwrap();
         let buf = buf.into_inner();
         assert_eq!(buf[0..13], [0, 0, 0x40, 0x09, 7, 0, 0, 0, 1, 0, 0, 0, 1]);
         for i in 0..16_384 {
@@ -302,6 +550,92 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_write_batch() {
+        let mut w = Writer::new();
+        let mut conn = Counting {
+            data: Vec::new(),
+            limit: usize::MAX,
+            writes: 0,
+        };
+        w.queue_message(Message::Unchoke);
+        w.queue_message(Message::Have(1));
+        w.queue_message(Message::Have(2));
+        w.flush(&mut conn).unwrap();
+        assert_eq!(conn.writes, 1);
+        assert_eq!(
+            conn.data,
+            [0, 0, 0, 1, 1, 0, 0, 0, 5, 4, 0, 0, 0, 1, 0, 0, 0, 5, 4, 0, 0, 0, 2]
+        );
+
+        // Partial writes resume in the middle of a message
+        let mut conn = Counting {
+            data: Vec::new(),
+            limit: 7,
+            writes: 0,
+        };
+        w.queue_message(Message::Have(1));
+        w.queue_message(Message::Have(2));
+        w.flush(&mut conn).unwrap();
+        assert_eq!(conn.writes, 3);
+        assert_eq!(
+            conn.data,
+            [0, 0, 0, 5, 4, 0, 0, 0, 1, 0, 0, 0, 5, 4, 0, 0, 0, 2]
+        );
+    }
+
+    #[test]
+    fn test_write_file() {
+        use std::fs::File;
+        use std::io::Write;
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("piece");
+        File::create(&path)
+            .unwrap()
+            .write_all(&[2u8; 20_000])
+            .unwrap();
+        let mut w = Writer::new();
+        let piece = FilePiece {
+            index: 1,
+            begin: 1,
+            length: 16_384,
+            file: Arc::new(File::open(&path).unwrap()),
+            offset: 100,
+        };
+        let mut sbuf = [0u8; 16_384 + 13];
+        w.write_file(piece, &mut Cursor::new(&mut sbuf[..]))
+            .unwrap();
+        assert_eq!(sbuf[0..13], [0, 0, 0x40, 0x09, 7, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert!(sbuf[13..].iter().all(|b| *b == 2));
+        assert_eq!(w.blocks_written, 1);
+    }
+
+    #[test]
+    fn test_write_range() {
+        let mut w = Writer::new();
+        let mut data = PieceBuf::get(65_536).unwrap();
+        data.iter_mut().for_each(|b| *b = 3);
+        let piece = RangePiece {
+            index: 1,
+            begin: 16_384,
+            data,
+        };
+        // Lengths past u16 are sent over several writes
+        let mut conn = Counting {
+            data: Vec::new(),
+            limit: 20_000,
+            writes: 0,
+        };
+        w.write_range(piece, &mut conn).unwrap();
+        assert_eq!(conn.writes, 4);
+        assert_eq!(conn.data.len(), 65_536 + 13);
+        assert_eq!(conn.data[0..13], [0, 1, 0, 9, 7, 0, 0, 0, 1, 0, 0, 0x40, 0]);
+        assert!(conn.data[13..].iter().all(|b| *b == 3));
+        assert_eq!(w.blocks_written, 1);
+    }
+
     #[test]
     fn test_write_cancel() {
         let mut w = Writer::new();
@@ -311,23 +645,26 @@ mod tests {
             begin: 1,
             length: 1,
         };
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         assert_eq!(buf, [0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1])
     }
 
     #[test]
     fn test_write_handshake() {
+        use crate::protocol::Handshake;
         use crate::PEER_ID;
         let mut w = Writer::new();
-        let m = Message::Handshake {
+        let m = Message::Handshake(Box::new(Handshake {
             rsv: [0; 8],
             hash: [0; 20],
             id: *PEER_ID,
-        };
+        }));
         let mut buf = [0u8; 68];
         let mut abuf = [0u8; 68];
         m.encode(&mut abuf).unwrap();
-        w.write_message(m, &mut &mut buf[..]).unwrap();
+        w.queue_message(m);
+        w.flush(&mut &mut buf[..]).unwrap();
         w.writable(&mut &mut buf[..]).unwrap();
         assert_eq!(buf[..], abuf[..])
     }
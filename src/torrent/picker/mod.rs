@@ -3,6 +3,7 @@ use std::sync::Arc;
 use std::time;
 
 use crate::control::cio;
+use crate::rpc::resource::Strategy;
 use crate::torrent::{Bitfield, Info, Peer};
 use crate::util::FHashSet;
 
@@ -33,6 +34,13 @@ pub struct Picker {
     picker: PickerKind,
     /// Piece priorities
     priorities: Vec<u8>,
+    /// Whether the first and last pieces of each file are picked first
+    first_last: bool,
+    /// Time critical pieces, ordered by deadline
+    deadlines: Vec<(u32, time::Instant)>,
+    /// Pieces which failed validation and the peers which supplied their
+    /// blocks, which are picked by other peers first
+    failed: Vec<(u32, Vec<usize>)>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -72,6 +80,10 @@ const MAX_DUP_REQS: usize = 3;
 const MAX_PC_SIZE: usize = 50;
 const MAX_DL_REREQ: usize = 150;
 const REQ_TIMEOUT: u64 = 10;
+/// Number of top ranked peers which time critical pieces are requested from
+const DEADLINE_PEERS: usize = 4;
+/// Priority of the first and last pieces of files, when picked first
+const FIRST_LAST_PRI: u8 = 5;
 
 impl Picker {
     /// Creates a new picker, which will select over
@@ -80,7 +92,7 @@ impl Picker {
     /// will default to rarest first.
     pub fn new(info: &Arc<Info>, pieces: &Bitfield, priorities: &[u8]) -> Picker {
         let scale = info.piece_len / 16_384;
-        let picker = rarest::Picker::new(pieces);
+        let picker = rarest::Picker::new(pieces, false);
         let last_piece = info.pieces().saturating_sub(1);
         let lpl = info.piece_len(last_piece);
         let last_piece_scale = if lpl % 16_384 == 0 {
@@ -108,6 +120,9 @@ impl Picker {
             unpicked: pieces.clone(),
             stalled: FHashSet::default(),
             priorities: vec![3; info.pieces() as usize],
+            first_last: false,
+            deadlines: Vec::new(),
+            failed: Vec::new(),
             blocks,
         };
         picker.set_priorities(priorities, info);
@@ -122,13 +137,50 @@ impl Picker {
         }
     }
 
+    /// Returns the current picking strategy
+    pub fn strategy(&self) -> Strategy {
+        match self.picker {
+            PickerKind::Sequential(_) => Strategy::Sequential,
+            PickerKind::Rarest(ref p) if p.random_first() => Strategy::RandomFirst,
+            PickerKind::Rarest(_) => Strategy::Rarest,
+        }
+    }
+
+    /// Whether the first and last pieces of each file are picked first.
+    pub fn first_last(&self) -> bool {
+        self.first_last
+    }
+
+    /// Sets whether the first and last pieces of each file which isn't
+    /// skipped are picked first, e.g. so media can be previewed. This
+    /// takes effect once priorities are next set.
+    pub fn set_first_last(&mut self, first_last: bool) {
+        self.first_last = first_last;
+    }
+
     pub fn done(&mut self) {
         self.downloading = HashMap::with_capacity(0);
         self.blocks = vec![];
         self.stalled = FHashSet::default();
+        self.deadlines.clear();
+        self.failed.clear();
+    }
+
+    /// Sets a deadline for a piece. Pieces with a deadline are picked
+    /// ahead of all others, and only from the highest ranked peers until
+    /// the deadline has passed.
+    pub fn set_deadline(&mut self, piece: u32, deadline: time::Instant) {
+        self.deadlines.retain(|&(p, _)| p != piece);
+        let pos = self
+            .deadlines
+            .iter()
+            .position(|&(_, d)| d > deadline)
+            .unwrap_or(self.deadlines.len());
+        self.deadlines.insert(pos, (piece, deadline));
     }
 
     pub fn tick(&mut self) {
+        let now = time::Instant::now();
         let mut expired = 0;
         for (block, req) in &mut self.downloading {
             let reqd = self.blocks[block.index as usize].0;
@@ -137,7 +189,13 @@ impl Picker {
             let deadline = (REQ_TIMEOUT as isize
                 + (3 - self.priorities[block.index as usize] as isize))
                 as u64;
-            if req.requested_at.elapsed().as_secs() >= deadline && !self.stalled.contains(block) {
+            let late = self
+                .deadlines
+                .iter()
+                .any(|&(p, d)| p == block.index && d <= now);
+            if (req.requested_at.elapsed().as_secs() >= deadline || late)
+                && !self.stalled.contains(block)
+            {
                 expired += 1;
                 self.stalled.insert(*block);
             }
@@ -171,6 +229,14 @@ impl Picker {
             }
         }
 
+        if let Some(piece) = self.pick_deadline(peer) {
+            return Some(self.pick_piece(piece, peer.id(), peer.rank));
+        }
+
+        if let Some(piece) = self.pick_failed(peer, false) {
+            return Some(self.pick_piece(piece, peer.id(), peer.rank));
+        }
+
         let piece = match self.picker {
             PickerKind::Sequential(ref mut p) => p.pick(peer),
             PickerKind::Rarest(ref mut p) => p.pick(peer),
@@ -178,10 +244,46 @@ impl Picker {
         piece
             .map(|p| self.pick_piece(p, peer.id(), peer.rank))
             .or_else(|| self.pick_dl(peer))
+            .or_else(|| {
+                // Failed pieces only the peers which supplied them have
+                let piece = self.pick_failed(peer, true)?;
+                Some(self.pick_piece(piece, peer.id(), peer.rank))
+            })
+    }
+
+    /// Picks a failed piece which the peer didn't supply blocks of,
+    /// or any failed piece the peer has if `any` is set.
+    fn pick_failed<T: cio::CIO>(&mut self, peer: &Peer<T>, any: bool) -> Option<u32> {
+        let pos = self.failed.iter().position(|(piece, suppliers)| {
+            peer.pieces().has_bit(u64::from(*piece)) && (any || !suppliers.contains(&peer.id()))
+        })?;
+        let (piece, _) = self.failed.remove(pos);
+        // The rest of the piece may be picked as usual once started
+        match self.picker {
+            PickerKind::Sequential(ref mut p) => p.incomplete(piece),
+            PickerKind::Rarest(ref mut p) => p.incomplete(piece),
+        }
+        Some(piece)
+    }
+
+    /// Picks the earliest deadline piece which still has blocks to request.
+    fn pick_deadline<T: cio::CIO>(&self, peer: &Peer<T>) -> Option<u32> {
+        let now = time::Instant::now();
+        self.deadlines
+            .iter()
+            .find(|&&(piece, deadline)| {
+                !self.unpicked.has_bit(u64::from(piece))
+                    && peer.pieces().has_bit(u64::from(piece))
+                    && (peer.rank < DEADLINE_PEERS || deadline <= now)
+            })
+            .map(|&(piece, _)| piece)
     }
 
     /// Picks a block from a given piece for a peer
     fn pick_piece(&mut self, piece: u32, id: usize, rank: usize) -> Block {
+        if !self.failed.is_empty() {
+            self.failed.retain(|&(p, _)| p != piece);
+        }
         self.blocks[piece as usize].0 += 1;
         let amnt = self.blocks[piece as usize].0;
         let offset = (amnt - 1) as u32 * 16_384;
@@ -243,6 +345,7 @@ impl Picker {
         if amnt == self.scale as usize
             || (b.index == self.last_piece && amnt == self.last_piece_scale as usize)
         {
+            self.deadlines.retain(|&(p, _)| p != b.index);
             Ok(true)
         } else {
             Ok(false)
@@ -259,6 +362,19 @@ impl Picker {
             PickerKind::Sequential(ref mut p) => p.incomplete(idx),
             PickerKind::Rarest(ref mut p) => p.incomplete(idx),
         }
+        self.failed.retain(|&(p, _)| p != idx);
+        self.reset_piece(idx);
+    }
+
+    /// Invalidates a piece which failed validation, so that it's
+    /// requested from peers other than those which supplied it first.
+    pub fn piece_failed(&mut self, idx: u32, suppliers: Vec<usize>) {
+        self.failed.retain(|&(p, _)| p != idx);
+        self.failed.push((idx, suppliers));
+        self.reset_piece(idx);
+    }
+
+    fn reset_piece(&mut self, idx: u32) {
         if self.blocks.is_empty() {
             self.blocks = vec![(0, 0); self.priorities.len()];
         }
@@ -301,20 +417,19 @@ impl Picker {
         }
     }
 
-    /// Alters the picker to sequential/non sequential. If changing
-    /// from sequential to non sequential, peer state will need to be loaded
-    /// after this.
-    pub fn change_picker(&mut self, sequential: bool) {
-        self.picker = if sequential {
-            PickerKind::Sequential(sequential::Picker::new(&self.unpicked))
-        } else {
-            PickerKind::Rarest(rarest::Picker::new(&self.unpicked))
+    /// Alters the picking strategy. If changing to a rarest first
+    /// strategy, peer state will need to be loaded after this.
+    pub fn change_picker(&mut self, strategy: Strategy) {
+        self.picker = match strategy {
+            Strategy::Sequential => PickerKind::Sequential(sequential::Picker::new(&self.unpicked)),
+            Strategy::Rarest => PickerKind::Rarest(rarest::Picker::new(&self.unpicked, false)),
+            Strategy::RandomFirst => PickerKind::Rarest(rarest::Picker::new(&self.unpicked, true)),
         };
     }
 
     pub fn set_priorities(&mut self, pri: &[u8], info: &Arc<Info>) {
         self.unapply_priorities();
-        self.priorities = generate_piece_pri(pri, info);
+        self.priorities = generate_piece_pri(pri, info, self.first_last);
         self.apply_priorities();
     }
 
@@ -327,15 +442,9 @@ impl Picker {
         } else {
             for (piece, pri) in self.priorities.iter().enumerate() {
                 if let PickerKind::Rarest(ref mut p) = self.picker {
-                    for _ in 0..*pri {
-                        p.piece_unavailable(piece as u32);
-                    }
-                }
-
-                if *pri == 0 && !self.unpicked.has_bit(piece as u64) {
-                    match self.picker {
-                        PickerKind::Rarest(ref mut p) => p.completed(piece as u32),
-                        _ => unreachable!(),
+                    p.set_priority(piece as u32, *pri);
+                    if *pri == 0 && !self.unpicked.has_bit(piece as u64) {
+                        p.completed(piece as u32);
                     }
                 }
             }
@@ -346,15 +455,8 @@ impl Picker {
         if !self.is_sequential() {
             for (piece, pri) in self.priorities.iter().enumerate() {
                 if let PickerKind::Rarest(ref mut p) = self.picker {
-                    for _ in 0..*pri {
-                        p.piece_available(piece as u32);
-                    }
-                }
-
-                if *pri == 0 && !self.unpicked.has_bit(piece as u64) {
-                    match self.picker {
-                        PickerKind::Rarest(ref mut p) => p.incomplete(piece as u32),
-                        _ => unreachable!(),
+                    if *pri == 0 && !self.unpicked.has_bit(piece as u64) {
+                        p.incomplete(piece as u32);
                     }
                 }
             }
@@ -362,7 +464,7 @@ impl Picker {
     }
 }
 
-fn generate_piece_pri(pri: &[u8], info: &Arc<Info>) -> Vec<u8> {
+fn generate_piece_pri(pri: &[u8], info: &Arc<Info>, first_last: bool) -> Vec<u8> {
     // Map piece -> priority
     let mut priorities = Vec::with_capacity(info.pieces() as usize);
     // If a piece is completely in a file, just assign that pri.
@@ -374,6 +476,17 @@ fn generate_piece_pri(pri: &[u8], info: &Arc<Info>) -> Vec<u8> {
             .expect("Piece must have locations!");
         priorities.push(max);
     }
+    if first_last {
+        let pl = u64::from(info.piece_len);
+        let mut offset = 0;
+        for (i, file) in info.files.iter().enumerate() {
+            if pri[i] != 0 && file.length != 0 && !file.is_padding() {
+                priorities[(offset / pl) as usize] = FIRST_LAST_PRI;
+                priorities[((offset + file.length - 1) / pl) as usize] = FIRST_LAST_PRI;
+            }
+            offset += file.length;
+        }
+    }
     priorities
 }
 
@@ -393,7 +506,17 @@ impl Picker {
             pieces,
             &vec![3u8; info.files.len()],
         );
-        p.change_picker(true);
+        p.change_picker(Strategy::Sequential);
+        p
+    }
+
+    pub fn new_random_first(info: &Info, pieces: &Bitfield) -> Picker {
+        let mut p = Picker::new(
+            &Arc::new(info.clone()),
+            pieces,
+            &vec![3u8; info.files.len()],
+        );
+        p.change_picker(Strategy::RandomFirst);
         p
     }
 }
@@ -1,23 +1,32 @@
 // Implementation based off of http://blog.libtorrent.org/2011/11/writing-a-fast-piece-picker/
-use std::ops::IndexMut;
+use rand::{self, Rng};
 
 use super::MAX_PC_SIZE;
 use crate::control::cio;
 use crate::torrent::{Bitfield, Peer};
 
+/// Rarest first piece picker. Pieces are kept sorted into buckets
+/// by availability and priority, so a change in availability only
+/// requires swapping a piece across a single bucket boundary.
 #[derive(Clone, Debug)]
 pub struct Picker {
-    /// Current order of pieces
+    /// Current order of pieces, with completed pieces at the end
     pieces: Vec<u32>,
-    /// Indices into pieces which indicate priority bounds
-    priorities: Vec<usize>,
+    /// End index in pieces of each bucket, the last of which
+    /// is the start of the completed pieces
+    bounds: Vec<usize>,
     /// Index mapping a piece to a position in the pieces field
     piece_idx: Vec<PieceInfo>,
+    /// Whether or not the first pieces are picked randomly
+    random_first: bool,
+    /// Number of pieces left to pick randomly
+    random: usize,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 enum PieceStatus {
     Incomplete,
+    Started,
     Complete,
 }
 
@@ -25,42 +34,49 @@ enum PieceStatus {
 struct PieceInfo {
     idx: usize,
     availability: usize,
+    priority: u8,
     status: PieceStatus,
 }
 
-const PIECE_COMPLETE_DEC: usize = 100;
+const MAX_PRI: u8 = 5;
+const DEFAULT_PRI: u8 = 3;
+/// Number of pieces picked at random before switching to rarest first,
+/// so that we quickly have complete pieces to trade.
+const RANDOM_FIRST_PIECES: usize = 4;
 
 impl Picker {
-    pub fn new(pieces: &Bitfield) -> Picker {
-        let mut piece_idx = Vec::new();
-        for i in 0..pieces.len() {
-            piece_idx.push(PieceInfo {
-                idx: i as usize,
+    pub fn new(pieces: &Bitfield, random_first: bool) -> Picker {
+        let len = pieces.len() as usize;
+        let piece_idx: Vec<_> = (0..len)
+            .map(|idx| PieceInfo {
+                idx,
                 availability: 0,
+                priority: DEFAULT_PRI,
                 status: PieceStatus::Incomplete,
-            });
-        }
+            })
+            .collect();
+        let mut bounds = vec![0; piece_idx.first().map(|p| p.bucket()).unwrap_or(0)];
+        bounds.push(len);
         let mut p = Picker {
-            pieces: (0..pieces.len() as u32).collect(),
+            pieces: (0..len as u32).collect(),
+            bounds,
             piece_idx,
-            priorities: vec![pieces.len() as usize],
+            random_first,
+            random: 0,
         };
-
-        // Start every piece at an availability of 6.
-        // This way when we decrement availability for an initial
-        // pick we never underflow, and can keep track of which pieces
-        // are unpicked(odd) and picked(even).
-        for i in (0..pieces.len()).rev() {
-            for _ in 0..6 {
-                p.piece_available(i as u32);
-            }
-            if pieces.has_bit(i) {
-                p.completed(i as u32);
-            }
+        for i in pieces.iter() {
+            p.completed(i as u32);
+        }
+        if random_first {
+            p.random = RANDOM_FIRST_PIECES.saturating_sub(len - p.done());
         }
         p
     }
 
+    pub fn random_first(&self) -> bool {
+        self.random_first
+    }
+
     pub fn add_peer<T: cio::CIO>(&mut self, peer: &Peer<T>) {
         for idx in peer.pieces().iter() {
             self.piece_available(idx as u32);
@@ -74,45 +90,31 @@ impl Picker {
     }
 
     pub fn piece_available(&mut self, piece: u32) {
-        self.dec_pri(piece);
-        self.dec_pri(piece);
-    }
-
-    pub fn dec_pri(&mut self, piece: u32) {
-        let (idx, avail) = {
-            let piece = self.piece_idx.index_mut(piece as usize);
-            self.priorities[piece.availability] -= 1;
-            piece.availability += 1;
-            if self.priorities.len() == piece.availability {
-                self.priorities.push(self.pieces.len());
-            }
-            (piece.idx, piece.availability - 1)
-        };
-
-        let swap_idx = self.priorities[avail];
-        self.swap_piece(idx, swap_idx);
+        let from = self.piece_idx[piece as usize].bucket();
+        self.piece_idx[piece as usize].availability += 1;
+        self.rebucket(piece, from);
     }
 
     pub fn piece_unavailable(&mut self, piece: u32) {
-        self.inc_pri(piece);
-        self.inc_pri(piece);
+        let info = &self.piece_idx[piece as usize];
+        if info.availability == 0 {
+            return;
+        }
+        let from = info.bucket();
+        self.piece_idx[piece as usize].availability -= 1;
+        self.rebucket(piece, from);
     }
 
-    pub fn inc_pri(&mut self, piece: u32) {
-        let (idx, avail) = {
-            let piece = self.piece_idx.index_mut(piece as usize);
-            piece.availability -= 1;
-            self.priorities[piece.availability] += 1;
-            (piece.idx, piece.availability)
-        };
-
-        let swap_idx = self.priorities[avail - 1];
-        self.swap_piece(idx, swap_idx);
+    /// Sets the priority of a piece, where higher priority pieces
+    /// are treated as being rarer.
+    pub fn set_priority(&mut self, piece: u32, priority: u8) {
+        let from = self.piece_idx[piece as usize].bucket();
+        self.piece_idx[piece as usize].priority = priority.min(MAX_PRI);
+        self.rebucket(piece, from);
     }
 
     pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<u32> {
-        while !peer.piece_cache().is_empty() {
-            let p = peer.piece_cache().last().cloned().unwrap();
+        while let Some(p) = peer.piece_cache().last().cloned() {
             if self.piece_idx[p as usize].status == PieceStatus::Complete {
                 peer.piece_cache().pop();
             } else {
@@ -121,45 +123,113 @@ impl Picker {
         }
 
         if peer.piece_cache().is_empty() {
-            for piece in &self.pieces {
-                if peer.pieces().has_bit(u64::from(*piece))
-                    && self.piece_idx[*piece as usize].status == PieceStatus::Incomplete
-                {
-                    peer.piece_cache().push(*piece);
+            let done = self.done();
+            if self.random > 0 {
+                // Only cache the one piece, so the peer sticks with it until complete
+                if let Some(piece) = self.pick_random(peer) {
+                    peer.piece_cache().push(piece);
                 }
-                if peer.piece_cache().len() >= MAX_PC_SIZE {
-                    break;
+            } else {
+                for piece in &self.pieces[..done] {
+                    if peer.pieces().has_bit(u64::from(*piece)) {
+                        peer.piece_cache().push(*piece);
+                    }
+                    if peer.piece_cache().len() >= MAX_PC_SIZE {
+                        break;
+                    }
                 }
+                peer.piece_cache().reverse();
             }
-            peer.piece_cache().reverse();
         }
 
-        let piece = peer.piece_cache().last();
+        let piece = peer.piece_cache().last().cloned();
         if let Some(p) = piece {
-            if (self.piece_idx[*p as usize].availability % 2) == 0 {
-                self.inc_pri(*p);
+            // Prefer pieces which have already been started
+            if self.piece_idx[p as usize].status == PieceStatus::Incomplete {
+                let from = self.piece_idx[p as usize].bucket();
+                self.piece_idx[p as usize].status = PieceStatus::Started;
+                self.rebucket(p, from);
             }
         }
-        piece.cloned()
+        piece
+    }
+
+    fn pick_random<T: cio::CIO>(&self, peer: &Peer<T>) -> Option<u32> {
+        let done = self.done();
+        if done == 0 {
+            return None;
+        }
+        let start = rand::thread_rng().gen_range(0, done);
+        (start..done)
+            .chain(0..start)
+            .map(|i| self.pieces[i])
+            .find(|p| peer.pieces().has_bit(u64::from(*p)))
     }
 
     pub fn incomplete(&mut self, piece: u32) {
-        if self.piece_idx[piece as usize].status != PieceStatus::Incomplete {
-            self.piece_idx[piece as usize].status = PieceStatus::Incomplete;
-            for _ in 0..PIECE_COMPLETE_DEC {
-                self.inc_pri(piece);
+        match self.piece_idx[piece as usize].status {
+            PieceStatus::Incomplete => {}
+            PieceStatus::Started => {
+                let from = self.piece_idx[piece as usize].bucket();
+                self.piece_idx[piece as usize].status = PieceStatus::Incomplete;
+                self.rebucket(piece, from);
+            }
+            PieceStatus::Complete => {
+                self.piece_idx[piece as usize].status = PieceStatus::Incomplete;
+                let to = self.piece_idx[piece as usize].bucket();
+                self.add_buckets(to);
+                let from = self.bounds.len();
+                self.shift(piece, from, to);
             }
         }
     }
 
     pub fn completed(&mut self, piece: u32) {
         if self.piece_idx[piece as usize].status != PieceStatus::Complete {
+            let from = self.piece_idx[piece as usize].bucket();
             self.piece_idx[piece as usize].status = PieceStatus::Complete;
-            // As hacky as this is, it's a good way to ensure that
-            // we never waste time picking already selected pieces
-            for _ in 0..PIECE_COMPLETE_DEC {
-                self.dec_pri(piece);
-            }
+            let to = self.bounds.len();
+            self.shift(piece, from, to);
+            self.random = self.random.saturating_sub(1);
+        }
+    }
+
+    /// Index of the first completed piece
+    fn done(&self) -> usize {
+        *self.bounds.last().unwrap()
+    }
+
+    /// Moves a piece from its previous bucket into its current one.
+    fn rebucket(&mut self, piece: u32, from: usize) {
+        if self.piece_idx[piece as usize].status == PieceStatus::Complete {
+            return;
+        }
+        let to = self.piece_idx[piece as usize].bucket();
+        self.add_buckets(to);
+        self.shift(piece, from, to);
+    }
+
+    fn add_buckets(&mut self, bucket: usize) {
+        while self.bounds.len() <= bucket {
+            let done = self.done();
+            self.bounds.push(done);
+        }
+    }
+
+    /// Shifts a piece across bucket boundaries, one bucket at a time.
+    /// A bucket of bounds.len() refers to the completed pieces.
+    fn shift(&mut self, piece: u32, mut from: usize, to: usize) {
+        while from < to {
+            let last = self.bounds[from] - 1;
+            self.swap_piece(self.piece_idx[piece as usize].idx, last);
+            self.bounds[from] -= 1;
+            from += 1;
+        }
+        while from > to {
+            let first = self.bounds[from - 1];
+            self.swap_piece(self.piece_idx[piece as usize].idx, first);
+            self.bounds[from - 1] += 1;
+            from -= 1;
         }
     }
 
@@ -170,6 +240,18 @@ impl Picker {
     }
 }
 
+impl PieceInfo {
+    /// Bucket the piece belongs in, where lower buckets are picked first.
+    fn bucket(&self) -> usize {
+        let unstarted = if self.status == PieceStatus::Started {
+            0
+        } else {
+            1
+        };
+        2 * (self.availability + usize::from(MAX_PRI - self.priority)) + unstarted
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Picker;
@@ -178,7 +260,7 @@ mod tests {
     #[test]
     fn test_available() {
         let b = Bitfield::new(3);
-        let mut picker = Picker::new(&b);
+        let mut picker = Picker::new(&b, false);
         let mut peers = vec![
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
@@ -208,7 +290,7 @@ mod tests {
     fn test_unavailable() {
         let b = Bitfield::new(3);
 
-        let mut picker = Picker::new(&b);
+        let mut picker = Picker::new(&b, false);
         let mut peers = vec![
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
@@ -239,4 +321,59 @@ mod tests {
         picker.incomplete(1);
         assert_eq!(picker.pick(&mut peers[1]), Some(1));
     }
+
+    #[test]
+    fn test_priority() {
+        let b = Bitfield::new(3);
+        let mut picker = Picker::new(&b, false);
+        let mut peers = [
+            Peer::test_from_pieces(0, b.clone()),
+            Peer::test_from_pieces(0, b.clone()),
+        ];
+        for i in 0..3 {
+            peers[0].pieces_mut().set_bit(i);
+        }
+        peers[1].pieces_mut().set_bit(1);
+        for peer in peers.iter() {
+            picker.add_peer(peer);
+        }
+
+        picker.set_priority(0, 1);
+        picker.set_priority(1, 5);
+        assert_eq!(picker.pick(&mut peers[0]), Some(1));
+        picker.completed(1);
+        assert_eq!(picker.pick(&mut peers[0]), Some(2));
+        picker.completed(2);
+        assert_eq!(picker.pick(&mut peers[0]), Some(0));
+    }
+
+    #[test]
+    fn test_random_first() {
+        let mut b = Bitfield::new(10);
+        let mut picker = Picker::new(&b, true);
+        for i in 0..10 {
+            b.set_bit(i);
+        }
+        let mut peers = [
+            Peer::test_from_pieces(0, b.clone()),
+            Peer::test_from_pieces(1, b.clone()),
+        ];
+        peers[1].pieces_mut().unset_bit(9);
+        for peer in peers.iter() {
+            picker.add_peer(peer);
+        }
+
+        // Random picks stick with a peer until complete
+        let mut picked = vec![];
+        for _ in 0..4 {
+            let p = picker.pick(&mut peers[0]).unwrap();
+            assert_eq!(picker.pick(&mut peers[0]), Some(p));
+            picker.completed(p);
+            picked.push(p);
+        }
+        assert_eq!(picker.random, 0);
+        if !picked.contains(&9) {
+            assert_eq!(picker.pick(&mut peers[0]), Some(9));
+        }
+    }
 }
@@ -72,7 +72,9 @@ impl Picker {
     pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
         self.pieces[self.piece_idx..]
             .iter()
-            .find(|p| peer.pieces().has_bit(u64::from(p.pos)))
+            .find(|p| {
+                p.status == PieceStatus::Incomplete && peer.pieces().has_bit(u64::from(p.pos))
+            })
             .map(|p| p.pos)
     }
 
@@ -101,10 +103,10 @@ impl Picker {
     }
 
     fn update_piece_idx(&mut self) {
-        for i in self.piece_idx..self.pieces.len() {
-            if self.pieces[i].status == PieceStatus::Complete {
-                self.piece_idx += 1;
-            }
+        while self.piece_idx < self.pieces.len()
+            && self.pieces[self.piece_idx].status == PieceStatus::Complete
+        {
+            self.piece_idx += 1;
         }
     }
 }
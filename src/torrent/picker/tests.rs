@@ -4,6 +4,7 @@ use crate::torrent::{Bitfield, Info, Peer as TGPeer};
 use rand::distributions::{Distribution, Range};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 type TPeer = TGPeer<control::cio::test::TCIO>;
 
@@ -245,6 +246,23 @@ fn test_rarest_efficiency() {
     test_efficiency(cfg, p);
 }
 
+#[ignore]
+#[test]
+fn test_random_first_efficiency() {
+    let cfg = TestCfg {
+        pieces: 100,
+        peers: 20,
+        unchoke_limit: 5,
+        connect_limit: 20,
+        req_per_tick: 2,
+        req_queue_len: 2,
+    };
+    let info = Info::with_pieces(cfg.pieces as usize);
+    let b = Bitfield::new(cfg.pieces as u64);
+    let p = Picker::new_random_first(&info, &b);
+    test_efficiency(cfg, p);
+}
+
 #[test]
 fn test_seq_picker() {
     let mut i = Info::with_pieces(10);
@@ -276,3 +294,135 @@ fn test_seq_picker() {
 
     assert_eq!(p.pick(&mut peer), Some(Block::new(5, 0)));
 }
+
+#[test]
+fn test_first_last() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let mut p = Picker::new_sequential(&i, &Bitfield::new(10));
+    p.set_first_last(true);
+    p.set_priorities(&[3], &Arc::new(i));
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb);
+    for &piece in &[0, 9, 1, 2] {
+        assert_eq!(p.pick(&mut peer), Some(Block::new(piece, 0)));
+    }
+}
+
+#[test]
+fn test_deadline() {
+    use std::time::{Duration, Instant};
+
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut fast = TPeer::test_from_pieces(0, pb.clone());
+    let mut slow = TPeer::test_from_pieces(1, pb);
+    slow.rank = 10;
+
+    p.set_deadline(7, Instant::now() + Duration::from_secs(60));
+    p.set_deadline(4, Instant::now() - Duration::from_secs(1));
+    assert_eq!(p.pick(&mut slow), Some(Block::new(4, 0)));
+    assert_eq!(p.pick(&mut slow), Some(Block::new(0, 0)));
+    assert_eq!(p.pick(&mut fast), Some(Block::new(7, 0)));
+    assert_eq!(p.pick(&mut fast), Some(Block::new(1, 0)));
+    assert_eq!(p.completed(Block::new(7, 0), |_| ()), Ok(true));
+    assert!(p.deadlines.iter().all(|&(piece, _)| piece != 7));
+}
+
+#[test]
+fn test_piece_failed() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut bad = TPeer::test_from_pieces(0, pb.clone());
+    let mut good = TPeer::test_from_pieces(1, pb);
+    let mut only = Bitfield::new(10);
+    only.set_bit(1);
+    let mut single = TPeer::test_from_pieces(2, only);
+
+    assert_eq!(p.pick(&mut bad), Some(Block::new(0, 0)));
+    assert_eq!(p.completed(Block::new(0, 0), |_| ()), Ok(true));
+    p.piece_failed(0, vec![0]);
+    // The failed piece goes to a different peer first
+    assert_eq!(p.pick(&mut bad), Some(Block::new(1, 0)));
+    assert_eq!(p.pick(&mut good), Some(Block::new(0, 0)));
+
+    // Unless only its suppliers have it
+    assert_eq!(p.completed(Block::new(0, 0), |_| ()), Ok(true));
+    assert_eq!(p.completed(Block::new(1, 0), |_| ()), Ok(true));
+    p.piece_failed(1, vec![2]);
+    assert_eq!(p.pick(&mut single), Some(Block::new(1, 0)));
+}
+
+/// Times the picker over a large swarm: building availability from peer bitfields,
+/// applying a stream of Haves, and picking every piece to completion.
+/// Run with `cargo test --release bench_large_swarm -- --ignored --nocapture`.
+#[ignore]
+#[test]
+fn bench_large_swarm() {
+    use rand::Rng;
+    use std::time::Instant;
+
+    const PIECES: u32 = 8_000;
+    const PEERS: usize = 300;
+
+    let mut rng = rand::thread_rng();
+    let mut info = Info::with_pieces(PIECES as usize);
+    info.piece_idx =
+        Info::generate_piece_idx(info.hashes.len(), info.piece_len as u64, &info.files);
+    let mut peers = (0..PEERS)
+        .map(|id| {
+            let mut b = Bitfield::new(u64::from(PIECES));
+            for i in 0..PIECES {
+                if rng.gen_range(0, 3) == 0 {
+                    b.set_bit(u64::from(i));
+                }
+            }
+            TPeer::test_from_pieces(id, b)
+        })
+        .collect::<Vec<_>>();
+    let haves = (0..200_000)
+        .map(|_| (rng.gen_range(0, PEERS), rng.gen_range(0, PIECES)))
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let mut picker = Picker::new_rarest(&info, &Bitfield::new(u64::from(PIECES)));
+    for peer in &peers {
+        picker.add_peer(peer);
+    }
+    println!("Bitfields: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    for &(peer, piece) in &haves {
+        if !peers[peer].pieces().has_bit(u64::from(piece)) {
+            peers[peer].pieces_mut().set_bit(u64::from(piece));
+            picker.piece_available(piece);
+        }
+    }
+    println!("Haves: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let mut picked = 0;
+    while picked < PIECES {
+        let peer = &mut peers[rng.gen_range(0, PEERS)];
+        if let Some(block) = picker.pick(peer) {
+            picker.completed(block, |_| ()).unwrap();
+            picked += 1;
+        }
+    }
+    println!("Picks: {:?}", start.elapsed());
+}
@@ -0,0 +1,169 @@
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+
+use crate::util::sha1_hash;
+
+/// Maximum size of a bencoded item value
+pub const MAX_VALUE_LEN: usize = 1000;
+/// Maximum size of a mutable item's salt
+pub const MAX_SALT_LEN: usize = 64;
+
+/// An item stored in the DHT, as specified by BEP 44.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+    /// Bencoded value of the item
+    pub value: Vec<u8>,
+    pub mutable: Option<Mutable>,
+}
+
+/// Signature data of a mutable item.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mutable {
+    /// ed25519 public key
+    pub key: Vec<u8>,
+    pub sig: Vec<u8>,
+    pub seq: i64,
+    pub salt: Vec<u8>,
+}
+
+impl Item {
+    pub fn immutable(value: Vec<u8>) -> Item {
+        Item {
+            value,
+            mutable: None,
+        }
+    }
+
+    /// Creates a mutable item signed by the given key.
+    pub fn mutable(key: &Ed25519KeyPair, value: Vec<u8>, seq: i64, salt: Vec<u8>) -> Item {
+        let sig = key.sign(&signable(&value, seq, &salt));
+        Item {
+            mutable: Some(Mutable {
+                key: key.public_key().as_ref().to_vec(),
+                sig: sig.as_ref().to_vec(),
+                seq,
+                salt,
+            }),
+            value,
+        }
+    }
+
+    /// The DHT key the item is stored under.
+    pub fn target(&self) -> [u8; 20] {
+        match self.mutable {
+            Some(ref m) => mutable_target(&m.key, &m.salt),
+            None => sha1_hash(&self.value),
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.mutable.as_ref().map(|m| m.seq)
+    }
+
+    /// Checks the size limits and, for mutable items, the signature.
+    pub fn valid(&self) -> bool {
+        if self.value.len() > MAX_VALUE_LEN {
+            return false;
+        }
+        match self.mutable {
+            Some(ref m) => {
+                m.salt.len() <= MAX_SALT_LEN
+                    && UnparsedPublicKey::new(&signature::ED25519, &m.key)
+                        .verify(&signable(&self.value, m.seq, &m.salt), &m.sig)
+                        .is_ok()
+            }
+            None => true,
+        }
+    }
+}
+
+pub fn mutable_target(key: &[u8], salt: &[u8]) -> [u8; 20] {
+    sha1_hash(&[key, salt].concat())
+}
+
+/// Constructs the buffer which mutable item signatures cover.
+fn signable(value: &[u8], seq: i64, salt: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if !salt.is_empty() {
+        buf.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend_from_slice(salt);
+    }
+    buf.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buf.extend_from_slice(value);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    // Test vectors from BEP 44
+    const PUBLIC_KEY: &str = "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548";
+    const SIG: &str = "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+                       1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01";
+    const SALTED_SIG: &str = "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+                              df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08";
+
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn vector(sig: &str, salt: &[u8]) -> Item {
+        Item {
+            value: b"12:Hello World!".to_vec(),
+            mutable: Some(Mutable {
+                key: from_hex(PUBLIC_KEY),
+                sig: from_hex(sig),
+                seq: 1,
+                salt: salt.to_vec(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_immutable_target() {
+        let item = Item::immutable(b"12:Hello World!".to_vec());
+        assert_eq!(
+            item.target().to_vec(),
+            from_hex("e5f96f6f38320f0f33959cb4d3d656452117aadb")
+        );
+        assert!(item.valid());
+    }
+
+    #[test]
+    fn test_mutable_vectors() {
+        let item = vector(SIG, b"");
+        assert_eq!(
+            item.target().to_vec(),
+            from_hex("4a533d47ec9c7d95b1ad75f576cffc641853b750")
+        );
+        assert!(item.valid());
+
+        let salted = vector(SALTED_SIG, b"foobar");
+        assert_eq!(
+            salted.target().to_vec(),
+            from_hex("411eba73b6f087ca51a3795d9c8c938d365e32c1")
+        );
+        assert!(salted.valid());
+
+        let mut forged = salted;
+        forged.mutable.as_mut().unwrap().seq = 2;
+        assert!(!forged.valid());
+    }
+
+    #[test]
+    fn test_mutable_sign() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let item = Item::mutable(&key, b"i5e".to_vec(), 4, b"salt".to_vec());
+        assert!(item.valid());
+        assert_eq!(item.seq(), Some(4));
+        assert_eq!(
+            item.target(),
+            mutable_target(key.public_key().as_ref(), b"salt")
+        );
+    }
+}
@@ -1,17 +1,26 @@
-use std::fs::OpenOptions;
-use std::io::{self, Read};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
 use std::net::{SocketAddr, UdpSocket};
-use std::path::Path;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::time;
 
+use chrono::Utc;
 use num_bigint::BigUint;
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
 
 use crate::disk;
 use crate::tracker;
+use crate::util::{io_err_val, net};
 use crate::CONFIG;
 
+mod item;
 mod proto;
 mod rt;
+mod security;
+
+pub use self::item::{mutable_target, Item, MAX_SALT_LEN, MAX_VALUE_LEN};
 
 type ID = BigUint;
 
@@ -19,86 +28,198 @@ const BUCKET_MAX: usize = 8;
 const MAX_BUCKETS: usize = 512;
 const VERSION: &str = "SY";
 const SESSION_FILE: &str = "dht_data";
+const SESSION_FILE6: &str = "dht6_data";
+const KEY_FILE: &str = "dht_key";
 const MIN_BOOTSTRAP_BKTS: usize = 32;
 const TX_TIMEOUT_SECS: i64 = 20;
 
-pub struct Manager {
+/// A routing table along with the socket for its address family.
+struct Dht {
     id: usize,
+    sock: UdpSocket,
     table: rt::RoutingTable,
+    session: &'static str,
+}
+
+pub struct Manager {
+    v4: Dht,
+    /// IPv6 DHT as specified by BEP 32, if an IPv6 socket could be bound
+    v6: Option<Dht>,
     dht_flush: time::Instant,
-    sock: UdpSocket,
     buf: Vec<u8>,
     db: amy::Sender<disk::Request>,
+    /// Key used to sign mutable items
+    key: Ed25519KeyPair,
 }
 
 impl Manager {
     pub fn new(reg: &amy::Registrar, db: amy::Sender<disk::Request>) -> io::Result<Manager> {
-        let sock = UdpSocket::bind(("0.0.0.0", CONFIG.dht.port))?;
-        sock.set_nonblocking(true)?;
-        let id = reg.register(&sock, amy::Event::Read)?;
+        let sock = net::udp_socket(false, CONFIG.dht.port)?;
+        let v4 = Dht::new(reg, sock, SESSION_FILE, CONFIG.dht.bootstrap_node)?;
         // Turn off DHT if no bootstrap is specified.
-        if CONFIG.dht.bootstrap_node.is_none() {
-            reg.deregister(&sock)?;
-        }
-
-        let p = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
-        let mut data = Vec::new();
-        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
-            f.read_to_end(&mut data)?;
-        }
-        let mut table = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
-            t
+        let v6 = if CONFIG.dht.bootstrap_node.is_none() {
+            reg.deregister(&v4.sock)?;
+            None
         } else {
-            info!("DHT table could not be read from disk, creating new table!");
-            rt::RoutingTable::new()
-        };
-        if !table.is_bootstrapped() {
-            info!("Attempting DHT bootstrap with node: {:?}!", CONFIG.dht.bootstrap_node);
-            if let Some(addr) = CONFIG.dht.bootstrap_node {
-                let (msg, _) = table.add_addr(addr);
-                let bootstrap_result = sock.send_to(&msg.encode(), addr);
+            match net::udp_socket(true, CONFIG.dht.port)
+                .and_then(|sock| Dht::new(reg, sock, SESSION_FILE6, CONFIG.dht.bootstrap_node6))
+            {
+                Ok(dht) => Some(dht),
+                Err(e) => {
+                    info!("Failed to bind IPv6 DHT socket, IPv6 DHT disabled: {}", e);
+                    None
+                }
             }
-        }
+        };
 
         Ok(Manager {
-            table,
-            sock,
-            id,
+            v4,
+            v6,
             db,
-            buf: vec![0u8; 500],
+            key: load_key()?,
+            buf: vec![0u8; 1500],
             dht_flush: time::Instant::now(),
         })
     }
 
     pub fn init(&mut self) {
         debug!("Initializing DHT nodes!");
-        for (q, a) in self.table.init() {
-            self.send_msg(&q.encode(), a);
+        let reqs: Vec<_> = self.tables().flat_map(|d| d.table.init()).collect();
+        for (q, a) in reqs {
+            self.send_req(q, a);
+        }
+    }
+
+    /// Checks whether an event is for one of the DHT sockets.
+    pub fn owns(&self, id: usize) -> bool {
+        self.v4.id == id || self.v6.as_ref().map(|d| d.id == id).unwrap_or(false)
+    }
+
+    /// Looks up an item. Items are only stored in the IPv4 DHT.
+    pub fn get_item(
+        &mut self,
+        client: usize,
+        serial: u64,
+        target: [u8; 20],
+    ) -> Vec<tracker::Response> {
+        if net::down() {
+            return vec![item_failed(client, serial, target)];
+        }
+        let lookup = rt::Lookup::new(client, serial, target, None);
+        for (req, a) in self.v4.table.lookup(lookup) {
+            self.send_req(req, a);
+        }
+        self.finish_lookups()
+    }
+
+    /// Stores an item on the nodes closest to it, signing it with
+    /// the DHT key if mutable. If no sequence number is given for a
+    /// mutable item the current time is used.
+    pub fn put_item(
+        &mut self,
+        client: usize,
+        serial: u64,
+        value: Vec<u8>,
+        mutable: bool,
+        salt: Vec<u8>,
+        seq: Option<i64>,
+    ) -> Vec<tracker::Response> {
+        let item = if mutable {
+            let seq = seq.unwrap_or_else(|| Utc::now().timestamp());
+            Item::mutable(&self.key, value, seq, salt)
+        } else {
+            Item::immutable(value)
+        };
+        if net::down() {
+            return vec![item_failed(client, serial, item.target())];
+        }
+        let lookup = rt::Lookup::new(client, serial, item.target(), Some(item));
+        for (req, a) in self.v4.table.lookup(lookup) {
+            self.send_req(req, a);
         }
+        self.finish_lookups()
     }
 
-    pub fn id(&self) -> usize {
-        self.id
+    fn finish_lookups(&mut self) -> Vec<tracker::Response> {
+        let mut resps = Vec::new();
+        for lookup in self.v4.table.finished_lookups() {
+            let item = match lookup.put {
+                Some(ref item) => {
+                    let reqs = self.v4.table.put_item(&lookup, item);
+                    let stored = !reqs.is_empty();
+                    for (req, a) in reqs {
+                        self.send_req(req, a);
+                    }
+                    if stored {
+                        Some(item.clone())
+                    } else {
+                        None
+                    }
+                }
+                None => lookup.item,
+            };
+            resps.push(tracker::Response::DHTItem {
+                client: lookup.client,
+                serial: lookup.serial,
+                target: lookup.target,
+                item,
+            });
+        }
+        resps
     }
 
     pub fn readable(&mut self) -> Vec<tracker::Response> {
+        let mut resps = self.read(false);
+        if self.v6.is_some() {
+            resps.extend(self.read(true));
+        }
+        resps
+    }
+
+    fn read(&mut self, v6: bool) -> Vec<tracker::Response> {
         let mut resps = Vec::new();
         loop {
-            match self.sock.recv_from(&mut self.buf[..]) {
+            let res = match self.v6 {
+                Some(ref d) if v6 => d.sock.recv_from(&mut self.buf[..]),
+                _ => self.v4.sock.recv_from(&mut self.buf[..]),
+            };
+            match res {
                 Ok((v, addr)) => {
                     trace!("Processing msg from {}", addr);
                     if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
-                        let resp = self.table.handle_req(req, addr).encode();
-                        self.send_msg(&resp, addr);
-                    } else if let Ok(resp) = proto::Response::decode(&self.buf[..v]) {
-                        match self.table.handle_resp(resp, addr) {
+                        if let Some(resp) = self.handle_req(req, addr, v6) {
+                            self.send_msg(&resp.encode(), addr);
+                        }
+                    } else if let Ok(mut resp) = proto::Response::decode(&self.buf[..v]) {
+                        // Nodes of the other family seed its table while it bootstraps
+                        let other = resp.split_nodes(!v6);
+                        let table = match self.table_mut(v6) {
+                            Some(table) => table,
+                            None => continue,
+                        };
+                        let res = table.handle_resp(resp, addr);
+                        if let Some(ip) = table.take_external_ip() {
+                            resps.push(tracker::Response::ExternalIp { ip, voter: None });
+                        }
+                        match res {
                             Ok(r) => resps.push(r),
                             Err(q) => {
                                 for (req, a) in q {
-                                    self.send_msg(&req.encode(), a);
+                                    self.send_req(req, a);
                                 }
                             }
                         }
+                        let mut reqs = Vec::new();
+                        if let Some(d) = self.other_mut(v6) {
+                            if !d.table.is_bootstrapped() {
+                                reqs.extend(other.into_iter().map(|n| d.table.add_addr(n.addr)));
+                            }
+                        }
+                        for (req, a) in reqs {
+                            self.send_req(req, a);
+                        }
+                        self.finish_announces();
+                        resps.extend(self.finish_lookups());
                     } else {
                         trace!("Received invalid message from {:?}!", addr);
                     }
@@ -119,38 +240,125 @@ impl Manager {
         resps
     }
 
-    pub fn get_peers(&mut self, tid: usize, hash: [u8; 20]) {
-        for (req, a) in self.table.get_peers(tid, hash) {
-            self.send_msg(&req.encode(), a);
+    /// Answers a query, adding nodes and peers from the table of
+    /// the other address family if they are wanted.
+    fn handle_req(
+        &mut self,
+        req: proto::Request,
+        addr: SocketAddr,
+        v6: bool,
+    ) -> Option<proto::Response> {
+        let other = if v6 { req.want.v4 } else { req.want.v6 };
+        let target = req.target();
+        let hash = match req.kind {
+            proto::RequestKind::GetPeers { hash, .. } => Some(hash),
+            _ => None,
+        };
+        let mut resp = self.table_mut(v6)?.handle_req(req, addr);
+        resp.ip = Some(addr);
+        if let (true, Some(target), Some(d)) = (other, target, self.other_mut(v6)) {
+            let peers = hash.map(|h| d.table.peers(&h)).unwrap_or_default();
+            resp.extend(d.table.closest_nodes(&target, BUCKET_MAX), peers);
         }
+        Some(resp)
     }
 
     pub fn add_addr(&mut self, addr: SocketAddr) {
-        self.table.add_addr(addr);
+        if let Some(table) = self.table_mut(addr.is_ipv6()) {
+            table.add_addr(addr);
+        }
+    }
+
+    /// Looks up peers of a torrent and announces it to the closest
+    /// nodes found.
+    pub fn announce(&mut self, tid: usize, hash: [u8; 20]) {
+        let reqs: Vec<_> = self
+            .tables()
+            .flat_map(|d| d.table.announce(tid, hash))
+            .collect();
+        for (req, a) in reqs {
+            self.send_req(req, a);
+        }
     }
 
-    pub fn announce(&mut self, hash: [u8; 20]) {
-        for (req, a) in self.table.announce(hash) {
-            self.send_msg(&req.encode(), a);
+    fn finish_announces(&mut self) {
+        let reqs: Vec<_> = self
+            .tables()
+            .flat_map(|d| d.table.finished_announces())
+            .collect();
+        for (req, a) in reqs {
+            self.send_req(req, a);
         }
     }
 
-    pub fn tick(&mut self) {
+    pub fn tick(&mut self) -> Vec<tracker::Response> {
         if self.dht_flush.elapsed() > time::Duration::from_secs(60) {
-            let data = self.table.serialize();
-            let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
-            self.db.send(disk::Request::WriteFile { data, path }).ok();
+            let flushes: Vec<_> = self
+                .tables()
+                .map(|d| (d.table.serialize(), d.session))
+                .collect();
+            for (data, file) in flushes {
+                let path = PathBuf::from(file);
+                self.db.send(disk::Request::WriteFile { data, path }).ok();
+            }
             self.dht_flush = time::Instant::now();
         }
-        for (req, a) in self.table.tick() {
-            self.send_msg(&req.encode(), a);
+        let reqs: Vec<_> = self.tables().flat_map(|d| d.table.tick()).collect();
+        for (req, a) in reqs {
+            self.send_req(req, a);
+        }
+        self.finish_announces();
+        self.finish_lookups()
+    }
+
+    fn tables(&mut self) -> impl Iterator<Item = &mut Dht> {
+        Some(&mut self.v4).into_iter().chain(self.v6.as_mut())
+    }
+
+    /// Table of an address family, if it has a DHT. Nodes of a family
+    /// without one are dropped, as the other's socket can't reach them.
+    fn table_mut(&mut self, v6: bool) -> Option<&mut rt::RoutingTable> {
+        match self.v6 {
+            Some(ref mut d) if v6 => Some(&mut d.table),
+            _ if v6 => None,
+            _ => Some(&mut self.v4.table),
+        }
+    }
+
+    fn other_mut(&mut self, v6: bool) -> Option<&mut Dht> {
+        if v6 {
+            Some(&mut self.v4)
+        } else {
+            self.v6.as_mut()
         }
     }
 
+    fn send_req(&mut self, mut req: proto::Request, addr: SocketAddr) {
+        // Ask IPv4 nodes for IPv6 nodes until the IPv6 table is bootstrapped
+        let bootstrap6 = self
+            .v6
+            .as_ref()
+            .map(|d| !d.table.is_bootstrapped())
+            .unwrap_or(false);
+        if addr.is_ipv4() && bootstrap6 && req.target().is_some() {
+            req.want = proto::Want { v4: true, v6: true };
+        }
+        self.send_msg(&req.encode(), addr);
+    }
+
     fn send_msg(&mut self, msg: &[u8], addr: SocketAddr) {
+        // Nothing is sent while network activity is paused, including
+        // answers to queries
+        if net::down() {
+            return;
+        }
+        let sock = match self.v6 {
+            Some(ref d) if addr.is_ipv6() => &d.sock,
+            _ => &self.v4.sock,
+        };
         // Cap tries to avoid burning CPU
         for _ in 0..25 {
-            if let Err(e) = self.sock.send_to(msg, addr) {
+            if let Err(e) = sock.send_to(msg, addr) {
                 if e.raw_os_error().map(|c| c != 11).unwrap_or(true) {
                     error!("Failed to send message on UDP socket: {:?}", e);
                     break;
@@ -161,3 +369,81 @@ impl Manager {
         }
     }
 }
+
+impl Dht {
+    fn new(
+        reg: &amy::Registrar,
+        sock: UdpSocket,
+        session: &'static str,
+        bootstrap: Option<SocketAddr>,
+    ) -> io::Result<Dht> {
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+
+        let p = Path::new(&CONFIG.disk.session[..]).join(session);
+        let mut data = Vec::new();
+        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
+            f.read_to_end(&mut data)?;
+        }
+        let mut table = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
+            t
+        } else {
+            info!("DHT table could not be read from disk, creating new table!");
+            rt::RoutingTable::new()
+        };
+        if !table.is_bootstrapped() {
+            info!("Attempting DHT bootstrap with node: {:?}!", bootstrap);
+            if let Some(addr) = bootstrap {
+                let (msg, _) = table.add_addr(addr);
+                let bootstrap_result = sock.send_to(&msg.encode(), addr);
+            }
+        }
+
+        Ok(Dht {
+            id,
+            sock,
+            table,
+            session,
+        })
+    }
+}
+
+/// Response to an item lookup which couldn't be made, as network
+/// activity is paused.
+fn item_failed(client: usize, serial: u64, target: [u8; 20]) -> tracker::Response {
+    tracker::Response::DHTItem {
+        client,
+        serial,
+        target,
+        item: None,
+    }
+}
+
+/// Loads the key used to sign mutable items from the session
+/// directory, generating it if needed.
+fn load_key() -> io::Result<Ed25519KeyPair> {
+    fn err<E>(_: E) -> io::Error {
+        io_err_val("Invalid DHT key")
+    }
+    let sd = Path::new(&CONFIG.disk.session[..]);
+    let p = sd.join(KEY_FILE);
+    let mut data = Vec::new();
+    if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
+        f.read_to_end(&mut data)?;
+    } else {
+        info!("Generating DHT item signing key");
+        data = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(err)?
+            .as_ref()
+            .to_vec();
+        fs::create_dir_all(sd)?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&p)?
+            .write_all(&data)?;
+    }
+    Ed25519KeyPair::from_pkcs8(&data).map_err(err)
+}
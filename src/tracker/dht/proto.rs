@@ -1,12 +1,24 @@
+use super::item::{Item, Mutable};
 use super::{ID, VERSION};
-use crate::bencode::{self, BEncode};
+use crate::bencode::{self, BEncode, BEncodeRef};
 use crate::util::{addr_to_bytes, bytes_to_addr};
-use crate::CONFIG;
+use crate::LISTEN_PORT;
 use num_bigint::BigUint;
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::sync::atomic;
 // use std::u16;
 
+type Dict<'a> = BTreeMap<&'a [u8], BEncodeRef<'a>>;
+
+/// Limits on decoding KRPC messages, which fit in a UDP packet and only
+/// nest item values any deeper than their lists of peers.
+const LIMITS: bencode::Limits = bencode::Limits {
+    depth: 8,
+    items: 1024,
+    string_len: 4096,
+};
+
 error_chain! {
     errors {
         Generic(r: String) {
@@ -29,6 +41,11 @@ error_chain! {
                 display("method unknown: {}", r)
         }
 
+        Item(code: i64, r: String) {
+            description("item storage error")
+                display("item storage error {}: {}", code, r)
+        }
+
         InvalidResponse(r: &'static str) {
             description("invalid response")
                 display("invalid response: {}", r)
@@ -45,9 +62,18 @@ error_chain! {
 pub struct Request {
     pub transaction: Vec<u8>,
     pub version: Option<String>,
+    pub want: Want,
     pub kind: RequestKind,
 }
 
+/// Address families a query wants nodes for, as specified by BEP 32.
+/// If neither is set, nodes of the query's own family are wanted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Want {
+    pub v4: bool,
+    pub v6: bool,
+}
+
 #[derive(Debug)]
 pub enum RequestKind {
     Ping(ID),
@@ -66,12 +92,25 @@ pub enum RequestKind {
         port: u16,
         implied_port: bool,
     },
+    Get {
+        id: ID,
+        target: [u8; 20],
+        seq: Option<i64>,
+    },
+    Put {
+        id: ID,
+        token: Vec<u8>,
+        item: Item,
+        cas: Option<i64>,
+    },
 }
 
 #[derive(Debug)]
 pub struct Response {
     pub transaction: Vec<u8>,
     pub kind: ResponseKind,
+    /// Address of the querying node as seen by the responder
+    pub ip: Option<SocketAddr>,
 }
 
 #[derive(Debug)]
@@ -87,6 +126,12 @@ pub enum ResponseKind {
         values: Vec<SocketAddr>,
         nodes: Vec<Node>,
     },
+    Get {
+        id: ID,
+        token: Vec<u8>,
+        nodes: Vec<Node>,
+        item: Option<Item>,
+    },
     Error(ErrorKind),
 }
 
@@ -101,6 +146,7 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
+            want: Want::default(),
             kind: RequestKind::Ping(id),
         }
     }
@@ -109,6 +155,7 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
+            want: Want::default(),
             kind: RequestKind::FindNode { id, target },
         }
     }
@@ -117,6 +164,7 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
+            want: Want::default(),
             kind: RequestKind::GetPeers { id, hash },
         }
     }
@@ -125,18 +173,57 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
+            want: Want::default(),
             kind: RequestKind::AnnouncePeer {
                 id,
                 hash,
                 token,
-                port: CONFIG.dht.port,
+                port: LISTEN_PORT.load(atomic::Ordering::Relaxed),
                 implied_port: false,
             },
         }
     }
 
+    pub fn get(transaction: Vec<u8>, id: ID, target: [u8; 20]) -> Self {
+        Request {
+            transaction,
+            version: Some(VERSION.to_owned()),
+            want: Want::default(),
+            kind: RequestKind::Get {
+                id,
+                target,
+                seq: None,
+            },
+        }
+    }
+
+    pub fn put(transaction: Vec<u8>, id: ID, token: Vec<u8>, item: Item) -> Self {
+        Request {
+            transaction,
+            version: Some(VERSION.to_owned()),
+            want: Want::default(),
+            kind: RequestKind::Put {
+                id,
+                token,
+                item,
+                cas: None,
+            },
+        }
+    }
+
+    /// The ID a query looks for the closest nodes to.
+    pub fn target(&self) -> Option<ID> {
+        match self.kind {
+            RequestKind::FindNode { ref target, .. } => Some(target.clone()),
+            RequestKind::GetPeers { ref hash, .. } => Some(BigUint::from_bytes_be(hash)),
+            RequestKind::Get { ref target, .. } => Some(BigUint::from_bytes_be(target)),
+            _ => None,
+        }
+    }
+
     pub fn encode(self) -> Vec<u8> {
         let mut b = BTreeMap::new();
+        let want = self.want;
         b.insert(b"t".to_vec(), BEncode::String(self.transaction));
         b.insert(b"y".to_vec(), BEncode::from_str("q"));
         if let Some(v) = self.version {
@@ -192,26 +279,72 @@ impl Request {
 
                 b.insert(b"a".to_vec(), BEncode::Dict(args));
             }
+            RequestKind::Get { id, target, seq } => {
+                b.insert(b"q".to_vec(), BEncode::from_str("get"));
+
+                let mut args = BTreeMap::new();
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
+                args.insert(b"target".to_vec(), BEncode::String(target.to_vec()));
+                if let Some(seq) = seq {
+                    args.insert(b"seq".to_vec(), BEncode::Int(seq));
+                }
+
+                b.insert(b"a".to_vec(), BEncode::Dict(args));
+            }
+            RequestKind::Put {
+                id,
+                token,
+                item,
+                cas,
+            } => {
+                b.insert(b"q".to_vec(), BEncode::from_str("put"));
+
+                let mut args = BTreeMap::new();
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
+                args.insert(b"token".to_vec(), BEncode::String(token));
+                if let Some(cas) = cas {
+                    args.insert(b"cas".to_vec(), BEncode::Int(cas));
+                }
+                encode_item(&mut args, item);
+
+                b.insert(b"a".to_vec(), BEncode::Dict(args));
+            }
+        }
+        if want.v4 || want.v6 {
+            if let Some(BEncode::Dict(args)) = b.get_mut(b"a".as_ref()) {
+                let mut families = Vec::new();
+                if want.v4 {
+                    families.push(BEncode::from_str("n4"));
+                }
+                if want.v6 {
+                    families.push(BEncode::from_str("n6"));
+                }
+                args.insert(b"want".to_vec(), BEncode::List(families));
+            }
         }
         BEncode::Dict(b).encode_to_buf()
     }
 
     pub fn decode(buf: &[u8]) -> Result<Self> {
-        let b: BEncode = bencode::decode_buf(buf)
+        let b = bencode::decode_ref_with(buf, &LIMITS)
             .chain_err(|| ErrorKind::InvalidRequest("Invalid BEncoded data"))?;
-        let mut d = b
-            .into_dict()
+        let d = b
+            .as_dict()
             .ok_or_else(|| ErrorKind::InvalidRequest("Invalid BEncoded data(must be dict)"))?;
         let transaction = d
-            .remove(b"t".as_ref())
-            .and_then(|b| b.into_bytes())
+            .get(b"t".as_ref())
+            .and_then(BEncodeRef::as_bytes)
+            .map(<[u8]>::to_vec)
             .ok_or_else(|| {
                 ErrorKind::InvalidRequest("Invalid BEncoded data(dict must have t field)")
             })?;
-        let version = d.remove(b"v".as_ref()).and_then(|b| b.into_string());
+        let version = d
+            .get(b"v".as_ref())
+            .and_then(BEncodeRef::as_str)
+            .map(str::to_owned);
         let y = d
-            .remove(b"y".as_ref())
-            .and_then(|b| b.into_string())
+            .get(b"y".as_ref())
+            .and_then(BEncodeRef::as_str)
             .ok_or_else(|| {
                 Error::from(ErrorKind::InvalidRequest(
                     "Invalid BEncoded data(dict must have y field)",
@@ -223,36 +356,46 @@ impl Request {
             )));
         }
         let q = d
-            .remove(b"q".as_ref())
-            .and_then(|b| b.into_string())
+            .get(b"q".as_ref())
+            .and_then(BEncodeRef::as_str)
             .ok_or_else(|| {
                 Error::from(ErrorKind::InvalidRequest(
                     "Invalid BEncoded data(dict must have q field)",
                 ))
             })?;
-        let mut a = d
-            .remove(b"a".as_ref())
-            .and_then(|b| b.into_dict())
+        let a = d
+            .get(b"a".as_ref())
+            .and_then(BEncodeRef::as_dict)
             .ok_or_else(|| {
                 Error::from(ErrorKind::InvalidRequest(
                     "Invalid BEncoded data(dict must have a field)",
                 ))
             })?;
         let id = a
-            .remove(b"id".as_ref())
-            .and_then(|b| b.into_bytes())
+            .get(b"id".as_ref())
+            .and_then(BEncodeRef::as_bytes)
             .and_then(|b| b.get(0..20).map(BigUint::from_bytes_be))
             .ok_or_else(|| {
                 Error::from(ErrorKind::InvalidRequest(
                     "Invalid BEncoded data(ping must have id field)",
                 ))
             })?;
-        let kind = match &q[..] {
+        let mut want = Want::default();
+        if let Some(families) = a.get(b"want".as_ref()).and_then(BEncodeRef::as_list) {
+            for family in families {
+                match family.as_str() {
+                    Some("n4") => want.v4 = true,
+                    Some("n6") => want.v6 = true,
+                    _ => {}
+                }
+            }
+        }
+        let kind = match q {
             "ping" => RequestKind::Ping(id),
             "find_node" => {
                 let target = a
-                    .remove(b"target".as_ref())
-                    .and_then(|b| b.into_bytes())
+                    .get(b"target".as_ref())
+                    .and_then(BEncodeRef::as_bytes)
                     .and_then(|b| b.get(0..20).map(BigUint::from_bytes_be))
                     .ok_or_else(|| {
                         Error::from(ErrorKind::InvalidRequest(
@@ -263,8 +406,8 @@ impl Request {
             }
             "get_peers" => {
                 let mut hash = [0u8; 20];
-                a.remove(b"info_hash".as_ref())
-                    .and_then(|b| b.into_bytes())
+                a.get(b"info_hash".as_ref())
+                    .and_then(BEncodeRef::as_bytes)
                     .and_then(|b| {
                         if b.len() != 20 {
                             return None;
@@ -281,8 +424,8 @@ impl Request {
             }
             "announce_peer" => {
                 let mut hash = [0u8; 20];
-                a.remove(b"info_hash".as_ref())
-                    .and_then(|b| b.into_bytes())
+                a.get(b"info_hash".as_ref())
+                    .and_then(BEncodeRef::as_bytes)
                     .and_then(|b| {
                         if b.len() != 20 {
                             return None;
@@ -296,13 +439,13 @@ impl Request {
                         ))
                     })?;
                 let implied_port = a
-                    .remove(b"implied_port".as_ref())
-                    .and_then(|b| b.into_int())
+                    .get(b"implied_port".as_ref())
+                    .and_then(BEncodeRef::as_int)
                     .map(|b| b > 0)
                     .unwrap_or(false);
                 let port = a
-                    .remove(b"port".as_ref())
-                    .and_then(|b| b.into_int())
+                    .get(b"port".as_ref())
+                    .and_then(BEncodeRef::as_int)
                     .and_then(|b| {
                         if b > 65_535 || b < 0 {
                             None
@@ -316,8 +459,9 @@ impl Request {
                         ))
                     })?;
                 let token = a
-                    .remove(b"token".as_ref())
-                    .and_then(|b| b.into_bytes())
+                    .get(b"token".as_ref())
+                    .and_then(BEncodeRef::as_bytes)
+                    .map(<[u8]>::to_vec)
                     .ok_or_else(|| {
                         Error::from(ErrorKind::InvalidRequest(
                             "Invalid BEncoded data(announce_peer must have port field)",
@@ -331,6 +475,48 @@ impl Request {
                     token,
                 }
             }
+            "get" => {
+                let mut target = [0u8; 20];
+                a.get(b"target".as_ref())
+                    .and_then(BEncodeRef::as_bytes)
+                    .and_then(|b| {
+                        if b.len() != 20 {
+                            return None;
+                        }
+                        target.copy_from_slice(&b[..20]);
+                        Some(())
+                    })
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidRequest(
+                            "Invalid BEncoded data(get must have target field)",
+                        ))
+                    })?;
+                let seq = a.get(b"seq".as_ref()).and_then(BEncodeRef::as_int);
+                RequestKind::Get { id, target, seq }
+            }
+            "put" => {
+                let token = a
+                    .get(b"token".as_ref())
+                    .and_then(BEncodeRef::as_bytes)
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidRequest(
+                            "Invalid BEncoded data(put must have token field)",
+                        ))
+                    })?;
+                let cas = a.get(b"cas".as_ref()).and_then(BEncodeRef::as_int);
+                let item = decode_item(a).ok_or_else(|| {
+                    Error::from(ErrorKind::InvalidRequest(
+                        "Invalid BEncoded data(put must have a valid item)",
+                    ))
+                })?;
+                RequestKind::Put {
+                    id,
+                    token,
+                    item,
+                    cas,
+                }
+            }
             _ => {
                 return Err(ErrorKind::InvalidRequest(
                     "Invalid BEncoded data(request must be a valid query type)",
@@ -341,6 +527,7 @@ impl Request {
         Ok(Request {
             transaction,
             version,
+            want,
             kind,
         })
     }
@@ -350,6 +537,7 @@ impl Response {
     pub fn id(transaction: Vec<u8>, id: ID) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::ID(id),
         }
     }
@@ -357,6 +545,7 @@ impl Response {
     pub fn find_node(transaction: Vec<u8>, id: ID, nodes: Vec<Node>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::FindNode { id, nodes },
         }
     }
@@ -364,6 +553,7 @@ impl Response {
     pub fn peers(transaction: Vec<u8>, id: ID, token: Vec<u8>, nodes: Vec<SocketAddr>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::GetPeers {
                 id,
                 token,
@@ -376,6 +566,7 @@ impl Response {
     pub fn nodes(transaction: Vec<u8>, id: ID, token: Vec<u8>, nodes: Vec<Node>) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::GetPeers {
                 id,
                 token,
@@ -385,9 +576,61 @@ impl Response {
         }
     }
 
+    pub fn item(
+        transaction: Vec<u8>,
+        id: ID,
+        token: Vec<u8>,
+        nodes: Vec<Node>,
+        item: Option<Item>,
+    ) -> Self {
+        Response {
+            transaction,
+            ip: None,
+            kind: ResponseKind::Get {
+                id,
+                token,
+                nodes,
+                item,
+            },
+        }
+    }
+
+    /// Adds nodes and peers from the routing table of the other
+    /// address family to the response.
+    pub fn extend(&mut self, extra: Vec<Node>, peers: Vec<SocketAddr>) {
+        match self.kind {
+            ResponseKind::FindNode { ref mut nodes, .. }
+            | ResponseKind::Get { ref mut nodes, .. } => nodes.extend(extra),
+            ResponseKind::GetPeers {
+                ref mut nodes,
+                ref mut values,
+                ..
+            } => {
+                nodes.extend(extra);
+                values.extend(peers);
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes and returns the nodes of the given address family.
+    pub fn split_nodes(&mut self, v6: bool) -> Vec<Node> {
+        match self.kind {
+            ResponseKind::FindNode { ref mut nodes, .. }
+            | ResponseKind::GetPeers { ref mut nodes, .. }
+            | ResponseKind::Get { ref mut nodes, .. } => {
+                let (split, rest) = nodes.drain(..).partition(|n| n.addr.is_ipv6() == v6);
+                *nodes = rest;
+                split
+            }
+            _ => Vec::new(),
+        }
+    }
+
     pub fn error(transaction: Vec<u8>, error: ErrorKind) -> Self {
         Response {
             transaction,
+            ip: None,
             kind: ResponseKind::Error(error),
         }
     }
@@ -396,17 +639,16 @@ impl Response {
         let mut b = BTreeMap::new();
         let is_err = self.is_err();
         b.insert(b"t".to_vec(), BEncode::String(self.transaction));
+        if let Some(ip) = self.ip {
+            b.insert(b"ip".to_vec(), BEncode::String(addr_to_bytes(&ip)));
+        }
         let mut args = BTreeMap::new();
         match self.kind {
             ResponseKind::ID(id) => {
                 args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::FindNode { id, nodes } => {
-                let mut data = Vec::new();
-                for node in nodes {
-                    data.extend(node.to_bytes())
-                }
-                args.insert(b"nodes".to_vec(), BEncode::String(data));
+                encode_nodes(&mut args, nodes);
                 args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::GetPeers {
@@ -419,15 +661,24 @@ impl Response {
                 args.insert(b"token".to_vec(), BEncode::String(token));
                 let mut values_b = Vec::new();
                 for addr in values {
-                    values_b.push(BEncode::String(addr_to_bytes(&addr).to_vec()));
+                    values_b.push(BEncode::String(addr_to_bytes(&addr)));
                 }
                 args.insert(b"values".to_vec(), BEncode::List(values_b));
 
-                let mut nodes_b = Vec::new();
-                for node in nodes {
-                    nodes_b.extend(node.to_bytes())
+                encode_nodes(&mut args, nodes);
+            }
+            ResponseKind::Get {
+                id,
+                token,
+                nodes,
+                item,
+            } => {
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
+                args.insert(b"token".to_vec(), BEncode::String(token));
+                encode_nodes(&mut args, nodes);
+                if let Some(item) = item {
+                    encode_item(&mut args, item);
                 }
-                args.insert(b"nodes".to_vec(), BEncode::String(nodes_b));
             }
             ResponseKind::Error(e) => {
                 let mut err = Vec::new();
@@ -448,6 +699,10 @@ impl Response {
                         err.push(BEncode::from_int(204));
                         err.push(BEncode::from_str(&msg));
                     }
+                    ErrorKind::Item(code, msg) => {
+                        err.push(BEncode::from_int(code));
+                        err.push(BEncode::from_str(&msg));
+                    }
                     _ => unreachable!(),
                 }
                 b.insert(b"e".to_vec(), BEncode::List(err));
@@ -463,34 +718,40 @@ impl Response {
     }
 
     pub fn decode(buf: &[u8]) -> Result<Self> {
-        let b: BEncode = bencode::decode_buf(buf)
+        let b = bencode::decode_ref_with(buf, &LIMITS)
             .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded data"))?;
-        let mut d = b.into_dict().ok_or_else(|| {
+        let d = b.as_dict().ok_or_else(|| {
             Error::from(ErrorKind::InvalidResponse(
                 "Invalid BEncoded data(must be dict)",
             ))
         })?;
         let transaction = d
-            .remove(b"t".as_ref())
-            .and_then(|b| b.into_bytes())
+            .get(b"t".as_ref())
+            .and_then(BEncodeRef::as_bytes)
+            .map(<[u8]>::to_vec)
             .ok_or_else(|| {
                 Error::from(ErrorKind::InvalidResponse(
                     "Invalid BEncoded data(dict must have t field)",
                 ))
             })?;
+        let ip = d
+            .get(b"ip".as_ref())
+            .and_then(BEncodeRef::as_bytes)
+            .filter(|b| b.len() == 6 || b.len() == 18)
+            .map(bytes_to_addr);
         let y = d
-            .remove(b"y".as_ref())
-            .and_then(|b| b.into_string())
+            .get(b"y".as_ref())
+            .and_then(BEncodeRef::as_str)
             .ok_or_else(|| {
                 Error::from(ErrorKind::InvalidResponse(
                     "Invalid BEncoded data(dict must have y field)",
                 ))
             })?;
-        match &y[..] {
+        match y {
             "e" => {
-                let mut e = d
-                    .remove(b"e".as_ref())
-                    .and_then(|b| b.into_list())
+                let e = d
+                    .get(b"e".as_ref())
+                    .and_then(BEncodeRef::as_list)
                     .ok_or_else(|| {
                         Error::from(ErrorKind::InvalidResponse(
                             "Invalid BEncoded data(error resp must have e field)",
@@ -502,12 +763,12 @@ impl Response {
                     )
                     .into());
                 }
-                let code = e.remove(0).into_int().ok_or_else(|| {
+                let code = e[0].as_int().ok_or_else(|| {
                     Error::from(ErrorKind::InvalidResponse(
                         "Invalid BEncoded data(e field must start with integer code)",
                     ))
                 })?;
-                let msg = e.remove(0).into_string().ok_or_else(|| {
+                let msg = e[1].as_str().map(str::to_owned).ok_or_else(|| {
                     Error::from(ErrorKind::InvalidResponse(
                         "Invalid BEncoded data(e field must end with string data)",
                     ))
@@ -517,6 +778,7 @@ impl Response {
                     202 => ErrorKind::Server(msg),
                     203 => ErrorKind::Protocol(msg),
                     204 => ErrorKind::MethodUnknown(msg),
+                    205..=207 | 301 | 302 => ErrorKind::Item(code, msg),
                     _ => {
                         return Err(ErrorKind::InvalidResponse(
                             "Invalid BEncoded data(invalid error code)",
@@ -527,12 +789,13 @@ impl Response {
                 Ok(Response {
                     transaction,
                     kind: ResponseKind::Error(err),
+                    ip,
                 })
             }
             "r" => {
-                let mut r = d
-                    .remove(b"r".as_ref())
-                    .and_then(|b| b.into_dict())
+                let r = d
+                    .get(b"r".as_ref())
+                    .and_then(BEncodeRef::as_dict)
                     .ok_or_else(|| {
                         Error::from(ErrorKind::InvalidResponse(
                             "Invalid BEncoded data(resp must have r field)",
@@ -540,8 +803,8 @@ impl Response {
                     })?;
 
                 let id = r
-                    .remove(b"id".as_ref())
-                    .and_then(|b| b.into_bytes())
+                    .get(b"id".as_ref())
+                    .and_then(BEncodeRef::as_bytes)
                     .and_then(|b| b.get(0..20).map(BigUint::from_bytes_be))
                     .ok_or_else(|| {
                         Error::from(ErrorKind::InvalidResponse(
@@ -550,44 +813,51 @@ impl Response {
                     })?;
 
                 let kind = if let Some(token) =
-                    r.remove(b"token".as_ref()).and_then(|b| b.into_bytes())
+                    r.get(b"token".as_ref()).and_then(BEncodeRef::as_bytes)
                 {
+                    let token = token.to_vec();
+                    if r.contains_key(b"v".as_ref()) {
+                        let nodes = decode_nodes(r);
+                        let item = decode_item(r);
+                        return Ok(Response {
+                            transaction,
+                            kind: ResponseKind::Get {
+                                id,
+                                token,
+                                nodes,
+                                item,
+                            },
+                            ip,
+                        });
+                    }
                     let mut values = Vec::new();
-                    if let Some(addrs) = r.remove(b"values".as_ref()).and_then(|b| b.into_list()) {
+                    if let Some(addrs) = r.get(b"values".as_ref()).and_then(BEncodeRef::as_list) {
                         for addr in addrs {
-                            if let Some(data) = addr.into_bytes() {
-                                if data.len() == 6 {
-                                    values.push(bytes_to_addr(&data));
+                            if let Some(data) = addr.as_bytes() {
+                                if data.len() == 6 || data.len() == 18 {
+                                    values.push(bytes_to_addr(data));
                                 }
                             }
                         }
                     }
-                    let mut nodes = Vec::new();
-                    if let Some(ns) = r.remove(b"nodes".as_ref()).and_then(|b| b.into_bytes()) {
-                        for n in ns.chunks(26) {
-                            if n.len() == 26 {
-                                nodes.push(Node::new(n));
-                            }
-                        }
-                    }
+                    let nodes = decode_nodes(r);
                     ResponseKind::GetPeers {
                         id,
                         token,
                         nodes,
                         values,
                     }
-                } else if let Some(ns) = r.remove(b"nodes".as_ref()).and_then(|b| b.into_bytes()) {
-                    let mut nodes = Vec::new();
-                    for n in ns.chunks(26) {
-                        if n.len() == 26 {
-                            nodes.push(Node::new(n));
-                        }
-                    }
+                } else if r.contains_key(b"nodes".as_ref()) || r.contains_key(b"nodes6".as_ref()) {
+                    let nodes = decode_nodes(r);
                     ResponseKind::FindNode { id, nodes }
                 } else {
                     ResponseKind::ID(id)
                 };
-                Ok(Response { transaction, kind })
+                Ok(Response {
+                    transaction,
+                    kind,
+                    ip,
+                })
             }
             _ => {
                 Err(ErrorKind::InvalidResponse("Invalid BEncoded data(y field must be e/r)").into())
@@ -603,6 +873,75 @@ impl Response {
     }
 }
 
+/// Decodes compact IPv4 and IPv6 node lists.
+fn decode_nodes(d: &Dict<'_>) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    for &(key, len) in &[(b"nodes".as_ref(), 26), (b"nodes6".as_ref(), 38)] {
+        if let Some(ns) = d.get(key).and_then(BEncodeRef::as_bytes) {
+            for n in ns.chunks(len) {
+                if n.len() == len {
+                    nodes.push(Node::new(n));
+                }
+            }
+        }
+    }
+    nodes
+}
+
+/// Encodes nodes compactly, with IPv6 nodes stored under
+/// nodes6 as specified by BEP 32.
+fn encode_nodes(d: &mut BTreeMap<Vec<u8>, BEncode>, nodes: Vec<Node>) {
+    let (nodes6, nodes): (Vec<Node>, Vec<Node>) = nodes.into_iter().partition(|n| n.addr.is_ipv6());
+    let mut data = Vec::new();
+    for node in nodes {
+        data.extend(node.to_bytes())
+    }
+    d.insert(b"nodes".to_vec(), BEncode::String(data));
+    if !nodes6.is_empty() {
+        let mut data = Vec::new();
+        for node in nodes6 {
+            data.extend(node.to_bytes())
+        }
+        d.insert(b"nodes6".to_vec(), BEncode::String(data));
+    }
+}
+
+/// Decodes the item carried by a put query or get response.
+fn decode_item(d: &Dict<'_>) -> Option<Item> {
+    let value = d.get(b"v".as_ref())?.encode_to_buf();
+    let mutable = match d.get(b"k".as_ref()).and_then(BEncodeRef::as_bytes) {
+        Some(key) => Some(Mutable {
+            key: key.to_vec(),
+            sig: d
+                .get(b"sig".as_ref())
+                .and_then(BEncodeRef::as_bytes)?
+                .to_vec(),
+            seq: d.get(b"seq".as_ref()).and_then(BEncodeRef::as_int)?,
+            salt: d
+                .get(b"salt".as_ref())
+                .and_then(BEncodeRef::as_bytes)
+                .map(<[u8]>::to_vec)
+                .unwrap_or_default(),
+        }),
+        None => None,
+    };
+    Some(Item { value, mutable })
+}
+
+fn encode_item(d: &mut BTreeMap<Vec<u8>, BEncode>, item: Item) {
+    if let Ok(v) = bencode::decode_buf(&item.value) {
+        d.insert(b"v".to_vec(), v);
+    }
+    if let Some(m) = item.mutable {
+        d.insert(b"k".to_vec(), BEncode::String(m.key));
+        d.insert(b"sig".to_vec(), BEncode::String(m.sig));
+        d.insert(b"seq".to_vec(), BEncode::Int(m.seq));
+        if !m.salt.is_empty() {
+            d.insert(b"salt".to_vec(), BEncode::String(m.salt));
+        }
+    }
+}
+
 impl Node {
     pub fn new(data: &[u8]) -> Node {
         let id = BigUint::from_bytes_be(&data[0..20]);
@@ -614,7 +953,7 @@ impl Node {
 
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut data = self.id.to_bytes_be();
-        data.extend_from_slice(&addr_to_bytes(&self.addr)[..]);
+        data.extend(addr_to_bytes(&self.addr));
         data
     }
 }
@@ -1,14 +1,24 @@
-use super::{proto, BUCKET_MAX, ID, MAX_BUCKETS, MIN_BOOTSTRAP_BKTS, TX_TIMEOUT_SECS};
+use super::item::{Item, MAX_SALT_LEN, MAX_VALUE_LEN};
+use super::{proto, security, BUCKET_MAX, ID, MAX_BUCKETS, MIN_BOOTSTRAP_BKTS, TX_TIMEOUT_SECS};
 use crate::tracker;
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use chrono::{DateTime, Utc};
 use num_bigint::BigUint;
 use rand::{self, Rng};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::{cmp, mem};
 
 const MAX_SEARCH_DEPTH: u8 = 5;
+const MAX_ITEMS: usize = 1024;
+const ITEM_TTL_MINS: i64 = 120;
+const MAX_LOOKUP_NODES: usize = 64;
+/// Number of nodes which must report our external IP before
+/// a node ID valid for it is generated
+const IP_VOTES: usize = 10;
+
+/// Node which responded to a search, along with its write token
+type Responder = (ID, SocketAddr, Vec<u8>);
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RoutingTable {
@@ -21,6 +31,23 @@ pub struct RoutingTable {
     transactions: HashMap<u32, Transaction>,
     torrents: HashMap<[u8; 20], Torrent>,
     bootstrapping: bool,
+    #[serde(skip)]
+    items: HashMap<[u8; 20], StoredItem>,
+    #[serde(skip)]
+    lookups: HashMap<usize, Lookup>,
+    #[serde(skip)]
+    next_lookup: usize,
+    /// Torrents being searched for, along with the nodes which responded
+    /// and their write tokens. Torrents are announced to the closest
+    /// nodes once their search ends.
+    #[serde(skip)]
+    announces: HashMap<[u8; 20], Vec<Responder>>,
+    /// External IPs reported by responding nodes
+    #[serde(skip)]
+    ip_votes: HashMap<IpAddr, IpAddr>,
+    /// External IP most nodes agreed on, until it's taken
+    #[serde(skip)]
+    external_ip: Option<IpAddr>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -39,6 +66,11 @@ enum TransactionKind {
         hash: [u8; 20],
         depth: u8,
     },
+    ItemSearch {
+        id: ID,
+        lookup: usize,
+        depth: u8,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -46,6 +78,27 @@ struct Torrent {
     peers: Vec<(ID, SocketAddr)>,
 }
 
+#[derive(Clone, Debug)]
+struct StoredItem {
+    item: Item,
+    stored: DateTime<Utc>,
+}
+
+/// A search for the nodes closest to an item's target.
+#[derive(Clone, Debug)]
+pub struct Lookup {
+    pub client: usize,
+    pub serial: u64,
+    pub target: [u8; 20],
+    /// Item to store on the closest nodes once they are found
+    pub put: Option<Item>,
+    /// Most recent valid item which was found
+    pub item: Option<Item>,
+    queried: HashSet<SocketAddr>,
+    /// Nodes which responded along with their write tokens
+    responded: Vec<(ID, SocketAddr, Vec<u8>)>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Bucket {
     start: ID,
@@ -90,6 +143,12 @@ impl RoutingTable {
             transactions: HashMap::new(),
             torrents: HashMap::new(),
             bootstrapping: true,
+            items: HashMap::new(),
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            announces: HashMap::new(),
+            ip_votes: HashMap::new(),
+            external_ip: None,
         }
     }
 
@@ -135,21 +194,113 @@ impl RoutingTable {
         reqs
     }
 
-    pub fn announce(&mut self, hash: [u8; 20]) -> Vec<(proto::Request, SocketAddr)> {
-        let mut nodes: Vec<(proto::Node, Vec<u8>)> = Vec::new();
-        for bucket in &self.buckets {
-            for node in &bucket.nodes {
-                if let Some(ref tok) = node.rem_token {
-                    nodes.push((node.into(), tok.clone()))
-                }
+    /// Peers which have announced themselves for a torrent.
+    pub fn peers(&self, hash: &[u8; 20]) -> Vec<SocketAddr> {
+        self.torrents
+            .get(hash)
+            .map(|t| t.peers.iter().map(|p| p.1).collect())
+            .unwrap_or_default()
+    }
+
+    /// Searches for peers of a torrent, announcing it to the closest
+    /// nodes found once the search ends.
+    pub fn announce(
+        &mut self,
+        torrent: usize,
+        hash: [u8; 20],
+    ) -> Vec<(proto::Request, SocketAddr)> {
+        self.announces.insert(hash, Vec::new());
+        self.get_peers(torrent, hash)
+    }
+
+    /// Announces torrents whose search has no outstanding queries,
+    /// using the tokens the closest responding nodes gave.
+    pub fn finished_announces(&mut self) -> Vec<(proto::Request, SocketAddr)> {
+        if self.announces.is_empty() {
+            return Vec::new();
+        }
+        let active: HashSet<[u8; 20]> = self
+            .transactions
+            .values()
+            .filter_map(|tx| match tx.kind {
+                TransactionKind::TSearch { hash, .. } => Some(hash),
+                _ => None,
+            })
+            .collect();
+        let done: Vec<[u8; 20]> = self
+            .announces
+            .keys()
+            .filter(|h| !active.contains(*h))
+            .cloned()
+            .collect();
+
+        let mut reqs = Vec::new();
+        for hash in done {
+            let mut nodes = self.announces.remove(&hash).unwrap();
+            let target = BigUint::from_bytes_be(&hash[..]);
+            nodes.sort_by_key(|n| &n.0 ^ &target);
+            nodes.dedup_by(|a, b| a.0 == b.0);
+            nodes.truncate(BUCKET_MAX);
+            for (id, addr, token) in nodes {
+                let tx = self.new_query_tx(id);
+                let req = proto::Request::announce(tx, self.id.clone(), hash, token);
+                reqs.push((req, addr));
             }
         }
+        reqs
+    }
+
+    pub fn lookup(&mut self, mut lookup: Lookup) -> Vec<(proto::Request, SocketAddr)> {
+        let lid = self.next_lookup;
+        self.next_lookup = self.next_lookup.wrapping_add(1);
+        let target = BigUint::from_bytes_be(&lookup.target[..]);
 
         let mut reqs = Vec::new();
-        for (node, tok) in nodes {
-            let tx = self.new_query_tx(node.id);
-            let req = proto::Request::announce(tx, self.id.clone(), hash, tok);
-            reqs.push((req, node.addr));
+        for node in self.closest_nodes(&target, BUCKET_MAX) {
+            lookup.queried.insert(node.addr);
+            let tx = self.new_item_tx(node.id, lid, 0);
+            reqs.push((
+                proto::Request::get(tx, self.id.clone(), lookup.target),
+                node.addr,
+            ));
+        }
+        self.lookups.insert(lid, lookup);
+        reqs
+    }
+
+    /// Removes and returns all lookups with no outstanding queries.
+    pub fn finished_lookups(&mut self) -> Vec<Lookup> {
+        let active: HashSet<usize> = self
+            .transactions
+            .values()
+            .filter_map(|tx| match tx.kind {
+                TransactionKind::ItemSearch { lookup, .. } => Some(lookup),
+                _ => None,
+            })
+            .collect();
+        let done: Vec<usize> = self
+            .lookups
+            .keys()
+            .filter(|l| !active.contains(l))
+            .cloned()
+            .collect();
+        done.into_iter()
+            .filter_map(|l| self.lookups.remove(&l))
+            .collect()
+    }
+
+    /// Stores an item on the closest nodes which responded to a lookup.
+    pub fn put_item(&mut self, lookup: &Lookup, item: &Item) -> Vec<(proto::Request, SocketAddr)> {
+        let target = BigUint::from_bytes_be(&lookup.target[..]);
+        let mut nodes = lookup.responded.clone();
+        nodes.sort_by_key(|n| (!security::is_secure(&n.0, n.1.ip()), &n.0 ^ &target));
+        nodes.truncate(BUCKET_MAX);
+
+        let mut reqs = Vec::new();
+        for (id, addr, token) in nodes {
+            let tx = self.new_query_tx(id);
+            let req = proto::Request::put(tx, self.id.clone(), token, item.clone());
+            reqs.push((req, addr));
         }
         reqs
     }
@@ -242,6 +393,58 @@ impl RoutingTable {
                     proto::Response::nodes(req.transaction, self.id.clone(), token, nodes)
                 }
             }
+            proto::RequestKind::Get { id, target, seq } => {
+                if !self.contains_id(&id) {
+                    let n = Node::new(id.clone(), addr);
+                    if self.add_node(n).is_err() {
+                        // This will be processed immediately after.
+                    }
+                }
+                let token = if !self.contains_id(&id) {
+                    return proto::Response::error(
+                        req.transaction,
+                        proto::ErrorKind::Protocol("Unregistered peer!".to_owned()),
+                    );
+                } else {
+                    self.get_node(&id).token.clone()
+                };
+                let nodes = self.closest_nodes(&BigUint::from_bytes_be(&target[..]), BUCKET_MAX);
+                // Only send the item if it is newer than the requester's copy
+                let item = self.items.get(&target).map(|s| s.item.clone()).filter(|i| {
+                    match (i.seq(), seq) {
+                        (Some(cur), Some(seq)) => cur > seq,
+                        _ => true,
+                    }
+                });
+                proto::Response::item(req.transaction, self.id.clone(), token, nodes, item)
+            }
+            proto::RequestKind::Put {
+                id,
+                token,
+                item,
+                cas,
+            } => {
+                if !self.contains_id(&id) {
+                    return proto::Response::error(
+                        req.transaction,
+                        proto::ErrorKind::Protocol("Unregistered peer!".to_owned()),
+                    );
+                }
+                {
+                    let node = self.get_node_mut(&id);
+                    if !node.token_valid(&token) {
+                        return proto::Response::error(
+                            req.transaction,
+                            proto::ErrorKind::Protocol("Bad token!".to_owned()),
+                        );
+                    }
+                    node.update();
+                }
+                if let Err(e) = self.store_item(item, cas) {
+                    return proto::Response::error(req.transaction, e);
+                }
+                proto::Response::id(req.transaction, self.id.clone())
+            }
         }
     }
 
@@ -261,6 +464,9 @@ impl RoutingTable {
         } else {
             return Err(reqs);
         };
+        if let Some(ip) = resp.ip {
+            reqs.extend(self.vote_ip(addr.ip(), ip.ip()));
+        }
 
         match (tx.kind, resp.kind) {
             (TransactionKind::Initialization, proto::ResponseKind::ID(id)) => {
@@ -347,6 +553,9 @@ impl RoutingTable {
                     ref mut token,
                 },
             ) if id1 == id2 => {
+                if let Some(responded) = self.announces.get_mut(&hash) {
+                    responded.push((id1.clone(), addr, token.clone()));
+                }
                 if self.contains_id(id1) {
                     let node = self.get_node_mut(id1);
                     node.update();
@@ -376,6 +585,10 @@ impl RoutingTable {
                 }
             }
 
+            (TransactionKind::ItemSearch { id, lookup, depth }, kind) => {
+                reqs = self.handle_item_resp(id, lookup, depth, kind, addr);
+            }
+
             (TransactionKind::Query(id), proto::ResponseKind::Error(_)) => {
                 if !self.contains_id(&id) {
                     return Err(reqs);
@@ -387,6 +600,7 @@ impl RoutingTable {
             (TransactionKind::Query(id), proto::ResponseKind::ID(_))
             | (TransactionKind::Query(id), proto::ResponseKind::FindNode { .. })
             | (TransactionKind::Query(id), proto::ResponseKind::GetPeers { .. })
+            | (TransactionKind::Query(id), proto::ResponseKind::Get { .. })
             | (TransactionKind::TSearch { id, .. }, proto::ResponseKind::GetPeers { .. })
             | (TransactionKind::TSearch { id, .. }, _) => {
                 self.remove_node(&id);
@@ -420,6 +634,9 @@ impl RoutingTable {
 
         let dur = Utc::now().signed_duration_since(self.last_token_refresh);
         let tok_refresh = dur.num_minutes() > 5;
+        if tok_refresh {
+            self.last_token_refresh = Utc::now();
+        }
 
         for bucket in &mut self.buckets {
             for node in &mut bucket.nodes {
@@ -444,6 +661,9 @@ impl RoutingTable {
             let tx = self.new_query_tx(node.id);
             reqs.push((proto::Request::ping(tx, self.id.clone()), node.addr));
         }
+        self.items.retain(|_, s| {
+            Utc::now().signed_duration_since(s.stored).num_minutes() < ITEM_TTL_MINS
+        });
         let buckets = &self.buckets;
         self.torrents.retain(|_, t| {
             t.peers.retain(|p| {
@@ -482,6 +702,186 @@ impl RoutingTable {
         reqs
     }
 
+    fn handle_item_resp(
+        &mut self,
+        id1: ID,
+        lookup: usize,
+        depth: u8,
+        resp: proto::ResponseKind,
+        addr: SocketAddr,
+    ) -> Vec<(proto::Request, SocketAddr)> {
+        let mut reqs = Vec::new();
+        let (id2, token, nodes, item) = match resp {
+            proto::ResponseKind::Get {
+                id,
+                token,
+                nodes,
+                item,
+            } => (id, token, nodes, item),
+            proto::ResponseKind::GetPeers {
+                id, token, nodes, ..
+            } => (id, token, nodes, None),
+            _ => {
+                self.remove_node(&id1);
+                return reqs;
+            }
+        };
+        if id1 != id2 {
+            self.remove_node(&id1);
+            return reqs;
+        }
+        if self.contains_id(&id1) {
+            self.get_node_mut(&id1).update();
+        }
+
+        let l = match self.lookups.get_mut(&lookup) {
+            Some(l) => l,
+            None => return reqs,
+        };
+        if let Some(item) = item.filter(|i| i.target() == l.target && i.valid()) {
+            if l.item
+                .as_ref()
+                .map(|i| item.seq() > i.seq())
+                .unwrap_or(true)
+            {
+                l.item = Some(item);
+            }
+        }
+        let target = BigUint::from_bytes_be(&l.target[..]);
+        let dist = &id1 ^ &target;
+        l.responded.push((id1, addr, token));
+        if depth >= MAX_SEARCH_DEPTH {
+            return reqs;
+        }
+
+        // Continue the search with any nodes closer to the target
+        let mut next = Vec::new();
+        for node in nodes {
+            if l.queried.len() >= MAX_LOOKUP_NODES {
+                break;
+            }
+            if (&node.id ^ &target) < dist && l.queried.insert(node.addr) {
+                next.push(node);
+            }
+        }
+        let hash = l.target;
+        for node in next {
+            let tx = self.new_item_tx(node.id, lookup, depth + 1);
+            reqs.push((proto::Request::get(tx, self.id.clone(), hash), node.addr));
+        }
+        reqs
+    }
+
+    /// Records the external IP reported by a node, switching to a node
+    /// ID which is valid for it once enough nodes agree on the IP.
+    fn vote_ip(&mut self, voter: IpAddr, ip: IpAddr) -> Vec<(proto::Request, SocketAddr)> {
+        self.ip_votes.insert(voter, ip);
+        if self.ip_votes.len() < IP_VOTES {
+            return Vec::new();
+        }
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in self.ip_votes.values() {
+            *counts.entry(*ip).or_insert(0) += 1;
+        }
+        self.ip_votes.clear();
+        let (ip, votes) = counts.into_iter().max_by_key(|&(_, c)| c).unwrap();
+        if votes * 2 <= IP_VOTES {
+            return Vec::new();
+        }
+        self.external_ip = Some(ip);
+        if security::is_secure(&self.id, ip) {
+            return Vec::new();
+        }
+        info!("External IP is {}, generating new DHT node ID!", ip);
+        self.set_id(security::secure_id(ip))
+    }
+
+    /// The external IP nodes last agreed on, if it wasn't taken yet.
+    pub fn take_external_ip(&mut self) -> Option<IpAddr> {
+        self.external_ip.take()
+    }
+
+    /// Changes our node ID, rebuilding the buckets around it.
+    fn set_id(&mut self, id: ID) -> Vec<(proto::Request, SocketAddr)> {
+        self.id = id;
+        let buckets = mem::replace(
+            &mut self.buckets,
+            vec![Bucket::new(BigUint::from(0u8), id_from_pow(160))],
+        );
+        for node in buckets.into_iter().flat_map(|b| b.nodes) {
+            self.add_node(node).ok();
+        }
+        self.bootstrapping = true;
+
+        let mut reqs = Vec::new();
+        for node in self.closest_nodes(&self.id.clone(), BUCKET_MAX) {
+            let tx = self.new_query_tx(node.id);
+            reqs.push((
+                proto::Request::find_node(tx, self.id.clone(), self.id.clone()),
+                node.addr,
+            ));
+        }
+        reqs
+    }
+
+    fn store_item(&mut self, item: Item, cas: Option<i64>) -> Result<(), proto::ErrorKind> {
+        if item.value.len() > MAX_VALUE_LEN {
+            return Err(proto::ErrorKind::Item(
+                205,
+                "Message (v field) too big.".to_owned(),
+            ));
+        }
+        if item.mutable.as_ref().map(|m| m.salt.len()).unwrap_or(0) > MAX_SALT_LEN {
+            return Err(proto::ErrorKind::Item(
+                207,
+                "Salt (salt field) too big.".to_owned(),
+            ));
+        }
+        if !item.valid() {
+            return Err(proto::ErrorKind::Item(206, "Invalid signature".to_owned()));
+        }
+        let target = item.target();
+        if let Some(cur) = self.items.get(&target) {
+            if let (Some(cur_seq), Some(seq)) = (cur.item.seq(), item.seq()) {
+                if cas.map(|c| c != cur_seq).unwrap_or(false) {
+                    return Err(proto::ErrorKind::Item(
+                        301,
+                        "The CAS hash mismatched, re-read value and try again.".to_owned(),
+                    ));
+                }
+                if seq < cur_seq {
+                    return Err(proto::ErrorKind::Item(
+                        302,
+                        "Sequence number less than current.".to_owned(),
+                    ));
+                }
+            }
+        } else if self.items.len() >= MAX_ITEMS {
+            return Err(proto::ErrorKind::Server("Item storage full!".to_owned()));
+        }
+        self.items.insert(
+            target,
+            StoredItem {
+                item,
+                stored: Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn closest_nodes(&self, target: &ID, count: usize) -> Vec<proto::Node> {
+        let mut nodes: Vec<proto::Node> = self
+            .buckets
+            .iter()
+            .flat_map(|b| b.nodes.iter())
+            .map(|n| n.into())
+            .collect();
+        // Rank nodes with IDs invalid for their IP lower
+        nodes.sort_by_key(|n| (!security::is_secure(&n.id, n.addr.ip()), &n.id ^ target));
+        nodes.truncate(count);
+        nodes
+    }
+
     fn get_node_mut(&mut self, id: &ID) -> &mut Node {
         let idx = self.bucket_idx(id);
         let bidx = self.buckets[idx].idx_of(id).unwrap();
@@ -546,6 +946,20 @@ impl RoutingTable {
         tb
     }
 
+    fn new_item_tx(&mut self, id: ID, lookup: usize, depth: u8) -> Vec<u8> {
+        let mut tb = Vec::new();
+        let tid = rand::random::<u32>();
+        tb.write_u32::<BigEndian>(tid).unwrap();
+        self.transactions.insert(
+            tid,
+            Transaction {
+                created: Utc::now(),
+                kind: TransactionKind::ItemSearch { id, lookup, depth },
+            },
+        );
+        tb
+    }
+
     fn add_node(&mut self, node: Node) -> Result<(), ()> {
         let idx = self.bucket_idx(&node.id);
         if self.buckets[idx].full() {
@@ -553,7 +967,7 @@ impl RoutingTable {
                 self.split_bucket(idx);
                 self.add_node(node)
             } else {
-                Err(())
+                self.buckets[idx].replace_insecure(node)
             }
         } else {
             self.buckets[idx].add_node(node);
@@ -604,6 +1018,20 @@ impl RoutingTable {
     }
 }
 
+impl Lookup {
+    pub fn new(client: usize, serial: u64, target: [u8; 20], put: Option<Item>) -> Lookup {
+        Lookup {
+            client,
+            serial,
+            target,
+            put,
+            item: None,
+            queried: HashSet::new(),
+            responded: Vec::new(),
+        }
+    }
+}
+
 impl Bucket {
     fn new(start: ID, end: ID) -> Bucket {
         Bucket {
@@ -627,6 +1055,21 @@ impl Bucket {
         }
     }
 
+    /// Replaces a node whose ID is invalid for its IP with
+    /// the given node, if its ID is valid.
+    fn replace_insecure(&mut self, node: Node) -> Result<(), ()> {
+        if !node.secure() {
+            return Err(());
+        }
+        match self.nodes.iter().position(|n| !n.secure()) {
+            Some(i) => {
+                self.nodes[i] = node;
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
     fn could_hold(&self, id: &ID) -> bool {
         &self.start <= id && id < &self.end
     }
@@ -675,6 +1118,10 @@ impl Node {
         }
     }
 
+    fn secure(&self) -> bool {
+        security::is_secure(&self.id, self.addr.ip())
+    }
+
     fn new_token(&mut self) {
         let new_prev = mem::replace(&mut self.token, Node::create_token());
         self.prev_token = new_prev;
@@ -726,8 +1173,10 @@ fn id_from_pow(pow: usize) -> ID {
 
 #[cfg(test)]
 mod tests {
-    use super::{id_from_pow, Bucket, Node, RoutingTable};
+    use super::{id_from_pow, Bucket, Node, RoutingTable, TransactionKind, BUCKET_MAX};
+    use crate::tracker::dht::proto;
     use num_bigint::BigUint;
+    use std::net::SocketAddr;
 
     #[test]
     fn test_id_from_pow() {
@@ -761,4 +1210,39 @@ mod tests {
         assert_eq!(rt.buckets[0].nodes.len(), 0);
         assert_eq!(rt.buckets[1].nodes.len(), 8);
     }
+
+    #[test]
+    fn test_announce_closest() {
+        let mut rt = RoutingTable::new();
+        let hash = [0u8; 20];
+        assert!(rt.announce(0, hash).is_empty());
+        let responded = rt.announces.get_mut(&hash).unwrap();
+        for i in (0..12u16).rev() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 1000 + i));
+            responded.push((BigUint::from(i), addr, vec![i as u8]));
+        }
+        // Nodes respond to each search query
+        responded.push((
+            BigUint::from(0u8),
+            SocketAddr::from(([127, 0, 0, 1], 1000)),
+            vec![0],
+        ));
+
+        let reqs = rt.finished_announces();
+        assert!(rt.announces.is_empty());
+        assert_eq!(reqs.len(), BUCKET_MAX);
+        for (i, (req, addr)) in reqs.iter().enumerate() {
+            assert_eq!(addr.port(), 1000 + i as u16);
+            match req.kind {
+                proto::RequestKind::AnnouncePeer { ref token, .. } => {
+                    assert_eq!(token, &vec![i as u8])
+                }
+                _ => panic!("expected announce"),
+            }
+        }
+        assert!(rt
+            .transactions
+            .values()
+            .all(|tx| matches!(tx.kind, TransactionKind::Query(_))));
+    }
 }
@@ -0,0 +1,125 @@
+use std::net::IpAddr;
+
+use num_bigint::BigUint;
+use rand::{self, Rng};
+
+use super::ID;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// Generates a random node ID which is valid for the given IP.
+pub fn secure_id(ip: IpAddr) -> ID {
+    let mut rng = rand::thread_rng();
+    let mut id = [0u8; 20];
+    for i in &mut id {
+        *i = rng.gen::<u8>();
+    }
+    let crc = ip_crc(ip, id[19]);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x7);
+    BigUint::from_bytes_be(&id)
+}
+
+/// Checks whether or not a node ID is valid for the IP it uses.
+/// Nodes on local networks are exempt.
+pub fn is_secure(id: &ID, ip: IpAddr) -> bool {
+    if exempt(ip) {
+        return true;
+    }
+    let id = id_bytes(id);
+    let crc = ip_crc(ip, id[19]);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+fn exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback(),
+    }
+}
+
+fn ip_crc(ip: IpAddr, r: u8) -> u32 {
+    let mut data = match ip {
+        IpAddr::V4(ip) => ip
+            .octets()
+            .iter()
+            .zip(V4_MASK.iter())
+            .map(|(b, m)| b & m)
+            .collect::<Vec<u8>>(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .zip(V6_MASK.iter())
+            .map(|(b, m)| b & m)
+            .collect(),
+    };
+    data[0] |= (r & 0x7) << 5;
+    crc32c(&data)
+}
+
+fn id_bytes(id: &ID) -> [u8; 20] {
+    let b = id.to_bytes_be();
+    let mut id = [0u8; 20];
+    let len = b.len().min(20);
+    id[20 - len..].copy_from_slice(&b[b.len() - len..]);
+    id
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from BEP 42, as (ip, random byte, id prefix)
+    const VECTORS: [(&str, u8, [u8; 3]); 5] = [
+        ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+        ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+        ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+        ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+        ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+    ];
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn test_vectors() {
+        for &(ip, r, prefix) in &VECTORS {
+            let ip = ip.parse().unwrap();
+            let mut id = [0u8; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = r;
+            assert!(is_secure(&BigUint::from_bytes_be(&id), ip));
+            id[1] ^= 1;
+            assert!(!is_secure(&BigUint::from_bytes_be(&id), ip));
+        }
+    }
+
+    #[test]
+    fn test_secure_id() {
+        let ip = "124.31.75.21".parse().unwrap();
+        assert!(is_secure(&secure_id(ip), ip));
+        let local = "192.168.1.2".parse().unwrap();
+        assert!(is_secure(&BigUint::from(0u8), local));
+    }
+}
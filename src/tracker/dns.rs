@@ -1,7 +1,14 @@
 use std::io;
 use std::net::{IpAddr, UdpSocket};
+use std::time::Duration;
 
 use crate::tracker::{ErrorKind, Result};
+use crate::util::net;
+use crate::CONFIG;
+
+/// Interval the blocking resolver waits for responses between checking
+/// for timed out queries
+const POLL_MS: u64 = 100;
 
 #[derive(Debug)]
 pub struct QueryResponse {
@@ -13,24 +20,35 @@ pub struct Resolver {
     pub id: usize,
     pub res: adns::Resolver,
     pub sock: UdpSocket,
+    /// Stats last sent to the control thread
+    pub stats: adns::Stats,
 }
 
 impl Resolver {
     pub fn new(reg: &amy::Registrar) -> io::Result<Resolver> {
-        let sock = UdpSocket::bind("0.0.0.0:0")?;
-        sock.set_nonblocking(true)?;
+        let (res, sock) = resolver()?;
         let id = reg.register(&sock, amy::Event::Read)?;
-
         Ok(Resolver {
             id,
             sock,
-            res: adns::Resolver::from_resolv()?,
+            res,
+            stats: adns::Stats::default(),
         })
     }
 
     pub fn new_query(&mut self, id: usize, host: &str) -> io::Result<Option<IpAddr>> {
         self.res.query(&mut self.sock, id, host)
     }
+
+    /// The resolver's stats, if they changed since last called.
+    pub fn stats_changed(&mut self) -> Option<adns::Stats> {
+        let stats = self.res.stats();
+        if stats == self.stats {
+            return None;
+        }
+        self.stats = stats;
+        Some(stats)
+    }
 }
 
 impl From<adns::Response> for QueryResponse {
@@ -45,3 +63,45 @@ impl From<adns::Response> for QueryResponse {
         }
     }
 }
+
+/// Resolver of the configured servers, and a socket to query them with.
+fn resolver() -> io::Result<(adns::Resolver, UdpSocket)> {
+    let mut res = match CONFIG.net.dns_server {
+        Some(server) => adns::Resolver::new(&[server]),
+        None => adns::Resolver::from_resolv()?,
+    };
+    // Servers are queried through one socket, which must be of their
+    // family, so IPv6 ones are only used if there are no others
+    let v6 = !res.retain_family(false);
+    let sock = net::udp_socket(v6, 0)?;
+    Ok((res, sock))
+}
+
+/// Resolves a host from a thread which may block, with the same servers
+/// and socket binding as trackers rather than the system resolver.
+pub fn resolve(host: &str) -> io::Result<IpAddr> {
+    let (mut res, mut sock) = resolver()?;
+    sock.set_nonblocking(false)?;
+    sock.set_read_timeout(Some(Duration::from_millis(POLL_MS)))?;
+    if let Some(ip) = res.query(&mut sock, 0, host)? {
+        return Ok(ip);
+    }
+    loop {
+        let mut result = None;
+        res.read(&mut sock, |resp| result = Some(resp.result))?;
+        res.tick(&mut sock, |resp| result = Some(resp.result))?;
+        match result {
+            Some(Ok(ip)) => return Ok(ip),
+            Some(Err(adns::Error::NotFound)) => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "host not found"));
+            }
+            Some(Err(adns::Error::Timeout)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "DNS query timed out",
+                ));
+            }
+            None => {}
+        }
+    }
+}
@@ -39,5 +39,15 @@ error_chain! {
             description("the tracker url does not correspond to a valid IP address")
                 display("tracker dns invalid")
         }
+
+        ScrapeUnsupported {
+            description("the tracker url has no scrape counterpart")
+                display("tracker doesn't support scrape")
+        }
+
+        NetworkDown {
+            description("the interface sockets are bound to is unavailable")
+                display("bound interface unavailable")
+        }
     }
 }
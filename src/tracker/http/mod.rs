@@ -1,23 +1,25 @@
 mod reader;
 mod writer;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::{io, mem};
 
 use sstream::SStream;
-use url::Url;
+use url::{Position, Url};
 
 use self::reader::{ReadRes, Reader};
 use self::writer::Writer;
-use crate::tracker::{
-    self, dns, Announce, Error, ErrorKind, Response, Result, ResultExt, TrackerResponse,
-};
-use crate::util::{http, UHashMap};
-use crate::{bencode, PEER_ID};
+use crate::tracker::{self, dns, Error, ErrorKind, Query, Response, Result, ResultExt};
+use crate::util::{http, net, UHashMap};
+use crate::{CONFIG, PEER_ID};
 
 const TIMEOUT_MS: u64 = 5_000;
+/// Time allowed for resolving a tracker's host, before connecting
+/// to it gets TIMEOUT_MS
+const DNS_TIMEOUT_MS: u64 = 10_000;
+const MAX_REDIRECTS: u8 = 5;
 
 pub struct Handler {
     reg: amy::Registrar,
@@ -31,19 +33,40 @@ enum Event {
 }
 
 struct Tracker {
-    torrent: usize,
+    query: Query,
+    /// URL last requested, which relative redirects are resolved against
     url: Arc<Url>,
     last_updated: Instant,
-    redirect: bool,
+    /// Redirects followed so far
+    redirects: u8,
     state: TrackerState,
 }
 
+/// An HTTPS request made through a tunnel set up by the proxy.
+struct Tunnel {
+    host: String,
+    req: Vec<u8>,
+}
+
 enum TrackerState {
     Error,
     ResolvingDNS {
         sock: SStream,
+        /// Host TLS is started with, which a new socket needs
+        host: Option<String>,
         req: Vec<u8>,
         port: u16,
+        tunnel: Option<Tunnel>,
+    },
+    ProxyWriting {
+        sock: SStream,
+        writer: Writer,
+        tunnel: Tunnel,
+    },
+    ProxyReading {
+        sock: SStream,
+        reader: Reader,
+        tunnel: Tunnel,
     },
     Writing {
         sock: SStream,
@@ -54,18 +77,30 @@ enum TrackerState {
         reader: Reader,
     },
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(Vec<u8>),
 }
 
 enum HTTPRes {
     None,
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(Vec<u8>),
 }
 
 impl TrackerState {
-    fn new(sock: SStream, req: Vec<u8>, port: u16) -> TrackerState {
-        TrackerState::ResolvingDNS { sock, req, port }
+    fn new(
+        sock: SStream,
+        host: Option<String>,
+        req: Vec<u8>,
+        port: u16,
+        tunnel: Option<Tunnel>,
+    ) -> TrackerState {
+        TrackerState::ResolvingDNS {
+            sock,
+            host,
+            req,
+            port,
+            tunnel,
+        }
     }
 
     fn handle(&mut self, event: Event) -> Result<HTTPRes> {
@@ -87,18 +122,69 @@ impl TrackerState {
                     mut sock,
                     req,
                     port,
+                    tunnel,
+                    ..
                 },
                 Event::DNSResolved(r),
             ) => {
                 let addr = SocketAddr::new(r.res?, port);
                 sock.connect(addr).chain_err(|| ErrorKind::IO)?;
-                Ok(TrackerState::Writing {
+                let writer = Writer::new(req);
+                let state = match tunnel {
+                    Some(tunnel) => TrackerState::ProxyWriting {
+                        sock,
+                        writer,
+                        tunnel,
+                    },
+                    None => TrackerState::Writing { sock, writer },
+                };
+                Ok(state.next(Event::Writable)?.next(Event::Readable)?)
+            }
+            (
+                TrackerState::ProxyWriting {
+                    mut sock,
+                    mut writer,
+                    tunnel,
+                },
+                _,
+            ) => match writer.writable(&mut sock)? {
+                Some(()) => Ok(TrackerState::ProxyReading {
                     sock,
-                    writer: Writer::new(req),
+                    reader: Reader::tunnel(),
+                    tunnel,
                 }
-                .next(Event::Writable)?
-                .next(Event::Readable)?)
-            }
+                .next(Event::Readable)?),
+                None => Ok(TrackerState::ProxyWriting {
+                    sock,
+                    writer,
+                    tunnel,
+                }),
+            },
+            (
+                TrackerState::ProxyReading {
+                    mut sock,
+                    mut reader,
+                    tunnel,
+                },
+                _,
+            ) => match reader.readable(&mut sock)? {
+                ReadRes::Tunneled => {
+                    debug!("Proxy tunnel established, beginning TLS");
+                    let sock = sock.start_tls(&tunnel.host).chain_err(|| ErrorKind::IO)?;
+                    Ok(TrackerState::Writing {
+                        sock,
+                        writer: Writer::new(tunnel.req),
+                    }
+                    .next(Event::Writable)?
+                    .next(Event::Readable)?)
+                }
+                ReadRes::None => Ok(TrackerState::ProxyReading {
+                    sock,
+                    reader,
+                    tunnel,
+                }),
+                _ => bail!("Unexpected proxy response!"),
+            },
             (
                 TrackerState::Writing {
                     mut sock,
@@ -120,14 +206,10 @@ impl TrackerState {
                 },
                 _,
             ) => match reader.readable(&mut sock)? {
-                ReadRes::Done(data) => {
-                    let content = bencode::decode_buf(&data)
-                        .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))?;
-                    let resp = TrackerResponse::from_bencode(content)?;
-                    Ok(TrackerState::Complete(resp))
-                }
+                ReadRes::Done(data) => Ok(TrackerState::Complete(data)),
                 ReadRes::Redirect(l) => Ok(TrackerState::Redirect(l)),
                 ReadRes::None => Ok(TrackerState::Reading { sock, reader }),
+                ReadRes::Tunneled => bail!("Unexpected tunnel response!"),
             },
             (s @ TrackerState::ResolvingDNS { .. }, _) => Ok(s),
             _ => bail!("Unknown state transition encountered!"),
@@ -156,17 +238,19 @@ impl Handler {
     }
 
     pub fn dns_resolved(&mut self, resp: dns::QueryResponse) -> Option<Response> {
-        let id = resp.id;
+        let mut id = resp.id;
         debug!("Received a DNS resp for {:?}", id);
+        if let Ok(IpAddr::V6(_)) = resp.res {
+            match self.reopen_v6(id) {
+                Ok(new) => id = new,
+                Err(e) => return self.connections.remove(&id).map(|trk| trk.query.failed(e)),
+            }
+        }
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::DNSResolved(resp)) {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.query.failed(e)),
             }
         } else {
             None
@@ -177,16 +261,35 @@ impl Handler {
         resp
     }
 
+    /// Moves a tracker resolved to an IPv6 address to a socket of that
+    /// family, as sockets are made for IPv4 before resolving. Returns the
+    /// id the tracker is registered with.
+    fn reopen_v6(&mut self, id: usize) -> Result<usize> {
+        let host = match self.connections.get(&id).map(|trk| &trk.state) {
+            Some(TrackerState::ResolvingDNS { host, .. }) => host.clone(),
+            _ => return Ok(id),
+        };
+        let sock = net::tcp_stream(true)
+            .and_then(|conn| SStream::new(conn, host))
+            .chain_err(|| ErrorKind::IO)?;
+        let new = self
+            .reg
+            .register(&sock, amy::Event::Both)
+            .chain_err(|| ErrorKind::IO)?;
+        let mut trk = self.connections.remove(&id).unwrap();
+        if let TrackerState::ResolvingDNS { sock: ref mut old, .. } = trk.state {
+            *old = sock;
+        }
+        self.connections.insert(new, trk);
+        Ok(new)
+    }
+
     pub fn writable(&mut self, id: usize) -> Option<Response> {
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::Writable) {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.query.failed(e)),
             }
         } else {
             None
@@ -202,24 +305,16 @@ impl Handler {
         let mut resp = if let Some(trk) = self.connections.get_mut(&id) {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::Readable) {
-                Ok(HTTPRes::Complete(r)) => {
-                    debug!("Announce response received for {:?} succesfully", id);
-                    Some(Response::Tracker {
-                        tid: trk.torrent,
-                        url: trk.url.clone(),
-                        resp: Ok(r),
-                    })
+                Ok(HTTPRes::Complete(data)) => {
+                    debug!("Tracker response received for {:?} succesfully", id);
+                    Some(trk.query.response(&data))
                 }
                 Ok(HTTPRes::Redirect(l)) => {
-                    loc = Some((l, trk.url.clone()));
+                    loc = Some(l);
                     None
                 }
                 Ok(HTTPRes::None) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.query.failed(e)),
             }
         } else {
             None
@@ -229,85 +324,101 @@ impl Handler {
             self.connections.remove(&id);
         }
 
-        if let Some((l, old)) = loc {
+        if let Some(l) = loc {
             let trk = self.connections.remove(&id).unwrap();
-            // Disallow 2 levels of redirection
-            if trk.redirect {
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::InvalidResponse("Too many redirects").into()),
-                });
-            }
-            if let Err(e) = self.try_redirect(&l, old, trk.torrent, dns) {
-                debug!(
-                    "Announce response received for {:?}, redirecting!",
-                    trk.torrent
+            if trk.redirects >= MAX_REDIRECTS {
+                resp = Some(
+                    trk.query
+                        .failed(ErrorKind::InvalidResponse("Too many redirects").into()),
                 );
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url,
-                    resp: Err(e),
-                });
+            } else {
+                debug!("Tracker response received for {:?}, redirecting!", id);
+                let query = trk.query.clone();
+                if let Err(e) = self.try_redirect(&l, trk, dns) {
+                    resp = Some(query.failed(e));
+                }
             }
         }
         resp
     }
 
-    fn try_redirect(
-        &mut self,
-        url: &str,
-        original_url: Arc<Url>,
-        torrent: usize,
-        dns: &mut dns::Resolver,
-    ) -> Result<()> {
-        let url = match Url::parse(url) {
-            Ok(url) => Ok(url),
-            Err(url::ParseError::RelativeUrlWithoutBase) => Ok(original_url
-                .join(url)
-                .map_err(|_| ErrorKind::InvalidResponse("Invalid relative redirect URL"))?),
-            Err(e) => Err(e),
-        }
-        .chain_err(|| {
-            error!("{} {}", original_url, url);
-            ErrorKind::InvalidResponse("Malformed redirect!")
-        })?;
+    fn try_redirect(&mut self, url: &str, trk: Tracker, dns: &mut dns::Resolver) -> Result<()> {
+        let url = redirect_url(&trk.url, url)?;
         let host = url.host_str().ok_or_else(|| {
             error!("{}", url);
             Error::from(ErrorKind::InvalidResponse("Malformed redirect!"))
         })?;
         let mut http_req = Vec::with_capacity(512);
-        http::RequestBuilder::new("GET", url.path(), url.query())
-            .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
-            .header("Connection", "close")
-            .header("Host", host)
-            .encode(&mut http_req);
-
-        let ohost = if url.scheme() == "https" {
-            Some(host.to_owned())
-        } else {
-            None
+        request(&url, host).encode(&mut http_req);
+        debug!("Dispatching redirect to {}", url);
+        self.connect(
+            &url,
+            http_req,
+            Tracker {
+                url: Arc::new(url.clone()),
+                redirects: trk.redirects + 1,
+                ..trk
+            },
+            dns,
+        )
+    }
+
+    /// Connects to the URL's host and sends it the request once
+    /// its address is resolved.
+    fn connect(
+        &mut self,
+        url: &Url,
+        http_req: Vec<u8>,
+        mut trk: Tracker,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        let host = url.host_str().ok_or_else(|| {
+            Error::from(ErrorKind::InvalidRequest(
+                "Tracker url has no host!".to_owned(),
+            ))
+        })?;
+        let https = url.scheme() == "https";
+        let port = url.port().unwrap_or(if https { 443 } else { 80 });
+
+        // Connect to the proxy if there is one, tunneling HTTPS requests
+        // through it and sending it HTTP requests directly
+        let (host, port, ohost, http_req, tunnel) = match CONFIG.trk.proxy {
+            Some(ref proxy) => {
+                let (phost, pport) = proxy_addr(proxy)?;
+                if https {
+                    let connect = format!(
+                        "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n\r\n",
+                        host, port
+                    );
+                    let tunnel = Tunnel {
+                        host: host.to_owned(),
+                        req: http_req,
+                    };
+                    (phost, pport, None, connect.into_bytes(), Some(tunnel))
+                } else {
+                    (phost, pport, None, http_req, None)
+                }
+            }
+            None => {
+                let ohost = if https { Some(host.to_owned()) } else { None };
+                (host, port, ohost, http_req, None)
+            }
         };
 
-        // Setup actual connection and start DNS query
-        let sock = SStream::new_v4(ohost).chain_err(|| ErrorKind::IO)?;
+        // Setup actual connection and start DNS query, the socket being
+        // replaced if the host has an IPv6 address
+        let sock = net::tcp_stream(false)
+            .and_then(|conn| SStream::new(conn, ohost.clone()))
+            .chain_err(|| ErrorKind::IO)?;
         let id = self
             .reg
             .register(&sock, amy::Event::Both)
             .chain_err(|| ErrorKind::IO)?;
-        let port = url.port().unwrap_or(80);
-        self.connections.insert(
-            id,
-            Tracker {
-                last_updated: Instant::now(),
-                redirect: true,
-                torrent,
-                url: original_url,
-                state: TrackerState::new(sock, http_req, port),
-            },
-        );
+        trk.last_updated = Instant::now();
+        trk.state = TrackerState::new(sock, ohost, http_req, port, tunnel);
+        self.connections.insert(id, trk);
 
-        debug!("Dispatching redirect DNS req, id {:?}", id);
+        debug!("Dispatching DNS req, id {:?}", id);
         if let Some(ip) = dns.new_query(id, host).chain_err(|| ErrorKind::IO)? {
             debug!("Using cached DNS response");
             let res = self.dns_resolved(dns::QueryResponse { id, res: Ok(ip) });
@@ -321,13 +432,13 @@ impl Handler {
     pub fn tick(&mut self) -> Vec<Response> {
         let mut resps = Vec::new();
         self.connections.retain(|id, trk| {
-            if trk.last_updated.elapsed() > Duration::from_millis(TIMEOUT_MS) {
-                debug!("Announce {:?} timed out", id);
-                resps.push(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::Timeout.into()),
-                });
+            let (timeout, err) = match trk.state {
+                TrackerState::ResolvingDNS { .. } => (DNS_TIMEOUT_MS, ErrorKind::DNSTimeout),
+                _ => (TIMEOUT_MS, ErrorKind::Timeout),
+            };
+            if trk.last_updated.elapsed() > Duration::from_millis(timeout) {
+                debug!("Tracker request {:?} timed out", id);
+                resps.push(trk.query.failed(err.into()));
                 false
             } else {
                 true
@@ -336,74 +447,130 @@ impl Handler {
         resps
     }
 
-    pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
-        debug!("Received a new announce req for {:?}", req.url);
-        let host = req.url.host_str().ok_or_else(|| {
+    pub fn new_query(&mut self, query: Query, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new tracker req for {:?}", query.url());
+        let url = match query {
+            Query::Announce(_) => query.url().clone(),
+            Query::Scrape(_) => {
+                Arc::new(tracker::scrape_url(query.url()).ok_or(ErrorKind::ScrapeUnsupported)?)
+            }
+        };
+        let host = url.host_str().ok_or_else(|| {
             Error::from(ErrorKind::InvalidRequest(
                 "Tracker announce url has no host!".to_owned(),
             ))
         })?;
 
         let mut http_req = Vec::with_capacity(512);
-        let num_want = req.num_want.map(|nw| nw.to_string());
-        let event = match req.event {
-            Some(tracker::Event::Started) => Some("started"),
-            Some(tracker::Event::Stopped) => Some("stopped"),
-            Some(tracker::Event::Completed) => Some("completed"),
-            None => None,
-        };
-        http::RequestBuilder::new("GET", req.url.path(), req.url.query())
-            .query("info_hash", &req.hash)
-            .query("peer_id", &PEER_ID[..])
-            .query("uploaded", req.uploaded.to_string().as_bytes())
-            .query("downloaded", req.downloaded.to_string().as_bytes())
-            .query("left", req.left.to_string().as_bytes())
-            .query("compact", b"1")
-            .query("port", req.port.to_string().as_bytes())
-            .query_opt("numwant", num_want.as_ref().map(|nw| nw.as_bytes()))
-            .query_opt("event", event.map(|e| e.as_bytes()))
-            .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
-            .header("Connection", "close")
-            .header("Host", host)
-            .encode(&mut http_req);
-
-        let port =
-            req.url
-                .port()
-                .unwrap_or_else(|| if req.url.scheme() == "https" { 443 } else { 80 });
-
-        let ohost = if req.url.scheme() == "https" {
-            Some(host.to_owned())
-        } else {
-            None
-        };
+        match query {
+            Query::Announce(ref req) => {
+                let num_want = req.num_want.map(|nw| nw.to_string());
+                let event = match req.event {
+                    Some(tracker::Event::Started) => Some("started"),
+                    Some(tracker::Event::Stopped) => Some("stopped"),
+                    Some(tracker::Event::Completed) => Some("completed"),
+                    None => None,
+                };
+                let key = CONFIG.trk.key.map(|k| format!("{:08X}", k));
+                let (uploaded, downloaded, left, port) = (
+                    req.uploaded.to_string(),
+                    req.downloaded.to_string(),
+                    req.left.to_string(),
+                    req.port.to_string(),
+                );
+                request(&url, host)
+                    .query("info_hash", &req.hash)
+                    .query("peer_id", &PEER_ID[..])
+                    .query("uploaded", uploaded.as_bytes())
+                    .query("downloaded", downloaded.as_bytes())
+                    .query("left", left.as_bytes())
+                    .query("compact", b"1")
+                    .query("port", port.as_bytes())
+                    .query_opt("numwant", num_want.as_ref().map(|nw| nw.as_bytes()))
+                    .query_opt("event", event.map(|e| e.as_bytes()))
+                    .query_opt("key", key.as_ref().map(|k| k.as_bytes()))
+                    .encode(&mut http_req);
+            }
+            Query::Scrape(ref req) => {
+                request(&url, host)
+                    .query("info_hash", &req.hash)
+                    .encode(&mut http_req);
+            }
+        }
 
-        // Setup actual connection and start DNS query
-        let sock = SStream::new_v4(ohost).chain_err(|| ErrorKind::IO)?;
-        let id = self
-            .reg
-            .register(&sock, amy::Event::Both)
-            .chain_err(|| ErrorKind::IO)?;
-        self.connections.insert(
-            id,
+        self.connect(
+            &url,
+            http_req,
             Tracker {
-                url: req.url.clone(),
+                query,
+                url: url.clone(),
                 last_updated: Instant::now(),
-                torrent: req.id,
-                state: TrackerState::new(sock, http_req, port),
-                redirect: false,
+                redirects: 0,
+                state: TrackerState::Error,
             },
-        );
+            dns,
+        )
+    }
+}
 
-        debug!("Dispatching DNS req, id {:?}", id);
-        if let Some(ip) = dns.new_query(id, host).chain_err(|| ErrorKind::IO)? {
-            debug!("Using cached DNS response");
-            let res = self.dns_resolved(dns::QueryResponse { id, res: Ok(ip) });
-            if res.is_some() {
-                bail!("Failed to establish connection to tracker!");
-            }
-        }
+/// The URL a Location header redirects to, which may be relative to the
+/// URL requested.
+fn redirect_url(current: &Url, location: &str) -> Result<Url> {
+    current.join(location).chain_err(|| {
+        error!("{} {}", current, location);
+        ErrorKind::InvalidResponse("Malformed redirect!")
+    })
+}
 
-        Ok(())
+/// A GET request for a URL, with the headers every tracker request has.
+/// Plain HTTP requests to a proxy name the whole URL.
+fn request<'a>(url: &'a Url, host: &'a str) -> http::RequestBuilder<'a> {
+    let path = if CONFIG.trk.proxy.is_some() && url.scheme() == "http" {
+        &url[..Position::AfterPath]
+    } else {
+        url.path()
+    };
+    let mut req = http::RequestBuilder::new("GET", path, url.query());
+    let agent = CONFIG
+        .trk
+        .user_agent
+        .as_deref()
+        .unwrap_or(concat!("synapse/", env!("CARGO_PKG_VERSION")));
+    req.header("User-agent", agent)
+        .header("Connection", "close")
+        .header("Accept-Encoding", "gzip")
+        .header("Host", host);
+    req
+}
+
+/// Host and port of a proxy given as host:port.
+fn proxy_addr(proxy: &str) -> Result<(&str, u16)> {
+    proxy
+        .rsplit_once(':')
+        .and_then(|(host, port)| {
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            Some((host, port.parse().ok()?))
+        })
+        .ok_or_else(|| ErrorKind::InvalidRequest(format!("Invalid tracker proxy {}", proxy)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redirect_url;
+    use url::Url;
+
+    #[test]
+    fn test_redirect_url() {
+        let url = Url::parse("http://a.example/b/announce?x=1").unwrap();
+        let join = |location| redirect_url(&url, location).unwrap().to_string();
+        assert_eq!(join("/announce2"), "http://a.example/announce2");
+        assert_eq!(join("c/announce"), "http://a.example/b/c/announce");
+        assert_eq!(join("https://c.example/d"), "https://c.example/d");
+        // Redirects of redirects are relative to the URL they came from
+        let url = redirect_url(&url, "https://c.example/d/").unwrap();
+        assert_eq!(
+            redirect_url(&url, "e").unwrap().as_str(),
+            "https://c.example/d/e"
+        );
     }
 }
@@ -1,19 +1,31 @@
-use std::io;
+use std::io::{self, Read};
 use std::mem;
 
+use flate2::read::GzDecoder;
+
 use crate::tracker::errors::{ErrorKind, Result};
 use crate::util::{aread, IOR};
 
+/// Largest response body accepted once decompressed.
+const MAX_BODY_BYTES: u64 = 4 * 1024 * 1024;
+
 pub struct Reader {
     data: Vec<u8>,
     idx: usize,
     state: ReadState,
+    /// Whether the body is gzip compressed
+    gzip: bool,
+    /// Whether this is a proxy's response to a CONNECT, which ends
+    /// with the headers
+    tunnel: bool,
 }
 
 pub enum ReadRes {
     None,
     Done(Vec<u8>),
     Redirect(String),
+    /// The proxy tunnel is established
+    Tunneled,
 }
 
 enum ReadState {
@@ -27,6 +39,16 @@ impl Reader {
             data: vec![0; 75],
             idx: 0,
             state: ReadState::Header,
+            gzip: false,
+            tunnel: false,
+        }
+    }
+
+    /// Reads a proxy's response to a CONNECT request.
+    pub fn tunnel() -> Reader {
+        Reader {
+            tunnel: true,
+            ..Reader::new()
         }
     }
 
@@ -52,6 +74,9 @@ impl Reader {
                     ReadState::Body => {
                         let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
                         data.truncate(self.idx);
+                        if self.gzip {
+                            data = gunzip(&data)?;
+                        }
                         return Ok(ReadRes::Done(data));
                     }
                     _ => return Err(ErrorKind::EOF.into()),
@@ -69,6 +94,12 @@ impl Reader {
                 let mut resp = httparse::Response::new(&mut headers);
                 match resp.parse(&self.data[..self.idx]) {
                     Ok(httparse::Status::Complete(i)) => {
+                        if self.tunnel {
+                            return match resp.code {
+                                Some(200..=299) => Ok(Some(ReadRes::Tunneled)),
+                                _ => Err(ErrorKind::InvalidResponse("Proxy refused tunnel").into()),
+                            };
+                        }
                         // Redirect handling
                         let redirect_codes = [301, 302, 303, 307, 308];
                         if resp
@@ -87,6 +118,10 @@ impl Reader {
                             }
                             return Ok(Some(ReadRes::Redirect(loc.unwrap())));
                         }
+                        self.gzip = resp.headers.iter().any(|h| {
+                            h.name.eq_ignore_ascii_case("Content-Encoding")
+                                && h.value.eq_ignore_ascii_case(b"gzip")
+                        });
                         header_done = Some(i);
                     }
                     Ok(httparse::Status::Partial) => {}
@@ -106,3 +141,65 @@ impl Reader {
         Ok(None)
     }
 }
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(data.len() * 4);
+    GzDecoder::new(data)
+        .take(MAX_BODY_BYTES + 1)
+        .read_to_end(&mut body)
+        .map_err(|_| ErrorKind::InvalidResponse("Invalid gzip encoded response"))?;
+    if body.len() as u64 > MAX_BODY_BYTES {
+        return Err(ErrorKind::InvalidResponse("Response too large").into());
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::{ReadRes, Reader};
+
+    fn read(reader: &mut Reader, resp: Vec<u8>) -> ReadRes {
+        reader.readable(&mut Cursor::new(resp)).unwrap()
+    }
+
+    #[test]
+    fn test_read_gzip() {
+        let body = b"d8:intervali1800e5:peers0:e";
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(body).unwrap();
+        let mut resp = b"HTTP/1.0 200 OK\r\nContent-Encoding: gzip\r\n\r\n".to_vec();
+        resp.extend(enc.finish().unwrap());
+        match read(&mut Reader::new(), resp) {
+            ReadRes::Done(data) => assert_eq!(data, &body[..]),
+            _ => panic!("response not read"),
+        }
+
+        let resp = b"HTTP/1.0 200 OK\r\ncontent-encoding: GZIP\r\n\r\nnot gzip".to_vec();
+        assert!(Reader::new().readable(&mut Cursor::new(resp)).is_err());
+    }
+
+    #[test]
+    fn test_read_redirect() {
+        let resp = b"HTTP/1.0 302 Found\r\nLocation: /announce2\r\n\r\n".to_vec();
+        match read(&mut Reader::new(), resp) {
+            ReadRes::Redirect(l) => assert_eq!(l, "/announce2"),
+            _ => panic!("redirect not read"),
+        }
+    }
+
+    #[test]
+    fn test_read_tunnel() {
+        let resp = b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec();
+        assert!(matches!(
+            read(&mut Reader::tunnel(), resp),
+            ReadRes::Tunneled
+        ));
+        let resp = b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec();
+        assert!(Reader::tunnel().readable(&mut Cursor::new(resp)).is_err());
+    }
+}
@@ -1,33 +1,37 @@
 mod dht;
-mod dns;
+pub mod dns;
 mod errors;
 mod http;
+mod stun;
 mod udp;
 
 use std::collections::VecDeque;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{atomic, Arc};
+use std::time::{Duration, Instant};
 use std::{io, result, thread};
 
 use byteorder::{BigEndian, ByteOrder};
 use url::Url;
 
+pub use self::dht::{mutable_target, Item, MAX_SALT_LEN, MAX_VALUE_LEN};
 pub use self::errors::{Error, ErrorKind, Result, ResultExt};
-use crate::bencode::BEncode;
+use crate::bencode::{self, BEncodeRef};
 use crate::control::cio;
 use crate::disk;
 use crate::handle;
-use crate::torrent::Torrent;
-use crate::CONFIG;
+use crate::torrent::{Torrent, TrackerStatus};
+use crate::{config, util, CONFIG, LISTEN_PORT};
 
 pub struct Tracker {
     poll: amy::Poller,
     ch: handle::Handle<Request, Response>,
     http: http::Handler,
-    queue: VecDeque<Announce>,
+    queue: VecDeque<Query>,
     udp: udp::Handler,
     dht: dht::Manager,
     dns: dns::Resolver,
+    stun: Option<stun::Stun>,
     timer: usize,
     shutting_down: bool,
 }
@@ -35,15 +39,31 @@ pub struct Tracker {
 #[derive(Debug)]
 pub enum Request {
     Announce(Announce),
-    GetPeers(GetPeers),
+    Scrape(Scrape),
     AddNode(SocketAddr),
-    DHTAnnounce([u8; 20]),
+    /// Looks up peers in the DHT and announces the torrent to the
+    /// closest nodes found
+    DHTAnnounce(GetPeers),
+    GetItem {
+        client: usize,
+        serial: u64,
+        target: [u8; 20],
+    },
+    PutItem {
+        client: usize,
+        serial: u64,
+        /// Bencoded value of the item
+        value: Vec<u8>,
+        mutable: bool,
+        salt: Vec<u8>,
+        seq: Option<i64>,
+    },
     PurgeDNS,
     Ping,
     Shutdown,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Announce {
     id: usize,
     url: Arc<Url>,
@@ -56,13 +76,28 @@ pub struct Announce {
     event: Option<Event>,
 }
 
+/// Request for a tracker's swarm statistics of a torrent
+#[derive(Clone, Debug)]
+pub struct Scrape {
+    id: usize,
+    url: Arc<Url>,
+    hash: [u8; 20],
+}
+
+/// Requests sent to trackers, queued while too many are outstanding
+#[derive(Clone, Debug)]
+enum Query {
+    Announce(Announce),
+    Scrape(Scrape),
+}
+
 #[derive(Debug)]
 pub struct GetPeers {
     pub id: usize,
     pub hash: [u8; 20],
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Event {
     Started,
     Stopped,
@@ -76,6 +111,11 @@ pub enum Response {
         url: Arc<Url>,
         resp: Result<TrackerResponse>,
     },
+    Scrape {
+        tid: usize,
+        url: Arc<Url>,
+        resp: Result<ScrapeResponse>,
+    },
     DHT {
         tid: usize,
         peers: Vec<SocketAddr>,
@@ -84,17 +124,69 @@ pub enum Response {
         tid: usize,
         peers: Vec<SocketAddr>,
     },
+    /// Result of a DHT item lookup, or the item which was stored
+    /// if a put was requested. The item is None if it couldn't
+    /// be found or stored.
+    DHTItem {
+        client: usize,
+        serial: u64,
+        target: [u8; 20],
+        item: Option<Item>,
+    },
+    /// Our address as seen from elsewhere. If it was reported by a peer,
+    /// the voter is the peer's address, as one peer alone can't be trusted.
+    ExternalIp {
+        ip: IpAddr,
+        voter: Option<IpAddr>,
+    },
+    /// Addresses of a peer we're connected to under one IP version, which
+    /// told us its address of the other in its extended handshake.
+    DualStack {
+        tid: usize,
+        v4: SocketAddr,
+        v6: SocketAddr,
+    },
+    /// Address a peer which connected to us listens on, as told in its
+    /// extended handshake.
+    Listening {
+        tid: usize,
+        pid: usize,
+        addr: SocketAddr,
+    },
+    /// Lookups made by the tracker DNS resolver, sent when they change
+    DnsStats(adns::Stats),
 }
 
 #[derive(Debug)]
 pub struct TrackerResponse {
     pub peers: Vec<SocketAddr>,
+    /// Our address as seen by the tracker
+    pub external_ip: Option<IpAddr>,
+    /// Warning the tracker sent along with the response
+    pub warning: Option<String>,
     pub interval: u32,
     pub leechers: u32,
     pub seeders: u32,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct ScrapeResponse {
+    pub seeders: u32,
+    pub leechers: u32,
+    /// Number of times the torrent was downloaded in full
+    pub completed: u32,
+}
+
 const POLL_INT_MS: usize = 1000;
+/// Maximum time spent finishing outstanding announces on shutdown
+const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+/// Limits on decoding HTTP tracker responses, which nest no deeper than
+/// the stats of a torrent in a scrape or a peer dict in a peer list.
+const LIMITS: bencode::Limits = bencode::Limits {
+    depth: 4,
+    items: 1 << 16,
+    string_len: 1 << 20,
+};
 
 impl Tracker {
     pub fn start(
@@ -109,6 +201,11 @@ impl Tracker {
         let dht = dht::Manager::new(&reg, db)?;
         let http = http::Handler::new(&reg)?;
         let dns = dns::Resolver::new(&reg)?;
+        let stun = CONFIG.net.stun_server.as_ref().and_then(|server| {
+            stun::Stun::new(&reg, server)
+                .map_err(|e| error!("Failed to set up STUN server {}: {}", server, e))
+                .ok()
+        });
         let th = dh.run("trk", move |h| {
             Tracker {
                 poll,
@@ -117,6 +214,7 @@ impl Tracker {
                 dht,
                 http,
                 dns,
+                stun,
                 timer,
                 queue: VecDeque::new(),
                 shutting_down: false,
@@ -147,17 +245,35 @@ impl Tracker {
 
         self.shutting_down = true;
 
-        // Shutdown loop - wait for all requests to complete
-        loop {
-            for event in self.poll.wait(POLL_INT_MS).unwrap() {
-                if self.handle_event(event).is_err() {}
-                if self.http.complete() && self.udp.complete() {
+        // Shutdown loop - wait for all requests to complete, giving up
+        // on unresponsive trackers after a timeout
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_TIMEOUT_MS);
+        while !self.shutdown_complete() {
+            if Instant::now() >= deadline {
+                info!(
+                    "Abandoning {} outstanding announces on shutdown",
+                    self.http.active_requests() + self.udp.active_requests() + self.queue.len()
+                );
+                return;
+            }
+            match self.poll.wait(POLL_INT_MS) {
+                Ok(events) => {
+                    for event in events {
+                        self.handle_event(event).ok();
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to poll for events: {}", e);
                     return;
                 }
             }
         }
     }
 
+    fn shutdown_complete(&self) -> bool {
+        self.http.complete() && self.udp.complete() && self.queue.is_empty()
+    }
+
     fn handle_event(&mut self, event: amy::Notification) -> result::Result<(), ()> {
         if event.id == self.ch.rx.get_id() {
             return self.handle_request();
@@ -173,19 +289,47 @@ impl Tracker {
 
     fn handle_request(&mut self) -> result::Result<(), ()> {
         while let Ok(r) = self.ch.recv() {
+            let down = util::net::down();
             match r {
-                Request::Announce(req) => self.handle_announce(req),
-                Request::GetPeers(gp) => {
-                    trace!("Handling dht peer find req!");
-                    self.dht.get_peers(gp.id, gp.hash);
+                Request::Announce(req) if down => {
+                    self.send_response(Query::Announce(req).failed(ErrorKind::NetworkDown.into()));
+                }
+                Request::Scrape(req) if down => {
+                    self.send_response(Query::Scrape(req).failed(ErrorKind::NetworkDown.into()));
                 }
+                Request::AddNode(_) | Request::DHTAnnounce(_) if down => {}
+                Request::Announce(req) => self.handle_query(Query::Announce(req)),
+                Request::Scrape(req) => self.handle_query(Query::Scrape(req)),
                 Request::AddNode(addr) => {
                     trace!("Handling dht node addition req!");
                     self.dht.add_addr(addr);
                 }
-                Request::DHTAnnounce(hash) => {
+                Request::DHTAnnounce(gp) => {
                     trace!("Handling dht announce req!");
-                    self.dht.announce(hash);
+                    self.dht.announce(gp.id, gp.hash);
+                }
+                Request::GetItem {
+                    client,
+                    serial,
+                    target,
+                } => {
+                    trace!("Handling dht item get req!");
+                    for r in self.dht.get_item(client, serial, target) {
+                        self.send_response(r);
+                    }
+                }
+                Request::PutItem {
+                    client,
+                    serial,
+                    value,
+                    mutable,
+                    salt,
+                    seq,
+                } => {
+                    trace!("Handling dht item put req!");
+                    for r in self.dht.put_item(client, serial, value, mutable, salt, seq) {
+                        self.send_response(r);
+                    }
                 }
                 Request::Ping => {}
                 Request::PurgeDNS => {
@@ -199,35 +343,32 @@ impl Tracker {
         Ok(())
     }
 
-    fn handle_announce(&mut self, req: Announce) {
-        debug!("Handling announce request!");
-        if self.udp.active_requests() + self.http.active_requests() > CONFIG.net.max_open_announces
+    fn handle_query(&mut self, req: Query) {
+        debug!("Handling tracker request!");
+        if self.udp.active_requests() + self.http.active_requests()
+            > config::settings().max_open_announces
         {
             self.queue.push_back(req);
         } else {
-            let id = req.id;
-            let url = req.url.clone();
-            let response = match url.scheme() {
-                "http" | "https" => self.http.new_announce(req, &mut self.dns),
-                "udp" => self.udp.new_announce(req, &mut self.dns),
+            // Kept to report failures, as the request is consumed
+            let failed = req.clone();
+            let response = match req.url().scheme() {
+                "http" | "https" => self.http.new_query(req, &mut self.dns),
+                "udp" => self.udp.new_query(req, &mut self.dns),
                 s => Err(
                     ErrorKind::InvalidRequest(format!("Unknown tracker url scheme: {}", s)).into(),
                 ),
             };
             if let Err(e) = response {
-                self.send_response(Response::Tracker {
-                    tid: id,
-                    url,
-                    resp: Err(e),
-                });
+                self.send_response(failed.failed(e));
             }
         }
     }
 
     fn dequeue_req(&mut self) {
         // Attempt to dequeue next request if we can
-        if let Some(a) = self.queue.pop_front() {
-            self.handle_announce(a);
+        if let Some(q) = self.queue.pop_front() {
+            self.handle_query(q);
         }
     }
 
@@ -245,7 +386,12 @@ impl Tracker {
     }
 
     fn handle_dns_resp(&mut self, r: dns::QueryResponse) {
-        let resp = if self.http.contains(r.id) {
+        let resp = if r.id == stun::DNS_ID {
+            if let Some(ref mut stun) = self.stun {
+                stun.resolved(r.res);
+            }
+            None
+        } else if self.http.contains(r.id) {
             self.http.dns_resolved(r)
         } else if self.udp.contains(r.id) {
             self.udp.dns_resolved(r)
@@ -267,7 +413,14 @@ impl Tracker {
             self.send_response(r);
         }
 
-        self.dht.tick();
+        if !util::net::down() {
+            for r in self.dht.tick() {
+                self.send_response(r);
+            }
+            if let Some(ref mut stun) = self.stun {
+                stun.tick(&mut self.dns);
+            }
+        }
         let mut dresps = vec![];
         let res = self.dns.res.tick(&mut self.dns.sock, |resp| {
             dresps.push(resp);
@@ -278,6 +431,9 @@ impl Tracker {
         for r in dresps {
             self.handle_dns_resp(r.into());
         }
+        if let Some(stats) = self.dns.stats_changed() {
+            self.send_response(Response::DnsStats(stats));
+        }
     }
 
     fn handle_socket(&mut self, event: amy::Notification) {
@@ -290,14 +446,18 @@ impl Tracker {
             if let Some(r) = resp {
                 self.send_response(r);
             }
-        } else if self.udp.id() == event.id {
+        } else if self.udp.owns(event.id) {
             for resp in self.udp.readable() {
                 self.send_response(resp);
             }
-        } else if self.dht.id() == event.id {
+        } else if self.dht.owns(event.id) {
             for resp in self.dht.readable() {
                 self.send_response(resp);
             }
+        } else if self.stun.as_ref().map(|s| s.id) == Some(event.id) {
+            if let Some(ip) = self.stun.as_mut().and_then(|s| s.readable()) {
+                self.send_response(Response::ExternalIp { ip, voter: None });
+            }
         } else {
             error!("Unknown event occured for tracker: {:?}", event);
         };
@@ -329,7 +489,7 @@ impl Request {
             id: torrent.id(),
             url,
             hash: torrent.info().hash,
-            port: CONFIG.port,
+            port: LISTEN_PORT.load(atomic::Ordering::Relaxed),
             uploaded: torrent.uploaded(),
             downloaded: torrent.downloaded(),
             // This should be fine because the true len is usually slightly less than
@@ -354,6 +514,17 @@ impl Request {
         Request::new_announce(torrent, Some(Event::Stopped))
     }
 
+    /// Scrapes the torrent's primary tracker.
+    pub fn scrape<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
+        torrent.trackers().front().map(|trk| {
+            Request::Scrape(Scrape {
+                id: torrent.id(),
+                url: trk.url.clone(),
+                hash: torrent.info().hash,
+            })
+        })
+    }
+
     pub fn completed<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
         Request::new_announce(torrent, Some(Event::Completed))
     }
@@ -362,13 +533,77 @@ impl Request {
         Request::new_announce(torrent, None)
     }
 
+    /// Creates stopped announces for the primary tracker and any other
+    /// tracker which has successfully been announced to.
+    pub fn stopped_all<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        torrent
+            .trackers()
+            .iter()
+            .enumerate()
+            .filter(|&(i, trk)| i == 0 || matches!(trk.status, TrackerStatus::Ok { .. }))
+            .filter_map(|(_, trk)| {
+                Request::new_announce(torrent, Some(Event::Stopped))
+                    .map(|r| r.with_url(trk.url.clone()))
+            })
+            .collect()
+    }
+
     pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, url: Arc<Url>) -> Option<Request> {
-        Request::new_announce(torrent, None).map(|mut r| {
-            if let Request::Announce(ref mut a) = r {
-                a.url = url
+        Request::new_announce(torrent, None).map(|r| r.with_url(url))
+    }
+
+    fn with_url(mut self, url: Arc<Url>) -> Request {
+        if let Request::Announce(ref mut a) = self {
+            a.url = url
+        }
+        self
+    }
+}
+
+impl Query {
+    fn url(&self) -> &Arc<Url> {
+        match self {
+            Query::Announce(a) => &a.url,
+            Query::Scrape(s) => &s.url,
+        }
+    }
+
+    /// The response to the request, given the tracker's reply.
+    fn response(&self, data: &[u8]) -> Response {
+        let data = match bencode::decode_ref_with(data, &LIMITS) {
+            Ok(data) => data,
+            Err(_) => {
+                return self.failed(ErrorKind::InvalidResponse("Invalid BEncoded response!").into())
             }
-            r
-        })
+        };
+        match self {
+            Query::Announce(a) => Response::Tracker {
+                tid: a.id,
+                url: a.url.clone(),
+                resp: TrackerResponse::from_bencode(&data),
+            },
+            Query::Scrape(s) => Response::Scrape {
+                tid: s.id,
+                url: s.url.clone(),
+                resp: ScrapeResponse::from_bencode(&data, &s.hash),
+            },
+        }
+    }
+
+    /// The response reporting the request failed.
+    fn failed(&self, e: Error) -> Response {
+        match self {
+            Query::Announce(a) => Response::Tracker {
+                tid: a.id,
+                url: a.url.clone(),
+                resp: Err(e),
+            },
+            Query::Scrape(s) => Response::Scrape {
+                tid: s.id,
+                url: s.url.clone(),
+                resp: Err(e),
+            },
+        }
     }
 }
 
@@ -376,23 +611,25 @@ impl TrackerResponse {
     pub fn empty() -> TrackerResponse {
         TrackerResponse {
             peers: vec![],
+            external_ip: None,
+            warning: None,
             interval: 900,
             leechers: 0,
             seeders: 0,
         }
     }
 
-    pub fn from_bencode(data: BEncode) -> Result<TrackerResponse> {
-        let mut d = data.into_dict().ok_or(ErrorKind::InvalidResponse(
+    pub fn from_bencode(data: &BEncodeRef<'_>) -> Result<TrackerResponse> {
+        let d = data.as_dict().ok_or(ErrorKind::InvalidResponse(
             "Tracker response must be a dictionary type!",
         ))?;
-        if let Some(BEncode::String(data)) = d.remove(b"failure reason".as_ref()) {
-            let reason = String::from_utf8(data)
+        if let Some(BEncodeRef::String(data)) = d.get(b"failure reason".as_ref()) {
+            let reason = String::from_utf8(data.to_vec())
                 .chain_err(|| ErrorKind::InvalidResponse("Failure reason must be UTF8!"))?;
             return Err(ErrorKind::TrackerError(reason).into());
         }
         let mut resp = TrackerResponse::empty();
-        if let Some(BEncode::String(ref data)) = d.remove(b"peers".as_ref()) {
+        if let Some(BEncodeRef::String(data)) = d.get(b"peers".as_ref()) {
             for p in data.chunks(6) {
                 if p.len() != 6 {
                     debug!("Unusual trailing bytes received for tracker!");
@@ -403,8 +640,24 @@ impl TrackerResponse {
                 resp.peers.push(SocketAddr::V4(socket));
             }
         }
-        match d.remove(b"interval".as_ref()) {
-            Some(BEncode::Int(ref i)) => {
+        for (key, count) in [
+            (b"complete".as_ref(), &mut resp.seeders),
+            (b"incomplete".as_ref(), &mut resp.leechers),
+        ] {
+            if let Some(i) = d.get(key).and_then(BEncodeRef::as_int) {
+                *count = i as u32;
+            }
+        }
+        resp.warning = d
+            .get(b"warning message".as_ref())
+            .and_then(BEncodeRef::as_bytes)
+            .map(|b| String::from_utf8_lossy(b).into_owned());
+        resp.external_ip = d
+            .get(b"external ip".as_ref())
+            .and_then(BEncodeRef::as_bytes)
+            .and_then(util::bytes_to_ip);
+        match d.get(b"interval".as_ref()) {
+            Some(BEncodeRef::Int(i)) => {
                 resp.interval = *i as u32;
             }
             _ => {
@@ -414,3 +667,103 @@ impl TrackerResponse {
         Ok(resp)
     }
 }
+
+impl ScrapeResponse {
+    pub fn from_bencode(data: &BEncodeRef<'_>, hash: &[u8; 20]) -> Result<ScrapeResponse> {
+        let d = data.as_dict().ok_or(ErrorKind::InvalidResponse(
+            "Scrape response must be a dictionary type!",
+        ))?;
+        if let Some(BEncodeRef::String(data)) = d.get(b"failure reason".as_ref()) {
+            let reason = String::from_utf8(data.to_vec())
+                .chain_err(|| ErrorKind::InvalidResponse("Failure reason must be UTF8!"))?;
+            return Err(ErrorKind::TrackerError(reason).into());
+        }
+        let stats = d
+            .get(b"files".as_ref())
+            .and_then(BEncodeRef::as_dict)
+            .and_then(|files| files.get(hash.as_ref()))
+            .and_then(BEncodeRef::as_dict)
+            .ok_or(ErrorKind::InvalidResponse(
+                "Scrape response must include the torrent!",
+            ))?;
+        let count = |key: &[u8]| {
+            stats
+                .get(key)
+                .and_then(BEncodeRef::as_int)
+                .map(|i| i as u32)
+                .unwrap_or(0)
+        };
+        Ok(ScrapeResponse {
+            seeders: count(b"complete"),
+            leechers: count(b"incomplete"),
+            completed: count(b"downloaded"),
+        })
+    }
+}
+
+/// The scrape URL corresponding to an HTTP tracker's announce URL,
+/// which only exists if the last path segment begins with "announce".
+fn scrape_url(url: &Url) -> Option<Url> {
+    let path = url.path();
+    let idx = path.rfind('/')? + 1;
+    if !path[idx..].starts_with("announce") {
+        return None;
+    }
+    let mut scrape = url.clone();
+    scrape.set_path(&format!("{}scrape{}", &path[..idx], &path[idx + 8..]));
+    Some(scrape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode;
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape = |s: &str| scrape_url(&Url::parse(s).unwrap()).map(|u| u.to_string());
+        assert_eq!(
+            scrape("http://example.com/announce"),
+            Some("http://example.com/scrape".to_owned())
+        );
+        assert_eq!(
+            scrape("http://example.com/x/announce.php?passkey=1"),
+            Some("http://example.com/x/scrape.php?passkey=1".to_owned())
+        );
+        assert_eq!(scrape("http://example.com/a"), None);
+        assert_eq!(scrape("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn test_scrape_response() {
+        let hash = [b'a'; 20];
+        let mut data = b"d5:filesd20:".to_vec();
+        data.extend_from_slice(&hash);
+        data.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let resp = ScrapeResponse::from_bencode(&bencode::decode_ref(&data).unwrap(), &hash);
+        assert_eq!(
+            resp.unwrap(),
+            ScrapeResponse {
+                seeders: 5,
+                leechers: 10,
+                completed: 50,
+            }
+        );
+        let other = ScrapeResponse::from_bencode(&bencode::decode_ref(&data).unwrap(), &[0; 20]);
+        assert!(other.is_err());
+    }
+
+    #[test]
+    fn test_tracker_response() {
+        let parse = |d: &[u8]| TrackerResponse::from_bencode(&bencode::decode_ref(d).unwrap());
+        let resp = parse(b"d8:intervali60e5:peers6:\x7f\0\0\x01\x1a\xe115:warning message4:slowe")
+            .unwrap();
+        assert_eq!(resp.interval, 60);
+        assert_eq!(resp.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(resp.warning, Some("slow".to_owned()));
+        match parse(b"d14:failure reason20:unregistered torrente") {
+            Err(Error(ErrorKind::TrackerError(r), _)) => assert_eq!(r, "unregistered torrent"),
+            _ => panic!("expected tracker error"),
+        }
+    }
+}
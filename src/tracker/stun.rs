@@ -0,0 +1,209 @@
+//! Minimal STUN (RFC 5389) client, asking a server which address our
+//! UDP packets come from.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::tracker::{dns, Result};
+use crate::util::net;
+
+/// Interval between queries
+const QUERY_INTERVAL_SECS: u64 = 30 * 60;
+/// Interval between queries while the server hasn't answered
+const RETRY_SECS: u64 = 60;
+/// ID the server's host is resolved with, kept clear of the connection
+/// IDs of UDP trackers
+pub const DNS_ID: usize = usize::MAX;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+
+pub struct Stun {
+    pub id: usize,
+    sock: UdpSocket,
+    host: String,
+    port: u16,
+    /// Address of the server, once resolved
+    server: Option<SocketAddr>,
+    transaction: [u8; 12],
+    next_query: Instant,
+    buf: Vec<u8>,
+}
+
+impl Stun {
+    pub fn new(reg: &amy::Registrar, server: &str) -> io::Result<Stun> {
+        let (host, port) = server
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "expected host:port"))?;
+        let sock = net::udp_socket(false, 0)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        Ok(Stun {
+            id,
+            sock,
+            host: host.to_owned(),
+            port,
+            server: None,
+            transaction: [0; 12],
+            next_query: Instant::now(),
+            buf: vec![0; 512],
+        })
+    }
+
+    /// Queries the server if it's time to, resolving it first.
+    pub fn tick(&mut self, dns: &mut dns::Resolver) {
+        let now = Instant::now();
+        if now < self.next_query {
+            return;
+        }
+        self.next_query = now + Duration::from_secs(RETRY_SECS);
+        let server = match self.server {
+            Some(server) => server,
+            None => {
+                match dns.new_query(DNS_ID, &self.host) {
+                    Ok(Some(ip)) => self.resolved(Ok(ip)),
+                    Ok(None) => {}
+                    Err(e) => debug!("Failed to resolve STUN server {}: {}", self.host, e),
+                }
+                return;
+            }
+        };
+        self.transaction = rand::random();
+        if let Err(e) = self
+            .sock
+            .send_to(&encode_request(&self.transaction), server)
+        {
+            debug!("Failed to send STUN request: {}", e);
+        }
+    }
+
+    /// Sets the server's address once resolved, to be queried on the
+    /// next tick.
+    pub fn resolved(&mut self, res: Result<IpAddr>) {
+        match res {
+            Ok(ip @ IpAddr::V4(_)) => {
+                self.server = Some(SocketAddr::new(ip, self.port));
+                self.next_query = Instant::now();
+            }
+            Ok(_) => debug!("STUN server {} has no IPv4 address", self.host),
+            Err(e) => debug!("Failed to resolve STUN server {}: {}", self.host, e),
+        }
+    }
+
+    /// Our address as reported by the server, if it answered.
+    pub fn readable(&mut self) -> Option<IpAddr> {
+        let mut ip = None;
+        while let Ok((len, addr)) = self.sock.recv_from(&mut self.buf) {
+            if Some(addr) != self.server {
+                continue;
+            }
+            if let Some(a) = decode_response(&self.buf[..len], &self.transaction) {
+                self.next_query = Instant::now() + Duration::from_secs(QUERY_INTERVAL_SECS);
+                ip = Some(a.ip());
+            }
+        }
+        ip
+    }
+}
+
+fn encode_request(transaction: &[u8; 12]) -> [u8; HEADER_LEN] {
+    let mut req = [0u8; HEADER_LEN];
+    BigEndian::write_u16(&mut req[0..2], BINDING_REQUEST);
+    BigEndian::write_u32(&mut req[4..8], MAGIC_COOKIE);
+    req[8..].copy_from_slice(transaction);
+    req
+}
+
+/// The mapped address of a binding response to a transaction.
+fn decode_response(data: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if data.len() < HEADER_LEN
+        || BigEndian::read_u16(&data[0..2]) != BINDING_RESPONSE
+        || BigEndian::read_u32(&data[4..8]) != MAGIC_COOKIE
+        || &data[8..HEADER_LEN] != transaction
+    {
+        return None;
+    }
+    let len = BigEndian::read_u16(&data[2..4]) as usize;
+    let mut attrs = data.get(HEADER_LEN..HEADER_LEN + len)?;
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = BigEndian::read_u16(&attrs[0..2]);
+        let len = BigEndian::read_u16(&attrs[2..4]) as usize;
+        let value = attrs.get(4..4 + len)?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return decode_addr(value, Some(&data[4..HEADER_LEN])),
+            MAPPED_ADDRESS => mapped = decode_addr(value, None),
+            _ => {}
+        }
+        // Attributes are padded to multiples of 4 bytes
+        attrs = attrs.get(4 + len.div_ceil(4) * 4..).unwrap_or(&[]);
+    }
+    mapped
+}
+
+/// Decodes an address attribute, XORed with the cookie and transaction
+/// ID if given.
+fn decode_addr(value: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let mut addr = value[2..].to_vec();
+    if let Some(key) = xor {
+        // Both the port and address are XORed from the start of the key
+        for (b, k) in addr[..2].iter_mut().zip(key) {
+            *b ^= k;
+        }
+        for (b, k) in addr[2..].iter_mut().zip(key) {
+            *b ^= k;
+        }
+    }
+    let port = BigEndian::read_u16(&addr[0..2]);
+    let ip = match (value[1], addr.len()) {
+        (1, 6) => IpAddr::V4(Ipv4Addr::new(addr[2], addr[3], addr[4], addr[5])),
+        (2, 18) => {
+            let mut oct = [0u8; 16];
+            oct.copy_from_slice(&addr[2..]);
+            IpAddr::V6(Ipv6Addr::from(oct))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_response() {
+        let tx = [7u8; 12];
+        let req = encode_request(&tx);
+        assert_eq!(&req[..4], &[0, 1, 0, 0]);
+
+        let mut resp = req.to_vec();
+        BigEndian::write_u16(&mut resp[0..2], BINDING_RESPONSE);
+        // An unknown attribute needing padding, then 1.2.3.4:6881 XORed
+        // with the cookie
+        resp.extend_from_slice(&[0x80, 0x22, 0, 3, b'a', b'b', b'c', 0]);
+        resp.extend_from_slice(&[0, 0x20, 0, 8, 0, 1]);
+        resp.extend_from_slice(&(6881u16 ^ 0x2112).to_be_bytes());
+        for (b, k) in [1u8, 2, 3, 4].iter().zip(&MAGIC_COOKIE.to_be_bytes()) {
+            resp.push(b ^ k);
+        }
+        let len = (resp.len() - HEADER_LEN) as u16;
+        BigEndian::write_u16(&mut resp[2..4], len);
+        assert_eq!(
+            decode_response(&resp, &tx),
+            Some("1.2.3.4:6881".parse().unwrap())
+        );
+        assert_eq!(decode_response(&resp, &[8u8; 12]), None);
+        assert_eq!(decode_response(&resp[..resp.len() - 1], &tx), None);
+    }
+}
@@ -6,9 +6,10 @@ use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use rand::random;
 
 use crate::tracker::{
-    dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, TrackerResponse,
+    dns, Error, ErrorKind, Event, Query, Response, Result, ResultExt, ScrapeResponse,
+    TrackerResponse,
 };
-use crate::util::{bytes_to_addr, FHashMap, UHashMap};
+use crate::util::{bytes_to_addr, net, FHashMap, UHashMap};
 use crate::{CONFIG, PEER_ID};
 
 // We're not going to bother with backoff, if the tracker/network aren't working now
@@ -20,6 +21,8 @@ const MAGIC_NUM: u64 = 0x417_2710_1980;
 pub struct Handler {
     id: usize,
     sock: UdpSocket,
+    /// Socket for IPv6 trackers and its id, if IPv6 is available
+    v6: Option<(usize, UdpSocket)>,
     connections: UHashMap<Connection>,
     transactions: FHashMap<u32, usize>,
     conn_count: usize,
@@ -27,28 +30,37 @@ pub struct Handler {
 }
 
 struct Connection {
-    torrent: usize,
     last_updated: time::Instant,
     last_retrans: time::Instant,
     state: State,
-    announce: Announce,
+    query: Query,
 }
 
 enum State {
     ResolvingDNS { port: u16 },
     Connecting { addr: SocketAddr, data: [u8; 16] },
     Announcing { addr: SocketAddr, data: [u8; 98] },
+    Scraping { addr: SocketAddr, data: [u8; 36] },
 }
 
 impl Handler {
     pub fn new(reg: &amy::Registrar) -> io::Result<Handler> {
         let port = CONFIG.trk.port;
-        let sock = UdpSocket::bind(("0.0.0.0", port))?;
-        sock.set_nonblocking(true)?;
+        let sock = net::udp_socket(false, port)?;
         let id = reg.register(&sock, amy::Event::Read)?;
+        let v6 = match net::udp_socket(true, port)
+            .and_then(|sock| Ok((reg.register(&sock, amy::Event::Read)?, sock)))
+        {
+            Ok(v6) => Some(v6),
+            Err(e) => {
+                info!("Failed to bind IPv6 tracker socket, IPv6 trackers disabled: {}", e);
+                None
+            }
+        };
         Ok(Handler {
             id,
             sock,
+            v6,
             connections: UHashMap::default(),
             transactions: FHashMap::default(),
             conn_count: 0,
@@ -56,8 +68,8 @@ impl Handler {
         })
     }
 
-    pub fn id(&self) -> usize {
-        self.id
+    pub fn owns(&self, id: usize) -> bool {
+        self.id == id || self.v6.as_ref().map(|&(v6, _)| v6 == id).unwrap_or(false)
     }
 
     pub fn complete(&self) -> bool {
@@ -72,9 +84,9 @@ impl Handler {
         self.connections.contains_key(&id)
     }
 
-    pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
-        let url = req.url.clone();
-        debug!("Received a new announce req for {:?}", url);
+    pub fn new_query(&mut self, query: Query, dns: &mut dns::Resolver) -> Result<()> {
+        let url = query.url().clone();
+        debug!("Received a new tracker req for {:?}", url);
         let host = url.host_str().ok_or_else(|| {
             Error::from(ErrorKind::InvalidRequest(
                 "Tracker announce url has no host!".to_owned(),
@@ -90,11 +102,10 @@ impl Handler {
         self.connections.insert(
             id,
             Connection {
-                torrent: req.id,
                 last_updated: time::Instant::now(),
                 last_retrans: time::Instant::now(),
                 state: State::ResolvingDNS { port },
-                announce: req,
+                query,
             },
         );
         debug!("Dispatching DNS req for {:?}, url: {:?}", id, host);
@@ -134,11 +145,7 @@ impl Handler {
                             self.transactions.insert(tid, id);
                             None
                         }
-                        Err(e) => Some(Response::Tracker {
-                            tid: conn.torrent,
-                            url: conn.announce.url.clone(),
-                            resp: Err(e),
-                        }),
+                        Err(e) => Some(conn.query.failed(e)),
                     }
                 }
                 _ => None,
@@ -158,7 +165,7 @@ impl Handler {
 
     pub fn readable(&mut self) -> Vec<Response> {
         let mut resps = Vec::new();
-        while let Ok((v, _)) = self.sock.recv_from(&mut self.buf[..]) {
+        while let Some(v) = self.recv() {
             let action = BigEndian::read_u32(&self.buf[0..4]);
             match action {
                 0 if v == 16 => {
@@ -171,6 +178,11 @@ impl Handler {
                         resps.push(r);
                     }
                 }
+                2 if v >= 20 => {
+                    if let Some(r) = self.process_scrape() {
+                        resps.push(r);
+                    }
+                }
                 3 if v >= 8 => {
                     if let Some(r) = self.process_error(v) {
                         resps.push(r);
@@ -190,12 +202,8 @@ impl Handler {
         {
             self.connections.retain(|id, conn| {
                 if conn.last_updated.elapsed() > time::Duration::from_millis(TIMEOUT_MS) {
-                    resps.push(Response::Tracker {
-                        tid: conn.torrent,
-                        url: conn.announce.url.clone(),
-                        resp: Err(ErrorKind::Timeout.into()),
-                    });
-                    debug!("Announce {:?} timed out", id);
+                    resps.push(conn.query.failed(ErrorKind::Timeout.into()));
+                    debug!("Tracker request {:?} timed out", id);
                     false
                 } else {
                     if conn.last_retrans.elapsed() > time::Duration::from_millis(RETRANS_MS) {
@@ -231,39 +239,32 @@ impl Handler {
             None => return None,
         };
 
-        let mut data = [0u8; 98];
-        {
-            let conn = match self.connections.get_mut(&id) {
-                Some(conn) => conn,
-                None => return None,
-            };
-            let addr = match conn.state {
-                State::Connecting { addr, .. } => addr,
-                _ => return None,
-            };
-
-            {
+        let conn = self.connections.get_mut(&id)?;
+        let addr = match conn.state {
+            State::Connecting { addr, .. } => addr,
+            _ => return None,
+        };
+        let tid = random::<u32>();
+        self.transactions.insert(tid, id);
+        conn.state = match conn.query {
+            Query::Announce(ref announce) => {
+                let mut data = [0u8; 98];
                 let mut announce_req = Cursor::new(&mut data[..]);
                 announce_req.write_u64::<BigEndian>(connection_id).unwrap();
                 // announce action
                 announce_req.write_u32::<BigEndian>(1).unwrap();
-
-                let tid = random::<u32>();
                 announce_req.write_u32::<BigEndian>(tid).unwrap();
-                self.transactions.insert(tid, id);
 
-                announce_req.write_all(&conn.announce.hash).unwrap();
+                announce_req.write_all(&announce.hash).unwrap();
                 announce_req.write_all(&PEER_ID[..]).unwrap();
                 announce_req
-                    .write_u64::<BigEndian>(conn.announce.downloaded as u64)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.left as u64)
+                    .write_u64::<BigEndian>(announce.downloaded)
                     .unwrap();
+                announce_req.write_u64::<BigEndian>(announce.left).unwrap();
                 announce_req
-                    .write_u64::<BigEndian>(conn.announce.uploaded as u64)
+                    .write_u64::<BigEndian>(announce.uploaded)
                     .unwrap();
-                match conn.announce.event {
+                match announce.event {
                     Some(Event::Started) => {
                         announce_req.write_u32::<BigEndian>(2).unwrap();
                     }
@@ -280,19 +281,28 @@ impl Handler {
 
                 // IP
                 announce_req.write_u32::<BigEndian>(0).unwrap();
-                // Key - TODO: randomly generate this
-                announce_req.write_u32::<BigEndian>(0xFFFF_00BA).unwrap();
+                // Key
+                let key = CONFIG.trk.key.unwrap_or(0xFFFF_00BA);
+                announce_req.write_u32::<BigEndian>(key).unwrap();
                 // Num want
-                let nw = conn.announce.num_want.map(i32::from).unwrap_or(-1);
+                let nw = announce.num_want.map(i32::from).unwrap_or(-1);
                 announce_req.write_i32::<BigEndian>(nw).unwrap();
                 // port
-                announce_req
-                    .write_u16::<BigEndian>(conn.announce.port)
-                    .unwrap();
+                announce_req.write_u16::<BigEndian>(announce.port).unwrap();
+                State::Announcing { addr, data }
             }
-            conn.state = State::Announcing { addr, data };
-            conn.last_updated = time::Instant::now();
-        }
+            Query::Scrape(ref scrape) => {
+                let mut data = [0u8; 36];
+                let mut scrape_req = Cursor::new(&mut data[..]);
+                scrape_req.write_u64::<BigEndian>(connection_id).unwrap();
+                // scrape action
+                scrape_req.write_u32::<BigEndian>(2).unwrap();
+                scrape_req.write_u32::<BigEndian>(tid).unwrap();
+                scrape_req.write_all(&scrape.hash).unwrap();
+                State::Scraping { addr, data }
+            }
+        };
+        conn.last_updated = time::Instant::now();
         self.send_data(id)
     }
 
@@ -310,6 +320,13 @@ impl Handler {
             Some(c) => c,
             None => return None,
         };
+        let (tid, url) = match conn.query {
+            Query::Announce(a) => (a.id, a.url),
+            q => {
+                let e = ErrorKind::InvalidResponse("Unexpected announce response");
+                return Some(q.failed(e.into()));
+            }
+        };
 
         resp.interval = announce_resp.read_u32::<BigEndian>().unwrap();
         resp.leechers = announce_resp.read_u32::<BigEndian>().unwrap();
@@ -321,12 +338,31 @@ impl Handler {
             }
         }
         Some(Response::Tracker {
-            tid: conn.torrent,
-            url: conn.announce.url,
+            tid,
+            url,
             resp: Ok(resp),
         })
     }
 
+    fn process_scrape(&mut self) -> Option<Response> {
+        let transaction_id = BigEndian::read_u32(&self.buf[4..8]);
+        let id = self.transactions.remove(&transaction_id)?;
+        let conn = self.connections.remove(&id)?;
+        let resp = ScrapeResponse {
+            seeders: BigEndian::read_u32(&self.buf[8..12]),
+            completed: BigEndian::read_u32(&self.buf[12..16]),
+            leechers: BigEndian::read_u32(&self.buf[16..20]),
+        };
+        match conn.query {
+            Query::Scrape(s) => Some(Response::Scrape {
+                tid: s.id,
+                url: s.url,
+                resp: Ok(resp),
+            }),
+            q => Some(q.failed(ErrorKind::InvalidResponse("Unexpected scrape response").into())),
+        }
+    }
+
     fn process_error(&mut self, len: usize) -> Option<Response> {
         let mut s = String::new();
         let mut connect_resp = Cursor::new(&self.buf[4..len]);
@@ -343,20 +379,21 @@ impl Handler {
         };
 
         if connect_resp.read_to_string(&mut s).is_err() {
-            let resp =
-                Err(ErrorKind::InvalidResponse("Tracker error response was invalid UTF8").into());
-            Some(Response::Tracker {
-                tid: conn.torrent,
-                url: conn.announce.url,
-                resp,
-            })
+            Some(conn.query.failed(
+                ErrorKind::InvalidResponse("Tracker error response was invalid UTF8").into(),
+            ))
         } else {
-            Some(Response::Tracker {
-                tid: conn.torrent,
-                url: conn.announce.url,
-                resp: Err(ErrorKind::TrackerError(s).into()),
-            })
+            Some(conn.query.failed(ErrorKind::TrackerError(s).into()))
+        }
+    }
+
+    /// Reads a packet from either socket, returning its length.
+    fn recv(&mut self) -> Option<usize> {
+        if let Ok((v, _)) = self.sock.recv_from(&mut self.buf[..]) {
+            return Some(v);
         }
+        let (_, ref sock) = self.v6.as_ref()?;
+        sock.recv_from(&mut self.buf[..]).ok().map(|(v, _)| v)
     }
 
     fn new_conn(&mut self) -> usize {
@@ -366,34 +403,26 @@ impl Handler {
     }
 
     fn send_data(&mut self, id: usize) -> Option<Response> {
-        let tid;
         let res = {
             let conn = self.connections.get_mut(&id).unwrap();
-            tid = conn.torrent;
             // If this actually blocks, something is really fucked(prob with the NIC)
             // and i dont think we need to care
-            match conn.state {
-                State::Connecting { ref addr, ref data } => {
-                    conn.last_retrans = time::Instant::now();
-                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
-                }
-                State::Announcing { ref addr, ref data } => {
-                    conn.last_retrans = time::Instant::now();
-                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
-                }
-                _ => Ok(0),
-            }
+            let (addr, data): (_, &[u8]) = match conn.state {
+                State::Connecting { addr, ref data } => (addr, data),
+                State::Announcing { addr, ref data } => (addr, data),
+                State::Scraping { addr, ref data } => (addr, data),
+                _ => return None,
+            };
+            conn.last_retrans = time::Instant::now();
+            let sock = match self.v6 {
+                Some((_, ref sock)) if addr.is_ipv6() => sock,
+                _ => &self.sock,
+            };
+            sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
         };
 
         match res {
-            Err(e) => {
-                let url = self.connections.remove(&id).unwrap().announce.url;
-                Some(Response::Tracker {
-                    tid,
-                    url,
-                    resp: Err(e),
-                })
-            }
+            Err(e) => Some(self.connections.remove(&id).unwrap().query.failed(e)),
             Ok(_) => None,
         }
     }
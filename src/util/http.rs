@@ -1,3 +1,6 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
 use url::percent_encoding::percent_encode_byte;
 
 #[derive(Debug)]
@@ -87,6 +90,97 @@ impl<'a> RequestBuilder<'a> {
     }
 }
 
+/// Guesses the MIME type of a file from its extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.to_lowercase(),
+        None => return "application/octet-stream",
+    };
+    match ext.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ogv" => "video/ogg",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "aac" => "audio/aac",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "txt" | "nfo" | "log" | "md" => "text/plain; charset=utf-8",
+        "srt" => "application/x-subrip",
+        "vtt" => "text/vtt",
+        "pdf" => "application/pdf",
+        "epub" => "application/epub+zip",
+        "zip" => "application/zip",
+        "tar" => "application/x-tar",
+        "gz" => "application/gzip",
+        "torrent" => "application/x-bittorrent",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether a browser can display or stream the MIME type
+/// directly, rather than having to save it. Types which can run
+/// scripts are never displayed, as the data comes from torrents.
+pub fn mime_inline(mime: &str) -> bool {
+    if mime == "image/svg+xml" || mime.starts_with("text/html") {
+        return false;
+    }
+    mime.starts_with("video/")
+        || mime.starts_with("audio/")
+        || mime.starts_with("image/")
+        || mime.starts_with("text/")
+        || mime == "application/pdf"
+}
+
+/// Formats a timestamp as an RFC 7231 IMF-fixdate, e.g. for Last-Modified.
+pub fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Parses an HTTP date as sent in If-Modified-Since or If-Range.
+pub fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date.trim())
+        .ok()
+        .map(|d| d.with_timezone(&Utc))
+}
+
+/// Formats a Content-Disposition header value for a file name. Names
+/// are given both as a quoted ASCII fallback and RFC 5987 encoded, so
+/// neither can break out of the header.
+pub fn content_disposition(disposition: &str, name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(percent_encode_byte(byte));
+        }
+    }
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, fallback, encoded
+    )
+}
+
 fn encode_param(param: &[u8], buf: &mut Vec<u8>) {
     for byte in param {
         let c = char::from(*byte);
@@ -137,6 +231,38 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_http_date() {
+        let date = parse_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(date.timestamp(), 784_111_777);
+        assert_eq!(format_date(date), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(parse_date("yesterday").is_none());
+    }
+
+    #[test]
+    fn test_mime_type() {
+        assert_eq!(mime_type(Path::new("a/b/Movie.MKV")), "video/x-matroska");
+        assert_eq!(mime_type(Path::new("song.flac")), "audio/flac");
+        assert_eq!(mime_type(Path::new("README")), "application/octet-stream");
+        assert!(mime_inline(mime_type(Path::new("clip.mp4"))));
+        assert!(!mime_inline(mime_type(Path::new("data.bin"))));
+        assert!(!mime_inline(mime_type(Path::new("logo.svg"))));
+        assert!(!mime_inline("text/html; charset=utf-8"));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("inline", "a b.mkv"),
+            "inline; filename=\"a b.mkv\"; filename*=UTF-8''a%20b.mkv"
+        );
+        assert_eq!(
+            content_disposition("attachment", "x\"\r\nSet-Cookie: é.zip"),
+            "attachment; filename=\"x___Set-Cookie: _.zip\"; \
+             filename*=UTF-8''x%22%0D%0ASet-Cookie%3A%20%C3%A9.zip"
+        );
+    }
+
     #[test]
     fn test_percent_encode_query() {
         let mut encoded = Vec::new();
@@ -1,13 +1,15 @@
 pub mod http;
 mod io;
 pub mod native;
+pub mod net;
+pub mod systemd;
 
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write as FWrite;
 use std::hash::BuildHasherDefault;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
 
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use metrohash::MetroHash;
 use rand::distributions::Alphanumeric;
 use rand::{self, Rng};
@@ -48,6 +50,13 @@ pub fn random_string(len: usize) -> String {
         .collect::<String>()
 }
 
+/// SHA-1 digest of data, using the implementation selected at build time.
+#[cfg(feature = "sha1-openssl")]
+pub fn sha1_hash(data: &[u8]) -> [u8; 20] {
+    openssl::sha::sha1(data)
+}
+
+#[cfg(not(feature = "sha1-openssl"))]
 pub fn sha1_hash(data: &[u8]) -> [u8; 20] {
     let mut ctx = Sha1::new();
     ctx.update(data);
@@ -108,6 +117,16 @@ pub fn id_to_hash(s: &str) -> Option<[u8; 20]> {
     Some(data)
 }
 
+pub fn hex_to_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 == 1 {
+        return None;
+    }
+    let c: Vec<char> = s.chars().collect();
+    c.chunks(2)
+        .map(|p| Some(hex_to_bit(p[0])? << 4 | hex_to_bit(p[1])?))
+        .collect()
+}
+
 fn hex_to_bit(c: char) -> Option<u8> {
     let r = match c {
         '0' => 0,
@@ -131,31 +150,62 @@ fn hex_to_bit(c: char) -> Option<u8> {
     Some(r)
 }
 
+/// Parses a compact IPv4 (6 byte) or IPv6 (18 byte) address.
 pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
+    if p.len() == 18 {
+        let mut oct = [0u8; 16];
+        oct.copy_from_slice(&p[..16]);
+        let ip = Ipv6Addr::from(oct);
+        return SocketAddr::V6(SocketAddrV6::new(ip, BigEndian::read_u16(&p[16..]), 0, 0));
+    }
     let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
     SocketAddr::V4(SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..])))
 }
 
-pub fn addr_to_bytes(addr: &SocketAddr) -> [u8; 6] {
-    let mut data = [0u8; 6];
-    match *addr {
-        SocketAddr::V4(s) => {
-            let oct = s.ip().octets();
-            data[0] = oct[0];
-            data[1] = oct[1];
-            data[2] = oct[2];
-            data[3] = oct[3];
-            BigEndian::write_u16(&mut data[4..], s.port());
+/// Parses a compact IPv4 (4 byte) or IPv6 (16 byte) address.
+pub fn bytes_to_ip(p: &[u8]) -> Option<IpAddr> {
+    match p.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(p[0], p[1], p[2], p[3]))),
+        16 => {
+            let mut oct = [0u8; 16];
+            oct.copy_from_slice(p);
+            Some(IpAddr::V6(Ipv6Addr::from(oct)))
         }
-        _ => panic!("IPv6 DHT not supported"),
+        _ => None,
     }
-    data
 }
 
-pub fn find_subseq(haystack: &[u8], needle: &[u8]) -> Option<usize> {
-    haystack
-        .windows(needle.len())
-        .position(|window| window == needle)
+pub fn ip_to_bytes(ip: &IpAddr) -> Vec<u8> {
+    match *ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// The globally routable IPv6 address of this host, if it has one.
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    lazy_static! {
+        static ref LOCAL_IPV6: Option<Ipv6Addr> = {
+            // Connecting a UDP socket sends nothing, but picks the
+            // source address which would be used to reach the internet
+            let sock = UdpSocket::bind("[::]:0").ok()?;
+            sock.connect("[2001:4860:4860::8888]:53").ok()?;
+            match sock.local_addr().ok()?.ip() {
+                IpAddr::V6(ip) if ip.segments()[0] & 0xe000 == 0x2000 => Some(ip),
+                _ => None,
+            }
+        };
+    }
+    *LOCAL_IPV6
+}
+
+pub fn addr_to_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut data = match *addr {
+        SocketAddr::V4(s) => s.ip().octets().to_vec(),
+        SocketAddr::V6(s) => s.ip().octets().to_vec(),
+    };
+    data.write_u16::<BigEndian>(addr.port()).unwrap();
+    data
 }
 
 #[macro_export]
@@ -175,4 +225,36 @@ mod test {
         let s = hash_to_id(&hash);
         assert_eq!(id_to_hash(&s).unwrap(), hash);
     }
+
+    #[test]
+    fn test_compact_addr() {
+        for addr in &["1.2.3.4:6881", "[2001:db8::1]:6881"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(bytes_to_addr(&addr_to_bytes(&addr)), addr);
+        }
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            hash_to_id(&sha1_hash(b"abc")),
+            "A9993E364706816ABA3E25717850C26C9CD0D89D"
+        );
+    }
+
+    /// Compare SHA-1 implementations with
+    /// cargo test --release [--features sha1-asm|sha1-openssl] bench_sha1 -- --ignored --nocapture
+    #[ignore]
+    #[test]
+    fn bench_sha1() {
+        use std::time::Instant;
+
+        let data = vec![7u8; 4 * 1024 * 1024];
+        let start = Instant::now();
+        for _ in 0..64 {
+            sha1_hash(&data);
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!("SHA-1: {:.0} MiB/s", 256. / secs);
+    }
 }
@@ -4,6 +4,7 @@ use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 
 use nix::errno::Errno;
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
 
 use crate::util::io::io_err;
 
@@ -45,3 +46,57 @@ pub fn fallocate(f: &File, len: u64) -> io::Result<bool> {
         }
     }
 }
+
+/// Whether a range of a file is entirely in the page cache, so that
+/// sending it won't block on reading from disk.
+#[cfg(target_os = "linux")]
+pub fn is_cached(f: &File, offset: u64, len: u64) -> bool {
+    use nix::libc;
+
+    if len == 0 {
+        return true;
+    }
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let start = offset - offset % page;
+    let map_len = (offset + len - start) as usize;
+    let pages = (map_len as u64).div_ceil(page) as usize;
+    let mut resident = vec![0u8; pages];
+    unsafe {
+        let addr = libc::mmap(
+            std::ptr::null_mut(),
+            map_len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            f.as_raw_fd(),
+            start as libc::off_t,
+        );
+        if addr == libc::MAP_FAILED {
+            return false;
+        }
+        let res = libc::mincore(addr, map_len, resident.as_mut_ptr());
+        libc::munmap(addr, map_len);
+        res == 0 && resident.iter().all(|p| p & 1 == 1)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_cached(_: &File, _: u64, _: u64) -> bool {
+    false
+}
+
+/// Raises the soft limit on open file descriptors to the hard limit,
+/// returning the resulting limit.
+pub fn raise_fd_limit() -> io::Result<u64> {
+    let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE)?;
+    if soft >= hard {
+        return Ok(soft);
+    }
+    match setrlimit(Resource::RLIMIT_NOFILE, hard, hard) {
+        Ok(()) => Ok(hard),
+        // Some systems don't allow the hard limit if it's unlimited
+        Err(e) => {
+            debug!("Failed to raise the open file limit to {}: {}", hard, e);
+            Ok(soft)
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Sockets bound to the configured local address and interface, keeping
+//! traffic off other routes, e.g. when it should only go through a VPN.
+
+use std::io;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream,
+    UdpSocket,
+};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic;
+
+use net2::{TcpBuilder, UdpBuilder};
+
+use crate::{CONFIG, NET_DOWN, NET_STOPPED};
+
+/// Local address for sockets of a family on a port. Fails if an address
+/// of the other family is configured, as no traffic may bypass it.
+fn local_addr(v6: bool, port: u16) -> io::Result<SocketAddr> {
+    match (CONFIG.net.bind_address, v6) {
+        (Some(ip @ IpAddr::V4(_)), false) | (Some(ip @ IpAddr::V6(_)), true) => {
+            Ok(SocketAddr::new(ip, port))
+        }
+        (Some(_), _) => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no bind address of this family",
+        )),
+        (None, false) => Ok(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)),
+        (None, true) => Ok(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    if let Some(ref iface) = CONFIG.net.bind_interface {
+        setsockopt(sock.as_raw_fd(), sockopt::BindToDevice, &iface.into())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device<S: AsRawFd>(_: &S) -> io::Result<()> {
+    if CONFIG.net.bind_interface.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "binding to an interface is only supported on Linux",
+        ));
+    }
+    Ok(())
+}
+
+/// Traffic class configured for peer sockets, as a DSCP or raw TOS byte.
+fn traffic_class() -> Option<u8> {
+    CONFIG.net.dscp.map(|d| d << 2).or(CONFIG.net.tos)
+}
+
+/// Sets the configured traffic class and fwmark on a peer socket, so
+/// routers and policy routing can tell its traffic apart.
+#[cfg(target_os = "linux")]
+pub fn classify<S: AsRawFd>(sock: &S, v6: bool) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    if let Some(class) = traffic_class() {
+        let class = i32::from(class);
+        if v6 {
+            setsockopt(sock.as_raw_fd(), sockopt::Ipv6TClass, &class)?;
+        }
+        // IPv6 sockets also use this for IPv4 mapped peers
+        if let Err(e) = setsockopt(sock.as_raw_fd(), sockopt::IpTos, &class) {
+            if !v6 {
+                return Err(e.into());
+            }
+        }
+    }
+    if let Some(mark) = CONFIG.net.mark {
+        setsockopt(sock.as_raw_fd(), sockopt::Mark, &mark)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn classify<S: AsRawFd>(_: &S, _: bool) -> io::Result<()> {
+    if traffic_class().is_some() || CONFIG.net.mark.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "setting the traffic class and mark is only supported on Linux",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the configured fwmark can be set, as it needs CAP_NET_ADMIN,
+/// so a missing capability fails startup rather than every connection.
+#[cfg(target_os = "linux")]
+pub fn check_mark() -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    if let Some(mark) = CONFIG.net.mark {
+        let sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        setsockopt(sock.as_raw_fd(), sockopt::Mark, &mark).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("setting the mark requires CAP_NET_ADMIN: {}", e),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_mark() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_congestion<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    if let Some(ref algo) = CONFIG.net.congestion {
+        setsockopt(sock.as_raw_fd(), sockopt::TcpCongestion, &algo.into()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("congestion control {} is unavailable: {}", algo, e),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_congestion<S: AsRawFd>(_: &S) -> io::Result<()> {
+    if CONFIG.net.congestion.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "selecting the congestion control is only supported on Linux",
+        ));
+    }
+    Ok(())
+}
+
+/// Sets the configured buffer sizes and congestion control on a peer
+/// socket, or a listener whose accepted connections inherit them.
+pub fn tune<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    if let Some(size) = CONFIG.net.send_buffer {
+        setsockopt(sock.as_raw_fd(), sockopt::SndBuf, &size)?;
+    }
+    if let Some(size) = CONFIG.net.recv_buffer {
+        setsockopt(sock.as_raw_fd(), sockopt::RcvBuf, &size)?;
+    }
+    set_congestion(sock)
+}
+
+/// A TCP socket for an outgoing connection over IPv6 if v6 is set,
+/// IPv4 otherwise.
+pub fn tcp_stream(v6: bool) -> io::Result<TcpStream> {
+    let sock = if v6 {
+        TcpBuilder::new_v6()?
+    } else {
+        TcpBuilder::new_v4()?
+    };
+    bind_device(&sock)?;
+    if CONFIG.net.bind_address.is_some() {
+        sock.bind(local_addr(v6, 0)?)?;
+    }
+    sock.to_tcp_stream()
+}
+
+/// A TCP listener for incoming connections on a local port, over IPv6
+/// only if an IPv6 bind address is configured.
+pub fn tcp_listener(port: u16) -> io::Result<TcpListener> {
+    let v6 = CONFIG.net.bind_address.is_some_and(|ip| ip.is_ipv6());
+    let sock = if v6 {
+        TcpBuilder::new_v6()?
+    } else {
+        TcpBuilder::new_v4()?
+    };
+    sock.reuse_address(true)?;
+    bind_device(&sock)?;
+    sock.bind(local_addr(v6, port)?)?;
+    sock.listen(128)
+}
+
+/// A nonblocking UDP socket on a local port, which only handles IPv6
+/// if v6 is set.
+pub fn udp_socket(v6: bool, port: u16) -> io::Result<UdpSocket> {
+    let sock = if v6 {
+        let b = UdpBuilder::new_v6()?;
+        b.only_v6(true)?;
+        b
+    } else {
+        UdpBuilder::new_v4()?
+    };
+    bind_device(&sock)?;
+    let sock = sock.bind(local_addr(v6, port)?)?;
+    sock.set_nonblocking(true)?;
+    Ok(sock)
+}
+
+/// Whether network activity is paused, as the bound interface is missing
+/// or a client stopped it.
+pub fn down() -> bool {
+    NET_DOWN.load(atomic::Ordering::Relaxed) || NET_STOPPED.load(atomic::Ordering::Relaxed)
+}
+
+/// Whether the configured interface is up and holds the configured
+/// address, or true if neither is configured.
+pub fn bind_available() -> bool {
+    let (iface, ip) = (&CONFIG.net.bind_interface, CONFIG.net.bind_address);
+    if iface.is_none() && ip.is_none() {
+        return true;
+    }
+    let addrs = match nix::ifaddrs::getifaddrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            error!("Failed to list network interfaces: {}", e);
+            return false;
+        }
+    };
+    let (mut iface_up, mut has_ip) = (iface.is_none(), ip.is_none());
+    for a in addrs {
+        if let Some(i) = iface {
+            if *i != a.interface_name {
+                continue;
+            }
+        }
+        if !a.flags.contains(nix::net::if_::InterfaceFlags::IFF_UP) {
+            continue;
+        }
+        iface_up = true;
+        let addr = a.address.and_then(|addr| {
+            if let Some(sin) = addr.as_sockaddr_in() {
+                Some(IpAddr::V4(*SocketAddrV4::from(*sin).ip()))
+            } else {
+                addr.as_sockaddr_in6()
+                    .map(|sin6| IpAddr::V6(*SocketAddrV6::from(*sin6).ip()))
+            }
+        });
+        if addr.is_some() && addr == ip {
+            has_ip = true;
+        }
+    }
+    iface_up && has_ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unbound() {
+        // Nothing is bound to without a configured address or interface
+        assert!(bind_available());
+        let sock = udp_socket(false, 0).unwrap();
+        assert!(sock.local_addr().unwrap().ip().is_unspecified());
+        assert!(tcp_stream(true).is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+//! Integration with systemd: readiness and watchdog notifications for
+//! `Type=notify` services, and listening sockets passed by socket units.
+
+use std::ffi::OsStr;
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{env, io, process};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+use nix::time::{clock_gettime, ClockId};
+
+/// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+lazy_static! {
+    /// Sockets passed by systemd and their names, removed once taken
+    static ref LISTEN_FDS: Mutex<Vec<(String, RawFd)>> = Mutex::new(Vec::new());
+}
+
+/// Collects the sockets passed by systemd, if any. Must be called before
+/// any other thread is spawned, as it unsets the variables describing
+/// them so they aren't inherited by child processes.
+pub fn init() {
+    let fds = listen_fds(
+        process::id(),
+        env::var("LISTEN_PID").ok(),
+        env::var("LISTEN_FDS").ok(),
+        env::var("LISTEN_FDNAMES").ok(),
+    );
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    for (name, fd) in &fds {
+        if let Err(e) = fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            error!("Failed to set FD_CLOEXEC on socket {}: {}", name, e);
+        }
+        debug!("Received socket {} from systemd as fd {}", name, fd);
+    }
+    *LISTEN_FDS.lock().unwrap() = fds;
+}
+
+/// Takes the TCP socket passed by systemd with a name, as given by
+/// `FileDescriptorName=` in the socket unit.
+pub fn take_tcp(name: &str) -> io::Result<Option<TcpListener>> {
+    let fd = match take(name, |f| {
+        f == AddressFamily::Inet || f == AddressFamily::Inet6
+    }) {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+/// Takes the unix socket passed by systemd with a name.
+pub fn take_unix(name: &str) -> io::Result<Option<UnixListener>> {
+    let fd = match take(name, |f| f == AddressFamily::Unix) {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+fn take<F: Fn(AddressFamily) -> bool>(name: &str, family: F) -> Option<RawFd> {
+    let mut fds = LISTEN_FDS.lock().unwrap();
+    let pos = fds.iter().position(|&(ref n, fd)| {
+        n == name
+            && getsockname::<SockaddrStorage>(fd)
+                .ok()
+                .and_then(|addr| addr.family())
+                .map(&family)
+                .unwrap_or(false)
+    })?;
+    Some(fds.remove(pos).1)
+}
+
+/// Closes the sockets passed by systemd which weren't taken.
+pub fn close_unused() {
+    for (name, fd) in LISTEN_FDS.lock().unwrap().drain(..) {
+        error!(
+            "Socket {} passed by systemd is not used, it should be named peer or rpc",
+            name
+        );
+        nix::unistd::close(fd).ok();
+    }
+}
+
+/// Parses the sockets systemd passed to a process, which start at fd 3
+/// and are named by the colon separated LISTEN_FDNAMES.
+fn listen_fds(
+    pid: u32,
+    listen_pid: Option<String>,
+    count: Option<String>,
+    names: Option<String>,
+) -> Vec<(String, RawFd)> {
+    // The sockets may have been meant for a parent process
+    if listen_pid.and_then(|p| p.parse().ok()) != Some(pid) {
+        return Vec::new();
+    }
+    let count: RawFd = match count.and_then(|c| c.parse().ok()) {
+        Some(count) => count,
+        None => return Vec::new(),
+    };
+    let names: Vec<_> = names
+        .as_ref()
+        .map(|n| n.split(':').collect())
+        .unwrap_or_default();
+    (0..count)
+        .map(|i| {
+            let name = names.get(i as usize).cloned().unwrap_or("unknown");
+            (name.to_owned(), LISTEN_FDS_START + i)
+        })
+        .collect()
+}
+
+/// Sends a state change such as READY=1 to systemd, if it's supervising
+/// the process as a notify service.
+pub fn notify(state: &str) {
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        if let Err(e) = send(&path, state) {
+            error!("Failed to notify systemd of {}: {}", state, e);
+        }
+    }
+}
+
+/// Tells systemd the config is being reloaded, which READY=1 should
+/// follow once done.
+pub fn notify_reloading() {
+    let now = match clock_gettime(ClockId::CLOCK_MONOTONIC) {
+        Ok(now) => now,
+        Err(e) => {
+            error!("Failed to get the monotonic time: {}", e);
+            return;
+        }
+    };
+    let usec = now.tv_sec() as u64 * 1_000_000 + now.tv_nsec() as u64 / 1000;
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let sock = UnixDatagram::unbound()?;
+    match path.as_bytes().split_first() {
+        Some((b'@', name)) => send_abstract(&sock, name, state),
+        _ => sock.send_to(state.as_bytes(), path).map(|_| ()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(sock: &UnixDatagram, name: &[u8], state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    sock.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_: &UnixDatagram, _: &[u8], _: &str) -> io::Result<()> {
+    Err(io::Error::other(
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+/// Time within which systemd's watchdog must be sent WATCHDOG=1, if it's
+/// enabled for the process.
+pub fn watchdog() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec)).filter(|d| *d > Duration::from_secs(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        let s = |s: &str| Some(s.to_owned());
+        assert_eq!(
+            listen_fds(10, s("10"), s("3"), s("peer:rpc")),
+            vec![
+                ("peer".to_owned(), 3),
+                ("rpc".to_owned(), 4),
+                ("unknown".to_owned(), 5)
+            ]
+        );
+        assert!(listen_fds(10, s("11"), s("1"), None).is_empty());
+        assert!(listen_fds(10, None, s("1"), None).is_empty());
+        assert!(listen_fds(10, s("10"), None, None).is_empty());
+    }
+}
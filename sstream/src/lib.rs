@@ -1,7 +1,9 @@
 use std::io::{self, Read};
 use std::net::{SocketAddr, TcpStream};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::sync::Arc;
+use std::time::Duration;
 
 use net2::{TcpBuilder, TcpStreamExt};
 use rustls::{self, Session};
@@ -10,7 +12,8 @@ use webpki_roots;
 
 const EINPROGRESS: i32 = 115;
 
-/// Nonblocking Secure TcpStream implementation.
+/// Nonblocking Secure TcpStream implementation, which may also wrap a
+/// plain UnixStream.
 pub struct SStream {
     conn: SConn,
     fd: i32,
@@ -18,6 +21,7 @@ pub struct SStream {
 
 enum SConn {
     Plain(TcpStream),
+    Unix(UnixStream),
     SSLC {
         conn: TcpStream,
         session: rustls::ClientSession,
@@ -39,36 +43,36 @@ impl SStream {
         SStream::new(conn, host)
     }
 
-    fn new(conn: TcpStream, host: Option<String>) -> io::Result<SStream> {
+    /// Wraps an unconnected socket, using TLS to the host if given.
+    pub fn new(conn: TcpStream, host: Option<String>) -> io::Result<SStream> {
         conn.set_nonblocking(true)?;
         let fd = conn.as_raw_fd();
-        let sock = match host {
-            Some(h) => {
-                let mut config = rustls::ClientConfig::new();
-                config
-                    .root_store
-                    .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-                let dns_name = match webpki::DNSNameRef::try_from_ascii_str(&h) {
-                    Ok(name) => name,
-                    Err(_) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "invalid host string used",
-                        ))
-                    }
-                };
-                let session = rustls::ClientSession::new(&Arc::new(config), dns_name);
-                SStream {
-                    conn: SConn::SSLC { conn, session },
-                    fd,
-                }
-            }
-            None => SStream {
-                conn: SConn::Plain(conn),
-                fd,
+        let conn = match host {
+            Some(h) => SConn::SSLC {
+                session: client_session(&h)?,
+                conn,
             },
+            None => SConn::Plain(conn),
         };
-        Ok(sock)
+        Ok(SStream { conn, fd })
+    }
+
+    /// Begins a TLS session to the host over a plain connection, such
+    /// as a tunnel through a proxy.
+    pub fn start_tls(self, host: &str) -> io::Result<SStream> {
+        match self.conn {
+            SConn::Plain(conn) => Ok(SStream {
+                conn: SConn::SSLC {
+                    session: client_session(host)?,
+                    conn,
+                },
+                fd: self.fd,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS started over a non plain connection",
+            )),
+        }
     }
 
     pub fn connect(&mut self, addr: SocketAddr) -> io::Result<()> {
@@ -84,6 +88,7 @@ impl SStream {
                 }
                 Ok(())
             }
+            SConn::Unix(_) => unreachable!("Unix socket TCP connect"),
             SConn::SSLS { .. } => unreachable!("Server side TLS connect"),
         }
     }
@@ -97,6 +102,15 @@ impl SStream {
         })
     }
 
+    pub fn from_unix(stream: UnixStream) -> io::Result<SStream> {
+        stream.set_nonblocking(true)?;
+        let fd = stream.as_raw_fd();
+        Ok(SStream {
+            conn: SConn::Unix(stream),
+            fd,
+        })
+    }
+
     pub fn from_ssl(conn: TcpStream, config: &Arc<rustls::ServerConfig>) -> io::Result<SStream> {
         conn.set_nonblocking(true)?;
         let fd = conn.as_raw_fd();
@@ -107,17 +121,33 @@ impl SStream {
         })
     }
 
-    pub fn get_stream(&self) -> &TcpStream {
+    /// Whether the stream is a unix socket connection.
+    pub fn is_unix(&self) -> bool {
+        matches!(self.conn, SConn::Unix(_))
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
         match self.conn {
-            SConn::Plain(ref c) => c,
-            SConn::SSLC { ref conn, .. } => conn,
-            SConn::SSLS { ref conn, .. } => conn,
+            SConn::Plain(ref c) => c.set_nonblocking(nonblocking),
+            SConn::Unix(ref c) => c.set_nonblocking(nonblocking),
+            SConn::SSLC { ref conn, .. } => conn.set_nonblocking(nonblocking),
+            SConn::SSLS { ref conn, .. } => conn.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.conn {
+            SConn::Plain(ref c) => c.set_write_timeout(timeout),
+            SConn::Unix(ref c) => c.set_write_timeout(timeout),
+            SConn::SSLC { ref conn, .. } => conn.set_write_timeout(timeout),
+            SConn::SSLS { ref conn, .. } => conn.set_write_timeout(timeout),
         }
     }
 
     fn read_(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self.conn {
             SConn::Plain(ref mut c) => c.read(buf),
+            SConn::Unix(ref mut c) => c.read(buf),
             SConn::SSLC {
                 ref mut conn,
                 ref mut session,
@@ -160,6 +190,16 @@ impl SStream {
     }
 }
 
+fn client_session(host: &str) -> io::Result<rustls::ClientSession> {
+    let mut config = rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str(host)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid host string used"))?;
+    Ok(rustls::ClientSession::new(&Arc::new(config), dns_name))
+}
+
 impl io::Read for SStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self.read_(buf) {
@@ -178,6 +218,7 @@ impl io::Write for SStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self.conn {
             SConn::Plain(ref mut c) => c.write(buf),
+            SConn::Unix(ref mut c) => c.write(buf),
             SConn::SSLC {
                 ref mut conn,
                 ref mut session,
@@ -200,6 +241,7 @@ impl io::Write for SStream {
     fn flush(&mut self) -> io::Result<()> {
         match self.conn {
             SConn::Plain(ref mut c) => c.flush(),
+            SConn::Unix(ref mut c) => c.flush(),
             SConn::SSLC {
                 ref mut conn,
                 ref mut session,
@@ -1,3 +1,6 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+
 use sstream::SStream;
 use url::Url;
 use ws::protocol::Message as WSMessage;
@@ -16,6 +19,19 @@ pub struct Client {
 
 impl Client {
     pub fn new(url: Url) -> Result<Client> {
+        if url.scheme() == "unix" {
+            let conn = UnixStream::connect(url.path()).chain_err(|| ErrorKind::Websocket)?;
+            let stream = SStream::from_unix(conn).chain_err(|| ErrorKind::Websocket)?;
+            stream
+                .set_nonblocking(false)
+                .chain_err(|| ErrorKind::Websocket)?;
+            // The handshake needs a host, though the server ignores it
+            let ws_url = Url::parse("ws://localhost/").unwrap();
+            return match Client::handshake(ws_url, stream)? {
+                Some(c) => Ok(c),
+                None => bail!("Could not connect to provided url!"),
+            };
+        }
         if !url.has_host() {
             bail!("Invalid websocket URL!");
         }
@@ -47,26 +63,33 @@ impl Client {
                 other => other.chain_err(|| ErrorKind::Websocket)?,
             };
             stream
-                .get_stream()
                 .set_nonblocking(false)
                 .chain_err(|| ErrorKind::Websocket)?;
-            if let Ok((client, _response)) = ws::client(url.clone(), stream) {
-                let mut c = Client {
-                    ws: client,
-                    serial: 0,
-                    version: Version { major: 0, minor: 0 },
-                };
-                if let SMessage::RpcVersion(v) = c.recv()? {
-                    c.version = v;
-                    return Ok(c);
-                } else {
-                    bail!("Expected a version message on start!");
-                }
+            if let Some(c) = Client::handshake(url.clone(), stream)? {
+                return Ok(c);
             }
         }
         bail!("Could not connect to provided url!");
     }
 
+    fn handshake(url: Url, stream: SStream) -> Result<Option<Client>> {
+        if let Ok((client, _response)) = ws::client(url, stream) {
+            let mut c = Client {
+                ws: client,
+                serial: 0,
+                version: Version { major: 0, minor: 0 },
+            };
+            if let SMessage::RpcVersion(v) = c.recv()? {
+                c.version = v;
+                Ok(Some(c))
+            } else {
+                bail!("Expected a version message on start!");
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn version(&self) -> &Version {
         &self.version
     }
@@ -106,3 +129,57 @@ impl Client {
         self.recv()
     }
 }
+
+/// Performs an HTTP request over the unix socket synapse listens on,
+/// returning the response body.
+pub fn unix_request(
+    url: &Url,
+    method: &str,
+    target: &str,
+    token: Option<&str>,
+    body: &[u8],
+) -> Result<Box<dyn Read>> {
+    let mut conn = UnixStream::connect(url.path()).chain_err(|| ErrorKind::HTTP)?;
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        target,
+        body.len()
+    );
+    if let Some(token) = token {
+        req.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    req.push_str("\r\n");
+    conn.write_all(req.as_bytes())
+        .and_then(|_| conn.write_all(body))
+        .chain_err(|| ErrorKind::HTTP)?;
+
+    let mut resp = BufReader::new(conn);
+    let mut line = String::new();
+    resp.read_line(&mut line).chain_err(|| ErrorKind::HTTP)?;
+    let status = line.split_whitespace().nth(1).unwrap_or("").to_owned();
+    if !status.starts_with('2') {
+        bail!("Unexpected response status: {}", line.trim());
+    }
+    let mut len = None;
+    loop {
+        line.clear();
+        if resp.read_line(&mut line).chain_err(|| ErrorKind::HTTP)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+                .chain_err(|| ErrorKind::HTTP);
+        }
+        let header = line.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse::<u64>().ok();
+            }
+        }
+    }
+    Ok(match len {
+        Some(len) => Box::new(resp.take(len)),
+        None => Box::new(resp),
+    })
+}
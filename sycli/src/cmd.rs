@@ -7,30 +7,43 @@ use prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE as TABLE_FORMAT;
 use prettytable::Table;
 use sha1::{Digest, Sha1};
 use ureq;
-use url::Url;
+use url::{Position, Url};
 
 use rpc::criterion::{Criterion, Operation, Value};
 use rpc::message::{self, CMessage, SMessage};
-use rpc::resource::{CResourceUpdate, Resource, ResourceKind, SResourceUpdate, Server};
+use rpc::resource::{
+    CResourceUpdate, Label, Resource, ResourceKind, SResourceUpdate, Server, ThrottleGroup,
+};
 use synapse_rpc as rpc;
 
-use crate::client::Client;
+use crate::client::{self, Client};
 use crate::error::{ErrorKind, Result, ResultExt};
 
+/// Options torrents are added with.
+pub struct AddOptions<'a> {
+    pub dir: Option<&'a str>,
+    pub start: bool,
+    pub import: bool,
+    pub seed_only: bool,
+    pub label: Option<&'a str>,
+    pub name: Option<&'a str>,
+    pub priorities: Vec<u8>,
+    pub merge: bool,
+    pub metadata_only: bool,
+}
+
 pub fn add(
     mut c: Client,
     url: &str,
     files: Vec<&str>,
-    dir: Option<&str>,
-    start: bool,
-    import: bool,
+    opts: &AddOptions<'_>,
     output: &str,
 ) -> Result<()> {
     for file in files {
         if let Ok(magnet) = Url::parse(file) {
-            add_magnet(&mut c, magnet, dir, start, output)?;
+            add_magnet(&mut c, magnet, opts, output)?;
         } else {
-            add_file(&mut c, url, file, dir, start, import, output)?;
+            add_file(&mut c, url, file, opts, output)?;
         }
     }
     Ok(())
@@ -40,9 +53,7 @@ fn add_file(
     c: &mut Client,
     url: &str,
     file: &str,
-    dir: Option<&str>,
-    start: bool,
-    import: bool,
+    opts: &AddOptions<'_>,
     output: &str,
 ) -> Result<()> {
     let mut torrent = Vec::new();
@@ -53,20 +64,31 @@ fn add_file(
     let msg = CMessage::UploadTorrent {
         serial: c.next_serial(),
         size: torrent.len() as u64,
-        path: dir.as_ref().map(|d| format!("{}", d)),
-        start,
-        import,
+        path: opts.dir.map(|d| d.to_owned()),
+        start: opts.start,
+        import: opts.import,
+        seed_only: opts.seed_only,
+        label: opts.label.map(|l| l.to_owned()),
+        name: opts.name.map(|n| n.to_owned()),
+        priorities: opts.priorities.clone(),
+        merge: opts.merge,
     };
     let token = if let SMessage::TransferOffer { token, .. } = c.rr(msg)? {
         token
     } else {
         bail!("Failed to receieve transfer offer from synapse!");
     };
-    let resp = ureq::post(url)
-        .set("Authorization", &format!("Bearer {}", token))
-        .send_bytes(&torrent);
-    if resp.error() {
-        bail!("Could not POST to synapse: {:?}", resp);
+    let url = Url::parse(url).unwrap();
+    if url.scheme() == "unix" {
+        client::unix_request(&url, "POST", "/", Some(&token), &torrent)
+            .chain_err(|| "Could not POST to synapse")?;
+    } else {
+        let resp = ureq::post(url.as_str())
+            .set("Authorization", &format!("Bearer {}", token))
+            .send_bytes(&torrent);
+        if resp.error() {
+            bail!("Could not POST to synapse: {:?}", resp);
+        }
     }
 
     match c.recv()? {
@@ -86,18 +108,16 @@ fn add_file(
 
     Ok(())
 }
-fn add_magnet(
-    c: &mut Client,
-    magnet: Url,
-    dir: Option<&str>,
-    start: bool,
-    output: &str,
-) -> Result<()> {
+fn add_magnet(c: &mut Client, magnet: Url, opts: &AddOptions<'_>, output: &str) -> Result<()> {
     let msg = CMessage::UploadMagnet {
         serial: c.next_serial(),
         uri: magnet.as_str().to_owned(),
-        path: dir.as_ref().map(|d| format!("{}", d)),
-        start,
+        path: opts.dir.map(|d| d.to_owned()),
+        start: opts.start,
+        label: opts.label.map(|l| l.to_owned()),
+        name: opts.name.map(|n| n.to_owned()),
+        merge: opts.merge,
+        metadata_only: opts.metadata_only,
     };
     match c.rr(msg)? {
         SMessage::ResourcesExtant { ids, .. } => {
@@ -186,25 +206,35 @@ pub fn dl(mut c: Client, url: &str, name: &str) -> Result<()> {
     };
 
     for file in files {
-        let mut dl_url = Url::parse(url).unwrap();
-        dl_url
-            .path_segments_mut()
-            .unwrap()
-            .push("dl")
-            .push(file.id());
         let digest = Sha1::digest(format!("{}{}", file.id(), token).as_bytes());
         let dl_token = base64::encode(&digest.as_slice());
-        let resp = ureq::get(dl_url.as_str()).query("token", &dl_token).call();
-        if resp.error() {
-            bail!("Failed to download from synapse: {:?}", resp);
-        }
+        let mut dl_url = Url::parse(url).unwrap();
+        let mut body = if dl_url.scheme() == "unix" {
+            let mut target = Url::parse("http://localhost/dl").unwrap();
+            target.path_segments_mut().unwrap().push(file.id());
+            target.query_pairs_mut().append_pair("token", &dl_token);
+            let target = &target[Position::BeforePath..];
+            client::unix_request(&dl_url, "GET", target, None, &[])
+                .chain_err(|| "Failed to download from synapse")?
+        } else {
+            dl_url
+                .path_segments_mut()
+                .unwrap()
+                .push("dl")
+                .push(file.id());
+            let resp = ureq::get(dl_url.as_str()).query("token", &dl_token).call();
+            if resp.error() {
+                bail!("Failed to download from synapse: {:?}", resp);
+            }
+            Box::new(resp.into_reader())
+        };
         if let Resource::File(f) = file {
             let p = Path::new(&f.path);
             if let Some(par) = p.parent() {
                 fs::create_dir_all(par).chain_err(|| ErrorKind::FileIO)?;
             }
             let mut f = fs::File::create(p).chain_err(|| ErrorKind::FileIO)?;
-            io::copy(&mut resp.into_reader(), &mut f).chain_err(|| ErrorKind::FileIO)?;
+            io::copy(&mut body, &mut f).chain_err(|| ErrorKind::FileIO)?;
         } else {
             bail!("Expected a file resource");
         }
@@ -236,7 +266,13 @@ pub fn get_(c: &mut Client, id: &str, output: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Result<()> {
+pub fn list(
+    mut c: Client,
+    kind: &str,
+    mut crit: Vec<Criterion>,
+    labels: Vec<&str>,
+    output: &str,
+) -> Result<()> {
     let k = match kind {
         "torrent" => ResourceKind::Torrent,
         "tracker" => ResourceKind::Tracker,
@@ -246,6 +282,14 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
         "server" => ResourceKind::Server,
         _ => bail!("Unexpected resource kind {}", kind),
     };
+    if !labels.is_empty() {
+        let field = match k {
+            ResourceKind::Torrent => "label",
+            ResourceKind::Server => bail!("Servers do not have labels"),
+            _ => "torrent/label",
+        };
+        crit.push(label_criterion(field, labels));
+    }
     let results = search(&mut c, k, crit)?;
     if output == "text" {
         let mut table = Table::new();
@@ -258,7 +302,7 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
                 table.set_titles(row!["URL", "Torrent", "Error"]);
             }
             ResourceKind::Peer => {
-                table.set_titles(row!["IP", "Torrent", "DL RT", "UL RT"]);
+                table.set_titles(row!["IP", "Client", "Torrent", "DL RT", "UL RT"]);
             }
             ResourceKind::Piece => {
                 table.set_titles(row!["Torrent", "DLd", "Avail"]);
@@ -298,7 +342,11 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
                     let p = res.as_peer();
                     let rd = fmt_bytes(p.rate_down as f64) + "/s";
                     let ru = fmt_bytes(p.rate_up as f64) + "/s";
-                    table.add_row(row![p.ip, p.torrent_id, rd, ru]);
+                    let client = match (&p.client_name, &p.client_version) {
+                        (Some(n), Some(v)) => format!("{} {}", n, v),
+                        _ => String::new(),
+                    };
+                    table.add_row(row![p.ip, client, p.torrent_id, rd, ru]);
                 }
                 ResourceKind::Piece => {
                     let p = res.as_piece();
@@ -332,6 +380,58 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
     Ok(())
 }
 
+/// Applies an action to every torrent with one of the given labels.
+pub fn bulk(
+    mut c: Client,
+    action: message::Action,
+    labels: Vec<&str>,
+    artifacts: bool,
+) -> Result<()> {
+    let msg = CMessage::BulkAction {
+        serial: c.next_serial(),
+        criteria: vec![label_criterion("label", labels)],
+        action,
+        artifacts,
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
+fn label_criterion(field: &str, labels: Vec<&str>) -> Criterion {
+    Criterion {
+        field: field.to_owned(),
+        op: Operation::In,
+        value: Value::V(labels.into_iter().map(|l| Value::S(l.to_owned())).collect()),
+    }
+}
+
+pub fn pause_all(mut c: Client) -> Result<()> {
+    let msg = CMessage::PauseAll {
+        serial: c.next_serial(),
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
+pub fn resume_all(mut c: Client) -> Result<()> {
+    let msg = CMessage::ResumeAll {
+        serial: c.next_serial(),
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
+pub fn set_network(mut c: Client, on: bool) -> Result<()> {
+    let serial = c.next_serial();
+    let msg = if on {
+        CMessage::StartNetwork { serial }
+    } else {
+        CMessage::StopNetwork { serial }
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
 pub fn pause(mut c: Client, torrents: Vec<&str>) -> Result<()> {
     for torrent in torrents {
         pause_torrent(&mut c, torrent)?;
@@ -400,6 +500,54 @@ fn resume_torrent(c: &mut Client, torrent: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn reannounce(mut c: Client, torrents: Vec<&str>) -> Result<()> {
+    for torrent in torrents {
+        torrent_action(&mut c, torrent, |serial, id| CMessage::ReannounceTorrent {
+            serial,
+            id,
+        })?;
+    }
+    Ok(())
+}
+
+pub fn dht_refresh(mut c: Client, torrents: Vec<&str>) -> Result<()> {
+    for torrent in torrents {
+        torrent_action(&mut c, torrent, |serial, id| CMessage::RefreshDht {
+            serial,
+            id,
+        })?;
+    }
+    Ok(())
+}
+
+/// Sends the message built for the torrent matching a name, if exactly one does.
+fn torrent_action<F>(c: &mut Client, torrent: &str, msg: F) -> Result<()>
+where
+    F: Fn(u64, String) -> CMessage,
+{
+    let resources = search_torrent_name(c, torrent)?;
+    if resources.len() == 1 {
+        let serial = c.next_serial();
+        c.send(msg(serial, resources[0].id().to_owned()))?;
+    } else if resources.is_empty() {
+        eprintln!("Could not find any matching torrents for {}", torrent);
+    } else {
+        eprintln!(
+            "Ambiguous results searching for {}. Potential alternatives include: ",
+            torrent
+        );
+        for res in resources.into_iter().take(3) {
+            if let Resource::Torrent(t) = res {
+                eprintln!(
+                    "{}",
+                    t.name.unwrap_or_else(|| "[Unknown Magnet]".to_owned())
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 pub fn watch(mut c: Client, id: &str, output: &str, completion: bool) -> Result<()> {
     let res = get_resources(&mut c, vec![id.to_owned()])?;
     if res.is_empty() {
@@ -481,6 +629,44 @@ pub fn move_torrent(mut c: Client, id: &str, dir: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn find_data(mut c: Client, id: &str, dir: &str) -> Result<()> {
+    let torrent = search_torrent_name(&mut c, id)?;
+    if torrent.len() != 1 {
+        bail!("Could not find appropriate torrent!");
+    }
+    let msg = CMessage::FindData {
+        serial: c.next_serial(),
+        id: torrent[0].id().to_owned(),
+        path: dir.to_owned(),
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
+pub fn magnet(mut c: Client, id: &str) -> Result<()> {
+    let torrent = search_torrent_name(&mut c, id)?;
+    if torrent.len() != 1 {
+        bail!("Could not find appropriate torrent!");
+    }
+    let msg = CMessage::GetMagnet {
+        serial: c.next_serial(),
+        id: torrent[0].id().to_owned(),
+    };
+    match c.rr(msg)? {
+        SMessage::Magnet { uri, .. } => {
+            println!("{}", uri);
+            Ok(())
+        }
+        SMessage::InvalidResource(message::Error { reason, .. })
+        | SMessage::UnknownResource(message::Error { reason, .. }) => {
+            bail!("{}", reason);
+        }
+        _ => {
+            bail!("Failed to receive magnet from synapse!");
+        }
+    }
+}
+
 pub fn verify_torrent(mut c: Client, id: &str) -> Result<()> {
     let torrent = search_torrent_name(&mut c, id)?;
     if torrent.len() != 1 {
@@ -688,6 +874,140 @@ pub fn set_torrent_pri(mut c: Client, id: &str, pri: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn list_labels(mut c: Client, output: &str) -> Result<()> {
+    let labels = get_server(&mut c)?.labels;
+    if output == "text" {
+        let mut table = Table::new();
+        table.set_format(*TABLE_FORMAT);
+        table.set_titles(row![
+            "Name",
+            "Directory",
+            "DL Limit",
+            "UL Limit",
+            "Ratio",
+            "Seed Time"
+        ]);
+        for l in labels {
+            table.add_row(row![
+                l.name,
+                l.path.unwrap_or_default(),
+                l.throttle_down.map(|t| t.to_string()).unwrap_or_default(),
+                l.throttle_up.map(|t| t.to_string()).unwrap_or_default(),
+                l.seed_ratio.map(|r| r.to_string()).unwrap_or_default(),
+                l.seed_time.map(|t| t.to_string()).unwrap_or_default()
+            ]);
+        }
+        table.printstd();
+    } else {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&labels).chain_err(|| ErrorKind::Serialization)?
+        );
+    }
+    Ok(())
+}
+
+pub fn set_label(mut c: Client, label: Label) -> Result<()> {
+    let msg = CMessage::SetLabel {
+        serial: c.next_serial(),
+        label,
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
+pub fn remove_label(mut c: Client, name: &str) -> Result<()> {
+    let msg = CMessage::RemoveLabel {
+        serial: c.next_serial(),
+        name: name.to_owned(),
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
+pub fn assign_label(mut c: Client, label: Option<&str>, torrents: Vec<&str>) -> Result<()> {
+    for id in torrents {
+        let torrent = search_torrent_name(&mut c, id)?;
+        if torrent.len() != 1 {
+            bail!("Could not find appropriate torrent!");
+        }
+        let update = CMessage::UpdateResource {
+            serial: c.next_serial(),
+            resource: CResourceUpdate {
+                id: torrent[0].id().to_owned(),
+                label: Some(label.map(|l| l.to_owned())),
+                ..Default::default()
+            },
+        };
+        c.send(update)?;
+    }
+    Ok(())
+}
+
+pub fn list_throttle_groups(mut c: Client, output: &str) -> Result<()> {
+    let groups = get_server(&mut c)?.throttle_groups;
+    if output == "text" {
+        let mut table = Table::new();
+        table.set_format(*TABLE_FORMAT);
+        table.set_titles(row!["Name", "DL Limit", "UL Limit"]);
+        for g in groups {
+            table.add_row(row![
+                g.name,
+                g.throttle_down.map(|t| t.to_string()).unwrap_or_default(),
+                g.throttle_up.map(|t| t.to_string()).unwrap_or_default()
+            ]);
+        }
+        table.printstd();
+    } else {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&groups).chain_err(|| ErrorKind::Serialization)?
+        );
+    }
+    Ok(())
+}
+
+pub fn set_throttle_group(mut c: Client, group: ThrottleGroup) -> Result<()> {
+    let msg = CMessage::SetThrottleGroup {
+        serial: c.next_serial(),
+        group,
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
+pub fn remove_throttle_group(mut c: Client, name: &str) -> Result<()> {
+    let msg = CMessage::RemoveThrottleGroup {
+        serial: c.next_serial(),
+        name: name.to_owned(),
+    };
+    c.send(msg)?;
+    Ok(())
+}
+
+pub fn assign_throttle_group(
+    mut c: Client,
+    group: Option<&str>,
+    torrents: Vec<&str>,
+) -> Result<()> {
+    for id in torrents {
+        let torrent = search_torrent_name(&mut c, id)?;
+        if torrent.len() != 1 {
+            bail!("Could not find appropriate torrent!");
+        }
+        let update = CMessage::UpdateResource {
+            serial: c.next_serial(),
+            resource: CResourceUpdate {
+                id: torrent[0].id().to_owned(),
+                throttle_group: Some(group.map(|g| g.to_owned())),
+                ..Default::default()
+            },
+        };
+        c.send(update)?;
+    }
+    Ok(())
+}
+
 pub fn set_file_pri(mut c: Client, id: &str, pri: &str) -> Result<()> {
     let p: u8 = pri.parse().chain_err(|| ErrorKind::Parse)?;
     let update = CMessage::UpdateResource {
@@ -763,6 +1083,21 @@ pub fn status(mut c: Client) -> Result<()> {
                 fmt_bytes(s.transferred_up as f64),
                 fmt_bytes(s.transferred_down as f64),
             );
+            println!(
+                "session UL: {}, session DL: {}, ratio: {:.3}, uptime: {}h",
+                fmt_bytes(s.ses_transferred_up as f64),
+                fmt_bytes(s.ses_transferred_down as f64),
+                s.ratio,
+                s.uptime / 3600,
+            );
+            println!(
+                "session overhead UL: {}, session overhead DL: {}",
+                fmt_bytes(s.ses_overhead_up as f64),
+                fmt_bytes(s.ses_overhead_down as f64),
+            );
+            if s.network_stopped {
+                println!("network activity is stopped");
+            }
         }
         _ => {
             bail!("synapse server incorrectly reported server status!");
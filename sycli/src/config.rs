@@ -1,15 +1,24 @@
 use std::collections::HashMap;
 use std::io::Read;
+use std::path::Path;
 use std::{fs, process};
 
 pub type Config = HashMap<String, ServerInfo>;
 
+const DEFAULT_SERVER: &str = "ws://localhost:8412";
+
 #[derive(Deserialize)]
 pub struct ServerInfo {
-    pub server: String,
+    /// Found from the running instance's lock file if not given
+    pub server: Option<String>,
     pub password: String,
 }
 
+#[derive(Deserialize)]
+struct Instance {
+    rpc: Vec<String>,
+}
+
 pub fn load() -> Config {
     enum EK {
         Nonext,
@@ -49,9 +58,33 @@ pub fn default() -> Config {
     config.insert(
         "default".to_owned(),
         ServerInfo {
-            server: "ws://localhost:8412".to_owned(),
+            server: None,
             password: "hackme".to_owned(),
         },
     );
     config
 }
+
+/// Finds the RPC endpoint of the synapse instance using a session
+/// directory, or the default one, preferring a unix socket.
+pub fn discover(session: Option<&str>) -> String {
+    let dirs = match session {
+        Some(dir) => vec![dir],
+        None => vec!["$XDG_DATA_HOME/synapse", "~/.local/share/synapse"],
+    };
+    for dir in dirs {
+        let mut s = String::new();
+        let res = shellexpand::full(dir)
+            .ok()
+            .and_then(|d| fs::File::open(Path::new(&*d).join("synapse.lock")).ok())
+            .and_then(|mut f| f.read_to_string(&mut s).ok())
+            .and_then(|_| serde_json::from_str::<Instance>(&s).ok());
+        if let Some(instance) = res {
+            let unix = instance.rpc.iter().find(|url| url.starts_with("unix://"));
+            if let Some(url) = unix.or_else(|| instance.rpc.first()) {
+                return url.clone();
+            }
+        }
+    }
+    DEFAULT_SERVER.to_owned()
+}
@@ -9,6 +9,7 @@ use synapse_rpc as rpc;
 extern crate tungstenite as ws;
 
 use rpc::criterion::Criterion;
+use rpc::message::Action;
 
 mod client;
 mod cmd;
@@ -17,11 +18,12 @@ mod error;
 
 use std::process;
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use error_chain::ChainedError;
 use url::Url;
 
 use self::client::Client;
+use self::error::ErrorKind;
 
 fn main() {
     let config = config::load();
@@ -41,11 +43,18 @@ fn main() {
         )
         .arg(
             Arg::with_name("server")
-                .help("URI of the synapse client to connect to.")
+                .help("URI of the synapse client to connect to, or unix:///path for a unix socket.")
                 .short("s")
                 .long("server")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("session")
+                .help("Session directory of the synapse instance to connect to, if no server is given.")
+                .short("S")
+                .long("session")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("password")
                 .help("Password to use when connecting to synapse.")
@@ -75,6 +84,46 @@ fn main() {
                         .short("i")
                         .long("import"),
                 )
+                .arg(
+                    Arg::with_name("seed-only")
+                        .help(
+                            "Seed existing data in the given directory without ever writing to it.",
+                        )
+                        .long("seed-only")
+                        .requires("directory"),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .help("Label to assign the torrent, inheriting its policies.")
+                        .short("l")
+                        .long("label")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .help("Name to show in place of the torrent's own.")
+                        .short("n")
+                        .long("name")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("priorities")
+                        .help("Comma separated priority of each file in the torrent, 0-5.")
+                        .long("priorities")
+                        .takes_value(true)
+                        .use_delimiter(true),
+                )
+                .arg(
+                    Arg::with_name("merge")
+                        .help("Merge trackers into the torrent if it was already added.")
+                        .short("m")
+                        .long("merge"),
+                )
+                .arg(
+                    Arg::with_name("metadata-only")
+                        .help("Stop magnets once their metadata is fetched, without downloading.")
+                        .long("metadata-only"),
+                )
                 .arg(
                     Arg::with_name("files")
                         .help("Torrent files or magnets to add")
@@ -92,6 +141,21 @@ fn main() {
                         .possible_values(&["json", "text"])
                         .default_value("text"),
                 ),
+            SubCommand::with_name("add-peer")
+                .about("Connects a torrent to the given peers, bypassing trackers.")
+                .arg(
+                    Arg::with_name("torrent")
+                        .help("Name of the torrent.")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("peers")
+                        .help("Addresses of peers to add, as ip:port.")
+                        .multiple(true)
+                        .index(2)
+                        .required(true),
+                ),
             SubCommand::with_name("del")
                 .about("Deletes torrents from synapse.")
                 .arg(
@@ -106,8 +170,18 @@ fn main() {
                         .multiple(true)
                         .short("t")
                         .long("torrents")
-                        .required(true)
+                        .required_unless("label")
                         .index(1),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .help("Delete all torrents with this label.")
+                        .short("l")
+                        .long("label")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .multiple(true)
+                        .conflicts_with("torrents"),
                 ),
             SubCommand::with_name("dl")
                 .about("Downloads a torrent.")
@@ -152,6 +226,169 @@ fn main() {
                         .index(1)
                         .required(true),
                 ),
+            SubCommand::with_name("group")
+                .about("Manipulate throttle groups")
+                .subcommands(vec![
+                    SubCommand::with_name("list").about("Lists throttle groups").arg(
+                        Arg::with_name("output")
+                            .help("Output the results in the specified format.")
+                            .short("o")
+                            .long("output")
+                            .possible_values(&["json", "text"])
+                            .default_value("text"),
+                    ),
+                    SubCommand::with_name("set")
+                        .about("Creates or updates a throttle group")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Name of the group")
+                                .index(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("throttle up")
+                                .help("Upload limit shared by the group in bytes per second.")
+                                .short("u")
+                                .long("throttle-up")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("throttle down")
+                                .help("Download limit shared by the group in bytes per second.")
+                                .short("D")
+                                .long("throttle-down")
+                                .takes_value(true),
+                        ),
+                    SubCommand::with_name("remove")
+                        .about("Removes a throttle group")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Name of the group")
+                                .index(1)
+                                .required(true),
+                        ),
+                    SubCommand::with_name("assign")
+                        .about("Moves torrents into a throttle group")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Name of the group")
+                                .index(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("torrents")
+                                .help("Names of torrents to move.")
+                                .multiple(true)
+                                .index(2)
+                                .required(true),
+                        ),
+                    SubCommand::with_name("clear")
+                        .about("Takes torrents out of their throttle group")
+                        .arg(
+                            Arg::with_name("torrents")
+                                .help("Names of torrents to take out.")
+                                .multiple(true)
+                                .index(1)
+                                .required(true),
+                        ),
+                ])
+                .setting(AppSettings::SubcommandRequiredElseHelp),
+            SubCommand::with_name("label")
+                .about("Manipulate labels")
+                .subcommands(vec![
+                    SubCommand::with_name("list").about("Lists labels").arg(
+                        Arg::with_name("output")
+                            .help("Output the results in the specified format.")
+                            .short("o")
+                            .long("output")
+                            .possible_values(&["json", "text"])
+                            .default_value("text"),
+                    ),
+                    SubCommand::with_name("set")
+                        .about("Creates or updates a label")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Name of the label")
+                                .index(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("directory")
+                                .help("Directory new torrents are downloaded to.")
+                                .short("d")
+                                .long("directory")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("throttle up")
+                                .help("Upload limit in bytes per second.")
+                                .short("u")
+                                .long("throttle-up")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("throttle down")
+                                .help("Download limit in bytes per second.")
+                                .short("D")
+                                .long("throttle-down")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("ratio")
+                                .help("Upload ratio after which torrents are paused.")
+                                .short("r")
+                                .long("ratio")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("seed time")
+                                .help("Seconds of seeding after which torrents are paused.")
+                                .short("t")
+                                .long("seed-time")
+                                .takes_value(true),
+                        ),
+                    SubCommand::with_name("remove")
+                        .about("Removes a label")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Name of the label")
+                                .index(1)
+                                .required(true),
+                        ),
+                    SubCommand::with_name("assign")
+                        .about("Assigns a label to torrents")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Name of the label")
+                                .index(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("torrents")
+                                .help("Names of torrents to label.")
+                                .multiple(true)
+                                .index(2)
+                                .required(true),
+                        ),
+                    SubCommand::with_name("clear")
+                        .about("Removes the label of torrents")
+                        .arg(
+                            Arg::with_name("torrents")
+                                .help("Names of torrents to unlabel.")
+                                .multiple(true)
+                                .index(1)
+                                .required(true),
+                        ),
+                ])
+                .setting(AppSettings::SubcommandRequiredElseHelp),
+            SubCommand::with_name("magnet")
+                .about("Prints a magnet link for a torrent.")
+                .arg(
+                    Arg::with_name("torrent")
+                        .help("Name or ID of the torrent.")
+                        .index(1)
+                        .required(true),
+                ),
             SubCommand::with_name("list")
                 .about("Lists resources of a given type in synapse.")
                 .arg(
@@ -169,6 +406,15 @@ fn main() {
                         .short("k")
                         .long("kind"),
                 )
+                .arg(
+                    Arg::with_name("label")
+                        .help("Only list resources of torrents with this label.")
+                        .short("l")
+                        .long("label")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .multiple(true),
+                )
                 .arg(
                     Arg::with_name("output")
                         .help("Output the results in the specified format.")
@@ -177,27 +423,110 @@ fn main() {
                         .possible_values(&["json", "text"])
                         .default_value("text"),
                 ),
+            SubCommand::with_name("network")
+                .about("Stops or starts all network activity, leaving torrents as they are")
+                .subcommands(vec![
+                    SubCommand::with_name("stop").about("Disconnects all peers and stops announcing"),
+                    SubCommand::with_name("start").about("Starts network activity again"),
+                ])
+                .setting(AppSettings::SubcommandRequiredElseHelp),
             SubCommand::with_name("pause")
                 .about("Pauses the given torrents.")
                 .arg(
                     Arg::with_name("torrents")
                         .help("Names of torrents to pause.")
-                        .required(true)
+                        .required_unless_one(&["label", "all"])
                         .multiple(true)
                         .short("t")
                         .long("torrents")
                         .index(1),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .help("Pause all torrents with this label.")
+                        .short("l")
+                        .long("label")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .multiple(true)
+                        .conflicts_with("torrents"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .help("Pause all torrents.")
+                        .short("a")
+                        .long("all")
+                        .conflicts_with_all(&["torrents", "label"]),
                 ),
             SubCommand::with_name("resume")
                 .about("Resumes the given torrents.")
                 .arg(
                     Arg::with_name("torrents")
                         .help("Names of torrents to resume.")
-                        .required(true)
+                        .required_unless_one(&["label", "all"])
+                        .multiple(true)
+                        .short("t")
+                        .long("torrents")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .help("Resume all torrents with this label.")
+                        .short("l")
+                        .long("label")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .multiple(true)
+                        .conflicts_with("torrents"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .help("Resume all torrents.")
+                        .short("a")
+                        .long("all")
+                        .conflicts_with_all(&["torrents", "label"]),
+                ),
+            SubCommand::with_name("reannounce")
+                .about("Announces the given torrents to their trackers now.")
+                .arg(
+                    Arg::with_name("torrents")
+                        .help("Names of torrents to announce.")
+                        .required_unless("label")
                         .multiple(true)
                         .short("t")
                         .long("torrents")
                         .index(1),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .help("Announce all torrents with this label.")
+                        .short("l")
+                        .long("label")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .multiple(true)
+                        .conflicts_with("torrents"),
+                ),
+            SubCommand::with_name("dht-refresh")
+                .about("Announces the given torrents to the DHT and looks up their peers.")
+                .arg(
+                    Arg::with_name("torrents")
+                        .help("Names of torrents to refresh.")
+                        .required_unless("label")
+                        .multiple(true)
+                        .short("t")
+                        .long("torrents")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .help("Refresh all torrents with this label.")
+                        .short("l")
+                        .long("label")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .multiple(true)
+                        .conflicts_with("torrents"),
                 ),
             SubCommand::with_name("status").about("Server status"),
             SubCommand::with_name("watch")
@@ -238,6 +567,14 @@ fn main() {
                                 .index(1)
                                 .required(true),
                         ),
+                    SubCommand::with_name("find-data")
+                        .about("Search a directory for the torrent's data and use it")
+                        .arg(
+                            Arg::with_name("directory")
+                                .help("Absolute path of the directory to search.")
+                                .index(1)
+                                .required(true),
+                        ),
                     SubCommand::with_name("tracker")
                         .about("Manipulate trackers for a torrent")
                         .subcommands(vec![
@@ -342,8 +679,8 @@ fn main() {
         ])
         .get_matches();
 
-    let (mut server, mut pass) = match config.get(matches.value_of("profile").unwrap()) {
-        Some(profile) => (profile.server.as_str(), profile.password.as_str()),
+    let (profile_server, mut pass) = match config.get(matches.value_of("profile").unwrap()) {
+        Some(profile) => (profile.server.as_ref(), profile.password.as_str()),
         None => {
             eprintln!(
                 "Nonexistent profile {} referenced in argument!",
@@ -352,9 +689,15 @@ fn main() {
             process::exit(1);
         }
     };
-    if let Some(url) = matches.value_of("server") {
-        server = url;
-    }
+    let discovered;
+    let server = match (matches.value_of("server"), profile_server) {
+        (Some(url), _) => url,
+        (None, Some(server)) => server.as_str(),
+        (None, None) => {
+            discovered = config::discover(matches.value_of("session"));
+            discovered.as_str()
+        }
+    };
     if let Some(password) = matches.value_of("password") {
         pass = password;
     }
@@ -395,10 +738,11 @@ fn main() {
         process::exit(1);
     }
 
-    if url.scheme() == "wss" {
-        url.set_scheme("https").unwrap();
-    } else {
-        url.set_scheme("http").unwrap();
+    // Transfers over a unix socket reuse the socket's URL
+    match url.scheme() {
+        "wss" => url.set_scheme("https").unwrap(),
+        "unix" => {}
+        _ => url.set_scheme("http").unwrap(),
     }
 
     match matches.subcommand_name().unwrap() {
@@ -409,27 +753,61 @@ fn main() {
                 files.push(file)
             }
             let output = args.value_of("output").unwrap();
-            let res = cmd::add(
+            let priorities = args
+                .values_of("priorities")
+                .map(|v| {
+                    v.map(str::parse::<u8>)
+                        .collect::<std::result::Result<Vec<_>, _>>()
+                })
+                .unwrap_or_else(|| Ok(Vec::new()))
+                .unwrap_or_else(|_| {
+                    eprintln!("File priorities must be numbers from 0 to 5");
+                    process::exit(1);
+                });
+            let opts = cmd::AddOptions {
+                dir: args.value_of("directory"),
+                start: !args.is_present("pause"),
+                import: args.is_present("import"),
+                seed_only: args.is_present("seed-only"),
+                label: args.value_of("label"),
+                name: args.value_of("name"),
+                priorities,
+                merge: args.is_present("merge"),
+                metadata_only: args.is_present("metadata-only"),
+            };
+            let res = cmd::add(client, url.as_str(), files, &opts, output);
+            if let Err(e) = res {
+                eprintln!("Failed to add torrents: {}", e.display_chain());
+                process::exit(1);
+            }
+        }
+        "add-peer" => {
+            let args = matches.subcommand_matches("add-peer").unwrap();
+            let res = cmd::add_peers(
                 client,
-                url.as_str(),
-                files,
-                args.value_of("directory"),
-                !args.is_present("pause"),
-                args.is_present("import"),
-                output,
+                args.value_of("torrent").unwrap(),
+                args.values_of("peers").unwrap().collect(),
             );
             if let Err(e) = res {
-                eprintln!("Failed to add torrents: {}", e.display_chain());
+                eprintln!("Failed to add peers: {}", e.display_chain());
                 process::exit(1);
             }
         }
         "del" => {
             let args = matches.subcommand_matches("del").unwrap();
-            let res = cmd::del(
-                client,
-                args.values_of("torrents").unwrap().collect(),
-                args.is_present("files"),
-            );
+            let res = match args.values_of("label") {
+                Some(labels) => cmd::bulk(
+                    client,
+                    Action::Remove,
+                    labels.collect(),
+                    args.is_present("files"),
+                ),
+                None => cmd::del(
+                    client,
+                    args.values_of("torrents").unwrap().collect(),
+                    args.is_present("files"),
+                ),
+            };
             if let Err(e) = res {
                 eprintln!("Failed to delete torrents: {}", e.display_chain());
                 process::exit(1);
@@ -469,6 +847,66 @@ fn main() {
                 process::exit(1);
             }
         }
+        "group" => {
+            let subcmd = matches.subcommand_matches("group").unwrap();
+            let res = match subcmd.subcommand() {
+                ("list", Some(args)) => {
+                    cmd::list_throttle_groups(client, args.value_of("output").unwrap())
+                }
+                ("set", Some(args)) => {
+                    parse_throttle_group(args).and_then(|g| cmd::set_throttle_group(client, g))
+                }
+                ("remove", Some(args)) => {
+                    cmd::remove_throttle_group(client, args.value_of("name").unwrap())
+                }
+                ("assign", Some(args)) => cmd::assign_throttle_group(
+                    client,
+                    args.value_of("name"),
+                    args.values_of("torrents").unwrap().collect(),
+                ),
+                ("clear", Some(args)) => cmd::assign_throttle_group(
+                    client,
+                    None,
+                    args.values_of("torrents").unwrap().collect(),
+                ),
+                _ => unreachable!(),
+            };
+            if let Err(e) = res {
+                eprintln!(
+                    "Failed to manipulate throttle groups: {}",
+                    e.display_chain()
+                );
+                process::exit(1);
+            }
+        }
+        "label" => {
+            let subcmd = matches.subcommand_matches("label").unwrap();
+            let res = match subcmd.subcommand() {
+                ("list", Some(args)) => cmd::list_labels(client, args.value_of("output").unwrap()),
+                ("set", Some(args)) => parse_label(args).and_then(|l| cmd::set_label(client, l)),
+                ("remove", Some(args)) => cmd::remove_label(client, args.value_of("name").unwrap()),
+                ("assign", Some(args)) => cmd::assign_label(
+                    client,
+                    args.value_of("name"),
+                    args.values_of("torrents").unwrap().collect(),
+                ),
+                ("clear", Some(args)) => {
+                    cmd::assign_label(client, None, args.values_of("torrents").unwrap().collect())
+                }
+                _ => unreachable!(),
+            };
+            if let Err(e) = res {
+                eprintln!("Failed to manipulate labels: {}", e.display_chain());
+                process::exit(1);
+            }
+        }
+        "magnet" => {
+            let args = matches.subcommand_matches("magnet").unwrap();
+            if let Err(e) = cmd::magnet(client, args.value_of("torrent").unwrap()) {
+                eprintln!("Failed to get magnet: {}", e.display_chain());
+                process::exit(1);
+            }
+        }
         "list" => {
             let args = matches.subcommand_matches("list").unwrap();
 
@@ -480,15 +918,37 @@ fn main() {
 
             let kind = args.value_of("kind").unwrap();
             let output = args.value_of("output").unwrap();
-            let res = cmd::list(client, kind, crit, output);
+            let labels = args
+                .values_of("label")
+                .map(|l| l.collect())
+                .unwrap_or_default();
+            let res = cmd::list(client, kind, crit, labels, output);
             if let Err(e) = res {
                 eprintln!("Failed to list torrents: {}", e.display_chain());
                 process::exit(1);
             }
         }
+        "network" => {
+            let subcmd = matches.subcommand_matches("network").unwrap();
+            let res = match subcmd.subcommand() {
+                ("stop", Some(_)) => cmd::set_network(client, false),
+                ("start", Some(_)) => cmd::set_network(client, true),
+                _ => unreachable!(),
+            };
+            if let Err(e) = res {
+                eprintln!("Failed to change network activity: {}", e.display_chain());
+                process::exit(1);
+            }
+        }
         "pause" => {
             let args = matches.subcommand_matches("pause").unwrap();
-            let res = cmd::pause(client, args.values_of("torrents").unwrap().collect());
+            let res = if args.is_present("all") {
+                cmd::pause_all(client)
+            } else if let Some(labels) = args.values_of("label") {
+                cmd::bulk(client, Action::Pause, labels.collect(), false)
+            } else {
+                cmd::pause(client, args.values_of("torrents").unwrap().collect())
+            };
             if let Err(e) = res {
                 eprintln!("Failed to pause torrents: {}", e.display_chain());
                 process::exit(1);
@@ -496,12 +956,43 @@ fn main() {
         }
         "resume" => {
             let args = matches.subcommand_matches("resume").unwrap();
-            let res = cmd::resume(client, args.values_of("torrents").unwrap().collect());
+            let res = if args.is_present("all") {
+                cmd::resume_all(client)
+            } else if let Some(labels) = args.values_of("label") {
+                cmd::bulk(client, Action::Resume, labels.collect(), false)
+            } else {
+                cmd::resume(client, args.values_of("torrents").unwrap().collect())
+            };
             if let Err(e) = res {
                 eprintln!("Failed to resume torrents: {}", e.display_chain());
                 process::exit(1);
             }
         }
+        "reannounce" => {
+            let args = matches.subcommand_matches("reannounce").unwrap();
+            let res = match args.values_of("label") {
+                Some(labels) => cmd::bulk(client, Action::Reannounce, labels.collect(), false),
+                None => cmd::reannounce(client, args.values_of("torrents").unwrap().collect()),
+            };
+            if let Err(e) = res {
+                eprintln!("Failed to reannounce torrents: {}", e.display_chain());
+                process::exit(1);
+            }
+        }
+        "dht-refresh" => {
+            let args = matches.subcommand_matches("dht-refresh").unwrap();
+            let res = match args.values_of("label") {
+                Some(labels) => cmd::bulk(client, Action::DhtRefresh, labels.collect(), false),
+                None => cmd::dht_refresh(client, args.values_of("torrents").unwrap().collect()),
+            };
+            if let Err(e) = res {
+                eprintln!(
+                    "Failed to refresh torrents in the DHT: {}",
+                    e.display_chain()
+                );
+                process::exit(1);
+            }
+        }
         "status" => {
             if let Err(e) = cmd::status(client) {
                 eprintln!("Failed to get server status: {}", e.display_chain());
@@ -524,6 +1015,17 @@ fn main() {
                         process::exit(1);
                     }
                 }
+                "find-data" => {
+                    let dir = subcmd
+                        .subcommand_matches("find-data")
+                        .unwrap()
+                        .value_of("directory")
+                        .unwrap();
+                    if let Err(e) = cmd::find_data(client, id, dir) {
+                        eprintln!("Failed to find torrent data: {}", e.display_chain());
+                        process::exit(1);
+                    }
+                }
                 "verify" => {
                     if let Err(e) = cmd::verify_torrent(client, id) {
                         eprintln!("Failed to verify integrity: {}", e.display_chain());
@@ -703,6 +1205,33 @@ fn main() {
     }
 }
 
+/// Parse an optional argument, failing if it's given but invalid
+fn parse<T: std::str::FromStr>(args: &ArgMatches<'_>, name: &str) -> error::Result<Option<T>> {
+    match args.value_of(name) {
+        Some(v) => v.parse().map(Some).map_err(|_| ErrorKind::Parse.into()),
+        None => Ok(None),
+    }
+}
+
+fn parse_label(args: &ArgMatches<'_>) -> error::Result<rpc::resource::Label> {
+    Ok(rpc::resource::Label {
+        name: args.value_of("name").unwrap().to_owned(),
+        path: args.value_of("directory").map(|d| d.to_owned()),
+        throttle_up: parse(args, "throttle up")?,
+        throttle_down: parse(args, "throttle down")?,
+        seed_ratio: parse(args, "ratio")?,
+        seed_time: parse(args, "seed time")?,
+    })
+}
+
+fn parse_throttle_group(args: &ArgMatches<'_>) -> error::Result<rpc::resource::ThrottleGroup> {
+    Ok(rpc::resource::ThrottleGroup {
+        name: args.value_of("name").unwrap().to_owned(),
+        throttle_up: parse(args, "throttle up")?,
+        throttle_down: parse(args, "throttle down")?,
+    })
+}
+
 /// Parse search criteria out of a filter string
 fn parse_filter(searches: &str) -> Vec<Criterion> {
     use regex::Regex;
@@ -714,7 +1243,7 @@ fn parse_filter(searches: &str) -> Vec<Criterion> {
     // regular expression for finding search criteria that take string types
     let string_searches = Regex::new(
         r#"(?x)
-        \b(name|path|status|tracker) # field name
+        \b(name|path|status|tracker|label|throttle_group) # field name
         (==|!=|::|:)                 # delimiter
         ("(.+?)"                     # quoted argument
         |([0-9.a-zA-Z]+))            # unquoted argument
@@ -725,7 +1254,7 @@ fn parse_filter(searches: &str) -> Vec<Criterion> {
     // regular expression for finding search criteria that take numeric types
     let numeric_searches = Regex::new(
         r#"(?x)
-        \b(size|progress|priority|availability
+        \b(size|wanted_size|progress|eta|priority|availability
            |rate_up|rate_down|throttle_up|throttle_down
            |transferred_up|transferred_down
            |peers|trackers|files)    # field name
@@ -59,6 +59,7 @@ impl State {
                 id: tid.clone(),
                 name: Some(tid.clone()),
                 status: resource::Status::Idle,
+                state: resource::TorrentState::Seeding,
                 progress: 1.0,
                 peers,
                 trackers,
@@ -311,7 +312,7 @@ impl State {
                     reason: format!("Validate not supported!"),
                 }));
             }
-            CMessage::UploadTorrent { serial, .. } => {
+            CMessage::UploadTorrent { serial, .. } | CMessage::UploadChunk { serial, .. } => {
                 resp.push(SMessage::InvalidRequest(Error {
                     serial: Some(serial),
                     reason: format!("Upload not supported!"),